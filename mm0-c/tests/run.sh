@@ -0,0 +1,64 @@
+#! /bin/sh
+# Tests for the side conditions checked when a theorem is applied: the arguments
+# for bound variables must be bound variables, and must be disjoint from each other
+# and from the arguments that are not declared to depend on them.
+#
+# Each X.mmb is compiled from X.mmu (using "mm0-rs compile X.mmu X.mmb", which
+# does not check proofs) and is checked against X.mm0. pass.mmb must verify, and
+# the others must fail with the given message. If mm0-rs is on the PATH, the
+# elaborator is also checked to reject the same proofs in X.mm1, and "mm0-rs verify"
+# is checked to agree with mm0-c. thm_axiom.mm0 is pass.mm0 with a theorem changed to
+# an axiom, so pass.mmb must fail to match it. flags.mmb is pass.mmb with the
+# compressed flag set in the header (without compressing it), which mm0-c must reject.
+cd "$(dirname "$0")"
+gcc ../main.c -O2 -Wall -o mm0-c || exit 1
+failed=0
+
+check_fail() {
+  if ./mm0-c "$1.mmb" < "$1.mm0" 2> "$1.out"; then
+    echo "FAIL: $1.mmb was accepted"; failed=1
+  elif ! grep -qF "$2" "$1.out"; then
+    echo "FAIL: $1.mmb: expected '$2', got:"; cat "$1.out"; failed=1
+  fi
+  rm -f "$1.out"
+}
+
+check_elab() {
+  if command -v mm0-rs > /dev/null && ! mm0-rs compile "$1.mm1" 2>&1 | grep -qF "$2"; then
+    echo "FAIL: $1.mm1: expected '$2'"; failed=1
+  fi
+}
+
+check_verify() {
+  if ! command -v mm0-rs > /dev/null; then return; fi
+  if [ -z "$3" ]; then
+    mm0-rs verify "$1" "$2" > /dev/null || { echo "FAIL: mm0-rs verify rejected $2"; failed=1; }
+  elif mm0-rs verify "$1" "$2" 2> verify.out; then
+    echo "FAIL: mm0-rs verify accepted $2 against $1"; failed=1
+  elif ! grep -qF "$3" verify.out; then
+    echo "FAIL: mm0-rs verify $2: expected '$3', got:"; cat verify.out; failed=1
+  fi
+  rm -f verify.out
+}
+
+./mm0-c pass.mmb < pass.mm0 || { echo "FAIL: pass.mmb was rejected"; failed=1; }
+check_fail dv_bound "between arguments 0 and 1 of ax"
+check_fail dv_regular "between arguments 0 and 1 of ax2"
+check_fail not_bound "type mismatch"
+cp pass.mmb flags.mmb; cp pass.mm0 flags.mm0
+printf '\001' | dd of=flags.mmb bs=1 seek=6 conv=notrunc 2> /dev/null
+check_fail flags "Unsupported header flags"
+rm -f flags.mmb flags.mm0
+check_elab dv_bound "(x, y) -> (x, x)"
+check_elab dv_regular "(x, a) -> (x, x)"
+check_elab not_bound "ax: argument x must be a bound variable, got a"
+check_verify pass.mm0 pass.mmb
+check_verify dv_bound.mm0 dv_bound.mmb "between arguments 0 and 1 of ax"
+check_verify dv_regular.mm0 dv_regular.mmb "between arguments 0 and 1 of ax2"
+check_verify not_bound.mm0 not_bound.mmb "type mismatch"
+check_verify thm_axiom.mm0 pass.mmb "a theorem is an axiom in the specification"
+check_verify dv_bound.mm1 dv_bound.mmb "the specification must be an .mm0 file"
+
+rm -f mm0-c
+[ $failed = 0 ] && echo "all tests passed"
+exit $failed
@@ -9,6 +9,15 @@ typedef uint64_t u64;
 #define PACKED __attribute__((packed))
 #define UNREACHABLE() __builtin_unreachable()
 
+// Branch prediction hints, for compilers that support them.
+#ifdef __GNUC__
+#define LIKELY(x) __builtin_expect(!!(x), 1)
+#define UNLIKELY(x) __builtin_expect(!!(x), 0)
+#else
+#define LIKELY(x) (x)
+#define UNLIKELY(x) (x)
+#endif
+
 // This is called by the ENSURE macro on failure. The verifier is optimized
 // for the non-failure case, and keeps very little nonessential information
 // about what is going on, so this function is responsible for reconstructing
@@ -20,7 +29,7 @@ typedef uint64_t u64;
 void fail(char* err, int e);
 
 #define EENSURE(err, e, cond) \
-  if (__builtin_expect(!(cond), 0)) { \
+  if (UNLIKELY(!(cond))) { \
     fail(err, e); \
   }
 #define ENSURE(err, cond) EENSURE(err, -1, cond)
@@ -35,6 +44,14 @@ void fail(char* err, int e);
 
 #define MM0B_MAGIC (u32)0x42304D4D // = "MM0B"
 #define MM0B_VERSION (u8)1
+// Header flag: the proof section is compressed. At p_proof there is a u64
+// giving the length n of the compressed data, followed by n bytes containing
+// a zstd frame, which decompresses to the proof section (up to p_index, or
+// the end of the file). All other pointers in the file (p_index and the index
+// itself) refer to positions in the decompressed file. This verifier does not
+// decompress, so such a file must be decompressed (e.g. by mm0-rs) before it
+// can be checked here; all files with a nonzero flags byte are rejected.
+#define MM0B_FLAG_COMPRESSED (u8)1
 #define MAX_SORTS 128
 
 typedef struct {
@@ -42,7 +59,8 @@ typedef struct {
   u8 version;        // = MM0B_VERSION
 
   u8 num_sorts;      // number of declared sorts ( <= MAX_SORTS )
-  u16 reserved;
+  u8 flags;          // MM0B_FLAG_* bit flags, must be 0 here
+  u8 reserved;
   u32 num_terms;     // number of terms and defs
   u32 num_thms;      // number of axioms and theorems
 
@@ -17,6 +17,14 @@ u8* g_ucmd;
 //
 // err: a static error message
 // e: the nonzero return code (default -1)
+#ifndef BARE
+// The theorem and argument indices of the last disjoint variable violation,
+// set by dv_fail() so that fail() can report them.
+bool g_dv_fail = false;
+u32 g_dv_thm;
+u16 g_dv_args[2];
+#endif
+
 void fail(char* err, int e) {
 #ifndef BARE
   if (g_stmt) {
@@ -34,7 +42,14 @@ void fail(char* err, int e) {
       fprintf(stderr, "at %s: ", ix->value);
     }
   }
-  fprintf(stderr, "%s\n\n", err);
+  fprintf(stderr, "%s\n", err);
+  if (g_dv_fail) {
+    fprintf(stderr, "  between arguments %d and %d of ", g_dv_args[0], g_dv_args[1]);
+    index_entry* ix = lookup_thm(g_dv_thm);
+    if (ix) fprintf(stderr, "%s\n", ix->value);
+    else fprintf(stderr, "thm %d\n", g_dv_thm);
+  }
+  fprintf(stderr, "\n");
   debug_print_input();
   fprintf(stderr, "cmds:\n");
   debug_print_cmds(g_cmd_start, g_cmd);
@@ -173,6 +188,22 @@ bool sorts_compatible(u64 from, u64 to) {
     (from & TYPE_BOUND_MASK) != 0);
 }
 
+// Fail with a disjoint variable violation between arguments i and j
+// in an application of theorem t.
+void dv_fail(u32 t, u16 i, u16 j) {
+#ifndef BARE
+  g_dv_fail = true; g_dv_thm = t;
+  g_dv_args[0] = i; g_dv_args[1] = j;
+#endif
+  fail("disjoint variable violation", -1);
+}
+
+// Returns the index of the j-th bound variable in a list of binders.
+u16 bound_arg(u64 args[], u8 j) {
+  for (u16 i = 0;; i++)
+    if ((args[i] & TYPE_BOUND_MASK) && j-- == 0) return i;
+}
+
 // Given a list of binders, load the main heap and allocate all the variables.
 // Also perform binder validity checking.
 void load_args(u64 args[], u32 num_args) {
@@ -459,16 +490,20 @@ u8* run_proof(proof_mode mode, u8* cmd) {
           u32 arg = as_type(g_stack_top[i], STACK_TYPE_EXPR);
           g_uheap[i] = arg;
           u64 target = targs[i];
-          u64 deps = get_expr(arg)->type & TYPE_DEPS_MASK;
+          u64 type = get_expr(arg)->type;
+          // The argument must have the sort of the binder, and if the binder
+          // is a bound variable then so must be the argument
+          ENSURE("type mismatch", sorts_compatible(type, target));
+          u64 deps = type & TYPE_DEPS_MASK;
           if (target & TYPE_BOUND_MASK) {
             g_deps[bound++] = deps;
             for (u16 j = 0; j < i; j++)
-              ENSURE("disjoint variable violation",
-                (get_expr(g_uheap[j])->type & deps) == 0);
+              if (UNLIKELY((get_expr(g_uheap[j])->type & deps) != 0))
+                dv_fail(data, j, i);
           } else {
             for (u8 j = 0; j < bound; j++)
-              ENSURE("disjoint variable violation",
-                (target & ((u64)1<<j)) || (g_deps[j] & deps) == 0);
+              if (UNLIKELY(!(target & ((u64)1<<j)) && (g_deps[j] & deps) != 0))
+                dv_fail(data, bound_arg(targs, j), i);
           }
         }
         // free g_deps;
@@ -637,6 +672,8 @@ void verify(u8* file, u64 len) {
   header* p = (header*)file;
   ENSURE("Not a MM0B file", p->magic == MM0B_MAGIC);
   ENSURE("Wrong version", p->version == MM0B_VERSION);
+  ENSURE("Unsupported header flags (a compressed file must be decompressed first)",
+    p->flags == 0);
   ENSURE("Too many sorts", p->num_sorts <= MAX_SORTS);
   ENSURE("header not long enough", len >= sizeof(header) + p->num_sorts);
   ENSURE("Term table out of range",
@@ -0,0 +1,266 @@
+//! Basic types shared by the MM0 toolchain: source locations ([`Span`], [`FileRef`],
+//! [`FileSpan`]), shared strings ([`ArcString`]), and some extension traits.
+//!
+//! These are used throughout `mm0-rs`, and are split out into this crate so that
+//! external tools working with its output can use them without depending on the
+//! elaborator. They are re-exported from `mm0-rs`'s `util` module.
+
+// rust lints we want
+#![warn(bare_trait_objects, elided_lifetimes_in_paths,
+  missing_copy_implementations, missing_debug_implementations, future_incompatible,
+  rust_2018_idioms, trivial_numeric_casts, variant_size_differences, unreachable_pub,
+  unused, missing_docs)]
+// all the clippy
+#![warn(clippy::all, clippy::pedantic, clippy::nursery, clippy::cargo)]
+#![allow(clippy::missing_const_for_fn, clippy::module_name_repetitions)]
+
+#[macro_use] extern crate lazy_static;
+
+use std::ops::{Deref, DerefMut};
+use std::borrow::Borrow;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::hash::{Hash, Hasher, BuildHasher};
+use std::collections::{HashMap, hash_map::{Entry, OccupiedEntry}};
+
+/// Extension trait for [`HashMap`]`<K, V>`.
+pub trait HashMapExt<K, V> {
+  /// Like `insert`, but if the insertion fails then it returns the value
+  /// that it attempted to insert, as well as an [`OccupiedEntry`] containing
+  /// the other value that was found.
+  fn try_insert(&mut self, k: K, v: V) -> Option<(V, OccupiedEntry<'_, K, V>)>;
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> HashMapExt<K, V> for HashMap<K, V, S> {
+  fn try_insert(&mut self, k: K, v: V) -> Option<(V, OccupiedEntry<'_, K, V>)> {
+    match self.entry(k) {
+      Entry::Vacant(e) => { e.insert(v); None }
+      Entry::Occupied(e) => Some((v, e))
+    }
+  }
+}
+
+/// Newtype for an `Arc<String>`, so that we can implement `From<&str>`.
+#[derive(Clone, Hash, PartialEq, Eq)]
+pub struct ArcString(pub Arc<[u8]>);
+
+impl Borrow<[u8]> for ArcString {
+  fn borrow(&self) -> &[u8] { &*self.0 }
+}
+impl Deref for ArcString {
+  type Target = [u8];
+  fn deref(&self) -> &[u8] { &*self.0 }
+}
+impl ArcString {
+  /// Constructs a new [`ArcString`].
+  #[must_use]
+  pub fn new(s: Box<[u8]>) -> Self { Self(s.into()) }
+}
+impl fmt::Display for ArcString {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", String::from_utf8_lossy(self))
+  }
+}
+impl fmt::Debug for ArcString {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{:?}", String::from_utf8_lossy(self))
+  }
+}
+impl From<&[u8]> for ArcString {
+  fn from(s: &[u8]) -> Self { Self::new(s.into()) }
+}
+impl From<Box<[u8]>> for ArcString {
+  fn from(s: Box<[u8]>) -> Self { Self::new(s) }
+}
+impl From<Vec<u8>> for ArcString {
+  fn from(s: Vec<u8>) -> Self { s.into_boxed_slice().into() }
+}
+impl From<String> for ArcString {
+  fn from(s: String) -> Self { s.into_bytes().into() }
+}
+
+impl ArcString {
+  /// Get this string as a `&str`, assuming it is UTF-8 (as atom names and tokens are).
+  #[must_use] pub fn as_str(&self) -> &str {
+    unsafe {std::str::from_utf8_unchecked(self)}
+  }
+}
+
+/// Points to a specific region of a source file by identifying the region's start and end points.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+  /// The byte index of the beginning of the span (inclusive).
+  pub start: usize,
+  /// The byte index of the end of the span (exclusive).
+  pub end: usize,
+}
+
+impl From<std::ops::Range<usize>> for Span {
+  #[inline] fn from(r: std::ops::Range<usize>) -> Self {
+    Span {start: r.start, end: r.end}
+  }
+}
+
+impl From<std::ops::RangeInclusive<usize>> for Span {
+  #[inline] fn from(r: std::ops::RangeInclusive<usize>) -> Self {
+    Span {start: *r.start(), end: *r.end()+1}
+  }
+}
+
+impl From<usize> for Span {
+  #[inline] fn from(n: usize) -> Self { Span {start: n, end: n} }
+}
+
+impl From<Span> for std::ops::Range<usize> {
+  #[inline] fn from(s: Span) -> Self { s.start..s.end }
+}
+
+impl Deref for Span {
+  type Target = std::ops::Range<usize>;
+  fn deref(&self) -> &std::ops::Range<usize> {
+    unsafe { &*(self as *const Span as *const std::ops::Range<usize>) }
+  }
+}
+
+impl DerefMut for Span {
+  fn deref_mut(&mut self) -> &mut std::ops::Range<usize> {
+    unsafe { &mut *(self as *mut Span as *mut std::ops::Range<usize>) }
+  }
+}
+
+impl IntoIterator for Span {
+  type Item = usize;
+  type IntoIter = std::ops::Range<usize>;
+  fn into_iter(self) -> std::ops::Range<usize> { (*self).clone() }
+}
+
+impl fmt::Debug for Span {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}..{}", self.start, self.end)
+  }
+}
+
+lazy_static! {
+  /// A [`PathBuf`] created by `lazy_static!` pointing to a canonicalized "."
+  pub static ref CURRENT_DIR: PathBuf =
+    std::fs::canonicalize(".").expect("failed to find current directory");
+}
+
+/// Given a [`PathBuf`] 'buf', constructs a relative path from [`CURRENT_DIR`]
+/// to buf, returning it as a String.
+///
+/// Example: If [`CURRENT_DIR`] is `/home/johndoe/mm0`, and `buf` is
+/// `/home/johndoe/Documents/ahoy.mm1` will return `../Documents/ahoy.mm1`
+///
+/// [`CURRENT_DIR`]: struct@CURRENT_DIR
+fn make_relative(buf: &PathBuf) -> String {
+  pathdiff::diff_paths(buf, &*CURRENT_DIR).as_ref().unwrap_or(buf)
+    .to_str().expect("bad unicode in file path").to_owned()
+}
+
+struct FileRefInner {
+  path: PathBuf,
+  rel: String,
+  #[cfg(feature = "server")]
+  url: lsp_types::Url
+}
+
+/// A reference to a file. It wraps an [`Arc`] so it can be cloned thread-safely.
+/// A [`FileRef`] can be constructed either from a [`PathBuf`] or a
+/// (`file://`) [`Url`](lsp_types::Url),
+/// and provides (precomputed) access to these views using
+/// [`path()`](FileRef::path) and [`url()`](FileRef::url), as well as
+/// [`rel()`](FileRef::rel) to get the relative path from [`struct@CURRENT_DIR`].
+#[derive(Clone)]
+pub struct FileRef(Arc<FileRefInner>);
+
+impl From<PathBuf> for FileRef {
+  fn from(path: PathBuf) -> FileRef {
+    FileRef(Arc::new(FileRefInner {
+      rel: make_relative(&path),
+      #[cfg(feature = "server")]
+      url: lsp_types::Url::from_file_path(&path).expect("bad file path"),
+      path,
+    }))
+  }
+}
+
+#[cfg(feature = "server")]
+impl From<lsp_types::Url> for FileRef {
+  fn from(url: lsp_types::Url) -> FileRef {
+    match url.to_file_path() {
+      Ok(path) => {
+        let rel = make_relative(&path);
+        FileRef(Arc::new(FileRefInner {path, rel, url}))
+      }
+      // Documents that are not files on disk, like unsaved editor buffers
+      // (`untitled:Untitled-1`), are identified by their URL. The path is only
+      // used for display and for resolving relative imports.
+      Err(()) => FileRef(Arc::new(FileRefInner {
+        path: PathBuf::from(url.path()),
+        rel: url.to_string(),
+        url,
+      }))
+    }
+  }
+}
+
+impl FileRef {
+  /// Convert this [`FileRef`] to a [`PathBuf`], for use with OS file actions.
+  #[must_use] pub fn path(&self) -> &PathBuf { &self.0.path }
+  /// Convert this [`FileRef`] to a relative path (as a `&str`).
+  #[must_use] pub fn rel(&self) -> &str { &self.0.rel }
+  /// Convert this [`FileRef`] to a `file:://` URL, for use with LSP.
+  #[cfg(feature = "server")]
+  #[must_use] pub fn url(&self) -> &lsp_types::Url { &self.0.url }
+  /// Returns true if this is a `file://` URL, so that its contents can be read from disk.
+  /// Other documents, like unsaved editor buffers, only exist in the language server.
+  #[cfg(feature = "server")]
+  #[must_use] pub fn is_on_disk(&self) -> bool { self.0.url.scheme() == "file" }
+  /// Get a pointer to this allocation, for use in hashing.
+  #[must_use] pub fn ptr(&self) -> *const PathBuf { self.path() }
+  /// Compare this with `other` for pointer equality.
+  #[must_use] pub fn ptr_eq(&self, other: &FileRef) -> bool { Arc::ptr_eq(&self.0, &other.0) }
+
+  /// Returns true if this file has the provided extension.
+  #[must_use] pub fn has_extension(&self, ext: &str) -> bool {
+    self.path().extension().map_or(false, |s| s == ext)
+  }
+}
+impl PartialEq for FileRef {
+  fn eq(&self, other: &Self) -> bool { self.0.rel == other.0.rel }
+}
+impl Eq for FileRef {}
+
+impl Hash for FileRef {
+  fn hash<H: Hasher>(&self, state: &mut H) { self.0.rel.hash(state) }
+}
+
+impl fmt::Display for FileRef {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    self.0.path.file_name().unwrap_or_else(|| self.0.path.as_os_str())
+      .to_str().expect("bad unicode in path").fmt(f)
+  }
+}
+
+impl fmt::Debug for FileRef {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    fmt::Display::fmt(self, f)
+  }
+}
+
+/// A span paired with a [`FileRef`].
+#[derive(Clone, PartialEq, Eq)]
+pub struct FileSpan {
+  /// The file in which this span occured.
+  pub file: FileRef,
+  /// The span (as byte indexes into the file source text).
+  pub span: Span,
+}
+
+impl fmt::Debug for FileSpan {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}:{:?}", self.file, self.span)
+  }
+}
@@ -0,0 +1,121 @@
+//! The on-disk compilation cache.
+//!
+//! When a cache directory is set (using `--cache DIR` in `mm0-rs compile` and
+//! `mm0-rs server`), the environment produced by elaborating an imported file is saved
+//! in the directory, in the format of [`elab::serialize`](crate::elab::serialize), and
+//! later runs that import the same file load it from there instead of elaborating it
+//! again.
+//!
+//! Entries are keyed by a [`Sha256`] hash of the path and contents of the file and the
+//! keys of its imports, so that an entry is not used once the file or anything it
+//! transitively imports has changed, together with the version of `mm0-rs` and the
+//! options that change the result of elaboration (`--no-proofs` and `--test`). Options that
+//! only change how the file is elaborated, like `--jobs`, or what is reported, like
+//! `--backtrace` and `--profile-lisp`, are not part of the key, since the saved environment
+//! does not depend on them. Only files that elaborate without errors are saved. Entries are never deleted,
+//! so the directory can grow over time, but it is safe to delete it at any time.
+//!
+//! The cache is trusted: the proofs in an entry are not checked again when it is loaded,
+//! so anyone who can write to the directory can make `mm0-rs` accept an unproved
+//! theorem. The `--kernel-check` option of `mm0-rs compile` checks all the proofs in the
+//! output, including the ones loaded from the cache.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::{fs, io};
+use crate::compiler::FileContents;
+use crate::elab::{FrozenEnv, serialize};
+use crate::parser::{AST, parse};
+use crate::util::{FileRef, MutexExt, Sha256};
+
+lazy_static! {
+  /// The cache directory, or `None` if the cache is disabled.
+  static ref CACHE_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+/// The key of a cache entry.
+pub(crate) type Key = [u8; 32];
+
+/// Enable the cache, storing the entries in directory `dir`.
+pub fn set_dir(dir: PathBuf) { *CACHE_DIR.ulock() = Some(dir) }
+
+/// Returns true if the cache is enabled.
+pub fn enabled() -> bool { CACHE_DIR.ulock().is_some() }
+
+/// The path of the cache entry with the given key.
+fn entry(key: &Key) -> Option<PathBuf> {
+  let mut name = String::with_capacity(2 * key.len() + 5);
+  for b in key { name.push_str(&format!("{:02x}", b)) }
+  name.push_str(".mm1c");
+  Some(CACHE_DIR.ulock().as_ref()?.join(name))
+}
+
+/// Resolve the import `f` in the file `path`, in the same way as the elaborator
+/// (see [`project::resolve_import`](crate::project::resolve_import)).
+/// Returns `None` if it can't be resolved.
+pub(crate) fn resolve_import(path: &FileRef, f: &[u8]) -> Option<FileRef> {
+  let f = std::str::from_utf8(f).ok()?;
+  Some(crate::project::resolve_import(path.path(), f).ok()?.into())
+}
+
+/// Resolve the imports of the file `path` with syntax tree `ast`, in the same way as the
+/// elaborator. Returns `None` if one of them can't be resolved.
+pub(crate) fn imports(path: &FileRef, ast: &AST) -> Option<Vec<FileRef>> {
+  ast.imports.iter().map(|(_, f)| resolve_import(path, f)).collect()
+}
+
+/// Compute the cache key of the file `path`, using `load` to read the file and its
+/// imports. The keys that have been computed are stored in `memo`, which should only be
+/// reused as long as the files don't change. Returns `None` if a file can't be read, an
+/// import can't be resolved, or there is an import cycle.
+pub(crate) fn key(path: &FileRef, memo: &mut HashMap<FileRef, Option<Key>>,
+  load: &mut impl FnMut(&FileRef) -> io::Result<FileContents>
+) -> Option<Key> {
+  fn bytes(hasher: &mut Sha256, s: &[u8]) {
+    hasher.update(&(s.len() as u64).to_le_bytes());
+    hasher.update(s)
+  }
+  if let Some(&k) = memo.get(path) { return k }
+  memo.insert(path.clone(), None);
+  let text = load(path).ok()?;
+  let mut hasher = Sha256::default();
+  bytes(&mut hasher, env!("CARGO_PKG_VERSION").as_bytes());
+  hasher.update(&serialize::VERSION.to_le_bytes());
+  hasher.update(&[crate::get_check_proofs().into(), crate::get_test_mode().into()]);
+  bytes(&mut hasher, path.path().to_string_lossy().as_bytes());
+  bytes(&mut hasher, &text[..]);
+  if let Some(src) = text.try_ascii().filter(|_| !path.has_extension("mmu")) {
+    let (_, ast) = parse(src.clone(), None);
+    for f in imports(path, &ast)? { hasher.update(&key(&f, memo, load)?) }
+  }
+  let k = hasher.finish();
+  memo.insert(path.clone(), Some(k));
+  Some(k)
+}
+
+/// Load the cache entry with the given key, if there is one. The function `file` converts
+/// the paths of the files mentioned in the environment to [`FileRef`]s.
+pub(crate) fn load(key: Key,
+  file: &mut dyn FnMut(PathBuf) -> io::Result<FileRef>
+) -> io::Result<Option<FrozenEnv>> {
+  let path = if let Some(path) = entry(&key) {path} else {return Ok(None)};
+  let data = match fs::read(&path) {
+    Ok(data) => data,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+    Err(e) => return Err(e)
+  };
+  Ok(Some(FrozenEnv::new(serialize::read_env(&data, file)?)))
+}
+
+/// Save `env` as the cache entry with the given key. The entry is written to a temporary
+/// file which is then renamed, so that a concurrent [`load`] never sees a partial entry.
+pub(crate) fn store(key: Key, env: &FrozenEnv) -> io::Result<()> {
+  let path = if let Some(path) = entry(&key) {path} else {return Ok(())};
+  if let Some(dir) = path.parent() { fs::create_dir_all(dir)? }
+  let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+  let res = fs::File::create(&tmp)
+    .and_then(|f| serialize::write_env(env, io::BufWriter::new(f)))
+    .and_then(|()| fs::rename(&tmp, &path));
+  if res.is_err() { let _ = fs::remove_file(&tmp); }
+  res
+}
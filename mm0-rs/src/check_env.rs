@@ -0,0 +1,286 @@
+//! Consistency checking of elaborated environments.
+//!
+//!     mm0-rs check-env a.mm1 b.mmb ...
+//!
+//! will elaborate (or import) each file and re-validate the internal invariants of the
+//! resulting environment: every atom, sort, term and theorem index is in range, the atom
+//! table agrees with the declarations that refer to it, binder dependencies only mention
+//! earlier bound variables, expressions apply terms to the right number of arguments and
+//! only reference earlier heap elements, and proofs only reference earlier theorems and
+//! existing hypotheses.
+//!
+//! None of these can fail for a correct elaborator or importer, so this is a debugging
+//! aid: run it after changing the importers or exporters, or when a cached environment
+//! is suspected to be corrupt. Every violation is reported, and the command fails if
+//! there are any.
+use std::io;
+use clap::ArgMatches;
+use crate::elab::environment::{Environment, AtomID, SortID, TermID, ThmID, DeclKey,
+  Type, ExprNode, ProofNode, TermKind, ThmKind, StmtTrace};
+
+/// Checks the invariants of an environment, collecting the violations.
+struct Checker<'a> {
+  env: &'a Environment,
+  /// The violations found so far.
+  errors: Vec<String>,
+}
+
+/// The context for checking an expression or proof: the name of the declaration
+/// (for error messages), the number of variables and the size of the heap.
+#[derive(Clone, Copy)]
+struct Ctx<'a> {
+  name: &'a str,
+  nargs: usize,
+  heap: usize,
+}
+
+impl<'a> Checker<'a> {
+  fn err(&mut self, msg: String) { self.errors.push(msg) }
+
+  fn atom(&mut self, what: &str, a: AtomID) -> bool {
+    let ok = (a.0 as usize) < self.env.data.len();
+    if !ok { self.err(format!("{}: atom {:?} out of range", what, a)) }
+    ok
+  }
+
+  fn sort(&mut self, what: &str, s: SortID) {
+    if self.env.sorts.get(s).is_none() { self.err(format!("{}: sort {:?} out of range", what, s)) }
+  }
+
+  /// Check a term application, where `bound` is the largest permitted term ID (exclusive)
+  /// and `n` is the number of arguments.
+  fn term(&mut self, cx: Ctx<'_>, bound: TermID, t: TermID, n: usize) -> Option<&'a TermKind> {
+    if t >= bound {
+      self.err(format!("{}: reference to undeclared term {:?}", cx.name, t));
+      return None
+    }
+    let env = self.env;
+    let td = &env.terms[t];
+    if td.args.len() != n {
+      self.err(format!("{}: term {} expects {} arguments, got {}",
+        cx.name, env.data[td.atom].name, td.args.len(), n))
+    }
+    Some(&td.kind)
+  }
+
+  fn binders(&mut self, name: &str, args: &[(Option<AtomID>, Type)]) -> usize {
+    let mut nbound = 0;
+    for (i, &(a, ty)) in args.iter().enumerate() {
+      if let Some(a) = a { self.atom(name, a); }
+      self.sort(name, ty.sort());
+      match ty {
+        Type::Bound(_) => nbound += 1,
+        Type::Reg(_, deps) => if !Self::deps(nbound, deps) {
+          self.err(format!("{}: binder {} depends on a later bound variable", name, i))
+        }
+      }
+    }
+    nbound
+  }
+
+  /// Returns true if `deps` only refers to the first `nbound` bound variables.
+  fn deps(nbound: usize, deps: u64) -> bool { nbound >= 64 || deps >> nbound == 0 }
+
+  fn heap<T>(&mut self, cx: Ctx<'_>, heap: &[T], mut f: impl FnMut(&mut Self, Ctx<'_>, &T)) {
+    if heap.len() < cx.nargs {
+      self.err(format!("{}: heap has {} elements, but there are {} variables",
+        cx.name, heap.len(), cx.nargs))
+    }
+    for (i, e) in heap.iter().enumerate().skip(cx.nargs) {
+      f(self, Ctx {heap: i, ..cx}, e)
+    }
+  }
+
+  fn heap_ref(&mut self, cx: Ctx<'_>, i: usize) {
+    if i >= cx.heap { self.err(format!("{}: heap reference {} out of range", cx.name, i)) }
+  }
+
+  fn expr(&mut self, cx: Ctx<'_>, terms: TermID, e: &ExprNode) {
+    match *e {
+      ExprNode::Ref(i) => self.heap_ref(cx, i),
+      ExprNode::Dummy(a, s) => { self.atom(cx.name, a); self.sort(cx.name, s) }
+      ExprNode::App(t, ref es) => {
+        self.term(cx, terms, t, es.len());
+        for e in &**es { self.expr(cx, terms, e) }
+      }
+    }
+  }
+
+  /// Check a proof node, where `thms` is the largest permitted theorem ID (exclusive)
+  /// and `nhyps` is the number of hypotheses.
+  #[allow(clippy::cast_possible_truncation)]
+  fn proof(&mut self, cx: Ctx<'_>, thms: ThmID, nhyps: usize, p: &ProofNode) {
+    let terms = TermID(self.env.terms.len() as u32);
+    match p {
+      &ProofNode::Ref(i) => self.heap_ref(cx, i),
+      &ProofNode::Dummy(a, s) => { self.atom(cx.name, a); self.sort(cx.name, s) }
+      ProofNode::Term {term, args} | ProofNode::Cong {term, args} => {
+        self.term(cx, terms, *term, args.len());
+        for e in &**args { self.proof(cx, thms, nhyps, e) }
+      }
+      ProofNode::Hyp(i, e) => {
+        if *i >= nhyps { self.err(format!("{}: hypothesis {} out of range", cx.name, i)) }
+        self.proof(cx, thms, nhyps, e)
+      }
+      ProofNode::Refl(e) | ProofNode::Sym(e) => self.proof(cx, thms, nhyps, e),
+      ProofNode::Thm {thm, args, res} => {
+        if *thm >= thms {
+          self.err(format!("{}: reference to undeclared theorem {:?}", cx.name, thm))
+        } else {
+          let env = self.env;
+          let td = &env.thms[*thm];
+          if td.args.len() + td.hyps.len() != args.len() {
+            self.err(format!("{}: theorem {} expects {} arguments, got {}", cx.name,
+              env.data[td.atom].name, td.args.len() + td.hyps.len(), args.len()))
+          }
+        }
+        for e in &**args { self.proof(cx, thms, nhyps, e) }
+        self.proof(cx, thms, nhyps, res)
+      }
+      ProofNode::Conv(p) => {
+        self.proof(cx, thms, nhyps, &p.0);
+        self.proof(cx, thms, nhyps, &p.1);
+        self.proof(cx, thms, nhyps, &p.2)
+      }
+      ProofNode::Unfold {term, args, res} => {
+        if let Some(TermKind::Term) = self.term(cx, terms, *term, args.len()) {
+          let env = self.env;
+          self.err(format!("{}: unfolding {}, which is not a definition",
+            cx.name, env.data[env.terms[*term].atom].name))
+        }
+        for e in &**args { self.proof(cx, thms, nhyps, e) }
+        self.proof(cx, thms, nhyps, &res.0);
+        self.proof(cx, thms, nhyps, &res.1);
+        self.proof(cx, thms, nhyps, &res.2)
+      }
+    }
+  }
+
+  #[allow(clippy::cast_possible_truncation)]
+  fn run(&mut self) {
+    let env = self.env;
+    for (i, ad) in env.data.iter().enumerate() {
+      match env.atoms.get(&ad.name) {
+        Some(&a) if a.0 as usize == i => {}
+        _ => self.err(format!("atom {}: not in the atom table", ad.name)),
+      }
+      if let Some(s) = ad.sort {
+        match env.sorts.get(s) {
+          Some(sd) if sd.atom.0 as usize == i => {}
+          _ => self.err(format!("atom {}: points to the wrong sort", ad.name)),
+        }
+      }
+      match ad.decl {
+        None => {}
+        Some(DeclKey::Term(t)) => match env.terms.get(t) {
+          Some(td) if td.atom.0 as usize == i => {}
+          _ => self.err(format!("atom {}: points to the wrong term", ad.name)),
+        }
+        Some(DeclKey::Thm(t)) => match env.thms.get(t) {
+          Some(td) if td.atom.0 as usize == i => {}
+          _ => self.err(format!("atom {}: points to the wrong theorem", ad.name)),
+        }
+      }
+    }
+    for (name, &a) in &env.atoms {
+      if env.data.get(a).map_or(true, |ad| ad.name != *name) {
+        self.err(format!("atom table: {} maps to the wrong atom", name))
+      }
+    }
+    for (i, sd) in env.sorts.iter().enumerate() {
+      let name = format!("sort {}", sd.name);
+      if !self.atom(&name, sd.atom) { continue }
+      let ad = &env.data[sd.atom];
+      if ad.name != sd.name || ad.sort != Some(SortID(i as u8)) {
+        self.err(format!("{}: atom does not point back to the sort", name))
+      }
+    }
+    for (i, td) in env.terms.iter().enumerate() {
+      let id = TermID(i as u32);
+      let name = format!("term {:?}", id);
+      if !self.atom(&name, td.atom) { continue }
+      let name = format!("term {}", env.data[td.atom].name);
+      if !matches!(env.data[td.atom].decl, Some(DeclKey::Term(t)) if t == id) {
+        self.err(format!("{}: atom does not point back to the term", name))
+      }
+      let nbound = self.binders(&name, &td.args);
+      self.sort(&name, td.ret.0);
+      if !Self::deps(nbound, td.ret.1) {
+        self.err(format!("{}: return type depends on a nonexistent bound variable", name))
+      }
+      if let TermKind::Def(Some(e)) = &td.kind {
+        let cx = Ctx {name: &name, nargs: td.args.len(), heap: e.heap.len()};
+        self.heap(cx, &e.heap, |this, cx, e| this.expr(cx, id, e));
+        self.expr(cx, id, &e.head)
+      }
+    }
+    let terms = TermID(env.terms.len() as u32);
+    for (i, td) in env.thms.iter().enumerate() {
+      let id = ThmID(i as u32);
+      let name = format!("theorem {:?}", id);
+      if !self.atom(&name, td.atom) { continue }
+      let name = format!("theorem {}", env.data[td.atom].name);
+      if !matches!(env.data[td.atom].decl, Some(DeclKey::Thm(t)) if t == id) {
+        self.err(format!("{}: atom does not point back to the theorem", name))
+      }
+      self.binders(&name, &td.args);
+      let cx = Ctx {name: &name, nargs: td.args.len(), heap: td.heap.len()};
+      self.heap(cx, &td.heap, |this, cx, e| this.expr(cx, terms, e));
+      for (a, e) in &*td.hyps {
+        if let Some(a) = *a { self.atom(&name, a); }
+        self.expr(cx, terms, e)
+      }
+      self.expr(cx, terms, &td.ret);
+      if let ThmKind::Thm(Some(pf)) = &td.kind {
+        let nhyps = td.hyps.len();
+        if pf.hyps.len() != nhyps {
+          self.err(format!("{}: proof has {} hypotheses, expected {}", name, pf.hyps.len(), nhyps))
+        }
+        let cx = Ctx {heap: pf.heap.len(), ..cx};
+        self.heap(cx, &pf.heap, |this, cx, p| this.proof(cx, id, nhyps, p));
+        for p in pf.hyps.iter().chain(Some(&pf.head)) { self.proof(cx, id, nhyps, p) }
+      }
+    }
+    for s in &env.stmts {
+      match *s {
+        StmtTrace::Sort(a) => if self.atom("statement", a) && env.data[a].sort.is_none() {
+          self.err(format!("statement: {} is not a sort", env.data[a].name))
+        }
+        StmtTrace::Decl(a) => if self.atom("statement", a) && env.data[a].decl.is_none() {
+          self.err(format!("statement: {} is not a declaration", env.data[a].name))
+        }
+        StmtTrace::Global(a) => { self.atom("statement", a); }
+        StmtTrace::OutputString(ref os) => {
+          let cx = Ctx {name: "output string", nargs: 0, heap: os.heap.len()};
+          self.heap(cx, &os.heap, |this, cx, e| this.expr(cx, terms, e));
+          for e in &*os.exprs { self.expr(cx, terms, e) }
+        }
+      }
+    }
+  }
+}
+
+/// Main entry point for `mm0-rs check-env` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs check-env <in.mm1>...`, where each `in.mm1` is an MM1, MM0, MMB or MMU file
+/// to check. The violations are printed to stderr, and the process exits with a nonzero
+/// status if there are any.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let mut ok = true;
+  for path in args.values_of("INPUT").expect("required arg") {
+    let (_, _, env) = crate::compiler::elab_file(path)?;
+    // Safety: the environment is not modified or cloned while we hold this reference
+    let mut ch = Checker {env: unsafe { env.thaw() }, errors: vec![]};
+    ch.run();
+    for e in &ch.errors { eprintln!("{}: {}", path, e) }
+    ok &= ch.errors.is_empty();
+  }
+  if ok {
+    println!("all environments are consistent");
+  } else {
+    std::process::exit(1)
+  }
+  Ok(())
+}
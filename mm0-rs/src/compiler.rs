@@ -8,9 +8,10 @@
 //!
 //! [`mm0_rs::server`]: crate::server
 //! [`mm0-c`]: https://github.com/digama0/mm0/tree/master/mm0-c
-use std::sync::{Arc, Mutex};
-use std::collections::{HashMap, hash_map::Entry};
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}};
+use std::collections::{HashMap, HashSet, hash_map::Entry};
 use std::{io, fs};
+use std::time::{Duration, Instant, SystemTime};
 use futures::{FutureExt, future::BoxFuture};
 use futures::channel::oneshot::{Sender as FSender, channel};
 use futures::executor::{ThreadPool, block_on};
@@ -20,7 +21,9 @@ use annotate_snippets::{
   display_list::{DisplayList, FormatOptions}};
 use typed_arena::Arena;
 use clap::ArgMatches;
-use crate::elab::{self, ElabError, ElabErrorKind, ElabResult, FrozenEnv};
+use serde_json::json;
+use crate::elab::{self, ElabError, ElabErrorKind, ElabResult, FrozenEnv,
+  environment::{ThmID, ThmKind}, lisp::output::{self, OutputChannel, WriteChannel}};
 use crate::parser::{parse, ParseError, ErrorLevel};
 use crate::lined_string::LinedString;
 use crate::mmb::import::elab as mmb_elab;
@@ -34,6 +37,55 @@ lazy_static! {
   /// The virtual file system of files that have been included via
   /// transitive imports, protected for concurrent access by a mutex.
   static ref VFS_: VFS = VFS(Mutex::new(HashMap::new()));
+  /// The keys of the files in the [compilation cache](crate::cache), which are
+  /// computed once per run since the files don't change.
+  static ref KEYS: Mutex<HashMap<FileRef, Option<crate::cache::Key>>> = Mutex::new(HashMap::new());
+  /// The diagnostics of the files that have been elaborated, which are printed
+  /// by [`print_reports`].
+  static ref REPORTS: Mutex<HashMap<FileRef, Report>> = Mutex::new(HashMap::new());
+}
+
+/// Print diagnostics as JSON lines instead of snippets (`--error-format json`).
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// Don't print anything while elaborating (see [`set_quiet`]).
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Don't print progress messages or diagnostics while elaborating, for subcommands that
+/// only elaborate files to analyze them, and may be printing their results to stdout.
+pub(crate) fn set_quiet(quiet: bool) { QUIET.store(quiet, Ordering::Relaxed) }
+
+/// The diagnostics of an elaborated file, rendered for printing.
+struct Report {
+  /// The files imported by this file.
+  deps: Vec<FileRef>,
+  /// The diagnostics, sorted by position.
+  diags: Vec<String>,
+}
+
+/// Print a progress message. With `--error-format json` these are not printed,
+/// so that the diagnostics are the only thing printed to stdout.
+fn progress(msg: std::fmt::Arguments<'_>) {
+  if !JSON_ERRORS.load(Ordering::Relaxed) && !QUIET.load(Ordering::Relaxed) { println!("{}", msg) }
+}
+
+/// Print the diagnostics of the files elaborated so far. Files are elaborated in parallel,
+/// so to get the same output on every run, the diagnostics are collected and printed at the
+/// end: a file is printed after the files it imports, starting from `roots`, and the
+/// diagnostics in a file are sorted by position.
+fn print_reports(roots: &[FileRef]) {
+  fn go(file: &FileRef, reports: &mut HashMap<FileRef, Report>) {
+    if let Some(r) = reports.remove(file) {
+      for dep in &r.deps { go(dep, reports) }
+      for d in r.diags { print!("{}", d) }
+    }
+  }
+  let mut reports = std::mem::take(&mut *REPORTS.ulock());
+  if QUIET.load(Ordering::Relaxed) { return }
+  for file in roots { go(file, &mut reports) }
+  let mut rest = reports.into_iter().collect::<Vec<_>>();
+  rest.sort_by(|a, b| a.0.rel().cmp(b.0.rel()));
+  for (_, r) in rest { for d in r.diags { print!("{}", d) } }
 }
 
 /// The cached [`Environment`](crate::elab::Environment) representing a
@@ -283,6 +335,53 @@ impl ElabError {
   }
 }
 
+/// The JSON representation of a location in a file: the byte offsets `span`, and the
+/// line and column `range` (which is `null` for binary files).
+fn json_loc(file: &FileRef, span: Span, range: Option<Range>) -> serde_json::Value {
+  json!({
+    "file": file.rel(),
+    "span": {"start": span.start, "end": span.end},
+    "range": range.map(|Range {start, end}| json!({
+      "start": {"line": start.line, "character": start.character},
+      "end": {"line": end.line, "character": end.character},
+    })),
+  })
+}
+
+/// Render the error `e` in file `path` with contents `text` for printing, either as a
+/// snippet, or as a line of JSON with `--error-format json`. The `code` is the kind of
+/// error: `parse`, `elab`, `upstream` (for errors in imported files) or `output` (for
+/// errors running `output string`).
+fn render_error(path: &FileRef, text: &FileContents, e: &ElabError, code: &str,
+    mut to_range: impl FnMut(&FileSpan) -> Option<Range>) -> String {
+  if JSON_ERRORS.load(Ordering::Relaxed) {
+    let mut related = |fsp: &FileSpan, msg: String| {
+      let mut loc = json_loc(&fsp.file, fsp.span, to_range(fsp));
+      loc["message"] = msg.into();
+      loc
+    };
+    let related = match &e.kind {
+      ElabErrorKind::Boxed(_, Some(info)) =>
+        info.iter().map(|(fsp, msg)| related(fsp, msg.to_string())).collect(),
+      ElabErrorKind::Upstream(file, errs, _) => {
+        let fsp = FileSpan {file: file.clone(), span: errs[0].pos};
+        vec![related(&fsp, errs[0].kind.raw_msg())]
+      }
+      ElabErrorKind::Boxed(_, None) => vec![],
+    };
+    let mut v = json_loc(path, e.pos, text.try_ascii().map(|src| src.to_range(e.pos)));
+    v["severity"] = e.level.to_string().into();
+    v["code"] = code.into();
+    v["message"] = e.kind.msg().into();
+    v["related"] = serde_json::Value::Array(related);
+    format!("{}\n", v)
+  } else if let FileContents::Ascii(src) = text {
+    e.to_snippet(path, src, to_range, |s| format!("{}\n\n", DisplayList::from(s)))
+  } else {
+    e.to_snippet_no_source(path, e.pos, |s| format!("{}\n\n", DisplayList::from(s)))
+  }
+}
+
 impl ParseError {
   /// Create a [`Snippet`] from this error. See [`ElabError::to_snippet`] for information
   /// about the parameters.
@@ -326,55 +425,61 @@ async fn elaborate(path: FileRef, rd: ArcList<FileRef>) -> io::Result<ElabResult
     }
   }
   let text = file.text.clone();
-  let (cyc, errors, env) = if path.has_extension("mmb") {
+  let (cyc, parse_errors, errors, env, deps) = if path.has_extension("mmb") {
     let (error, env) = mmb_elab(&path, &text);
-    (None, if let Err(e) = error {vec![e]} else {vec![]}, FrozenEnv::new(env))
+    (None, vec![], if let Err(e) = error {vec![e]} else {vec![]}, FrozenEnv::new(env), vec![])
   } else if path.has_extension("mmu") {
     let (error, env) = mmu_elab(&path, &text);
-    (None, if let Err(e) = error {vec![e]} else {vec![]}, FrozenEnv::new(env))
+    (None, vec![], if let Err(e) = error {vec![e]} else {vec![]}, FrozenEnv::new(env), vec![])
   } else {
-    let (_, ast) = parse(text.ascii().clone(), None);
-    if !ast.errors.is_empty() {
-      for e in &ast.errors {
-        e.to_snippet(&path, &ast.source,
-          |s| println!("{}", DisplayList::from(s).to_string()))
-      }
-    }
-    let ast = Arc::new(ast);
-    let mut deps = Vec::new();
-    println!("elab {}, memory = {}M", path, get_memory_usage() >> 20);
-    let rd = rd.push(path.clone());
-    let (cyc, _, errors, env) = elab::elaborate(
-      &ast, path.clone(), path.has_extension("mm0"),
-      crate::get_check_proofs(), false,
-      Arc::default(),
-      None,
-      |p| {
-        let p = VFS_.get_or_insert(p)?.0;
-        let (send, recv) = channel();
-        if rd.contains(&p) {
-          send.send(ElabResult::ImportCycle(rd.clone())).expect("failed to send");
-        } else {
-          POOL.spawn_ok(elaborate_and_send(p.clone(), send, rd.clone()));
-          deps.push(p);
-        }
-        Ok(recv)
-      }).await;
-    (cyc, errors, env)
-  };
-  println!("elabbed {}, memory = {}M", path, get_memory_usage() >> 20);
-  let errors: Option<Arc<[_]>> = if errors.is_empty() { None } else {
-    fn print(s: Snippet<'_>) { println!("{}\n", DisplayList::from(s).to_string()) }
-    let mut to_range = mk_to_range();
-    if let FileContents::Ascii(text) = &file.text {
-      for e in &errors { e.to_snippet(&path, text, &mut to_range, print) }
+    // Imported files are looked up in the cache, if it is enabled
+    let key = if rd.is_empty() || !crate::cache::enabled() { None } else {
+      crate::cache::key(&path, &mut KEYS.ulock(),
+        &mut |p| Ok(VFS_.get_or_insert(p.clone())?.1.text.clone()))
+    };
+    let cached = key.and_then(|key| {
+      crate::cache::load(key, &mut |p| Ok(VFS_.get_or_insert(p.into())?.0))
+        .unwrap_or_else(|e| { progress(format_args!("failed to load {} from cache: {}", path, e)); None })
+    });
+    if let Some(env) = cached {
+      progress(format_args!("loaded {} from cache", path));
+      (None, vec![], vec![], env, vec![])
     } else {
-      for e in &errors { e.to_snippet_no_source(&path, e.pos, print) }
+      let (_, ast) = parse(text.ascii().clone(), None);
+      let ast = Arc::new(ast);
+      let mut deps = Vec::new();
+      progress(format_args!("elab {}, memory = {}M", path, get_memory_usage() >> 20));
+      let rd = rd.push(path.clone());
+      let (cyc, _, errors, env) = elab::elaborate(
+        &ast, path.clone(), path.has_extension("mm0"),
+        crate::get_check_proofs(), false,
+        Arc::default(),
+        false, None,
+        |p| {
+          let p = VFS_.get_or_insert(p)?.0;
+          let (send, recv) = channel();
+          if rd.contains(&p) {
+            send.send(ElabResult::ImportCycle(rd.clone())).expect("failed to send");
+          } else {
+            POOL.spawn_ok(elaborate_and_send(p.clone(), send, rd.clone()));
+            deps.push(p);
+          }
+          Ok(recv)
+        }).await;
+      if let Some(key) = key {
+        if cyc.is_none() && ast.errors.is_empty() && errors.is_empty() {
+          if let Err(e) = crate::cache::store(key, &env) {
+            progress(format_args!("failed to save {} to cache: {}", path, e))
+          }
+        }
+      }
+      (cyc, ast.errors.clone(), errors, env, deps)
     }
-    Some(errors.into())
   };
+  progress(format_args!("elabbed {}, memory = {}M", path, get_memory_usage() >> 20));
+  let errors: Option<Arc<[_]>> = if errors.is_empty() { None } else { Some(errors.into()) };
   let res = match cyc {
-    None => ElabResult::Ok((), errors, env.clone()),
+    None => ElabResult::Ok((), errors.clone(), env.clone()),
     Some(cyc) => ElabResult::ImportCycle(cyc),
   };
   {
@@ -386,6 +491,19 @@ async fn elaborate(path: FileRef, rd: ArcList<FileRef>) -> io::Result<ElabResult
     }
     *g = Some(FileCache::Ready(env));
   }
+  // `to_range` is not `Send`, so it is created after the last `await`
+  let mut to_range = mk_to_range();
+  let mut diags = vec![];
+  for e in parse_errors {
+    diags.push((e.pos, render_error(&path, &text, &e.into(), "parse", &mut to_range)))
+  }
+  for e in errors.iter().flat_map(|es| es.iter()) {
+    let code = if let ElabErrorKind::Upstream(..) = e.kind {"upstream"} else {"elab"};
+    diags.push((e.pos, render_error(&path, &text, e, code, &mut to_range)))
+  }
+  diags.sort_by_key(|d| (d.0.start, d.0.end));
+  REPORTS.ulock().insert(path.clone(),
+    Report {deps, diags: diags.into_iter().map(|d| d.1).collect()});
   Ok(res)
 }
 
@@ -404,6 +522,92 @@ fn elaborate_and_send(path: FileRef, send: FSender<ElabResult<()>>, rd: ArcList<
   }.boxed()
 }
 
+/// Elaborate the file at `path`, for use by subcommands that need a completed environment.
+/// Errors are reported to stdout as usual, and the process exits if the file could not be
+/// elaborated at all. Returns the canonicalized path, the file contents, and the environment.
+pub(crate) fn elab_file(path: &str) -> io::Result<(FileRef, FileContents, FrozenEnv)> {
+  match try_elab_file(path)? {
+    Some(res) => Ok(res),
+    None => std::process::exit(1)
+  }
+}
+
+/// Like [`elab_file`], but returns `None` instead of exiting if the file could not be
+/// elaborated at all.
+fn try_elab_file(path: &str) -> io::Result<Option<(FileRef, FileContents, FrozenEnv)>> {
+  let (path, file) = VFS_.get_or_insert(fs::canonicalize(path)?.into())?;
+  let res = block_on(elaborate(path.clone(), Default::default()));
+  print_reports(&[path.clone()]);
+  Ok(match res? {
+    ElabResult::Ok(_, _, env) => Some((path, file.text.clone(), env)),
+    _ => None
+  })
+}
+
+/// Evaluate the lisp expression `expr` in the environment of the file at `path`, for use by
+/// `mm0-rs eval`. The expression is evaluated as if it were in a `do` block at the end of the
+/// file. Errors (and `display` output) are reported to stdout as usual. Returns the printed
+/// value of the expression (or `None` if the evaluation failed), and `true` if there were no
+/// errors, either in the elaboration of the file or in the evaluation. If `state` is provided,
+/// the proof state saved in it is restored first (see [`elab::eval_do`]).
+pub(crate) fn eval_in_file(path: &str, expr: &str,
+  state: Option<&serde_json::Value>
+) -> io::Result<(Option<String>, bool)> {
+  let (fref, _) = VFS_.get_or_insert(fs::canonicalize(path)?.into())?;
+  let res = block_on(elaborate(fref.clone(), Default::default()));
+  print_reports(&[fref.clone()]);
+  let (file_ok, env) = match res? {
+    ElabResult::Ok(_, errors, env) => (!errors.map_or(false, |errors|
+      errors.iter().any(|e| matches!(e.level, ErrorLevel::Error))), env),
+    _ => std::process::exit(1)
+  };
+  let eval_path = FileRef::from(fref.path().with_file_name("<eval>"));
+  let text = FileContents::new(format!("do {{ {} }};", expr));
+  VFS_.0.ulock().insert(eval_path.clone(), Arc::new(VirtualFile::new(text.clone())));
+  let (_, ast) = parse(text.ascii().clone(), None);
+  for e in &ast.errors {
+    e.to_snippet(&eval_path, &ast.source, |s| println!("{}", DisplayList::from(s).to_string()))
+  }
+  let parse_ok = !ast.errors.iter().any(|e| matches!(e.level, ErrorLevel::Error));
+  let (res, errors) = elab::eval_do(&Arc::new(ast), eval_path.clone(), &env, state);
+  let mut to_range = mk_to_range();
+  for e in &errors {
+    e.to_snippet(&eval_path, text.ascii(), &mut to_range,
+      |s| println!("{}\n", DisplayList::from(s).to_string()))
+  }
+  let ok = file_ok && parse_ok && !errors.iter().any(|e| matches!(e.level, ErrorLevel::Error));
+  Ok((res, ok))
+}
+
+/// Elaborate all the files in `paths` in parallel on the thread pool [`struct@POOL`], for use by
+/// subcommands that check many files at once. Imports shared between the files are only
+/// elaborated once. Errors are reported to stdout as usual. Returns, for each file in order,
+/// the elaboration result and the time taken to elaborate it (which includes the time spent
+/// waiting for its imports, if they are being elaborated for another file).
+pub(crate) fn elab_files(paths: Vec<FileRef>) -> Vec<(io::Result<ElabResult<()>>, Duration)> {
+  let recvs = paths.iter().cloned().map(|path| {
+    let (send, recv) = channel();
+    POOL.spawn_ok(async move {
+      let start = Instant::now();
+      let res = elaborate(path, Default::default()).await;
+      let _ = send.send((res, start.elapsed()));
+    });
+    recv
+  }).collect::<Vec<_>>();
+  let res = block_on(futures::future::join_all(recvs));
+  print_reports(&paths);
+  res.into_iter().map(|r|
+    r.unwrap_or_else(|_| (Ok(ElabResult::Canceled), Duration::default()))).collect()
+}
+
+/// The environment of the file `path`, if it has been elaborated by [`elab_file`] or
+/// [`elab_files`], including when it was imported by another file.
+pub(crate) fn elaborated(path: &FileRef) -> Option<FrozenEnv> {
+  let file = VFS_.0.ulock().get(path)?.clone();
+  let g = file.parsed.try_lock()?;
+  if let Some(FileCache::Ready(env)) = &*g { Some(env.clone()) } else { None }
+}
+
 /// Main entry point for `mm0-rs compile` subcommand.
 ///
 /// # Arguments
@@ -414,13 +618,155 @@ fn elaborate_and_send(path: FileRef, send: FSender<ElabResult<()>>, rd: ArcList<
 /// - `out.mmb` (or `out.mmu`) is the MMB file to generate, if the elaboration is
 ///   successful. The file extension is used to determine if we are outputting
 ///   binary. If this argument is omitted, the input is only elaborated.
+/// - `--append`: if `out.mmb` already exists and was compiled from an earlier version
+///   of the input, only the new declarations are appended to it, leaving the rest of
+///   the file in place. (See [`MMBExporter::append`].)
+/// - `--split-index`: write the debugging index (names, source positions and doc comments)
+///   to a separate file `out.mmz` instead of `out.mmb`, keeping the `.mmb` file minimal.
+///   (See [`MMBExporter::write_index_file`].)
+/// - `--compress`: compress the proof section of `out.mmb` using zstd. The resulting file
+///   can be imported by `mm0-rs` but must be decompressed before it is checked by `mm0-c`.
+///   This requires the `compress` feature, and cannot be combined with `--append`.
+///   (See [`mmb::export::compress`](crate::mmb::export::compress).)
+/// - `--display FILE`: write the output of `display`, `print` and `stat` to `FILE`,
+///   instead of reporting it as info diagnostics.
+/// - `--error-format json`: print each diagnostic as a line of JSON instead of a snippet,
+///   with the file, the span and line/column range, the severity, a code (`parse`, `elab`,
+///   `upstream` or `output`), the message and the related locations, and nothing else on
+///   stdout. In either format the diagnostics are printed in the same order on every run:
+///   each file after the files it imports, and the diagnostics in a file by position.
+/// - `--test`: run the tests in `@test do` blocks, reporting each failing test as an error
+///   and the number of tests that passed and failed at the end of each file.
+/// - `--jobs N`: check the proofs of theorems on `N` threads, while the statements are
+///   still elaborated in order (see [`elab::parallel`]).
+/// - `--cache DIR`: save the environments of the imported files in `DIR`, and load the
+///   ones whose sources have not changed from there instead of elaborating them
+///   (see [`cache`](crate::cache)).
+/// - `--allow-sorry`: write `out.mmb` even if some theorems have no proof, either because
+///   they used [`admit`](crate::elab::lisp::BuiltinProc::Admit) or because of `--no-proofs`.
+///   Such theorems are written as axioms, so the file will not verify against a
+///   specification. Without this flag, nothing is written if there are any.
+/// - `--kernel-check`: before writing any output, export the elaborated environment to MMB
+///   in memory and check it with [`mmb::verify`](crate::mmb::verify), which is independent
+///   of the elaborator. If there is an `.mm0` file next to the input with the same name, the
+///   public declarations are also checked against it. The checked bytes are the ones written
+///   to `out.mmb`. If the checker rejects it, or some theorems have no proof, nothing is
+///   written. This is always on when `mm0-rs` is built with the `kernel-check` feature.
+/// - `--watch`: after compiling, keep watching the input file and the files it imports,
+///   and compile again whenever one of them changes, printing the diagnostics and the time
+///   taken each time. Only the changed files and the files that import them are elaborated
+///   again. (See [`watch`].)
 pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
-  let path = args.value_of("INPUT").expect("required arg");
-  let (path, file) = VFS_.get_or_insert(fs::canonicalize(path)?.into())?;
-  let env = match block_on(elaborate(path.clone(), Default::default()))? {
-    ElabResult::Ok(_, _, env) => env,
-    _ => std::process::exit(1)
+  init(args)?;
+  let input = args.value_of("INPUT").expect("required arg");
+  if args.is_present("watch") { return watch(args, input) }
+  if !compile(args, input, args.value_of("OUTPUT"))? { std::process::exit(1) }
+  Ok(())
+}
+
+/// Set up the diagnostic format and the output channel according to the options
+/// `--error-format` and `--display` in `args`, before calling [`compile`].
+pub(crate) fn init(args: &ArgMatches<'_>) -> io::Result<()> {
+  let json = args.value_of("error_format") == Some("json");
+  JSON_ERRORS.store(json, Ordering::Relaxed);
+  if let Some(s) = args.value_of_os("display") {
+    let chan: Arc<dyn OutputChannel> =
+      Arc::new(WriteChannel::new(io::BufWriter::new(fs::File::create(s)?)));
+    output::set_channel(Some(chan));
+  }
+  Ok(())
+}
+
+/// Compile the file `input` once, writing the result to `output` if given, with the other
+/// options in `args` (see [`main`]). Returns false if there was an error that prevented
+/// writing the outputs.
+pub(crate) fn compile(args: &ArgMatches<'_>, input: &str, output: Option<&str>) -> io::Result<bool> {
+  use {fs::File, io::BufWriter, std::path::Path};
+  let (path, text, env) = if let Some(res) = try_elab_file(input)? {res} else {return Ok(false)};
+  let kernel_check =
+    (cfg!(feature = "kernel-check") || args.is_present("kernel_check")) && !input.ends_with(".mm0");
+  if kernel_check && !crate::get_check_proofs() {
+    eprintln!("--kernel-check cannot be combined with --no-proofs");
+    std::process::exit(1);
+  }
+  let unproved = env.thms().0.iter().enumerate()
+    .filter(|(_, td)| matches!(td.kind, ThmKind::Thm(None))).collect::<Vec<_>>();
+  let report_unproved = || for &(i, td) in &unproved {
+    #[allow(clippy::cast_possible_truncation)]
+    let admitted = env.admitted().contains(&ThmID(i as u32));
+    eprintln!("{}: theorem {} {}", td.span.file.rel(), env.data()[td.atom].name(),
+      if admitted {"has admitted goals"} else {"has no proof"});
   };
+  if let Some(out) = output {
+    if !unproved.is_empty() && (!args.is_present("allow_sorry") || out.ends_with(".mmu")) {
+      report_unproved();
+      if out.ends_with(".mmu") {
+        eprintln!("{}: theorems without a proof cannot be written to MMU", out);
+      } else {
+        eprintln!("{}: not written; use --allow-sorry to write theorems without a proof as axioms", out);
+      }
+      return Ok(false)
+    }
+  }
+  if kernel_check && !unproved.is_empty() {
+    report_unproved();
+    eprintln!("{}: the kernel check requires every theorem to have a proof", input);
+    return Ok(false)
+  }
+  // The MMB file is built in memory, so that the bytes that are checked are the ones written
+  let mmb_out = output.filter(|out| !out.ends_with(".mmu"));
+  let split = args.is_present("split_index");
+  let compress = args.is_present("compress");
+  let mut mmb = None;
+  if kernel_check || mmb_out.is_some() {
+    if compress && args.is_present("append") {
+      eprintln!("--compress cannot be combined with --append");
+      std::process::exit(1);
+    }
+    let index = mmb_out.is_some() && !split;
+    let mut mmz = io::Cursor::new(vec![]);
+    let buf = match mmb_out.filter(|out| args.is_present("append") && Path::new(out).exists()) {
+      Some(out) => {
+        let old = fs::read(out)?;
+        let mut buf = io::Cursor::new(old.clone());
+        let mut ex = MMBExporter::new(path, text.ascii(), &env, &mut buf);
+        if let Err(e) = ex.allow_sorry(args.is_present("allow_sorry")).append(&old, index) {
+          eprintln!("{}: {}", out, e);
+          return Ok(false)
+        }
+        if split { ex.write_index_file(&mut mmz)? }
+        #[allow(clippy::cast_possible_truncation)]
+        let len = ex.pos() as usize;
+        ex.finish()?;
+        let mut buf = buf.into_inner();
+        buf.truncate(len);
+        buf
+      }
+      None => {
+        let mut buf = io::Cursor::new(vec![]);
+        let mut ex = MMBExporter::new(path, text.ascii(), &env, &mut buf);
+        ex.allow_sorry(args.is_present("allow_sorry")).run(index)?;
+        if mmb_out.is_some() && split { ex.write_index_file(&mut mmz)? }
+        ex.finish()?;
+        buf.into_inner()
+      }
+    };
+    if kernel_check {
+      let spec = Path::new(input).with_extension("mm0");
+      let spec = if spec.exists() { Some(&*spec) } else { None };
+      if let Err(e) = crate::mmb::verify::check_kernel(&buf, spec) {
+        eprintln!("{}: the verifier rejected the elaborated environment: {}", input, e);
+        if let Some(spec) = spec {
+          eprintln!("Either the file does not match {}, or this is a bug in the elaborator; \
+            nothing was written.", spec.display());
+        } else {
+          eprintln!("This is a bug in the elaborator; nothing was written.");
+        }
+        return Ok(false)
+      }
+    }
+    mmb = Some((buf, mmz.into_inner()))
+  }
   if let Some(s) = args.value_of_os("output") {
     if let Err((fsp, e)) =
       if s == "-" { env.run_output(io::stdout()) }
@@ -428,21 +774,109 @@ pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
     {
       let e = ElabError::new_e(fsp.span, e);
       let file = VFS_.get_or_insert(fsp.file.clone())?.1;
-      e.to_snippet(&fsp.file, file.text.ascii(), &mut mk_to_range(),
-        |s| println!("{}\n", DisplayList::from(s).to_string()));
-      std::process::exit(1);
+      print!("{}", render_error(&fsp.file, &file.text, &e, "output", mk_to_range()));
+      return Ok(false)
     }
   }
-  if let Some(out) = args.value_of("OUTPUT") {
-    use {fs::File, io::BufWriter};
-    let w = BufWriter::new(File::create(out)?);
+  if let Some(out) = output {
     if out.ends_with(".mmu") {
-      env.export_mmu(w)?;
-    } else {
-      let mut ex = MMBExporter::new(path, file.text.ascii(), &env, w);
-      ex.run(true)?;
-      ex.finish()?;
+      env.export_mmu(BufWriter::new(File::create(out)?))?;
+    } else if let Some((buf, mmz)) = mmb {
+      if compress {
+        fs::write(out, crate::mmb::export::compress(&buf, 0)?)?;
+      } else {
+        fs::write(out, buf)?;
+      }
+      if split { fs::write(Path::new(out).with_extension("mmz"), mmz)? }
     }
   }
-  Ok(())
+  Ok(true)
+}
+
+/// The modification times of the files being watched by [`watch`].
+type MTimes = HashMap<FileRef, Option<SystemTime>>;
+
+/// Get the modification time of `path`, or `None` if it can't be read.
+fn mtime(path: &FileRef) -> Option<SystemTime> {
+  fs::metadata(path.path()).and_then(|m| m.modified()).ok()
+}
+
+/// Remove the files in `changed`, and the files that import them (directly or indirectly),
+/// from the [`VFS`] and from [`struct@KEYS`], so that the next compilation reads and
+/// elaborates them again and reuses the other files. Returns the number of files removed.
+fn invalidate(mut changed: HashSet<FileRef>) -> usize {
+  let mut vfs = VFS_.0.ulock();
+  let imports = vfs.iter().filter_map(|(path, file)| {
+    let src = file.text.try_ascii().filter(|_| !path.has_extension("mmu"))?;
+    let (_, ast) = parse(src.clone(), None);
+    let deps = ast.imports.iter()
+      .filter_map(|(_, f)| crate::cache::resolve_import(path, f)).collect::<Vec<_>>();
+    Some((path.clone(), deps))
+  }).collect::<Vec<_>>();
+  loop {
+    let n = changed.len();
+    for (path, deps) in &imports {
+      if deps.iter().any(|f| changed.contains(f)) { changed.insert(path.clone()); }
+    }
+    if changed.len() == n { break }
+  }
+  let mut keys = KEYS.ulock();
+  for path in &changed { vfs.remove(path); keys.remove(path); }
+  changed.len()
+}
+
+/// Wait until one of the files in `mtimes` changes, and return the changed files, updating
+/// their modification times. Files that have been read since the last call are added to
+/// `mtimes` first.
+///
+/// This polls the modification times every 500ms instead of using the file change
+/// notifications of the OS. Only the input and the files it imports are watched, so this
+/// is a few `stat` calls per poll, and polling behaves the same on every platform and on
+/// network file systems (where notifications are often unavailable), without another
+/// dependency. A change is noticed at most half a second late, which is small compared
+/// to the time it takes to compile again.
+fn wait_for_changes(mtimes: &mut MTimes) -> HashSet<FileRef> {
+  for path in VFS_.0.ulock().keys() {
+    if !mtimes.contains_key(path) { mtimes.insert(path.clone(), mtime(path)); }
+  }
+  loop {
+    std::thread::sleep(Duration::from_millis(500));
+    let changed = mtimes.iter_mut().filter_map(|(path, t)| {
+      let t2 = mtime(path);
+      if *t == t2 { None } else { *t = t2; Some(path.clone()) }
+    }).collect::<HashSet<_>>();
+    if !changed.is_empty() {
+      // Editors often write a file in several steps, so wait for them to finish
+      std::thread::sleep(Duration::from_millis(100));
+      for path in &changed { mtimes.insert(path.clone(), mtime(path)); }
+      return changed
+    }
+  }
+}
+
+/// Compile `input` repeatedly for `mm0-rs compile --watch`. After each compilation, this
+/// polls the modification times of the files that were read (the input and the files it
+/// imports) and compiles again when one of them changes. The files that did not change,
+/// and do not import a file that changed, are not elaborated again; their environments are
+/// reused from the [`VFS`]. This never returns, so it has to be interrupted to stop watching.
+///
+/// Errors that stop a single compilation, such as a missing file, are printed and do not
+/// stop watching. The status lines are printed to stderr, so that they are not mixed in
+/// with `--error-format json` output.
+fn watch(args: &ArgMatches<'_>, input: &str) -> io::Result<()> {
+  let mut mtimes = MTimes::new();
+  let path: FileRef = fs::canonicalize(input)?.into();
+  let t = mtime(&path);
+  mtimes.insert(path, t);
+  loop {
+    let start = Instant::now();
+    let ok = compile(args, input, args.value_of("OUTPUT")).unwrap_or_else(|e| { eprintln!("{}: {}", input, e); false });
+    eprintln!("[watch] {} {} in {:.2?}", input,
+      if ok {"compiled"} else {"failed"}, start.elapsed());
+    let changed = wait_for_changes(&mut mtimes);
+    let mut files = changed.iter().map(|f| f.rel().to_owned()).collect::<Vec<_>>();
+    files.sort_unstable();
+    let n = invalidate(changed);
+    eprintln!("[watch] changed: {}; elaborating {} file(s) again", files.join(", "), n);
+  }
 }
\ No newline at end of file
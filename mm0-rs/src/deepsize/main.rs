@@ -267,7 +267,7 @@ macro_rules! deep_size_0(
 use std::sync::atomic;
 deep_size_0!(
     u8, u16, u32, u64, usize, i8, i16, i32, i64, isize,
-    bool, char, f32, f64, (), {!Copy} str,
+    bool, char, f32, f64, (), {!Copy} str, std::time::Duration,
     {!Copy} atomic::AtomicBool, {!Copy} atomic::AtomicIsize, {!Copy} atomic::AtomicUsize,
     {T: ?Sized} &T,
     {!Copy T} std::cell::Cell<T>,
@@ -354,6 +354,33 @@ impl DeepSizeOf for lsp_types::Url {
     }
 }
 
+deep_size_0!(crate::util::Span);
+
+impl DeepSizeOf for crate::util::ArcString {
+    fn deep_size_of_children(&self, context: &mut Context) -> usize {
+        self.0.deep_size_of_children(context)
+    }
+}
+
+impl DeepSizeOf for crate::util::FileRef {
+    fn deep_size_of_children(&self, context: &mut Context) -> usize {
+        // A `FileRef` is an `Arc` we can't see into, so we use the pointer to the path
+        // (which is unique to the allocation) to count it only once.
+        if !context.arcs.insert(self.ptr() as usize) { return 0 }
+        let url = {
+            #[cfg(feature = "server")] { self.url().deep_size_of_children(context) }
+            #[cfg(not(feature = "server"))] { 0 }
+        };
+        self.path().deep_size_of_children(context) + self.rel().len() + url
+    }
+}
+
+impl DeepSizeOf for crate::util::FileSpan {
+    fn deep_size_of_children(&self, context: &mut Context) -> usize {
+        self.file.deep_size_of_children(context)
+    }
+}
+
 impl<T: DeepSizeOf> DeepSizeOf for std::cell::RefCell<T> {
     fn deep_size_of_children(&self, context: &mut Context) -> usize {
         if let Ok(g) = self.try_borrow() {
@@ -0,0 +1,83 @@
+//! Axiom dependency audit.
+//!
+//!     mm0-rs deps foo.mm1 thm1 thm2 ...
+//!
+//! will elaborate `foo.mm1` and print, for each of the given theorems, the axioms it depends
+//! on (transitively through the theorems used in its proof) and the holes in its proof, that
+//! is, the theorems it uses that have no proof. With no theorems given, every theorem in
+//! `foo.mm1` is reported. This is the same computation as the `(axioms-of)` lisp builtin,
+//! and is meant for reviewers who want to confirm that a result uses only the intended
+//! axiom base: with `--allow ax1,ax2` the command fails if any of the reported theorems
+//! depends on a hole or on an axiom not in the list.
+use std::collections::HashMap;
+use std::io;
+use clap::ArgMatches;
+use crate::elab::environment::{Environment, ThmID};
+use crate::lined_string::LinedString;
+use crate::util::{FileRef, FileSpan};
+
+/// The source files, loaded on demand to print the locations of declarations.
+#[derive(Default)]
+struct Sources(HashMap<FileRef, Option<LinedString>>);
+
+impl Sources {
+  /// Format the location of a declaration as `file:line:col`.
+  fn loc(&mut self, fsp: &FileSpan) -> String {
+    let src = self.0.entry(fsp.file.clone()).or_insert_with(||
+      std::fs::read_to_string(fsp.file.path()).ok().map(LinedString::from));
+    match src {
+      Some(src) => {
+        let pos = src.to_pos(fsp.span.start);
+        format!("{}:{}:{}", fsp.file.rel(), pos.line + 1, pos.character + 1)
+      }
+      None => fsp.file.rel().to_owned(),
+    }
+  }
+}
+
+/// Main entry point for `mm0-rs deps` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs deps [--allow ax1,ax2,...] <in.mm1> [thm...]`, where:
+///
+/// - `in.mm1` is the MM1 (or MM0) file to elaborate.
+/// - `thm` is a theorem to report on. If none are given, all theorems declared in
+///   `in.mm1` (but not its imports) are reported.
+/// - `--allow` sets the permitted axioms. If given, the process exits with a nonzero
+///   status if any reported theorem depends on a hole or on an axiom not in the list.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let (fref, _, env) = crate::compiler::elab_file(args.value_of("INPUT").expect("required arg"))?;
+  // Safety: the environment is not modified or cloned while we hold this reference
+  let env: &Environment = unsafe { env.thaw() };
+  let thms: Vec<ThmID> = match args.values_of("THM") {
+    Some(names) => names.map(|name| env.atoms.get(name.as_bytes())
+      .and_then(|&a| env.thm(a))
+      .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+        format!("unknown theorem '{}'", name))))
+      .collect::<io::Result<_>>()?,
+    None => (0..env.thms.len()).map(|i| ThmID(i as u32))
+      .filter(|&t| env.thms[t].span.file == fref).collect(),
+  };
+  let allow: Option<Vec<&str>> = args.values_of("allow").map(|v| v.collect());
+  let mut srcs = Sources::default();
+  let mut ok = true;
+  for t in thms {
+    let td = &env.thms[t];
+    let name = |t: ThmID| env.data[env.thms[t].atom].name.as_str();
+    println!("{} ({})", name(t), srcs.loc(&td.span));
+    let deps = env.axioms_of(t);
+    let axioms = deps.axioms.iter().map(|&t| name(t)).collect::<Vec<_>>();
+    println!("  axioms: {}", if axioms.is_empty() { "(none)".into() } else { axioms.join(" ") });
+    for &h in &deps.holes {
+      println!("  hole: {} ({})", name(h), srcs.loc(&env.thms[h].span));
+    }
+    if let Some(allow) = &allow {
+      let bad = axioms.iter().filter(|a| !allow.contains(*a)).copied().collect::<Vec<_>>();
+      if !bad.is_empty() { println!("  disallowed axioms: {}", bad.join(" ")) }
+      ok &= bad.is_empty() && deps.holes.is_empty();
+    }
+  }
+  if !ok { std::process::exit(1) }
+  Ok(())
+}
@@ -13,45 +13,54 @@ pub mod lisp;
 pub mod math_parser;
 pub mod local_context;
 pub mod refine;
+pub mod cong;
+pub mod norm_num;
+pub mod rewrite;
+pub mod proof_state;
 pub mod proof;
 pub mod inout;
+pub mod profile;
+pub mod parallel;
+pub mod serialize;
 
 use std::ops::{Deref, DerefMut};
 use std::mem;
 use std::result::Result as StdResult;
 use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
 use std::time::{Instant, Duration};
-use std::path::PathBuf;
 use std::collections::HashMap;
 use std::{future::Future, pin::Pin, task::{Context, Poll}};
-use futures::channel::oneshot::Receiver;
+use futures::channel::oneshot::{channel, Canceled, Receiver};
 use environment::{AtomData, AtomID, Coe, DeclKey, DocComment, Expr, ExprNode,
   LispData, NotaInfo, ObjectKind, Proof, ProofNode, Remap, Remapper, Sort, SortID,
   StmtTrace, Term, TermID, Thm, ThmID};
 use environment::Literal as ELiteral;
 use lisp::LispVal;
-use spans::Spans;
+use spans::{Hint, Spans};
 use inout::InoutHandlers;
 pub use {environment::Environment, local_context::LocalContext};
 pub use crate::parser::ErrorLevel;
 pub use frozen::{FrozenEnv, FrozenLispKind, FrozenLispVal, FrozenAtomData};
-use crate::util::{ArcList, ArcString, BoxError, FileRef, FileSpan, Span};
+use crate::util::{ArcError, ArcList, ArcString, BoxError, FileRef, FileSpan, Span};
 use crate::parser::{ParseError,
   ast::{self, AST, DeclKind, Delimiter, GenNota, LocalKind, Modifiers, Prec,
     SExpr, SExprKind, SimpleNota, SimpleNotaKind, Stmt, StmtKind, Literal as ALiteral}};
 
 use crate::lined_string::LinedString;
+use math_parser::{normalize_token, mixed_scripts};
 
 #[cfg(feature = "server")]
 use lsp_types::{Diagnostic, DiagnosticRelatedInformation, Location};
 
 /// An error payload.
-#[derive(Debug, DeepSizeOf)]
+#[derive(Clone, Debug, DeepSizeOf)]
 pub enum ElabErrorKind {
-  /// A boxed error. The main [`BoxError`] is the error message,
-  /// and the `Vec<(FileSpan, BoxError)>` is a list of other positions
-  /// related to the error, along with short descriptions.
-  Boxed(BoxError, Option<Vec<(FileSpan, BoxError)>>),
+  /// A boxed error. The main [`ArcError`] is the error message,
+  /// and the `Vec<(FileSpan, ArcError)>` is a list of other positions
+  /// related to the error, along with short descriptions. (The errors are shared
+  /// rather than boxed so that the errors of a file can be copied when elaboration
+  /// resumes partway through it.)
+  Boxed(ArcError, Option<Vec<(FileSpan, ArcError)>>),
   /// This is an error from a file upstream. The `usize` is the number of
   /// number of upstream errors after the first one.
   Upstream(FileRef, Arc<[ElabError]>, usize)
@@ -103,13 +112,13 @@ impl ElabErrorKind {
 }
 
 impl From<BoxError> for ElabErrorKind {
-  fn from(e: BoxError) -> ElabErrorKind { ElabErrorKind::Boxed(e, None) }
+  fn from(e: BoxError) -> ElabErrorKind { ElabErrorKind::Boxed(e.into(), None) }
 }
 
 /// The main error type for the elaborator. Each error has a location (which must be in
 /// the currently elaborating file), an error level, a message, and an optional list of
 /// related locations (possibly in other files) along with short messages.
-#[derive(Debug, DeepSizeOf)]
+#[derive(Clone, Debug, DeepSizeOf)]
 pub struct ElabError {
   /// The location of the error in the current file.
   pub pos: Span,
@@ -119,6 +128,12 @@ pub struct ElabError {
   pub kind: ElabErrorKind,
 }
 
+/// Convert anything that can be converted to a [`BoxError`] to an [`ArcError`].
+pub(crate) fn arc_error(e: impl Into<BoxError>) -> ArcError {
+  let e: BoxError = e.into();
+  e.into()
+}
+
 /// The main result type used by functions in the elaborator.
 pub type Result<T> = StdResult<T, ElabError>;
 
@@ -131,22 +146,23 @@ impl ElabError {
 
   /// Make an elaboration error from a position and anything that can be converted to a [`BoxError`].
   pub fn new_e(pos: impl Into<Span>, e: impl Into<BoxError>) -> ElabError {
-    ElabError::new(pos, ElabErrorKind::Boxed(e.into(), None))
+    ElabError::new(pos, ElabErrorKind::Boxed(arc_error(e), None))
   }
 
   /// Make an elaboration error from a position, a message, and a list of related info
   pub fn with_info(pos: impl Into<Span>, msg: BoxError, v: Vec<(FileSpan, BoxError)>) -> ElabError {
-    ElabError::new(pos, ElabErrorKind::Boxed(msg, Some(v)))
+    ElabError::new(pos, ElabErrorKind::Boxed(msg.into(),
+      Some(v.into_iter().map(|(fsp, e)| (fsp, e.into())).collect())))
   }
 
   /// Make an elaboration warning from a position and a message.
   pub fn warn(pos: impl Into<Span>, e: impl Into<BoxError>) -> ElabError {
-    ElabError { pos: pos.into(), level: ErrorLevel::Warning, kind: ElabErrorKind::Boxed(e.into(), None)}
+    ElabError { pos: pos.into(), level: ErrorLevel::Warning, kind: ElabErrorKind::Boxed(arc_error(e), None)}
   }
 
   /// Make an info message at a position
   pub fn info(pos: impl Into<Span>, e: impl Into<BoxError>) -> ElabError {
-    ElabError { pos: pos.into(), level: ErrorLevel::Info, kind: ElabErrorKind::Boxed(e.into(), None)}
+    ElabError { pos: pos.into(), level: ErrorLevel::Info, kind: ElabErrorKind::Boxed(arc_error(e), None)}
   }
 
   /// Convert an [`ElabError`] into the LSP [`Diagnostic`] type.
@@ -168,14 +184,14 @@ impl ElabError {
 
 impl From<ParseError> for ElabError {
   fn from(e: ParseError) -> Self {
-    ElabError {pos: e.pos, level: e.level, kind: ElabErrorKind::Boxed(e.msg, None) }
+    ElabError {pos: e.pos, level: e.level, kind: ElabErrorKind::Boxed(e.msg.into(), None) }
   }
 }
 
 /// Records the current reporting setting. A report that is suppressed by the reporting mode
 /// will not appear in the error list / as a diagnostic, but a fatal error will still prevent
 /// proof export.
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 struct ReportMode {
   /// Do we report on errors?
   error: bool,
@@ -185,6 +201,8 @@ struct ReportMode {
   info: bool,
 }
 
+crate::deep_size_0!(ReportMode);
+
 impl ReportMode {
   fn new() -> ReportMode {
     ReportMode {error: true, warn: true, info: true}
@@ -219,8 +237,18 @@ pub struct Elaborator {
   timeout: Option<Duration>,
   /// The time at which the current lisp evaluation will be aborted
   cur_timeout: Option<Instant>,
+  /// The maximum number of evaluation steps in one lisp evaluation (default unlimited)
+  fuel: Option<u64>,
+  /// The number of evaluation steps remaining before the current lisp evaluation is aborted
+  cur_fuel: Option<u64>,
+  /// The maximum number of evaluation steps in one pattern test `(? pred)` (default `2^24`)
+  test_fuel: Option<u64>,
   /// The maximum number of permitted stack frames during elaboration
   stack_limit: usize,
+  /// The maximum number of nodes in a statement or definition, see `set-size-limit`
+  term_size_limit: usize,
+  /// The maximum number of nodes in a proof, see `set-size-limit`
+  proof_size_limit: usize,
   /// The current proof context
   lc: LocalContext,
   /// Information attached to spans, used for hover queries
@@ -231,10 +259,119 @@ pub struct Elaborator {
   check_proofs: bool,
   /// The current reporting mode, whether we will report each severity of error
   reporting: ReportMode,
+  /// True if goals should be displayed sequent-style, with numbered hypotheses
+  sequent_goals: bool,
+  /// True if non-ASCII notation tokens are normalized to NFC (default true, ignored in MM0 mode)
+  normalize_tokens: bool,
+  /// True if math strings whose parse depends on a precedence difference of 1 should get a warning
+  warn_precedence: bool,
+  /// True if lisp error backtraces include the arguments of each call
+  backtrace: bool,
+  /// True if global definitions overriding a builtin must match the builtin's [`ProcSpec`](lisp::ProcSpec)
+  strict_overrides: bool,
+  /// The lisp debugger, if one was installed when elaboration started
+  debugger: Option<Arc<lisp::debugger::Debugger>>,
+  /// The stepping state of the lisp debugger
+  debug_step: lisp::debugger::StepMode,
+  /// Where the output of `display`, `print` and `stat` goes, or `None` to report it as info diagnostics
+  output: Option<Arc<dyn lisp::output::OutputChannel>>,
+  /// True if the tests (`@test do` blocks) should be run, otherwise they are skipped
+  test_mode: bool,
+  /// The number of tests that have passed and failed so far
+  tests: (usize, usize),
   /// The handlers for different kinds of input and output.
   inout: InoutHandlers,
   /// The arena for lisp data.
   arena: lisp::LispArena,
+  /// Timing data for the current statement, used to explain slow statements.
+  profile: profile::StmtProfile,
+  /// The detailed per-procedure profile of lisp evaluation, if turned on.
+  lisp_profile: profile::LispProfile,
+  /// The counter used by `gensym` to generate fresh atom names.
+  gensym: u64,
+  /// The checkpoints made by `checkpoint` in the current statement.
+  checkpoints: Vec<Checkpoint>,
+  /// The decision procedures registered with `register-tactic`, indexed by the head of
+  /// the goals they solve. `refine` calls them instead of leaving such goals open.
+  tactics: HashMap<TermID, LispVal>,
+  /// The imported environments merged so far, with the spans of their `import` statements.
+  imported: Vec<(Span, FrozenEnv)>,
+  /// The time the last [`ResumePoint`] was recorded, or `None` if they are not being recorded.
+  last_resume: Option<Instant>,
+  /// The minimum elaboration time until the next [`ResumePoint`] is recorded. This starts at
+  /// [`RESUME_INTERVAL`], and doubles whenever the resume points are thinned out.
+  resume_interval: Duration,
+  /// The number of threads used to check the proofs of theorems (see [`parallel`]).
+  /// If it is 1, proofs are elaborated sequentially.
+  jobs: usize,
+  /// True if the proofs of theorems in the current statement should be deferred,
+  /// to be checked in parallel.
+  defer_proofs: bool,
+  /// The theorems whose proofs have been deferred, in order.
+  deferred: Vec<parallel::Deferred>,
+  /// The worker elaborators used to check deferred proofs, which are kept between batches.
+  workers: Vec<parallel::Worker>,
+  /// In a worker elaborator checking deferred proofs, the theorem being checked, which is
+  /// already in the environment.
+  proof_worker: Option<ThmID>,
+}
+
+/// The initial minimum elaboration time between two [`ResumePoint`]s. Recording one copies
+/// the lisp globals, so this keeps the overhead small compared to the elaboration itself.
+const RESUME_INTERVAL: Duration = Duration::from_millis(200);
+
+/// The maximum number of [`ResumePoint`]s kept in an environment. When there are this many,
+/// every other one is dropped (keeping the latest) and the interval between them is doubled,
+/// so that a long file keeps a bounded number of copies of the globals, spread over the file.
+const MAX_RESUME_POINTS: usize = 16;
+
+/// The state of the elaborator between two statements of a file, recorded in the resulting
+/// [`Environment`] so that after an edit later in the file, the language server can restore
+/// it instead of elaborating the file from the start (see [`elaborate`]).
+///
+/// The lisp data in a resume point is a private copy (see [`Remapper::identity`]), so
+/// resume points can be shared by the environments of successive elaborations of the file.
+#[derive(Debug, DeepSizeOf)]
+pub struct ResumePoint {
+  /// The number of statements elaborated before this point
+  idx: usize,
+  /// The imported environments merged before this point. If any of them has changed,
+  /// this point can't be used.
+  imports: Vec<(Span, FrozenEnv)>,
+  /// The number of errors reported before this point
+  errors: usize,
+  /// The environment, including the global lisp definitions
+  env: environment::EnvSnapshot,
+  /// The decision procedures registered with `register-tactic`
+  tactics: HashMap<TermID, LispVal>,
+  /// The settings of the elaborator, see the fields of [`Elaborator`]
+  timeout: Option<Duration>,
+  fuel: Option<u64>,
+  test_fuel: Option<u64>,
+  stack_limit: usize,
+  term_size_limit: usize,
+  proof_size_limit: usize,
+  check_proofs: bool,
+  reporting: ReportMode,
+  sequent_goals: bool,
+  normalize_tokens: bool,
+  warn_precedence: bool,
+  backtrace: bool,
+  strict_overrides: bool,
+  tests: (usize, usize),
+  gensym: u64,
+}
+
+/// A saved elaborator state, created by [`Elaborator::checkpoint`], which can be
+/// restored by [`Elaborator::rollback`].
+#[derive(Debug)]
+pub struct Checkpoint {
+  /// The state of the environment, including the global lisp definitions
+  env: environment::EnvSnapshot,
+  /// The proof context
+  lc: LocalContext,
+  /// The contents of the goals and metavariables, which are mutable references
+  refs: Vec<(LispVal, lisp::LispWeak)>,
 }
 
 impl Deref for Elaborator {
@@ -258,7 +395,8 @@ impl Elaborator {
   /// - `check_proofs`: The initial setting of the `check_proofs` state at the top of each
   ///   file, which can be changed later using the `(check-proofs)` lisp command.
   /// - `cancel`: An atomic flag that can be flipped in another thread in order to cancel
-  ///   the elaboration before completion.
+  ///   the elaboration before completion. Once it is set, no further errors are reported,
+  ///   so that the "cancelled" errors raised while unwinding do not reach the user.
   #[must_use] pub fn new(ast: Arc<AST>, path: FileRef,
       mm0_mode: bool, check_proofs: bool, cancel: Arc<AtomicBool>) -> Elaborator {
     Elaborator {
@@ -267,14 +405,42 @@ impl Elaborator {
       env: Environment::new(),
       timeout: Some(Duration::from_secs(5)),
       cur_timeout: None,
+      fuel: None,
+      cur_fuel: None,
+      test_fuel: Some(1 << 24),
       stack_limit: 1024,
+      term_size_limit: 1 << 20,
+      proof_size_limit: 1 << 24,
       lc: LocalContext::new(),
       spans: Spans::new(),
       mm0_mode,
       check_proofs,
       inout: InoutHandlers::default(),
       reporting: ReportMode::new(),
+      sequent_goals: false,
+      normalize_tokens: true,
+      warn_precedence: false,
+      backtrace: crate::get_backtrace(),
+      strict_overrides: false,
+      debugger: lisp::debugger::current(),
+      debug_step: Default::default(),
+      output: lisp::output::current(),
+      test_mode: crate::get_test_mode(),
+      tests: (0, 0),
       arena: Default::default(),
+      profile: Default::default(),
+      lisp_profile: profile::LispProfile::new(crate::get_profile_lisp()),
+      gensym: 0,
+      checkpoints: vec![],
+      tactics: HashMap::new(),
+      imported: vec![],
+      last_resume: None,
+      resume_interval: RESUME_INTERVAL,
+      jobs: crate::get_jobs(),
+      defer_proofs: false,
+      deferred: vec![],
+      workers: vec![],
+      proof_worker: None,
     }
   }
 
@@ -284,7 +450,9 @@ impl Elaborator {
   pub fn fspan(&self, span: Span) -> FileSpan { FileSpan {file: self.path.clone(), span} }
 
   fn report(&mut self, e: ElabError) {
-    if self.reporting.active(e.level) {self.errors.push(e)}
+    if self.reporting.active(e.level) && !self.cancel.load(Ordering::Relaxed) {
+      self.errors.push(e)
+    }
   }
   fn catch(&mut self, r: Result<()>) { r.unwrap_or_else(|e| self.report(e)) }
 
@@ -292,6 +460,115 @@ impl Elaborator {
     self.env.spans.push(mem::take(&mut self.spans));
   }
 
+  /// Record a [`ResumePoint`] before statement `idx`, if resume points are being recorded
+  /// and it has been `resume_interval` since the last one. Nothing is recorded while
+  /// there are deferred proofs, because they are not saved. At most [`MAX_RESUME_POINTS`]
+  /// are kept.
+  fn record_resume(&mut self, idx: usize) {
+    match self.last_resume {
+      Some(t) if t.elapsed() >= self.resume_interval && self.deferred.is_empty() => {}
+      _ => return
+    }
+    let n = self.env.resume.len();
+    if n >= MAX_RESUME_POINTS {
+      let mut i = 0;
+      self.env.resume.retain(|_| { i += 1; (n - i) % 2 == 0 });
+      self.resume_interval *= 2;
+    }
+    let r = &mut Remapper::identity(&self.env);
+    let p = ResumePoint {
+      idx,
+      imports: self.imported.clone(),
+      errors: self.errors.len(),
+      env: self.env.snapshot().remap(r),
+      tactics: self.tactics.iter().map(|(&t, v)| (t, v.remap(r))).collect(),
+      timeout: self.timeout,
+      fuel: self.fuel,
+      test_fuel: self.test_fuel,
+      stack_limit: self.stack_limit,
+      term_size_limit: self.term_size_limit,
+      proof_size_limit: self.proof_size_limit,
+      check_proofs: self.check_proofs,
+      reporting: self.reporting,
+      sequent_goals: self.sequent_goals,
+      normalize_tokens: self.normalize_tokens,
+      warn_precedence: self.warn_precedence,
+      backtrace: self.backtrace,
+      strict_overrides: self.strict_overrides,
+      tests: self.tests,
+      gensym: self.gensym,
+    };
+    self.env.resume.push(Arc::new(p));
+    self.last_resume = Some(Instant::now());
+  }
+
+  /// Restore the state at the resume point `p`, which was recorded during the elaboration
+  /// of this file that produced `old`, with errors `errors`. As with [`rollback`](Self::rollback),
+  /// changes made after `p` to lisp references created before it are not undone.
+  fn resume(&mut self, p: &ResumePoint, old: &FrozenEnv, errors: &[ElabError]) {
+    // Safety: `old` is only read here; everything we need is copied out of it
+    let old = unsafe { old.thaw() };
+    self.env = Environment::resume(old, &p.env, p.idx);
+    self.env.resume = old.resume.iter().take_while(|q| q.idx <= p.idx).cloned().collect();
+    self.errors = errors[..p.errors].to_vec();
+    let r = &mut Remapper::identity(&self.env);
+    self.tactics = p.tactics.iter().map(|(&t, v)| (t, v.remap(r))).collect();
+    self.imported = p.imports.clone();
+    self.timeout = p.timeout;
+    self.fuel = p.fuel;
+    self.test_fuel = p.test_fuel;
+    self.stack_limit = p.stack_limit;
+    self.term_size_limit = p.term_size_limit;
+    self.proof_size_limit = p.proof_size_limit;
+    self.check_proofs = p.check_proofs;
+    self.reporting = p.reporting;
+    self.sequent_goals = p.sequent_goals;
+    self.normalize_tokens = p.normalize_tokens;
+    self.warn_precedence = p.warn_precedence;
+    self.backtrace = p.backtrace;
+    self.strict_overrides = p.strict_overrides;
+    self.tests = p.tests;
+    self.gensym = p.gensym;
+  }
+
+  /// Save the current environment, proof context and lisp globals, so that declarations
+  /// and metavariable assignments made after this can be undone with [`rollback`](Self::rollback).
+  #[must_use] pub fn checkpoint(&self) -> Checkpoint {
+    let refs = self.lc.goals.iter().chain(&self.lc.mvars)
+      .filter_map(|e| e.as_lref(|m| (e.clone(), m.get_weak().clone())))
+      .collect();
+    Checkpoint {env: self.env.snapshot(), lc: self.lc.clone(), refs}
+  }
+
+  /// Restore the state saved in the checkpoint `c`, which must have been made by this
+  /// elaborator. Atoms created since the checkpoint are not removed, but they lose
+  /// any definitions they have gained. The spans referring to the sorts, terms and
+  /// theorems that were removed are dropped too.
+  pub fn rollback(&mut self, c: &Checkpoint) {
+    fn coe_ok(c: &Coe, sorts: usize, terms: usize) -> bool {
+      match *c {
+        Coe::One(_, t) => (t.0 as usize) < terms,
+        Coe::Trans(ref c1, s, ref c2) =>
+          (s.0 as usize) < sorts && coe_ok(c1, sorts, terms) && coe_ok(c2, sorts, terms)
+      }
+    }
+    self.env.restore(&c.env);
+    let (sorts, terms, thms) = (self.sorts.len(), self.terms.len(), self.thms.len());
+    self.spans.retain(|k| match *k {
+      ObjectKind::Sort(s) => (s.0 as usize) < sorts,
+      ObjectKind::Term(t, _) => (t.0 as usize) < terms,
+      ObjectKind::Thm(t) => (t.0 as usize) < thms,
+      _ => true
+    }, |h| match h {
+      Hint::Sort(s) => (s.0 as usize) < sorts,
+      Hint::Coe(c) => coe_ok(c, sorts, terms),
+    });
+    self.lc.clone_from(&c.lc);
+    for (e, w) in &c.refs {
+      e.as_lref(|m| *m.get_mut_weak() = w.clone());
+    }
+  }
+
   fn name_of(&mut self, stmt: &Stmt) -> LispVal {
     match &stmt.k {
       StmtKind::Annot(_, s) => self.name_of(s),
@@ -305,7 +582,7 @@ impl Elaborator {
     let a = self.env.get_atom(self.ast.span(n.id));
     let term = self.term(a).ok_or_else(|| ElabError::new_e(n.id, "term not declared"))?;
     self.spans.insert(n.id, ObjectKind::Term(term, n.id));
-    let tk: ArcString = self.span(n.c.trim).into();
+    let tk = self.token_str(n.c.trim);
     let (rassoc, nargs, lits) = match n.k {
       SimpleNotaKind::Prefix => {
         let nargs = self.terms[term].args.len();
@@ -354,8 +631,40 @@ impl Elaborator {
     self.add_coe(s1, s2, fsp, t)
   }
 
+  /// Get the notation token at `tk`, normalized to NFC if token normalization is enabled.
+  fn token_str(&self, tk: Span) -> ArcString {
+    let s = self.span(tk);
+    if self.normalize_tokens && !self.mm0_mode {
+      if let Some(s) = normalize_token(s) { return s.into() }
+    }
+    s.into()
+  }
+
+  /// Warn about non-normalized tokens and tokens mixing letters from different scripts,
+  /// which are easily confused with other tokens that look the same.
+  fn check_token(&mut self, tk: Span) {
+    let ast = self.ast.clone();
+    let s = ast.span(tk);
+    if let Some(t) = normalize_token(s) {
+      let msg = if self.normalize_tokens && !self.mm0_mode {
+        format!("token '{}' is not in NFC normal form, and has been normalized to '{}'",
+          String::from_utf8_lossy(s), t)
+      } else {
+        format!("token '{}' is not in NFC normal form (the NFC form is '{}')",
+          String::from_utf8_lossy(s), t)
+      };
+      self.report(ElabError::warn(tk, msg))
+    }
+    if let Some((a, b)) = mixed_scripts(s) {
+      let msg = format!("token '{}' mixes {} and {} letters, \
+        and may be confused with a similar looking token", String::from_utf8_lossy(s), a, b);
+      self.report(ElabError::warn(tk, msg))
+    }
+  }
+
   fn add_const(&mut self, tk: Span, p: Prec) -> Result<()> {
-    let s = self.span(tk).into();
+    self.check_token(tk);
+    let s = self.token_str(tk);
     let fsp = self.fspan(tk);
     self.pe.add_const(s, fsp, p).map_err(|r| ElabError::with_info(tk,
       "constant already declared with a different precedence".into(),
@@ -422,7 +731,7 @@ impl Elaborator {
           };
           let lits = vec![
             ELiteral::Var(get_var(var)?, bump(rassoc.unwrap_or(false), cnst.fmla.0, prec)?),
-            ELiteral::Const(self.span(cnst.trim).into())];
+            ELiteral::Const(self.token_str(cnst.trim))];
           (lits, rassoc, true, cnst, prec)
         }
       }
@@ -433,7 +742,7 @@ impl Elaborator {
     while let Some(lit) = it.next() {
       match *lit {
         ALiteral::Const(ref cnst, prec) => {
-          lits.push(ELiteral::Const(self.span(cnst.trim).into()));
+          lits.push(ELiteral::Const(self.token_str(cnst.trim)));
           self.add_const(cnst.trim, prec)?;
         }
         ALiteral::Var(var) => {
@@ -463,7 +772,7 @@ impl Elaborator {
           "variable not used in notation"))
       }
     }
-    let s: ArcString = self.span(tk.trim).into();
+    let s = self.token_str(tk.trim);
     let info = NotaInfo { span: self.fspan(nota.id), term, nargs, rassoc, lits };
     if infix { self.pe.add_infix(s.clone(), info) }
     else { self.pe.add_prefix(s.clone(), info) }
@@ -477,6 +786,26 @@ impl Elaborator {
     if val.is_def() {self.print_lisp(e.span, &val)}
     Ok(())
   }
+
+  /// Run the tests in a `@test do { e1 e2 ... };` block, if we are in test mode (otherwise
+  /// the block is skipped). Each expression is a separate test, which fails if it throws
+  /// an error or returns `#f`.
+  fn elab_test(&mut self, es: &[SExpr]) {
+    if !self.test_mode {return}
+    for e in es {
+      match self.eval_lisp(e) {
+        Ok(v) if v.as_bool() == Some(false) => {
+          self.tests.1 += 1;
+          self.report(ElabError::new_e(e.span, "test failed: returned #f"))
+        }
+        Ok(_) => self.tests.0 += 1,
+        Err(err) => {
+          self.tests.1 += 1;
+          self.report(err)
+        }
+      }
+    }
+  }
 }
 
 /// The result type of [`Elaborator::elab_stmt`].
@@ -498,6 +827,7 @@ impl Elaborator {
     }
 
     self.cur_timeout = self.timeout.and_then(|d| Instant::now().checked_add(d));
+    self.cur_fuel = self.fuel;
     self.spans.set_stmt(span);
     match &stmt.k {
       &StmtKind::Sort(sp, sd) => {
@@ -520,6 +850,13 @@ impl Elaborator {
         for e in es { self.parse_and_print(e, mem::take(&mut doc))? }
       }
       StmtKind::Annot(e, s) => {
+        // `@test do` is handled here, unless the user has defined their own `annotate`
+        if let (SExprKind::Atom(ast::Atom::Ident), StmtKind::Do(es)) = (&e.k, &s.k) {
+          if self.ast.span(e.span) == b"test" && self.data[AtomID::ANNOTATE].lisp.is_none() {
+            self.elab_test(es);
+            return Ok(ElabStmt::Ok)
+          }
+        }
         let v = self.eval_lisp(e)?;
         self.elab_stmt(doc, s, span)?;
         let ann = match &self.data[AtomID::ANNOTATE].lisp {
@@ -566,9 +903,15 @@ pub enum ElabResult<T> {
 /// - `report_upstream_errors`: If true, an error will be reported if a file in an import itself
 ///   has an error. This can be disabled to avoid reporting the same error many times.
 ///
-/// - `_old`: The last successful parse of the same file, used for incremental elaboration.
-///   A value of `Some((idx, errs, env))` means that the new file first differs from the
-///   old one at `idx`, and the last parse produced environment `env` with errors `errs`.
+/// - `incremental`: If true, [`ResumePoint`]s are recorded in the final environment,
+///   and `old` is used to skip the unchanged statements at the start of the file.
+///
+/// - `old`: The last successful parse of the same file, used for incremental elaboration.
+///   A value of `Some((idx, errs, env))` means that the first `idx` statements of the new
+///   file are the same as in the old one, and the last parse produced environment `env`
+///   with errors `errs`. Elaboration resumes from the last resume point in `env` before
+///   statement `idx`, provided the files imported before it are unchanged (that is,
+///   their elaboration results are the same objects as before).
 ///
 /// - `mk`: A function which is called when an `import` is encountered, with the [`FileRef`] of
 ///   the file being imported. It sets up a channel and passes the [`Receiver`] end here,
@@ -591,7 +934,7 @@ pub enum ElabResult<T> {
 pub fn elaborate<T: Send>(
   ast: &Arc<AST>, path: FileRef,
   mm0_mode: bool, check_proofs: bool, report_upstream_errors: bool, cancel: Arc<AtomicBool>,
-  _: Option<(usize, Option<Arc<[ElabError]>>, FrozenEnv)>,
+  incremental: bool, old: Option<(usize, Option<Arc<[ElabError]>>, FrozenEnv)>,
   mut mk: impl FnMut(FileRef) -> StdResult<Receiver<ElabResult<T>>, BoxError>
 ) -> impl Future<Output=(Option<ArcList<FileRef>>, Vec<T>, Vec<ElabError>, FrozenEnv)> + Send {
 
@@ -603,6 +946,11 @@ pub fn elaborate<T: Send>(
   enum UnfinishedStmt<T> {
     None,
     Import(Span, FileRef, Receiver<ElabResult<T>>),
+    /// Waiting for the files imported before a resume point (given by its index in the
+    /// resume points of the old environment), to check that they have not changed.
+    /// The last field holds the results received so far.
+    Resume(usize, FrozenEnv, Option<Arc<[ElabError]>>,
+      Vec<(Span, FileRef, StdResult<ElabResult<T>, Canceled>)>),
   }
 
   struct ElabFutureInner<T> {
@@ -629,6 +977,40 @@ pub fn elaborate<T: Send>(
       'l: loop {
         match progress {
           UnfinishedStmt::None => {},
+          UnfinishedStmt::Resume(i, old, _, done) => {
+            let p = &old.resume_points()[*i];
+            while let Some((sp, env)) = p.imports.get(done.len()) {
+              let res = match recv.get_mut(sp) {
+                None => break,
+                Some((_, other)) => ready!(unsafe { Pin::new_unchecked(other) }.poll(cx)),
+              };
+              let unchanged = matches!(&res, Ok(ElabResult::Ok(_, _, env2)) if env2.ptr_eq(env));
+              let (file, _) = recv.remove(sp).expect("just polled");
+              done.push((*sp, file, res));
+              if !unchanged { break }
+            }
+            if let UnfinishedStmt::Resume(i, old, errors, done) =
+              mem::replace(progress, UnfinishedStmt::None) {
+              let p = &old.resume_points()[i];
+              let unchanged = done.len() == p.imports.len() &&
+                done.iter().zip(&p.imports).all(|((_, _, res), (_, env))|
+                  matches!(res, Ok(ElabResult::Ok(_, _, env2)) if env2.ptr_eq(env)));
+              if unchanged {
+                elab.resume(p, &old, errors.as_deref().unwrap_or(&[]));
+                for (_, _, res) in done {
+                  if let Ok(ElabResult::Ok(t, _, _)) = res { toks.push(t) }
+                }
+                *idx = p.idx;
+              } else {
+                // Elaborate from the start, passing on the results we already have
+                for (sp, file, res) in done {
+                  let (send, other) = channel();
+                  if let Ok(res) = res { let _ = send.send(res); }
+                  recv.insert(sp, (file, other));
+                }
+              }
+            }
+          }
           UnfinishedStmt::Import(sp, p, other) => {
             match ready!(unsafe { Pin::new_unchecked(other) }.poll(cx)) {
               Ok(ElabResult::Ok(t, errors, env)) => {
@@ -649,6 +1031,7 @@ pub fn elaborate<T: Send>(
                 }
                 let r = elab.env.merge(&env, *sp, &mut elab.errors);
                 elab.catch(r);
+                elab.imported.push((*sp, env));
               }
               Ok(ElabResult::Canceled) => {
                 elab.report(ElabError::new_e(*sp, "canceled"));
@@ -660,8 +1043,10 @@ pub fn elaborate<T: Send>(
                 for p2 in &cyc2 { write!(&mut s, " -> {}", p2).unwrap() }
                 elab.report(ElabError::new_e(*sp, s));
                 if cyc.is_none() { *cyc = Some(cyc2) }
+                // Resume points after a failed import would not notice when it is fixed
+                elab.last_resume = None;
               }
-              Err(_) => {} // already handled
+              Err(_) => elab.last_resume = None, // already handled
             }
             *idx += 1;
           }
@@ -669,7 +1054,16 @@ pub fn elaborate<T: Send>(
         let ast = elab.ast.clone();
         while let Some(s) = ast.stmts.get(*idx) {
           if elab.cancel.load(Ordering::Relaxed) {break}
-          match elab.elab_stmt(String::new(), s, s.span) {
+          elab.defer_proofs = elab.jobs > 1 && !elab.mm0_mode && parallel::deferrable(s);
+          if !elab.defer_proofs { elab.check_deferred() }
+          elab.record_resume(*idx);
+          elab.profile.reset();
+          elab.checkpoints.clear();
+          let res = elab.elab_stmt(String::new(), s, s.span);
+          if let Some(msg) = elab.profile.explain(elab.format_env()) {
+            elab.report(ElabError::info(s.head(), msg))
+          }
+          match res {
             Ok(ElabStmt::Ok) => {}
             Ok(ElabStmt::Import(sp)) => {
               if let Some((file, recv)) = recv.remove(&sp) {
@@ -684,6 +1078,16 @@ pub fn elaborate<T: Send>(
           elab.push_spans();
           *idx += 1;
         }
+        if !elab.cancel.load(Ordering::Relaxed) { elab.check_deferred() }
+        if elab.test_mode && elab.tests != (0, 0) {
+          let (passed, failed) = elab.tests;
+          elab.report(ElabError::info(Span::default(),
+            format!("tests: {} passed, {} failed", passed, failed)))
+        }
+        if crate::get_profile_lisp() && !elab.lisp_profile.is_empty() {
+          let msg = elab.lisp_profile.report(elab.format_env());
+          elab.report(ElabError::info(Span::default(), msg))
+        }
         break
       }
       lisp::LispArena::uninstall_thread_local();
@@ -697,17 +1101,31 @@ pub fn elaborate<T: Send>(
   let mut recv = HashMap::new();
   let mut elab = Elaborator::new(ast.clone(), path, mm0_mode, check_proofs, cancel);
   elab.arena.install_thread_local();
+  let mut imports_ok = true;
   for &(sp, ref f) in &ast.imports {
     (|| -> Result<_> {
       let f = std::str::from_utf8(f).map_err(|e| ElabError::new_e(sp, e))?;
-      let path = elab.path.path().parent().map_or_else(|| PathBuf::from(f), |p| p.join(f));
-      let r: FileRef = path.canonicalize().map_err(|e| ElabError::new_e(sp, e))?.into();
+      let r: FileRef = crate::project::resolve_import(elab.path.path(), f)
+        .map_err(|e| ElabError::new_e(sp, e))?.into();
       let tok = mk(r.clone()).map_err(|e| ElabError::new_e(sp, e))?;
       recv.insert(sp, (r, tok));
       Ok(())
-    })().unwrap_or_else(|e| elab.report(e));
+    })().unwrap_or_else(|e| { imports_ok = false; elab.report(e) });
   }
   lisp::LispArena::uninstall_thread_local();
+  let progress = match old {
+    Some((n, errors, old)) if incremental => {
+      let nerrs = errors.as_ref().map_or(0, |es| es.len());
+      let p = old.resume_points().iter()
+        .rposition(|p| 0 < p.idx && p.idx <= n && p.errors <= nerrs);
+      match p {
+        Some(p) => UnfinishedStmt::Resume(p, old, errors, vec![]),
+        None => UnfinishedStmt::None,
+      }
+    }
+    _ => UnfinishedStmt::None,
+  };
+  if incremental && imports_ok { elab.last_resume = Some(Instant::now()) }
   ElabFuture(Some(ElabFutureInner {
     elab: FrozenElaborator(elab),
     toks: vec![],
@@ -715,6 +1133,101 @@ pub fn elaborate<T: Send>(
     recv,
     idx: 0,
     report_upstream_errors,
-    progress: UnfinishedStmt::None,
+    progress,
   }))
-}
\ No newline at end of file
+}
+
+/// Evaluate the `do` blocks in `ast` in the environment `env`, for `mm0-rs eval`. Other
+/// statements in `ast` are ignored. Evaluation stops at the first error. If `state` is
+/// provided, it is a proof state saved by `(save-state)` (see [`proof_state`]), which is
+/// restored before evaluation, for `mm0-rs load-state`.
+///
+/// # Returns
+///
+/// A pair `(res, errs)` where `res` is the value of the last expression, printed (or `None`
+/// if there was an error or no expression), and `errs` are the errors (and `display`
+/// messages) reported during evaluation.
+pub fn eval_do(ast: &Arc<AST>, path: FileRef, env: &FrozenEnv,
+  state: Option<&serde_json::Value>
+) -> (Option<String>, Vec<ElabError>) {
+  let mut elab = Elaborator::new(ast.clone(), path, false, crate::get_check_proofs(), Arc::default());
+  elab.arena.install_thread_local();
+  let r = elab.env.merge(env, Span::default(), &mut elab.errors);
+  elab.catch(r);
+  let mut res = None;
+  let stmts = match state.map(|state| elab.load_state(Span::default(), state)) {
+    Some(Err(e)) => { elab.report(ElabError::new_e(Span::default(), e)); &[][..] }
+    _ => &*ast.stmts
+  };
+  'l: for s in stmts {
+    if let StmtKind::Do(es) = &s.k {
+      elab.cur_timeout = elab.timeout.and_then(|d| Instant::now().checked_add(d));
+      elab.cur_fuel = elab.fuel;
+      for e in es {
+        match elab.eval_lisp(e) {
+          Ok(v) => res = Some(v),
+          Err(err) => { elab.report(err); res = None; break 'l }
+        }
+      }
+    }
+  }
+  let res = res.map(|v| format!("{}", elab.print(&v)));
+  lisp::LispArena::uninstall_thread_local();
+  elab.arena.clear();
+  (res, elab.errors)
+}
+
+/// The result of [`elaborate_snippet`].
+#[derive(Debug)]
+pub struct SnippetResult {
+  /// The environment extended with the declarations of the snippet. This can be passed
+  /// back to [`elaborate_snippet`] to elaborate a sequence of snippets.
+  pub env: FrozenEnv,
+  /// The values of the expressions in `do` blocks, printed, in order of evaluation
+  pub values: Vec<String>,
+  /// The parse and elaboration errors, and the `display` messages, in the snippet
+  pub errors: Vec<ElabError>,
+}
+
+/// Elaborate `text`, a sequence of MM1 statements (for example a single theorem, or a `do`
+/// block of lisp expressions), in the environment `env`. This is the entry point for
+/// programs that embed the elaborator, such as web playgrounds and grading scripts, which
+/// check one statement at a time against a library instead of compiling whole files.
+/// `path` is the name used for the snippet in the spans of the errors.
+///
+/// Each statement is elaborated as if it were at the end of the file that produced `env`,
+/// and an error in one statement does not stop the elaboration of the following ones.
+/// `import` statements are not supported, and are reported as errors.
+pub fn elaborate_snippet(text: &str, path: FileRef, env: &FrozenEnv) -> SnippetResult {
+  let (_, mut ast) = crate::parser::parse(Arc::new(LinedString::from(text.to_owned())), None);
+  let parse_errors = mem::take(&mut ast.errors);
+  let ast = Arc::new(ast);
+  let mut elab = Elaborator::new(ast.clone(), path, false, crate::get_check_proofs(), Arc::default());
+  for e in parse_errors { elab.report(e.into()) }
+  elab.arena.install_thread_local();
+  let r = elab.env.merge(env, Span::default(), &mut elab.errors);
+  elab.catch(r);
+  let mut values = vec![];
+  for s in &*ast.stmts {
+    if let StmtKind::Do(es) = &s.k {
+      elab.cur_timeout = elab.timeout.and_then(|d| Instant::now().checked_add(d));
+      elab.cur_fuel = elab.fuel;
+      for e in es {
+        match elab.eval_lisp(e) {
+          Ok(v) => values.push(format!("{}", elab.print(&v))),
+          Err(err) => { elab.report(err); break }
+        }
+      }
+    } else {
+      match elab.elab_stmt(String::new(), s, s.span) {
+        Ok(ElabStmt::Ok) => {}
+        Ok(ElabStmt::Import(sp)) => elab.report(ElabError::new_e(sp, "imports are not supported here")),
+        Err(e) => elab.report(e)
+      }
+    }
+    elab.push_spans();
+  }
+  lisp::LispArena::uninstall_thread_local();
+  elab.arena.clear();
+  SnippetResult {env: FrozenEnv::new(elab.env), values, errors: elab.errors}
+}
@@ -0,0 +1,485 @@
+//! The `(cong)` tactic, which proves equalities by congruence closure.
+//!
+//! Equality relations and congruence lemmas are registered in the environment using
+//! `register-eq` and `register-congr`. An equality relation `eq` on a sort `s` comes with
+//! three lemmas:
+//!
+//! * reflexivity: `eq x x`
+//! * symmetry: `(h: eq x y): eq y x`
+//! * transitivity: `(h1: eq x y) (h2: eq y z): eq x z`
+//!
+//! and a congruence lemma for a term constructor `t` has the form
+//! `(h1: eq1 a1 b1) ... (hn: eqn an bn): eq (t a1 ... an) (t b1 ... bn)`,
+//! where each `eqi` is the registered equality for the sort of the `i`th argument.
+//! An argument can instead be shared between the two sides (`ai` = `bi`), in which case
+//! there is no hypothesis for it; this is needed for bound variables.
+//!
+//! Given a goal `eq a b`, the tactic builds the congruence closure of the equalities
+//! among the hypotheses of the current proof, and if `a` and `b` end up in the same
+//! class, it reconstructs a proof of the goal from the hypotheses and lemmas.
+
+use std::collections::{HashMap, VecDeque, hash_map::Entry};
+use super::{Elaborator, environment::{AtomID, Environment, ExprNode,
+  Remap, Remapper, SortID, TermID, Thm, ThmID}};
+use super::lisp::{LispKind, LispVal};
+
+/// A theorem used by the `(cong)` tactic, together with the instructions for applying it.
+/// The lemma is applied to a list of expressions (the "roles", for example `[x, y, z]` for a
+/// transitivity lemma) and a list of subproofs.
+#[derive(Clone, Debug, DeepSizeOf)]
+pub struct EqLemma {
+  /// The theorem to apply
+  pub thm: ThmID,
+  /// For each binder of the theorem, the index of the role it is instantiated with
+  pub args: Box<[usize]>,
+  /// For each hypothesis of the theorem, the index of the subproof that proves it
+  pub hyps: Box<[usize]>,
+}
+
+/// An equality relation registered with `register-eq`.
+#[derive(Clone, Debug, DeepSizeOf)]
+pub struct EqRel {
+  /// The sort of the arguments of the relation
+  pub sort: SortID,
+  /// The reflexivity lemma `eq x x`, with roles `[x]`
+  pub refl: EqLemma,
+  /// The symmetry lemma `eq x y -> eq y x`, with roles `[x, y]`
+  pub symm: EqLemma,
+  /// The transitivity lemma `eq x y -> eq y z -> eq x z`, with roles `[x, y, z]`
+  pub trans: EqLemma,
+}
+
+/// The equality relations and congruence lemmas used by the `(cong)` tactic.
+#[derive(Clone, Debug, Default, DeepSizeOf)]
+pub struct EqLemmas {
+  /// The registered equality relations, indexed by their term constructor
+  pub eqs: HashMap<TermID, EqRel>,
+  /// The equality relation on each sort
+  pub sorts: HashMap<SortID, TermID>,
+  /// The congruence lemmas, indexed by term constructor. For a term with `n` arguments
+  /// the roles are the `n` arguments on the left followed by the `n` arguments on the right,
+  /// and the subproofs are indexed by argument.
+  pub congr: HashMap<TermID, EqLemma>,
+  /// The transport lemmas `eq x y -> y -> x` used by the `(rw)` tactic, indexed by the
+  /// equality. The roles are `[x, y]`, and the subproofs are `[eq x y, y]`.
+  pub mp: HashMap<TermID, EqLemma>,
+}
+
+impl Remap for EqLemma {
+  type Target = Self;
+  fn remap(&self, r: &mut Remapper) -> Self {
+    EqLemma {thm: self.thm.remap(r), args: self.args.clone(), hyps: self.hyps.clone()}
+  }
+}
+
+impl Remap for EqRel {
+  type Target = Self;
+  fn remap(&self, r: &mut Remapper) -> Self {
+    EqRel {
+      sort: self.sort.remap(r),
+      refl: self.refl.remap(r),
+      symm: self.symm.remap(r),
+      trans: self.trans.remap(r),
+    }
+  }
+}
+
+impl EqLemmas {
+  /// Add the lemmas registered in an imported environment, overriding the current ones
+  /// for the same terms and sorts.
+  pub fn merge(&mut self, other: &Self, r: &mut Remapper) {
+    for (t, rel) in &other.eqs { self.eqs.insert(t.remap(r), rel.remap(r)); }
+    for (s, t) in &other.sorts { self.sorts.insert(s.remap(r), t.remap(r)); }
+    for (t, l) in &other.congr { self.congr.insert(t.remap(r), l.remap(r)); }
+    for (t, l) in &other.mp { self.mp.insert(t.remap(r), l.remap(r)); }
+  }
+}
+
+impl EqLemma {
+  /// Returns true if argument `i` of a term with this congruence lemma must be the same
+  /// on both sides.
+  pub(crate) fn fixed(&self, i: usize) -> bool { !self.hyps.contains(&i) }
+}
+
+/// Follow the heap references in an expression of the theorem `td`, stopping at variables.
+pub(crate) fn deref<'a>(td: &'a Thm, mut e: &'a ExprNode) -> &'a ExprNode {
+  while let ExprNode::Ref(i) = *e {
+    if i < td.args.len() {break}
+    e = &td.heap[i]
+  }
+  e
+}
+
+/// If `e` is a variable of the theorem `td`, get its index.
+pub(crate) fn as_var(td: &Thm, e: &ExprNode) -> Option<usize> {
+  match *deref(td, e) {
+    ExprNode::Ref(i) if i < td.args.len() => Some(i),
+    _ => None
+  }
+}
+
+/// If `e` is an application of the binary term `eq` to two variables, get the variables.
+fn as_eq(td: &Thm, eq: TermID, e: &ExprNode) -> Option<(usize, usize)> {
+  match deref(td, e) {
+    ExprNode::App(t, es) if *t == eq && es.len() == 2 =>
+      Some((as_var(td, &es[0])?, as_var(td, &es[1])?)),
+    _ => None
+  }
+}
+
+/// Assign binder `v` to `role`, returning false if it is already assigned to another role.
+fn bind(args: &mut [Option<usize>], v: usize, role: usize) -> bool {
+  *args[v].get_or_insert(role) == role
+}
+
+/// Check that `td` has the form `hyps -> ret`, where each hypothesis and the conclusion
+/// is `eq` applied to two roles, and determine the roles of the binders.
+/// The hypotheses may come in any order.
+fn match_lemma(td: &Thm, thm: ThmID, eq: TermID,
+  hyps: &[(usize, usize)], ret: (usize, usize)
+) -> Option<EqLemma> {
+  if td.hyps.len() != hyps.len() {return None}
+  let mut args = vec![None; td.args.len()];
+  let (x, y) = as_eq(td, eq, &td.ret)?;
+  if !(bind(&mut args, x, ret.0) && bind(&mut args, y, ret.1)) {return None}
+  let mut used = vec![false; hyps.len()];
+  let mut hyp_roles = Vec::with_capacity(hyps.len());
+  for (_, h) in &*td.hyps {
+    let (x, y) = as_eq(td, eq, h)?;
+    let ok = |args: &[Option<usize>], v: usize, role: usize| args[v].map_or(true, |r| r == role);
+    let j = (0..hyps.len()).find(|&j| {
+      let (rx, ry) = hyps[j];
+      !used[j] && ok(&args, x, rx) && ok(&args, y, ry) && (x != y || rx == ry)
+    })?;
+    used[j] = true;
+    bind(&mut args, x, hyps[j].0);
+    bind(&mut args, y, hyps[j].1);
+    hyp_roles.push(j);
+  }
+  Some(EqLemma {
+    thm,
+    args: args.into_iter().collect::<Option<_>>()?,
+    hyps: hyp_roles.into(),
+  })
+}
+
+impl Environment {
+  /// Register `eq` as an equality relation for the `(cong)` tactic, with the given
+  /// reflexivity, symmetry and transitivity lemmas. This replaces any equality
+  /// previously registered for the same sort.
+  pub fn register_eq(&mut self, eq: TermID, refl: ThmID, symm: ThmID, trans: ThmID) -> Result<(), String> {
+    let td = &self.terms[eq];
+    let sort = match *td.args {
+      [(_, t1), (_, t2)] if !t1.bound() && !t2.bound() && t1.sort() == t2.sort() => t1.sort(),
+      _ => return Err(format!("register-eq: '{}' is not a binary relation on a sort",
+        self.data[td.atom].name))
+    };
+    let get = |thm: ThmID, kind: &str, hyps: &[(usize, usize)], ret| {
+      let td = &self.thms[thm];
+      match_lemma(td, thm, eq, hyps, ret).ok_or_else(|| format!(
+        "register-eq: '{}' does not have the form of a {} lemma", self.data[td.atom].name, kind))
+    };
+    let rel = EqRel {
+      sort,
+      refl: get(refl, "reflexivity", &[], (0, 0))?,
+      symm: get(symm, "symmetry", &[(0, 1)], (1, 0))?,
+      trans: get(trans, "transitivity", &[(0, 1), (1, 2)], (0, 2))?,
+    };
+    self.eqs.sorts.insert(sort, eq);
+    self.eqs.eqs.insert(eq, rel);
+    Ok(())
+  }
+
+  /// Register `thm` as the congruence lemma for the term constructor in its conclusion,
+  /// for the `(cong)` tactic. Returns the term constructor.
+  pub fn register_congr(&mut self, thm: ThmID) -> Result<TermID, String> {
+    let td = &self.thms[thm];
+    let name = &self.data[td.atom].name;
+    let err = |msg: &str| format!("register-congr: '{}' {}", name, msg);
+    let (eq, lhs, rhs) = match deref(td, &td.ret) {
+      ExprNode::App(eq, es) if es.len() == 2 && self.eqs.eqs.contains_key(eq) => (*eq, &es[0], &es[1]),
+      _ => return Err(err("does not prove a registered equality"))
+    };
+    let (t, ls, rs) = match (deref(td, lhs), deref(td, rhs)) {
+      (ExprNode::App(t, ls), ExprNode::App(t2, rs)) if t == t2 => (*t, ls, rs),
+      _ => return Err(err("does not prove an equality between two applications of the same term"))
+    };
+    let targs = &self.terms[t].args;
+    if self.eqs.sorts.get(&self.terms[t].ret.0) != Some(&eq) {
+      return Err(err("does not use the registered equality for the sort of the term"))
+    }
+    let n = ls.len();
+    let mut args = vec![None; td.args.len()];
+    let mut fixed = Vec::with_capacity(n);
+    for (i, (l, r)) in ls.iter().zip(&**rs).enumerate() {
+      let (l, r) = as_var(td, l).zip(as_var(td, r))
+        .ok_or_else(|| err("must have variables as the arguments of the term"))?;
+      fixed.push(l == r);
+      if !(bind(&mut args, l, i) && bind(&mut args, r, if l == r {i} else {n + i})) {
+        return Err(err("must have distinct variables as the arguments of the term"))
+      }
+    }
+    let mut hyps = Vec::with_capacity(td.hyps.len());
+    for (_, h) in &*td.hyps {
+      let i = match deref(td, h) {
+        ExprNode::App(eq, es) if es.len() == 2 => {
+          let (l, r) = as_var(td, &es[0]).zip(as_var(td, &es[1]))
+            .ok_or_else(|| err("has a hypothesis that is not an equality of variables"))?;
+          match (args[l], args[r]) {
+            (Some(i), Some(j)) if i < n && j == n + i && !hyps.contains(&i) &&
+              self.eqs.sorts.get(&targs[i].1.sort()) == Some(eq) => i,
+            _ => return Err(err("has a hypothesis that is not an equality of corresponding arguments"))
+          }
+        }
+        _ => return Err(err("has a hypothesis that is not an equality of variables"))
+      };
+      hyps.push(i)
+    }
+    let args = args.into_iter().collect::<Option<Box<[_]>>>()
+      .ok_or_else(|| err("has variables that do not appear in the conclusion"))?;
+    if (0..n).any(|i| !fixed[i] && !hyps.contains(&i)) {
+      return Err(err("is missing a hypothesis for an argument"))
+    }
+    self.eqs.congr.insert(t, EqLemma {thm, args, hyps: hyps.into()});
+    Ok(t)
+  }
+
+  /// Register `thm`, of the form `(h1: eq x y) (h2: y): x` for a registered equality `eq`,
+  /// as the lemma the `(rw)` tactic uses to prove a goal from its rewritten form.
+  /// Returns the equality.
+  pub fn register_mp(&mut self, thm: ThmID) -> Result<TermID, String> {
+    let td = &self.thms[thm];
+    let err = || format!("register-mp: '{}' does not have the form \
+      '(h1: eq x y) (h2: y): x' for a registered equality", self.data[td.atom].name);
+    let x = as_var(td, &td.ret).ok_or_else(err)?;
+    let (eq, i, y) = td.hyps.iter().enumerate().find_map(|(i, (_, h))| match deref(td, h) {
+      ExprNode::App(eq, es) if es.len() == 2 && self.eqs.eqs.contains_key(eq) &&
+        as_var(td, &es[0]) == Some(x) => Some((*eq, i, as_var(td, &es[1])?)),
+      _ => None
+    }).ok_or_else(err)?;
+    if td.args.len() != 2 || td.hyps.len() != 2 || x == y ||
+      as_var(td, &td.hyps[1 - i].1) != Some(y) {return Err(err())}
+    let mut args = [0; 2];
+    args[y] = 1;
+    let hyps = if i == 0 {[0, 1]} else {[1, 0]};
+    self.eqs.mp.insert(eq, EqLemma {thm, args: args.into(), hyps: hyps.into()});
+    Ok(eq)
+  }
+}
+
+/// Apply the lemma `l` to the expressions `roles` and the subproofs `subproofs`.
+pub(crate) fn apply_lemma(env: &Environment, l: &EqLemma, roles: &[LispVal], subproofs: &[LispVal]) -> LispVal {
+  let mut args = vec![LispVal::atom(env.thms[l.thm].atom)];
+  args.extend(l.args.iter().map(|&r| roles[r].clone()));
+  args.extend(l.hyps.iter().map(|&j| subproofs[j].clone()));
+  LispVal::list(args)
+}
+
+/// A node in the congruence closure graph, which is a hash-consed expression.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Node {
+  Var(AtomID),
+  App(TermID, Box<[usize]>),
+}
+
+/// The reason for an edge in the congruence closure graph.
+enum Reason {
+  /// The hypothesis with this name proves `eq a b`
+  Hyp(AtomID),
+  /// `a` and `b` are applications of the same term to equal arguments
+  Congr,
+}
+
+/// The congruence closure graph. Every union of two classes adds an edge, so the
+/// edges form a spanning forest of the classes, and there is a unique path between
+/// any two equal nodes. An equality is explained by following this path.
+struct Closure<'a> {
+  env: &'a Environment,
+  nodes: Vec<Node>,
+  /// The expression for each node, used in the constructed proof
+  exprs: Vec<LispVal>,
+  map: HashMap<Node, usize>,
+  /// The union-find parent of each node
+  parent: Vec<usize>,
+  edges: Vec<(usize, usize, Reason)>,
+  /// The edges touching each node
+  adj: Vec<Vec<usize>>,
+}
+
+impl<'a> Closure<'a> {
+  fn new(env: &'a Environment) -> Self {
+    Closure {
+      env, nodes: vec![], exprs: vec![], map: HashMap::new(),
+      parent: vec![], edges: vec![], adj: vec![],
+    }
+  }
+
+  /// Add an expression to the graph, returning its node.
+  fn add(&mut self, e: &LispVal) -> Result<usize, String> {
+    let node = e.unwrapped(|k| -> Result<_, String> { match k {
+      &LispKind::Atom(a) => Ok(Node::Var(a)),
+      LispKind::List(es) if !es.is_empty() => {
+        let t = es[0].as_atom().and_then(|a| self.env.term(a))
+          .ok_or("cong: expected a term constructor")?;
+        let args = es[1..].iter().map(|e| self.add(e)).collect::<Result<_, _>>()?;
+        Ok(Node::App(t, args))
+      }
+      LispKind::MVar(_, _) => Err("cong: the expression contains metavariables".into()),
+      _ => Err("cong: expected an expression".into()),
+    }})?;
+    Ok(match self.map.entry(node) {
+      Entry::Occupied(e) => *e.get(),
+      Entry::Vacant(v) => {
+        let i = self.nodes.len();
+        self.nodes.push(v.key().clone());
+        v.insert(i);
+        self.exprs.push(e.clone());
+        self.parent.push(i);
+        self.adj.push(vec![]);
+        i
+      }
+    })
+  }
+
+  fn find(&self, mut i: usize) -> usize {
+    while self.parent[i] != i { i = self.parent[i] }
+    i
+  }
+
+  fn union(&mut self, a: usize, b: usize, reason: Reason) {
+    let (ra, rb) = (self.find(a), self.find(b));
+    if ra == rb {return}
+    let k = self.edges.len();
+    self.edges.push((a, b, reason));
+    self.adj[a].push(k);
+    self.adj[b].push(k);
+    self.parent[ra] = rb;
+  }
+
+  /// Merge applications of the same term to equal arguments, until nothing changes.
+  fn close(&mut self) {
+    loop {
+      let mut sigs = HashMap::new();
+      let mut new = vec![];
+      for (i, n) in self.nodes.iter().enumerate() {
+        if let Node::App(t, args) = n {
+          if let Some(l) = self.env.eqs.congr.get(t) {
+            let sig: Vec<usize> = args.iter().enumerate()
+              .map(|(j, &a)| if l.fixed(j) {a} else {self.find(a)}).collect();
+            match sigs.entry((*t, sig)) {
+              Entry::Vacant(e) => {e.insert(i);}
+              Entry::Occupied(e) => if self.find(*e.get()) != self.find(i) {new.push((*e.get(), i))}
+            }
+          }
+        }
+      }
+      if new.is_empty() {break}
+      for (a, b) in new { self.union(a, b, Reason::Congr) }
+    }
+  }
+
+  /// Apply the lemma `l` to the expressions of the nodes `roles` and the subproofs `subproofs`.
+  fn apply(&self, l: &EqLemma, roles: &[usize], subproofs: &[LispVal]) -> LispVal {
+    let roles: Vec<_> = roles.iter().map(|&i| self.exprs[i].clone()).collect();
+    apply_lemma(self.env, l, &roles, subproofs)
+  }
+
+  /// The path of edges from `a` to `b`, each with a flag that is true if the edge
+  /// is traversed in its original direction.
+  fn path(&self, a: usize, b: usize) -> Vec<(usize, bool)> {
+    let mut prev = HashMap::new();
+    let mut queue = VecDeque::from(vec![a]);
+    while let Some(i) = queue.pop_front() {
+      if i == b {break}
+      for &k in &self.adj[i] {
+        let (x, y, _) = self.edges[k];
+        let (j, fwd) = if x == i {(y, true)} else {(x, false)};
+        if j != a && !prev.contains_key(&j) {
+          prev.insert(j, (i, k, fwd));
+          queue.push_back(j)
+        }
+      }
+    }
+    let mut path = vec![];
+    let mut i = b;
+    while i != a {
+      let (j, k, fwd) = prev[&i];
+      path.push((k, fwd));
+      i = j
+    }
+    path.reverse();
+    path
+  }
+
+  /// Construct a proof of `eq a b`, where `a` and `b` are in the same class.
+  fn explain(&self, a: usize, b: usize, eq: TermID) -> Result<LispVal, String> {
+    let rel = &self.env.eqs.eqs[&eq];
+    if a == b { return Ok(self.apply(&rel.refl, &[a], &[])) }
+    let mut proof: Option<LispVal> = None;
+    let mut cur = a;
+    for (k, fwd) in self.path(a, b) {
+      let (x, y, ref reason) = self.edges[k];
+      let next = if fwd {y} else {x};
+      let step = match reason {
+        &Reason::Hyp(h) if fwd => LispVal::atom(h),
+        &Reason::Hyp(h) => self.apply(&rel.symm, &[x, y], &[LispVal::atom(h)]),
+        Reason::Congr => self.explain_congr(cur, next)?,
+      };
+      proof = Some(match proof {
+        None => step,
+        Some(p) => self.apply(&rel.trans, &[a, cur, next], &[p, step]),
+      });
+      cur = next
+    }
+    Ok(proof.expect("a != b"))
+  }
+
+  /// Construct a proof of `eq (t as) (t bs)` by congruence, where the arguments are equal.
+  fn explain_congr(&self, a: usize, b: usize) -> Result<LispVal, String> {
+    let (t, xs, ys) = match (&self.nodes[a], &self.nodes[b]) {
+      (Node::App(t, xs), Node::App(_, ys)) => (*t, xs, ys),
+      _ => unreachable!("congruence between non-applications"),
+    };
+    let l = &self.env.eqs.congr[&t];
+    let mut subproofs = Vec::with_capacity(xs.len());
+    for (i, (&x, &y)) in xs.iter().zip(&**ys).enumerate() {
+      subproofs.push(if l.fixed(i) {LispVal::undef()} else {
+        let s = self.env.terms[t].args[i].1.sort();
+        let eq = *self.env.eqs.sorts.get(&s).ok_or_else(|| format!(
+          "cong: no equality is registered for sort '{}'", self.env.sorts[s].name))?;
+        self.explain(x, y, eq)?
+      })
+    }
+    let roles: Vec<usize> = xs.iter().chain(&**ys).copied().collect();
+    Ok(self.apply(l, &roles, &subproofs))
+  }
+}
+
+impl Elaborator {
+  /// Prove the goal `ty`, which should be a registered equality `eq a b`, by congruence
+  /// closure over the equalities among the hypotheses of the current proof.
+  /// Returns a proof term that can be passed to `refine`.
+  pub fn cong_proof(&self, ty: &LispVal) -> Result<LispVal, String> {
+    let mut cc = Closure::new(&self.env);
+    let as_eq = |e: &LispVal| e.unwrapped(|k| match k {
+      LispKind::List(es) if es.len() == 3 => es[0].as_atom()
+        .and_then(|a| self.env.term(a))
+        .filter(|t| self.env.eqs.eqs.get(t)
+          .map_or(false, |rel| self.env.eqs.sorts.get(&rel.sort) == Some(t)))
+        .map(|t| (t, es[1].clone(), es[2].clone())),
+      _ => None
+    });
+    let (eq, lhs, rhs) = as_eq(ty).ok_or("cong: the goal is not a registered equality")?;
+    let (lhs, rhs) = (cc.add(&lhs)?, cc.add(&rhs)?);
+    for (h, e, _) in &self.lc.proof_order {
+      if let Some((_, x, y)) = as_eq(e) {
+        if let (Ok(x), Ok(y)) = (cc.add(&x), cc.add(&y)) { cc.union(x, y, Reason::Hyp(*h)) }
+      }
+    }
+    cc.close();
+    if cc.find(lhs) != cc.find(rhs) {
+      return Err("cong: the goal does not follow from the hypotheses by congruence".into())
+    }
+    cc.explain(lhs, rhs, eq)
+  }
+}
@@ -12,8 +12,10 @@ use std::hash::Hash;
 use std::collections::HashMap;
 use super::{ElabError, BoxError, spans::Spans, FrozenEnv, FrozenLispVal};
 use crate::util::{ArcString, FileRef, FileSpan, HashMapExt, Span};
-use super::lisp::{LispVal, Syntax};
+use super::lisp::{BuiltinProc, LispVal, Syntax};
 use super::frozen::{FrozenLispKind, FrozenLispRef};
+use super::cong::EqLemmas;
+use super::norm_num::NumLemmas;
 pub use crate::parser::ast::{Modifiers, Prec};
 
 macro_rules! id_wrapper {
@@ -253,6 +255,27 @@ impl ProofNode {
       }
     }
   }
+
+  /// Call `f` on every theorem directly referenced by this proof node
+  /// (not including theorems referenced through [`Ref`](ProofNode::Ref) nodes).
+  pub fn thms(&self, f: &mut impl FnMut(ThmID)) {
+    match self {
+      ProofNode::Ref(_) | ProofNode::Dummy(..) => {}
+      ProofNode::Term {args, ..} | ProofNode::Cong {args, ..} =>
+        for e in &**args { e.thms(f) },
+      ProofNode::Hyp(_, e) | ProofNode::Refl(e) | ProofNode::Sym(e) => e.thms(f),
+      ProofNode::Thm {thm, args, res} => {
+        f(*thm);
+        for e in &**args { e.thms(f) }
+        res.thms(f)
+      }
+      ProofNode::Conv(p) => { p.0.thms(f); p.1.thms(f); p.2.thms(f) }
+      ProofNode::Unfold {args, res, ..} => {
+        for e in &**args { e.thms(f) }
+        res.0.thms(f); res.1.thms(f); res.2.thms(f)
+      }
+    }
+  }
 }
 
 impl From<&ExprNode> for ProofNode {
@@ -297,6 +320,25 @@ pub enum ThmKind {
   Thm(Option<Proof>)
 }
 
+impl Proof {
+  /// Call `f` on every theorem referenced by this proof.
+  pub fn thms(&self, f: &mut impl FnMut(ThmID)) {
+    for p in self.heap.iter().chain(&*self.hyps).chain(Some(&self.head)) { p.thms(f) }
+  }
+}
+
+/// The axioms and holes that a theorem depends on, as computed by
+/// [`Environment::axioms_of`].
+#[derive(Clone, Debug, Default)]
+pub struct AxiomDeps {
+  /// The axioms used (transitively) in the proof, in declaration order.
+  pub axioms: Vec<ThmID>,
+  /// The theorems without a proof that are used (transitively) in the proof,
+  /// in declaration order. These are holes in the proof: a theorem that depends on
+  /// one has not actually been proved, even if it elaborated without error.
+  pub holes: Vec<ThmID>,
+}
+
 /// The data associated to an `axiom` or `theorem` declaration.
 #[derive(Clone, Debug, DeepSizeOf)]
 pub struct Thm {
@@ -472,7 +514,9 @@ pub struct LispData {
   /// The documentation on the declaration of the item.
   pub doc: Option<DocComment>,
   /// The value associated to the name.
-  pub val: LispVal
+  pub val: LispVal,
+  /// The builtin procedure that this definition overrides, if its name is the name of a builtin.
+  pub overrides: Option<BuiltinProc>,
 }
 
 impl Deref for LispData {
@@ -568,8 +612,19 @@ pub struct Environment {
   pub data: AtomVec<AtomData>,
   /// The global statement order.
   pub stmts: Vec<StmtTrace>,
+  /// The equalities and congruence lemmas used by the `(cong)` tactic.
+  pub eqs: EqLemmas,
+  /// The numerals and arithmetic lemmas used by the `(norm-num)` tactic.
+  pub nums: NumLemmas,
   /// The list of spans that have been collected in the current statement.
   pub spans: Vec<Spans<ObjectKind>>,
+  /// The points at which a later elaboration of the same file can pick up,
+  /// in order. Only a bounded number of them are kept. See [`ResumePoint`](super::ResumePoint).
+  pub resume: Vec<Arc<super::ResumePoint>>,
+  /// The theorems whose proofs used [`admit`](super::lisp::BuiltinProc::Admit), in
+  /// declaration order. These are added without a proof, and can only be exported
+  /// to MMB with `--allow-sorry`.
+  pub admitted: Vec<ThmID>,
 }
 
 macro_rules! make_atoms {
@@ -604,7 +659,11 @@ macro_rules! make_atoms {
           terms: Default::default(),
           thms: Default::default(),
           stmts: Default::default(),
+          eqs: Default::default(),
+          nums: Default::default(),
           spans: Default::default(),
+          resume: Default::default(),
+          admitted: Default::default(),
         }
       }
     }
@@ -660,6 +719,10 @@ make_atoms! {
   WARN: "warn",
   /// `info` is an error level recognized by `set-reporting`
   INFO: "info",
+  /// `flat` is a goal display mode recognized by `set-goal-display`
+  FLAT: "flat",
+  /// `sequent` is a goal display mode recognized by `set-goal-display`
+  SEQUENT: "sequent",
   /// The `annotate` function is a callback used to define what happens when an annotation like
   /// `@foo def bar = ...` is used.
   ANNOTATE: "annotate",
@@ -668,6 +731,12 @@ make_atoms! {
   REFINE_EXTRA_ARGS: "refine-extra-args",
   /// `to-expr-fallback` is called when elaborating a term that is not otherwise recognized
   TO_EXPR_FALLBACK: "to-expr-fallback",
+  /// The `name-policy` function is a callback used to check that the names of
+  /// declarations follow the naming conventions of the library.
+  NAME_POLICY: "name-policy",
+  /// In refine, `(admit)` closes the goal with a warning, and the theorem is added without
+  /// a proof (unless there is a theorem or hypothesis named `admit`)
+  ADMIT: "admit",
 }
 
 /// An implementation of a map `u8 -> bool` using a 32 byte array as a bitset.
@@ -915,6 +984,91 @@ impl Remap for Coe {
     }
   }
 }
+impl Remap for LispData {
+  type Target = Self;
+  fn remap(&self, r: &mut Remapper) -> Self {
+    LispData {
+      src: self.src.clone(),
+      doc: self.doc.clone(),
+      val: self.val.remap(r),
+      overrides: self.overrides,
+    }
+  }
+}
+impl Remap for AtomData {
+  type Target = Self;
+  fn remap(&self, r: &mut Remapper) -> Self {
+    AtomData {
+      name: self.name.clone(),
+      lisp: self.lisp.remap(r),
+      graveyard: self.graveyard.clone(),
+      sort: self.sort.remap(r),
+      decl: self.decl.map(|d| match d {
+        DeclKey::Term(t) => DeclKey::Term(t.remap(r)),
+        DeclKey::Thm(t) => DeclKey::Thm(t.remap(r)),
+      }),
+    }
+  }
+}
+impl Remap for ObjectKind {
+  type Target = Self;
+  fn remap(&self, r: &mut Remapper) -> Self {
+    match self {
+      ObjectKind::Sort(s) => ObjectKind::Sort(s.remap(r)),
+      ObjectKind::Term(t, sp) => ObjectKind::Term(t.remap(r), *sp),
+      ObjectKind::Thm(t) => ObjectKind::Thm(t.remap(r)),
+      ObjectKind::Var(a) => ObjectKind::Var(a.remap(r)),
+      ObjectKind::Global(a) => ObjectKind::Global(a.remap(r)),
+      ObjectKind::Expr(e) => ObjectKind::expr(e.remap(r)),
+      ObjectKind::Proof(e) => ObjectKind::proof(e.remap(r)),
+      ObjectKind::Syntax(s) => ObjectKind::Syntax(*s),
+      ObjectKind::Import(f) => ObjectKind::Import(f.clone()),
+    }
+  }
+}
+impl Remap for EnvSnapshot {
+  type Target = Self;
+  fn remap(&self, r: &mut Remapper) -> Self {
+    EnvSnapshot {
+      sorts: self.sorts,
+      terms: self.terms,
+      thms: self.thms,
+      stmts: self.stmts,
+      pe: self.pe.clone(),
+      eqs: self.eqs.clone(),
+      nums: self.nums.clone(),
+      data: self.data.remap(r),
+    }
+  }
+}
+
+impl Remapper {
+  /// A remapper which maps every sort, term, theorem and atom of `env` to itself.
+  /// Remapping with it makes a deep copy of lisp data, which does not share any
+  /// reference counted values with the original.
+  #[allow(clippy::cast_possible_truncation)]
+  #[must_use] pub fn identity(env: &Environment) -> Remapper {
+    Remapper {
+      sort: (0..env.sorts.len()).map(|i| SortID(i as u8)).collect(),
+      term: (0..env.terms.len()).map(|i| TermID(i as u32)).collect(),
+      thm: (0..env.thms.len()).map(|i| ThmID(i as u32)).collect(),
+      atom: (0..env.data.len()).map(|i| AtomID(i as u32)).collect(),
+      ..Default::default()
+    }
+  }
+
+  /// Make a remapper for copying lisp data from `other`, a copy of `env` made by
+  /// [`Environment::resume`], back into `env`. The atoms are matched by name, so atoms
+  /// allocated in `other` after the copy was made are added to `env`. (As in
+  /// [`Environment::merge`], only the atoms are remapped, since lisp data does not
+  /// refer to sorts, terms or theorems.)
+  pub(crate) fn from_copy(env: &mut Environment, other: &Environment) -> Remapper {
+    Remapper {
+      atom: other.data.0.iter().map(|d| env.get_atom_arc(d.name.clone())).collect(),
+      ..Default::default()
+    }
+  }
+}
 
 /// Several operations have an "incompatibility error" result, involving a conflict between
 /// two definitions. This keeps just the locations of those definitions.
@@ -1116,6 +1270,98 @@ impl Environment {
   #[must_use] pub fn thm(&self, a: AtomID) -> Option<ThmID> {
     if let Some(DeclKey::Thm(i)) = self.data[a].decl { Some(i) } else { None }
   }
+
+  /// Compute the set of axioms and holes (theorems without a proof) that theorem `t`
+  /// depends on, by following the theorems referenced in its proof transitively.
+  /// If `t` is itself an axiom or hole, then it is the only element of the result.
+  #[must_use] pub fn axioms_of(&self, t: ThmID) -> AxiomDeps {
+    let mut deps = AxiomDeps::default();
+    let mut seen = vec![false; self.thms.len()];
+    let mut stack = vec![t];
+    seen[t.0 as usize] = true;
+    while let Some(t) = stack.pop() {
+      match &self.thms[t].kind {
+        ThmKind::Axiom => deps.axioms.push(t),
+        ThmKind::Thm(None) => deps.holes.push(t),
+        ThmKind::Thm(Some(pf)) => pf.thms(&mut |th| {
+          if let Some(b @ false) = seen.get_mut(th.0 as usize) { *b = true; stack.push(th) }
+        }),
+      }
+    }
+    deps.axioms.sort_unstable();
+    deps.holes.sort_unstable();
+    deps
+  }
+
+  /// Take a snapshot of the environment, which can be restored later using
+  /// [`restore`](Self::restore) to undo any declarations made in the meantime.
+  #[must_use] pub fn snapshot(&self) -> EnvSnapshot {
+    EnvSnapshot {
+      sorts: self.sorts.len(),
+      terms: self.terms.len(),
+      thms: self.thms.len(),
+      stmts: self.stmts.len(),
+      pe: self.pe.clone(),
+      eqs: self.eqs.clone(),
+      nums: self.nums.clone(),
+      data: self.data.0.clone(),
+    }
+  }
+
+  /// Restore the environment to the state in the snapshot `s`, which must have been
+  /// taken from this environment. Atoms allocated since the snapshot are not removed
+  /// (since lisp values may still refer to them), but their data is cleared.
+  pub fn restore(&mut self, s: &EnvSnapshot) {
+    self.sorts.0.truncate(s.sorts);
+    self.terms.0.truncate(s.terms);
+    self.thms.0.truncate(s.thms);
+    self.admitted.retain(|t| (t.0 as usize) < s.thms);
+    self.stmts.truncate(s.stmts);
+    self.pe.clone_from(&s.pe);
+    self.eqs.clone_from(&s.eqs);
+    self.nums.clone_from(&s.nums);
+    let (old, new) = self.data.0.split_at_mut(s.data.len());
+    old.clone_from_slice(&s.data);
+    for ad in new { *ad = AtomData::new(ad.name.clone()) }
+  }
+
+  /// Reconstruct the environment of the file which produced `old`, as it was when the
+  /// snapshot `s` was taken, along with the first `spans` statement spans. The snapshot must
+  /// have been taken while elaborating `old` (and [`Remap`]ped so that it does not share
+  /// lisp data with it). Everything is copied, so the result does not share any lisp data
+  /// with `old` or `s`, and can be used on another thread.
+  #[must_use] pub fn resume(old: &Environment, s: &EnvSnapshot, spans: usize) -> Environment {
+    let r = &mut Remapper::identity(old);
+    let mut data: AtomVec<AtomData> = s.data.iter().map(|ad| ad.remap(r)).collect();
+    for ad in &old.data.0[s.data.len()..] { data.push(AtomData::new(ad.name.clone())) }
+    Environment {
+      sorts: old.sorts.0[..s.sorts].iter().cloned().collect(),
+      pe: s.pe.clone(),
+      terms: old.terms.0[..s.terms].iter().cloned().collect(),
+      thms: old.thms.0[..s.thms].iter().cloned().collect(),
+      atoms: old.atoms.clone(),
+      data,
+      stmts: old.stmts[..s.stmts].to_vec(),
+      eqs: s.eqs.clone(),
+      nums: s.nums.clone(),
+      spans: old.spans[..spans].iter().map(|sp| sp.remap(r)).collect(),
+      resume: vec![],
+      admitted: old.admitted.iter().copied().filter(|t| (t.0 as usize) < s.thms).collect(),
+    }
+  }
+}
+
+/// A snapshot of an [`Environment`], created by [`Environment::snapshot`].
+#[derive(Debug, DeepSizeOf)]
+pub struct EnvSnapshot {
+  sorts: usize,
+  terms: usize,
+  thms: usize,
+  stmts: usize,
+  pe: ParserEnv,
+  eqs: EqLemmas,
+  nums: NumLemmas,
+  data: Vec<AtomData>,
 }
 
 /// Adding an item (sort, term, theorem, atom) can result in a redeclaration error,
@@ -1328,6 +1574,7 @@ impl Environment {
             };
             assert_eq!(remap.thm.len(), tid.0 as usize);
             remap.thm.push(id);
+            if other.admitted().binary_search(&tid).is_ok() { self.admitted.push(id) }
           }
         },
         StmtTrace::Global(_) => {}
@@ -1335,6 +1582,8 @@ impl Environment {
       }
     }
     self.pe.merge(other.pe(), remap, sp, &self.sorts, errors);
+    self.eqs.merge(other.eqs(), remap);
+    self.nums.merge(other.nums(), remap);
     Ok(())
   }
 
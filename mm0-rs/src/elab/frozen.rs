@@ -31,18 +31,18 @@
 //!
 //! [`freeze`]: LispVal::freeze
 
-use std::cell::{Cell, RefCell};
+use std::cell::RefCell;
 use std::ops::Deref;
 use std::sync::Arc;
 use std::rc::Rc;
 use std::collections::{HashMap, hash_map::Entry};
 use num::BigInt;
-use super::{Spans, ObjectKind, Remap, Remapper,
+use super::{Spans, ObjectKind, Remap, Remapper, ResumePoint, cong::EqLemmas, norm_num::NumLemmas,
   environment::{Environment, ParserEnv,
     AtomVec, TermVec, ThmVec, SortVec, DeclKey, StmtTrace, DocComment, LispData,
     SortID, TermID, ThmID, AtomID, Sort, Term, Thm, AtomData},
   lisp::{LispVal, LispKind, LispRef, LispWeak,
-    InferTarget, Proc, Annot, Syntax, print::FormatEnv}};
+    InferTarget, Proc, Promise, BuiltinProc, MatchCont, ContExpiry, Annot, Syntax, print::FormatEnv}};
 use crate::util::{ArcString, FileSpan, Span};
 use crate::{lined_string::LinedString, __mk_lisp_kind};
 
@@ -58,6 +58,10 @@ impl FrozenEnv {
   /// Create a new [`FrozenEnv`] from an [`Environment`].
   #[must_use] pub fn new(env: Environment) -> Self { Self(Arc::new(env)) }
 
+  /// Returns true if the two environments are the same object (the same result of
+  /// elaborating a file, not just equal).
+  #[must_use] pub fn ptr_eq(&self, other: &Self) -> bool { Arc::ptr_eq(&self.0, &other.0) }
+
   /// Convert a [`&FrozenEnv`] into an [`&Environment`].
   /// # Safety
   /// The reference derived here is only usable for reading, so in particular
@@ -97,12 +101,20 @@ impl FrozenEnv {
   #[must_use] pub fn thms(&self) -> &ThmVec<Thm> { &unsafe { self.thaw() }.thms }
   /// Accessor for [`Environment::thms`]
   #[must_use] pub fn thm(&self, t: ThmID) -> &Thm { &self.thms()[t] }
+  /// Accessor for [`Environment::admitted`]
+  #[must_use] pub fn admitted(&self) -> &[ThmID] { &unsafe { self.thaw() }.admitted }
   /// Accessor for [`Environment::stmts`]
   #[must_use] pub fn stmts(&self) -> &[StmtTrace] { &unsafe { self.thaw() }.stmts }
+  /// Accessor for [`Environment::resume`]
+  #[must_use] pub fn resume_points(&self) -> &[Arc<ResumePoint>] { &unsafe { self.thaw() }.resume }
   /// Parse a string into an atom.
   #[must_use] pub fn get_atom(&self, s: &[u8]) -> Option<AtomID> { unsafe { self.thaw() }.atoms.get(s).copied() }
   /// Accessor for [`Environment::pe`]
   #[must_use] pub fn pe(&self) -> &ParserEnv { &unsafe { self.thaw() }.pe }
+  /// Accessor for [`Environment::eqs`]
+  #[must_use] pub fn eqs(&self) -> &EqLemmas { &unsafe { self.thaw() }.eqs }
+  /// Accessor for [`Environment::nums`]
+  #[must_use] pub fn nums(&self) -> &NumLemmas { &unsafe { self.thaw() }.nums }
 }
 
 /// A wrapper around an [`AtomData`] that is frozen.
@@ -135,6 +147,8 @@ impl FrozenLispData {
   #[must_use] pub fn src(&self) -> &Option<(FileSpan, Span)> { &self.0.src }
   /// Accessor for [`LispData::doc`]
   #[must_use] pub fn doc(&self) -> &Option<DocComment> { &self.0.doc }
+  /// Accessor for [`LispData::overrides`]
+  #[must_use] pub fn overrides(&self) -> Option<BuiltinProc> { self.0.overrides }
 }
 impl Deref for FrozenLispData {
   type Target = FrozenLispVal;
@@ -305,7 +319,8 @@ impl Remap for FrozenLispData {
     LispData {
       src: self.src().clone(),
       doc: self.doc().clone(),
-      val: (**self).remap(r)
+      val: (**self).remap(r),
+      overrides: self.overrides(),
     }
   }
 }
@@ -324,6 +339,7 @@ impl Remap for FrozenLispKind {
       FrozenLispKind::Atom(a) => LispVal::atom(a.remap(r)),
       FrozenLispKind::List(v) => LispVal::list(v.remap(r)),
       FrozenLispKind::DottedList(v, l) => LispVal::dotted_list(v.remap(r), l.remap(r)),
+      FrozenLispKind::Vector(v) => LispVal::vector(v.remap(r)),
       FrozenLispKind::Annot(sp, m) => LispVal::new(LispKind::Annot(sp.clone(), m.remap(r))),
       FrozenLispKind::Proc(f) => LispVal::proc(f.remap(r)),
       FrozenLispKind::AtomMap(m) => LispVal::new(LispKind::AtomMap(m.remap(r))),
@@ -341,6 +357,7 @@ impl Remap for FrozenLispKind {
       &FrozenLispKind::MVar(n, is) => LispVal::new(LispKind::MVar(n, is.remap(r))),
       FrozenLispKind::Goal(e) => LispVal::new(LispKind::Goal(e.remap(r))),
       FrozenLispKind::Number(n) => LispVal::number(n.clone()),
+      &FrozenLispKind::SmallInt(n) => LispVal::small_int(n),
       FrozenLispKind::String(s) => LispVal::string(s.clone()),
       &FrozenLispKind::Bool(b) => LispVal::bool(b),
       &FrozenLispKind::Syntax(s) => LispVal::syntax(s),
@@ -364,7 +381,11 @@ impl Remap for FrozenProc {
       &Proc::Builtin(p) => Proc::Builtin(p),
       &Proc::Lambda {ref pos, ref env, spec, ref code} =>
         Proc::Lambda {pos: pos.remap(r), env: env.remap(r), spec, code: code.remap(r)},
-      Proc::MatchCont(_) => Proc::MatchCont(Rc::new(Cell::new(false))),
+      Proc::MatchCont(k) => {
+        let k2 = MatchCont::new(k.span.clone());
+        k2.expire(k.expiry().unwrap_or(ContExpiry::Frozen));
+        Proc::MatchCont(Rc::new(k2))
+      }
       Proc::RefineCallback => Proc::RefineCallback,
       Proc::ProofThunk(x, m) => Proc::ProofThunk(x.remap(r), RefCell::new(
         match &*unsafe { m.try_borrow_unguarded() }.expect("failed to deref ref") {
@@ -372,6 +393,13 @@ impl Remap for FrozenProc {
           Err(v) => Err(v.remap(r)),
         }
       )),
+      Proc::Promise(m) => Proc::Promise(Rc::new(RefCell::new(
+        match &*unsafe { m.try_borrow_unguarded() }.expect("failed to deref ref") {
+          Promise::Done(e) => Promise::Done(e.remap(r)),
+          // The running call can only be joined once, so the copy does not get the result
+          Promise::Running(_) | Promise::Lost => Promise::Lost,
+        }
+      ))),
       Proc::MMCCompiler(c) => Proc::MMCCompiler(c.remap(r)),
     }
   }
@@ -7,6 +7,8 @@
 pub mod parser;
 pub mod eval;
 pub mod debug;
+pub mod debugger;
+pub mod output;
 pub mod print;
 pub mod pretty;
 
@@ -16,7 +18,7 @@ use std::rc::{Rc, Weak};
 use std::cell::{Cell, RefCell};
 use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
-use num::BigInt;
+use num::{BigInt, ToPrimitive};
 use owning_ref::{OwningRef, StableAddress, CloneStableAddress};
 use crate::parser::ast::Atom;
 use crate::util::{ArcString, FileSpan, Span, SliceExt, MutexExt, StackList};
@@ -40,6 +42,9 @@ macro_rules! str_enum {
     crate::deep_size_0!($name);
 
     impl $name {
+      /// All the values of this type, in declaration order.
+      pub const ALL: &'static [Self] = &[$($name::$e),*];
+
       #[doc=$to_str]
       #[must_use] pub fn to_str(self) -> &'static str {
         match self {
@@ -106,6 +111,9 @@ str_enum! {
     MatchFn: "match-fn",
     /// `match-fn*`: a lambda taking any number of arguments that pattern matches on the list of arguments.
     MatchFns: "match-fn*",
+    /// `label`: evaluate a sequence of expressions, with an escape point that can be
+    /// jumped to using `escape`.
+    Label: "label",
   }
 }
 
@@ -116,6 +124,7 @@ impl Syntax {
       Atom::Ident => Syntax::from_bytes(s).ok_or(s),
       Atom::Quote => Ok(Syntax::Quote),
       Atom::Unquote => Ok(Syntax::Unquote),
+      Atom::UnquoteSplicing => Err(b"unquote-splicing"),
       Atom::Nfx => Err(b":nfx"),
     }
   }
@@ -165,6 +174,13 @@ impl InferTarget {
 /// A lisp value. These are the "values" that are passed around by lisp code.
 /// See [`LispKind`] for the list of different types of lisp object. This is
 /// a wrapper around `Rc<LispKind>`, and it is cloned frequently in client code.
+///
+/// Evaluation is single-threaded per file, so we use non-atomic reference counts here,
+/// and values only cross thread boundaries in a frozen environment (see
+/// [`frozen`](super::frozen)), where they are never cloned. For the same reason,
+/// small values like `#undef` and `#t` are not shared through a global or thread-local
+/// cache: a paused elaboration can resume on another thread, and a frozen environment
+/// can be dropped from any thread, so such a cache would race on the reference count.
 #[derive(Default, Debug, EnvDebug, Clone, DeepSizeOf)]
 pub struct LispVal(Rc<LispKind>);
 
@@ -193,13 +209,25 @@ macro_rules! __mk_lisp_kind {
       /// all functions that deal with lists should check that `(a b . (c d . (e f g)))`
       /// is treated the same as `(a b c d e f g)`.
       DottedList(Box<[$val]>, $val),
+      /// A vector of values, `(vector a b c)`. Unlike a [`List`](Self::List), this is
+      /// never confused with a cons cell, it supports constant time indexing using
+      /// `vector-ref`, and it can be modified in place using `vector-set!` if it is
+      /// behind a [`Ref`](Self::Ref).
+      Vector(Box<[$val]>),
       /// Annotates a lisp value with some information that should be invisible to the
       /// front end. Currently we primarily use it for associating file locations to
       /// lisp objects, so that client code can give targeted error messages.
       Annot(Annot, $val),
       /// A number like `123`. These use bignum arithmetic so that client code
-      /// doesn't have to worry about overflow.
+      /// doesn't have to worry about overflow. Numbers that fit in an `i64` are
+      /// stored as [`SmallInt`](Self::SmallInt) instead, so this only holds
+      /// large numbers (see [`LispVal::number`]).
       Number(BigInt),
+      /// A number that fits in an `i64`. This is semantically identical to
+      /// [`Number`](Self::Number), but it avoids allocating a bignum for the
+      /// common case of small numbers, and the arithmetic builtins have a fast
+      /// path for it that falls back to bignum arithmetic on overflow.
+      SmallInt(i64),
       /// An immutable string like `"foo"`.
       String(ArcString),
       /// A boolean value, `#t` or `#f`.
@@ -237,7 +265,10 @@ __mk_lisp_kind! {
 
 impl LispVal {
   /// Make a [`LispVal`] from the inner enum type [`LispKind`].
-  #[must_use] pub fn new(e: LispKind) -> LispVal { LispVal(Rc::new(e)) }
+  #[must_use] pub fn new(e: LispKind) -> LispVal {
+    ALLOCS.with(|n| if let Some(k) = n.get() { n.set(Some(k + 1)) });
+    LispVal(Rc::new(e))
+  }
   /// Construct a [`LispVal`] for an atom.
   #[must_use] pub fn atom(a: AtomID) -> LispVal { LispVal::new(LispKind::Atom(a)) }
   /// Construct a [`LispVal`] for a list.
@@ -246,8 +277,20 @@ impl LispVal {
   #[must_use] pub fn dotted_list(es: impl Into<Box<[LispVal]>>, r: LispVal) -> LispVal {
     LispVal::new(LispKind::DottedList(es.into(), r))
   }
-  /// Construct a [`LispVal`] for an improper list.
-  #[must_use] pub fn number(n: BigInt) -> LispVal { LispVal::new(LispKind::Number(n)) }
+  /// Construct a [`LispVal`] for a vector.
+  #[must_use] pub fn vector(es: impl Into<Box<[LispVal]>>) -> LispVal {
+    LispVal::new(LispKind::Vector(es.into()))
+  }
+  /// Construct a [`LispVal`] for a number. This uses the
+  /// [`SmallInt`](LispKind::SmallInt) representation if the number fits in an `i64`.
+  #[must_use] pub fn number(n: BigInt) -> LispVal {
+    match n.to_i64() {
+      Some(n) => LispVal::small_int(n),
+      None => LispVal::new(LispKind::Number(n))
+    }
+  }
+  /// Construct a [`LispVal`] for a number that fits in an `i64`.
+  #[must_use] pub fn small_int(n: i64) -> LispVal { LispVal::new(LispKind::SmallInt(n)) }
   /// Construct a [`LispVal`] for a string.
   #[must_use] pub fn string(s: ArcString) -> LispVal { LispVal::new(LispKind::String(s)) }
   /// Construct a [`LispVal`] for a syntax element.
@@ -376,10 +419,28 @@ impl Eq for LispVal {}
 pub(crate) struct LispArena(typed_arena::Arena<Weak<LispKind>>);
 
 thread_local!(static REFS: Cell<Option<*const LispArena>> = Cell::new(None));
+thread_local!(static ALLOCS: Cell<Option<u64>> = Cell::new(None));
+
+/// Turn counting of [`LispVal`] allocations on this thread on or off. It is only on while
+/// the lisp profiler is, so that allocation does not pay for it otherwise.
+pub(crate) fn count_allocs(on: bool) {
+  ALLOCS.with(|n| if on != n.get().is_some() { n.set(if on { Some(0) } else { None }) })
+}
+
+/// The number of [`LispVal`]s allocated on this thread since counting was turned on by
+/// [`count_allocs`], used by the lisp profiler.
+pub(crate) fn alloc_count() -> u64 { ALLOCS.with(|n| n.get().unwrap_or(0)) }
 
 impl LispArena {
-  pub(crate) fn install_thread_local(&self) { REFS.with(|refs| refs.set(Some(self))) }
+  /// Install this arena on the current thread, returning the arena that was installed
+  /// before, which can be put back with [`restore_thread_local`](Self::restore_thread_local).
+  pub(crate) fn install_thread_local(&self) -> Option<*const LispArena> {
+    REFS.with(|refs| refs.replace(Some(self)))
+  }
   pub(crate) fn uninstall_thread_local() { REFS.with(|refs| refs.set(None)) }
+  pub(crate) fn restore_thread_local(old: Option<*const LispArena>) {
+    REFS.with(|refs| refs.set(old))
+  }
 
   #[allow(clippy::unused_self)]
   pub(crate) fn clear(self) {
@@ -393,9 +454,21 @@ impl std::fmt::Debug for LispArena {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { write!(f, "LispArena") }
 }
 
+/// The state of a [`Proc::Promise`], created by `spawn`.
+#[derive(Debug, EnvDebug, DeepSizeOf)]
+pub enum Promise {
+  /// The call is still being evaluated on another thread.
+  Running(super::parallel::Task),
+  /// The promise has been joined, and the call returned this value.
+  Done(LispVal),
+  /// The result is not available, because the call failed, or because the promise was
+  /// copied (for example, into another file's environment) before it was joined.
+  Lost,
+}
+
 /// The target of a reference can be either a weak reference or a strong reference.
 /// Weak references are used to break cycles.
-#[derive(Debug, EnvDebug, DeepSizeOf)]
+#[derive(Clone, Debug, EnvDebug, DeepSizeOf)]
 pub enum LispWeak {
   /// A regular (strong) reference.
   Strong(LispVal),
@@ -493,15 +566,22 @@ impl LispRef {
   ///
   /// [`FrozenLispRef::get`]: super::frozen::FrozenLispRef::get
   pub(crate) unsafe fn get_unsafe(&self) -> Option<&LispKind> {
-    match self.0.try_borrow_unguarded().unwrap_or_else(|_| {
-      std::sync::atomic::fence(std::sync::atomic::Ordering::SeqCst);
-      self.0.try_borrow_unguarded().expect("could not deref refcell")
-    }) {
+    match self.get_weak_unsafe() {
       LispWeak::Strong(e) => Some(e),
       LispWeak::Weak(e) if e.strong_count() == 0 => None,
       LispWeak::Weak(e) => Some(&*e.as_ptr())
     }
   }
+
+  /// Get the stored (strong or weak) reference without changing the reference count.
+  /// # Safety
+  /// This function should not be used unless the value is frozen.
+  pub(crate) unsafe fn get_weak_unsafe(&self) -> &LispWeak {
+    self.0.try_borrow_unguarded().unwrap_or_else(|_| {
+      std::sync::atomic::fence(std::sync::atomic::Ordering::SeqCst);
+      self.0.try_borrow_unguarded().expect("could not deref refcell")
+    })
+  }
 }
 
 impl PartialEq<LispRef> for LispRef {
@@ -556,6 +636,27 @@ impl LispKind {
   pub fn truthy(&self) -> bool {
     self.unwrapped(|e| !matches!(e, LispKind::Bool(false)))
   }
+  /// A short description of the type of this value, used in error messages.
+  /// [`Ref`](Self::Ref) and [`Annot`](Self::Annot) nodes are unwrapped.
+  pub fn type_name(&self) -> &'static str {
+    self.unwrapped(|e| match e {
+      LispKind::Atom(_) => "atom",
+      LispKind::List(es) if es.is_empty() => "()",
+      LispKind::List(_) => "list",
+      LispKind::DottedList(_, _) => "dotted list",
+      LispKind::Vector(_) => "vector",
+      LispKind::Number(_) | LispKind::SmallInt(_) => "number",
+      LispKind::String(_) => "string",
+      LispKind::Bool(_) => "bool",
+      LispKind::Syntax(_) => "syntax",
+      LispKind::Undef => "#undef",
+      LispKind::Proc(_) => "procedure",
+      LispKind::AtomMap(_) => "atom map",
+      LispKind::Annot(_, _) | LispKind::Ref(_) => "ref",
+      LispKind::MVar(_, _) => "metavariable",
+      LispKind::Goal(_) => "goal",
+    })
+  }
   /// Returns true if this value is a boolean.
   pub fn is_bool(&self) -> bool {
     self.unwrapped(|e| matches!(e, LispKind::Bool(_)))
@@ -574,11 +675,19 @@ impl LispKind {
   }
   /// Returns true if this value is a number.
   pub fn is_int(&self) -> bool {
-    self.unwrapped(|e| matches!(e, LispKind::Number(_)))
+    self.unwrapped(|e| matches!(e, LispKind::Number(_) | LispKind::SmallInt(_)))
   }
   /// Get the number that this value stores, if applicable.
   pub fn as_int<T>(&self, f: impl FnOnce(&BigInt) -> T) -> Option<T> {
-    self.unwrapped(|e| if let LispKind::Number(n) = e {Some(f(n))} else {None})
+    self.unwrapped(|e| match *e {
+      LispKind::Number(ref n) => Some(f(n)),
+      LispKind::SmallInt(n) => Some(f(&n.into())),
+      _ => None
+    })
+  }
+  /// Get the number that this value stores, if it is a number that fits in an `i64`.
+  pub fn as_small_int(&self) -> Option<i64> {
+    self.unwrapped(|e| if let LispKind::SmallInt(n) = *e {Some(n)} else {None})
   }
   /// Returns true if this value is a procedure.
   pub fn is_proc(&self) -> bool {
@@ -588,6 +697,14 @@ impl LispKind {
   pub fn is_string(&self) -> bool {
     self.unwrapped(|e| matches!(e, LispKind::String(_)))
   }
+  /// Returns true if this value is a vector.
+  pub fn is_vector(&self) -> bool {
+    self.unwrapped(|e| matches!(e, LispKind::Vector(_)))
+  }
+  /// Get the elements of the vector that this value stores, if applicable.
+  pub fn as_vector<T>(&self, f: impl FnOnce(&[LispVal]) -> T) -> Option<T> {
+    self.unwrapped(|e| if let LispKind::Vector(es) = e {Some(f(es))} else {None})
+  }
   /// Returns true if this value is an atom map.
   pub fn is_map(&self) -> bool {
     self.unwrapped(|e| matches!(e, LispKind::AtomMap(_)))
@@ -733,11 +850,13 @@ impl PartialEq<LispKind> for LispKind {
     self.unwrapped(|s| other.unwrapped(|o| match (s, o) {
       (&LispKind::Atom(a), &LispKind::Atom(b)) => a == b,
       (LispKind::Number(a), LispKind::Number(b)) => a == b,
+      (LispKind::SmallInt(a), LispKind::SmallInt(b)) => a == b,
       (LispKind::String(a), LispKind::String(b)) => a == b,
       (LispKind::Bool(a), LispKind::Bool(b)) => a == b,
       (LispKind::Syntax(a), LispKind::Syntax(b)) => a == b,
       (LispKind::Undef, LispKind::Undef) => true,
       (LispKind::List(a), LispKind::List(b)) => a == b,
+      (LispKind::Vector(a), LispKind::Vector(b)) => a == b,
       (LispKind::List(a), _) => other.eq_list(a.iter()),
       (_, LispKind::List(b)) => self.eq_list(b.iter()),
       (LispKind::DottedList(es1, r1), LispKind::DottedList(es2, r2)) => {
@@ -792,6 +911,62 @@ impl ProcPos {
   }
 }
 
+/// The reason a match continuation expired, along with the place where it happened.
+#[derive(Clone, Debug, EnvDebug, DeepSizeOf)]
+pub enum ContExpiry {
+  /// The match branch returned normally, from the `match` at this location.
+  Returned(FileSpan),
+  /// The branch was exited by calling a match continuation or `escape` at this location.
+  Jumped(FileSpan),
+  /// The branch was exited by a tail call at this location.
+  TailCall(FileSpan),
+  /// The branch was exited by an exception raised at this location.
+  Exception(FileSpan),
+  /// The continuation was saved in the environment at the end of a file.
+  Frozen,
+}
+
+impl ContExpiry {
+  /// A description of the reason for expiry, and the location where it happened.
+  #[must_use] pub fn describe(&self) -> (&'static str, Option<&FileSpan>) {
+    match self {
+      ContExpiry::Returned(fsp) => ("the match branch returned", Some(fsp)),
+      ContExpiry::Jumped(fsp) => ("the match branch was exited by a jump", Some(fsp)),
+      ContExpiry::TailCall(fsp) => ("the match branch was exited by a tail call", Some(fsp)),
+      ContExpiry::Exception(fsp) => ("the match branch was exited by an exception", Some(fsp)),
+      ContExpiry::Frozen => ("continuations do not survive the end of the file", None),
+    }
+  }
+}
+
+/// The shared state of a match continuation, between the [`Proc::MatchCont`] value and
+/// the stack frame of the `match` that it jumps back to.
+#[derive(Debug, EnvDebug, DeepSizeOf)]
+pub struct MatchCont {
+  /// The span of the `match` expression that created the continuation.
+  pub span: FileSpan,
+  /// `None` while the continuation is valid, and the reason for expiry afterwards.
+  expired: RefCell<Option<ContExpiry>>,
+}
+
+impl MatchCont {
+  /// Construct a new (valid) match continuation for the `match` at `span`.
+  #[must_use] pub fn new(span: FileSpan) -> Self { Self {span, expired: RefCell::new(None)} }
+
+  /// Returns true if the continuation can still be called.
+  #[must_use] pub fn is_valid(&self) -> bool { self.expired.borrow().is_none() }
+
+  /// Get the reason for expiry, if the continuation is no longer valid.
+  #[must_use] pub fn expiry(&self) -> Option<ContExpiry> { self.expired.borrow().clone() }
+
+  /// Mark the continuation as expired. If it has already expired, the original
+  /// reason is kept.
+  pub fn expire(&self, why: ContExpiry) {
+    let mut e = self.expired.borrow_mut();
+    if e.is_none() { *e = Some(why) }
+  }
+}
+
 /// A callable procedure. There are several sources of procedures,
 /// all of which are interactable only via function calls `(f)` and
 /// printing (which shows only basic information about the procedure).
@@ -819,11 +994,11 @@ pub enum Proc {
   /// A match continuation, which is passed to client code in the variable `k`
   /// of `(match e [pat (=> k) code])`. It is a *delimited* continuation, which means
   /// that it is only valid while inside the scope of `code`, but it is a regular
-  /// value and can be passed around, so the `Rc<Cell<bool>>` is a validity marker
+  /// value and can be passed around, so the `Rc<MatchCont>` is a validity marker
   /// that is used both to identify which continuation is being jumped to, in case
   /// multiple are in scope, as well as to determine if we are still in the dynamic
   /// extent of `code`.
-  MatchCont(Rc<Cell<bool>>),
+  MatchCont(Rc<MatchCont>),
   /// A callback used by `refine` when it finds a procedure in a refine script.
   /// The callback acts like `refine` as well, but it orders generated subgoals with
   /// respect to an outer invocation of `refine`. This callback also only works
@@ -837,6 +1012,9 @@ pub enum Proc {
   /// stores `Err(args)`, where `args` is the list of variables, while the
   /// evaluated form stores `Ok(proof)`.
   ProofThunk(AtomID, RefCell<Result<LispVal, Box<[LispVal]>>>),
+  /// A promise, created by `spawn`, for the result of a function call which is evaluated
+  /// on another thread. Joining it (or calling it) waits for the result, and caches it.
+  Promise(Rc<RefCell<Promise>>),
   /// The compiler object, which can be called as a procedure and stores its own
   /// internal state here. See [`Compiler::call`].
   ///
@@ -848,7 +1026,7 @@ pub enum Proc {
 /// by the call. Individual procedures may have additional rules on top of
 /// this for validity, but every procedure must declare its specification
 /// in [`Proc::spec`].
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ProcSpec {
   /// This function must be called with exactly `n` arguments.
   Exact(usize),
@@ -867,6 +1045,15 @@ impl ProcSpec {
   }
 }
 
+impl std::fmt::Display for ProcSpec {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match *self {
+      ProcSpec::Exact(n) => write!(f, "exactly {} argument(s)", n),
+      ProcSpec::AtLeast(n) => write!(f, "at least {} argument(s)", n),
+    }
+  }
+}
+
 impl Proc {
   /// Returns the specification (number of expected arguments) for a procedure.
   pub fn spec(&self) -> ProcSpec {
@@ -874,7 +1061,8 @@ impl Proc {
       Proc::Builtin(p) => p.spec(),
       &Proc::Lambda {spec, ..} => spec,
       Proc::MatchCont(_) |
-      Proc::ProofThunk(_, _) => ProcSpec::AtLeast(0),
+      Proc::ProofThunk(_, _) |
+      Proc::Promise(_) => ProcSpec::AtLeast(0),
       Proc::RefineCallback |
       Proc::MMCCompiler(_) => ProcSpec::AtLeast(1),
     }
@@ -885,8 +1073,10 @@ str_enum! {
   /// The set of built in procedures. These each have names that can be shadowed
   /// but not overridden by global names in the environment.
   enum BuiltinProc {
-    /// `display` takes a string and prints it. In the interactive editor mode,
-    /// this appears as an info diagnostic over the word "`display`".
+    /// `display` takes a string and prints it. When compiling, this is printed to
+    /// stdout, and in the interactive editor mode it is sent to the output pane
+    /// (see [`output`]). It is reported as an info diagnostic over the word
+    /// "`display`" if no output channel is set.
     /// ```metamath-zero
     /// (display "hello world")         -- hello world
     /// (display 42)                    -- error, expected string
@@ -894,6 +1084,46 @@ str_enum! {
     Display: "display",
     /// `error` takes a string and throws an error with the given string as the message.
     Error: "error",
+    /// * `(assert cond msg)` throws an error with the message `msg` (a string) if `cond`
+    ///   is `#f`, and otherwise returns `#undef`.
+    /// * `(assert cond)` is the same, with a generic error message.
+    ///
+    /// This is intended for writing tests (see `@test do`), but can be used anywhere.
+    /// ```metamath-zero
+    /// (assert (= 1 1) "math is broken") -- #undef
+    /// (assert (= 1 2) "math is broken") -- error, math is broken
+    /// ```
+    Assert: "assert",
+    /// `(check-equal a b)` throws an error showing both values if `a` and `b` are not equal
+    /// in the sense of `==`, and otherwise returns `#undef`. By convention, `a` is the value
+    /// being tested and `b` is the expected value.
+    /// ```metamath-zero
+    /// (check-equal (+ 1 1) 2) -- #undef
+    /// (check-equal (+ 1 1) 3) -- error, check-equal: got 2, expected 3
+    /// ```
+    CheckEqual: "check-equal",
+    /// `(raise e)` raises an exception carrying the value `e`, which can be caught by
+    /// an enclosing call to `try`. If it is not caught, it is reported as an error.
+    /// ```metamath-zero
+    /// (try (fn () (raise 'foo)) (fn (e) e)) -- foo
+    /// ```
+    Raise: "raise",
+    /// `(try f handler)` calls `(f)` and returns the result, unless the call raises an
+    /// exception, in which case it is abandoned and `(handler e)` is called instead,
+    /// where `e` is the exception value. This is a function rather than a syntax form,
+    /// so the protected code is passed as a thunk, and `try` is not a reserved word.
+    /// ```metamath-zero
+    /// (try (fn () (raise 'foo)) (fn (e) e))  -- foo
+    /// (try (fn () (error "bad")) (fn (e) e)) -- "bad"
+    /// (try (fn () 1) (fn (e) 2))             -- 1
+    /// ```
+    Try: "try",
+    /// `(escape 'x v)` exits early from the nearest enclosing `(label 'x es)`,
+    /// which returns `v`. It is an error if there is no such label in scope.
+    /// ```metamath-zero
+    /// (label 'done (escape 'done 1) 2) -- 1
+    /// ```
+    Escape: "escape",
     /// `print` takes an arbitrary expression and pretty-prints it.
     Print: "print",
     /// `(report-at sp type msg)` will report the message `msg` at a position
@@ -989,16 +1219,50 @@ str_enum! {
     /// (string->atom "foo$bar baz") -- foo$bar baz
     /// ```
     StringToAtom: "string->atom",
+    /// `(atom->string a)` returns the name of the atom `a` as a string.
+    /// ```metamath-zero
+    /// (atom->string 'foo) -- "foo"
+    /// ```
+    AtomToString: "atom->string",
+    /// `(gensym)` returns a fresh atom, whose name is distinct from every atom that has
+    /// been created so far. `(gensym prefix)` uses the string or atom `prefix` as the start
+    /// of the name (the default is `_g`). The name contains a `#`, so it cannot clash
+    /// with an atom written in the source. This is useful for naming hypotheses in tactics.
+    /// ```metamath-zero
+    /// (gensym)     -- _g#1
+    /// (gensym 'h)  -- h#2
+    /// ```
+    Gensym: "gensym",
     /// `(string-append s1 s2 s3)` stringifies and appends all the inputs.
     /// ```metamath-zero
     /// (string-append "foo" 'bar 42) -- "foobar42"
     /// ```
     StringAppend: "string-append",
+    /// `(open-output-string)` returns a new, empty string builder. Appending to a
+    /// string builder with `write-str!` is much faster than repeated `string-append`
+    /// when building a large string piece by piece.
+    /// ```metamath-zero
+    /// (def b (open-output-string))
+    /// (write-str! b "foo" 'bar)
+    /// (write-str! b 42)
+    /// (get-output-string b) -- "foobar42"
+    /// ```
+    OpenOutputString: "open-output-string",
+    /// `(write-str! b s1 s2 ...)` stringifies the inputs (as in `string-append`)
+    /// and appends them to the string builder `b`.
+    WriteStr: "write-str!",
+    /// `(get-output-string b)` returns the contents of the string builder `b` as a string.
+    GetOutputString: "get-output-string",
     /// `(string-len s)` returns the length of the string (number of bytes).
     /// ```metamath-zero
     /// (string-len "foo") -- 3
     /// ```
     StringLen: "string-len",
+    /// `(string-char-len s)` returns the number of (UTF-8 encoded) characters in the string.
+    /// ```metamath-zero
+    /// (string-char-len "λx") -- 2
+    /// ```
+    StringCharLen: "string-char-len",
     /// `(string-nth n s)` returns the character code of the nth byte (zero-indexed) in the string.
     /// ```metamath-zero
     /// (string-nth 1 "bar") -- 97, ascii 'a'
@@ -1011,6 +1275,13 @@ str_enum! {
     /// (substr 6 11 "hello world!") -- "world"
     /// ```
     Substr: "substr",
+    /// `(char-substr start end s)` is like `substr`, but `start` and `end` count
+    /// (UTF-8 encoded) characters rather than bytes, where
+    /// `0 <= start <= end <= (string-char-len s)`.
+    /// ```metamath-zero
+    /// (char-substr 1 3 "λxy") -- "xy"
+    /// ```
+    CharSubstr: "char-substr",
     /// `(string->list s)` converts a string to a list of character codes.
     /// ```metamath-zero
     /// (string->list "bar") -- (98 97 114)
@@ -1021,6 +1292,46 @@ str_enum! {
     /// (list->string '(98 97 114)) -- "bar"
     /// ```
     ListToString: "list->string",
+    /// `(string-split sep s)` splits the string `s` at every occurrence of the nonempty
+    /// string `sep`, returning the list of pieces.
+    /// ```metamath-zero
+    /// (string-split "," "a,b,,c") -- ("a" "b" "" "c")
+    /// ```
+    StringSplit: "string-split",
+    /// `(char->number c)` returns the unicode code point of `c`, which must be a string
+    /// containing exactly one (UTF-8 encoded) character.
+    /// ```metamath-zero
+    /// (char->number "a") -- 97
+    /// (char->number "λ") -- 955
+    /// ```
+    CharToNumber: "char->number",
+    /// `(number->char n)` returns the string containing the character with unicode code
+    /// point `n`, encoded as UTF-8.
+    /// ```metamath-zero
+    /// (number->char 955) -- "λ"
+    /// ```
+    NumberToChar: "number->char",
+    /// `(string->number s)` parses a number literal, in decimal or in hexadecimal with a `0x`
+    /// prefix, optionally preceded by a `-` sign. It returns `#undef` if `s` is not a
+    /// valid number. `(string->number s radix)` parses the digits of `s` in the given
+    /// radix (between 2 and 36) instead, with no `0x` prefix.
+    /// ```metamath-zero
+    /// (string->number "-42")       -- -42
+    /// (string->number "0x1f")      -- 31
+    /// (string->number "foo")       -- #undef
+    /// (string->number "1010" 2)    -- 10
+    /// ```
+    StringToNumber: "string->number",
+    /// `(number->string n)` formats the number `n` in decimal, and
+    /// `(number->string n radix)` formats it in the given radix (between 2 and 36), using
+    /// lowercase letters for digits above 9. `(number->string n radix width)` additionally
+    /// pads the digits with zeros on the left to at least `width` digits.
+    /// ```metamath-zero
+    /// (number->string 42)         -- "42"
+    /// (number->string 255 16)     -- "ff"
+    /// (number->string -5 2 8)     -- "-00000101"
+    /// ```
+    NumberToString: "number->string",
     /// `(not e1 e2 e3)` returns `#f` if any argument is truthy, and `#t` otherwise.
     /// It is not short-circuiting.
     Not: "not",
@@ -1033,6 +1344,10 @@ str_enum! {
     /// `(list e1 e2 e3)` returns the list `(e1 e2 e3)`. It differs from `quote`
     /// in that it evaluates its arguments.
     List: "list",
+    /// `(append l1 l2 ... ln)` returns the concatenation of the lists `l1 ... ln`.
+    /// The last argument does not have to be a list, and becomes the tail of the result,
+    /// so `(append '(1 2) 3)` returns `(1 2 . 3)`. `(append)` returns the empty list.
+    Append: "append",
     /// `(cons e1 e2)` returns `(e1 . e2)`. With more or less arguments:
     /// * `(cons)` returns the empty list.
     /// * `(cons e1)` returns `e1`.
@@ -1051,6 +1366,42 @@ str_enum! {
     /// calling `f` on the heads of all the arguments, then the second elements and so on.
     /// All lists must be the same length.
     Map: "map",
+    /// `(fixpoint f x)` computes `(f x)`, `(f (f x))`, and so on, until it reaches a value
+    /// `y` such that `(f y)` is equal to `y` (in the sense of `==`), and returns `y`.
+    /// `(fixpoint f x n)` fails if no fixpoint is reached after `n` applications of `f`
+    /// (the default limit is 10000), which guards against transformations that cycle.
+    /// This is useful for saturation procedures, where `x` is a worklist or a set of facts
+    /// and `f` adds the consequences of the current facts.
+    /// ```metamath-zero
+    /// (fixpoint (fn (n) (if {n < 10} {n + 1} n)) 0) -- 10
+    /// ```
+    Fixpoint: "fixpoint",
+    /// `(vector e1 e2 e3)` returns a vector containing `e1`, `e2` and `e3`. Vectors are
+    /// distinct from lists, and support constant time indexing.
+    Vector: "vector",
+    /// `(vector? e)` is true if the argument is a vector.
+    IsVector: "vector?",
+    /// `(vector-len v)` returns the number of elements of the vector `v`.
+    VectorLen: "vector-len",
+    /// `(vector-ref v n)` returns the `n`th element (zero-indexed) of the vector `v`.
+    /// It fails if `n` is out of range.
+    /// ```metamath-zero
+    /// (vector-ref (vector 1 2 3) 1) -- 2
+    /// ```
+    VectorRef: "vector-ref",
+    /// `(vector-set! r n e)` sets the `n`th element (zero-indexed) of the vector in the
+    /// ref-cell `r` to `e`. The vector is modified in place if it is not shared, and otherwise
+    /// the ref-cell is updated with a modified copy.
+    /// ```metamath-zero
+    /// (def r (ref! (vector 1 2 3)))
+    /// (vector-set! r 1 5)
+    /// r -- (vector 1 5 3)
+    /// ```
+    VectorSet: "vector-set!",
+    /// `(list->vector l)` converts a list to a vector with the same elements.
+    ListToVector: "list->vector",
+    /// `(vector->list v)` converts a vector to a list with the same elements.
+    VectorToList: "vector->list",
     /// `(bool? e)` is true if the argument is a boolean, `#t` or `#f`.
     IsBool: "bool?",
     /// `(atom? e)` is true if the argument is an atom (also known as a symbol), `'x`.
@@ -1066,6 +1417,15 @@ str_enum! {
     IsString: "string?",
     /// `(fn? e)` is true if the argument is a procedure.
     IsProc: "fn?",
+    /// `(callable? e)` is true if the argument is a procedure that can be called now.
+    /// This is the same as `fn?` except for the match continuation `k` bound by
+    /// `(match e [pat (=> k) code])`, which is only callable inside `code`.
+    IsCallable: "callable?",
+    /// `(cont-valid? k)` is true if `k`, which must be a match continuation bound by
+    /// `(match e [pat (=> k) code])`, can still be called, that is, if evaluation
+    /// is still inside `code`. Calling an expired continuation is an error which
+    /// explains why it expired.
+    IsContValid: "cont-valid?",
     /// `(def? e)` is true if the argument is not `#undef`.
     IsDef: "def?",
     /// `(ref? e)` is true if the argument is a ref-cell.
@@ -1088,9 +1448,20 @@ str_enum! {
     /// which can then be copied to a term using `(copy-span)`.
     /// (Useful for targeted error reporting in scripts.)
     StackSpan: "stack-span",
-    /// `(async f args)` evaluates `(f args)` on another thread, and returns a
-    /// procedure that will join on the thread to wait for the result.
+    /// `(async f args)` evaluates `(f args)` and returns the result. (The call is
+    /// evaluated on the current thread; use `spawn` to evaluate it on another thread.)
     Async: "async",
+    /// `(spawn f args)` starts evaluating `(f args)` on another thread, and returns a
+    /// promise for the result, which can be obtained using `(join p)` (or by calling `(p)`).
+    /// The call is evaluated in a copy of the environment, so changes it makes to the
+    /// environment or to the lisp globals are lost. The threads come from a pool of
+    /// `--jobs` threads, and if none of them has started the call when it is joined,
+    /// `join` evaluates it on the current thread.
+    Spawn: "spawn",
+    /// `(join p)` waits for the result of a promise `p` created by `spawn`, and returns it.
+    /// Errors reported by the call are reported when it is joined. Joining a promise
+    /// more than once returns the same result.
+    Join: "join",
     /// `(atom-map? m)` is true if the argument is an atom map.
     IsAtomMap: "atom-map?",
     /// `(atom-map! [k1 v1] [k2 v2] ...)` creates a new mutable atom map, a key-value store.
@@ -1107,13 +1478,40 @@ str_enum! {
     /// * `(insert m k v)` returns an immutable map based on the immutable map `m`,
     ///   with the value `v` inserted at key `k`.
     /// * `(insert m k)` returns `k` erased from `m`.
+    ///
+    /// If `m` is a mutable map, it is copied rather than modified. If `m` is not
+    /// shared, the map is reused, so a chain of `insert` calls does not copy the map
+    /// on every step.
     InsertNew: "insert",
-    /// `(set-timeout n)` sets the timeout for running individual theorems and
-    /// `do` blocks to `n` milliseconds. The default is 5 seconds.
+    /// * `(set-timeout n)` sets the timeout for running individual theorems and
+    ///   `do` blocks to `n` milliseconds. The default is 5 seconds.
+    /// * `(set-timeout n k)` additionally limits evaluation to `k` steps of the lisp
+    ///   interpreter. Unlike the time limit, this is deterministic, so a proof
+    ///   script that passes on one machine will not time out on a slower one.
+    ///   The default is no step limit.
+    /// * `(set-timeout n k t)` additionally limits each pattern test `(? pred)` in a
+    ///   `match` to `t` steps, so that a diverging predicate is reported at the test.
+    ///   The default is `2^24` steps.
+    ///
+    /// Setting any limit to `0` disables it. When a limit is exceeded, evaluation
+    /// is aborted with a `timeout` error, which reports the lisp stack at the point
+    /// the limit was reached.
     SetTimeout: "set-timeout",
     /// `(set-stack-limit n)` sets the maximum number of stack frames used during
     /// evaluation of theorems and `do` blocks to `n`. The default is 1024.
+    /// Calls in tail position do not use additional stack frames (even inside a
+    /// `match` branch with a continuation, if the continuation is not passed on),
+    /// so tail recursive functions run in constant space.
     SetStackLimit: "set-stack-limit",
+    /// * `(set-size-limit n)` sets the maximum size of the statements and definitions
+    ///   of declarations (counted in lisp nodes, before deduplication) to `n`.
+    /// * `(set-size-limit n k)` also sets the maximum size of proofs to `k`.
+    ///
+    /// A declaration that exceeds the limit is an error, which is reported at the tactic
+    /// call that produced the offending node if possible, to catch tactics that build
+    /// exponentially large terms before they exhaust memory. Setting a limit to 0
+    /// removes it. The defaults are `2^20` for terms and `2^24` for proofs.
+    SetSizeLimit: "set-size-limit",
     /// `(mvar? e)` returns `#t` if `e` is an unsolved metavariable value.
     /// *Note:* Holes in expressions are *not* represented as raw metavariables,
     /// they are ref-cells to metavariables. So to test if a metavariable has not
@@ -1130,10 +1528,28 @@ str_enum! {
     /// (mvar? (get! (mvar! "foo" #t)))     -- #t
     /// ```
     NewMVar: "mvar!",
-    /// `(pp e)` pretty-prints a (fully elaborated) term expression using declared
-    /// math notations. It relies on the theorem context to typecheck the formulas
-    /// and provide context, and will fall back on the generic lisp printer
-    /// for things it doesn't understand.
+    /// `(unify e1 e2)` unifies the expressions `e1` and `e2` as `refine` does, assigning
+    /// metavariables and unfolding definitions as needed. It returns an atom map from
+    /// the names of the assigned metavariables (such as `?a`) to their values, or `#f`
+    /// if the expressions do not unify, in which case no metavariables are assigned.
+    Unify: "unify",
+    /// `(match-term pat e)` matches the expression `e` against the pattern `pat`, where
+    /// the unassigned metavariables in `pat` act as pattern variables. It returns an
+    /// atom map from the names of these metavariables to the subterms of `e` they match,
+    /// or `#f` if there is no match. No metavariables are assigned, and definitions are
+    /// not unfolded.
+    MatchTerm: "match-term",
+    /// * `(pp e)` pretty-prints a (fully elaborated) term expression using declared
+    ///   math notations. It relies on the theorem context to typecheck the formulas
+    ///   and provide context, and will fall back on the generic lisp printer
+    ///   for things it doesn't understand. The result is a string, which can be
+    ///   combined with other strings using `string-append` and displayed with `display`.
+    /// * `(pp e n)` does the same, but uses a line width of `n` characters
+    ///   instead of the default of 80. The width must be nonnegative.
+    /// ```metamath-zero
+    /// (pp '(im ph ps))                -- "ph -> ps"
+    /// (display (string-append "goal: " (pp $ ph -> ps $)))
+    /// ```
     PrettyPrint: "pp",
     /// `(goal e)` creates a new goal value given a statement expression.
     /// It will need to be wrapped with a `ref!` to be used with `set-goals`.
@@ -1142,6 +1558,7 @@ str_enum! {
     GoalType: "goal-type",
     /// `(infer-type p)` gets the statement proven by the proof `p`.
     /// This does not perform full typechecking on `p`.
+    /// If `p` is a term expression rather than a proof, it returns the sort of `p`.
     InferType: "infer-type",
     /// `(infer-sort e)` returns the sort and boundedness of the expression.
     InferSort: "infer-sort",
@@ -1159,11 +1576,21 @@ str_enum! {
     /// responsible for reporting all unfinished goals. Passing `#undef` instead of
     /// a function will reset it to the default closer.
     SetCloseFn: "set-close-fn",
+    /// `(checkpoint)` saves the current environment, goals, metavariable assignments
+    /// and global lisp definitions, and returns a number `c` identifying the saved state.
+    /// Checkpoints are discarded at the end of the current statement.
+    Checkpoint: "checkpoint",
+    /// `(rollback c)` restores the state saved by `(checkpoint)` (which returned `c`),
+    /// undoing any declarations, definitions and assignments made since then.
+    /// Checkpoints made after `c` are discarded, but `c` can be rolled back to again.
+    Rollback: "rollback",
     /// `(local-ctx)` returns the list of hypothesis names (`(infer-type)`
     /// can be used to get the type of the hypotheses).
     LocalCtx: "local-ctx",
-    ///`(to-expr e)` elaborates a term pre-expression into an expression,
-    /// producing metavariables for `_` placeholders in the expression.
+    /// * `(to-expr e)` elaborates a term pre-expression into an expression,
+    ///   producing metavariables for `_` placeholders in the expression.
+    /// * `(to-expr e s)` elaborates `e` as an expression of sort `s`,
+    ///   inserting coercions if necessary.
     ToExpr: "to-expr",
     /// * `(refine p)` elaborates a proof pre-expression into a proof, and unifies
     ///   its type against the first goal.
@@ -1175,9 +1602,96 @@ str_enum! {
     ///   after which `h` may be referred to like any other theorem hypothesis.
     /// * `(have h e p)` is the same except that `p` is elaborated with `e` as the expected type.
     Have: "have",
+    /// * `(admit)` closes the first goal without proving it. This reports a warning, and the
+    ///   theorem is added without a proof, so it is reported as a hole by `axioms-of` and
+    ///   `mm0-rs deps`, and the `.mmb` file can only be written with `--allow-sorry`.
+    ///   This is meant for stubbing out parts of a proof during development.
+    /// * `(admit _ e)` returns an admitted proof of `e`. This is the form used when `admit`
+    ///   is used as a procedure in a refine script, such as `(refine '(mp ,admit h))`.
+    ///   In a quoted refine script `(admit)` works as well, as in `(refine '(mp (admit) h))`.
+    Admit: "admit",
+    /// * `(unfold defs e)` unfolds the definitions `defs` everywhere in the expression `e`,
+    ///   including in the results of unfolding, and returns `(e2 c)` where `e2` is the
+    ///   unfolded expression and `c` is a conversion proof of `e = e2`. `defs` is a list of
+    ///   definition names, or `#t` for all definitions except `abstract` definitions and
+    ///   `local` definitions of other files.
+    /// * `(unfold defs)` unfolds `defs` in the first goal, which is replaced by a goal
+    ///   for the unfolded statement.
+    Unfold: "unfold",
+    /// * `(unfold-head defs e)` is like `(unfold defs e)`, but only unfolds the definition at
+    ///   the head of `e`, once. It returns `#f` if the head of `e` is not one of `defs`.
+    /// * `(unfold-head defs)` unfolds the head of the first goal.
+    UnfoldHead: "unfold-head",
+    /// `(cong)` proves the first goal, which must be an equality `eq a b` registered with
+    /// `register-eq`, by congruence closure: it succeeds if `a = b` follows from the
+    /// equalities among the hypotheses using reflexivity, symmetry, transitivity and
+    /// the congruence lemmas registered with `register-congr`.
+    Cong: "cong",
+    /// `(register-eq 'eq 'refl 'symm 'trans)` registers the binary term `eq` as the
+    /// equality on the sort of its arguments for `cong`, where `refl: eq x x`,
+    /// `symm (h: eq x y): eq y x` and `trans (h1: eq x y) (h2: eq y z): eq x z`.
+    RegisterEq: "register-eq",
+    /// `(register-congr 'thm)` registers `thm` as the congruence lemma for `cong`
+    /// for the term `t` in its conclusion. It should have the form
+    /// `(h1: eq1 a1 b1) ... (hn: eqn an bn): eq (t a1 ... an) (t b1 ... bn)`, where
+    /// arguments that must be the same on both sides (such as bound variables) are
+    /// written with the same variable and have no hypothesis.
+    RegisterCongr: "register-congr",
+    /// * `(rw 'h)` rewrites the first goal with `h`, a theorem or hypothesis proving a
+    ///   registered equality `eq l r`: the first instance of `l` in the goal is found, and
+    ///   it and all other occurrences of the same instance are replaced by the instance of `r`.
+    ///   The goal is replaced by the rewritten goal and the hypotheses of `h`.
+    /// * `(rw '(<- h))` rewrites from right to left.
+    /// * `(rw 'h n)` only rewrites the `n`th occurrence of the instance, counting from 1.
+    Rw: "rw",
+    /// `(register-mp 'thm)` registers `thm: (h1: eq x y) (h2: y): x` as the lemma `rw`
+    /// uses to prove a goal from the rewritten goal, where `eq` is the equality registered
+    /// with `register-eq` for the sort of the goals.
+    RegisterMp: "register-mp",
+    /// * `(norm-num)` proves the first goal, which must be an equality, order or
+    ///   disequality between expressions built from binary numerals with addition and
+    ///   multiplication, using the terms and lemmas registered with `register-norm-num`.
+    ///   If the right side of an equality is a metavariable, it is assigned the value of
+    ///   the left side, as a normal numeral.
+    /// * `(norm-num e)` evaluates the numeral expression `e`, returning `(n p)` where
+    ///   `n` is the normal numeral with the same value and `p` is a proof of `e = n`.
+    NormNum: "norm-num",
+    /// `(register-norm-num 'role 'x)` registers the term or theorem `x` for `role` in the
+    /// `norm-num` tactic. The terms `zero`, `one`, `bit0`, `bit1`, `add`, `mul` and `eq` must
+    /// be registered before any lemmas; see the documentation of `norm-num` for the list of
+    /// lemma roles and the statements they must have.
+    RegisterNormNum: "register-norm-num",
+    /// `(register-tactic 't f)` registers the procedure `f` as a decision procedure
+    /// for goals whose statement is an application of the term `t`. When `refine` would
+    /// create a new goal for such a statement (for a `_` or an omitted hypothesis),
+    /// it instead calls `f` as a refine procedure, with the arguments `refine` and the goal
+    /// statement. `(register-tactic 't #undef)` removes the registration.
+    RegisterTactic: "register-tactic",
     /// `(stat)` prints the current proof state, which consists of a list of
     /// subproofs, a list of goals, and a list of metavariables accompanied by their sorts.
     Stat: "stat",
+    /// `(get-stat)` returns the current proof state as a string, in the same
+    /// format that `(stat)` prints it.
+    GetStat: "get-stat",
+    /// `(save-state "f")` writes the current proof state (variables, hypotheses, goals and
+    /// metavariables) to the file `f`, relative to the current file, so that it can be
+    /// attached to a bug report or resumed later with `mm0-rs load-state f`.
+    SaveState: "save-state",
+    /// * `(set-goal-display 'sequent)` makes `stat` display the proof state sequent-style,
+    ///   with numbered hypotheses followed by the goals after a `⊢`.
+    /// * `(set-goal-display 'flat)` returns to the default display, which shows
+    ///   one line per subproof and one `|-` line per goal.
+    SetGoalDisplay: "set-goal-display",
+    /// `(goal->sequent g)` returns the goal `g` as a sequent `(hyps concl)`, where
+    /// `hyps` is a list of `(h e)` pairs, one for each hypothesis `h: e` in the local
+    /// context, and `concl` is the statement of the goal.
+    /// ```metamath-zero
+    /// theorem foo (h1: $ a $) (h2: $ b $): $ c $ =
+    /// (focus (display (->string (goal->sequent (hd (get-goals)))))
+    ///   -- (((h1 a) (h2 b)) c)
+    ///   ...)
+    /// ```
+    GoalToSequent: "goal->sequent",
     /// `(get-decl x)` returns the declaration information associated to declaration `x`.
     /// The result has one of the following forms:
     ///
@@ -1205,6 +1719,26 @@ str_enum! {
     ///    or atom map of dummy variables, and `proof` is the proof s-expression. `vtask`
     ///    can also have the form `(ds proof)` itself.
     GetDecl: "get-decl",
+    /// `(sort? x)` is true if `x` is an atom naming a sort.
+    IsSort: "sort?",
+    /// `(term? x)` is true if `x` is an atom naming a term or def.
+    IsTerm: "term?",
+    /// `(thm? x)` is true if `x` is an atom naming an axiom or theorem.
+    IsThm: "thm?",
+    /// `(axioms-of x)` returns `(axs holes)`, where `axs` is the list of axioms that the
+    /// theorem `x` depends on (transitively through the theorems used in its proof), and
+    /// `holes` is the list of theorems without a proof that it depends on. Both lists are
+    /// in declaration order. A theorem with an empty `holes` list is fully proved from
+    /// the axioms `axs`. For example, `(axioms-of 'id)` returns `((ax_1 ax_2 ax_mp) ())`.
+    AxiomsOf: "axioms-of",
+    /// `(get-sort x)` returns `('sort x mods)`, where `mods` is the list of sort modifiers
+    /// of sort `x`, a subset of `'(pure strict provable free)` in that order.
+    /// It returns `#undef` if `x` is not a sort.
+    GetSort: "get-sort",
+    /// `(term-sorts x)` returns `((s1 ... sn) s)`, where `s1, ..., sn` are the sorts of the
+    /// arguments of the term or def `x`, and `s` is its return sort.
+    /// For example, `(term-sorts 'imp)` returns `((wff wff) wff)`.
+    TermSorts: "term-sorts",
     /// `(add-decl! decl-data ...)` adds a new declaration, as if a new `def` or `theorem`
     /// declaration was created. This does not do any elaboration - all information is
     /// expected to be fully elaborated. The input format is the same as the output format
@@ -1218,20 +1752,66 @@ str_enum! {
     /// * `(add-thm! x bis hyps ret vis vtask)` is the same as
     ///   `(add-decl! 'theorem x bis hyps ret vis vtask)`.
     AddThm: "add-thm!",
+    /// `(fresh-name x)` returns the first atom among `x`, `x_1`, `x_2`, ... that is not
+    /// already the name of a declaration. `x` may be a string or an atom. This is useful
+    /// for tactics that generate lemmas, so that the generated names do not clash
+    /// with existing declarations. If `name-policy` is defined, the result is also
+    /// checked against it, as the name of a declaration of kind `k` in
+    /// `(fresh-name x k)` (default `'theorem`), and if the policy suggests another name
+    /// the search continues from there.
+    FreshName: "fresh-name",
     /// * `(dummy! x s)` produces a new dummy variable called `x` with sort `s`, and returns `x`;
     /// * `(dummy! s)` automatically gives the variable a name like `_123` that is guaranteed to be unused.
     NewDummy: "dummy!",
     /// `(check-proofs b)` turns on (`b = #t`) or off (`b = #f`) proof checking for theorems.
     CheckProofs: "check-proofs",
+    /// `(normalize-tokens b)` turns on (`b = #t`) or off (`b = #f`) the normalization of
+    /// notation tokens to Unicode NFC form. It is on by default (except in MM0 files),
+    /// so that tokens which are written differently but are canonically equivalent,
+    /// like `U+00C5` (`Å`) and `A` followed by the combining ring `U+030A`, are the same token.
+    NormalizeTokens: "normalize-tokens",
+    /// `(warn-precedence b)` turns on (`b = #t`) or off (`b = #f`) a warning for math
+    /// strings containing an infix operator applied directly to another infix operator
+    /// with a precedence differing by only 1, such as `a + b * c` when `+` has precedence 65
+    /// and `*` has precedence 66, whose parse would change if either precedence changed by 1.
+    WarnPrecedence: "warn-precedence",
+    /// `(set-backtrace b)` turns on (`b = #t`) or off (`b = #f`) detailed backtraces
+    /// for lisp errors. When it is on, each call in the backtrace of an error is shown
+    /// with its arguments (truncated if they are long), instead of just the function name.
+    /// It is off by default, unless `mm0-rs compile` is run with `--backtrace`.
+    SetBacktrace: "set-backtrace",
+    /// `(profile-lisp b)` turns on (`b = #t`) or off (`b = #f`) the lisp profiler. While it
+    /// is on, the number of calls, evaluation steps, time and allocations of each named
+    /// procedure are counted (the steps, time and allocations do not include those of the named
+    /// procedures it calls). Turning it on resets the counters. This slows down evaluation
+    /// considerably, so it should only be used for finding the slow parts of a tactic.
+    ProfileLisp: "profile-lisp",
+    /// `(profile-report)` returns the counters collected by the lisp profiler (see `profile-lisp`)
+    /// as a string containing a table, sorted by the time spent in each procedure.
+    ProfileReport: "profile-report",
+    /// `(strict-overrides b)` turns on (`b = #t`) or off (`b = #f`) strict checking of
+    /// global definitions that override a builtin. While it is on, such a definition must
+    /// be a procedure taking the same number of arguments as the builtin it replaces,
+    /// or else it is an error. It is off by default.
+    StrictOverrides: "strict-overrides",
+    /// `(overridden-builtins)` returns the list of names of builtins that have been
+    /// redefined by a global definition in the current environment.
+    OverriddenBuiltins: "overridden-builtins",
     /// * `(set-reporting type b)` turns on (`b = #t`) or off (`b = #f`)
     ///   error reporting for error type `type`, which can be `'error`, `'info` or `'warn`.
     ///   (Compilation will still be aborted if there are errors, even if the
     ///   display is suppressed.)
     /// * `(set-reporting b)` will set the error reporting to `b` for all error types.
     SetReporting: "set-reporting",
-    /// `refine-extra-args` can be called directly, but it simply returns an error. It is called
-    /// by `refine` when elaborating a term with too many arguments, and is expected to be
-    /// overridden by user code to provide a more useful behavior.
+    /// * `(with-reporting type b f)` calls `(f)` with error reporting for error type `type`
+    ///   turned on or off, as in `(set-reporting type b)`, and restores the previous
+    ///   reporting mode when `f` returns (or throws an error).
+    /// * `(with-reporting b f)` is the same but sets the error reporting for all error types.
+    WithReporting: "with-reporting",
+    /// `(refine-extra-args callback tgt e args..)` is called by `refine` when elaborating
+    /// a proof application `e` with too many arguments `args`, and is expected to be
+    /// overridden by user code to provide a more useful behavior. The default implementation
+    /// returns `e` if there are no extra arguments and gives an error otherwise.
     RefineExtraArgs: "refine-extra-args",
     /// `(eval-string e1 e2 ...)` takes as input zero or more expressions which are elaborated
     /// as type `string`, and then evaluates them to an actual lisp string. This has the same
@@ -1246,6 +1826,8 @@ str_enum! {
   }
 }
 
+crate::deep_size_0!(BuiltinProc);
+
 impl std::fmt::Display for BuiltinProc {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     self.to_str().fmt(f)
@@ -255,6 +255,7 @@ env_debug! {
   crate::mmc::Compiler,
   crate::elab::lisp::BuiltinProc,
   crate::elab::lisp::ProcSpec,
+  crate::elab::parallel::Task,
   crate::parser::ast::Prec,
   crate::elab::environment::Literal,
   crate::parser::ast::Modifiers,
@@ -0,0 +1,228 @@
+//! A step debugger for lisp evaluation.
+//!
+//! When a [`Debugger`] is installed (see [`install`]), every elaborator created
+//! afterwards checks it before each procedure call. If the call is to a procedure with a
+//! breakpoint, or the user has asked to step, the evaluator pauses: it sends a [`Paused`]
+//! report (the call, the stack and the local context) to the client, and blocks until
+//! the client sends a [`DebugCommand`] saying how to continue.
+//!
+//! Only one evaluation can be paused at a time; if another evaluation hits a breakpoint
+//! while one is paused, it waits until the first one is resumed before reporting.
+//!
+//! A paused evaluation blocks its thread, so evaluations only pause on threads started
+//! by [`spawn`], and never on the threads of a shared pool, where they would hold up
+//! unrelated jobs.
+
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use crate::util::{FileSpan, MutexExt};
+
+lazy_static! {
+  static ref DEBUGGER: Mutex<Option<Arc<Debugger>>> = Mutex::new(None);
+}
+
+// True on a thread started by `spawn`, on which evaluations may pause.
+thread_local!(static DEBUG_THREAD: Cell<bool> = Cell::new(false));
+
+/// How often a paused evaluation checks whether it has been canceled.
+const CANCEL_POLL: Duration = Duration::from_millis(100);
+
+/// A command from the client telling a paused evaluation how to continue.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DebugCommand {
+  /// Run until the next breakpoint.
+  Continue,
+  /// Pause at the next procedure call.
+  Step,
+  /// Pause at the next procedure call that is not inside the current call.
+  Next,
+  /// Pause at the next procedure call after the current procedure returns.
+  StepOut,
+  /// Abort the evaluation with an error.
+  Stop,
+}
+
+/// The stepping state of an evaluator, which is updated by each [`DebugCommand`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StepMode {
+  /// Only pause at breakpoints.
+  Continue,
+  /// Pause at the next procedure call.
+  Step,
+  /// Pause at the next procedure call with a stack depth of at most `n`.
+  Next(usize),
+  /// Pause at the next procedure call with a stack depth less than `n`.
+  StepOut(usize),
+}
+
+impl Default for StepMode {
+  fn default() -> Self { StepMode::Continue }
+}
+
+impl StepMode {
+  /// Returns true if the evaluator should pause at a call with the given stack depth.
+  #[must_use] pub fn should_pause(self, depth: usize) -> bool {
+    match self {
+      StepMode::Continue => false,
+      StepMode::Step => true,
+      StepMode::Next(n) => depth <= n,
+      StepMode::StepOut(n) => depth < n,
+    }
+  }
+
+  /// The new stepping state after the command `cmd` is given at a call with the given
+  /// stack depth.
+  #[must_use] pub fn after(cmd: DebugCommand, depth: usize) -> Self {
+    match cmd {
+      DebugCommand::Continue | DebugCommand::Stop => StepMode::Continue,
+      DebugCommand::Step => StepMode::Step,
+      DebugCommand::Next => StepMode::Next(depth),
+      DebugCommand::StepOut => StepMode::StepOut(depth),
+    }
+  }
+}
+
+/// A frame of the lisp call stack, in a [`Paused`] report.
+#[derive(Debug)]
+pub struct Frame {
+  /// The function and its arguments, like `(foo 1 2)`.
+  pub label: String,
+  /// The location of the call.
+  pub span: FileSpan,
+}
+
+/// The report sent to the client when an evaluation pauses.
+#[derive(Debug)]
+pub struct Paused {
+  /// The location of the call that is about to be made.
+  pub span: FileSpan,
+  /// The call that is about to be made, like `(foo 1 2)`.
+  pub call: String,
+  /// The lisp call stack, innermost first.
+  pub stack: Vec<Frame>,
+  /// The values in the local context of the caller, oldest first.
+  pub locals: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct DebugState {
+  /// The names of the procedures to pause at.
+  breakpoints: HashSet<String>,
+  /// True if the next procedure call (in any evaluation) should pause.
+  break_next: bool,
+  /// True if an evaluation is currently paused.
+  paused: bool,
+  /// The command for the paused evaluation, once the client has sent it.
+  command: Option<DebugCommand>,
+}
+
+/// The shared state of the debugger, between the server (which receives commands from
+/// the client) and the evaluators (which pause and wait for them).
+pub struct Debugger {
+  state: Mutex<DebugState>,
+  cvar: Condvar,
+  /// True once the debugger has been uninstalled, after which nothing pauses.
+  ended: AtomicBool,
+  /// The function that reports a pause to the client.
+  on_pause: Box<dyn Fn(Paused) + Send + Sync>,
+}
+
+impl std::fmt::Debug for Debugger {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Debugger").field("state", &self.state).finish()
+  }
+}
+
+impl Debugger {
+  /// Set the names of the procedures to pause at, replacing the previous breakpoints.
+  pub fn set_breakpoints(&self, procs: impl IntoIterator<Item=String>) {
+    self.state.ulock().breakpoints = procs.into_iter().collect()
+  }
+
+  /// Pause at the next procedure call, in whatever evaluation makes it.
+  pub fn pause_next(&self) { self.state.ulock().break_next = true }
+
+  /// Resume the paused evaluation with the command `cmd`. Does nothing if no
+  /// evaluation is paused.
+  pub fn command(&self, cmd: DebugCommand) {
+    let mut g = self.state.ulock();
+    if g.paused {
+      g.command = Some(cmd);
+      self.cvar.notify_all();
+    }
+  }
+
+  /// Returns true if a call to the procedure `name` should pause, because it has a
+  /// breakpoint or the client asked to pause at the next call.
+  /// Calls on a thread not started by [`spawn`] never pause.
+  #[must_use] pub fn is_breakpoint(&self, name: Option<&[u8]>) -> bool {
+    if self.ended.load(Ordering::Relaxed) || !DEBUG_THREAD.with(Cell::get) {return false}
+    let g = self.state.ulock();
+    g.break_next || name.map_or(false, |name|
+      std::str::from_utf8(name).map_or(false, |name| g.breakpoints.contains(name)))
+  }
+
+  /// Report the pause `p` to the client, and block until the client sends a command
+  /// or `cancel` is set (in which case the evaluation is stopped).
+  pub fn pause(&self, p: Paused, cancel: &AtomicBool) -> DebugCommand {
+    let mut g = self.state.ulock();
+    while g.paused {
+      if self.ended.load(Ordering::Relaxed) || cancel.load(Ordering::Relaxed) {
+        return DebugCommand::Continue
+      }
+      g = self.cvar.wait_timeout(g, CANCEL_POLL).expect("propagating poisoned mutex").0;
+    }
+    g.paused = true;
+    g.break_next = false;
+    g.command = None;
+    drop(g);
+    (self.on_pause)(p);
+    let mut g = self.state.ulock();
+    let cmd = loop {
+      if let Some(cmd) = g.command.take() {break cmd}
+      if self.ended.load(Ordering::Relaxed) {break DebugCommand::Continue}
+      if cancel.load(Ordering::Relaxed) {break DebugCommand::Stop}
+      g = self.cvar.wait_timeout(g, CANCEL_POLL).expect("propagating poisoned mutex").0;
+    };
+    g.paused = false;
+    self.cvar.notify_all();
+    cmd
+  }
+
+  /// Stop pausing, and resume any paused evaluation.
+  fn end(&self) {
+    self.ended.store(true, Ordering::Relaxed);
+    let _g = self.state.ulock();
+    self.cvar.notify_all();
+  }
+}
+
+/// Install a new debugger, which reports pauses using `on_pause`, replacing the current
+/// one if there is one. Elaborations that started before this keep the old debugger,
+/// which no longer pauses.
+pub fn install(on_pause: impl Fn(Paused) + Send + Sync + 'static) -> Arc<Debugger> {
+  let dbg = Arc::new(Debugger {
+    state: Mutex::default(),
+    cvar: Condvar::new(),
+    ended: AtomicBool::new(false),
+    on_pause: Box::new(on_pause),
+  });
+  if let Some(old) = DEBUGGER.ulock().replace(dbg.clone()) { old.end() }
+  dbg
+}
+
+/// Uninstall the current debugger, resuming any paused evaluation.
+pub fn uninstall() {
+  if let Some(old) = DEBUGGER.ulock().take() { old.end() }
+}
+
+/// Run `f` on a new thread, on which evaluations may pause in the debugger.
+pub fn spawn(f: impl FnOnce() + Send + 'static) {
+  std::thread::spawn(move || { DEBUG_THREAD.with(|t| t.set(true)); f() });
+}
+
+/// The current debugger, if one is installed.
+#[must_use] pub fn current() -> Option<Arc<Debugger>> { DEBUGGER.ulock().clone() }
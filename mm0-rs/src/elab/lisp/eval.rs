@@ -8,21 +8,24 @@ use std::ops::{Deref, DerefMut};
 use std::mem;
 use std::time::{Instant, Duration};
 use std::sync::atomic::Ordering;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 use std::convert::TryInto;
-use num::{BigInt, ToPrimitive};
+use num::{BigInt, ToPrimitive, Zero};
 use crate::util::{ArcString, FileRef, FileSpan, SliceExt, Span};
 use crate::parser::ast::SExpr;
 use super::super::{Result, Elaborator, LispData,
   AtomID, Environment, AtomData, DeclKey, StmtTrace,
-  ElabError, ElabErrorKind, ErrorLevel, BoxError, ObjectKind,
-  refine::{RStack, RState, RefineResult}};
-use super::{Arc, BuiltinProc, Cell, InferTarget, LispKind, LispRef, LispVal,
-  Modifiers, Proc, ProcPos, ProcSpec, QExpr, Rc, RefCell, ThmID, Uncons};
+  ElabError, ElabErrorKind, ErrorLevel, BoxError, arc_error, ObjectKind, ReportMode,
+  refine::{RStack, RState, RefineResult, UnfoldDefs}};
+use super::{Arc, BuiltinProc, ContExpiry, InferTarget, LispKind, LispRef, LispVal,
+  MatchCont, Modifiers, Proc, ProcPos, ProcSpec, Promise, QExpr, Rc, RefCell, ThmID, Uncons};
 use super::parser::{IR, Branch, Pattern, MVarPattern, DefTarget};
 use super::super::local_context::{InferSort, AwaitingProof, try_get_span};
 use super::super::environment::{TermKind, ThmKind, ExprNode, ProofNode};
-use super::print::{FormatEnv, EnvDisplay};
+use super::print::{FormatEnv, EnvDisplay, alphanumber};
+use super::debugger::{DebugCommand, Debugger, Frame, Paused, StepMode};
+use super::output::Output;
 
 #[derive(Debug)]
 enum Stack<'a> {
@@ -31,23 +34,29 @@ enum Stack<'a> {
   DottedList2(Vec<LispVal>),
   App(Span, Span, &'a [IR]),
   App2(Span, Span, LispVal, Vec<LispVal>, std::slice::Iter<'a, IR>),
-  AppHead(Span, Span, LispVal),
+  AppHead(Span, Span, Vec<LispVal>),
   If(&'a IR, &'a IR),
   NoTailRec,
+  Letrec(usize, &'a DefTarget, std::slice::Iter<'a, (DefTarget, IR)>, &'a IR),
   Def(Option<&'a DefTarget>),
   Eval(&'a IR, std::slice::Iter<'a, IR>),
   Match(Span, std::slice::Iter<'a, Branch>),
   TestPattern(Span, LispVal, std::slice::Iter<'a, Branch>,
-    &'a Branch, Vec<PatternStack<'a>>, Box<[LispVal]>),
+    &'a Branch, Vec<PatternStack<'a>>, Box<[LispVal]>, Span, bool),
+  Guard(Span, LispVal, std::slice::Iter<'a, Branch>, &'a Branch, usize),
   Drop(usize),
-  Ret(FileSpan, ProcPos, Vec<LispVal>, Arc<IR>),
-  MatchCont(Span, LispVal, std::slice::Iter<'a, Branch>, Rc<Cell<bool>>),
+  Ret(FileSpan, ProcPos, ProcSpec, Vec<LispVal>),
+  MatchCont(Span, LispVal, std::slice::Iter<'a, Branch>, Rc<MatchCont>),
   MapProc(Span, Span, LispVal, Box<[Uncons]>, Vec<LispVal>),
+  Fixpoint(Span, Span, LispVal, LispVal, usize, usize),
   AddThmProc(FileSpan, Box<AwaitingProof>),
   Refines(Span, Option<Span>, std::slice::Iter<'a, IR>),
   Refine {sp: Span, stack: Vec<RStack>},
   Focus(Span, bool, Vec<LispVal>),
   Have(Span, LispVal, AtomID),
+  Reporting(ReportMode),
+  Try(Span, LispVal),
+  Label(AtomID),
 }
 
 impl<'a> EnvDisplay for Stack<'a> {
@@ -61,16 +70,21 @@ impl<'a> EnvDisplay for Stack<'a> {
       &Stack::App(_, _, irs) => write!(f, "(_ {})", fe.to(irs)),
       Stack::App2(_, _, e, es, irs) => write!(f, "({} {}\n  _ {})",
         fe.to(e), fe.to(es), fe.to(irs.as_slice())),
-      Stack::AppHead(_, _, e) => write!(f, "(_ {})", fe.to(e)),
+      Stack::AppHead(_, _, es) => write!(f, "(_ {})", fe.to(es)),
       &Stack::If(e1, e2) => write!(f, "(if _ {} {})", fe.to(e1), fe.to(e2)),
       Stack::NoTailRec => write!(f, "(no-tail-rec)"),
+      &Stack::Letrec(_, x, _, body) => write!(f, "(letrec ({} _) ...\n  {})",
+        fe.to(&x.as_ref().map_or(AtomID::UNDER, |&(_, _, _, a)| a)), fe.to(body)),
       &Stack::Def(Some(&Some((_, _, _, a)))) => write!(f, "(def {} _)", fe.to(&a)),
       Stack::Def(_) => write!(f, "(def _ _)"),
       &Stack::Eval(ir, ref es) => write!(f, "(begin\n  _ {} {})", fe.to(ir), fe.to(es.as_slice())),
       Stack::Match(_, bs) => write!(f, "(match _\n  {})", fe.to(bs.as_slice())),
-      &Stack::TestPattern(_, ref e, ref bs, br, _, _) => write!(f,
+      &Stack::TestPattern(_, ref e, ref bs, br, _, _, _, _) => write!(f,
         "(match {}\n  {}\n  {})\n  ->(? _)",
         fe.to(e), fe.to(br), fe.to(bs.as_slice())),
+      &Stack::Guard(_, ref e, ref bs, br, _) => write!(f,
+        "(match {}\n  {}\n  {})\n  ->(:when _)",
+        fe.to(e), fe.to(br), fe.to(bs.as_slice())),
       &Stack::Drop(n) => write!(f, "drop {}", n),
       Stack::Ret(_, pos, _, _) => match pos {
         &ProcPos::Named(_, _, a) => write!(f, "ret {}", fe.to(&a)),
@@ -80,11 +94,16 @@ impl<'a> EnvDisplay for Stack<'a> {
         fe.to(e), fe.to(bs.as_slice())),
       Stack::MapProc(_, _, e, us, es) => write!(f, "(map {}\n  {})\n  ->{} _",
         fe.to(e), fe.to(&**us), fe.to(es)),
+      &Stack::Fixpoint(_, _, ref e, ref x, n, _) => write!(f, "(fixpoint {} {})\n  ->#{} _",
+        fe.to(e), fe.to(x), n),
       Stack::AddThmProc(_, ap) => write!(f, "(add-thm {} _)", fe.to(&ap.atom())),
       Stack::Refines(_, _, irs) => write!(f, "(refine _ {})", fe.to(irs.as_slice())),
       Stack::Refine {..} => write!(f, "(refine _)"),
       &Stack::Focus(_, cl, ref es) => write!(f, "(focus {} _)\n  ->{}", cl, fe.to(es)),
       Stack::Have(_, _, a) => write!(f, "(have {} _)", fe.to(a)),
+      Stack::Reporting(_) => write!(f, "(with-reporting _)"),
+      Stack::Try(_, h) => write!(f, "(try _ {})", fe.to(h)),
+      Stack::Label(a) => write!(f, "(label {} _)", fe.to(a)),
     }
   }
 }
@@ -153,6 +172,21 @@ impl LispKind {
       _ => (None, None)
     }
   }
+
+  fn make_vec_mut<T>(&self, f: impl FnOnce(&mut [LispVal]) -> T) -> (Option<T>, Option<LispVal>) {
+    match self {
+      LispKind::Vector(es) => {
+        let mut es = es.clone();
+        (Some(f(&mut es)), Some(LispVal::vector(es)))
+      }
+      LispKind::Annot(sp, e) => match e.make_vec_mut(f) {
+        (r, None) => (r, None),
+        (r, Some(e)) => (r, Some(LispVal::new(LispKind::Annot(sp.clone(), e)))),
+      },
+      LispKind::Ref(m) => (m.get_mut(|e| e.as_vec_mut(f)), None),
+      _ => (None, None)
+    }
+  }
 }
 impl LispVal {
   fn as_map_mut<T>(&mut self, f: impl FnOnce(&mut HashMap<AtomID, LispVal>) -> T) -> Option<T> {
@@ -168,6 +202,41 @@ impl LispVal {
       Some(_) => None
     }
   }
+
+  fn as_vec_mut<T>(&mut self, f: impl FnOnce(&mut [LispVal]) -> T) -> Option<T> {
+    match self.get_mut() {
+      None => {
+        let (r, new) = self.make_vec_mut(f);
+        if let Some(e) = new {*self = e}
+        r
+      }
+      Some(LispKind::Vector(es)) => Some(f(es)),
+      Some(LispKind::Annot(_, e)) => Self::as_vec_mut(e, f),
+      Some(LispKind::Ref(m)) => m.get_mut(|e| Self::as_vec_mut(e, f)),
+      Some(_) => None
+    }
+  }
+
+  /// Copy-on-write modification of an immutable map. Unlike [`as_map_mut`](Self::as_map_mut),
+  /// this never writes through a [`Ref`](LispKind::Ref); the map is modified in place
+  /// only if this is the unique owner, and otherwise it is cloned first.
+  fn into_map_mut<T>(self, f: impl FnOnce(&mut HashMap<AtomID, LispVal>) -> T) -> Option<(T, LispVal)> {
+    match self.try_unwrap() {
+      Ok(LispKind::AtomMap(mut m)) => Some((f(&mut m), LispVal::new(LispKind::AtomMap(m)))),
+      Ok(LispKind::Annot(_, e)) => e.into_map_mut(f),
+      Ok(LispKind::Ref(m)) => m.into_inner().into_map_mut(f),
+      Ok(_) => None,
+      Err(e) => match &*e {
+        LispKind::AtomMap(m) => {
+          let mut m = m.clone();
+          Some((f(&mut m), LispVal::new(LispKind::AtomMap(m))))
+        }
+        LispKind::Annot(_, e) => e.clone().into_map_mut(f),
+        LispKind::Ref(m) => m.unref().into_map_mut(f),
+        _ => None
+      }
+    }
+  }
 }
 
 #[derive(Debug)]
@@ -208,11 +277,36 @@ impl<'a> EnvDisplay for PatternState<'a> {
   }
 }
 
-struct TestPending<'a>(Span, LispVal, &'a IR);
+struct TestPending<'a>(Span, Vec<LispVal>, &'a IR);
+
+/// An error raised by a lisp builtin, used by functions that work without an elaboration
+/// context. It is converted to an [`ElabError`] by the builtin that raised it, which also
+/// determines which of its arguments was at fault.
+#[derive(Debug)]
+pub enum LispError {
+  /// An error described by a message.
+  Msg(String),
+  /// A value had the wrong type. `expected` is the name of the expected type, in the format
+  /// of [`LispKind::type_name`], and `actual` is the value that was provided.
+  Type {
+    /// The name of the expected type.
+    expected: &'static str,
+    /// The value that was provided.
+    actual: LispVal,
+  },
+}
+
+impl From<String> for LispError {
+  fn from(s: String) -> Self { LispError::Msg(s) }
+}
+
+impl From<&str> for LispError {
+  fn from(s: &str) -> Self { LispError::Msg(s.into()) }
+}
 
-/// A [`Result`](std::result::Result) type alias for string errors, used by functions that
+/// A [`Result`](std::result::Result) type alias for [`LispError`], used by functions that
 /// work without an elaboration context.
-pub type SResult<T> = std::result::Result<T, String>;
+pub type SResult<T> = std::result::Result<T, LispError>;
 
 fn pattern_match<'b>(stack: &mut Vec<PatternStack<'b>>, ctx: &mut [LispVal],
     mut active: PatternState<'b>) -> std::result::Result<bool, TestPending<'b>> {
@@ -230,7 +324,11 @@ fn pattern_match<'b>(stack: &mut Vec<PatternStack<'b>>, ctx: &mut [LispVal],
           if let LispKind::Bool(b2) = *e {b == b2} else {false})),
         Pattern::Undef => PatternState::Ret(e.unwrapped(|e| *e == LispKind::Undef)),
         Pattern::Number(i) => PatternState::Ret(e.unwrapped(|e|
-          if let LispKind::Number(i2) = e {i == i2} else {false})),
+          match *e {
+            LispKind::Number(ref i2) => i == i2,
+            LispKind::SmallInt(i2) => i.to_i64() == Some(i2),
+            _ => false
+          })),
         Pattern::MVar(p) => e.unwrapped(|e| match e {
           LispKind::MVar(_, is) => match (p, is) {
             (MVarPattern::Any, _) |
@@ -262,12 +360,18 @@ fn pattern_match<'b>(stack: &mut Vec<PatternStack<'b>>, ctx: &mut [LispVal],
         })),
         Pattern::DottedList(ps, r) => PatternState::List(Uncons::from(e), ps.iter(), Dot::DottedList(r)),
         &Pattern::List(ref ps, n) => PatternState::List(Uncons::from(e), ps.iter(), Dot::List(n)),
+        Pattern::Vector(ps) => match e.as_vector(|es| es.to_vec()) {
+          Some(es) => PatternState::List(Uncons::from(LispVal::list(es)), ps.iter(), Dot::List(None)),
+          None => PatternState::Ret(false),
+        },
         Pattern::And(ps) => PatternState::Binary(false, false, e, ps.iter()),
         Pattern::Or(ps) => PatternState::Binary(true, true, e, ps.iter()),
         Pattern::Not(ps) => PatternState::Binary(true, false, e, ps.iter()),
-        &Pattern::Test(sp, ref ir, ref ps) => {
+        &Pattern::Test(sp, ref ir, ref xs, ref ps) => {
           stack.push(PatternStack::Binary(false, false, e.clone(), ps.iter()));
-          return Err(TestPending(sp, e, ir))
+          let mut args = vec![e];
+          args.extend(xs.iter().map(|&i| ctx[i].clone()));
+          return Err(TestPending(sp, args, ir))
         },
       },
       PatternState::Ret(b) => match stack.pop() {
@@ -307,6 +411,90 @@ fn pattern_match<'b>(stack: &mut Vec<PatternStack<'b>>, ctx: &mut [LispVal],
   }
 }
 
+/// Returns true if the match continuation `valid` cannot be called
+/// once the local context `ctx` is discarded, because the only reference to it is the
+/// variable bound in `ctx` (or there is none). In this case a call in tail position
+/// does not need to preserve the [`Stack::MatchCont`] frame.
+fn cont_unreachable(ctx: &mut [LispVal], valid: &Rc<MatchCont>) -> bool {
+  match Rc::strong_count(valid) {
+    1 => true,
+    2 => ctx.iter_mut().rev().any(|e| matches!(e.get_mut(),
+      Some(LispKind::Proc(Proc::MatchCont(v))) if Rc::ptr_eq(v, valid))),
+    _ => false
+  }
+}
+
+/// Fold `f` over `args` (starting from `init`, or the first argument if `init` is `None`),
+/// if all the arguments are small integers and no overflow occurs. If this returns `None`,
+/// the caller should fall back on the bignum implementation.
+fn small_int_fold(args: &[LispVal], init: Option<i64>, f: impl Fn(i64, i64) -> Option<i64>) -> Option<i64> {
+  let mut it = args.iter();
+  let mut n = match init { Some(n) => n, None => it.next()?.as_small_int()? };
+  for e in it { n = f(n, e.as_small_int()?)? }
+  Some(n)
+}
+
+impl Environment {
+  fn proof_node(&self, hyps: &[(Option<AtomID>, ExprNode)],
+    heap: &[LispVal], ds: &mut Vec<LispVal>, p: &ProofNode) -> LispVal {
+    match p {
+      &ProofNode::Ref(n) => heap[n].clone(),
+      &ProofNode::Dummy(a, s) => {
+        let a = LispVal::atom(a);
+        ds.push(LispVal::list(vec![a.clone(), LispVal::atom(self.sorts[s].atom)]));
+        a
+      }
+      &ProofNode::Term {term, args: ref es} |
+      &ProofNode::Cong {term, args: ref es} => {
+        let mut args = vec![LispVal::atom(self.terms[term].atom)];
+        args.extend(es.iter().map(|e| self.proof_node(hyps, heap, ds, e)));
+        LispVal::list(args)
+      }
+      &ProofNode::Hyp(h, _) => LispVal::atom(hyps[h].0.unwrap_or(AtomID::UNDER)),
+      &ProofNode::Thm {thm, args: ref es, ..} => {
+        let mut args = vec![LispVal::atom(self.thms[thm].atom)];
+        args.extend(es.iter().map(|e| self.proof_node(hyps, heap, ds, e)));
+        LispVal::list(args)
+      }
+      ProofNode::Conv(es) => {
+        let (t, c, p) = &**es;
+        LispVal::list(vec![LispVal::atom(AtomID::CONV),
+          self.proof_node(hyps, heap, ds, t),
+          self.proof_node(hyps, heap, ds, c),
+          self.proof_node(hyps, heap, ds, p),
+        ])
+      }
+      ProofNode::Refl(p) => self.proof_node(hyps, heap, ds, p),
+      ProofNode::Sym(p) =>
+        LispVal::list(vec![LispVal::atom(AtomID::SYM), self.proof_node(hyps, heap, ds, p)]),
+      &ProofNode::Unfold {term, ref args, ref res} =>
+        LispVal::list(vec![LispVal::atom(AtomID::UNFOLD),
+          LispVal::atom(self.terms[term].atom),
+          LispVal::list(args.iter().map(|e| self.proof_node(hyps, heap, ds, e)).collect::<Vec<_>>()),
+          self.proof_node(hyps, heap, ds, &res.2)]),
+    }
+  }
+
+  /// Convert the proof of theorem `t` to a lisp value of the form `(ds proof)`, where `ds`
+  /// is the list of dummy variables used in the proof, or `sorry` if the theorem has no proof.
+  /// The `heap` argument provides values for the first heap elements (usually the variables).
+  pub fn get_proof(&self, t: ThmID, mut heap: Vec<LispVal>) -> LispVal {
+    let tdata = &self.thms[t];
+    match &tdata.kind {
+      ThmKind::Thm(Some(pr)) => {
+        let mut ds = Vec::new();
+        for e in &pr.heap[heap.len()..] {
+          let e = self.proof_node(&tdata.hyps, &heap, &mut ds, e);
+          heap.push(e)
+        }
+        let ret = self.proof_node(&tdata.hyps, &heap, &mut ds, &pr.head);
+        LispVal::list(vec![LispVal::list(ds), ret])
+      }
+      _ => LispVal::atom(AtomID::SORRY),
+    }
+  }
+}
+
 impl Elaborator {
   /// Render a lisp expression using the basic printer, and print it to the front end.
   pub fn print_lisp(&mut self, sp: Span, e: &LispVal) {
@@ -321,7 +509,10 @@ impl Elaborator {
   /// Parse and evaluate a lisp expression, with the given doc comment.
   pub fn eval_lisp_doc(&mut self, e: &SExpr, doc: String) -> Result<LispVal> {
     let sp = e.span;
-    let ir = self.parse_lisp_doc(e, doc)?;
+    let start = Instant::now();
+    let ir = self.parse_lisp_doc(e, doc);
+    self.profile.parse += start.elapsed();
+    let ir = ir?;
     // println!("{}", self.print(&ir));
     self.evaluate(sp, &ir)
   }
@@ -329,44 +520,76 @@ impl Elaborator {
   /// Parse and evaluate a math formula.
   pub fn eval_qexpr(&mut self, e: QExpr) -> Result<LispVal> {
     let sp = e.span;
-    let ir = self.parse_qexpr(e)?;
-    self.evaluate(sp, &ir)
+    let start = Instant::now();
+    let ir = self.parse_qexpr(e);
+    self.profile.parse += start.elapsed();
+    self.evaluate(sp, &ir?)
   }
 
   /// Parse and evaluate a lisp expression being used as a proof. Essentially the same
   /// as evaluating `(refine e)` where `e` is the input expression.
   pub fn elab_lisp(&mut self, e: &SExpr) -> Result<LispVal> {
     let sp = e.span;
-    let ir = self.parse_lisp(e)?;
-    Evaluator::new(self, sp).run(State::Refines(sp, [ir].iter()))
+    let start = Instant::now();
+    let ir = self.parse_lisp(e);
+    self.profile.parse += start.elapsed();
+    let ir = ir?;
+    let arena = CodeArena::default();
+    Evaluator::new(self, &arena, sp).run(State::Refines(sp, [ir].iter()))
   }
 
   /// Evaluate a compiled lisp expression.
   pub fn evaluate<'b>(&'b mut self, sp: Span, ir: &'b IR) -> Result<LispVal> {
-    Evaluator::new(self, sp).run(State::Eval(ir))
+    let arena = CodeArena::default();
+    Evaluator::new(self, &arena, sp).run(State::Eval(ir))
   }
 
   /// Shorthand to call a lisp function from the top level.
   pub fn call_func(&mut self, sp: Span, f: LispVal, es: Vec<LispVal>) -> Result<LispVal> {
-    Evaluator::new(self, sp).run(State::App(sp, sp, f, es, [].iter()))
+    let arena = CodeArena::default();
+    Evaluator::new(self, &arena, sp).run(State::App(sp, sp, f, es, [].iter()))
+  }
+
+  /// Get the result of a promise created by `spawn`, waiting for the call to finish
+  /// if it is still running.
+  fn join_promise(&mut self, sp: Span, m: &RefCell<Promise>) -> Result<LispVal> {
+    let res = match mem::replace(&mut *m.borrow_mut(), Promise::Lost) {
+      Promise::Running(t) => self.join_task(t),
+      Promise::Done(e) => Ok(e),
+      Promise::Lost => Err(ElabError::new_e(sp,
+        "the result of this promise is not available, because the call failed \
+        or the promise was copied before it was joined")),
+    };
+    if let Ok(e) = &res { *m.borrow_mut() = Promise::Done(e.clone()) }
+    res
   }
 
   /// Call an overridable lisp function. This uses the name of a builtin procedure `foo`
   /// and calls `(foo)` using the usual name resolution, meaning that if the user redefines
   /// `foo` then that function will be called instead of the builtin.
   pub fn call_overridable(&mut self, sp: Span, p: BuiltinProc, es: Vec<LispVal>) -> Result<LispVal> {
+    let val = self.overridable(p);
+    self.call_func(sp, val, es)
+  }
+
+  /// Get the current value of the overridable lisp function `foo`, where `foo` is the name
+  /// of the builtin procedure `p`. This is the user's definition of `foo` if there is one,
+  /// and the builtin otherwise.
+  fn overridable(&mut self, p: BuiltinProc) -> LispVal {
     let a = self.get_atom(p.to_byte_str());
-    let val = match &self.data[a].lisp {
+    match &self.data[a].lisp {
       Some(e) => (**e).clone(),
       None => LispVal::proc(Proc::Builtin(p))
-    };
-    self.call_func(sp, val, es)
+    }
   }
 
   fn as_string(&self, e: &LispVal) -> SResult<ArcString> {
-    e.unwrapped(|e| if let LispKind::String(s) = e {Ok(s.clone())} else {
-      Err(format!("expected a string, got {}", self.print(e)))
-    })
+    e.unwrapped(|e| if let LispKind::String(s) = e {Some(s.clone())} else {None})
+      .ok_or_else(|| LispError::Type {expected: "string", actual: e.clone()})
+  }
+
+  fn as_atom(&self, e: &LispVal) -> SResult<AtomID> {
+    e.as_atom().ok_or_else(|| LispError::Type {expected: "atom", actual: e.clone()})
   }
 
   fn as_string_atom(&mut self, e: &LispVal) -> Option<AtomID> {
@@ -377,28 +600,61 @@ impl Elaborator {
     })
   }
 
-  fn with_int<T>(&self, e: &LispVal, f: impl FnOnce(&BigInt) -> SResult<T>) -> SResult<T> {
-    e.unwrapped(|e| if let LispKind::Number(n) = e {f(n)} else {
-      Err(format!("expected a integer, got {}", self.print(e)))
+  fn as_unfold_defs(&mut self, e: &LispVal) -> std::result::Result<UnfoldDefs, String> {
+    if e.unwrapped(|e| matches!(e, LispKind::Bool(true))) {return Ok(UnfoldDefs::All)}
+    if !e.is_list() {return Err(format!("unfold: expected a list of definitions or #t, got {}", self.print(e)))}
+    let mut ts = HashSet::new();
+    for x in Uncons::from(e.clone()) {
+      let a = x.as_atom().ok_or_else(|| format!("unfold: expected an atom, got {}", self.print(&x)))?;
+      match self.data[a].decl {
+        Some(DeclKey::Term(t)) if matches!(self.terms[t].kind, TermKind::Def(Some(_))) => {ts.insert(t);}
+        Some(DeclKey::Term(t)) if matches!(self.terms[t].kind, TermKind::Def(None)) =>
+          return Err(format!("unfold: the value of definition '{}' is not available", self.data[a].name)),
+        _ => return Err(format!("unfold: unknown definition '{}'", self.data[a].name)),
+      }
+    }
+    Ok(UnfoldDefs::Only(ts))
+  }
+
+  fn with_int<T, E: Into<LispError>>(&self, v: &LispVal,
+      f: impl FnOnce(&BigInt) -> std::result::Result<T, E>) -> SResult<T> {
+    v.unwrapped(|e| match *e {
+      LispKind::Number(ref n) => f(n).map_err(Into::into),
+      LispKind::SmallInt(n) => f(&n.into()).map_err(Into::into),
+      _ => Err(LispError::Type {expected: "number", actual: v.clone()})
     })
   }
 
+  /// Make an atom map from the names of the metavariables `?a`, `?b`, ... (by index)
+  /// to the given values, for `unify` and `match-term`.
+  fn mvar_map(&mut self, v: impl IntoIterator<Item=(usize, LispVal)>) -> LispVal {
+    LispVal::new(LispKind::AtomMap(v.into_iter().map(|(n, e)|
+      (self.get_atom(format!("?{}", alphanumber(n)).as_bytes()), e)).collect()))
+  }
+
   fn as_int(&self, e: &LispVal) -> SResult<BigInt> {
-    self.with_int(e, |n| Ok(n.clone()))
+    self.with_int(e, |n| Ok::<_, LispError>(n.clone()))
+  }
+
+  fn as_radix(&self, e: &LispVal) -> SResult<u32> {
+    self.with_int(e, |n| match n.to_u32() {
+      Some(r) if (2..=36).contains(&r) => Ok(r),
+      _ => Err(format!("radix out of range: {}", n))
+    })
   }
 
-  fn as_lref<T>(&self, e: &LispKind, f: impl FnOnce(&LispRef) -> SResult<T>) -> SResult<T> {
-    e.as_lref(f).unwrap_or_else(|| Err(format!("not a ref-cell: {}", self.print(e))))
+  fn as_lref<T>(&self, e: &LispVal, f: impl FnOnce(&LispRef) -> SResult<T>) -> SResult<T> {
+    e.as_lref(f).unwrap_or_else(|| Err(LispError::Type {expected: "ref", actual: e.clone()}))
   }
 
-  fn as_ref<T>(&self, e: &LispKind, f: impl FnOnce(&mut LispVal) -> SResult<T>) -> SResult<T> {
+  fn as_ref<T>(&self, e: &LispVal, f: impl FnOnce(&mut LispVal) -> SResult<T>) -> SResult<T> {
     self.as_lref(e, |m| m.get_mut(f))
   }
 
-  fn as_map<T>(&self, e: &LispKind, f: impl FnOnce(&HashMap<AtomID, LispVal>) -> SResult<T>) -> SResult<T> {
-    e.unwrapped(|e| match e {
+  fn as_map<T>(&self, v: &LispVal, f: impl FnOnce(&HashMap<AtomID, LispVal>) -> SResult<T>) -> SResult<T> {
+    v.unwrapped(|e| match e {
       LispKind::AtomMap(m) => f(m),
-      _ => Err(format!("not an atom map: {}", self.print(e)))
+      _ => Err(LispError::Type {expected: "atom map", actual: v.clone()})
     })
   }
 
@@ -409,16 +665,20 @@ impl Elaborator {
       LispKind::String(s) => s.clone(),
       &LispKind::Atom(a) => self.data[a].name.clone(),
       LispKind::Number(n) => n.to_string().into(),
+      LispKind::SmallInt(n) => n.to_string().into(),
       _ => format!("{}", self.print(e)).into()
     }
   }
 
-  fn int_bool_binop(&self, mut f: impl FnMut(&BigInt, &BigInt) -> bool, args: &[LispVal]) -> SResult<bool> {
+  fn int_bool_binop(&self, f: impl Fn(std::cmp::Ordering) -> bool, args: &[LispVal]) -> SResult<bool> {
+    if let Some(ns) = args.iter().map(|a| a.as_small_int()).collect::<Option<Vec<_>>>() {
+      return Ok(ns.windows(2).all(|w| f(w[0].cmp(&w[1]))))
+    }
     let mut it = args.iter();
     let mut last = self.as_int(it.next().expect("int_bool_binop([])"))?;
     for v in it {
       let new = self.as_int(v)?;
-      if !f(&last, &new) {return Ok(false)}
+      if !f(last.cmp(&new)) {return Ok(false)}
       last = new;
     }
     Ok(true)
@@ -427,111 +687,73 @@ impl Elaborator {
   fn stat(&self) -> String {
     use std::fmt::Write;
     let mut s = String::new();
-    for (a, e, _) in &self.lc.proof_order {
-      writeln!(s, "{}: {}", self.print(a), self.format_env().pp(e, 80)).unwrap()
+    if self.sequent_goals {
+      for (i, (a, e, _)) in self.lc.proof_order.iter().enumerate() {
+        writeln!(s, "{:>3}. {}: {}", i + 1, self.print(a), self.format_env().pp(e, 80)).unwrap()
+      }
+      let goals = self.lc.goals.iter().filter_map(|e| e.goal_type()).collect::<Vec<_>>();
+      for (i, e) in goals.iter().enumerate() {
+        if goals.len() > 1 { write!(s, "({}) ", i + 1).unwrap() }
+        writeln!(s, "⊢ {}", self.format_env().pp(e, 80)).unwrap()
+      }
+    } else {
+      for (a, e, _) in &self.lc.proof_order {
+        writeln!(s, "{}: {}", self.print(a), self.format_env().pp(e, 80)).unwrap()
+      }
+      for e in &self.lc.goals {
+        e.unwrapped(|r| if let LispKind::Goal(e) = r {
+          writeln!(s, "|- {}", self.format_env().pp(e, 80)).unwrap()
+        })
+      }
     }
-    for e in &self.lc.goals {
-      e.unwrapped(|r| if let LispKind::Goal(e) = r {
-        writeln!(s, "|- {}", self.format_env().pp(e, 80)).unwrap()
+    let mut first = true;
+    for e in &self.lc.mvars {
+      e.unwrapped(|r| if let LispKind::MVar(_, tgt) = *r {
+        if mem::take(&mut first) { s.push_str("\nmetavariables:\n") }
+        writeln!(s, "  {}: {}", self.print(r), self.print(&tgt)).unwrap()
       })
     }
     s
   }
 
-  fn head_err(&self, e: &LispKind) -> SResult<LispVal> {
-    e.unwrapped(|e| match e {
+  fn head_err(&self, v: &LispVal) -> SResult<LispVal> {
+    v.unwrapped(|e| match e {
       LispKind::List(es) if es.is_empty() => Err("evaluating 'hd ()'".into()),
       LispKind::List(es) => Ok(es[0].clone()),
       LispKind::DottedList(es, r) if es.is_empty() => self.head_err(r),
       LispKind::DottedList(es, _) => Ok(es[0].clone()),
-      _ => Err(format!("expected a list, got {}", self.print(e)))
+      _ => Err(LispError::Type {expected: "list", actual: v.clone()})
     })
   }
 
-  fn tail(&self, e: &LispKind) -> SResult<LispVal> {
+  fn tail(&self, v: &LispVal) -> SResult<LispVal> {
     fn exponential_backoff(es: &[LispVal], i: usize, r: impl FnOnce(Vec<LispVal>) -> LispVal) -> LispVal {
       let j = 2 * i;
       if j >= es.len() { r(es[i..].into()) }
       else { LispVal::dotted_list(es[i..j].cloned_box(), exponential_backoff(es, j, r)) }
     }
-    e.unwrapped(|e| match e {
+    v.unwrapped(|e| match e {
       LispKind::List(es) if es.is_empty() => Err("evaluating 'tl ()'".into()),
       LispKind::List(es) =>
         Ok(exponential_backoff(es, 1, LispVal::list)),
       LispKind::DottedList(es, r) if es.is_empty() => self.tail(r),
       LispKind::DottedList(es, r) =>
         Ok(exponential_backoff(es, 1, |v| LispVal::dotted_list(v, r.clone()))),
-      _ => Err(format!("expected a list, got {}", self.print(e)))
+      _ => Err(LispError::Type {expected: "list", actual: v.clone()})
     })
   }
 
-  fn nth(&self, e: &LispKind, i: usize) -> SResult<LispVal> {
-    e.unwrapped(|e| match e {
+  fn nth(&self, v: &LispVal, i: usize) -> SResult<LispVal> {
+    v.unwrapped(|e| match e {
       LispKind::List(es) => Ok(es.get(i).cloned().unwrap_or_else(LispVal::undef)),
       LispKind::DottedList(es, r) => match es.get(i) {
         Some(e) => Ok(e.clone()),
         None => self.nth(r, i - es.len()),
       },
-      _ => Err(format!("expected a list, got {}", self.print(e)))
+      _ => Err(LispError::Type {expected: "list", actual: v.clone()})
     })
   }
 
-  fn proof_node(&self, hyps: &[(Option<AtomID>, ExprNode)],
-    heap: &[LispVal], ds: &mut Vec<LispVal>, p: &ProofNode) -> LispVal {
-    match p {
-      &ProofNode::Ref(n) => heap[n].clone(),
-      &ProofNode::Dummy(a, s) => {
-        let a = LispVal::atom(a);
-        ds.push(LispVal::list(vec![a.clone(), LispVal::atom(self.env.sorts[s].atom)]));
-        a
-      }
-      &ProofNode::Term {term, args: ref es} |
-      &ProofNode::Cong {term, args: ref es} => {
-        let mut args = vec![LispVal::atom(self.terms[term].atom)];
-        args.extend(es.iter().map(|e| self.proof_node(hyps, heap, ds, e)));
-        LispVal::list(args)
-      }
-      &ProofNode::Hyp(h, _) => LispVal::atom(hyps[h].0.unwrap_or(AtomID::UNDER)),
-      &ProofNode::Thm {thm, args: ref es, ..} => {
-        let mut args = vec![LispVal::atom(self.thms[thm].atom)];
-        args.extend(es.iter().map(|e| self.proof_node(hyps, heap, ds, e)));
-        LispVal::list(args)
-      }
-      ProofNode::Conv(es) => {
-        let (t, c, p) = &**es;
-        LispVal::list(vec![LispVal::atom(AtomID::CONV),
-          self.proof_node(hyps, heap, ds, t),
-          self.proof_node(hyps, heap, ds, c),
-          self.proof_node(hyps, heap, ds, p),
-        ])
-      }
-      ProofNode::Refl(p) => self.proof_node(hyps, heap, ds, p),
-      ProofNode::Sym(p) =>
-        LispVal::list(vec![LispVal::atom(AtomID::SYM), self.proof_node(hyps, heap, ds, p)]),
-      &ProofNode::Unfold {term, ref args, ref res} =>
-        LispVal::list(vec![LispVal::atom(AtomID::UNFOLD),
-          LispVal::atom(self.terms[term].atom),
-          LispVal::list(args.iter().map(|e| self.proof_node(hyps, heap, ds, e)).collect::<Vec<_>>()),
-          self.proof_node(hyps, heap, ds, &res.2)]),
-    }
-  }
-
-  fn get_proof(&self, t: ThmID, mut heap: Vec<LispVal>) -> LispVal {
-    let tdata = &self.thms[t];
-    match &tdata.kind {
-      ThmKind::Thm(Some(pr)) => {
-        let mut ds = Vec::new();
-        for e in &pr.heap[heap.len()..] {
-          let e = self.proof_node(&tdata.hyps, &heap, &mut ds, e);
-          heap.push(e)
-        }
-        let ret = self.proof_node(&tdata.hyps, &heap, &mut ds, &pr.head);
-        LispVal::list(vec![LispVal::list(ds), ret])
-      }
-      _ => LispVal::atom(AtomID::SORRY),
-    }
-  }
-
   fn get_decl(&mut self, fsp: Option<FileSpan>, x: AtomID) -> LispVal {
     fn vis(mods: Modifiers) -> LispVal {
       match mods {
@@ -612,6 +834,22 @@ impl Elaborator {
   }
 }
 
+/// The maximum number of distinct lambda bodies that one evaluation can call. The bodies
+/// are kept until the evaluation finishes, so this bounds the memory used by code that
+/// keeps calling new procedures (for example ones returned by `spawn`, which are copied
+/// when they are joined); procedures created by the same `fn` expression share their
+/// body, so ordinary code uses only a few.
+const CODE_ARENA_LIMIT: usize = 1 << 16;
+
+/// The storage for the bodies of the lambdas called during an evaluation.
+/// See [`Evaluator::code`].
+#[derive(Default)]
+struct CodeArena(typed_arena::Arena<Arc<IR>>);
+
+impl std::fmt::Debug for CodeArena {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { write!(f, "CodeArena") }
+}
+
 /// The lisp evaluation context, representing a lisp evaluation in progress.
 /// This is an explicitly unfolled state machine (rather than using recursive functions)
 /// so that we can explicitly manipulate the program stack for error reporting purposes.
@@ -631,6 +869,20 @@ pub struct Evaluator<'a> {
   /// The evaluation stack. This is a structured object containing a stack of continuations
   /// each of which represent a context which awaiting a value from a sub-computation.
   stack: Vec<Stack<'a>>,
+  /// The bodies of the lambdas that have been called, which are kept alive in `arena`
+  /// until the evaluation is finished, so that the stack can borrow from them.
+  /// They are indexed by address, so that each body is only stored once.
+  bodies: HashMap<*const IR, &'a IR>,
+  /// The storage for `bodies`.
+  arena: &'a CodeArena,
+  /// The number of steps remaining for the pattern test `(? pred)` currently being
+  /// evaluated, if any. Nested tests share the budget of the outermost one.
+  cur_test_fuel: Option<u64>,
+  /// The value passed to `raise`, if we are currently unwinding because of a `raise`.
+  exception: Option<LispVal>,
+  /// True if the error being reported should not be caught by `try`, because it
+  /// is a resource limit like a timeout or stack overflow.
+  uncatchable: bool,
 }
 impl<'a> Deref for Evaluator<'a> {
   type Target = Elaborator;
@@ -641,9 +893,26 @@ impl<'a> DerefMut for Evaluator<'a> {
 }
 
 impl<'a> Evaluator<'a> {
-  fn new(elab: &'a mut Elaborator, orig_span: Span) -> Evaluator<'a> {
+  fn new(elab: &'a mut Elaborator, arena: &'a CodeArena, orig_span: Span) -> Evaluator<'a> {
     let file = elab.path.clone();
-    Evaluator {elab, ctx: vec![], file, orig_span, stack: vec![]}
+    Evaluator {elab, ctx: vec![], file, orig_span, stack: vec![], bodies: HashMap::new(), arena,
+      cur_test_fuel: None, exception: None, uncatchable: false}
+  }
+
+  /// Get the body of a lambda, borrowed for the rest of the evaluation. The body is owned
+  /// by the [`Proc`], which may be dropped while the body is still running (for example
+  /// if the procedure is a local variable that goes out of scope), so we keep a reference
+  /// to it in the arena. It is an error to call more than [`CODE_ARENA_LIMIT`] distinct
+  /// bodies, since they cannot be freed until the evaluation is finished.
+  fn code(&mut self, code: &Arc<IR>) -> Result<&'a IR> {
+    if let Some(&ir) = self.bodies.get(&(&**code as *const IR)) { return Ok(ir) }
+    if self.bodies.len() >= CODE_ARENA_LIMIT {
+      return Err(self.limit_err(format!(
+        "called more than {} distinct procedure bodies in one evaluation", CODE_ARENA_LIMIT)))
+    }
+    let ir: &'a IR = self.arena.0.alloc(code.clone());
+    self.bodies.insert(ir, ir);
+    Ok(ir)
   }
 
   fn fspan_base(&mut self, sp: Span) -> FileSpan {
@@ -653,29 +922,77 @@ impl<'a> Evaluator<'a> {
     self.fspan(sp)
   }
 
+  /// Print `e`, truncated if it is long, for a backtrace or the debugger.
+  fn print_short(&self, e: &LispVal) -> String {
+    /// The maximum length of a printed argument.
+    const MAX_ARG_LEN: usize = 40;
+    let mut arg = format!("{}", self.print(e));
+    if let Some((i, _)) = arg.char_indices().nth(MAX_ARG_LEN) {
+      arg.truncate(i);
+      arg.push_str("...")
+    }
+    arg
+  }
+
+  /// The label for a call to the function at `pos` in a backtrace. If `show_args` is true
+  /// (for example if detailed backtraces are enabled, see `set-backtrace`), the arguments are
+  /// also printed: they are the values in `ctx` (the local context of the call) starting at
+  /// `start`, as specified by `spec`.
+  fn frame_label(&self, pos: &ProcPos, spec: ProcSpec, ctx: &[LispVal], start: usize,
+    show_args: bool
+  ) -> String {
+    let mut s = match pos {
+      ProcPos::Named(_, _, a) => format!("({}", self.data[*a].name),
+      ProcPos::Unnamed(_) if show_args => "([fn]".into(),
+      ProcPos::Unnamed(_) => return "[fn]".into(),
+    };
+    if show_args {
+      let args = ctx.get(start..).unwrap_or(&[]);
+      let (args, rest) = match spec {
+        ProcSpec::Exact(n) => (args.get(..n).unwrap_or(args), None),
+        ProcSpec::AtLeast(n) => (args.get(..n).unwrap_or(args), args.get(n)),
+      };
+      let rest = rest.map_or_else(Vec::new, |r| Uncons::from(r.clone()).collect());
+      for e in args.iter().chain(&rest) {
+        s.push(' ');
+        s.push_str(&self.print_short(e))
+      }
+    }
+    s.push(')');
+    s
+  }
+
   fn make_stack_err(&mut self, sp: Option<(Span, bool)>, level: ErrorLevel,
       base: BoxError, err: impl Into<BoxError>) -> ElabError {
     let mut old = sp.map(|(sp, good)| (self.fspan(sp), good, base));
     let mut info = vec![];
-    for s in self.stack.iter().rev() {
-      if let Stack::Ret(fsp, pos, _, _) = s {
-        let x = match pos {
-          ProcPos::Named(_, _, a) => format!("({})", self.data[*a].name).into(),
-          ProcPos::Unnamed(_) => "[fn]".into(),
-        };
-        if let Some((sp, good, base)) = old.take() {
-          let (sp, osp) = if good {(sp, fsp.clone())} else {(fsp.clone(), sp)};
-          info.push((osp, base));
-          old = Some((sp, good, x));
-        } else {
-          old = Some((fsp.clone(), false, x));
+    let mut file = &self.file;
+    let mut ctx = &*self.ctx;
+    for (i, s) in self.stack.iter().enumerate().rev() {
+      let (fsp, x): (FileSpan, BoxError) = match s {
+        Stack::Ret(fsp, pos, spec, caller) => {
+          file = &fsp.file;
+          let start = match self.stack.get(i + 1) { Some(&Stack::Drop(n)) => n, _ => ctx.len() };
+          let x = self.frame_label(pos, *spec, ctx, start, self.backtrace).into();
+          ctx = caller;
+          (fsp.clone(), x)
         }
+        &Stack::TestPattern(_, _, _, _, _, _, sp, _) =>
+          (FileSpan {file: file.clone(), span: sp}, "[pattern test]".into()),
+        _ => continue
+      };
+      if let Some((sp, good, base)) = old.take() {
+        let (sp, osp) = if good {(sp, fsp)} else {(fsp, sp)};
+        info.push((osp, base.into()));
+        old = Some((sp, good, x));
+      } else {
+        old = Some((fsp, false, x));
       }
     }
     ElabError {
       pos: old.map_or(self.orig_span, |(sp, _, _)| sp.span),
       level,
-      kind: ElabErrorKind::Boxed(err.into(), Some(info))
+      kind: ElabErrorKind::Boxed(arc_error(err), Some(info))
     }
   }
 
@@ -696,10 +1013,91 @@ impl<'a> Evaluator<'a> {
     self.report(msg)
   }
 
+  /// Print the user-facing output `msg` of `display`, `print` or `stat` (`base`) at `sp`,
+  /// to the output channel if there is one, and as an info diagnostic otherwise.
+  fn output(&mut self, sp: Span, base: &str, msg: String) {
+    match self.elab.output.clone() {
+      None => self.info(sp, false, base, msg),
+      Some(chan) => if self.reporting.info { chan.write(Output {span: self.fspan(sp), text: msg}) }
+    }
+  }
+
   fn err(&mut self, sp: Option<(Span, bool)>, err: impl Into<BoxError>) -> ElabError {
     self.make_stack_err(sp, ErrorLevel::Error, "error occurred here".into(), err)
   }
 
+  /// Construct the error for a call to `func` at `sp` with the wrong number of arguments.
+  /// The error gives the expected and received number of arguments and the types of the
+  /// arguments, and points to the definition of `func` if it is a lambda.
+  fn arity_err(&mut self, sp: Span, func: &Proc, spec: ProcSpec, args: &[LispVal]) -> ElabError {
+    let name = match func {
+      Proc::Builtin(p) => p.to_str().to_owned(),
+      Proc::Lambda {pos: ProcPos::Named(_, _, a), ..} => format!("{}", self.data[*a].name),
+      Proc::Lambda {pos: ProcPos::Unnamed(_), ..} => "[fn]".into(),
+      _ => "[procedure]".into(),
+    };
+    let expected = match spec {
+      ProcSpec::Exact(n) => format!("{} argument(s)", n),
+      ProcSpec::AtLeast(n) => format!("at least {} argument(s)", n),
+    };
+    let msg = format!("{}: expected {}, received {} ({})", name, expected, args.len(),
+      args.iter().map(|e| e.type_name()).collect::<Vec<_>>().join(" "));
+    let mut err = self.err(Some((sp, false)), msg);
+    if let (Proc::Lambda {pos, ..}, ElabErrorKind::Boxed(_, Some(info))) = (func, &mut err.kind) {
+      let fsp = match pos {
+        ProcPos::Named(fsp, sp, _) => FileSpan {file: fsp.file.clone(), span: *sp},
+        ProcPos::Unnamed(fsp) => fsp.clone(),
+      };
+      info.push((fsp, arc_error(format!("{} is defined here", name))));
+    }
+    err
+  }
+
+  /// Construct the error for a call at `sp` to the expired match continuation `k`.
+  /// The error gives the reason for expiry, and points to the `match` that created the
+  /// continuation and the place where it expired.
+  fn expired_cont_err(&mut self, sp: Span, k: &MatchCont, why: &ContExpiry) -> ElabError {
+    let (reason, at) = why.describe();
+    let mut err = self.err(Some((sp, false)), format!("continuation has expired: {}", reason));
+    if let ElabErrorKind::Boxed(_, Some(info)) = &mut err.kind {
+      info.push((k.span.clone(), arc_error("the continuation was created by this match")));
+      if let Some(at) = at { info.push((at.clone(), arc_error("the continuation expired here"))) }
+    }
+    err
+  }
+
+  /// Construct the error for a failure of the builtin `f` called at `sp`. For a type error,
+  /// `arg` is the index of the argument at fault if it is known, which is named in the
+  /// stack trace, and the exception value seen by `try` is set to
+  /// `(type-error f n expected v)`, where `n` is the argument number (or `#undef` if unknown),
+  /// `expected` is the name of the expected type and `v` is the value provided.
+  fn builtin_err(&mut self, sp: Span, f: BuiltinProc, arg: Option<usize>, e: LispError) -> ElabError {
+    let (expected, actual) = match e {
+      LispError::Msg(msg) => return self.make_stack_err(
+        Some((sp, false)), ErrorLevel::Error, format!("({})", f).into(), msg),
+      LispError::Type {expected, actual} => (expected, actual),
+    };
+    let n = arg.map(|n| n + 1);
+    let base = match n {
+      Some(n) => format!("({}) argument {}", f, n),
+      None => format!("({})", f),
+    };
+    let article = if expected.starts_with(|c: char| "aeiou".contains(c)) {"an"} else {"a"};
+    let msg = format!("expected {} {}, got {}", article, expected, self.print(&actual));
+    let fsp = actual.fspan();
+    self.exception = Some(LispVal::list(vec![
+      LispVal::atom(self.get_atom(b"type-error")),
+      LispVal::atom(self.get_atom(f.to_str().as_bytes())),
+      n.map_or_else(LispVal::undef, |n| LispVal::number(n.into())),
+      LispVal::string(expected.as_bytes().into()),
+      actual]));
+    let mut err = self.make_stack_err(Some((sp, false)), ErrorLevel::Error, base.into(), msg);
+    if let (Some(fsp), ElabErrorKind::Boxed(_, Some(info))) = (fsp, &mut err.kind) {
+      info.push((fsp, arc_error("this argument has the wrong type")))
+    }
+    err
+  }
+
   fn add_thm(&mut self, fsp: FileSpan, args: &[LispVal]) -> Result<State<'a>> {
     Ok(match self.elab.add_thm(fsp.clone(), args)? {
       Ok(()) => State::Ret(LispVal::undef()),
@@ -728,15 +1126,23 @@ macro_rules! make_builtins {
       #[allow(clippy::unwrap_used)]
       fn evaluate_builtin(&mut $self, $sp1: Span, $sp2: Span, f: BuiltinProc, mut $args: Vec<LispVal>) -> Result<State<'a>> {
         macro_rules! print {($sp:expr, $x:expr) => {{
-          let msg = $x; $self.info($sp, false, f.to_str(), msg)
-        }}}
-        macro_rules! try1 {($x:expr) => {{
-          match $x {
-            Ok(e) => e,
-            Err(s) => return Err($self.make_stack_err(
-              Some(($sp1, false)), ErrorLevel::Error, format!("({})", f).into(), s))
-          }
+          let msg = $x; $self.output($sp, f.to_str(), msg.into())
         }}}
+        // `try1!(i; e)` says that a type error in `e` is about argument `i` (from 0).
+        macro_rules! try1 {
+          ($i:expr; $x:expr) => {{
+            match $x {
+              Ok(e) => e,
+              Err(e) => return Err($self.builtin_err($sp1, f, Some($i), LispError::from(e)))
+            }
+          }};
+          ($x:expr) => {{
+            match $x {
+              Ok(e) => e,
+              Err(e) => return Err($self.builtin_err($sp1, f, None, LispError::from(e)))
+            }
+          }};
+        }
 
         Ok(State::Ret(match f { $(BuiltinProc::$e => $res),* }))
       }
@@ -746,13 +1152,50 @@ macro_rules! make_builtins {
 
 make_builtins! { self, sp1, sp2, args,
   Display: Exact(1) => {
-    let s = try1!(self.as_string(&args[0]));
+    let s = try1!(0; self.as_string(&args[0]));
     print!(sp1, String::from_utf8_lossy(&s));
     LispVal::undef()
   },
   Error: Exact(1) => {
-    let s = try1!(self.as_string(&args[0]));
-    try1!(Err(String::from_utf8_lossy(&s)))
+    let s = try1!(0; self.as_string(&args[0]));
+    try1!(Err(String::from_utf8_lossy(&s).into_owned()))
+  },
+  Assert: AtLeast(1) => {
+    if args.len() > 2 { try1!(Err("invalid arguments")) }
+    if !args[0].truthy() {
+      let msg = match args.get(1) {
+        None => "assertion failed".into(),
+        Some(msg) => String::from_utf8_lossy(&try1!(1; self.as_string(msg))).into_owned(),
+      };
+      try1!(Err(msg))
+    }
+    LispVal::undef()
+  },
+  CheckEqual: Exact(2) => {
+    if args[0] != args[1] {
+      try1!(Err(format!("check-equal: got {}, expected {}", self.print(&args[0]), self.print(&args[1]))))
+    }
+    LispVal::undef()
+  },
+  Raise: Exact(1) => {
+    let msg = format!("uncaught exception: {}", self.print(&args[0]));
+    self.exception = args.pop();
+    try1!(Err(msg))
+  },
+  Try: Exact(2) => {
+    let h = args.pop().expect("nonempty");
+    let f = args.pop().expect("nonempty");
+    let sp = f.fspan().map_or(sp2, |fsp| fsp.span);
+    self.stack.push(Stack::Try(sp1, h));
+    return Ok(State::App(sp1, sp, f, vec![], [].iter()))
+  },
+  Escape: Exact(2) => {
+    let x = try1!(0; self.as_atom(&args[0]));
+    if !self.stack.iter().any(|s| matches!(*s, Stack::Label(y) if x == y)) {
+      try1!(Err(format!("escape: no enclosing label '{}'", self.data[x].name)))
+    }
+    self.unwind(sp1, ContExpiry::Jumped, |s| matches!(*s, Stack::Label(y) if x == y));
+    return Ok(State::Ret(args.pop().expect("impossible")))
   },
   Print: Exact(1) => {print!(sp1, format!("{}", self.print(&args[0]))); LispVal::undef()},
   ReportAt: Exact(3) => {
@@ -764,12 +1207,12 @@ make_builtins! { self, sp1, sp2, args,
     };
     let FileSpan {file, span} = try1!(args[1].fspan().ok_or("expected a span"));
     if file == self.file {
-      let s = try1!(self.as_string(&args[2]));
-      let s = String::from_utf8_lossy(&s).into();
+      let s = try1!(2; self.as_string(&args[2]));
+      let s: BoxError = String::from_utf8_lossy(&s).into();
       let msg = if let Some(true) = args[1].as_bool() {
         self.make_stack_err(Some((span, true)), level, "(report-at)".into(), s)
       } else {
-        ElabError { pos: span, level, kind: ElabErrorKind::Boxed(s, None) }
+        ElabError { pos: span, level, kind: ElabErrorKind::Boxed(s.into(), None) }
       };
       self.report(msg);
     }
@@ -792,73 +1235,92 @@ make_builtins! { self, sp1, sp2, args,
     }
     return Ok(State::App(sp1, sp, proc, args, [].iter()))
   },
-  Add: AtLeast(0) => {
+  Add: AtLeast(0) => if let Some(n) = small_int_fold(&args, Some(0), i64::checked_add) {
+    LispVal::small_int(n)
+  } else {
     let mut n: BigInt = 0.into();
-    for e in args { n += try1!(self.as_int(&e)) }
+    for (i, e) in args.iter().enumerate() { n += try1!(i; self.as_int(e)) }
     LispVal::number(n)
   },
-  Mul: AtLeast(0) => {
+  Mul: AtLeast(0) => if let Some(n) = small_int_fold(&args, Some(1), i64::checked_mul) {
+    LispVal::small_int(n)
+  } else {
     let mut n: BigInt = 1.into();
-    for e in args { n *= try1!(self.as_int(&e)) }
+    for (i, e) in args.iter().enumerate() { n *= try1!(i; self.as_int(e)) }
     LispVal::number(n)
   },
   Pow: AtLeast(0) => {
-    let mut it = args.into_iter().rev();
+    let mut it = args.iter().enumerate().rev();
     match it.next() {
       None => LispVal::number(1.into()),
-      Some(b) => {
-        let mut n = try1!(self.as_int(&b));
-        for e in it {
+      Some((i, b)) => {
+        let mut n = try1!(i; self.as_int(b));
+        for (i, e) in it {
           let exp: u32 = try1!(n.try_into().map_err(|_| "exponent out of range"));
-          let base = try1!(self.as_int(&e));
+          let base = try1!(i; self.as_int(e));
           n = if base == 2.into() { BigInt::from(1) << exp } else { BigInt::pow(&base, exp) };
         }
         LispVal::number(n)
       }
     }
   },
-  Max: AtLeast(1) => {
-    let mut it = args.into_iter();
-    let mut n: BigInt = try1!(self.as_int(&it.next().unwrap()));
-    for e in it { n = n.max(try1!(self.as_int(&e)).clone()) }
+  Max: AtLeast(1) => if let Some(n) = small_int_fold(&args, None, |a, b| Some(a.max(b))) {
+    LispVal::small_int(n)
+  } else {
+    let mut n: BigInt = try1!(0; self.as_int(&args[0]));
+    for (i, e) in args.iter().enumerate().skip(1) { n = n.max(try1!(i; self.as_int(e))) }
     LispVal::number(n)
   },
-  Min: AtLeast(1) => {
-    let mut it = args.into_iter();
-    let mut n: BigInt = try1!(self.as_int(&it.next().unwrap()));
-    for e in it { n = n.min(try1!(self.as_int(&e)).clone()) }
+  Min: AtLeast(1) => if let Some(n) = small_int_fold(&args, None, |a, b| Some(a.min(b))) {
+    LispVal::small_int(n)
+  } else {
+    let mut n: BigInt = try1!(0; self.as_int(&args[0]));
+    for (i, e) in args.iter().enumerate().skip(1) { n = n.min(try1!(i; self.as_int(e))) }
     LispVal::number(n)
   },
   Sub: AtLeast(1) => if args.len() == 1 {
-    LispVal::number(-try1!(self.as_int(&args[0])))
+    match args[0].as_small_int().and_then(i64::checked_neg) {
+      Some(n) => LispVal::small_int(n),
+      None => LispVal::number(-try1!(0; self.as_int(&args[0])))
+    }
+  } else if let Some(n) = small_int_fold(&args, None, i64::checked_sub) {
+    LispVal::small_int(n)
   } else {
-    let mut it = args.into_iter();
-    let mut n: BigInt = try1!(self.as_int(&it.next().unwrap()));
-    for e in it { n -= try1!(self.as_int(&e)) }
+    let mut n: BigInt = try1!(0; self.as_int(&args[0]));
+    for (i, e) in args.iter().enumerate().skip(1) { n -= try1!(i; self.as_int(e)) }
     LispVal::number(n)
   },
-  Div: AtLeast(1) => {
-    let mut it = args.into_iter();
-    let mut n: BigInt = try1!(self.as_int(&it.next().unwrap()));
-    for e in it { n /= try1!(self.as_int(&e)) }
+  Div: AtLeast(1) => if let Some(n) = small_int_fold(&args, None, i64::checked_div) {
+    LispVal::small_int(n)
+  } else {
+    let mut n: BigInt = try1!(0; self.as_int(&args[0]));
+    for (i, e) in args.iter().enumerate().skip(1) {
+      let d = try1!(i; self.as_int(e));
+      if d.is_zero() { try1!(Err("division by zero")) }
+      n /= d
+    }
     LispVal::number(n)
   },
-  Mod: AtLeast(1) => {
-    let mut it = args.into_iter();
-    let mut n: BigInt = try1!(self.as_int(&it.next().unwrap()));
-    for e in it { n %= try1!(self.as_int(&e)) }
+  Mod: AtLeast(1) => if let Some(n) = small_int_fold(&args, None, i64::checked_rem) {
+    LispVal::small_int(n)
+  } else {
+    let mut n: BigInt = try1!(0; self.as_int(&args[0]));
+    for (i, e) in args.iter().enumerate().skip(1) {
+      let d = try1!(i; self.as_int(e));
+      if d.is_zero() { try1!(Err("division by zero")) }
+      n %= d
+    }
     LispVal::number(n)
   },
-  Lt: AtLeast(1) => LispVal::bool(try1!(self.int_bool_binop(|a, b| a < b, &args))),
-  Le: AtLeast(1) => LispVal::bool(try1!(self.int_bool_binop(|a, b| a <= b, &args))),
-  Gt: AtLeast(1) => LispVal::bool(try1!(self.int_bool_binop(|a, b| a > b, &args))),
-  Ge: AtLeast(1) => LispVal::bool(try1!(self.int_bool_binop(|a, b| a >= b, &args))),
-  Eq: AtLeast(1) => LispVal::bool(try1!(self.int_bool_binop(|a, b| a == b, &args))),
+  Lt: AtLeast(1) => LispVal::bool(try1!(self.int_bool_binop(|o| o == std::cmp::Ordering::Less, &args))),
+  Le: AtLeast(1) => LispVal::bool(try1!(self.int_bool_binop(|o| o != std::cmp::Ordering::Greater, &args))),
+  Gt: AtLeast(1) => LispVal::bool(try1!(self.int_bool_binop(|o| o == std::cmp::Ordering::Greater, &args))),
+  Ge: AtLeast(1) => LispVal::bool(try1!(self.int_bool_binop(|o| o != std::cmp::Ordering::Less, &args))),
+  Eq: AtLeast(1) => LispVal::bool(try1!(self.int_bool_binop(|o| o == std::cmp::Ordering::Equal, &args))),
   Shl: AtLeast(1) => {
-    let mut it = args.into_iter();
-    let mut n: BigInt = try1!(self.as_int(&it.next().unwrap()));
-    for e in it {
-      try1!(self.with_int(&e, |e| {
+    let mut n: BigInt = try1!(0; self.as_int(&args[0]));
+    for (i, e) in args.iter().enumerate().skip(1) {
+      try1!(i; self.with_int(e, |e| -> SResult<()> {
         if matches!(e.sign(), num::bigint::Sign::Minus) {
           let i: u64 = e.try_into().map_err(|_| "shift out of range")?;
           n >>= &i
@@ -872,10 +1334,9 @@ make_builtins! { self, sp1, sp2, args,
     LispVal::number(n)
   },
   Shr: AtLeast(1) => {
-    let mut it = args.into_iter();
-    let mut n: BigInt = try1!(self.as_int(&it.next().unwrap()));
-    for e in it {
-      try1!(self.with_int(&e, |e| {
+    let mut n: BigInt = try1!(0; self.as_int(&args[0]));
+    for (i, e) in args.iter().enumerate().skip(1) {
+      try1!(i; self.with_int(e, |e| -> SResult<()> {
         if matches!(e.sign(), num::bigint::Sign::Minus) {
           let i: u64 = e.try_into().map_err(|_| "shift out of range")?;
           n <<= &i
@@ -890,25 +1351,25 @@ make_builtins! { self, sp1, sp2, args,
   },
   BAnd: AtLeast(0) => {
     let mut n: BigInt = (-1).into();
-    for e in args { n &= try1!(self.as_int(&e)) }
+    for (i, e) in args.iter().enumerate() { n &= try1!(i; self.as_int(e)) }
     LispVal::number(n)
   },
   BOr: AtLeast(0) => {
     let mut n: BigInt = 0.into();
-    for e in args { n |= try1!(self.as_int(&e)) }
+    for (i, e) in args.iter().enumerate() { n |= try1!(i; self.as_int(e)) }
     LispVal::number(n)
   },
   BXor: AtLeast(0) => {
     let mut n: BigInt = 0.into();
-    for e in args { n ^= try1!(self.as_int(&e)) }
+    for (i, e) in args.iter().enumerate() { n ^= try1!(i; self.as_int(e)) }
     LispVal::number(n)
   },
   BNot: AtLeast(0) => {
     let n = if let [e] = &*args {
-      try1!(self.as_int(e))
+      try1!(0; self.as_int(e))
     } else {
       let mut n: BigInt = (-1).into();
-      for e in args { n &= try1!(self.as_int(&e)) }
+      for (i, e) in args.iter().enumerate() { n &= try1!(i; self.as_int(e)) }
       n
     };
     LispVal::number(!n)
@@ -919,35 +1380,108 @@ make_builtins! { self, sp1, sp2, args,
   },
   ToString: Exact(1) => LispVal::string(self.to_string(&args[0])),
   StringToAtom: Exact(1) => {
-    let s = try1!(self.as_string(&args[0]));
+    let s = try1!(0; self.as_string(&args[0]));
     LispVal::atom(self.get_atom(&s))
   },
+  AtomToString: Exact(1) => {
+    let a = try1!(0; self.as_atom(&args[0]));
+    LispVal::string(self.data[a].name.clone())
+  },
+  Gensym: AtLeast(0) => {
+    let prefix = match args.first() {
+      None => ArcString::from(&b"_g"[..]),
+      Some(e) => self.to_string(e),
+    };
+    loop {
+      self.gensym += 1;
+      // `#` is not an identifier character, so the user cannot write these atoms
+      // in the source (except through `string->atom`), even in a later declaration
+      let mut s = prefix.to_vec();
+      s.extend_from_slice(format!("#{}", self.gensym).as_bytes());
+      if !self.atoms.contains_key(&*s) { break LispVal::atom(self.get_atom(&s)) }
+    }
+  },
   StringAppend: AtLeast(0) => {
     let mut out = Vec::new();
     for e in args { out.extend_from_slice(&self.to_string(&e)) }
     LispVal::string(out.into())
   },
-  StringLen: Exact(1) => LispVal::number(try1!(self.as_string(&args[0])).len().into()),
+  OpenOutputString: Exact(0) => LispVal::new_ref(LispVal::nil()),
+  WriteStr: AtLeast(1) => {
+    let mut out = Vec::new();
+    for e in &args[1..] { out.extend_from_slice(&self.to_string(e)) }
+    if !out.is_empty() {
+      // A string builder is a ref to a list of chunks. Chunks that are not much longer
+      // than the new one are merged into it, so that the chunk lengths at least double
+      // from the end of the list to the start. This keeps the list logarithmically short,
+      // while each byte is copied only a logarithmic number of times.
+      try1!(0; self.as_ref(&args[0], |b| {
+        let mut u = Uncons::New(b.clone());
+        let mut chunks = u.by_ref().collect::<Vec<_>>();
+        if !u.is_empty() { return Err("expected a string builder".into()) }
+        while let Some(last) = chunks.last() {
+          let last = self.as_string(last)?;
+          if last.len() > 2 * out.len() {break}
+          let mut s = last.to_vec();
+          s.extend_from_slice(&out);
+          out = s;
+          chunks.pop();
+        }
+        chunks.push(LispVal::string(out.into()));
+        *b = LispVal::list(chunks);
+        Ok(())
+      }))
+    }
+    LispVal::undef()
+  },
+  GetOutputString: Exact(1) => {
+    let mut u = Uncons::New(try1!(0; self.as_ref(&args[0], |b| Ok(b.clone()))));
+    let mut out = Vec::new();
+    for e in &mut u { out.extend_from_slice(&try1!(self.as_string(&e))) }
+    if !u.is_empty() { try1!(Err("expected a string builder")) }
+    LispVal::string(out.into())
+  },
+  StringLen: Exact(1) => LispVal::number(try1!(0; self.as_string(&args[0])).len().into()),
+  StringCharLen: Exact(1) => {
+    let s = try1!(0; self.as_string(&args[0]));
+    let s = try1!(std::str::from_utf8(&s).map_err(|_| "string-char-len: invalid UTF-8"));
+    LispVal::number(s.chars().count().into())
+  },
   StringNth: Exact(2) => {
-    let i: usize = try1!(self.with_int(&args[0],
+    let i: usize = try1!(0; self.with_int(&args[0],
       |n| n.try_into().map_err(|_| format!("index out of range: {}", n))));
-    let s = try1!(self.as_string(&args[1]));
+    let s = try1!(1; self.as_string(&args[1]));
     let c = *try1!(s.get(i).ok_or_else(||
       format!("index out of range: index {}, length {}", i, s.len())));
     LispVal::number(c.into())
   },
   Substr: Exact(3) => {
-    let start: usize = try1!(self.with_int(&args[0],
+    let start: usize = try1!(0; self.with_int(&args[0],
       |n| n.try_into().map_err(|_| format!("index out of range: start {}", n))));
-    let end: usize = try1!(self.with_int(&args[1],
+    let end: usize = try1!(1; self.with_int(&args[1],
       |n| n.try_into().map_err(|_| format!("index out of range: end {}", n))));
     if start > end { try1!(Err(format!("start {} > end {}", start, end))) }
-    let s = try1!(self.as_string(&args[2]));
+    let s = try1!(2; self.as_string(&args[2]));
     if end > s.len() { try1!(Err(format!("index out of range: end {}, length {}", end, s.len()))) }
     LispVal::string(ArcString::new(s[start..end].into()))
   },
+  CharSubstr: Exact(3) => {
+    let start: usize = try1!(0; self.with_int(&args[0],
+      |n| n.try_into().map_err(|_| format!("index out of range: start {}", n))));
+    let end: usize = try1!(1; self.with_int(&args[1],
+      |n| n.try_into().map_err(|_| format!("index out of range: end {}", n))));
+    if start > end { try1!(Err(format!("start {} > end {}", start, end))) }
+    let s = try1!(2; self.as_string(&args[2]));
+    let s = try1!(std::str::from_utf8(&s).map_err(|_| "char-substr: invalid UTF-8"));
+    let mut it = s.char_indices().map(|(i, _)| i).chain(std::iter::once(s.len()));
+    let lo = it.nth(start);
+    let hi = if start == end { lo } else { it.nth(end - start - 1) };
+    let (lo, hi) = try1!(lo.zip(hi).ok_or_else(|| format!(
+      "index out of range: end {}, length {}", end, s.chars().count())));
+    LispVal::string(s[lo..hi].as_bytes().into())
+  },
   StringToList: Exact(1) => {
-    let s = try1!(self.as_string(&args[0]));
+    let s = try1!(0; self.as_string(&args[0]));
     LispVal::list(s.iter()
       .map(|&c| LispVal::number(c.into()))
       .collect::<Vec<_>>())
@@ -964,10 +1498,94 @@ make_builtins! { self, sp1, sp2, args,
     }
     LispVal::string(out.into())
   },
+  StringSplit: Exact(2) => {
+    let sep = try1!(0; self.as_string(&args[0]));
+    if sep.is_empty() { try1!(Err("string-split: empty separator")) }
+    let s = try1!(1; self.as_string(&args[1]));
+    // Knuth-Morris-Pratt, so that the search is linear in `s.len() + sep.len()`
+    let mut fail = vec![0; sep.len()];
+    let mut k = 0;
+    for i in 1..sep.len() {
+      while k > 0 && sep[i] != sep[k] { k = fail[k - 1] }
+      if sep[i] == sep[k] { k += 1 }
+      fail[i] = k;
+    }
+    let (mut out, mut start, mut k) = (vec![], 0, 0);
+    for (i, &c) in s.iter().enumerate() {
+      while k > 0 && c != sep[k] { k = fail[k - 1] }
+      if c == sep[k] { k += 1 }
+      if k == sep.len() {
+        out.push(LispVal::string(s[start..i + 1 - k].into()));
+        start = i + 1;
+        k = 0;
+      }
+    }
+    out.push(LispVal::string(s[start..].into()));
+    LispVal::list(out)
+  },
+  CharToNumber: Exact(1) => {
+    let s = try1!(0; self.as_string(&args[0]));
+    let mut it = try1!(std::str::from_utf8(&s).map_err(|_| "char->number: invalid UTF-8")).chars();
+    match (it.next(), it.next()) {
+      (Some(c), None) => LispVal::number(u32::from(c).into()),
+      _ => try1!(Err(format!("expected a single character, got {}", self.print(&args[0]))))
+    }
+  },
+  NumberToChar: Exact(1) => {
+    let c = try1!(0; self.with_int(&args[0], |n| n.to_u32().and_then(std::char::from_u32)
+      .ok_or_else(|| format!("not a unicode code point: {}", n))));
+    LispVal::string(c.to_string().into())
+  },
+  StringToNumber: AtLeast(1) => {
+    let radix = match args.get(1) {
+      None => None,
+      Some(e) => Some(try1!(1; self.as_radix(e))),
+    };
+    let s = try1!(0; self.as_string(&args[0]));
+    let (neg, s) = match s.split_first() { Some((&b'-', s)) => (true, s), _ => (false, &*s) };
+    let (radix, digits) = match (radix, s) {
+      (Some(radix), _) => (radix, s),
+      (None, [b'0', b'x', s @ ..]) => (16, s),
+      (None, _) => (10, s),
+    };
+    if digits.is_empty() || !digits.iter().all(|&c| char::from(c).is_digit(radix)) {
+      LispVal::undef()
+    } else {
+      let n = BigInt::parse_bytes(digits, radix).expect("digits are valid");
+      LispVal::number(if neg {-n} else {n})
+    }
+  },
+  NumberToString: AtLeast(1) => {
+    let radix = match args.get(1) {
+      None => 10,
+      Some(e) => try1!(1; self.as_radix(e)),
+    };
+    let width: usize = match args.get(2) {
+      None => 0,
+      Some(e) => try1!(2; self.with_int(e,
+        |n| n.try_into().map_err(|_| format!("width out of range: {}", n)))),
+    };
+    let n = try1!(0; self.as_int(&args[0]));
+    let digits = n.magnitude().to_str_radix(radix);
+    let sign = if matches!(n.sign(), num::bigint::Sign::Minus) {"-"} else {""};
+    LispVal::string(format!("{}{:0>w$}", sign, digits, w = width).into())
+  },
   Not: AtLeast(0) => LispVal::bool(!args.iter().any(|e| e.truthy())),
   And: AtLeast(0) => LispVal::bool(args.iter().all(|e| e.truthy())),
   Or: AtLeast(0) => LispVal::bool(args.iter().any(|e| e.truthy())),
   List: AtLeast(0) => LispVal::list(args),
+  Append: AtLeast(0) => {
+    let r = args.pop().unwrap_or_else(LispVal::nil);
+    let mut vec = vec![];
+    for e in &args {
+      let mut u = Uncons::from(e.clone());
+      vec.extend(u.by_ref());
+      if !u.is_empty() { try1!(Err(format!("append: not a list: {}", self.print(e)))) }
+    }
+    if vec.is_empty() {r}
+    else if r.exactly(0) {LispVal::list(vec)}
+    else {LispVal::dotted_list(vec, r)}
+  },
   Cons: AtLeast(0) => match args.len() {
     0 => LispVal::nil(),
     1 => args[0].clone(),
@@ -977,9 +1595,9 @@ make_builtins! { self, sp1, sp2, args,
       else {LispVal::dotted_list(args, r)}
     }
   },
-  Head: Exact(1) => try1!(self.head_err(&args[0])),
-  Tail: Exact(1) => try1!(self.tail(&args[0])),
-  Nth: Exact(2) => try1!(self.nth(&args[1],
+  Head: Exact(1) => try1!(0; self.head_err(&args[0])),
+  Tail: Exact(1) => try1!(0; self.tail(&args[0])),
+  Nth: Exact(2) => try1!(1; self.nth(&args[1],
     try1!(args[0].as_int(|n| n.to_usize().unwrap_or(usize::MAX)).ok_or("expected a number")))),
   Map: AtLeast(1) => {
     let mut it = args.into_iter();
@@ -991,6 +1609,52 @@ make_builtins! { self, sp1, sp2, args,
     return Ok(State::MapProc(sp1, sp, proc,
       it.map(Uncons::from).collect(), vec![]))
   },
+  Fixpoint: AtLeast(2) => {
+    let limit = match args.get(2) {
+      None => 10000,
+      Some(e) => try1!(2; self.with_int(e,
+        |n| n.try_into().map_err(|_| format!("iteration limit out of range: {}", n)))),
+    };
+    let x = args.swap_remove(1);
+    let proc = args.swap_remove(0);
+    let sp = proc.fspan().map_or(sp2, |fsp| fsp.span);
+    self.stack.push(Stack::Fixpoint(sp1, sp, proc.clone(), x.clone(), 1, limit));
+    return Ok(State::App(sp1, sp, proc, vec![x], [].iter()))
+  },
+  Vector: AtLeast(0) => LispVal::vector(args),
+  IsVector: Exact(1) => LispVal::bool(args[0].is_vector()),
+  VectorLen: Exact(1) => LispVal::number(try1!(0; args[0].as_vector(|es| es.len())
+    .ok_or_else(|| LispError::Type {expected: "vector", actual: args[0].clone()})).into()),
+  VectorRef: Exact(2) => {
+    let i: usize = try1!(1; self.with_int(&args[1],
+      |n| n.try_into().map_err(|_| format!("index out of range: {}", n))));
+    match args[0].as_vector(|es| es.get(i).cloned().ok_or(es.len())) {
+      None => try1!(0; Err(LispError::Type {expected: "vector", actual: args[0].clone()})),
+      Some(Ok(e)) => e,
+      Some(Err(n)) => try1!(Err(format!("index out of range: index {}, length {}", i, n))),
+    }
+  },
+  VectorSet: Exact(3) => {
+    let i: usize = try1!(1; self.with_int(&args[1],
+      |n| n.try_into().map_err(|_| format!("index out of range: {}", n))));
+    let v = args.pop().expect("impossible");
+    try1!(try1!(args[0].as_ref_mut(|r| r.as_vec_mut(|es| match es.get_mut(i) {
+      Some(e) => {*e = v; Ok(())}
+      None => Err(format!("index out of range: index {}, length {}", i, es.len())),
+    })).unwrap_or(None).ok_or("expected a mutable vector")));
+    LispVal::undef()
+  },
+  ListToVector: Exact(1) => {
+    let mut u = Uncons::New(args[0].clone());
+    let mut out = Vec::with_capacity(u.len());
+    for e in &mut u { out.push(e) }
+    if !u.is_empty() {
+      try1!(Err(format!("list->vector: not a list: {}", self.print(&args[0]))))
+    }
+    LispVal::vector(out)
+  },
+  VectorToList: Exact(1) => LispVal::list(try1!(0; args[0].as_vector(|es| es.to_vec())
+    .ok_or_else(|| LispError::Type {expected: "vector", actual: args[0].clone()}))),
   IsBool: Exact(1) => LispVal::bool(args[0].is_bool()),
   IsAtom: Exact(1) => LispVal::bool(args[0].is_atom()),
   IsPair: Exact(1) => LispVal::bool(args[0].at_least(1)),
@@ -998,16 +1662,24 @@ make_builtins! { self, sp1, sp2, args,
   IsNumber: Exact(1) => LispVal::bool(args[0].is_int()),
   IsString: Exact(1) => LispVal::bool(args[0].is_string()),
   IsProc: Exact(1) => LispVal::bool(args[0].is_proc()),
+  IsCallable: Exact(1) => LispVal::bool(args[0].unwrapped(|e| match e {
+    LispKind::Proc(Proc::MatchCont(valid)) => valid.is_valid(),
+    e => matches!(e, LispKind::Proc(_)),
+  })),
+  IsContValid: Exact(1) => LispVal::bool(try1!(args[0].unwrapped(|e| match e {
+    LispKind::Proc(Proc::MatchCont(k)) => Ok(k.is_valid()),
+    _ => Err("expected a match continuation"),
+  }))),
   IsDef: Exact(1) => LispVal::bool(args[0].is_def()),
   IsRef: Exact(1) => LispVal::bool(args[0].is_ref()),
   NewRef: AtLeast(0) => LispVal::new_ref(args.get(0).cloned().unwrap_or_else(LispVal::undef)),
-  GetRef: Exact(1) => try1!(self.as_ref(&args[0], |e| Ok(e.clone()))),
+  GetRef: Exact(1) => try1!(0; self.as_ref(&args[0], |e| Ok(e.clone()))),
   SetRef: Exact(2) => {
-    try1!(self.as_ref(&args[0], |e| {*e = args[1].clone(); Ok(())}));
+    try1!(0; self.as_ref(&args[0], |e| {*e = args[1].clone(); Ok(())}));
     LispVal::undef()
   },
   SetWeak: Exact(2) => {
-    try1!(self.as_lref(&args[0], |e| {e.set_weak(&args[1]); Ok(())}));
+    try1!(0; self.as_lref(&args[0], |e| {e.set_weak(&args[1]); Ok(())}));
     LispVal::undef()
   },
   CopySpan: Exact(2) => {
@@ -1027,17 +1699,29 @@ make_builtins! { self, sp1, sp2, args,
   Async: AtLeast(1) => {
     let proc = args.remove(0);
     let sp = proc.fspan().map_or(sp2, |fsp| fsp.span);
-    // TODO: actually async this
     return Ok(State::App(sp1, sp, proc, args, [].iter()))
   },
+  Spawn: AtLeast(1) => {
+    let proc = args.remove(0);
+    if !proc.is_proc() { try1!(Err("expected a procedure")) }
+    let t = self.spawn_task(sp1, &proc, &args);
+    LispVal::proc(Proc::Promise(Rc::new(RefCell::new(Promise::Running(t)))))
+  },
+  Join: Exact(1) => {
+    let m = try1!(args[0].unwrapped(|e| match e {
+      LispKind::Proc(Proc::Promise(m)) => Some(m.clone()),
+      _ => None
+    }).ok_or("expected a promise"));
+    self.join_promise(sp1, &m)?
+  },
   IsAtomMap: Exact(1) => LispVal::bool(args[0].is_map()),
   NewAtomMap: AtLeast(0) => {
     let mut m = HashMap::new();
-    for e in args {
+    for (i, e) in args.into_iter().enumerate() {
       let mut u = Uncons::from(e);
       let e = try1!(u.next().ok_or("invalid arguments"));
-      let a = try1!(self.as_string_atom(&e)
-        .ok_or_else(|| format!("expected an atom, got {}", self.print(&e))));
+      let a = try1!(i; self.as_string_atom(&e)
+        .ok_or_else(|| LispError::Type {expected: "atom", actual: e.clone()}));
       let ret = u.next();
       if !u.exactly(0) {try1!(Err("invalid arguments"))}
       if let Some(v) = ret {m.insert(a, v);} else {m.remove(&a);}
@@ -1048,7 +1732,7 @@ make_builtins! { self, sp1, sp2, args,
     match self.as_string_atom(&args[1]) {
       None => LispVal::undef(),
       Some(k) => {
-        let e = try1!(self.as_map(&args[0], |m| Ok(m.get(&k).cloned())));
+        let e = try1!(0; self.as_map(&args[0], |m| Ok(m.get(&k).cloned())));
         if let Some(e) = e {e} else {
           let v = args.get(2).cloned().unwrap_or_else(LispVal::undef);
           if v.is_proc() {
@@ -1060,10 +1744,10 @@ make_builtins! { self, sp1, sp2, args,
     }
   },
   Insert: AtLeast(2) => {
-    try1!(try1!(args[0].as_ref_mut(|r| {
+    try1!(1; try1!(args[0].as_ref_mut(|r| {
       r.as_map_mut(|m| -> SResult<_> {
         let k = self.as_string_atom(&args[1])
-          .ok_or_else(|| format!("expected an atom, got {}", self.print(&args[1])))?;
+          .ok_or_else(|| LispError::Type {expected: "atom", actual: args[1].clone()})?;
         match args.get(2) {
           Some(v) => {m.insert(k, v.clone());}
           None => {m.remove(&k);}
@@ -1075,20 +1759,17 @@ make_builtins! { self, sp1, sp2, args,
   },
   InsertNew: AtLeast(2) => {
     let mut it = args.into_iter();
-    let mut m = it.next().unwrap();
+    let m = it.next().unwrap();
     let k = it.next().unwrap();
-    let k = self.as_string_atom(&k)
-      .ok_or_else(|| format!("expected an atom, got {}", self.print(&k)));
-    try1!(try1!(m.as_map_mut(|m| -> SResult<_> {
-      match it.next() {
-        Some(v) => {m.insert(k?, v);}
-        None => {m.remove(&k?);}
-      }
-      Ok(())
-    }).ok_or("expected a map")));
-    LispVal::undef()
+    let k = try1!(1; self.as_string_atom(&k)
+      .ok_or_else(|| LispError::Type {expected: "atom", actual: k.clone()}));
+    try1!(m.into_map_mut(|m| match it.next() {
+      Some(v) => {m.insert(k, v);}
+      None => {m.remove(&k);}
+    }).ok_or("expected a map")).1
   },
-  SetTimeout: Exact(1) => {
+  SetTimeout: AtLeast(1) => {
+    if args.len() > 3 { try1!(Err("expected 1 to 3 arguments")) }
     match try1!(args[0].as_int(|n| n.to_u64()).ok_or("expected a number")) {
       None | Some(0) => {self.timeout = None; self.cur_timeout = None},
       Some(n) => {
@@ -1097,6 +1778,18 @@ make_builtins! { self, sp1, sp2, args,
         self.cur_timeout = Instant::now().checked_add(d)
       }
     }
+    if let Some(e) = args.get(1) {
+      match try1!(e.as_int(|n| n.to_u64()).ok_or("expected a number")) {
+        None | Some(0) => {self.fuel = None; self.cur_fuel = None},
+        Some(n) => {self.fuel = Some(n); self.cur_fuel = Some(n)}
+      }
+    }
+    if let Some(e) = args.get(2) {
+      self.test_fuel = match try1!(e.as_int(|n| n.to_u64()).ok_or("expected a number")) {
+        None | Some(0) => None,
+        n => n,
+      }
+    }
     LispVal::undef()
   },
   SetStackLimit: Exact(1) => {
@@ -1105,27 +1798,58 @@ make_builtins! { self, sp1, sp2, args,
         .unwrap_or(usize::MAX);
     LispVal::undef()
   },
+  SetSizeLimit: AtLeast(1) => {
+    if args.len() > 2 { try1!(Err("invalid arguments")) }
+    let limit = |e: &LispVal| e.as_int(|n| n.to_usize())
+      .ok_or("expected a number").map(|n| match n { None | Some(0) => usize::MAX, Some(n) => n });
+    self.term_size_limit = try1!(limit(&args[0]));
+    if let Some(e) = args.get(1) { self.proof_size_limit = try1!(limit(e)) }
+    LispVal::undef()
+  },
   IsMVar: Exact(1) => LispVal::bool(args[0].is_mvar()),
   IsGoal: Exact(1) => LispVal::bool(args[0].is_goal()),
   NewMVar: AtLeast(0) => {
     let fsp = self.fspan(sp1);
-    self.lc.new_mvar(
-      if args.is_empty() { InferTarget::Unknown }
-      else if args.len() == 2 {
-        let sort = try1!(args[0].as_atom().ok_or("expected an atom"));
-        if try1!(args[1].as_bool().ok_or("expected a bool")) {
-          InferTarget::Bound(sort)
-        } else {
-          InferTarget::Reg(sort)
-        }
-      } else {try1!(Err("invalid arguments"))},
-      Some(fsp))
+    let tgt = if args.is_empty() { InferTarget::Unknown }
+    else if args.len() == 2 {
+      let sort = try1!(0; self.as_atom(&args[0]));
+      if try1!(1; args[1].as_bool().ok_or("expected a bool")) {
+        InferTarget::Bound(sort)
+      } else {
+        InferTarget::Reg(sort)
+      }
+    } else {try1!(Err("invalid arguments"))};
+    self.lc.new_mvar(tgt, Some(fsp))
+  },
+  Unify: Exact(2) => match self.unify_mvars(&args[0], &args[1]) {
+    Some(v) => self.mvar_map(v),
+    None => LispVal::bool(false),
+  },
+  MatchTerm: Exact(2) => {
+    let mut subst = HashMap::new();
+    if self.match_term(&args[0], &args[1], &mut subst) {
+      self.mvar_map(subst)
+    } else { LispVal::bool(false) }
+  },
+  PrettyPrint: AtLeast(1) => {
+    if args.len() > 2 { try1!(Err("expected 1 or 2 arguments")) }
+    let width = match args.get(1) {
+      None => 80,
+      Some(e) => try1!(try1!(e.as_int(|n| if matches!(n.sign(), num::bigint::Sign::Minus) {
+        Err(format!("width must be nonnegative: {}", n))
+      } else { Ok(n.to_usize().unwrap_or(usize::MAX)) }).ok_or("expected a number"))),
+    };
+    LispVal::string(format!("{}", self.format_env().pp(&args[0], width)).into())
   },
-  PrettyPrint: Exact(1) =>
-    LispVal::string(format!("{}", self.format_env().pp(&args[0], 80)).into()),
   NewGoal: Exact(1) => LispVal::goal(self.fspan(sp1), args.pop().unwrap()),
   GoalType: Exact(1) => try1!(args[0].goal_type().ok_or("expected a goal")),
-  InferType: Exact(1) => try1!(self.infer_type(sp1, &args[0]).map_err(|e| e.kind.msg())),
+  InferType: Exact(1) => match self.infer_type(sp1, &args[0]) {
+    Ok(e) => e,
+    Err(e) => match self.infer_target(sp1, &args[0]) {
+      Ok(InferTarget::Bound(s)) | Ok(InferTarget::Reg(s)) => LispVal::atom(s),
+      _ => try1!(Err(e.kind.msg()))
+    }
+  },
   InferSort: Exact(1) => match try1!(self.infer_target(sp1, &args[0]).map_err(|e| e.kind.msg())) {
     InferTarget::Bound(s) | InferTarget::Reg(s) => LispVal::atom(s),
     InferTarget::Unknown | InferTarget::Provable => LispVal::undef(),
@@ -1139,12 +1863,39 @@ make_builtins! { self, sp1, sp2, args,
     self.lc.closer = e;
     LispVal::undef()
   },
+  Checkpoint: Exact(0) => {
+    let c = self.checkpoint();
+    self.checkpoints.push(c);
+    LispVal::number((self.checkpoints.len() - 1).into())
+  },
+  Rollback: Exact(1) => {
+    let n = try1!(args[0].as_int(|n| n.to_usize()).ok_or("expected a number"));
+    let c = try1!(n.filter(|&n| n < self.checkpoints.len()).ok_or("rollback: invalid checkpoint"));
+    let mut cps = mem::take(&mut self.checkpoints);
+    cps.truncate(c + 1);
+    self.rollback(&cps[c]);
+    self.checkpoints = cps;
+    LispVal::undef()
+  },
   LocalCtx: Exact(0) =>
     LispVal::list(self.lc.proof_order.iter().map(|a| LispVal::atom(a.0)).collect::<Vec<_>>()),
-  ToExpr: Exact(1) => return Ok(State::Refine {
-    sp: sp1, stack: vec![RStack::DeferGoals(mem::take(&mut self.lc.goals))],
-    state: RState::RefineExpr {tgt: InferTarget::Unknown, e: args.swap_remove(0)}
-  }),
+  ToExpr: AtLeast(1) => {
+    if args.len() > 2 {try1!(Err("expected 1 or 2 arguments"))}
+    let tgt = match args.get(1) {
+      None => InferTarget::Unknown,
+      Some(s) => {
+        let s = try1!(1; self.as_atom(s));
+        if self.data[s].sort.is_none() {
+          try1!(Err(format!("unknown sort '{}'", self.print(&s))))
+        }
+        InferTarget::Reg(s)
+      }
+    };
+    return Ok(State::Refine {
+      sp: sp1, stack: vec![RStack::DeferGoals(mem::take(&mut self.lc.goals))],
+      state: RState::RefineExpr {tgt, e: args.swap_remove(0)}
+    })
+  },
   Refine: AtLeast(0) => return Ok(State::Refine {
     sp: sp1, stack: vec![],
     state: RState::Goals {
@@ -1156,7 +1907,7 @@ make_builtins! { self, sp1, sp2, args,
     if args.len() > 3 {try1!(Err("invalid arguments"))}
     let mut args = args.drain(..);
     let xarg = args.next().unwrap();
-    let a = try1!(xarg.as_atom().ok_or("expected an atom"));
+    let a = try1!(0; self.as_atom(&xarg));
     let x_sp = try_get_span(&self.fspan(sp1), &xarg);
     self.stack.push(Stack::Have(sp1, xarg, a));
     let mut stack = vec![RStack::DeferGoals(mem::take(&mut self.lc.goals))];
@@ -1172,14 +1923,214 @@ make_builtins! { self, sp1, sp2, args,
     };
     return Ok(State::Refine {sp: sp1, stack, state})
   },
+  Admit: AtLeast(0) => match args.len() {
+    0 => {
+      if self.lc.goals.is_empty() {try1!(Err("no goals"))}
+      let g = self.lc.goals.remove(0);
+      let tgt = try1!(g.goal_type().ok_or("expected a goal"));
+      let p = self.admit(sp1, tgt);
+      g.as_ref_(|e| *e = p).expect("a goal is a ref");
+      LispVal::undef()
+    }
+    2 => self.admit(sp1, args.pop().unwrap()),
+    _ => try1!(Err("expected 0 or 2 arguments")),
+  },
+  Unfold: AtLeast(1) => {
+    if args.len() > 2 {try1!(Err("expected 1 or 2 arguments"))}
+    let defs = try1!(self.as_unfold_defs(&args[0]));
+    match args.get(1) {
+      None => { let fsp = self.fspan(sp1); try1!(self.unfold_goal(fsp, &defs, false)); LispVal::undef() }
+      Some(e) => { let (e2, c) = self.unfold_all(&defs, e); LispVal::list(vec![e2, c]) }
+    }
+  },
+  UnfoldHead: AtLeast(1) => {
+    if args.len() > 2 {try1!(Err("expected 1 or 2 arguments"))}
+    let defs = try1!(self.as_unfold_defs(&args[0]));
+    match args.get(1) {
+      None => { let fsp = self.fspan(sp1); try1!(self.unfold_goal(fsp, &defs, true)); LispVal::undef() }
+      Some(e) => match self.unfold_once(&defs, e) {
+        Some((e2, c)) => LispVal::list(vec![e2, c]),
+        None => LispVal::bool(false),
+      }
+    }
+  },
+  Cong: Exact(0) => {
+    let ty = try1!(self.lc.goals.iter().find_map(|g| g.goal_type()).ok_or("cong: no goals"));
+    let p = try1!(self.cong_proof(&ty));
+    return Ok(State::Refine {
+      sp: sp1, stack: vec![],
+      state: RState::Goals {
+        gs: mem::take(&mut self.lc.goals).into_iter(),
+        es: vec![p].into_iter()
+      }
+    })
+  },
+  RegisterEq: Exact(4) => {
+    let x = try1!(0; self.as_atom(&args[0]));
+    let eq = match self.data[x].decl {
+      Some(DeclKey::Term(t)) => t,
+      _ => try1!(Err(format!("register-eq: unknown term '{}'", self.data[x].name))),
+    };
+    let mut thms = [ThmID(0); 3];
+    for (i, (t, e)) in thms.iter_mut().zip(&args[1..]).enumerate() {
+      let x = try1!(i + 1; self.as_atom(e));
+      *t = match self.data[x].decl {
+        Some(DeclKey::Thm(t)) => t,
+        _ => try1!(Err(format!("register-eq: unknown theorem '{}'", self.data[x].name))),
+      };
+    }
+    try1!(self.env.register_eq(eq, thms[0], thms[1], thms[2]));
+    LispVal::undef()
+  },
+  RegisterCongr: Exact(1) => {
+    let x = try1!(0; self.as_atom(&args[0]));
+    let t = match self.data[x].decl {
+      Some(DeclKey::Thm(t)) => t,
+      _ => try1!(Err(format!("register-congr: unknown theorem '{}'", self.data[x].name))),
+    };
+    try1!(self.env.register_congr(t));
+    LispVal::undef()
+  },
+  Rw: AtLeast(1) => {
+    if args.len() > 2 { try1!(Err("rw: expected 1 or 2 arguments")) }
+    let occ = match args.get(1) {
+      None => None,
+      Some(e) => match e.as_int(|n| n.to_usize()).flatten() {
+        Some(n) if n > 0 => Some(n),
+        _ => try1!(Err("rw: expected a positive number"))
+      }
+    };
+    let ty = try1!(self.lc.goals.iter().find_map(|g| g.goal_type()).ok_or("rw: no goals"));
+    let p = try1!(self.rw_proof(&ty, &args[0], occ));
+    return Ok(State::Refine {
+      sp: sp1, stack: vec![],
+      state: RState::Goals {
+        gs: mem::take(&mut self.lc.goals).into_iter(),
+        es: vec![p].into_iter()
+      }
+    })
+  },
+  RegisterMp: Exact(1) => {
+    let x = try1!(0; self.as_atom(&args[0]));
+    let t = match self.data[x].decl {
+      Some(DeclKey::Thm(t)) => t,
+      _ => try1!(Err(format!("register-mp: unknown theorem '{}'", self.data[x].name))),
+    };
+    try1!(self.env.register_mp(t));
+    LispVal::undef()
+  },
+  NormNum: AtLeast(0) => {
+    if args.len() > 1 { try1!(Err("norm-num: expected at most one argument")) }
+    if let Some(e) = args.first() {
+      let (n, p) = try1!(self.norm_num_eval(e));
+      LispVal::list(vec![n, p])
+    } else {
+      let ty = try1!(self.lc.goals.iter().find_map(|g| g.goal_type()).ok_or("norm-num: no goals"));
+      let p = try1!(self.norm_num_proof(&ty));
+      return Ok(State::Refine {
+        sp: sp1, stack: vec![],
+        state: RState::Goals {
+          gs: mem::take(&mut self.lc.goals).into_iter(),
+          es: vec![p].into_iter()
+        }
+      })
+    }
+  },
+  RegisterNormNum: Exact(2) => {
+    let role = try1!(0; self.as_atom(&args[0]));
+    let x = try1!(1; self.as_atom(&args[1]));
+    let role = self.data[role].name.clone();
+    try1!(self.env.register_norm_num(role.as_str(), x));
+    LispVal::undef()
+  },
+  RegisterTactic: Exact(2) => {
+    let x = try1!(0; self.as_atom(&args[0]));
+    let t = try1!(self.term(x).ok_or_else(||
+      format!("register-tactic: unknown term '{}'", self.data[x].name)));
+    if !args[1].is_def() {
+      self.tactics.remove(&t);
+    } else if args[1].is_proc() {
+      self.tactics.insert(t, args[1].clone());
+    } else {
+      try1!(1; Err(LispError::Type {expected: "procedure", actual: args[1].clone()}))
+    }
+    LispVal::undef()
+  },
   Stat: Exact(0) => {print!(sp1, self.stat()); LispVal::undef()},
+  GetStat: Exact(0) => LispVal::string(self.stat().into()),
+  SaveState: Exact(1) => {
+    let s = try1!(0; self.as_string(&args[0]));
+    let file = self.path.path().with_file_name(s.as_str());
+    let dir = file.parent().map_or_else(Default::default, Path::to_path_buf);
+    let state = try1!(self.save_state(&dir));
+    try1!(std::fs::write(&file, state.to_string()).map_err(|e| format!("save-state: {}", e)));
+    LispVal::undef()
+  },
+  SetGoalDisplay: Exact(1) => {
+    match try1!(0; self.as_atom(&args[0])) {
+      AtomID::FLAT => self.sequent_goals = false,
+      AtomID::SEQUENT => self.sequent_goals = true,
+      s => try1!(Err(format!("unknown goal display mode '{}'", self.print(&s))))
+    }
+    LispVal::undef()
+  },
+  GoalToSequent: Exact(1) => {
+    let concl = try1!(args[0].goal_type().ok_or("expected a goal"));
+    let hyps = self.lc.proof_order.iter()
+      .map(|(a, e, _)| LispVal::list(vec![LispVal::atom(*a), e.clone()]))
+      .collect::<Vec<_>>();
+    LispVal::list(vec![LispVal::list(hyps), concl])
+  },
   GetDecl: Exact(1) => {
-    let x = try1!(args[0].as_atom().ok_or("expected an atom"));
+    let x = try1!(0; self.as_atom(&args[0]));
     self.get_decl(args[0].fspan(), x)
   },
+  IsSort: Exact(1) => LispVal::bool(args[0].as_atom().map_or(false, |x| self.data[x].sort.is_some())),
+  IsTerm: Exact(1) => LispVal::bool(matches!(
+    args[0].as_atom().and_then(|x| self.data[x].decl), Some(DeclKey::Term(_)))),
+  IsThm: Exact(1) => LispVal::bool(matches!(
+    args[0].as_atom().and_then(|x| self.data[x].decl), Some(DeclKey::Thm(_)))),
+  AxiomsOf: Exact(1) => {
+    let x = try1!(0; self.as_atom(&args[0]));
+    let t = match self.data[x].decl {
+      Some(DeclKey::Thm(t)) => t,
+      _ => try1!(Err(format!("axioms-of: unknown theorem '{}'", self.data[x].name))),
+    };
+    let deps = self.axioms_of(t);
+    let f = |ts: Vec<ThmID>| LispVal::list(ts.into_iter()
+      .map(|t| LispVal::atom(self.thms[t].atom)).collect::<Vec<_>>());
+    LispVal::list(vec![f(deps.axioms), f(deps.holes)])
+  },
+  GetSort: Exact(1) => {
+    let x = try1!(0; self.as_atom(&args[0]));
+    match self.data[x].sort {
+      None => LispVal::undef(),
+      Some(s) => {
+        let mods = self.sorts[s].mods;
+        let mut es = vec![];
+        for &(m, name) in &[(Modifiers::PURE, "pure"), (Modifiers::STRICT, "strict"),
+            (Modifiers::PROVABLE, "provable"), (Modifiers::FREE, "free")] {
+          if mods.contains(m) { es.push(LispVal::atom(self.get_atom(name.as_bytes()))) }
+        }
+        LispVal::list(vec![LispVal::atom(self.get_atom(b"sort")), LispVal::atom(x), LispVal::list(es)])
+      }
+    }
+  },
+  TermSorts: Exact(1) => {
+    let x = try1!(0; self.as_atom(&args[0]));
+    let t = match self.data[x].decl {
+      Some(DeclKey::Term(t)) => t,
+      _ => try1!(Err(format!("term-sorts: unknown term '{}'", self.data[x].name))),
+    };
+    let td = &self.env.terms[t];
+    LispVal::list(vec![
+      LispVal::list(td.args.iter().map(|(_, ty)| LispVal::atom(self.sorts[ty.sort()].atom))
+        .collect::<Vec<_>>()),
+      LispVal::atom(self.sorts[td.ret.0].atom)])
+  },
   AddDecl: AtLeast(4) => {
     let fsp = self.fspan_base(sp1);
-    match try1!(args[0].as_atom().ok_or("expected an atom")) {
+    match try1!(0; self.as_atom(&args[0])) {
       AtomID::TERM | AtomID::DEF => self.add_term(&fsp, &args[1..])?,
       AtomID::AXIOM | AtomID::THM => return self.add_thm(fsp, &args[1..]),
       e => try1!(Err(format!("invalid declaration type '{}'", self.print(&e))))
@@ -1195,6 +2146,15 @@ make_builtins! { self, sp1, sp2, args,
     let fsp = self.fspan_base(sp1);
     return self.add_thm(fsp, &args)
   },
+  FreshName: AtLeast(1) => {
+    if args.len() > 2 { try1!(Err("expected 1 or 2 arguments")) }
+    let x = try1!(0; self.as_string_atom(&args[0]).ok_or("expected a string or atom"));
+    let kind = match args.get(1) {
+      None => AtomID::THM,
+      Some(k) => try1!(1; self.as_atom(k))
+    };
+    LispVal::atom(self.fresh_name(sp1, kind, x)?)
+  },
   NewDummy: AtLeast(1) => {
     if args.len() > 2 {try1!(Err("expected 1 or 2 armuments"))}
     let (x, s) = match args.get(1) {
@@ -1207,38 +2167,75 @@ make_builtins! { self, sp1, sp2, args,
         };
         (x, &args[0])
       }
-      Some(s) => (try1!(args[0].as_atom().ok_or("expected an atom")), s)
+      Some(s) => (try1!(0; self.as_atom(&args[0])), s)
     };
     let sort = try1!(s.as_atom().and_then(|s| self.data[s].sort).ok_or("expected a sort"));
     self.lc.vars.insert(x, (true, InferSort::Bound(sort)));
     LispVal::atom(x)
   },
   SetReporting: AtLeast(1) => {
-    if args.len() == 1 {
-      if let Some(b) = args[0].as_bool() {
-        self.reporting.error = b;
-        self.reporting.warn = b;
-        self.reporting.info = b;
-      } else {try1!(Err("invalid arguments"))}
-    } else if let Some(b) = args[1].as_bool() {
-      match try1!(args[0].as_atom().ok_or("expected an atom")) {
-        AtomID::ERROR => self.reporting.error = b,
-        AtomID::WARN => self.reporting.warn = b,
-        AtomID::INFO => self.reporting.info = b,
-        s => try1!(Err(format!("unknown error level '{}'", self.print(&s))))
-      }
-    } else {try1!(Err("invalid arguments"))}
+    try1!(self.set_reporting(&args));
     LispVal::undef()
   },
+  WithReporting: AtLeast(2) => {
+    let f = args.pop().expect("nonempty");
+    let old = self.reporting;
+    try1!(self.set_reporting(&args));
+    self.stack.push(Stack::Reporting(old));
+    return Ok(State::App(sp1, sp1, f, vec![], [].iter()))
+  },
   CheckProofs: Exact(1) => {
     if let Some(b) = args[0].as_bool() {
       self.check_proofs = b;
     } else {try1!(Err("invalid arguments"))}
     LispVal::undef()
   },
-  RefineExtraArgs: AtLeast(2) => {
-    if args.len() > 2 {try1!(Err("too many arguments"))}
-    args.into_iter().nth(1).unwrap()
+  NormalizeTokens: Exact(1) => {
+    if let Some(b) = args[0].as_bool() {
+      self.normalize_tokens = b;
+    } else {try1!(Err("invalid arguments"))}
+    LispVal::undef()
+  },
+  WarnPrecedence: Exact(1) => {
+    if let Some(b) = args[0].as_bool() {
+      self.warn_precedence = b;
+    } else {try1!(Err("invalid arguments"))}
+    LispVal::undef()
+  },
+  SetBacktrace: Exact(1) => {
+    if let Some(b) = args[0].as_bool() {
+      self.backtrace = b;
+    } else {try1!(Err("invalid arguments"))}
+    LispVal::undef()
+  },
+  ProfileLisp: Exact(1) => {
+    if let Some(b) = args[0].as_bool() {
+      self.elab.lisp_profile.set_active(b);
+    } else {try1!(Err("invalid arguments"))}
+    LispVal::undef()
+  },
+  ProfileReport: Exact(0) => {
+    let s = self.lisp_profile.report(self.format_env());
+    LispVal::string(s.into())
+  },
+  StrictOverrides: Exact(1) => {
+    if let Some(b) = args[0].as_bool() {
+      self.strict_overrides = b;
+    } else {try1!(Err("invalid arguments"))}
+    LispVal::undef()
+  },
+  OverriddenBuiltins: Exact(0) => {
+    LispVal::list(self.data.iter().enumerate()
+      .filter(|(_, d)| d.lisp.as_ref().map_or(false, |l| l.overrides.is_some()))
+      .map(|(i, _)| LispVal::atom(AtomID(i.try_into().expect("too many atoms")))).collect::<Vec<_>>())
+  },
+  RefineExtraArgs: AtLeast(3) => {
+    if args.len() > 3 {
+      try1!(Err(format!("too many arguments: {} is applied to the extra argument(s) {} \
+        (define refine-extra-args to handle this)",
+        self.print(&args[2]), self.print(&args[3..]))))
+    }
+    args.swap_remove(2)
   },
   EvalString: AtLeast(0) => {
     let fsp = self.fspan(sp1);
@@ -1254,16 +2251,172 @@ impl<'a> Evaluator<'a> {
     FileSpan {file: self.file.clone(), span}
   }
 
+  /// The innermost named procedure on the stack, for profiling.
+  fn innermost_proc(&self) -> Option<AtomID> {
+    self.stack.iter().rev().find_map(|s| match s {
+      Stack::Ret(_, ProcPos::Named(_, _, a), _, _) => Some(*a),
+      _ => None
+    })
+  }
+
+  /// Check with the debugger before calling `func` on `args` at `sp`, and if there is a
+  /// breakpoint on `func` or we are stepping, report the call and wait for the client to
+  /// say how to continue.
+  fn debug_call(&mut self, dbg: &Debugger, sp: Span, func: &Proc, args: &[LispVal]) -> Result<()> {
+    let name = match func {
+      Proc::Builtin(p) => Some(p.to_byte_str()),
+      Proc::Lambda {pos: ProcPos::Named(_, _, a), ..} => Some(&*self.data[*a].name),
+      _ => None
+    };
+    let depth = self.stack.len();
+    if !self.debug_step.should_pause(depth) && !dbg.is_breakpoint(name) {return Ok(())}
+    let mut call = name.map_or_else(|| "([fn]".into(), |n| format!("({}", String::from_utf8_lossy(n)));
+    for e in args {
+      call.push(' ');
+      call.push_str(&self.print_short(e))
+    }
+    call.push(')');
+    let mut stack = vec![];
+    let mut ctx = &*self.ctx;
+    for (i, s) in self.stack.iter().enumerate().rev() {
+      if let Stack::Ret(fsp, pos, spec, caller) = s {
+        let start = match self.stack.get(i + 1) { Some(&Stack::Drop(n)) => n, _ => ctx.len() };
+        stack.push(Frame {label: self.frame_label(pos, *spec, ctx, start, true), span: fsp.clone()});
+        ctx = caller;
+      }
+    }
+    let locals = self.ctx.iter().map(|e| self.print_short(e)).collect();
+    let start = Instant::now();
+    let cmd = dbg.pause(Paused {span: self.fspan(sp), call, stack, locals}, &self.cancel);
+    // The time spent paused does not count toward the timeout or the profile
+    let paused = start.elapsed();
+    self.cur_timeout = self.cur_timeout.and_then(|t| t.checked_add(paused));
+    self.profile.resume();
+    self.elab.lisp_profile.resume();
+    self.elab.debug_step = StepMode::after(cmd, depth);
+    if cmd == DebugCommand::Stop {
+      return Err(self.limit_err("evaluation stopped by the debugger"))
+    }
+    Ok(())
+  }
+
   fn proc_pos(&self, sp: Span) -> ProcPos {
-    if let Some(Stack::Def(Some(&Some((sp1, sp2, _, x))))) = self.stack.last() {
-      ProcPos::Named(self.fspan(sp2), sp1, x)
-    } else {
-      ProcPos::Unnamed(self.fspan(sp))
+    match self.stack.last() {
+      Some(Stack::Def(Some(&Some((sp1, sp2, _, x))))) |
+      Some(&Stack::Letrec(_, &Some((sp1, sp2, _, x)), ..)) =>
+        ProcPos::Named(self.fspan(sp2), sp1, x),
+      _ => ProcPos::Unnamed(self.fspan(sp))
     }
   }
 
-  #[allow(clippy::never_loop)]
+  /// Change the reporting mode according to the arguments of `set-reporting`.
+  fn set_reporting(&mut self, args: &[LispVal]) -> SResult<()> {
+    match *args {
+      [ref b] => {
+        let b = b.as_bool().ok_or("invalid arguments")?;
+        self.reporting.error = b;
+        self.reporting.warn = b;
+        self.reporting.info = b;
+      }
+      [ref ty, ref b] => {
+        let b = b.as_bool().ok_or("invalid arguments")?;
+        match self.as_atom(ty)? {
+          AtomID::ERROR => self.reporting.error = b,
+          AtomID::WARN => self.reporting.warn = b,
+          AtomID::INFO => self.reporting.info = b,
+          s => return Err(format!("unknown error level '{}'", self.print(&s)).into())
+        }
+      }
+      _ => return Err("invalid arguments".into())
+    }
+    Ok(())
+  }
+
   fn run(&mut self, mut active: State<'a>) -> Result<LispVal> {
+    let res = loop {
+      match self.run_loop(active) {
+        Err(e) => match self.catch(&e) {
+          Some(st) => active = st,
+          None => break Err(e),
+        },
+        res => break res,
+      }
+    };
+    if res.is_err() {
+      // Restore the reporting mode if we are unwinding out of a `with-reporting` block
+      if let Some(&mode) = self.stack.iter().find_map(|s|
+        if let Stack::Reporting(mode) = s {Some(mode)} else {None}) {
+        self.reporting = mode
+      }
+    }
+    res
+  }
+
+  /// Evaluate the body of the match branch `br`, after the pattern has matched the input `e`
+  /// and the guard has passed. The pattern variables are in the context starting at `start`.
+  fn branch_body(&mut self, sp: Span, e: LispVal,
+    it: std::slice::Iter<'a, Branch>, br: &'a Branch, start: usize
+  ) -> State<'a> {
+    if br.cont {
+      let valid = Rc::new(MatchCont::new(self.fspan(sp)));
+      self.ctx.push(LispVal::proc(Proc::MatchCont(valid.clone())));
+      self.stack.push(Stack::MatchCont(sp, e, it, valid));
+    }
+    self.stack.push(Stack::Drop(start));
+    State::Eval(&br.eval)
+  }
+
+  /// Pop stack frames up to and including the first one satisfying `f`, which is returned.
+  /// The frames that are skipped are discarded without being run, except that the context,
+  /// the reporting mode, the test fuel and the goals of a `focus` are restored, and match
+  /// continuations are invalidated (including the returned frame, if it is a match
+  /// continuation) with the reason `why`, applied to the location `sp` of the jump.
+  fn unwind(&mut self, sp: Span, why: fn(FileSpan) -> ContExpiry,
+    f: impl Fn(&Stack<'a>) -> bool
+  ) -> Option<Stack<'a>> {
+    let at = self.fspan(sp);
+    loop {
+      let mut s = self.stack.pop()?;
+      let found = f(&s);
+      match &mut s {
+        Stack::MatchCont(_, _, _, a) => a.expire(why(at.clone())),
+        &mut Stack::Drop(n) => self.ctx.truncate(n),
+        Stack::Ret(fsp, _, _, old) => {
+          self.file = fsp.file.clone();
+          self.ctx = mem::take(old)
+        }
+        &mut Stack::Reporting(mode) => self.reporting = mode,
+        Stack::TestPattern(_, _, _, _, _, _, _, true) => self.cur_test_fuel = None,
+        Stack::Focus(_, _, gs) => self.lc.goals.append(gs),
+        _ => {}
+      }
+      if found { return Some(s) }
+    }
+  }
+
+  /// Unwind the stack to the nearest enclosing `try`, if the error `e` can be caught,
+  /// and return the state that calls the handler on the exception value.
+  fn catch(&mut self, e: &ElabError) -> Option<State<'a>> {
+    let exn = self.exception.take();
+    if mem::take(&mut self.uncatchable) ||
+      !self.stack.iter().any(|s| matches!(s, Stack::Try(..))) {return None}
+    match self.unwind(e.pos, ContExpiry::Exception, |s| matches!(s, Stack::Try(..)))? {
+      Stack::Try(sp, h) => {
+        let exn = exn.unwrap_or_else(|| LispVal::string(e.kind.msg().into()));
+        Some(State::App(sp, sp, h, vec![exn], [].iter()))
+      }
+      _ => unreachable!()
+    }
+  }
+
+  /// Construct an error for exceeding a resource limit, which cannot be caught by `try`.
+  fn limit_err(&mut self, err: impl Into<BoxError>) -> ElabError {
+    self.uncatchable = true;
+    self.err(None, err)
+  }
+
+  #[allow(clippy::never_loop)]
+  fn run_loop(&mut self, mut active: State<'a>) -> Result<LispVal> {
     macro_rules! throw {($sp:expr, $e:expr) => {{
       let err = $e;
       return Err(self.err(Some(($sp, false)), err))
@@ -1273,20 +2426,53 @@ impl<'a> Evaluator<'a> {
       { #[allow(unused_imports)] use State::*; $ret }
     }}}
 
+    self.profile.resume();
+    self.elab.lisp_profile.resume();
     let mut iters: u8 = 0;
     // let mut stacklen = 0;
     loop {
       iters = iters.wrapping_add(1);
+      if self.elab.lisp_profile.active {
+        let proc = self.innermost_proc();
+        self.elab.lisp_profile.step(proc)
+      }
       if iters == 0 {
-        if self.cur_timeout.map_or(false, |t| t < Instant::now()) {
-          return Err(self.err(None, "timeout"))
+        let now = Instant::now();
+        let proc = self.innermost_proc();
+        self.profile.sample(now, proc);
+        if self.cur_timeout.map_or(false, |t| t < now) {
+          let ms = self.timeout.map_or(0, |d| d.as_millis());
+          return Err(self.limit_err(format!(
+            "timeout: evaluation took longer than {}ms (use (set-timeout n) to change the limit)", ms)))
         }
         if self.cancel.load(Ordering::Relaxed) {
-          return Err(self.err(None, "cancelled"))
+          return Err(self.limit_err("cancelled"))
+        }
+      }
+      if let Some(fuel) = &mut self.elab.cur_fuel {
+        match fuel.checked_sub(1) {
+          Some(n) => *fuel = n,
+          None => {
+            let n = self.fuel.unwrap_or(0);
+            return Err(self.limit_err(format!(
+              "timeout: evaluation used more than {} steps (use (set-timeout n k) to change the limit)", n)))
+          }
+        }
+      }
+      if let Some(fuel) = &mut self.cur_test_fuel {
+        match fuel.checked_sub(1) {
+          Some(n) => *fuel = n,
+          None => {
+            let n = self.test_fuel.unwrap_or(0);
+            return Err(self.limit_err(format!(
+              "timeout: pattern test used more than {} steps (use (set-timeout n k t) to change the limit)", n)))
+          }
         }
       }
       if self.stack.len() >= self.stack_limit {
-        return Err(self.err(None, "stack overflow"))
+        return Err(self.limit_err(format!(
+          "stack overflow: evaluation used more than {} stack frames \
+            (use (set-stack-limit n) to change the limit)", self.stack_limit)))
       }
       // if self.check_proofs {
       //   if self.stack.len() < stacklen {
@@ -1313,7 +2499,7 @@ impl<'a> Evaluator<'a> {
                 let s = name.clone();
                 let a = self.get_atom(&s);
                 let ret = LispVal::proc(Proc::Builtin(p));
-                self.data[a].lisp = Some(LispData {src: None, doc: None, val: ret.clone()});
+                self.data[a].lisp = Some(LispData {src: None, doc: None, val: ret.clone(), overrides: None});
                 ret
               }
             },
@@ -1324,10 +2510,16 @@ impl<'a> Evaluator<'a> {
           IR::DottedList(ls, e) => State::DottedList(vec![], ls.iter(), e),
           IR::App(sp1, sp2, f, es) => push!(App(*sp1, *sp2, es); Eval(f)),
           IR::If(e) => push!(If(&e.1, &e.2); Eval(&e.0)),
-          IR::NoTailRec => match self.stack.pop() {
-            None => State::Ret(LispVal::undef()),
-            Some(Stack::Eval(e, it)) => push!(NoTailRec; Evals(e, it)),
-            Some(s) => push!(s; State::Ret(LispVal::undef())),
+          &IR::Label(_, a, ref e) => push!(Label(a); Eval(e)),
+          &IR::Letrec(n, ref ds, ref body) => {
+            assert!(self.ctx.len() == n);
+            self.stack.push(Stack::Drop(n));
+            for _ in 0..ds.len() { self.ctx.push(LispVal::new_ref(LispVal::undef())) }
+            let mut it = ds.iter();
+            match it.next() {
+              None => State::Eval(body),
+              Some((x, e)) => push!(Letrec(n, x, it, body); Eval(e)),
+            }
           }
           &IR::Focus(sp, ref irs) => {
             if self.lc.goals.is_empty() {throw!(sp, "no goals")}
@@ -1358,6 +2550,28 @@ impl<'a> Evaluator<'a> {
               code: e.clone()
             }))
           }
+          &IR::OptArgs(sp, req, opt, ref kws, rest) => {
+            let mut u = Uncons::from(self.ctx.last().expect("missing arguments").clone());
+            let (mut args, mut kwargs) = (vec![], vec![LispVal::undef(); kws.len()]);
+            while let Some(e) = u.next() {
+              match e.as_atom().and_then(|a| kws.iter().position(|&k| k == a)) {
+                Some(i) => match u.next() {
+                  Some(v) => kwargs[i] = v,
+                  None => throw!(sp, format!("missing value for keyword argument {}",
+                    self.data[kws[i]].name)),
+                },
+                None => args.push(e),
+              }
+            }
+            if !rest && args.len() > opt {
+              throw!(sp, format!("expected at most {} argument(s)", req + opt))
+            }
+            let mut it = args.into_iter();
+            for _ in 0..opt { self.ctx.push(it.next().unwrap_or_else(LispVal::undef)) }
+            self.ctx.extend(kwargs);
+            if rest { self.ctx.push(LispVal::list(it.collect::<Vec<_>>())) }
+            State::Ret(LispVal::undef())
+          }
           &IR::Match(sp, ref e, ref brs) => push!(Match(sp, brs.iter()); Eval(e)),
         },
         State::Ret(ret) => match self.stack.pop() {
@@ -1373,8 +2587,16 @@ impl<'a> Evaluator<'a> {
           }),
           Some(Stack::App(sp1, sp2, es)) => State::App(sp1, sp2, ret, vec![], es.iter()),
           Some(Stack::App2(sp1, sp2, f, mut vec, it)) => { vec.push(ret); State::App(sp1, sp2, f, vec, it) }
-          Some(Stack::AppHead(sp1, sp2, e)) => State::App(sp1, sp2, ret, vec![e], [].iter()),
+          Some(Stack::AppHead(sp1, sp2, es)) => State::App(sp1, sp2, ret, es, [].iter()),
           Some(Stack::If(e1, e2)) => State::Eval(if ret.truthy() {e1} else {e2}),
+          Some(Stack::Letrec(i, _, mut it, body)) => {
+            self.ctx[i].as_lref(|r| r.set_weak(&ret));
+            self.ctx.push(ret);
+            match it.next() {
+              None => push!(NoTailRec; Eval(body)),
+              Some((x, e)) => push!(Letrec(i + 1, x, it, body); Eval(e)),
+            }
+          }
           Some(Stack::NoTailRec) => State::Ret(ret),
           Some(Stack::Def(x)) => if let Some(s) = self.stack.pop() {
             macro_rules! push_ret {($e:expr) => {{
@@ -1398,8 +2620,20 @@ impl<'a> Evaluator<'a> {
             if let Some(&Some((sp1, sp2, ref doc, a))) = x {
               let loc = (self.fspan(sp2), sp1);
               if ret.is_def_strict() {
+                let overrides = BuiltinProc::from_bytes(&self.data[a].name);
+                if let Some(p) = overrides.filter(|_| self.strict_overrides) {
+                  let spec = ret.unwrapped(|e| if let LispKind::Proc(f) = e {Some(f.spec())} else {None});
+                  if spec != Some(p.spec()) {
+                    throw!(sp1, match spec {
+                      None => format!("strict-overrides: '{}' overrides a builtin taking {}, \
+                        but the new definition is not a procedure", p, p.spec()),
+                      Some(spec) => format!("strict-overrides: '{}' overrides a builtin taking {}, \
+                        but the new definition takes {}", p, p.spec(), spec),
+                    })
+                  }
+                }
                 let e = mem::replace(&mut self.data[a].lisp,
-                  Some(LispData {src: Some(loc), doc: doc.clone(), val: ret}));
+                  Some(LispData {src: Some(loc), doc: doc.clone(), val: ret, overrides}));
                 if e.is_none() {
                   self.stmts.push(StmtTrace::Global(a))
                 }
@@ -1411,18 +2645,36 @@ impl<'a> Evaluator<'a> {
           },
           Some(Stack::Eval(e, it)) => State::Evals(e, it),
           Some(Stack::Match(sp, it)) => State::Match(sp, ret, it),
-          Some(Stack::TestPattern(sp, e, it, br, pstack, vars)) =>
-            State::Pattern(sp, e, it, br, pstack, vars, PatternState::Ret(ret.truthy())),
+          Some(Stack::TestPattern(sp, e, it, br, pstack, vars, _, owner)) => {
+            if owner { self.cur_test_fuel = None }
+            State::Pattern(sp, e, it, br, pstack, vars, PatternState::Ret(ret.truthy()))
+          }
+          Some(Stack::Guard(sp, e, it, br, start)) => if ret.truthy() {
+            self.ctx.truncate(start + br.vars + usize::from(br.whole));
+            self.branch_body(sp, e, it, br, start)
+          } else {
+            self.ctx.truncate(start);
+            State::Match(sp, e, it)
+          },
           Some(Stack::Drop(n)) => {self.ctx.truncate(n); State::Ret(ret)}
-          Some(Stack::Ret(fsp, _, old, _)) => {self.file = fsp.file; self.ctx = old; State::Ret(ret)}
-          Some(Stack::MatchCont(_, _, _, valid)) => {
-            if let Err(valid) = Rc::try_unwrap(valid) {valid.set(false)}
+          Some(Stack::Ret(fsp, _, _, old)) => {self.file = fsp.file; self.ctx = old; State::Ret(ret)}
+          Some(Stack::MatchCont(sp, _, _, valid)) => {
+            if Rc::strong_count(&valid) > 1 {valid.expire(ContExpiry::Returned(self.fspan(sp)))}
             State::Ret(ret)
           }
           Some(Stack::MapProc(sp1, sp2, f, us, mut vec)) => {
             vec.push(ret);
             State::MapProc(sp1, sp2, f, us, vec)
           }
+          Some(Stack::Fixpoint(sp1, sp2, f, x, n, limit)) =>
+            if *ret == *x { State::Ret(ret) }
+            else if n >= limit {
+              throw!(sp1, format!("fixpoint: no fixpoint reached after {} iterations, last value {}",
+                n, self.print(&ret)))
+            } else {
+              push!(Fixpoint(sp1, sp2, f.clone(), ret.clone(), n + 1, limit);
+                App(sp1, sp2, f, vec![ret], [].iter()))
+            }
           Some(Stack::AddThmProc(fsp, ap)) => {
             ap.finish(self, &fsp, ret)?;
             State::Ret(LispVal::undef())
@@ -1454,6 +2706,9 @@ impl<'a> Evaluator<'a> {
           },
           Some(Stack::Refine {sp, stack}) =>
             State::Refine {sp, stack, state: RState::Ret(ret)},
+          Some(Stack::Reporting(mode)) => { self.reporting = mode; State::Ret(ret) }
+          Some(Stack::Try(..)) => State::Ret(ret),
+          Some(Stack::Label(_)) => State::Ret(ret),
           Some(Stack::Have(sp, x, a)) => {
             let e = self.infer_type(sp, &ret)?;
             let span = try_get_span(&self.fspan(sp), &x);
@@ -1482,34 +2737,39 @@ impl<'a> Evaluator<'a> {
             let func = if let LispKind::Proc(f) = func { f }
             else { throw!(sp1, "not a function, cannot apply") };
             let spec = func.spec();
-            if !spec.valid(args.len()) {
-              match spec {
-                ProcSpec::Exact(n) => throw!(sp1, format!("expected {} argument(s)", n)),
-                ProcSpec::AtLeast(n) => throw!(sp1, format!("expected at least {} argument(s)", n)),
-              }
-            }
+            if !spec.valid(args.len()) { return Err(self.arity_err(sp1, func, spec, &args)) }
+            if let Some(dbg) = self.elab.debugger.clone() { self.debug_call(&dbg, sp1, func, &args)? }
             Ok(match func {
               &Proc::Builtin(func) => self.evaluate_builtin(sp1, sp2, func, args)?,
               Proc::Lambda {pos, env, code, ..} => {
                 let tail_call = (|| {
                   for (i, s) in self.stack.iter().enumerate().rev() {
                     match s {
-                      Stack::Ret(_, _, _, _) => return Some(i),
+                      Stack::Ret(..) => return Some(i),
                       Stack::Drop(_) => {}
+                      Stack::MatchCont(_, _, _, valid) if cont_unreachable(&mut self.ctx, valid) => {}
                       _ => break
                     }
                   }
                   None
                 })();
                 if let Some(i) = tail_call { // tail call
-                  let s = self.stack.drain(i..).next();
-                  if let Some(Stack::Ret(fsp, _, old, _)) = s {
+                  let at = self.fspan(sp1);
+                  let mut frames = self.stack.drain(i..);
+                  let s = frames.next();
+                  frames.for_each(|s| if let Stack::MatchCont(_, _, _, valid) = s {
+                    valid.expire(ContExpiry::TailCall(at.clone()))
+                  });
+                  if let Some(Stack::Ret(fsp, _, _, old)) = s {
                     self.ctx = (**env).into();
-                    self.stack.push(Stack::Ret(fsp, pos.clone(), old, code.clone()));
-                  } else {unsafe {std::hint::unreachable_unchecked()}}
+                    self.stack.push(Stack::Ret(fsp, pos.clone(), spec, old));
+                  } else {unreachable!()}
                 } else {
-                  self.stack.push(Stack::Ret(self.fspan(sp1), pos.clone(),
-                    mem::replace(&mut self.ctx, (**env).into()), code.clone()));
+                  self.stack.push(Stack::Ret(self.fspan(sp1), pos.clone(), spec,
+                    mem::replace(&mut self.ctx, (**env).into())));
+                }
+                if let (true, &ProcPos::Named(_, _, a)) = (self.elab.lisp_profile.active, pos) {
+                  self.elab.lisp_profile.call(a)
                 }
                 self.file = pos.fspan().file.clone();
                 self.stack.push(Stack::Drop(self.ctx.len()));
@@ -1520,32 +2780,14 @@ impl<'a> Evaluator<'a> {
                     self.ctx.push(LispVal::list(args));
                   }
                 }
-                // Unfortunately we're fighting the borrow checker here. The problem is that
-                // ir is borrowed in the Stack type, with most IR being owned outside the
-                // function, but when you apply a lambda, the Proc::LambdaExact constructor
-                // stores an Arc to the code to execute, hence it comes under our control,
-                // which means that when the temporaries in this block go away, so does
-                // ir (which is borrowed from f). We solve the problem by storing an Arc of
-                // the IR inside the Ret instruction above, so that it won't get deallocated
-                // while in use. Rust doesn't reason about other owners of an Arc though, so...
-                let code: *const IR = &**code;
-                State::Eval(unsafe { &*code })
+                State::Eval(self.code(code)?)
               },
               Proc::MatchCont(valid) => {
-                if !valid.get() {throw!(sp2, "continuation has expired")}
-                loop {
-                  match self.stack.pop() {
-                    Some(Stack::MatchCont(span, expr, it, a)) => {
-                      a.set(false);
-                      if Rc::ptr_eq(&a, valid) {
-                        break State::Match(span, expr, it)
-                      }
-                    }
-                    Some(Stack::Drop(n)) => {self.ctx.truncate(n);}
-                    Some(Stack::Ret(fsp, _, old, _)) => {self.file = fsp.file; self.ctx = old},
-                    Some(_) => {}
-                    None => throw!(sp2, "continuation has expired")
-                  }
+                if let Some(why) = valid.expiry() {return Err(self.expired_cont_err(sp2, valid, &why))}
+                match self.unwind(sp1, ContExpiry::Jumped,
+                  |s| matches!(s, Stack::MatchCont(_, _, _, a) if Rc::ptr_eq(a, valid))) {
+                  Some(Stack::MatchCont(span, expr, it, _)) => State::Match(span, expr, it),
+                  _ => throw!(sp2, "continuation has expired")
                 }
               }
               Proc::RefineCallback => State::Refine {
@@ -1574,10 +2816,11 @@ impl<'a> Evaluator<'a> {
                       let e = self.get_proof(t, heap.into());
                       *g = Ok(e.clone());
                       State::Ret(e)
-                    } else {unsafe {std::hint::unreachable_unchecked()}}
+                    } else {unreachable!()}
                   } else {unreachable!()}
                 }
               }
+              Proc::Promise(m) => State::Ret(self.join_promise(sp1, m)?),
               Proc::MMCCompiler(c) => {
                 let fsp = self.fspan(sp1);
                 State::Ret(c.borrow_mut().call(self, fsp, args)?)
@@ -1593,22 +2836,25 @@ impl<'a> Evaluator<'a> {
         },
         State::Pattern(sp, e, it, br, mut pstack, mut vars, st) => {
           match pattern_match(&mut pstack, &mut vars, st) {
-            Err(TestPending(sp2, e2, ir)) => push!(
-              TestPattern(sp, e, it, br, pstack, vars),
-              AppHead(sp2, sp2, e2),
-              Drop(self.ctx.len());
-              Eval(ir)),
+            Err(TestPending(sp2, args, ir)) => {
+              let owner = self.cur_test_fuel.is_none();
+              if owner { self.cur_test_fuel = self.test_fuel }
+              push!(
+                TestPattern(sp, e, it, br, pstack, vars, sp2, owner),
+                AppHead(sp2, sp2, args),
+                Drop(self.ctx.len());
+                Eval(ir))
+            }
             Ok(false) => State::Match(sp, e, it),
             Ok(true) => {
               let start = self.ctx.len();
               self.ctx.extend_from_slice(&vars);
-              if br.cont {
-                let valid = Rc::new(Cell::new(true));
-                self.ctx.push(LispVal::proc(Proc::MatchCont(valid.clone())));
-                self.stack.push(Stack::MatchCont(sp, e.clone(), it, valid));
+              if br.whole { self.ctx.push(e.clone()) }
+              if let Some(g) = &br.guard {
+                push!(Guard(sp, e, it, br, start); Eval(g))
+              } else {
+                self.branch_body(sp, e, it, br, start)
               }
-              self.stack.push(Stack::Drop(start));
-              State::Eval(&br.eval)
             },
           }
         }
@@ -1632,13 +2878,21 @@ impl<'a> Evaluator<'a> {
             }
           }
         }
-        State::Refines(sp, mut it) => match it.next() {
-          None => State::Ret(LispVal::undef()),
-          Some(e) => push!(Refines(sp, Some(e.span().unwrap_or(sp)), it); Eval(e))
-        },
+        State::Refines(sp, mut it) => {
+          let pos = it.as_slice().first().map_or(sp.end, |e| e.span().unwrap_or(sp).start);
+          let goals = self.lc.goals.iter().filter_map(|g| g.goal_type()).collect();
+          let n = self.lc.proof_order.len();
+          self.spans.insert_goals(pos, n, goals);
+          match it.next() {
+            None => State::Ret(LispVal::undef()),
+            Some(e) => push!(Refines(sp, Some(e.span().unwrap_or(sp)), it); Eval(e))
+          }
+        }
         State::Refine {sp, mut stack, state} => {
-          let res = self.elab.run_refine(self.orig_span, &mut stack, state)
-            .map_err(|e| self.err(Some((e.pos, true)), e.kind.msg()))?;
+          let start = Instant::now();
+          let res = self.elab.run_refine(self.orig_span, &mut stack, state);
+          self.profile.refine += start.elapsed();
+          let res = res.map_err(|e| self.err(Some((e.pos, true)), e.kind.msg()))?;
           match res {
             RefineResult::Ret(e) => {self.lc.clean_mvars(); State::Ret(e)}
             RefineResult::RefineExtraArgs(tgt, e, u) => {
@@ -1646,10 +2900,8 @@ impl<'a> Evaluator<'a> {
               for e in u {args.push(e)}
               stack.push(RStack::CoerceTo(tgt));
               self.stack.push(Stack::Refine {sp, stack});
-              match &self.data[AtomID::REFINE_EXTRA_ARGS].lisp {
-                None => self.evaluate_builtin(sp, sp, BuiltinProc::RefineExtraArgs, args)?,
-                Some(v) => State::App(sp, sp, v.val.clone(), args, [].iter()),
-              }
+              let f = self.overridable(BuiltinProc::RefineExtraArgs);
+              State::App(sp, sp, f, args, [].iter())
             }
             RefineResult::Proc(tgt, proc) => {
               let args = vec![LispVal::proc(Proc::RefineCallback), tgt];
@@ -0,0 +1,67 @@
+//! Output channels for the user-facing output of `display`, `print` and `stat`.
+//!
+//! By default this output is reported as info diagnostics, at the place where the
+//! printing function was called. Since that mixes the output of tactics with the actual
+//! diagnostics of the compiler, the front end can install an [`OutputChannel`] (see
+//! [`set_channel`]) to send it somewhere else: `mm0-rs compile --display` writes it to a
+//! file, and the server sends it to the client in a separate notification, if the client
+//! supports it.
+//!
+//! (This is unrelated to the `output string:` command, which is handled by
+//! [`inout`](super::super::inout).)
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use crate::util::{FileSpan, MutexExt};
+
+lazy_static! {
+  static ref CHANNEL: Mutex<Option<Arc<dyn OutputChannel>>> = Mutex::new(None);
+}
+
+/// A piece of output from `display`, `print` or `stat`.
+#[derive(Debug)]
+pub struct Output {
+  /// The location of the call that printed this
+  pub span: FileSpan,
+  /// The printed text
+  pub text: String,
+}
+
+/// A destination for the output of `display`, `print` and `stat`.
+pub trait OutputChannel: Send + Sync {
+  /// Send a piece of output to the channel.
+  fn write(&self, out: Output);
+}
+
+impl std::fmt::Debug for dyn OutputChannel {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { write!(f, "OutputChannel") }
+}
+
+impl<F: Fn(Output) + Send + Sync> OutputChannel for F {
+  fn write(&self, out: Output) { self(out) }
+}
+
+/// An [`OutputChannel`] that writes each piece of output on its own line to a writer,
+/// like stdout or a file.
+#[derive(Debug)]
+pub struct WriteChannel<W>(Mutex<W>);
+
+impl<W> WriteChannel<W> {
+  /// Construct a new channel writing to `w`.
+  pub fn new(w: W) -> Self { Self(Mutex::new(w)) }
+}
+
+impl<W: Write + Send> OutputChannel for WriteChannel<W> {
+  fn write(&self, out: Output) {
+    let mut w = self.0.ulock();
+    // There is nowhere to report a failure to write the output, so it is dropped.
+    let _ = writeln!(w, "{}", out.text).and_then(|_| w.flush());
+  }
+}
+
+/// Set the channel for the output of `display`, `print` and `stat` in elaborations that
+/// start after this. If it is `None`, the output is reported as info diagnostics.
+pub fn set_channel(chan: Option<Arc<dyn OutputChannel>>) { *CHANNEL.ulock() = chan }
+
+/// The current output channel, if one is set.
+#[must_use] pub fn current() -> Option<Arc<dyn OutputChannel>> { CHANNEL.ulock().clone() }
@@ -4,6 +4,7 @@
 
 use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
+use std::mem;
 use std::collections::HashMap;
 use num::{BigInt, ToPrimitive};
 use itertools::Itertools;
@@ -63,15 +64,33 @@ pub enum IR {
   ///   Evaluate the list of arguments, and return `#undef`. Unlike `(def x es)`,
   ///   this does not extend the context, i.e. it does not bind any variables.
   Eval(bool, Box<[IR]>),
-  /// A tail recursion barrier. This is needed in letrec compilation in order to prop up
-  /// the weak references to the functions.
-  NoTailRec,
+  /// The `(letrec ((x e) ...) body)` syntax form. `Letrec(n, defs, body)` extends the
+  /// context (which has length `n`) with a fresh reference for each of the definitions.
+  /// Then it evaluates each definition in turn, weakly assigns the result to its reference
+  /// (so that the definitions can refer to each other and to themselves, without a
+  /// reference cycle through the closures), and extends the context with the result,
+  /// which shadows the reference. Finally it evaluates `body`, which is not a tail call,
+  /// because the context keeps the results alive.
+  Letrec(usize, Box<[(DefTarget, IR)]>, Box<IR>),
   /// The `(fn xs e)` syntax form. Create a closure from the current context, and return
   /// it, using the provided [`ProcSpec`] and code. It can later be called by the
   /// [`App`](Self::App) instruction.
   Lambda(Span, usize, ProcSpec, Arc<IR>),
+  /// The prologue of a `fn` with optional or keyword parameters, like
+  /// `(fn (a [b 1] :key [c 2] . r) e)`. `OptArgs(sp, req, opt, kws, rest)` is the first
+  /// instruction of the function body, when the context contains the `req` required
+  /// arguments followed by the list of remaining arguments. It extends the context with
+  /// the `opt` optional arguments, the values of the keyword arguments `kws`, and (if `rest`
+  /// is true) the list of remaining positional arguments. Missing arguments are `#undef`;
+  /// the default values are filled in by the following instructions.
+  OptArgs(Span, usize, usize, Box<[AtomID]>, bool),
   /// The `(match e bs)` syntax form. Evaluate `e`, and then match it against the branches.
   Match(Span, Box<IR>, Box<[Branch]>),
+  /// The `(label 'x es)` syntax form. Evaluate `es`, returning early with `v`
+  /// if `(escape 'x v)` is called during the evaluation. Unlike a match continuation,
+  /// the label is not a first class value; `escape` looks it up by name on the stack,
+  /// and then unwinds using the same code as a match continuation.
+  Label(Span, AtomID, Box<IR>),
 }
 
 impl<'a> EnvDisplay for IR {
@@ -88,7 +107,11 @@ impl<'a> EnvDisplay for IR {
       IR::If(es) => write!(f, "(if {} {} {})",
         fe.to(&es.0), fe.to(&es.1), fe.to(&es.2)),
       IR::Focus(_, es) => write!(f, "(focus {})", es.iter().map(|ir| fe.to(ir)).format(" ")),
-      IR::NoTailRec => write!(f, "(no-tail-rec)"),
+      IR::Label(_, a, e) => write!(f, "(label {} {})", fe.to(a), fe.to(e)),
+      IR::Letrec(n, ds, e) => write!(f, "(letrec {}:({}) {})", n,
+        ds.iter().map(|(a, e)| format!("({} {})",
+          fe.to(&a.as_ref().map_or(AtomID::UNDER, |&(_, _, _, a)| a)), fe.to(e))).format(" "),
+        fe.to(e)),
       IR::Def(n, a, e) => write!(f, "(def {}:{} {})",
         n, fe.to(&a.as_ref().map_or(AtomID::UNDER, |&(_, _, _, a)| a)), fe.to(e)),
       IR::Eval(false, es) => write!(f, "(def _ {})", es.iter().map(|ir| fe.to(ir)).format(" ")),
@@ -101,6 +124,8 @@ impl<'a> EnvDisplay for IR {
         }
         write!(f, " {})", fe.to(e))
       }
+      IR::OptArgs(_, req, opt, kws, rest) => write!(f, "(opt-args {} {} ({}) {})",
+        req, opt, kws.iter().map(|k| fe.to(k)).format(" "), rest),
       IR::Match(_, e, bs) => write!(f, "(match {} {})", fe.to(e), fe.to(&**bs))
     }
   }
@@ -120,12 +145,6 @@ impl IR {
     IR::App(sp1, sp2, Box::new(IR::Const(LispVal::proc(Proc::Builtin(f)))), es)
   }
 
-  fn new_ref(sp1: Span, sp2: Span, e: IR) -> IR {
-    IR::builtin_app(sp1, sp2, BuiltinProc::NewRef, Box::new([e]))
-  }
-  fn set_weak(sp1: Span, sp2: Span, e1: IR, e2: IR) -> IR {
-    IR::builtin_app(sp1, sp2, BuiltinProc::SetWeak, Box::new([e1, e2]))
-  }
   fn match_fn_body(sp: Span, i: usize, brs: Box<[Branch]>) -> IR {
     IR::Match(sp, Box::new(IR::Local(i)), brs)
   }
@@ -137,6 +156,7 @@ impl IR {
       &IR::List(sp, _) |
       &IR::App(sp, _, _, _) |
       &IR::Focus(sp, _) |
+      &IR::Label(sp, _, _) |
       &IR::Lambda(sp, _, _, _) |
       &IR::Match(sp, _, _) => Some(sp),
       _ => None
@@ -150,6 +170,9 @@ impl IR {
 /// * `[pat (=> cont) eval]`: same thing, but `cont` is bound to a delimited continuation
 ///   that can be used to jump to the next case (essentially indicating that the branch fails to
 ///   apply even after the pattern succeeds).
+/// * `[pat (:as x) (:when guard) eval]`: `x` is bound to the whole input, and the branch only
+///   applies if `guard` evaluates to a truthy value after the pattern succeeds. Either clause
+///   can be omitted, and they can be combined with `(=> cont)`.
 #[derive(Debug, EnvDebug, DeepSizeOf)]
 pub struct Branch {
   /// The number of variables in the pattern. The context for `eval` is extended by this many variables
@@ -157,22 +180,28 @@ pub struct Branch {
   /// always bind variables `a` and `b` (so `vars = 2` here), and the one that is not matched in a
   /// given case will be `#undef`.
   pub vars: usize,
+  /// True if the branch includes `(:as x)`, in which case the context will be extended by the
+  /// input value (after the pattern variables).
+  pub whole: bool,
   /// True if the branch includes `(=> cont)`, in which case the context will be extended by the
   /// branch failure continuation.
   pub cont: bool,
   /// The pattern to match against.
   pub pat: Pattern,
+  /// The guard expression, from `(:when guard)`, which is evaluated after the pattern variables
+  /// (and `x` in `(:as x)`) are bound. If it returns `#f` the match moves on to the next branch.
+  pub guard: Option<Box<IR>>,
   /// The expression to evaluate with the result of the match.
   pub eval: Box<IR>,
 }
 
 impl<'a> EnvDisplay for Branch {
   fn fmt(&self, fe: FormatEnv<'_>, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    if self.cont {
-      write!(f, "[{} (=> _) {}]", fe.to(&self.pat), fe.to(&self.eval))
-    } else {
-      write!(f, "[{} {}]", fe.to(&self.pat), fe.to(&self.eval))
-    }
+    write!(f, "[{}", fe.to(&self.pat))?;
+    if self.whole { write!(f, " (:as _)")? }
+    if let Some(g) = &self.guard { write!(f, " (:when {})", fe.to(g))? }
+    if self.cont { write!(f, " (=> _)")? }
+    write!(f, " {}]", fe.to(&self.eval))
   }
 }
 
@@ -223,7 +252,11 @@ pub enum Pattern {
   /// The `(? f ps)` pattern. The expression `f` is evaluated in the context of the `match`,
   /// resulting in a procedure, and then `(f e)` is called, where `e` is the input.
   /// If this function returns truthy, then it acts like `(and ps)`, otherwise the pattern fails.
-  Test(Span, Box<IR>, Box<[Pattern]>),
+  /// The `(?? f (xs) ps)` pattern is the same, except that it calls `(f e xs)`, where
+  /// the `xs` are indexes of pattern variables bound earlier in the pattern.
+  Test(Span, Box<IR>, Box<[usize]>, Box<[Pattern]>),
+  /// The `(vector ps)` pattern. Matches a vector whose elements match the patterns `ps`.
+  Vector(Box<[Pattern]>),
   /// The `$foo$` pattern. This is equivalent to `(or 'foo ('foo))`.
   QExprAtom(AtomID),
 }
@@ -266,7 +299,10 @@ impl<'a> EnvDisplay for Pattern {
       Pattern::And(es) => write!(f, "(and {})", es.iter().map(|ir| fe.to(ir)).format(" ")),
       Pattern::Or(es) => write!(f, "(or {})", es.iter().map(|ir| fe.to(ir)).format(" ")),
       Pattern::Not(es) => write!(f, "(not {})", es.iter().map(|ir| fe.to(ir)).format(" ")),
-      Pattern::Test(_, ir, p) => write!(f, "(? {} {})", fe.to(&**ir), fe.to(&**p)),
+      Pattern::Vector(es) => write!(f, "(vector {})", es.iter().map(|ir| fe.to(ir)).format(" ")),
+      Pattern::Test(_, ir, xs, p) if xs.is_empty() => write!(f, "(? {} {})", fe.to(&**ir), fe.to(&**p)),
+      Pattern::Test(_, ir, xs, p) => write!(f, "(?? {} ({}) {})", fe.to(&**ir),
+        xs.iter().map(|i| format!("x{}", i)).format(" "), fe.to(&**p)),
       Pattern::QExprAtom(a) => write!(f, "${}$", fe.to(a)),
     }
   }
@@ -283,13 +319,20 @@ impl Remap for IR {
       IR::DottedList(v, e) => IR::DottedList(v.remap(r), e.remap(r)),
       &IR::App(s, t, ref e, ref es) => IR::App(s, t, e.remap(r), es.remap(r)),
       IR::If(e) => IR::If(e.remap(r)),
-      IR::NoTailRec => IR::NoTailRec,
+      &IR::Letrec(n, ref ds, ref e) => IR::Letrec(n,
+        ds.iter().map(|(a, e)| (
+          a.as_ref().map(|&(sp1, sp2, ref doc, a)| (sp1, sp2, doc.clone(), a.remap(r))),
+          e.remap(r))).collect(),
+        e.remap(r)),
       IR::Focus(sp, e) => IR::Focus(*sp, e.remap(r)),
+      &IR::Label(sp, a, ref e) => IR::Label(sp, a.remap(r), e.remap(r)),
       &IR::Def(n, ref a, ref e) => IR::Def(n,
         a.as_ref().map(|&(sp1, sp2, ref doc, a)| (sp1, sp2, doc.clone(), a.remap(r))),
         e.remap(r)),
       &IR::Eval(b, ref e) => IR::Eval(b, e.remap(r)),
       &IR::Lambda(sp, n, spec, ref e) => IR::Lambda(sp, n, spec, e.remap(r)),
+      &IR::OptArgs(sp, req, opt, ref kws, rest) =>
+        IR::OptArgs(sp, req, opt, kws.iter().map(|k| k.remap(r)).collect(), rest),
       &IR::Match(sp, ref e, ref br) => IR::Match(sp, e.remap(r), br.remap(r)),
     }
   }
@@ -300,8 +343,10 @@ impl Remap for Branch {
   fn remap(&self, r: &mut Remapper) -> Self {
     Self {
       vars: self.vars,
+      whole: self.whole,
       cont: self.cont,
       pat: self.pat.remap(r),
+      guard: self.guard.remap(r),
       eval: self.eval.remap(r)
     }
   }
@@ -325,7 +370,9 @@ impl Remap for Pattern {
       Pattern::And(es) => Pattern::And(es.remap(r)),
       Pattern::Or(es) => Pattern::Or(es.remap(r)),
       Pattern::Not(es) => Pattern::Not(es.remap(r)),
-      &Pattern::Test(sp, ref ir, ref es) => Pattern::Test(sp, ir.remap(r), es.remap(r)),
+      Pattern::Vector(es) => Pattern::Vector(es.remap(r)),
+      &Pattern::Test(sp, ref ir, ref xs, ref es) =>
+        Pattern::Test(sp, ir.remap(r), xs.clone(), es.remap(r)),
       Pattern::QExprAtom(a) => Pattern::QExprAtom(a.remap(r)),
     }
   }
@@ -456,38 +503,89 @@ impl<'a> LispParser<'a> {
   }
 
   fn def_ir(&mut self, sp: Span, es: &[SExpr], stack: Vec<Item<'_>>) -> Result<Vec<IR>, ElabError> {
+    let len = self.ctx.len();
+    let mut lams = vec![];
     for e in stack.iter().rev() {
-      match e {
-        Item::List(xs) => {
-          let xs = self.parse_idents(xs)?;
-          self.ctx.push_list(&xs);
-        }
-        Item::DottedList(xs, y) => {
-          let xs = self.parse_idents(xs)?;
-          self.ctx.push_list(&xs);
-          let y = self.parse_ident(y)?;
-          self.ctx.push(y);
-        }
-      }
+      lams.push(match *e {
+        Item::List(xs) => self.params(sp, xs, None)?,
+        Item::DottedList(xs, y) => self.params(sp, xs, Some(y))?,
+      })
     }
-    let mut len = self.ctx.len();
     let mut ir = self.exprs(false, es)?;
-    for e in stack {
-      ir = match e {
-        Item::List(xs) => {
-          len -= xs.len();
-          vec![IR::Lambda(sp, len, ProcSpec::Exact(xs.len()), IR::eval(ir).into())]
-        }
-        Item::DottedList(xs, _) => {
-          len -= xs.len() + 1;
-          vec![IR::Lambda(sp, len, ProcSpec::AtLeast(xs.len()), IR::eval(ir).into())]
-        }
-      }
+    for (n, spec, mut code) in lams.into_iter().rev() {
+      code.append(&mut ir);
+      ir = vec![IR::Lambda(sp, n, spec, IR::eval(code).into())];
     }
     self.ctx.restore(len);
     Ok(ir)
   }
 
+  /// Returns true if `e` is a keyword, an atom like `:foo` starting with a colon.
+  fn is_keyword(&self, e: &SExpr) -> bool {
+    matches!(e.k, SExprKind::Atom(a) if {
+      let s = self.ast.span_atom(e.span, a);
+      s.len() > 1 && s[0] == b':'
+    })
+  }
+
+  /// Parse a parameter `x` or `[x e]`, where `e` is the default value.
+  fn param<'c>(&mut self, e: &'c SExpr) -> Result<(AtomID, Option<&'c SExpr>), ElabError> {
+    match &e.k {
+      SExprKind::List(es) if (1..=2).contains(&es.len()) => Ok((self.parse_ident(&es[0])?, es.get(1))),
+      _ => Ok((self.parse_ident(e)?, None))
+    }
+  }
+
+  /// Parse the parameter list `(a b [c e] :key [d e] . r)` of a `fn` or function `def`,
+  /// and push the parameters to the context. Returns the context length before the
+  /// parameters, the [`ProcSpec`] of the function, and the code that binds the optional
+  /// and keyword parameters, which goes at the start of the function body.
+  fn params(&mut self, sp: Span, xs: &[SExpr], rest: Option<&SExpr>) ->
+      Result<(usize, ProcSpec, Vec<IR>), ElabError> {
+    let (mut req, mut opt, mut kws) = (vec![], vec![], vec![]);
+    let mut it = xs.iter();
+    while let Some(x) = it.next() {
+      if self.is_keyword(x) {
+        let k = self.parse_ident(x)?;
+        let y = it.next().ok_or_else(|| ElabError::new_e(x.span, "expected a parameter after keyword"))?;
+        let (y, e) = self.param(y)?;
+        kws.push((k, y, e))
+      } else if let SExprKind::List(_) = x.k {
+        if !kws.is_empty() {
+          return Err(ElabError::new_e(x.span,
+            "optional parameters must come before keyword parameters"))
+        }
+        opt.push(self.param(x)?)
+      } else if !opt.is_empty() || !kws.is_empty() {
+        return Err(ElabError::new_e(x.span,
+          "required parameters must come before optional and keyword parameters"))
+      } else {
+        req.push(self.parse_ident(x)?)
+      }
+    }
+    let rest = rest.map(|y| self.parse_ident(y)).transpose()?;
+    let n = self.ctx.push_list(&req);
+    if opt.is_empty() && kws.is_empty() {
+      return Ok(match rest {
+        None => (n, ProcSpec::Exact(req.len()), vec![]),
+        Some(y) => {self.ctx.push(y); (n, ProcSpec::AtLeast(req.len()), vec![])}
+      })
+    }
+    self.ctx.push(AtomID::UNDER);
+    let mut code = vec![IR::OptArgs(sp, req.len(), opt.len(),
+      kws.iter().map(|k| k.0).collect(), rest.is_some())];
+    let vars = opt.iter().map(|p| p.0).chain(kws.iter().map(|k| k.1)).chain(rest).collect::<Vec<_>>();
+    let k = self.ctx.push_list(&vars);
+    for (i, (x, e)) in opt.into_iter().chain(kws.into_iter().map(|(_, x, e)| (x, e))).enumerate() {
+      if let Some(e) = e {
+        let e = self.expr(false, e)?;
+        let test = IR::builtin_app(sp, sp, BuiltinProc::IsDef, Box::new([IR::Local(k + i)]));
+        code.push(IR::Def(self.ctx.push(x), None, Box::new(IR::If(Box::new((test, IR::Local(k + i), e))))))
+      }
+    }
+    Ok((n, ProcSpec::AtLeast(req.len()), code))
+  }
+
   fn def(&mut self, e: &SExpr, es: &[SExpr]) -> Result<(Span, AtomID, Vec<IR>), ElabError> {
     let (sp, x, stack) = self.def_var(e)?;
     let ir = self.def_ir(sp, es, stack)?;
@@ -519,12 +617,6 @@ impl<'a> LispParser<'a> {
     }
   }
 
-  fn parse_idents(&mut self, es: &[SExpr]) -> Result<Vec<AtomID>, ElabError> {
-    let mut xs = vec![];
-    for e in es {xs.push(self.parse_ident(e)?)}
-    Ok(xs)
-  }
-
   fn qexpr(&mut self, e: QExpr) -> Result<IR, ElabError> {
     match e.k {
       QExprKind::IdentApp(sp, es) => {
@@ -557,6 +649,20 @@ impl<'a> LispParser<'a> {
     Ok(cs)
   }
 
+  /// Parse the arguments of a function application. A keyword `:foo` followed by
+  /// another argument evaluates to itself, so that it can name a keyword parameter.
+  fn args(&mut self, es: &[SExpr]) -> Result<Vec<IR>, ElabError> {
+    let mut cs = vec![];
+    for (i, e) in es.iter().enumerate() {
+      cs.push(match e.k {
+        SExprKind::Atom(a) if i + 1 < es.len() && self.is_keyword(e) =>
+          IR::Const(LispVal::atom(self.parse_atom(e.span, a)?).span(self.fspan(e.span))),
+        _ => self.expr(false, e)?
+      })
+    }
+    Ok(cs)
+  }
+
   fn let_var<'c>(&mut self, e: &'c SExpr) -> Result<(Var<'c>, &'c [SExpr]), ElabError> {
     match &e.k {
       SExprKind::List(es) if !es.is_empty() => Ok((self.def_var(&es[0])?, &es[1..])),
@@ -569,42 +675,48 @@ impl<'a> LispParser<'a> {
     let ls = if let SExprKind::List(ls) = &es[0].k {ls} else {
       return Err(ElabError::new_e(es[0].span, "let: invalid spec"))
     };
-    let mut cs = vec![];
     if rec {
+      let start = self.ctx.len();
       let mut ds = Vec::with_capacity(ls.len());
       for l in ls {
         let ((sp, x, stk), e2) = self.let_var(l)?;
-        let n = self.ctx.push(x);
+        self.ctx.push(x);
         let sps = if x == AtomID::UNDER {None} else {Some((l.span, sp, None, x))};
-        cs.push(IR::Def(n, sps.clone(),
-          Box::new(IR::new_ref(sp, sp, IR::Const(LispVal::undef())))));
-        ds.push((sp, x, stk, e2, n, sps));
+        ds.push((sp, x, stk, e2, sps));
       }
-      for (sp, x, stk, e2, n, sps) in ds {
-        let mut v = self.def_ir(sp, e2, stk)?;
-        if let Some(r) = v.pop() {
-          cs.extend(v);
-          let m = self.ctx.push(x);
-          cs.push(IR::Def(m, sps, r.into()));
-          cs.push(IR::set_weak(sp, sp, IR::Local(n), IR::Local(m)));
-        }
+      let mut defs = Vec::with_capacity(ds.len());
+      for (sp, x, stk, e2, sps) in ds {
+        defs.push((sps, IR::eval(self.def_ir(sp, e2, stk)?)));
+        self.ctx.push(x);
       }
-      cs.push(IR::NoTailRec);
-    } else {
-      for l in ls {
-        let ((sp, x, stk), e2) = self.let_var(l)?;
-        let v = self.def_ir(sp, e2, stk)?;
-        if x == AtomID::UNDER {
-          cs.push(IR::Eval(false, v.into()))
-        } else {
-          cs.push(IR::Def(self.ctx.push(x), Some((l.span, sp, None, x)), IR::eval(v).into()))
-        }
+      let body = self.exprs(false, &es[1..])?;
+      self.ctx.restore(start);
+      return Ok(IR::Letrec(start, defs.into(), IR::eval(body).into()))
+    }
+    let mut cs = vec![];
+    for l in ls {
+      let ((sp, x, stk), e2) = self.let_var(l)?;
+      let v = self.def_ir(sp, e2, stk)?;
+      if x == AtomID::UNDER {
+        cs.push(IR::Eval(false, v.into()))
+      } else {
+        cs.push(IR::Def(self.ctx.push(x), Some((l.span, sp, None, x)), IR::eval(v).into()))
       }
     }
     for e in &es[1..] { cs.push(self.expr(false, e)?) }
     Ok(IR::Eval(true, cs.into()))
   }
 
+  /// If `e` is `,@e'`, that is, `(unquote-splicing e')`, returns `e'`.
+  fn splice<'c>(&self, e: &'c SExpr) -> Option<&'c SExpr> {
+    if let SExprKind::List(es) = &e.k {
+      if let [SExpr {span, k: SExprKind::Atom(a)}, e] = &**es {
+        if self.ast.span_atom(*span, *a) == b"unquote-splicing" { return Some(e) }
+      }
+    }
+    None
+  }
+
   fn list_pattern(&mut self, ctx: &mut LocalCtx, code: &mut Vec<IR>,
       quote: bool, mut es: &[SExpr]) -> Result<Pattern, ElabError> {
     let mut pfx = vec![];
@@ -615,6 +727,9 @@ impl<'a> LispParser<'a> {
           if self.ast.span_atom(span, a) == b"unquote" {
             break self.pattern(ctx, code, false, e)?
           },
+        [e] if quote => if let Some(e) = self.splice(e) {
+          break self.pattern(ctx, code, false, e)?
+        },
         _ if quote => {},
         [head, args @ ..] => if let SExprKind::Atom(a) = head.k {
           match self.ast.span_atom(head.span, a) {
@@ -641,16 +756,32 @@ impl<'a> LispParser<'a> {
             b"and" => break Pattern::And(self.patterns(ctx, code, quote, args)?),
             b"or" => break Pattern::Or(self.patterns(ctx, code, quote, args)?),
             b"not" => break Pattern::Not(self.patterns(ctx, code, quote, args)?),
+            b"vector" => break Pattern::Vector(self.patterns(ctx, code, quote, args)?),
             b"?" => match args {
               [test, tail @ ..] => {
                 let p = self.ctx.len();
                 let ir = self.expr(false, test)?;
                 self.ctx.restore(p);
                 let tail = self.patterns(ctx, code, quote, tail)?;
-                break Pattern::Test(test.span, Box::new(ir), tail)
+                break Pattern::Test(test.span, Box::new(ir), Box::new([]), tail)
               },
               _ => return Err(ElabError::new_e(head.span, "expected at least one argument")),
             }
+            b"??" => match args {
+              [test, SExpr {k: SExprKind::List(xs), ..}, tail @ ..] => {
+                let xs = xs.iter().map(|x| {
+                  let a = self.parse_ident(x)?;
+                  ctx.get(a).ok_or_else(|| ElabError::new_e(x.span,
+                    "expected a variable bound earlier in the pattern"))
+                }).collect::<Result<Box<[_]>, _>>()?;
+                let p = self.ctx.len();
+                let ir = self.expr(false, test)?;
+                self.ctx.restore(p);
+                let tail = self.patterns(ctx, code, quote, tail)?;
+                break Pattern::Test(test.span, Box::new(ir), xs, tail)
+              },
+              _ => return Err(ElabError::new_e(head.span, "expected a test and a list of variables")),
+            }
             b"cons" => match args {
               [] => return Err(ElabError::new_e(head.span, "expected at least one argument")),
               [es @ .., e] => break Pattern::DottedList(
@@ -671,6 +802,10 @@ impl<'a> LispParser<'a> {
           }
         }
       }
+      if quote && self.splice(&es[0]).is_some() {
+        return Err(ElabError::new_e(es[0].span,
+          "unquote-splicing is only allowed at the end of a list pattern"))
+      }
       pfx.push(self.pattern(ctx, code, quote, &es[0])?);
       es = &es[1..];
     };
@@ -746,25 +881,40 @@ impl<'a> LispParser<'a> {
       SExprKind::List(es) if !es.is_empty() => (&es[0], &es[1..]),
       _ => return Err(ElabError::new_e(e.span, "match: improper syntax"))
     };
-    let mut cont = AtomID::UNDER;
-    if let Some(e2) = es.get(0) {
+    let (mut whole, mut guard, mut cont) = (None, None, AtomID::UNDER);
+    while let Some(e2) = es.get(0) {
       if let SExprKind::List(v) = &e2.k {
         if let [SExpr {span, k: SExprKind::Atom(a)}, ref x] = **v {
-          if let b"=>" = self.ast.span_atom(span, a) {
-            cont = self.parse_ident(x)?;
-            es = &es[1..];
+          match self.ast.span_atom(span, a) {
+            b"=>" if cont == AtomID::UNDER => cont = self.parse_ident(x)?,
+            b":as" if whole.is_none() => whole = Some(self.parse_ident(x)?),
+            b":when" if guard.is_none() => guard = Some(x),
+            _ => break
           }
+          es = &es[1..];
+          continue
         }
       }
+      break
     }
     let mut ctx = LocalCtx::new();
     let pat = self.pattern(&mut ctx, code, false, e)?;
     let vars = ctx.ctx.len();
     let start = self.ctx.push_list(&ctx.ctx);
+    if let Some(x) = whole {self.ctx.push(x);}
+    let guard = match guard {
+      Some(g) => {
+        let n = self.ctx.len();
+        let g = self.expr(false, g)?;
+        self.ctx.restore(n);
+        Some(Box::new(g))
+      }
+      None => None
+    };
     if cont != AtomID::UNDER {self.ctx.push(cont);}
     let eval = Box::new(IR::eval(self.exprs(false, es)?));
     self.ctx.restore(start);
-    Ok(Branch {pat, vars, cont: cont != AtomID::UNDER, eval})
+    Ok(Branch {pat, vars, whole: whole.is_some(), cont: cont != AtomID::UNDER, guard, eval})
   }
   fn branches(&mut self, code: &mut Vec<IR>, es: &[SExpr]) -> Result<Box<[Branch]>, ElabError> {
     let mut bs = vec![];
@@ -840,25 +990,34 @@ impl<'a> LispParser<'a> {
       }
       SExprKind::List(es) if es.is_empty() => Ok(IR::Const(span!(e.span, LispVal::nil()))),
       SExprKind::List(es) => if quote {
+        let mut segs = vec![];
         let mut cs = vec![];
         let mut it = es.iter();
-        Ok(loop {
+        let tail = loop {
           if let Some(arg) = it.next() {
             if let SExprKind::Atom(a) = arg.k {
               if let Ok(Syntax::Unquote) = Syntax::parse(self.ast.span(arg.span), a) {
                 let r = it.next().ok_or_else(||
                   ElabError::new_e(arg.span, "expected at least one argument"))?;
                 break IR::dotted_list(e.span, cs, self.expr(false, r)?)
-              } else {cs.push(self.expr(true, arg)?)}
+              }
+            }
+            if let Some(r) = self.splice(arg) {
+              if !cs.is_empty() { segs.push(IR::list(self.fspan(e.span), mem::take(&mut cs))) }
+              segs.push(self.expr(false, r)?)
             } else {cs.push(self.expr(true, arg)?)}
           } else {break IR::list(self.fspan(e.span), cs)}
+        };
+        Ok(if segs.is_empty() {tail} else {
+          segs.push(tail);
+          IR::builtin_app(e.span, e.span, BuiltinProc::Append, segs.into())
         })
       } else if let SExprKind::Atom(a) = es[0].k {
         match self.parse_ident_or_syntax(es[0].span, a) {
           Ok(AtomID::UNDER) => return Err(ElabError::new_e(es[0].span, "'_' is not a function")),
           Ok(x) =>
             Ok(IR::App(e.span, es[0].span,
-              Box::new(self.eval_atom(es[0].span, x)), self.exprs(false, &es[1..])?.into())),
+              Box::new(self.eval_atom(es[0].span, x)), self.args(&es[1..])?.into())),
           Err(stx) => {
             self.spans.insert_if(es[0].span, || ObjectKind::Syntax(stx));
             match stx {
@@ -876,26 +1035,18 @@ impl<'a> LispParser<'a> {
                 }),
               Syntax::Lambda if es.len() < 2 => return Err(
                 ElabError::new_e(es[0].span, "expected at least one argument")),
-              Syntax::Lambda => match &es[1].k {
-                SExprKind::List(xs) => {
-                  let xs = self.parse_idents(xs)?;
-                  Ok(IR::Lambda(es[0].span, self.ctx.push_list(&xs), ProcSpec::Exact(xs.len()),
-                    IR::eval(self.exprs(false, &es[2..])?).into()))
-                }
-                SExprKind::DottedList(xs, y) => {
-                  let xs = self.parse_idents(xs)?;
-                  let y = self.parse_ident(y)?;
-                  let n = self.ctx.push_list(&xs);
-                  self.ctx.push(y);
-                  Ok(IR::Lambda(es[0].span, n, ProcSpec::AtLeast(xs.len()),
-                    IR::eval(self.exprs(false, &es[2..])?).into()))
-                }
-                _ => {
-                  let x = self.parse_ident(&es[1])?;
-                  Ok(IR::Lambda(es[0].span, self.ctx.push(x), ProcSpec::AtLeast(0),
-                    IR::eval(self.exprs(false, &es[2..])?).into()))
-                }
-              },
+              Syntax::Lambda => {
+                let (n, spec, mut code) = match &es[1].k {
+                  SExprKind::List(xs) => self.params(es[0].span, xs, None)?,
+                  SExprKind::DottedList(xs, y) => self.params(es[0].span, xs, Some(y))?,
+                  _ => {
+                    let x = self.parse_ident(&es[1])?;
+                    (self.ctx.push(x), ProcSpec::AtLeast(0), vec![])
+                  }
+                };
+                code.extend(self.exprs(false, &es[2..])?);
+                Ok(IR::Lambda(es[0].span, n, spec, IR::eval(code).into()))
+              }
               Syntax::Quote if es.len() < 2 => return Err(
                 ElabError::new_e(es[0].span, "expected at least one argument")),
               Syntax::Quote => self.expr(true, &es[1]),
@@ -913,6 +1064,21 @@ impl<'a> LispParser<'a> {
               Syntax::If => return Err(
                 ElabError::new_e(es[0].span, "expected two or three arguments")),
               Syntax::Focus => Ok(IR::Focus(es[0].span, self.exprs(false, &es[1..])?.into())),
+              Syntax::Label if es.len() < 2 => return Err(
+                ElabError::new_e(es[0].span, "expected at least one argument")),
+              Syntax::Label => {
+                let x = match &es[1].k {
+                  SExprKind::List(xs) => match &**xs {
+                    [SExpr {span, k: SExprKind::Atom(q)}, SExpr {span: sp, k: SExprKind::Atom(a)}]
+                    if self.ast.span_atom(*span, *q) == b"quote" =>
+                      Some(self.elab.env.get_atom(self.elab.ast.span_atom(*sp, *a))),
+                    _ => None
+                  },
+                  _ => None
+                };
+                let x = x.ok_or_else(|| ElabError::new_e(es[1].span, "expected a quoted atom"))?;
+                Ok(IR::Label(es[0].span, x, IR::eval(self.exprs(false, &es[2..])?).into()))
+              }
               Syntax::Let => self.let_(false, &es[1..]),
               Syntax::Letrec => self.let_(true, &es[1..]),
               Syntax::Match if es.len() < 2 => return Err(
@@ -937,7 +1103,7 @@ impl<'a> LispParser<'a> {
         }
       } else {
         Ok(IR::App(e.span, es[0].span, Box::new(self.expr(false, &es[0])?),
-          self.exprs(false, &es[1..])?.into()))
+          self.args(&es[1..])?.into()))
       },
       &SExprKind::Formula(f) => {let q = self.parse_formula(f)?; self.qexpr(q)}
     };
@@ -54,12 +54,14 @@ impl LispKind {
     match self {
       LispKind::List(es) => es.is_empty(),
       LispKind::DottedList(_, _) |
+      LispKind::Vector(_) |
       LispKind::AtomMap(_) |
       LispKind::Goal(_) => false,
       LispKind::Atom(_) |
       LispKind::MVar(_, _) |
       LispKind::Proc(_) |
       LispKind::Number(_) |
+      LispKind::SmallInt(_) |
       LispKind::String(_) |
       LispKind::Bool(_) |
       LispKind::Syntax(_) |
@@ -265,6 +267,12 @@ impl<'a> Pretty<'a> {
     self.alloc(Doc::Group(doc))
   }
 
+  /// Pretty-prints a math formula without `$` delimiters, in parentheses if its
+  /// precedence is lower than `p`.
+  pub fn expr_prec(&'a self, e: &LispVal, p: Prec) -> RefDoc<'a, ()> {
+    self.expr_paren(e, p).doc
+  }
+
   fn get_thm_args(&'a self, u: &mut Uncons, args: &mut Vec<LispVal>) -> Option<(&'a AtomData, &'a Thm)> {
     let env = self.fe.env;
     let a = u.next()?.as_atom()?;
@@ -95,7 +95,7 @@ fn list(init: &[LispVal], e: Option<&LispKind>, mut start: bool, fe: FormatEnv<'
   }
 }
 
-fn alphanumber(n: usize) -> String {
+pub(crate) fn alphanumber(n: usize) -> String {
   let mut out = Vec::with_capacity(2);
   let mut n = n + 1;
   while n != 0 {
@@ -152,6 +152,7 @@ impl EnvDisplay for LispKind {
       LispKind::List(es) => list(es, None, true, fe, f),
       LispKind::Annot(_, e) => e.fmt(fe, f),
       LispKind::Number(n) => n.fmt(f),
+      LispKind::SmallInt(n) => n.fmt(f),
       LispKind::String(s) => {
         write!(f, "\"")?;
         for &c in &**s {
@@ -185,7 +186,13 @@ impl EnvDisplay for LispKind {
       LispKind::Proc(Proc::MatchCont(_)) => write!(f, "#[match cont]"),
       LispKind::Proc(Proc::RefineCallback) => write!(f, "#[refine]"),
       LispKind::Proc(Proc::ProofThunk(x, _)) => write!(f, "#[proof of {}]", fe.to(x)),
+      LispKind::Proc(Proc::Promise(_)) => write!(f, "#[promise]"),
       LispKind::Proc(Proc::MMCCompiler(_)) => write!(f, "#[mmc-compiler]"),
+      LispKind::Vector(es) => {
+        write!(f, "(vector")?;
+        for e in &**es {write!(f, " {}", fe.to(e))?}
+        write!(f, ")")
+      }
       LispKind::AtomMap(m) => {
         write!(f, "(atom-map!")?;
         for (a, v) in m {write!(f, " [{} {}]", fe.data[*a].name, fe.to(v))?}
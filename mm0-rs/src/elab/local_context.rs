@@ -3,15 +3,18 @@
 
 use std::ops::Deref;
 use std::mem;
+use std::time::Instant;
 use std::result::Result as StdResult;
 use std::collections::{HashMap, hash_map::Entry};
 use itertools::Itertools;
-use super::environment::{AtomID, TermKind, ThmKind, Type as EType};
+use super::environment::{AtomID, Remap, Remapper, TermKind, ThmKind, Type as EType};
 use crate::parser::ast::{Decl, Type, DepType, LocalKind};
 use super::{Coe, DeclKind, DerefMut, DocComment, ElabError, Elaborator, Environment,
   Expr, Modifiers, ObjectKind, Proof, Result, SExprKind, SortID, Term, TermID, Thm};
 use super::lisp::{LispVal, LispKind, Uncons, InferTarget, print::FormatEnv};
-use super::proof::{NodeHasher, ProofHash, build, Dedup};
+use super::spans::Hint;
+use super::proof::{NodeHasher, ProofHash, SizeLimit, build, Dedup};
+use super::parallel::Deferred;
 use crate::util::{Span, FileSpan, BoxError};
 
 /// The infer status of a variable in a declaration. For example in
@@ -19,7 +22,7 @@ use crate::util::{Span, FileSpan, BoxError};
 /// but is known to be bound, `y` is not declared at all but known to be a bound non-dummy,
 /// and `z` is not declared and must be a bound dummy of type `var` (assuming
 /// that `all` has type `var` for its first argument).
-#[derive(Debug, EnvDebug, DeepSizeOf)]
+#[derive(Clone, Debug, EnvDebug, DeepSizeOf)]
 pub enum InferSort {
   /// This is a declared bound variable with the given sort.
   Bound(SortID),
@@ -68,7 +71,7 @@ impl InferSort {
 
 /// The local context is the collection of proof-local data. This is manipulated
 /// by lisp tactics in order to keep track of the proof state and eventually produce a proof.
-#[derive(Default, Debug, EnvDebug, DeepSizeOf)]
+#[derive(Default, Clone, Debug, EnvDebug, DeepSizeOf)]
 pub struct LocalContext {
   /// The collection of local variables. The key is the name of the variable, and the
   /// value is `(dummy, is)` where `dummy` is true if this is a dummy variable
@@ -102,6 +105,10 @@ pub struct LocalContext {
   ///
   /// [`set-close-fn`]: super::lisp::BuiltinProc::SetCloseFn
   pub closer: LispVal,
+  /// The goals closed by [`admit`](super::lisp::BuiltinProc::Admit), with the location
+  /// of the call and the statement of the goal. A theorem whose proof admits a goal is
+  /// added without a proof, and the goals are reported as warnings.
+  pub admits: Vec<(Span, LispVal)>,
 }
 
 fn new_mvar(mvars: &mut Vec<LispVal>, tgt: InferTarget, sp: Option<FileSpan>) -> LispVal {
@@ -112,6 +119,22 @@ fn new_mvar(mvars: &mut Vec<LispVal>, tgt: InferTarget, sp: Option<FileSpan>) ->
   e
 }
 
+impl Remap for LocalContext {
+  type Target = Self;
+  fn remap(&self, r: &mut Remapper) -> Self {
+    LocalContext {
+      vars: self.vars.clone(),
+      var_order: self.var_order.clone(),
+      mvars: self.mvars.remap(r),
+      goals: self.goals.remap(r),
+      proofs: self.proofs.clone(),
+      proof_order: self.proof_order.iter().map(|(a, e, p)| (*a, e.remap(r), p.remap(r))).collect(),
+      closer: self.closer.remap(r),
+      admits: self.admits.iter().map(|(sp, e)| (*sp, e.remap(r))).collect(),
+    }
+  }
+}
+
 impl LocalContext {
   /// Create a new local context.
   #[must_use] pub fn new() -> LocalContext { Default::default() }
@@ -126,6 +149,7 @@ impl LocalContext {
     self.proofs.clear();
     self.proof_order.clear();
     self.closer = LispVal::undef();
+    self.admits.clear();
   }
 
   /// Set the list of goals to `gs`, after filtering the elements that are not
@@ -271,27 +295,6 @@ impl<'a> ElabTerm<'a> {
     ElabError::new_e(self.try_get_span(e), msg)
   }
 
-  fn coerce(&self, src: &LispVal, from: SortID, res: LispVal, tgt: InferTarget) -> Result<LispVal> {
-    let fsp = src.fspan();
-    let res = match &fsp { None => res, Some(fsp) => res.replace_span(fsp.clone()) };
-    let to = match tgt {
-      InferTarget::Unknown => return Ok(res),
-      InferTarget::Provable if self.fe.sorts[from].mods.contains(Modifiers::PROVABLE) => return Ok(res),
-      InferTarget::Provable => *self.fe.pe.coe_prov.get(&from).ok_or_else(||
-        self.err(src, format!("type error: expected provable sort, got {}", self.fe.sorts[from].name)))?,
-      InferTarget::Reg(to) => self.fe.data[to].sort.expect("expected a sort"),
-      InferTarget::Bound(_) => return Err(
-        self.err(src, format!("expected a variable, got {}", self.fe.to(src))))
-    };
-    if from == to {return Ok(res)}
-    if let Some(c) = self.fe.pe.coes.get(&from).and_then(|m| m.get(&to)) {
-      Ok(self.fe.apply_coe(&fsp, c, res))
-    } else {
-      Err(self.err(src,
-        format!("type error: expected {}, got {}", self.fe.sorts[to].name, self.fe.sorts[from].name)))
-    }
-  }
-
   fn infer_sort(&self, e: &LispKind) -> Result<SortID> {
     e.unwrapped(|r| match r {
       &LispKind::Atom(a) => match self.lc.vars.get(&a) {
@@ -328,6 +331,34 @@ impl<'a> ElabTermMut<'a> {
     }
   }
 
+  /// Coerce `res`, the elaboration of `src` with sort `from`, to the target sort,
+  /// inserting a coercion if necessary. Inserted coercions are recorded for inlay hints.
+  fn coerce(&mut self, src: &LispVal, from: SortID, res: LispVal, tgt: InferTarget) -> Result<LispVal> {
+    let fsp = src.fspan();
+    let res = match &fsp { None => res, Some(fsp) => res.replace_span(fsp.clone()) };
+    let to = match tgt {
+      InferTarget::Unknown => return Ok(res),
+      InferTarget::Provable if self.env.sorts[from].mods.contains(Modifiers::PROVABLE) => return Ok(res),
+      InferTarget::Provable => *self.env.pe.coe_prov.get(&from).ok_or_else(||
+        self.as_ref().err(src, format!("type error: expected provable sort, got {}", self.env.sorts[from].name)))?,
+      InferTarget::Reg(to) => self.env.data[to].sort.expect("expected a sort"),
+      InferTarget::Bound(_) => return Err(
+        self.as_ref().err(src, format!("expected a variable, got {}", self.print(src))))
+    };
+    if from == to {return Ok(res)}
+    if let Some(c) = self.env.pe.coes.get(&from).and_then(|m| m.get(&to)).cloned() {
+      if let Some(fsp) = &fsp {
+        if self.fsp.file.ptr_eq(&fsp.file) {
+          self.elab.spans.insert_hint(fsp.span, Hint::Coe(c.clone()))
+        }
+      }
+      Ok(self.env.apply_coe(&fsp, &c, res))
+    } else {
+      Err(self.as_ref().err(src,
+        format!("type error: expected {}, got {}", self.env.sorts[to].name, self.env.sorts[from].name)))
+    }
+  }
+
   fn atom(&mut self, e: &LispVal, a: AtomID, tgt: InferTarget) -> Result<LispVal> {
     let a = if a == AtomID::UNDER {
       let mut n = 1;
@@ -363,7 +394,7 @@ impl<'a> ElabTermMut<'a> {
         Ok(sorts.entry(s).or_insert_with(|| new_mvar(mvars, tgt, Some(sp))).clone())
       }
       (&mut InferSort::Reg(sort, _), tgt) |
-      (&mut InferSort::Bound(sort), tgt) => self.as_ref().coerce(e, sort, LispVal::atom(a), tgt),
+      (&mut InferSort::Bound(sort), tgt) => self.coerce(e, sort, LispVal::atom(a), tgt),
     };
     self.spans_insert(e, || ObjectKind::Var(a));
     res
@@ -400,7 +431,7 @@ impl<'a> ElabTermMut<'a> {
       return Err(ElabError::new_e(sp1,
         format!("expected {} arguments, got {}", nargs, args.len() - 1)))
     }
-    self.as_ref().coerce(e, ret, LispVal::list(args), tgt)
+    self.coerce(e, ret, LispVal::list(args), tgt)
   }
 
   fn other(&mut self, e: &LispVal, tgt: InferTarget) -> Result<LispVal> {
@@ -411,8 +442,8 @@ impl<'a> ElabTermMut<'a> {
     let args = vec![tgt.sort().map_or_else(LispVal::undef, LispVal::atom), e.clone()];
     let sp = self.as_ref().try_get_span(e);
     let res = self.call_func(sp, proc, args)?;
-    let et = self.as_ref();
-    et.coerce(e, et.infer_sort(&res)?, res, tgt)
+    let s = self.as_ref().infer_sort(&res)?;
+    self.coerce(e, s, res, tgt)
   }
 
   // TODO: Unify this with RState::RefineExpr
@@ -579,8 +610,10 @@ impl Elaborator {
       },
       Some(Type::DepType(d)) => InferBinder::Var(x, self.elab_dep_type(error, lk, d)?),
       Some(&Type::Formula(f)) => {
-        let e = self.parse_formula(f)?;
-        let e = self.eval_qexpr(e)?;
+        let start = Instant::now();
+        let e = self.parse_formula(f);
+        self.profile.parse += start.elapsed();
+        let e = self.eval_qexpr(e?)?;
         let e = self.elaborate_term(f.0, &e, InferTarget::Provable)?;
         InferBinder::Hyp(x, e)
       },
@@ -597,6 +630,13 @@ impl Elaborator {
     ElabTerm::new(self, sp).infer_sort(e)
   }
 
+  /// The size limit for the statement or definition (or the proof, if `proof` is true)
+  /// of the declaration `decl`, if there is one. (See `set-size-limit`.)
+  fn size_limit(&self, decl: AtomID, proof: bool) -> Option<SizeLimit> {
+    let max = if proof {self.proof_size_limit} else {self.term_size_limit};
+    if max == usize::MAX {None} else {Some(SizeLimit {max, decl, proof})}
+  }
+
   fn finalize_vars(&mut self, dummy: bool) -> Vec<ElabError> {
     let mut errs = Vec::new();
     let mut newvars = Vec::new();
@@ -631,6 +671,7 @@ impl Elaborator {
                 m.get_mut(|e| *e = val);
               } else {unreachable!()}
             }
+            self.spans.insert_hint(src, Hint::Sort(sort));
             let new2 = if (dummy && *new) || must_bound {
               *is = InferSort::Bound(sort);
               dummy && d2
@@ -683,6 +724,15 @@ impl Elaborator {
     if self.mm0_mode && atom == AtomID::UNDER {
       self.report(ElabError::warn(d.id, "(MM0 mode) declaration name required"))
     }
+    if atom != AtomID::UNDER {
+      let kind = match d.k {
+        DeclKind::Term => AtomID::TERM,
+        DeclKind::Def => AtomID::DEF,
+        DeclKind::Axiom => AtomID::AXIOM,
+        DeclKind::Thm => AtomID::THM,
+      };
+      self.check_name_policy(d.id, kind, atom)
+    }
     match d.k {
       DeclKind::Term | DeclKind::Def => {
         for (bi, _, _) in e_hyps {report!(bi.span, "term/def declarations have no hypotheses")}
@@ -742,7 +792,8 @@ impl Elaborator {
             let deps = ba.expr_deps(&self.env, &val);
             let val = {
               let mut de = Dedup::new(&args);
-              let nh = NodeHasher::new(&self.lc, self.format_env(), self.fspan(sp));
+              let nh = NodeHasher::new(&self.lc, self.format_env(), self.fspan(sp))
+                .with_limit(self.size_limit(atom, false));
               let i = de.dedup(&nh, &val)?;
               let (mut ids, heap) = build(&de);
               Expr {heap, head: ids[i].take()}
@@ -791,8 +842,10 @@ impl Elaborator {
           None => return Err(ElabError::new_e(full, "return type required")),
           Some(Type::DepType(ty)) => return Err(ElabError::new_e(ty.sort, "expression expected")),
           &Some(Type::Formula(f)) => {
-            let e = self.parse_formula(f)?;
-            let e = self.eval_qexpr(e)?;
+            let start = Instant::now();
+            let e = self.parse_formula(f);
+            self.profile.parse += start.elapsed();
+            let e = self.eval_qexpr(e?)?;
             self.elaborate_term(f.0, &e, InferTarget::Provable)?
           }
         };
@@ -817,7 +870,8 @@ impl Elaborator {
         }
         let mut de = Dedup::new(&args);
         let span = self.fspan(d.id);
-        let nh = NodeHasher::new(&self.lc, self.format_env(), span.clone());
+        let nh = NodeHasher::new(&self.lc, self.format_env(), span.clone())
+          .with_limit(self.size_limit(atom, false));
         let mut is = Vec::new();
         for &(bi, a, ref e) in &e_hyps {
           if a.map_or(false, |a| self.lc.vars.contains_key(&a)) {
@@ -830,11 +884,16 @@ impl Elaborator {
         let (mut ids, heap) = build(&de);
         let hyps = is.iter().map(|&(a, i)| (a, ids[i].take())).collect();
         let ret = ids[ir].take();
+        let mut admitted = false;
+        // The proof is checked later, in parallel (see `parallel`)
+        let defer = self.defer_proofs && self.check_proofs && atom != AtomID::UNDER;
         let kind = match &d.val {
           None => ThmKind::Axiom,
           Some(e) => ThmKind::Thm({
-            if self.check_proofs {
+            if self.check_proofs && !defer {
               (|| -> Result<Option<Proof>> {
+                // The errors in the statement were already reported by the main elaborator
+                if self.proof_worker.is_some() { self.errors.clear() }
                 let mut de: Dedup<ProofHash> = de.map_proof();
                 let mut is2 = Vec::new();
                 for (i, (_, a, e)) in e_hyps.into_iter().enumerate() {
@@ -847,25 +906,42 @@ impl Elaborator {
                 let g = LispVal::new_ref(LispVal::goal(self.fspan(e.span), e_ret));
                 self.lc.goals = vec![g.clone()];
                 self.elab_lisp(e)?;
-                for g in mem::take(&mut self.lc.goals) {
+                // The unsolved goals are left in the local context, so that they are
+                // available to the server (for completion) after elaboration.
+                for g in self.lc.goals.clone() {
                   report!(try_get_span(&span, &g),
                     format!("|- {}", self.format_env().pp(&g.goal_type().expect("expected a goal"), 80)))
                 }
-                if error {return Ok(None)}
-                let nh = NodeHasher {var_map, fsp, fe: self.format_env(), lc: &self.lc};
+                admitted = self.report_admits(None);
+                if error || admitted {return Ok(None)}
+                let start = Instant::now();
+                let nh = NodeHasher {var_map, fsp, fe: self.format_env(), lc: &self.lc,
+                  limit: self.size_limit(atom, true)};
                 let ip = de.dedup(&nh, &g)?;
                 let (mut ids, heap) = build(&de);
+                self.profile.check += start.elapsed();
                 let hyps = is2.into_iter().map(|i| ids[i].take()).collect();
                 Ok(Some(Proof {heap, hyps, head: ids[ip].take()}))
               })().unwrap_or_else(|e| {self.report(e); None})
             } else {None}
           })
         };
+        if let Some(tid) = self.proof_worker {
+          self.env.thms[tid].kind = kind;
+          if admitted { self.env.admitted.push(tid) }
+          return Ok(())
+        }
         if atom != AtomID::UNDER {
           let tid = self.env.add_thm(Thm {
             atom, span, vis: d.mods, full, doc,
             args: args.into(), heap, hyps, ret, kind
           }).map_err(|e| e.into_elab_error(d.id))?;
+          if admitted { self.env.admitted.push(tid) }
+          if defer && d.val.is_some() {
+            self.deferred.push(Deferred {
+              thm: tid, full, errors: self.errors.len(), nterms: self.env.terms.len()
+            })
+          }
           self.spans.insert(d.id, ObjectKind::Thm(tid));
         }
       }
@@ -875,6 +951,71 @@ impl Elaborator {
   }
 }
 
+impl Elaborator {
+  /// Check the name `x` of a declaration of kind `kind` (one of `'term`, `'def`,
+  /// `'axiom`, `'theorem`) against the user's naming policy, by calling
+  /// `(name-policy kind x)` if `name-policy` is defined. The result is interpreted as:
+  ///
+  /// * `#t` or `#undef`: the name is acceptable
+  /// * `#f`: the name is not acceptable, with a generic warning
+  /// * a string: the name is not acceptable, and the string is the warning message
+  /// * an atom `y`: the name is not acceptable, and `y` is the suggested name
+  ///   (if `y` is `x` then the name is acceptable)
+  ///
+  /// Returns `None` if the name is acceptable, and otherwise the warning message and
+  /// the suggested name, if any.
+  fn name_policy(&mut self, sp: Span, kind: AtomID, x: AtomID) -> Result<Option<(String, Option<AtomID>)>> {
+    let policy = match &self.data[AtomID::NAME_POLICY].lisp {
+      Some(e) => e.val.clone(),
+      None => return Ok(None)
+    };
+    let args = vec![LispVal::atom(kind), LispVal::atom(x)];
+    let res = self.call_func(sp, policy, args)?;
+    Ok(res.unwrapped(|r| match *r {
+      LispKind::Bool(true) | LispKind::Undef => None,
+      LispKind::Bool(false) => Some((format!("'{}' does not follow the naming convention",
+        self.data[x].name), None)),
+      LispKind::String(ref s) => Some((s.to_string(), None)),
+      LispKind::Atom(y) if x == y => None,
+      LispKind::Atom(y) => Some((format!("'{}' does not follow the naming convention, \
+        expected '{}'", self.data[x].name, self.data[y].name), Some(y))),
+      _ => Some((format!("name-policy: expected a boolean, string or atom, got {}",
+        self.print(r)), None))
+    }))
+  }
+
+  /// Check the name `x` of a declaration of kind `kind` against the user's naming policy
+  /// (see [`name_policy`](Self::name_policy)), and report a warning if it is not acceptable.
+  pub fn check_name_policy(&mut self, sp: Span, kind: AtomID, x: AtomID) {
+    match self.name_policy(sp, kind, x) {
+      Ok(None) => {}
+      Ok(Some((msg, _))) => self.report(ElabError::warn(sp, msg)),
+      Err(e) => self.report(e)
+    }
+  }
+
+  /// Returns the first atom among `x`, `x_1`, `x_2`, ... that is not already the name of
+  /// a declaration and is accepted by the naming policy for declarations of kind `kind`.
+  /// If the policy suggests another name `y`, the search starts over from `y`; it is an
+  /// error if the policy rejects a name without a suggestion, or rejects its own suggestion.
+  pub fn fresh_name(&mut self, sp: Span, kind: AtomID, mut x: AtomID) -> Result<AtomID> {
+    let mut suggested = false;
+    loop {
+      let base = self.data[x].name.clone();
+      let (mut a, mut n) = (x, 0);
+      while self.data[a].decl.is_some() {
+        n += 1;
+        a = self.get_atom(format!("{}_{}", base, n).as_bytes());
+      }
+      match self.name_policy(sp, kind, a)? {
+        None => return Ok(a),
+        Some((_, Some(y))) if !mem::replace(&mut suggested, true) => x = y,
+        Some((msg, _)) => return Err(ElabError::new_e(sp, msg))
+      }
+    }
+  }
+}
+
 /// This is a temporary structure returned by [`add_thm`](Elaborator::add_thm)
 /// which implements the `(add-thm! x bis hyps ret vis vtask)` user-level function,
 /// when `vtask` is a lambda instead of a direct proof. In this case, we have to
@@ -1019,6 +1160,7 @@ impl Elaborator {
       return Err(ElabError::new_e(fsp.span,
         format!("duplicate term/def declaration '{}'", self.print(&x))))
     }
+    self.check_name_policy(span.span, if val.is_some() {AtomID::DEF} else {AtomID::TERM}, x);
     let mut vars = (HashMap::new(), 1);
     let (mut lc, args) = self.binders(fsp, Uncons::from(args.clone()), &mut vars)?;
     let ret = if let Some(s) = ret.as_atom() {
@@ -1044,7 +1186,8 @@ impl Elaborator {
       (vis, TermKind::Def((|| -> Result<Option<Expr>> {
         dummies(self.format_env(), fsp, &mut lc, ds)?;
         let mut de = Dedup::new(&args);
-        let nh = NodeHasher::new(&lc, self.format_env(), fsp.clone());
+        let nh = NodeHasher::new(&lc, self.format_env(), fsp.clone())
+          .with_limit(self.size_limit(x, false));
         let i = de.dedup(&nh, val)?;
         let (mut ids, heap) = build(&de);
         Ok(Some(Expr {heap, head: ids[i].take()}))
@@ -1080,6 +1223,7 @@ impl Elaborator {
       return Err(ElabError::new_e(fsp.span,
         format!("duplicate axiom/theorem declaration '{}'", self.print(&x))))
     }
+    self.check_name_policy(span.span, if proof.is_some() {AtomID::THM} else {AtomID::AXIOM}, x);
     let mut vars = (HashMap::new(), 1);
     let (mut lc, args) = self.binders(&fsp, Uncons::from(args.clone()), &mut vars)?;
     let mut is = Vec::new();
@@ -1103,7 +1247,8 @@ impl Elaborator {
     if let Some(e) = self.finalize_vars(true).into_iter().next() { return Err(e) }
     // crate::server::log(format!("{}: {:#?}", self.print(&x), lc));
     let mut de = Dedup::new(&args);
-    let nh = NodeHasher::new(&lc, self.format_env(), fsp.clone());
+    let nh = NodeHasher::new(&lc, self.format_env(), fsp.clone())
+      .with_limit(self.size_limit(x, false));
     // crate::server::log(format!("{}: {:#?}", self.print(&x), nh.var_map));
     let is = is.into_iter().map(|(a, ty)| {
       Ok((a, de.dedup(&nh, &ty)?, ty))
@@ -1147,10 +1292,15 @@ impl Elaborator {
   #[allow(clippy::option_option)]
   fn finish_add_thm(&mut self, fsp: &FileSpan, mut t: Thm, res: Option<Option<ThmVal>>) -> Result<()> {
     macro_rules! sp {($e:expr) => {$e.fspan().unwrap_or(fsp.clone()).span}}
+    let mut admitted = false;
     t.kind = match res {
       None => ThmKind::Axiom,
       Some(res) => ThmKind::Thm(res.and_then(|ThmVal {mut de, var_map, mut lc, is: is2, proof: e}| {
-        (|| -> Result<Option<Proof>> {
+        admitted = self.report_admits(lc.as_deref_mut());
+        if admitted { return None }
+        let start = Instant::now();
+        let limit = self.size_limit(t.atom, true);
+        let res = (|| -> Result<Option<Proof>> {
           let mut u = Uncons::from(e.clone());
           let (ds, pf) = match (u.next(), u.next(), u.exactly(0)) {
             (Some(ds), Some(pf), true) => (ds, pf),
@@ -1159,12 +1309,14 @@ impl Elaborator {
           let lc = lc.as_deref_mut().unwrap_or(&mut self.lc);
           let fe = FormatEnv {source: &self.ast.source, env: &self.env};
           dummies(fe, fsp, lc, &ds)?;
-          let nh = NodeHasher {var_map, lc, fe, fsp: fsp.clone()};
+          let nh = NodeHasher {var_map, lc, fe, fsp: fsp.clone(), limit};
           let ip = de.dedup(&nh, &pf)?;
           let (mut ids, heap) = build(&de);
           let hyps = is2.into_iter().map(|i| ids[i].take()).collect();
           Ok(Some(Proof {heap, hyps, head: ids[ip].take()}))
-        })().unwrap_or_else(|e| {
+        })();
+        self.profile.check += start.elapsed();
+        res.unwrap_or_else(|e| {
           self.report(ElabError::new_e(e.pos,
             format!("while adding {}: {}", self.print(&t.atom), e.kind.msg())));
           None
@@ -1172,7 +1324,21 @@ impl Elaborator {
       }))
     };
     let sp = fsp.span;
-    self.env.add_thm(t).map_err(|e| e.into_elab_error(sp))?;
+    let tid = self.env.add_thm(t).map_err(|e| e.into_elab_error(sp))?;
+    if admitted { self.env.admitted.push(tid) }
     Ok(())
   }
+
+  /// Report the goals admitted in the proof of a theorem as warnings, and clear them.
+  /// `lc` is the local context of the proof, if it is not the current one.
+  /// Returns true if there were any, in which case the theorem should be added
+  /// without a proof.
+  fn report_admits(&mut self, lc: Option<&mut LocalContext>) -> bool {
+    let admits = mem::take(&mut lc.unwrap_or(&mut self.lc).admits);
+    for (sp, tgt) in &admits {
+      self.report(ElabError::warn(*sp,
+        format!("admitted goal: |- {}", self.format_env().pp(tgt, 80))))
+    }
+    !admits.is_empty()
+  }
 }
\ No newline at end of file
@@ -7,6 +7,7 @@
 //!
 //! [`mm0.md`]: https://github.com/digama0/mm0/blob/master/mm0.md#secondary-parsing
 
+use std::borrow::Cow;
 use std::ops::{Deref, DerefMut};
 use std::mem;
 use std::fmt::{self, Display};
@@ -15,6 +16,7 @@ use crate::elab::{Elaborator, ElabError, ObjectKind};
 use crate::elab::ast::{Formula, SExpr};
 use crate::elab::lisp::print::{EnvDisplay, FormatEnv};
 use crate::elab::spans::Spans;
+use unicode_normalization::{UnicodeNormalization, is_nfc};
 use crate::util::{SliceUninit, Span};
 use crate::elab::environment::{Literal, ParserEnv, Prec, TermID};
 
@@ -75,26 +77,96 @@ impl EnvDisplay for QExpr {
 impl Elaborator {
   /// Parse a [`Formula`] object into a [`QExpr`].
   pub fn parse_formula(&mut self, f: Formula) -> Result<QExpr, ElabError> {
-    let mut p = MathParser {
-      pe: &self.env.pe,
-      p: Parser {
-        source: self.ast.source.as_bytes(),
-        errors: vec![],
-        imports: vec![],
-        idx: f.0.start + 1,
-        restart_pos: Some(0), // skip command checks
-      },
-      spans: &mut self.spans,
-    };
-    p.ws();
-    let expr = p.expr(Prec::Prec(0))?;
-    if let Some(tk) = p.token() {
-      return Err(ElabError::new_e(tk, "expected '$'"))
-    }
-    assert!(p.imports.is_empty());
+    let normalize = self.normalize_tokens && !self.mm0_mode;
+    let mut p = MathParser::new(&self.env.pe, self.ast.source.as_bytes(), f, &mut self.spans, normalize);
+    let expr = p.formula()?;
     for e in p.p.errors { self.report(e.into()) }
+    if self.warn_precedence {
+      let mut warnings = vec![];
+      self.check_precedence(&expr, &mut warnings);
+      for (sp, msg) in warnings { self.report(ElabError::warn(sp, msg)) }
+    }
     Ok(expr)
   }
+
+  /// If `e` is an application of an infix notation, without parentheses around it,
+  /// returns the operator token and its precedence.
+  fn infix_prec(&self, e: &QExpr) -> Option<(&[u8], u32)> {
+    if let QExprKind::App(tk, _, es) = &e.k {
+      if es.first()?.span.start != e.span.start {return None}
+      let s = self.ast.span(*tk);
+      self.pe.infixes.get(s)?;
+      if let Some(&(_, Prec::Prec(p))) = self.pe.consts.get(s) { return Some((s, p)) }
+    }
+    None
+  }
+
+  /// Find the infix applications in `e` whose parse depends on a precedence difference of 1,
+  /// for `(warn-precedence #t)`. For example, if `+` has precedence 65 and `*` has precedence
+  /// 66, then `a + b * c` parses as `a + (b * c)`, but it would parse as `(a + b) * c` if
+  /// either precedence changed by 1, so we suggest writing the parentheses explicitly.
+  fn check_precedence(&self, e: &QExpr, out: &mut Vec<(Span, String)>) {
+    let es = match &e.k {
+      QExprKind::App(_, _, es) | QExprKind::IdentApp(_, es) => es,
+      QExprKind::Unquote(_) => return,
+    };
+    let parent = self.infix_prec(e);
+    for c in &**es {
+      if let (Some((op, p)), Some((op2, p2))) = (parent, self.infix_prec(c)) {
+        if p.max(p2) - p.min(p2) == 1 {
+          out.push((c.span, format!(
+            "the parse of this expression depends on the precedences of '{}' ({}) and '{}' ({}), \
+            which differ by only 1; consider adding parentheses: ({})",
+            String::from_utf8_lossy(op2), p2, String::from_utf8_lossy(op), p,
+            String::from_utf8_lossy(self.ast.span(c.span)))))
+        }
+      }
+      self.check_precedence(c, out)
+    }
+  }
+}
+
+/// Parse a [`Formula`] object into a [`QExpr`], using the notations in `pe`.
+/// Unlike [`Elaborator::parse_formula`], this does not require an elaboration context,
+/// so it can be used to re-parse the formulas of a file after it has been elaborated.
+/// Any non-fatal parse errors are appended to `errors`.
+pub fn parse_formula(pe: &ParserEnv, source: &[u8], f: Formula,
+    errors: &mut Vec<ParseError>) -> Result<QExpr, ParseError> {
+  let mut spans = Spans::new();
+  let mut p = MathParser::new(pe, source, f, &mut spans, true);
+  let expr = p.formula()?;
+  errors.append(&mut p.p.errors);
+  Ok(expr)
+}
+
+/// Returns the NFC normal form of the token `tk`, or `None` if it is already normalized
+/// (which is always the case for ASCII tokens) or is not valid UTF-8.
+#[must_use] pub fn normalize_token(tk: &[u8]) -> Option<String> {
+  if tk.is_ascii() { return None }
+  let s = std::str::from_utf8(tk).ok()?;
+  if is_nfc(s) { None } else { Some(s.nfc().collect()) }
+}
+
+/// The script of a letter, for the letters that are common sources of homoglyphs
+/// (like Latin `a` and Cyrillic `а`).
+fn script(c: char) -> Option<&'static str> {
+  match c {
+    'A'..='Z' | 'a'..='z' | '\u{c0}'..='\u{d6}' | '\u{d8}'..='\u{f6}' |
+    '\u{f8}'..='\u{24f}' | '\u{1e00}'..='\u{1eff}' => Some("Latin"),
+    '\u{370}'..='\u{3ff}' | '\u{1f00}'..='\u{1fff}' => Some("Greek"),
+    '\u{400}'..='\u{52f}' => Some("Cyrillic"),
+    _ => None
+  }
+}
+
+/// If the token `tk` contains letters from two different scripts (Latin, Greek or Cyrillic),
+/// returns the first two scripts found. Such tokens are usually a mistake, since they can
+/// look identical to a token using only one script.
+#[must_use] pub fn mixed_scripts(tk: &[u8]) -> Option<(&'static str, &'static str)> {
+  if tk.is_ascii() { return None }
+  let mut it = std::str::from_utf8(tk).ok()?.chars().filter_map(script);
+  let a = it.next()?;
+  Some((a, it.find(|&b| b != a)?))
 }
 
 /// The precedence of application, `1024`. This determines whether
@@ -106,6 +178,9 @@ struct MathParser<'a> {
   p: Parser<'a>,
   pe: &'a ParserEnv,
   spans: &'a mut Spans<ObjectKind>,
+  /// True if tokens should be looked up in NFC normal form
+  /// if they are not found as written.
+  normalize: bool,
 }
 impl<'a> Deref for MathParser<'a> {
   type Target = Parser<'a>;
@@ -116,6 +191,32 @@ impl<'a> DerefMut for MathParser<'a> {
 }
 
 impl<'a> MathParser<'a> {
+  fn new(pe: &'a ParserEnv, source: &'a [u8], f: Formula,
+    spans: &'a mut Spans<ObjectKind>, normalize: bool) -> Self {
+    MathParser {
+      pe,
+      normalize,
+      p: Parser {
+        source,
+        errors: vec![],
+        imports: vec![],
+        idx: f.0.start + 1,
+        restart_pos: Some(0), // skip command checks
+      },
+      spans,
+    }
+  }
+
+  fn formula(&mut self) -> Result<QExpr, ParseError> {
+    self.ws();
+    let expr = self.expr(Prec::Prec(0))?;
+    if let Some(tk) = self.token() {
+      return Err(ParseError::new(tk, "expected '$'".into()))
+    }
+    assert!(self.imports.is_empty());
+    Ok(expr)
+  }
+
   fn ws(&mut self) {
     loop {
       match self.cur() {
@@ -144,6 +245,16 @@ impl<'a> MathParser<'a> {
     }
   }
 
+  /// The text of the token at `sp`. If the token is not a declared constant and
+  /// normalization is enabled, this returns its NFC normal form instead.
+  fn tok(&self, sp: Span) -> Cow<'a, [u8]> {
+    let s = self.span(sp);
+    if self.normalize && !self.pe.consts.contains_key(s) {
+      if let Some(t) = normalize_token(s) { return Cow::Owned(t.into_bytes()) }
+    }
+    Cow::Borrowed(s)
+  }
+
   fn peek_token(&mut self) -> (Option<Span>, usize) {
     let start = self.idx;
     let tk = self.token();
@@ -161,7 +272,7 @@ impl<'a> MathParser<'a> {
         },
         Literal::Const(ref c) => {
           let tk = self.token().ok_or_else(|| self.err(format!("expecting '{}'", c).into()))?;
-          if *self.span(tk) != **c {
+          if *self.tok(tk) != **c {
             return Err(ParseError::new(tk, format!("expecting '{}'", c).into()))
           }
           consts.push(tk);
@@ -193,10 +304,10 @@ impl<'a> MathParser<'a> {
       c => c
     };
     let sp = self.token().ok_or_else(|| self.err("expecting expression".into()))?;
-    let v = self.span(sp);
-    if let Some(&(_, q)) = self.pe.consts.get(v) {
+    let v = self.tok(sp);
+    if let Some(&(_, q)) = self.pe.consts.get(&*v) {
       if q >= p {
-        if let Some(info) = self.pe.prefixes.get(v) {
+        if let Some(info) = self.pe.prefixes.get(&*v) {
           let mut args = SliceUninit::new(info.nargs);
           let mut consts = vec![sp];
           let end = self.literals(&mut args, &info.lits, &mut consts, sp.end)?;
@@ -222,16 +333,42 @@ impl<'a> MathParser<'a> {
       self.idx = start;
       return Ok(QExpr {span, k: QExprKind::IdentApp(sp, args.into_boxed_slice())})
     } else {}
-    Err(ParseError::new(sp, format!("expecting prefix expression >= {}", p).into()))
+    let mut msg = format!("expecting prefix expression >= {}", p);
+    if let Some(help) = self.suggest_delimiters(sp) { msg += &help }
+    Err(ParseError::new(sp, msg.into()))
+  }
+
+  /// Look for single character notations embedded in the token at `tk`, which
+  /// were not split off because they are not declared as delimiters.
+  /// For example, with `notation add (a b) = a ($+$:20) b` and no delimiter
+  /// declaration for `+`, the string `$ x+y $` is a single token `x+y`.
+  /// Returns a help message with the suggested `delimiter` command, if any.
+  fn suggest_delimiters(&self, tk: Span) -> Option<String> {
+    let s = self.span(tk);
+    if s.len() <= 1 {return None}
+    let mut found = vec![];
+    for &c in s {
+      if !self.pe.delims_l.get(c) && !self.pe.delims_r.get(c) &&
+        self.pe.consts.contains_key(&[c][..]) && !found.contains(&c) {
+        found.push(c)
+      }
+    }
+    if found.is_empty() {return None}
+    let found = found.into_iter().map(|c| char::from(c).to_string()).collect::<Vec<_>>();
+    Some(format!("\n\nhelp: the token '{}' contains the notation{} '{}', \
+        which is not a delimiter. Either put spaces around it, \
+        or declare it as a delimiter:\n\n  delimiter $ {} $;",
+      String::from_utf8_lossy(s), if found.len() > 1 {"s"} else {""},
+      found.join("', '"), found.join(" ")))
   }
 
   fn lhs(&mut self, p: Prec, mut lhs: QExpr) -> Result<QExpr, ParseError> {
     let mut tok_end = self.peek_token();
     while let Some(tk) = tok_end.0 {
-      let s = self.span(tk);
-      let p1 = if let Some(&(_, q)) = self.pe.consts.get(s) {q} else {break};
+      let s = self.tok(tk);
+      let p1 = if let Some(&(_, q)) = self.pe.consts.get(&*s) {q} else {break};
       if p1 < p {break}
-      let info = if let Some(i) = self.pe.infixes.get(s) {i} else {break};
+      let info = if let Some(i) = self.pe.infixes.get(&*s) {i} else {break};
       self.idx = tok_end.1;
       let mut args = SliceUninit::new(info.nargs);
       let start = lhs.span.start;
@@ -246,9 +383,9 @@ impl<'a> MathParser<'a> {
         let mut rhs = self.prefix(q)?;
         loop {
           tok_end = self.peek_token();
-          let s = if let Some(tk) = tok_end.0 {self.span(tk)} else {break};
-          let info2 = if let Some(i) = self.pe.infixes.get(s) {i} else {break};
-          let q = self.pe.consts[s].1;
+          let s = if let Some(tk) = tok_end.0 {self.tok(tk)} else {break};
+          let info2 = if let Some(i) = self.pe.infixes.get(&*s) {i} else {break};
+          let q = self.pe.consts[&*s].1;
           let assoc = info2.rassoc.expect("infix with no associativity");
           if !(if assoc {q >= p1} else {q > p1}) {break}
           rhs = self.lhs(q, rhs)?;
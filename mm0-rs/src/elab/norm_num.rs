@@ -0,0 +1,526 @@
+//! The `(norm-num)` tactic, which decides goals about numeral arithmetic.
+//!
+//! The tactic works with binary numerals built from four term constructors, registered
+//! with `register-norm-num`: `zero`, `one`, `bit0 a` (meaning `2a`) and `bit1 a` (meaning
+//! `2a + 1`). A numeral is in normal form if it is `zero`, `one`, or `bit0 a` or `bit1 a`
+//! with `a` a normal numeral other than `zero`, so that every number has exactly one normal
+//! form. Expressions are built from numerals using `add`, `mul` and optionally `suc`, and
+//! goals are `eq a b`, or optionally `lt a b`, `le a b` or `ne a b`.
+//!
+//! The tactic evaluates both sides of the goal, and produces a proof using a fixed set of
+//! lemmas, also registered with `register-norm-num`, which are listed in [`NumRule`].
+//! The proofs follow the binary representation, so they are logarithmic in the size of the
+//! numbers involved, unlike proofs by repeated application of successor lemmas.
+
+use std::collections::HashMap;
+use num::{BigInt, Integer, One, Zero};
+use super::{Elaborator, environment::{AtomID, DeclKey, Environment, ExprNode,
+  Remap, Remapper, TermID, Thm, ThmID}};
+use super::cong::{deref, as_var};
+use super::lisp::{LispKind, LispVal};
+
+/// The role of a term constructor registered with `register-norm-num`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum NumTerm {
+  /// `zero`, the numeral 0
+  Zero,
+  /// `one`, the numeral 1
+  One,
+  /// `bit0 a`, the numeral `2a`
+  Bit0,
+  /// `bit1 a`, the numeral `2a + 1`
+  Bit1,
+  /// `add a b`, addition
+  Add,
+  /// `mul a b`, multiplication
+  Mul,
+  /// `eq a b`, equality
+  Eq,
+  /// `suc a`, the successor (optional)
+  Suc,
+  /// `lt a b`, strict order (optional)
+  Lt,
+  /// `le a b`, nonstrict order (optional)
+  Le,
+  /// `ne a b`, disequality (optional)
+  Ne,
+}
+crate::deep_size_0!(NumTerm);
+
+impl NumTerm {
+  pub(crate) const ALL: [NumTerm; 11] = [NumTerm::Zero, NumTerm::One, NumTerm::Bit0, NumTerm::Bit1,
+    NumTerm::Add, NumTerm::Mul, NumTerm::Eq, NumTerm::Suc, NumTerm::Lt, NumTerm::Le, NumTerm::Ne];
+
+  /// The name of the role, as used in `register-norm-num`.
+  #[must_use] pub fn name(self) -> &'static str {
+    match self {
+      NumTerm::Zero => "zero",
+      NumTerm::One => "one",
+      NumTerm::Bit0 => "bit0",
+      NumTerm::Bit1 => "bit1",
+      NumTerm::Add => "add",
+      NumTerm::Mul => "mul",
+      NumTerm::Eq => "eq",
+      NumTerm::Suc => "suc",
+      NumTerm::Lt => "lt",
+      NumTerm::Le => "le",
+      NumTerm::Ne => "ne",
+    }
+  }
+
+  /// The number of arguments of a term with this role.
+  fn arity(self) -> usize {
+    match self {
+      NumTerm::Zero | NumTerm::One => 0,
+      NumTerm::Bit0 | NumTerm::Bit1 | NumTerm::Suc => 1,
+      _ => 2,
+    }
+  }
+}
+
+/// The role of a lemma registered with `register-norm-num`. The statement each lemma must
+/// have is given by [`template`](Self::template), in terms of the registered term constructors.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum NumRule {
+  /// `eq-refl: a = a`
+  EqRefl,
+  /// `eq-norm: a = c -> b = c -> a = b`
+  EqNorm,
+  /// `add-0x: 0 + a = a`
+  Add0x,
+  /// `add-x0: a + 0 = a`
+  Addx0,
+  /// `add-11: 1 + 1 = bit0 1`
+  Add11,
+  /// `add-1b0: 1 + bit0 a = bit1 a`
+  Add1b0,
+  /// `add-1b1: a + 1 = b -> 1 + bit1 a = bit0 b`
+  Add1b1,
+  /// `add-b01: bit0 a + 1 = bit1 a`
+  Addb01,
+  /// `add-b11: a + 1 = b -> bit1 a + 1 = bit0 b`
+  Addb11,
+  /// `add-b0b0: a + b = c -> bit0 a + bit0 b = bit0 c`
+  Addb0b0,
+  /// `add-b0b1: a + b = c -> bit0 a + bit1 b = bit1 c`
+  Addb0b1,
+  /// `add-b1b0: a + b = c -> bit1 a + bit0 b = bit1 c`
+  Addb1b0,
+  /// `add-b1b1: a + b = c -> c + 1 = d -> bit1 a + bit1 b = bit0 d`
+  Addb1b1,
+  /// `mul-0x: 0 * a = 0`
+  Mul0x,
+  /// `mul-x0: a * 0 = 0`
+  Mulx0,
+  /// `mul-1x: 1 * a = a`
+  Mul1x,
+  /// `mul-x1: a * 1 = a`
+  Mulx1,
+  /// `mul-b0x: a * b = c -> bit0 a * b = bit0 c`
+  Mulb0x,
+  /// `mul-b1x: a * b = c -> bit0 c + b = d -> bit1 a * b = d`
+  Mulb1x,
+  /// `add-cong: a = c -> b = d -> c + d = e -> a + b = e`
+  AddCong,
+  /// `mul-cong: a = c -> b = d -> c * d = e -> a * b = e`
+  MulCong,
+  /// `suc-norm: a = b -> b + 1 = c -> suc a = c` (optional)
+  SucNorm,
+  /// `lt-intro: b + 1 = d -> a + d = c -> a < c` (optional)
+  LtIntro,
+  /// `le-intro: a + b = c -> a <= c` (optional)
+  LeIntro,
+  /// `ne-lt: a < b -> a != b` (optional)
+  NeLt,
+  /// `ne-gt: b < a -> a != b` (optional)
+  NeGt,
+}
+crate::deep_size_0!(NumRule);
+
+impl NumRule {
+  pub(crate) const ALL: [NumRule; 26] = [NumRule::EqRefl, NumRule::EqNorm,
+    NumRule::Add0x, NumRule::Addx0, NumRule::Add11, NumRule::Add1b0, NumRule::Add1b1,
+    NumRule::Addb01, NumRule::Addb11, NumRule::Addb0b0, NumRule::Addb0b1, NumRule::Addb1b0,
+    NumRule::Addb1b1, NumRule::Mul0x, NumRule::Mulx0, NumRule::Mul1x, NumRule::Mulx1,
+    NumRule::Mulb0x, NumRule::Mulb1x, NumRule::AddCong, NumRule::MulCong, NumRule::SucNorm,
+    NumRule::LtIntro, NumRule::LeIntro, NumRule::NeLt, NumRule::NeGt];
+
+  /// The name of the role, as used in `register-norm-num`.
+  #[must_use] pub fn name(self) -> &'static str { self.template().0 }
+
+  /// The name, hypotheses and conclusion of the lemma. The variables are the letters
+  /// `a` to `e`, and may be used in any order by the registered theorem, but the
+  /// hypotheses must come in this order.
+  fn template(self) -> (&'static str, &'static [&'static str], &'static str) {
+    match self {
+      NumRule::EqRefl => ("eq-refl", &[], "(eq a a)"),
+      NumRule::EqNorm => ("eq-norm", &["(eq a c)", "(eq b c)"], "(eq a b)"),
+      NumRule::Add0x => ("add-0x", &[], "(eq (add zero a) a)"),
+      NumRule::Addx0 => ("add-x0", &[], "(eq (add a zero) a)"),
+      NumRule::Add11 => ("add-11", &[], "(eq (add one one) (bit0 one))"),
+      NumRule::Add1b0 => ("add-1b0", &[], "(eq (add one (bit0 a)) (bit1 a))"),
+      NumRule::Add1b1 => ("add-1b1", &["(eq (add a one) b)"], "(eq (add one (bit1 a)) (bit0 b))"),
+      NumRule::Addb01 => ("add-b01", &[], "(eq (add (bit0 a) one) (bit1 a))"),
+      NumRule::Addb11 => ("add-b11", &["(eq (add a one) b)"], "(eq (add (bit1 a) one) (bit0 b))"),
+      NumRule::Addb0b0 => ("add-b0b0", &["(eq (add a b) c)"], "(eq (add (bit0 a) (bit0 b)) (bit0 c))"),
+      NumRule::Addb0b1 => ("add-b0b1", &["(eq (add a b) c)"], "(eq (add (bit0 a) (bit1 b)) (bit1 c))"),
+      NumRule::Addb1b0 => ("add-b1b0", &["(eq (add a b) c)"], "(eq (add (bit1 a) (bit0 b)) (bit1 c))"),
+      NumRule::Addb1b1 => ("add-b1b1", &["(eq (add a b) c)", "(eq (add c one) d)"],
+        "(eq (add (bit1 a) (bit1 b)) (bit0 d))"),
+      NumRule::Mul0x => ("mul-0x", &[], "(eq (mul zero a) zero)"),
+      NumRule::Mulx0 => ("mul-x0", &[], "(eq (mul a zero) zero)"),
+      NumRule::Mul1x => ("mul-1x", &[], "(eq (mul one a) a)"),
+      NumRule::Mulx1 => ("mul-x1", &[], "(eq (mul a one) a)"),
+      NumRule::Mulb0x => ("mul-b0x", &["(eq (mul a b) c)"], "(eq (mul (bit0 a) b) (bit0 c))"),
+      NumRule::Mulb1x => ("mul-b1x", &["(eq (mul a b) c)", "(eq (add (bit0 c) b) d)"],
+        "(eq (mul (bit1 a) b) d)"),
+      NumRule::AddCong => ("add-cong", &["(eq a c)", "(eq b d)", "(eq (add c d) e)"], "(eq (add a b) e)"),
+      NumRule::MulCong => ("mul-cong", &["(eq a c)", "(eq b d)", "(eq (mul c d) e)"], "(eq (mul a b) e)"),
+      NumRule::SucNorm => ("suc-norm", &["(eq a b)", "(eq (add b one) c)"], "(eq (suc a) c)"),
+      NumRule::LtIntro => ("lt-intro", &["(eq (add b one) d)", "(eq (add a d) c)"], "(lt a c)"),
+      NumRule::LeIntro => ("le-intro", &["(eq (add a b) c)"], "(le a c)"),
+      NumRule::NeLt => ("ne-lt", &["(lt a b)"], "(ne a b)"),
+      NumRule::NeGt => ("ne-gt", &["(lt b a)"], "(ne a b)"),
+    }
+  }
+}
+
+/// A pattern in a lemma template.
+enum Pat {
+  /// A template variable, `a` to `e`
+  Var(usize),
+  /// An application of a registered term
+  App(NumTerm, Vec<Pat>),
+}
+
+/// Parse a lemma template from a list of tokens, starting at `toks[*i]`. The templates
+/// are fixed, so this panics on bad input.
+fn parse_pat(toks: &[&str], i: &mut usize) -> Pat {
+  let role = |tk: &str| NumTerm::ALL.iter().copied().find(|t| t.name() == tk).expect("bad template");
+  *i += 1;
+  match toks[*i - 1] {
+    "(" => {
+      let t = role(toks[*i]);
+      *i += 1;
+      let mut es = vec![];
+      while toks[*i] != ")" { es.push(parse_pat(toks, i)) }
+      *i += 1;
+      Pat::App(t, es)
+    }
+    tk if tk.len() == 1 && (b'a'..=b'e').contains(&tk.as_bytes()[0]) =>
+      Pat::Var(usize::from(tk.as_bytes()[0] - b'a')),
+    tk => Pat::App(role(tk), vec![]),
+  }
+}
+
+fn template_pat(s: &str) -> Pat {
+  let s = s.replace('(', " ( ").replace(')', " ) ");
+  parse_pat(&s.split_whitespace().collect::<Vec<_>>(), &mut 0)
+}
+
+/// A lemma registered for `(norm-num)`.
+#[derive(Clone, Debug, DeepSizeOf)]
+pub struct NumLemma {
+  /// The theorem to apply
+  pub thm: ThmID,
+  /// For each binder of the theorem, the template variable it is instantiated with
+  pub args: Box<[usize]>,
+}
+
+/// The term constructors and lemmas used by the `(norm-num)` tactic.
+#[derive(Clone, Debug, Default, DeepSizeOf)]
+pub struct NumLemmas {
+  /// The registered term constructors
+  pub terms: HashMap<NumTerm, TermID>,
+  /// The registered lemmas
+  pub lemmas: HashMap<NumRule, NumLemma>,
+}
+
+impl Remap for NumLemma {
+  type Target = Self;
+  fn remap(&self, r: &mut Remapper) -> Self {
+    NumLemma {thm: self.thm.remap(r), args: self.args.clone()}
+  }
+}
+
+impl NumLemmas {
+  /// Add the terms and lemmas registered in an imported environment, overriding the
+  /// current ones for the same roles.
+  pub fn merge(&mut self, other: &Self, r: &mut Remapper) {
+    for (&k, t) in &other.terms { self.terms.insert(k, t.remap(r)); }
+    for (&k, l) in &other.lemmas { self.lemmas.insert(k, l.remap(r)); }
+  }
+
+  /// Check that `e` matches the pattern `p`, binding the template variables in `vars`
+  /// to distinct binders of `td`.
+  fn matches(&self, td: &Thm, e: &ExprNode, p: &Pat, vars: &mut [Option<usize>]) -> bool {
+    match p {
+      &Pat::Var(v) => match as_var(td, e) {
+        Some(i) => match vars[v] {
+          Some(j) => i == j,
+          None if vars.contains(&Some(i)) => false,
+          None => {vars[v] = Some(i); true}
+        },
+        None => false
+      },
+      Pat::App(t, ps) => match deref(td, e) {
+        ExprNode::App(t2, es) => self.terms.get(t) == Some(t2) && es.len() == ps.len() &&
+          es.iter().zip(ps).all(|(e, p)| self.matches(td, e, p, vars)),
+        _ => false
+      }
+    }
+  }
+}
+
+impl Environment {
+  /// Register the term or theorem `x` for the role `role` (see [`NumTerm`] and [`NumRule`])
+  /// of the `(norm-num)` tactic. The terms used in the statement of a lemma must be
+  /// registered before the lemma.
+  pub fn register_norm_num(&mut self, role: &str, x: AtomID) -> Result<(), String> {
+    let name = &self.data[x].name;
+    if let Some(t) = NumTerm::ALL.iter().copied().find(|t| t.name() == role) {
+      let tid = match self.data[x].decl {
+        Some(DeclKey::Term(tid)) => tid,
+        _ => return Err(format!("register-norm-num: unknown term '{}'", name))
+      };
+      if self.terms[tid].args.len() != t.arity() {
+        return Err(format!("register-norm-num: '{}' should have {} arguments", name, t.arity()))
+      }
+      self.nums.terms.insert(t, tid);
+      return Ok(())
+    }
+    let rule = NumRule::ALL.iter().copied().find(|r| r.name() == role)
+      .ok_or_else(|| format!("register-norm-num: unknown role '{}'", role))?;
+    let thm = match self.data[x].decl {
+      Some(DeclKey::Thm(thm)) => thm,
+      _ => return Err(format!("register-norm-num: unknown theorem '{}'", name))
+    };
+    let td = &self.thms[thm];
+    let (_, hyps, ret) = rule.template();
+    let err = || format!("register-norm-num: '{}' does not have the form of the {} lemma \
+      (the terms it uses must be registered first)", name, role);
+    if td.hyps.len() != hyps.len() {return Err(err())}
+    let mut vars = [None; 5];
+    if !self.nums.matches(td, &td.ret, &template_pat(ret), &mut vars) {return Err(err())}
+    for ((_, h), p) in td.hyps.iter().zip(hyps) {
+      if !self.nums.matches(td, h, &template_pat(p), &mut vars) {return Err(err())}
+    }
+    let args = (0..td.args.len()).map(|i| vars.iter().position(|&v| v == Some(i)))
+      .collect::<Option<Box<[_]>>>().ok_or_else(err)?;
+    self.nums.lemmas.insert(rule, NumLemma {thm, args});
+    Ok(())
+  }
+}
+
+/// The state of the `(norm-num)` tactic.
+struct NormNum<'a> {
+  env: &'a Elaborator,
+  /// The role of each registered term
+  roles: HashMap<TermID, NumTerm>,
+}
+
+fn two() -> BigInt { BigInt::from(2) }
+
+impl<'a> NormNum<'a> {
+  fn new(env: &'a Elaborator) -> Self {
+    NormNum {env, roles: env.nums.terms.iter().map(|(&k, &t)| (t, k)).collect()}
+  }
+
+  fn term(&self, t: NumTerm) -> Result<LispVal, String> {
+    let tid = self.env.nums.terms.get(&t).ok_or_else(||
+      format!("norm-num: no term registered for '{}'", t.name()))?;
+    Ok(LispVal::atom(self.env.terms[*tid].atom))
+  }
+
+  fn app(&self, t: NumTerm, args: &[LispVal]) -> Result<LispVal, String> {
+    let mut es = vec![self.term(t)?];
+    es.extend_from_slice(args);
+    Ok(LispVal::list(es))
+  }
+
+  /// The normal numeral for `n`.
+  fn numeral(&self, n: &BigInt) -> Result<LispVal, String> {
+    if n.is_zero() { self.app(NumTerm::Zero, &[]) }
+    else if n.is_one() { self.app(NumTerm::One, &[]) }
+    else {
+      let (q, r) = n.div_rem(&two());
+      let bit = if r.is_zero() {NumTerm::Bit0} else {NumTerm::Bit1};
+      self.app(bit, &[self.numeral(&q)?])
+    }
+  }
+
+  /// Apply the lemma `r` with the given values for the template variables and subproofs.
+  fn apply(&self, r: NumRule, vars: &[LispVal], hyps: Vec<LispVal>) -> Result<LispVal, String> {
+    let l = self.env.nums.lemmas.get(&r).ok_or_else(||
+      format!("norm-num: no lemma registered for '{}'", r.name()))?;
+    let mut args = vec![LispVal::atom(self.env.thms[l.thm].atom)];
+    args.extend(l.args.iter().map(|&v| vars[v].clone()));
+    args.extend(hyps);
+    Ok(LispVal::list(args))
+  }
+
+  /// Get the role and arguments of an application of a registered term.
+  fn as_app(&self, e: &LispVal) -> Option<(NumTerm, Vec<LispVal>)> {
+    e.unwrapped(|r| match r {
+      LispKind::List(es) if !es.is_empty() => {
+        let t = *self.roles.get(&self.env.term(es[0].as_atom()?)?)?;
+        if es.len() != t.arity() + 1 { return None }
+        Some((t, es[1..].to_vec()))
+      }
+      _ => None
+    })
+  }
+
+  /// If `e` is a normal numeral, get its value.
+  fn as_numeral(&self, e: &LispVal) -> Option<BigInt> {
+    match self.as_app(e)? {
+      (NumTerm::Zero, _) => Some(BigInt::zero()),
+      (NumTerm::One, _) => Some(BigInt::one()),
+      (NumTerm::Bit0, args) => self.as_numeral(&args[0]).filter(|n| !n.is_zero()).map(|n| n * 2),
+      (NumTerm::Bit1, args) => self.as_numeral(&args[0]).filter(|n| !n.is_zero()).map(|n| n * 2 + 1),
+      _ => None
+    }
+  }
+
+  /// Prove `a + b = c` for the normal numerals of `a`, `b` and `c = a + b`.
+  fn add(&self, a: &BigInt, b: &BigInt) -> Result<LispVal, String> {
+    let one = BigInt::one();
+    if a.is_zero() { return self.apply(NumRule::Add0x, &[self.numeral(b)?], vec![]) }
+    if b.is_zero() { return self.apply(NumRule::Addx0, &[self.numeral(a)?], vec![]) }
+    let (x, ra) = a.div_rem(&two());
+    let (y, rb) = b.div_rem(&two());
+    match (a.is_one(), b.is_one()) {
+      (true, true) => self.apply(NumRule::Add11, &[], vec![]),
+      (true, false) if rb.is_zero() => self.apply(NumRule::Add1b0, &[self.numeral(&y)?], vec![]),
+      (true, false) => self.apply(NumRule::Add1b1, &[self.numeral(&y)?, self.numeral(&(&y + 1))?],
+        vec![self.add(&y, &one)?]),
+      (false, true) if ra.is_zero() => self.apply(NumRule::Addb01, &[self.numeral(&x)?], vec![]),
+      (false, true) => self.apply(NumRule::Addb11, &[self.numeral(&x)?, self.numeral(&(&x + 1))?],
+        vec![self.add(&x, &one)?]),
+      (false, false) => {
+        let z = &x + &y;
+        let mut vars = vec![self.numeral(&x)?, self.numeral(&y)?, self.numeral(&z)?];
+        let p = self.add(&x, &y)?;
+        match (ra.is_zero(), rb.is_zero()) {
+          (true, true) => self.apply(NumRule::Addb0b0, &vars, vec![p]),
+          (true, false) => self.apply(NumRule::Addb0b1, &vars, vec![p]),
+          (false, true) => self.apply(NumRule::Addb1b0, &vars, vec![p]),
+          (false, false) => {
+            vars.push(self.numeral(&(&z + 1))?);
+            self.apply(NumRule::Addb1b1, &vars, vec![p, self.add(&z, &one)?])
+          }
+        }
+      }
+    }
+  }
+
+  /// Prove `a * b = c` for the normal numerals of `a`, `b` and `c = a * b`.
+  fn mul(&self, a: &BigInt, b: &BigInt) -> Result<LispVal, String> {
+    if a.is_zero() { return self.apply(NumRule::Mul0x, &[self.numeral(b)?], vec![]) }
+    if b.is_zero() { return self.apply(NumRule::Mulx0, &[self.numeral(a)?], vec![]) }
+    if a.is_one() { return self.apply(NumRule::Mul1x, &[self.numeral(b)?], vec![]) }
+    if b.is_one() { return self.apply(NumRule::Mulx1, &[self.numeral(a)?], vec![]) }
+    let (x, r) = a.div_rem(&two());
+    let c = &x * b;
+    let mut vars = vec![self.numeral(&x)?, self.numeral(b)?, self.numeral(&c)?];
+    let p = self.mul(&x, b)?;
+    if r.is_zero() {
+      self.apply(NumRule::Mulb0x, &vars, vec![p])
+    } else {
+      let c2 = &c * 2;
+      vars.push(self.numeral(&(&c2 + b))?);
+      self.apply(NumRule::Mulb1x, &vars, vec![p, self.add(&c2, b)?])
+    }
+  }
+
+  /// Evaluate the expression `e`, returning its value `n` and a proof of `e = n`
+  /// (where `n` is written as a normal numeral).
+  fn eval(&self, e: &LispVal) -> Result<(BigInt, LispVal), String> {
+    if let Some(n) = self.as_numeral(e) {
+      return Ok((n, self.apply(NumRule::EqRefl, &[e.clone()], vec![])?))
+    }
+    let err = || format!("norm-num: not a numeral expression: {}", self.env.print(e));
+    let (t, args) = self.as_app(e).ok_or_else(err)?;
+    let rule = match t {
+      NumTerm::Add => NumRule::AddCong,
+      NumTerm::Mul => NumRule::MulCong,
+      NumTerm::Suc => {
+        let (a, pa) = self.eval(&args[0])?;
+        let b = &a + 1;
+        let vars = [args[0].clone(), self.numeral(&a)?, self.numeral(&b)?];
+        let p = self.add(&a, &BigInt::one())?;
+        return Ok((b, self.apply(NumRule::SucNorm, &vars, vec![pa, p])?))
+      }
+      _ => return Err(err())
+    };
+    let (a, pa) = self.eval(&args[0])?;
+    let (b, pb) = self.eval(&args[1])?;
+    let (c, p) = if t == NumTerm::Add {(&a + &b, self.add(&a, &b)?)} else {(&a * &b, self.mul(&a, &b)?)};
+    if self.as_numeral(&args[0]).is_some() && self.as_numeral(&args[1]).is_some() {
+      return Ok((c, p))
+    }
+    let vars = [args[0].clone(), args[1].clone(),
+      self.numeral(&a)?, self.numeral(&b)?, self.numeral(&c)?];
+    Ok((c, self.apply(rule, &vars, vec![pa, pb, p])?))
+  }
+
+  /// Prove `a + n = c`, where `n` is a normal numeral and `a + n` has the same value as `c`.
+  fn add_eq(&self, a: &LispVal, n: &BigInt, c: &LispVal) -> Result<LispVal, String> {
+    let s = self.app(NumTerm::Add, &[a.clone(), self.numeral(n)?])?;
+    let (v, ps) = self.eval(&s)?;
+    let (_, pc) = self.eval(c)?;
+    self.apply(NumRule::EqNorm, &[s, c.clone(), self.numeral(&v)?], vec![ps, pc])
+  }
+
+  /// Prove `a < c`, where `a` has a smaller value than `c`.
+  fn lt(&self, a: &LispVal, va: &BigInt, c: &LispVal, vc: &BigInt) -> Result<LispVal, String> {
+    let d = vc - va;
+    let b = &d - 1;
+    let vars = [a.clone(), self.numeral(&b)?, c.clone(), self.numeral(&d)?];
+    let p1 = self.add(&b, &BigInt::one())?;
+    let p2 = self.add_eq(a, &d, c)?;
+    self.apply(NumRule::LtIntro, &vars, vec![p1, p2])
+  }
+
+  /// Prove the goal `ty`.
+  fn prove(&self, ty: &LispVal) -> Result<LispVal, String> {
+    let (t, args) = self.as_app(ty).filter(|(_, args)| args.len() == 2).ok_or_else(||
+      format!("norm-num: the goal is not an equality or comparison: {}", self.env.print(ty)))?;
+    let (a, b) = (&args[0], &args[1]);
+    let (va, pa) = self.eval(a)?;
+    if t == NumTerm::Eq && b.is_mvar() { return Ok(pa) }
+    let (vb, pb) = self.eval(b)?;
+    let false_goal = || Err(format!("norm-num: the goal is false: {} evaluates to {} and {} to {}",
+      self.env.print(a), va, self.env.print(b), vb));
+    match t {
+      NumTerm::Eq if va == vb => self.apply(NumRule::EqNorm, &[a.clone(), b.clone(), self.numeral(&va)?], vec![pa, pb]),
+      NumTerm::Le if va <= vb => {
+        let d = &vb - &va;
+        let p = self.add_eq(a, &d, b)?;
+        self.apply(NumRule::LeIntro, &[a.clone(), self.numeral(&d)?, b.clone()], vec![p])
+      }
+      NumTerm::Lt if va < vb => self.lt(a, &va, b, &vb),
+      NumTerm::Ne if va < vb => self.apply(NumRule::NeLt, &[a.clone(), b.clone()], vec![self.lt(a, &va, b, &vb)?]),
+      NumTerm::Ne if va > vb => self.apply(NumRule::NeGt, &[a.clone(), b.clone()], vec![self.lt(b, &vb, a, &va)?]),
+      NumTerm::Eq | NumTerm::Le | NumTerm::Lt | NumTerm::Ne => false_goal(),
+      _ => Err(format!("norm-num: the goal is not an equality or comparison: {}", self.env.print(ty)))
+    }
+  }
+}
+
+impl Elaborator {
+  /// Prove the goal `ty`, which should be an equality or comparison between numeral
+  /// expressions, using the lemmas registered with `register-norm-num`.
+  /// Returns a proof term that can be passed to `refine`.
+  pub fn norm_num_proof(&self, ty: &LispVal) -> Result<LispVal, String> {
+    NormNum::new(self).prove(ty)
+  }
+
+  /// Evaluate the numeral expression `e`, returning the normal numeral `n` with the same
+  /// value and a proof of `e = n`.
+  pub fn norm_num_eval(&self, e: &LispVal) -> Result<(LispVal, LispVal), String> {
+    let nn = NormNum::new(self);
+    let (n, p) = nn.eval(e)?;
+    Ok((nn.numeral(&n)?, p))
+  }
+}
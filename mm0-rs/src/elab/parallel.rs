@@ -0,0 +1,387 @@
+//! Parallel checking of theorem proofs, and lisp function calls evaluated on other threads.
+//!
+//! Once the statement of a theorem has been elaborated, the following statements only
+//! depend on the statement, not on the proof. When elaborating with more than one job
+//! (`mm0-rs compile -j N`), the statements of a file are still elaborated in order, but
+//! the proofs of theorems are deferred: the theorem is added without a proof, and the proof
+//! (including the evaluation of the tactics producing it) is elaborated later by one of `N`
+//! worker elaborators, each of which has its own copy of the environment. The workers run
+//! on a shared pool of `N` threads, and are kept for the whole file: before each batch,
+//! only the declarations added since the last batch are copied to them, along with the
+//! lisp globals (which cannot be shared between threads).
+//!
+//! The deferred proofs are checked as a batch whenever the elaborator reaches a statement
+//! that may look at the proofs or change the lisp state they are elaborated in (a `do`
+//! block, an annotation, an import or an output statement), and at the end of the file.
+//! The errors of each proof are inserted in the error list where they would have been
+//! reported sequentially, so the errors remain in source order.
+//!
+//! Compared to sequential elaboration, a proof can see the theorems and definitions declared
+//! after it in the same batch, and using them is an error (as it would be sequentially, with
+//! a different message); the lisp code in the statements of later declarations in the same
+//! batch (in their binders and types, or the values of definitions) is evaluated before the
+//! proof, so if it changes the lisp globals the proof sees the changes; changes that a proof
+//! makes to the environment or to the lisp globals (using `add-thm!` or `set!`, for example)
+//! are lost; and the hover information of the proofs is not recorded. The language server
+//! always elaborates sequentially.
+//!
+//! Independently of this, the lisp function `(spawn f args)` evaluates `(f args)` on the
+//! same thread pool, with a private copy of the environment, and returns a promise;
+//! `(join p)` waits for the call to finish and copies the result back. If the call has not
+//! been started yet, `join` evaluates it on the current thread instead. As with deferred
+//! proofs, changes that the call makes to the environment or the lisp globals are lost.
+use std::cmp::Ordering;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Instant;
+use futures::executor::ThreadPool;
+use crate::util::Span;
+use crate::parser::ast::{AST, Decl, Stmt, StmtKind};
+use super::{Elaborator, ElabError, Environment, Remap, Remapper, TermID, ThmID};
+use super::environment::{ProofNode, ThmKind};
+use super::lisp::{LispArena, LispVal};
+use super::spans::Spans;
+
+/// A theorem whose proof has been deferred, to be checked by [`Elaborator::check_deferred`].
+#[derive(Debug)]
+pub(crate) struct Deferred {
+  /// The theorem, which has been added without a proof.
+  pub(crate) thm: ThmID,
+  /// The span of the declaration statement, used to find it again.
+  pub(crate) full: Span,
+  /// The number of errors reported before the proof. The errors in the proof are
+  /// inserted at this position.
+  pub(crate) errors: usize,
+  /// The number of terms declared before the theorem.
+  pub(crate) nterms: usize,
+}
+
+/// Returns true if the proofs of theorems in statement `s` can be deferred, and the deferred
+/// proofs do not need to be checked before `s` is elaborated. These are the statements
+/// that do not evaluate lisp code (except in the statements of theorems and definitions)
+/// and do not look at the proofs of earlier theorems.
+pub(crate) fn deferrable(s: &Stmt) -> bool {
+  match &s.k {
+    StmtKind::Sort(..) | StmtKind::Decl(_) | StmtKind::Delimiter(_) |
+    StmtKind::SimpleNota(_) | StmtKind::Coercion {..} | StmtKind::Notation(_) => true,
+    StmtKind::DocComment(_, s) => deferrable(s),
+    _ => false,
+  }
+}
+
+/// Find the declaration whose statement has span `full` in `ast`.
+fn find_decl(ast: &AST, full: Span) -> Option<&Decl> {
+  let i = ast.stmts.binary_search_by(|s|
+    if s.span.end <= full.start { Ordering::Less }
+    else if s.span.start > full.start { Ordering::Greater }
+    else { Ordering::Equal }).ok()?;
+  let mut s = &ast.stmts[i];
+  loop {
+    match &s.k {
+      StmtKind::DocComment(_, s2) => s = s2,
+      StmtKind::Decl(d) => return Some(d),
+      _ => return None
+    }
+  }
+}
+
+/// Find a term or theorem used in `p` that was not declared before the theorem `d.thm`.
+fn later_decl(p: &ProofNode, d: &Deferred) -> Option<Result<TermID, ThmID>> {
+  let term = |t: TermID| if (t.0 as usize) < d.nterms {None} else {Some(Ok(t))};
+  let all = |ps: &[ProofNode]| ps.iter().find_map(|p| later_decl(p, d));
+  match p {
+    ProofNode::Ref(_) | ProofNode::Dummy(..) => None,
+    ProofNode::Term {term: t, args} | ProofNode::Cong {term: t, args} =>
+      term(*t).or_else(|| all(args)),
+    ProofNode::Hyp(_, e) | ProofNode::Refl(e) | ProofNode::Sym(e) => later_decl(e, d),
+    ProofNode::Thm {thm, args, res} =>
+      if *thm >= d.thm {Some(Err(*thm))} else {all(args).or_else(|| later_decl(res, d))},
+    ProofNode::Conv(p) => later_decl(&p.0, d)
+      .or_else(|| later_decl(&p.1, d)).or_else(|| later_decl(&p.2, d)),
+    ProofNode::Unfold {term: t, args, res} => term(*t).or_else(|| all(args))
+      .or_else(|| later_decl(&res.0, d)).or_else(|| later_decl(&res.1, d))
+      .or_else(|| later_decl(&res.2, d)),
+  }
+}
+
+/// The result of checking a deferred proof: the theorem, the proof (or `Thm(None)` if it
+/// failed), the errors, and whether the proof used `admit`.
+type Checked = (Deferred, ThmKind, Vec<ElabError>, bool);
+
+lazy_static! {
+  /// The thread pool for deferred proofs and `spawn`. It has [`get_jobs`](crate::get_jobs)
+  /// threads, which are started when the pool is first used and shared by all the files
+  /// being elaborated.
+  static ref POOL: ThreadPool = ThreadPool::builder()
+    .pool_size(crate::get_jobs().max(1))
+    .name_prefix("mm0-rs-worker-")
+    .create().expect("could not start thread pool");
+}
+
+/// The state of a job submitted to the [`POOL`] by [`Pending::spawn`].
+enum Slot<R> {
+  /// The job has not been started yet.
+  Queued(Box<dyn FnOnce() -> R + Send>),
+  /// The job is running.
+  Running,
+  /// The job has finished with this result, or with this panic.
+  Done(std::thread::Result<R>),
+}
+
+/// A job submitted to the [`POOL`], whose result can be waited for with [`join`](Self::join).
+struct Pending<R>(Arc<(Mutex<Slot<R>>, Condvar)>);
+
+impl<R> std::fmt::Debug for Pending<R> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { write!(f, "Pending") }
+}
+
+impl<R: Send + 'static> Pending<R> {
+  /// Submit the job `f` to the pool.
+  fn spawn(f: impl FnOnce() -> R + Send + 'static) -> Self {
+    let p = Arc::new((Mutex::new(Slot::Queued(Box::new(f))), Condvar::new()));
+    let p2 = Pending(p.clone());
+    POOL.spawn_ok(async move { p2.run() });
+    Pending(p)
+  }
+
+  /// Run the job on the current thread, unless it has already been started.
+  fn run(&self) {
+    let (slot, cvar) = &*self.0;
+    let mut g = slot.lock().expect("poisoned");
+    let f = match mem::replace(&mut *g, Slot::Running) {
+      Slot::Queued(f) => f,
+      s => { *g = s; return }
+    };
+    drop(g);
+    let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+    *slot.lock().expect("poisoned") = Slot::Done(r);
+    cvar.notify_all();
+  }
+
+  /// Wait for the job to finish, and return its result. If no pool thread has started the
+  /// job yet, it is run on the current thread instead, so that waiting for a job on a pool
+  /// thread cannot deadlock when all the pool threads are waiting.
+  fn join(self) -> R {
+    self.run();
+    let (slot, cvar) = &*self.0;
+    let mut g = slot.lock().expect("poisoned");
+    loop {
+      match mem::replace(&mut *g, Slot::Running) {
+        Slot::Done(r) => {
+          drop(g);
+          return r.unwrap_or_else(|e| std::panic::resume_unwind(e))
+        }
+        s => { *g = s; g = cvar.wait(g).expect("poisoned") }
+      }
+    }
+  }
+}
+
+/// A worker elaborator for checking deferred proofs, with a private copy of the environment.
+/// The workers are kept for the whole file, and brought up to date with the main elaborator
+/// by [`Elaborator::sync_worker`] before each batch.
+#[derive(Debug)]
+pub(crate) struct Worker {
+  elab: Elaborator,
+  /// The number of sorts, terms, theorems and statements copied from the main elaborator.
+  /// Anything after these was added by the worker itself, and is dropped at the next sync.
+  synced: (usize, usize, usize, usize),
+}
+// Safety: the lisp data in a worker is a private copy (see `Remapper::identity`), which is
+// only used by one thread at a time: the pool thread checking a batch of proofs, or the main
+// thread between batches, which waits for the batch to finish.
+unsafe impl Send for Worker {}
+
+impl Worker {
+  /// Check the deferred proofs `thms`, and return the worker for the next batch.
+  fn run(mut self, thms: Vec<Deferred>) -> (Self, Vec<Checked>) {
+    let elab = &mut self.elab;
+    let old = elab.arena.install_thread_local();
+    let ast = elab.ast.clone();
+    let out = thms.into_iter().map(|d| {
+      let decl = find_decl(&ast, d.full).expect("deferred proof of a declaration");
+      elab.proof_worker = Some(d.thm);
+      elab.cur_timeout = elab.timeout.and_then(|t| Instant::now().checked_add(t));
+      elab.cur_fuel = elab.fuel;
+      elab.spans = Spans::new();
+      elab.spans.set_stmt(d.full);
+      let r = elab.elab_decl(d.full, decl, None);
+      elab.catch(r);
+      let mut kind = mem::replace(&mut elab.env.thms[d.thm].kind, ThmKind::Thm(None));
+      if let ThmKind::Thm(Some(pf)) = &kind {
+        let bad = pf.heap.iter().chain(&*pf.hyps).chain(Some(&pf.head))
+          .find_map(|p| later_decl(p, &d));
+        if let Some(bad) = bad {
+          let a = match bad { Ok(t) => elab.env.terms[t].atom, Err(t) => elab.env.thms[t].atom };
+          elab.report(ElabError::new_e(decl.id,
+            format!("proof uses {}, which is declared after this theorem", elab.data[a].name)));
+          kind = ThmKind::Thm(None)
+        }
+      }
+      let admitted = elab.env.admitted.last() == Some(&d.thm);
+      (d, kind, mem::take(&mut elab.errors), admitted)
+    }).collect();
+    LispArena::restore_thread_local(old);
+    (self, out)
+  }
+}
+
+/// A lisp function call started by `spawn`, which is being evaluated on the [`POOL`].
+/// See [`Elaborator::spawn_task`].
+#[derive(Debug)]
+pub struct Task(Pending<Finished>);
+crate::deep_size_0!(Task);
+
+/// A worker elaborator with a private copy of the environment, and the function call
+/// it evaluates for `spawn`.
+struct Job(Elaborator, Span, LispVal, Vec<LispVal>);
+/// The worker elaborator of a [`Job`] after the call has been evaluated, and the result.
+struct Finished(Elaborator, Result<LispVal, ElabError>);
+// Safety: as for `Worker`, the lisp data in a job is a private copy, which is only used on
+// the thread evaluating the call until the call has finished. It is then only used by the
+// thread that joins the task.
+unsafe impl Send for Job {}
+unsafe impl Send for Finished {}
+
+impl Job {
+  fn run(self) -> Finished {
+    let Job(mut elab, sp, f, args) = self;
+    let old = elab.arena.install_thread_local();
+    elab.cur_timeout = elab.timeout.and_then(|t| Instant::now().checked_add(t));
+    elab.cur_fuel = elab.fuel;
+    let r = elab.call_func(sp, f, args);
+    LispArena::restore_thread_local(old);
+    Finished(elab, r)
+  }
+}
+
+impl Elaborator {
+  /// Make a new elaborator for evaluating lisp code on another thread. Its environment must
+  /// be replaced by a copy of this one, which does not share any lisp data with it.
+  fn worker(&self) -> Elaborator {
+    let mut w = Elaborator::new(self.ast.clone(), self.path.clone(), false, true, self.cancel.clone());
+    w.debugger = None;
+    w
+  }
+
+  /// Copy the settings of this elaborator to the worker elaborator `w`, using the remapper
+  /// `r` to copy the lisp data.
+  fn copy_settings(&self, w: &mut Elaborator, r: &mut Remapper) {
+    w.tactics = self.tactics.iter().map(|(&t, v)| (t, v.remap(r))).collect();
+    w.timeout = self.timeout;
+    w.fuel = self.fuel;
+    w.test_fuel = self.test_fuel;
+    w.stack_limit = self.stack_limit;
+    w.term_size_limit = self.term_size_limit;
+    w.proof_size_limit = self.proof_size_limit;
+    w.reporting = self.reporting;
+    w.sequent_goals = self.sequent_goals;
+    w.normalize_tokens = self.normalize_tokens;
+    w.warn_precedence = self.warn_precedence;
+    w.backtrace = self.backtrace;
+    w.strict_overrides = self.strict_overrides;
+    w.gensym = self.gensym;
+  }
+
+  /// Bring the environment and settings of the worker `w` up to date with this elaborator.
+  /// Sorts, terms, theorems and statements do not change once they are added (except for
+  /// the proofs of deferred theorems, which are copied to the workers when they are checked),
+  /// so only the ones added since the last sync are copied. The lisp data is copied in full,
+  /// since any lisp global may have changed.
+  fn sync_worker(&self, w: &mut Worker) {
+    let (env, we) = (&self.env, &mut w.elab.env);
+    let (sorts, terms, thms, stmts) = w.synced;
+    we.sorts.0.truncate(sorts);
+    we.sorts.0.extend_from_slice(&env.sorts.0[sorts..]);
+    we.terms.0.truncate(terms);
+    we.terms.0.extend_from_slice(&env.terms.0[terms..]);
+    we.thms.0.truncate(thms);
+    we.thms.0.extend_from_slice(&env.thms.0[thms..]);
+    we.stmts.truncate(stmts);
+    we.stmts.extend_from_slice(&env.stmts[stmts..]);
+    we.pe.clone_from(&env.pe);
+    we.eqs.clone_from(&env.eqs);
+    we.nums.clone_from(&env.nums);
+    we.atoms.clone_from(&env.atoms);
+    we.admitted.clone_from(&env.admitted);
+    w.synced = (env.sorts.len(), env.terms.len(), env.thms.len(), env.stmts.len());
+    // The remapper holds references to the lisp data of this thread, so it must not
+    // outlive this function, which returns before the worker is sent to another thread
+    let r = &mut Remapper::identity(env);
+    we.data = env.data.0.iter().map(|ad| ad.remap(r)).collect();
+    self.copy_settings(&mut w.elab, r);
+  }
+
+  /// Check the deferred proofs (see the [module documentation](self)) on up to
+  /// [`jobs`](Self::jobs) workers, and store the results in the environment.
+  pub(crate) fn check_deferred(&mut self) {
+    if self.deferred.is_empty() { return }
+    let deferred = mem::take(&mut self.deferred);
+    let n = self.jobs.min(deferred.len()).max(1);
+    let mut parts = (0..n).map(|_| vec![]).collect::<Vec<_>>();
+    for (i, d) in deferred.into_iter().enumerate() { parts[i % n].push(d) }
+    while self.workers.len() < n {
+      let elab = self.worker();
+      self.workers.push(Worker {elab, synced: (0, 0, 0, 0)})
+    }
+    let mut workers = mem::take(&mut self.workers);
+    let idle = workers.split_off(n);
+    let natoms = self.env.data.len();
+    let batches = workers.into_iter().zip(parts).map(|(mut w, thms)| {
+      self.sync_worker(&mut w);
+      Pending::spawn(move || w.run(thms))
+    }).collect::<Vec<_>>();
+    let mut results = vec![];
+    for p in batches {
+      let (w, r) = p.join();
+      let we = &w.elab.env;
+      if we.data.len() > natoms {
+        // The worker allocated new atoms (for example the names of dummy variables),
+        // so the atoms in the proofs have to be mapped to the atoms of this environment
+        let mut rm = Remapper::identity(we);
+        for (i, ad) in we.data.0.iter().enumerate().skip(natoms) {
+          rm.atom.0[i] = self.env.get_atom_arc(ad.name.clone())
+        }
+        results.extend(r.into_iter().map(|(d, kind, es, a)| (d, kind.remap(&mut rm), es, a)))
+      } else { results.extend(r) }
+      self.workers.push(w)
+    }
+    self.workers.extend(idle);
+    // Insert the errors from the back, so that the positions of the earlier ones stay valid
+    results.sort_by(|a, b| (b.0.errors, b.0.thm).cmp(&(a.0.errors, a.0.thm)));
+    for (d, kind, errors, admitted) in results {
+      for w in &mut self.workers {
+        if (d.thm.0 as usize) < w.synced.2 { w.elab.env.thms[d.thm].kind = kind.clone() }
+      }
+      self.env.thms[d.thm].kind = kind;
+      if admitted { self.env.admitted.push(d.thm) }
+      self.errors.splice(d.errors..d.errors, errors);
+    }
+    self.env.admitted.sort_unstable();
+  }
+
+  /// Start evaluating `(f args)` on the [`POOL`], for `spawn`. The call is evaluated in a
+  /// copy of the environment, and it does not see the local context of the current proof.
+  pub(crate) fn spawn_task(&self, sp: Span, f: &LispVal, args: &[LispVal]) -> Task {
+    // The snapshot and the remapper hold references to the lisp data of this thread,
+    // so they are dropped at the end of this block, before the job is sent to the pool
+    let job = {
+      let s = self.env.snapshot();
+      let mut w = self.worker();
+      w.env = Environment::resume(&self.env, &s, 0);
+      let r = &mut Remapper::identity(&self.env);
+      self.copy_settings(&mut w, r);
+      Job(w, sp, f.remap(r), args.iter().map(|e| e.remap(r)).collect())
+    };
+    Task(Pending::spawn(move || job.run()))
+  }
+
+  /// Wait for a call started by [`spawn_task`](Self::spawn_task) to finish, and copy the
+  /// result into this environment. The errors reported during the call are reported here.
+  pub(crate) fn join_task(&mut self, Task(p): Task) -> Result<LispVal, ElabError> {
+    let Finished(mut w, r) = p.join();
+    self.errors.append(&mut w.errors);
+    let e = r?;
+    Ok(e.remap(&mut Remapper::from_copy(&mut self.env, &w.env)))
+  }
+}
@@ -0,0 +1,177 @@
+//! Lightweight timing data for explaining slow statements.
+//!
+//! Every statement is timed, and the time is broken down into a few coarse
+//! categories. Time spent in lisp procedures is measured by sampling: every few
+//! hundred evaluation steps, the time since the last sample is charged to the innermost
+//! named procedure on the lisp stack. This is cheap enough to leave on all the time,
+//! so that when a statement turns out to be slow we can tell the user where the time went
+//! without them having to rerun anything.
+//!
+//! For finding the hotspots in slow lisp code, there is also a more precise (and expensive)
+//! [`LispProfile`], which must be turned on explicitly.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+use super::environment::AtomID;
+use super::lisp::print::FormatEnv;
+
+/// Statements that take longer than this get an info diagnostic explaining where
+/// the time was spent.
+pub const SLOW_STMT: Duration = Duration::from_secs(1);
+
+/// Timing data for the statement currently being elaborated.
+#[derive(Debug)]
+pub struct StmtProfile {
+  /// The time the statement started elaborating
+  start: Instant,
+  /// The time of the last sample, for charging time to lisp procedures
+  last_sample: Instant,
+  /// The time spent parsing lisp expressions and math formulas
+  pub(crate) parse: Duration,
+  /// The time spent in the unifier ([`run_refine`](super::Elaborator::run_refine))
+  pub(crate) refine: Duration,
+  /// The time spent checking and deduplicating the final proof terms
+  pub(crate) check: Duration,
+  /// The sampled time spent in each named lisp procedure
+  procs: HashMap<AtomID, Duration>,
+}
+
+impl Default for StmtProfile {
+  fn default() -> Self {
+    let now = Instant::now();
+    Self {start: now, last_sample: now, parse: Duration::default(),
+      refine: Duration::default(), check: Duration::default(), procs: HashMap::new()}
+  }
+}
+
+impl StmtProfile {
+  /// Reset the profile at the start of a new statement.
+  pub fn reset(&mut self) {
+    let now = Instant::now();
+    self.start = now;
+    self.last_sample = now;
+    self.parse = Duration::default();
+    self.refine = Duration::default();
+    self.check = Duration::default();
+    self.procs.clear();
+  }
+
+  /// Mark the start of a lisp evaluation, so that time spent outside the evaluator
+  /// is not charged to the first procedure sampled.
+  pub fn resume(&mut self) { self.last_sample = Instant::now() }
+
+  /// Take a sample at time `now`, charging the time since the last sample to
+  /// the procedure `proc`, if provided.
+  pub fn sample(&mut self, now: Instant, proc: Option<AtomID>) {
+    let d = now.saturating_duration_since(self.last_sample);
+    self.last_sample = now;
+    if let Some(a) = proc { *self.procs.entry(a).or_default() += d }
+  }
+
+  /// Returns an explanation of where the time was spent, if this statement took longer
+  /// than [`SLOW_STMT`].
+  #[must_use] pub fn explain(&self, fe: FormatEnv<'_>) -> Option<String> {
+    let total = self.start.elapsed();
+    if total < SLOW_STMT {return None}
+    let lisp = total.checked_sub(self.parse + self.refine + self.check).unwrap_or_default();
+    let mut s = format!("slow statement: took {:.2?}\n\
+      parsing: {:.2?}\n\
+      lisp evaluation: {:.2?}\n\
+      unification: {:.2?}\n\
+      proof checking: {:.2?}", total, self.parse, lisp, self.refine, self.check);
+    let mut procs = self.procs.iter().filter(|p| *p.1 > Duration::default()).collect::<Vec<_>>();
+    procs.sort_by(|a, b| b.1.cmp(a.1));
+    if !procs.is_empty() {
+      s.push_str("\nmost time spent in (approximately):");
+      for (&a, d) in procs.into_iter().take(3) {
+        write!(s, "\n  ({}): {:.2?}", fe.data[a].name, d).expect("writing to a string");
+      }
+    }
+    Some(s)
+  }
+}
+
+/// The counters for one procedure in a [`LispProfile`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProcCounters {
+  /// The number of times the procedure was called
+  pub calls: u64,
+  /// The number of evaluation steps taken in the procedure itself (not in the
+  /// named procedures it calls)
+  pub steps: u64,
+  /// The time spent in the procedure itself
+  pub time: Duration,
+  /// The number of lisp values allocated in the procedure itself
+  pub allocs: u64,
+}
+
+/// A detailed profile of lisp evaluation, enabled with `(profile-lisp #t)` or
+/// `mm0-rs compile --profile-lisp`. Unlike [`StmtProfile`], which only samples,
+/// every evaluation step is timed and charged to the innermost named procedure on
+/// the lisp stack, so this is much more expensive, but also much more precise.
+/// The counters accumulate over the whole file.
+#[derive(Debug, Default)]
+pub struct LispProfile {
+  /// True if profiling is currently on
+  pub(crate) active: bool,
+  /// The procedure that was running at the last step, and the time and allocation count then
+  last: Option<(Option<AtomID>, Instant, u64)>,
+  /// The counters for each named procedure
+  procs: HashMap<AtomID, ProcCounters>,
+}
+
+impl LispProfile {
+  /// Construct a new profile, which is initially on if `active` is true.
+  #[must_use] pub fn new(active: bool) -> Self { Self {active, ..Self::default()} }
+
+  /// Turn profiling on or off. Turning it on clears the counters.
+  pub fn set_active(&mut self, active: bool) {
+    if active { self.procs.clear() }
+    self.active = active;
+    self.last = None;
+    super::lisp::count_allocs(active);
+  }
+
+  /// Mark the start of a lisp evaluation, so that time spent outside the evaluator
+  /// is not charged to the last procedure that was running. This also turns the counting
+  /// of allocations on the current thread on or off, according to whether profiling is on.
+  pub fn resume(&mut self) {
+    self.last = None;
+    super::lisp::count_allocs(self.active);
+  }
+
+  /// Record an evaluation step, inside the procedure `proc` (if any). The time and
+  /// allocations since the last step are charged to the procedure running at that step.
+  pub fn step(&mut self, proc: Option<AtomID>) {
+    let (now, allocs) = (Instant::now(), super::lisp::alloc_count());
+    if let Some((Some(a), t, n)) = self.last {
+      let c = self.procs.entry(a).or_default();
+      c.time += now.saturating_duration_since(t);
+      c.allocs += allocs.saturating_sub(n);
+    }
+    if let Some(a) = proc { self.procs.entry(a).or_default().steps += 1 }
+    self.last = Some((proc, now, allocs));
+  }
+
+  /// Record a call to the procedure `a`.
+  pub fn call(&mut self, a: AtomID) { self.procs.entry(a).or_default().calls += 1 }
+
+  /// Returns true if no procedures have been profiled.
+  #[must_use] pub fn is_empty(&self) -> bool { self.procs.is_empty() }
+
+  /// Returns a table of the counters for each procedure, sorted by time.
+  #[must_use] pub fn report(&self, fe: FormatEnv<'_>) -> String {
+    let mut procs = self.procs.iter().collect::<Vec<_>>();
+    procs.sort_by(|a, b| b.1.time.cmp(&a.1.time).then_with(|| fe.data[*a.0].name.cmp(&fe.data[*b.0].name)));
+    let width = procs.iter().map(|p| fe.data[*p.0].name.len()).max().unwrap_or(0).max("procedure".len());
+    let mut s = format!("lisp profile:\n{:w$}  {:>8}  {:>10}  {:>10}  {:>10}",
+      "procedure", "calls", "steps", "time", "allocs", w = width);
+    for (&a, c) in procs {
+      write!(s, "\n{:w$}  {:>8}  {:>10}  {:>10}  {:>10}",
+        String::from_utf8_lossy(&fe.data[a].name), c.calls, c.steps,
+        format!("{:.2?}", c.time), c.allocs, w = width).expect("writing to a string");
+    }
+    s
+  }
+}
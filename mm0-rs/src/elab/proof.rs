@@ -25,6 +25,20 @@ pub struct NodeHasher<'a> {
   pub var_map: HashMap<AtomID, usize>,
   /// The file span for the theorem, used for error reporting.
   pub fsp: FileSpan,
+  /// The limit on the size of the term or proof, if any.
+  pub limit: Option<SizeLimit>,
+}
+
+/// A limit on the size of a term or proof being deduplicated, to catch tactics that build
+/// exponentially large terms before they exhaust memory. (See `set-size-limit`.)
+#[derive(Copy, Clone, Debug)]
+pub struct SizeLimit {
+  /// The maximum number of (distinct) lisp nodes to traverse.
+  pub max: usize,
+  /// The declaration that is being elaborated, for the error message.
+  pub decl: AtomID,
+  /// True if this is a proof, false if it is a term (a statement or a definition).
+  pub proof: bool,
 }
 
 impl<'a> NodeHasher<'a> {
@@ -35,7 +49,13 @@ impl<'a> NodeHasher<'a> {
     for (i, &(_, a, _)) in lc.var_order.iter().enumerate() {
       if let Some(a) = a {var_map.insert(a, i);}
     }
-    NodeHasher {lc, fe, var_map, fsp}
+    NodeHasher {lc, fe, var_map, fsp, limit: None}
+  }
+
+  /// Set the limit on the size of the term or proof.
+  #[must_use] pub fn with_limit(mut self, limit: Option<SizeLimit>) -> Self {
+    self.limit = limit;
+    self
   }
 
   /// Construct an error at the given expression's location.
@@ -47,6 +67,15 @@ impl<'a> NodeHasher<'a> {
   fn err_sp(&self, fsp: Option<&FileSpan>, msg: impl Into<BoxError>) -> ElabError {
     ElabError::new_e(try_get_span_from(&self.fsp, fsp), msg)
   }
+
+  /// Construct an error for exceeding the size limit `lim`, at the given location (which
+  /// is usually the tactic call that produced the node, if it is in the current file).
+  fn size_err(&self, fsp: Option<&FileSpan>, lim: &SizeLimit) -> ElabError {
+    self.err_sp(fsp, format!(
+      "{} of '{}' is too large: it has more than {} nodes \
+        (use (set-size-limit n k) to change the limit)",
+      if lim.proof {"proof"} else {"term"}, self.fe.data[lim.decl].name, lim.max))
+  }
 }
 
 /// A "hashable" type. We use this to abstract the difference between
@@ -100,6 +129,9 @@ pub struct Dedup<H: NodeHash> {
   /// lower than that, [55](super::local_context::MAX_BOUND_VARS),
   /// due to the way BV sets are stored in the compiled `.mmb` format.)
   bv: u64,
+  /// The number of distinct lisp nodes that have been traversed, for enforcing the
+  /// [`SizeLimit`].
+  visited: usize,
 }
 
 impl<H: NodeHash> Dedup<H> {
@@ -117,6 +149,7 @@ impl<H: NodeHash> Dedup<H> {
       prev: HashMap::new(),
       vec,
       bv,
+      visited: 0,
     }
   }
 
@@ -140,6 +173,10 @@ impl<H: NodeHash> Dedup<H> {
       },
       Entry::Vacant(v) => {
         v.insert(None);
+        self.visited += 1;
+        if let Some(lim) = &nh.limit {
+          if self.visited > lim.max { return Err(nh.size_err(e.fspan().as_ref(), lim)) }
+        }
         let n = match H::from(nh, e.fspan().as_ref(), &arc, self)? {
           Ok(v) => self.add_direct(v),
           Err(n) => n,
@@ -159,7 +196,7 @@ impl<H: NodeHash> Dedup<H> {
       map.insert(t.clone(), i);
       (t, b, v)
     }).collect();
-    Dedup { map, prev: self.prev.clone(), vec, bv: self.bv }
+    Dedup { map, prev: self.prev.clone(), vec, bv: self.bv, visited: 0 }
   }
 }
 
@@ -567,8 +604,9 @@ impl NodeHash for ProofHash {
             }
           }
           Some(DeclKey::Thm(tid)) => {
+            let args: Vec<_> = u.collect();
             let mut ns = Vec::new();
-            for e in u { ns.push(de.dedup(nh, &e)?) }
+            for e in &args { ns.push(de.dedup(nh, e)?) }
             let td = &nh.fe.thms[tid];
             if ns.len() != td.args.len() + td.hyps.len() {
               return Err(nh.err_sp(fsp,
@@ -576,11 +614,18 @@ impl NodeHash for ProofHash {
             }
             let mut heap = vec![None; td.heap.len()];
             let mut bvars: Vec<u64> = vec![];
-            for (i, (_, t)) in td.args.iter().enumerate() {
+            for (i, (x, t)) in td.args.iter().enumerate() {
               heap[i] = Some(ns[i]);
               let deps = de.vec[ns[i]].2;
               let ok = match t {
                 Type::Bound(_) => {
+                  let is_bv = args[i].as_atom().map_or(false, |a|
+                    matches!(nh.lc.vars.get(&a), Some((_, InferSort::Bound(_)))));
+                  if !is_bv {
+                    return Err(nh.err(&th_head, format!(
+                      "{}: argument {} must be a bound variable, got {}", adata.name,
+                      nh.fe.to(&x.unwrap_or(AtomID::UNDER)), nh.fe.pp(&args[i], 80))))
+                  }
                   bvars.push(deps);
                   ns[..i].iter().all(|&j| de.vec[j].2 & deps == 0)
                 }
@@ -606,7 +651,6 @@ impl NodeHash for ProofHash {
                   }
                 }
                 let mut err = format!("disjoint variable violation at {}", adata.name);
-                let args: Vec<_> = Uncons::from(r.clone()).skip(1).collect();
                 for (i, j) in dvs {
                   if de.vec[ns[i]].2 & de.vec[ns[j]].2 != 0 {
                     use std::fmt::Write;
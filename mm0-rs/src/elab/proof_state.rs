@@ -0,0 +1,220 @@
+//! Saving and restoring proof states, using `(save-state)` and `mm0-rs load-state`.
+//!
+//! A saved state is a small JSON file recording the local context of a proof in progress:
+//! the variables, the hypotheses and subproofs (by statement only), the goals, and the
+//! metavariables they contain. It also records the source file that provides the
+//! environment, and the slice of that environment that the state depends on (the sorts and
+//! term constructors that appear in it), so that a state loaded against a library that has
+//! changed in the meantime is rejected instead of being silently misread.
+//!
+//! The expressions are stored as nested JSON arrays, with atoms as strings,
+//! numbers as `{"num": "123"}`, and metavariables as `{"mvar": n}`, where `n` is an index
+//! into the `mvars` list. Proof terms are not saved: on reload, each hypothesis proves
+//! itself by name, as it does at the start of a theorem.
+
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::path::Path;
+use num::BigInt;
+use serde_json::{json, Value};
+use super::{Elaborator, environment::{AtomID, SortID, TermID}};
+use super::local_context::InferSort;
+use super::lisp::{InferTarget, LispKind, LispVal};
+use crate::util::Span;
+
+/// The version of the saved state format. This is bumped whenever the format changes in
+/// a way that older versions of `mm0-rs` would misread.
+const VERSION: u64 = 1;
+
+/// The sorts and terms that a saved state depends on.
+#[derive(Default)]
+struct Slice {
+  sorts: Vec<SortID>,
+  terms: Vec<TermID>,
+  seen: HashSet<AtomID>,
+}
+
+impl Elaborator {
+  fn save_sort(&self, slice: &mut Slice, s: SortID) -> Value {
+    let a = self.sorts[s].atom;
+    if slice.seen.insert(a) { slice.sorts.push(s) }
+    json!(self.data[a].name.as_str())
+  }
+
+  fn save_expr(&self, slice: &mut Slice, e: &LispVal) -> Result<Value, String> {
+    e.unwrapped(|r| Ok(match r {
+      &LispKind::Atom(a) => json!(self.data[a].name.as_str()),
+      LispKind::List(es) => {
+        if let Some(t) = es.first().and_then(|e| e.as_atom()).and_then(|a| self.term(a)) {
+          if slice.seen.insert(self.terms[t].atom) { slice.terms.push(t) }
+        }
+        Value::Array(es.iter().map(|e| self.save_expr(slice, e)).collect::<Result<_, _>>()?)
+      }
+      &LispKind::MVar(n, _) => json!({"mvar": n}),
+      &LispKind::SmallInt(n) => json!({"num": n.to_string()}),
+      LispKind::Number(n) => json!({"num": n.to_string()}),
+      _ => return Err(format!("save-state: not an expression: {}", self.print(e)))
+    }))
+  }
+
+  fn save_target(&self, slice: &mut Slice, tgt: InferTarget) -> Value {
+    match tgt {
+      InferTarget::Unknown => json!({}),
+      InferTarget::Provable => json!({"provable": true}),
+      InferTarget::Bound(s) | InferTarget::Reg(s) => {
+        if let Some(s) = self.data[s].sort { self.save_sort(slice, s); }
+        json!({"sort": self.data[s].name.as_str(), "bound": tgt.bound()})
+      }
+    }
+  }
+
+  /// Serialize the current proof state, for `(save-state)`. `dir` is the directory the
+  /// state file is written to, which the source file path is made relative to.
+  pub fn save_state(&mut self, dir: &Path) -> Result<Value, String> {
+    self.lc.clean_mvars();
+    let mut slice = Slice::default();
+    let mut vars = vec![];
+    for (_, a, _) in &self.lc.var_order {
+      let a = if let Some(a) = *a {a} else {continue};
+      let (dummy, is) = &self.lc.vars[&a];
+      let (bound, s, deps) = match is {
+        &InferSort::Bound(s) => (true, s, &[][..]),
+        InferSort::Reg(s, deps) => (false, *s, &**deps),
+        InferSort::Unknown {must_bound, ..} => match is.sort() {
+          Some(s) => (*must_bound, s, &[][..]),
+          None => return Err(format!(
+            "save-state: the sort of variable '{}' is not known", self.data[a].name))
+        }
+      };
+      vars.push(json!({
+        "name": self.data[a].name.as_str(),
+        "dummy": dummy,
+        "bound": bound,
+        "sort": self.save_sort(&mut slice, s),
+        "deps": deps.iter().map(|&d| json!(self.data[d].name.as_str())).collect::<Vec<_>>(),
+      }))
+    }
+    let mvars = self.lc.mvars.iter().map(|e| e.unwrapped(|r| match *r {
+      LispKind::MVar(_, tgt) => self.save_target(&mut slice, tgt),
+      _ => json!({}),
+    })).collect::<Vec<_>>();
+    let hyps = self.lc.proof_order.iter().map(|(a, e, _)| Ok(json!({
+      "name": self.data[*a].name.as_str(),
+      "type": self.save_expr(&mut slice, e)?,
+    }))).collect::<Result<Vec<_>, String>>()?;
+    let goals = self.lc.goals.iter().filter_map(|e| e.goal_type())
+      .map(|e| self.save_expr(&mut slice, &e)).collect::<Result<Vec<_>, _>>()?;
+    let file = pathdiff::diff_paths(self.path.path(), dir)
+      .unwrap_or_else(|| self.path.path().clone());
+    Ok(json!({
+      "version": VERSION,
+      "file": file.to_string_lossy(),
+      "env": {
+        "sorts": slice.sorts.iter().map(|&s| json!(self.sorts[s].name.as_str())).collect::<Vec<_>>(),
+        "terms": slice.terms.iter().map(|&t| {
+          let t = &self.terms[t];
+          json!([self.data[t.atom].name.as_str(), t.args.len(), self.sorts[t.ret.0].name.as_str()])
+        }).collect::<Vec<_>>(),
+      },
+      "vars": vars,
+      "mvars": mvars,
+      "hyps": hyps,
+      "goals": goals,
+    }))
+  }
+
+  fn load_atom(&mut self, v: &Value) -> Result<AtomID, String> {
+    let s = v.as_str().ok_or_else(|| format!("load-state: expected a name, got {}", v))?;
+    Ok(self.get_atom(s.as_bytes()))
+  }
+
+  fn load_sort(&mut self, v: &Value) -> Result<SortID, String> {
+    let a = self.load_atom(v)?;
+    self.data[a].sort.ok_or_else(|| format!("load-state: unknown sort '{}'", self.data[a].name))
+  }
+
+  fn load_expr(&mut self, mvars: &[LispVal], v: &Value) -> Result<LispVal, String> {
+    Ok(match v {
+      Value::String(_) => LispVal::atom(self.load_atom(v)?),
+      Value::Array(vs) => LispVal::list(vs.iter().map(|v| self.load_expr(mvars, v))
+        .collect::<Result<Vec<_>, _>>()?),
+      _ => if let Some(n) = v.get("mvar").and_then(Value::as_u64) {
+        usize::try_from(n).ok().and_then(|i| mvars.get(i)).cloned()
+          .ok_or_else(|| format!("load-state: unknown metavariable {}", n))?
+      } else if let Some(n) = v.get("num").and_then(Value::as_str) {
+        LispVal::number(n.parse::<BigInt>().map_err(|e| format!("load-state: {}", e))?)
+      } else {
+        return Err(format!("load-state: not an expression: {}", v))
+      }
+    })
+  }
+
+  fn list<'a>(state: &'a Value, key: &str) -> Result<&'a [Value], String> {
+    match state.get(key) {
+      None => Ok(&[]),
+      Some(v) => v.as_array().map(|v| &**v)
+        .ok_or_else(|| format!("load-state: '{}' should be a list", key))
+    }
+  }
+
+  /// Check that the environment slice of a saved state matches the current environment.
+  fn check_slice(&mut self, env: &Value) -> Result<(), String> {
+    for s in Self::list(env, "sorts")? { self.load_sort(s)?; }
+    for t in Self::list(env, "terms")? {
+      let (a, n, ret) = match t.as_array().map(|t| &**t) {
+        Some([a, n, ret]) => (self.load_atom(a)?, n.as_u64(), self.load_sort(ret)?),
+        _ => return Err(format!("load-state: bad term entry {}", t))
+      };
+      let t = self.term(a)
+        .ok_or_else(|| format!("load-state: unknown term '{}'", self.data[a].name))?;
+      let t = &self.terms[t];
+      if n != Some(t.args.len() as u64) || t.ret.0 != ret {
+        return Err(format!("load-state: term '{}' has changed", self.data[a].name))
+      }
+    }
+    Ok(())
+  }
+
+  /// Replace the local context with a proof state saved by [`save_state`](Self::save_state).
+  /// The environment should already contain the declarations of the source file of the state.
+  pub fn load_state(&mut self, sp: Span, state: &Value) -> Result<(), String> {
+    match state.get("version").and_then(Value::as_u64) {
+      Some(VERSION) => {}
+      Some(v) => return Err(format!("load-state: unsupported version {}", v)),
+      None => return Err("load-state: not a saved proof state".into()),
+    }
+    if let Some(env) = state.get("env") { self.check_slice(env)? }
+    self.lc.clear();
+    for v in Self::list(state, "vars")? {
+      let a = self.load_atom(&v["name"])?;
+      let s = self.load_sort(&v["sort"])?;
+      let is = if v["bound"].as_bool() == Some(true) {InferSort::Bound(s)} else {
+        InferSort::Reg(s, Self::list(v, "deps")?.iter()
+          .map(|d| self.load_atom(d)).collect::<Result<_, _>>()?)
+      };
+      let dummy = v["dummy"].as_bool() == Some(true);
+      if !dummy { self.lc.var_order.push((sp, Some(a), None)) }
+      self.lc.vars.insert(a, (dummy, is));
+    }
+    for v in Self::list(state, "mvars")? {
+      let tgt = if let Some(s) = v.get("sort") {
+        let a = self.load_atom(s)?;
+        if v["bound"].as_bool() == Some(true) {InferTarget::Bound(a)} else {InferTarget::Reg(a)}
+      } else if v.get("provable").is_some() {InferTarget::Provable} else {InferTarget::Unknown};
+      let fsp = self.fspan(sp);
+      self.lc.new_mvar(tgt, Some(fsp));
+    }
+    let mvars = self.lc.mvars.clone();
+    for v in Self::list(state, "hyps")? {
+      let a = self.load_atom(&v["name"])?;
+      let e = self.load_expr(&mvars, &v["type"])?;
+      self.lc.add_proof(a, e, LispVal::atom(a));
+    }
+    let goals = Self::list(state, "goals")?.iter().map(|v| {
+      let e = self.load_expr(&mvars, v)?;
+      Ok(LispVal::goal(self.fspan(sp), e))
+    }).collect::<Result<Vec<_>, String>>()?;
+    self.lc.set_goals(goals);
+    Ok(())
+  }
+}
@@ -6,15 +6,26 @@
 //! [`mm1.md`]: https://github.com/digama0/mm0/blob/master/mm0-hs/mm1.md#pre-expressions
 
 use std::result::Result as StdResult;
+use std::collections::{HashMap, HashSet};
 use crate::util::{FileSpan, Span};
 use super::{Elaborator, ElabError, Result};
 use super::environment::{AtomID, TermKind, DeclKey, Modifiers,
   ObjectKind, SortID, TermID, ThmID, Type};
 use super::lisp::{InferTarget, LispKind, LispRef, LispVal, Uncons,
-  print::{FormatEnv, EnvDisplay}, eval::SResult};
+  print::{FormatEnv, EnvDisplay}};
 use super::local_context::{InferSort, try_get_span};
 use super::proof::Subst;
 
+/// The definitions to unfold, for the `(unfold)` and `(unfold-head)` tactics.
+#[derive(Debug)]
+pub enum UnfoldDefs {
+  /// All definitions, except for `abstract` definitions and `local` definitions from
+  /// other files, which are only unfolded when requested by name.
+  All,
+  /// Only the given definitions.
+  Only(HashSet<TermID>),
+}
+
 /// The inference mode on an application, which determines which arguments are being
 /// omitted and which provided explicitly.
 #[derive(Copy, Clone, Debug)]
@@ -186,6 +197,7 @@ pub(crate) enum RState {
   },
   /// Elaborate the unelaborated proof `p` against the target type `tgt`.
   /// ```text
+  /// RState::RefineProof(tgt, '_) := return RState::Proc(tgt, tactic) if tgt has a tactic
   /// RState::RefineProof(tgt, '_) := return new_goal(tgt)
   /// RState::RefineProof(tgt, '(!im thm args)) :=
   ///   return RState::RefineBis(tgt, im, thm, [thm], args)
@@ -434,6 +446,23 @@ impl Elaborator {
     r
   }
 
+  /// Close a goal of type `ty` by [`admit`](super::lisp::BuiltinProc::Admit). The result
+  /// is an unassignable goal object, which serves as the proof; it is recorded in the local
+  /// context so that the theorem is added without a proof.
+  pub(crate) fn admit(&mut self, sp: Span, ty: LispVal) -> LispVal {
+    self.lc.admits.push((sp, ty.clone()));
+    LispVal::goal(self.fspan(sp), ty)
+  }
+
+  /// Get the procedure registered with `register-tactic` for goals of type `ty`, if any.
+  fn goal_tactic(&self, ty: &LispVal) -> Option<LispVal> {
+    let a = ty.unwrapped(|e| match e {
+      LispKind::List(es) => es.first()?.as_atom(),
+      _ => None
+    })?;
+    self.tactics.get(&self.term(a)?).cloned()
+  }
+
   /// Get the sort of the term `e` (with only minimal type-checking).
   pub fn infer_target(&self, sp: Span, e: &LispVal) -> Result<InferTarget> {
     macro_rules! err {
@@ -559,14 +588,14 @@ impl Elaborator {
 
   /// Unify expressions `e1` and `e2`. Returns a conversion proof
   /// `u: e1 = e2`, with `#undef` meaning that `e1` and `e2` are equal after unification.
-  fn unify1(&mut self, e1: &LispVal, e2: &LispVal) -> SResult<LispVal> {
+  fn unify1(&mut self, e1: &LispVal, e2: &LispVal) -> StdResult<LispVal, String> {
     self.unify_core(e1, e2).map_err(|e| self.format_env().pretty(|p|
       format!("{}\n{}", p.unify_err(e1, e2).pretty(80).to_string(), e)))
   }
 
   /// Unify expressions `e1` and `e2`. Returns a conversion proof
   /// `u: e1 = e2`, with `#undef` meaning that `e1` and `e2` are equal after unification.
-  fn unify_core(&mut self, e1: &LispVal, e2: &LispVal) -> SResult<LispVal> {
+  fn unify_core(&mut self, e1: &LispVal, e2: &LispVal) -> StdResult<LispVal, String> {
     // println!("{} =?= {}", self.format_env().pp(e1, 80), self.format_env().pp(e2, 80));
     // (|| {
     if e1.ptr_eq(e2) {return Ok(LispVal::undef())}
@@ -646,8 +675,47 @@ impl Elaborator {
     // })
   }
 
+  /// Unify `e1` and `e2`, for the `unify` builtin. On success, returns the metavariables
+  /// that were assigned by the unification (by index) together with their values.
+  /// On failure, the partial assignments are undone and `None` is returned.
+  pub fn unify_mvars(&mut self, e1: &LispVal, e2: &LispVal) -> Option<Vec<(usize, LispVal)>> {
+    let old: Vec<LispVal> = self.lc.mvars.iter()
+      .map(|m| m.as_ref_(|e| e.clone()).expect("mvar is a ref")).collect();
+    if self.unify_core(e1, e2).is_ok() {
+      Some(self.lc.mvars.iter().zip(old).filter_map(|(m, old)| {
+        let n = old.unwrapped(|e| if let LispKind::MVar(n, _) = *e {Some(n)} else {None})?;
+        let e = m.as_ref_(|e| e.clone()).expect("mvar is a ref");
+        if e.is_mvar() {None} else {Some((n, e))}
+      }).collect())
+    } else {
+      for (m, old) in self.lc.mvars.iter().zip(old) {
+        m.as_ref_(|e| *e = old).expect("mvar is a ref")
+      }
+      None
+    }
+  }
+
+  /// Match the expression `e` against the pattern `pat`, for the `match-term` builtin.
+  /// The unassigned metavariables in `pat` are the pattern variables, and they are bound
+  /// in `subst` (by index) instead of being assigned. The match is syntactic: definitions
+  /// are not unfolded, and the metavariables in `e` only match themselves.
+  pub fn match_term(&self, pat: &LispVal, e: &LispVal, subst: &mut HashMap<usize, LispVal>) -> bool {
+    if let Some((n, tgt)) = pat.unwrapped(|p|
+      if let LispKind::MVar(n, tgt) = *p {Some((n, tgt))} else {None}) {
+      if let Some(v) = subst.get(&n) { return v == e }
+      if tgt.bound() && e.as_atom().is_none() { return false }
+      subst.insert(n, e.clone());
+      return true
+    }
+    pat.unwrapped(|p| e.unwrapped(|k| match (p, k) {
+      (LispKind::List(ps), LispKind::List(es)) => ps.len() == es.len() &&
+        ps.iter().zip(&**es).all(|(p, e)| self.match_term(p, e, subst)),
+      _ => pat == e,
+    }))
+  }
+
   /// Produce a proof that `(tid u1) = e2` if `sym` is false, or `e2 = (tid u1)` if `sym` is true.
-  fn unfold(&mut self, sym: bool, tid: TermID, u1: &Uncons, e2: &LispVal) -> SResult<LispVal> {
+  fn unfold(&mut self, sym: bool, tid: TermID, u1: &Uncons, e2: &LispVal) -> StdResult<LispVal, String> {
     let tdata = &self.env.terms[tid];
     let a = tdata.atom;
     let nargs = tdata.args.len();
@@ -666,6 +734,78 @@ impl Elaborator {
     }
   }
 
+  /// Returns true if `(unfold defs)` should unfold the definition `t`.
+  fn unfoldable(&self, defs: &UnfoldDefs, t: TermID) -> bool {
+    let tdata = &self.terms[t];
+    matches!(tdata.kind, TermKind::Def(Some(_))) && match defs {
+      UnfoldDefs::All => !tdata.vis.contains(Modifiers::ABSTRACT) &&
+        (!tdata.vis.contains(Modifiers::LOCAL) || tdata.span.file == self.path),
+      UnfoldDefs::Only(ts) => ts.contains(&t),
+    }
+  }
+
+  /// If `e` is an application of one of the definitions `defs`, returns the definition,
+  /// its arguments, and the result of substituting the arguments in the definition.
+  fn unfold_head(&mut self, defs: &UnfoldDefs, e: &LispVal) -> Option<(AtomID, Vec<LispVal>, LispVal)> {
+    let mut u = Uncons::from(e.clone());
+    let a = u.next()?.as_atom()?;
+    let t = self.term(a)?;
+    if !self.unfoldable(defs, t) {return None}
+    let tdata = &self.env.terms[t];
+    let val = if let TermKind::Def(Some(val)) = &tdata.kind {val} else {return None};
+    let mut args = Vec::with_capacity(tdata.args.len());
+    if !u.extend_into(tdata.args.len(), &mut args) {return None}
+    let e1 = Subst::new(&self.env, &val.heap, args.clone()).subst_mut(&mut self.lc, &val.head);
+    Some((a, args, e1))
+  }
+
+  /// Unfold the definition at the head of `e`, for `(unfold-head)`. Returns the unfolded
+  /// expression `e2` and a conversion proof of `e = e2`, or `None` if the head of `e`
+  /// is not one of `defs`.
+  pub fn unfold_once(&mut self, defs: &UnfoldDefs, e: &LispVal) -> Option<(LispVal, LispVal)> {
+    let (a, args, e1) = self.unfold_head(defs, e)?;
+    Some((e1.clone(), LispVal::unfold(a, args, e1)))
+  }
+
+  /// Unfold all occurrences of the definitions `defs` in `e`, including those that appear
+  /// after unfolding, for `(unfold)`. Returns the unfolded expression `e2` and a
+  /// conversion proof of `e = e2`.
+  pub fn unfold_all(&mut self, defs: &UnfoldDefs, e: &LispVal) -> (LispVal, LispVal) {
+    if let Some((a, args, e1)) = self.unfold_head(defs, e) {
+      let (e2, c) = self.unfold_all(defs, &e1);
+      return (e2, LispVal::unfold(a, args, c))
+    }
+    let es = match e.unwrapped(|r| if let LispKind::List(es) = r {Some(es.clone())} else {None}) {
+      Some(es) if !es.is_empty() => es,
+      _ => return (e.clone(), e.clone()),
+    };
+    let (mut es2, mut cs) = (vec![es[0].clone()], vec![es[0].clone()]);
+    for x in &es[1..] {
+      let (x2, c) = self.unfold_all(defs, x);
+      es2.push(x2);
+      cs.push(c);
+    }
+    (LispVal::list(es2), LispVal::list(cs))
+  }
+
+  /// Unfold the definitions `defs` in the type of the first goal, replacing it with a new
+  /// goal for the unfolded type. If `head` is true, only the definition at the head is
+  /// unfolded (and it is an error if there is none).
+  pub fn unfold_goal(&mut self, fsp: FileSpan, defs: &UnfoldDefs, head: bool) -> StdResult<(), String> {
+    let (i, ty) = self.lc.goals.iter().enumerate()
+      .find_map(|(i, g)| Some((i, g.goal_type()?))).ok_or("unfold: no goals")?;
+    let (ty2, c) = if head {
+      self.unfold_once(defs, &ty).ok_or_else(|| format!(
+        "unfold-head: no definition to unfold at the head of {}", self.print(&ty)))?
+    } else {
+      self.unfold_all(defs, &ty)
+    };
+    let g = LispVal::new_ref(LispVal::goal(fsp, ty2));
+    self.lc.goals[i].as_ref_(|e| *e = LispVal::conv(ty, c, g.clone())).expect("a goal is a ref");
+    self.lc.goals[i] = g;
+    Ok(())
+  }
+
   fn type_target(&self, ty: &Type) -> InferTarget {
     match *ty {
       Type::Bound(s) => InferTarget::Bound(self.sorts[s].atom),
@@ -708,9 +848,13 @@ impl Elaborator {
           }
           RefineExpr::App {sp, sp2, head: AtomID::UNDER, u, ..} => {
             if u.is_empty() {
-              let head = self.new_goal(sp, tgt);
-              self.spans.insert_if(sp2, || ObjectKind::proof(head.clone()));
-              RState::Ret(head)
+              if let Some(p) = self.goal_tactic(&tgt) {
+                RState::Proc {tgt, p}
+              } else {
+                let head = self.new_goal(sp, tgt);
+                self.spans.insert_if(sp2, || ObjectKind::proof(head.clone()));
+                RState::Ret(head)
+              }
             } else {
               let mv = self.lc.new_mvar(InferTarget::Unknown, Some(self.fspan(sp2)));
               let head = self.new_goal(sp, mv);
@@ -725,6 +869,8 @@ impl Elaborator {
               RState::RefineArgs {sp, ty: ty.clone(), tgt, p: head, u}
             } else if let Some(DeclKey::Thm(t)) = self.data[a].decl {
               RState::RefineBis {sp, sp2, tgt, im, t, args: vec![head], u}
+            } else if a == AtomID::ADMIT && u.is_empty() {
+              RState::Ret(self.admit(sp, tgt))
             } else {
               return Err(ElabError::new_e(sp2, format!(
                 "unknown theorem/hypothesis '{}'", self.data[a].name)))
@@ -845,6 +991,9 @@ impl Elaborator {
               if let Some(p) = u.next() {
                 stack.push(RStack::RefineHyps {sp, sp2, tgt, t, u, args, hyps, res});
                 break 'l3 RState::RefineProof {tgt: h, p}
+              } else if let Some(p) = self.goal_tactic(&h) {
+                stack.push(RStack::RefineHyps {sp, sp2, tgt, t, u, args, hyps, res});
+                break 'l3 RState::Proc {tgt: h, p}
               } else {
                 args.push(self.new_goal(sp, h))
               }
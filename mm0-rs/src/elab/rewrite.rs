@@ -0,0 +1,202 @@
+//! The `(rw)` tactic, which rewrites the goal using an equality lemma.
+//!
+//! Given a theorem or hypothesis `h` proving `eq l r`, where `eq` is an equality registered
+//! with `register-eq`, `(rw 'h)` finds the first subterm of the goal (in pre-order) that is
+//! an instance of `l`, and replaces it and every other occurrence of the same instance with
+//! the corresponding instance of `r`. `(rw '(<- h))` rewrites from right to left instead,
+//! and `(rw 'h n)` only replaces the `n`th occurrence of the instance, counting from 1.
+//!
+//! The proof that the goal is equal to the rewritten goal is built from the congruence
+//! lemmas registered with `register-congr`, and the goal is then proved from the rewritten
+//! goal using the lemma registered with `register-mp` for the equality on its sort.
+//! The rewritten goal and the hypotheses of `h` (if it is a theorem) become new goals.
+
+use super::{Elaborator, environment::{AtomID, ExprNode, TermID, Thm}};
+use super::cong::{apply_lemma, deref};
+use super::lisp::{LispKind, LispVal};
+
+/// One side of the equality being rewritten with, as a pattern to find in the goal.
+enum Pat {
+  /// A variable of the theorem, by index
+  Var(usize),
+  /// An application of a term constructor
+  App(TermID, Box<[Pat]>),
+  /// A fixed expression, when rewriting with a hypothesis of the current proof
+  Expr(LispVal),
+}
+
+impl Pat {
+  fn from_expr(td: &Thm, e: &ExprNode) -> Option<Pat> {
+    match deref(td, e) {
+      &ExprNode::Ref(i) => Some(Pat::Var(i)),
+      ExprNode::Dummy(_, _) => None,
+      ExprNode::App(t, es) => Some(Pat::App(*t,
+        es.iter().map(|e| Pat::from_expr(td, e)).collect::<Option<_>>()?)),
+    }
+  }
+}
+
+/// The instance being rewritten, and the occurrences to rewrite.
+struct Rewrite {
+  /// The instance of the left side found in the goal
+  from: LispVal,
+  /// The corresponding instance of the right side
+  to: LispVal,
+  /// A proof of `eq from to`
+  step: LispVal,
+  /// The occurrence to rewrite, or `None` for all of them
+  occ: Option<usize>,
+  /// The number of occurrences seen so far
+  count: usize,
+}
+
+impl Elaborator {
+  fn term_app(&self, e: &LispVal) -> Option<(TermID, Box<[LispVal]>)> {
+    e.unwrapped(|k| match k {
+      LispKind::List(es) if !es.is_empty() =>
+        es[0].as_atom().and_then(|a| self.term(a)).map(|t| (t, es.clone())),
+      _ => None
+    })
+  }
+
+  /// Match `e` against the pattern `p`, extending the assignment `subst` of the variables.
+  fn match_pat(&self, p: &Pat, e: &LispVal, subst: &mut [Option<LispVal>]) -> bool {
+    match p {
+      &Pat::Var(i) => match &subst[i] {
+        Some(v) => v == e,
+        None => { subst[i] = Some(e.clone()); true }
+      },
+      Pat::Expr(v) => v == e,
+      Pat::App(t, ps) => match self.term_app(e) {
+        Some((t2, es)) if t2 == *t && es.len() == ps.len() + 1 =>
+          ps.iter().zip(&es[1..]).all(|(p, e)| self.match_pat(p, e, subst)),
+        _ => false
+      }
+    }
+  }
+
+  /// Find the first subterm of `e` in pre-order that matches `p`, returning the assignment.
+  fn find_pat(&self, p: &Pat, e: &LispVal, nargs: usize) -> Option<Vec<Option<LispVal>>> {
+    let mut subst = vec![None; nargs];
+    if self.match_pat(p, e, &mut subst) { return Some(subst) }
+    let (_, es) = self.term_app(e)?;
+    es[1..].iter().find_map(|e| self.find_pat(p, e, nargs))
+  }
+
+  /// Instantiate the pattern `p` with `subst`, or return `None` if a variable is unassigned.
+  fn inst_pat(&self, p: &Pat, subst: &[Option<LispVal>]) -> Option<LispVal> {
+    match p {
+      &Pat::Var(i) => subst[i].clone(),
+      Pat::Expr(v) => Some(v.clone()),
+      Pat::App(t, ps) => {
+        let mut es = vec![LispVal::atom(self.terms[*t].atom)];
+        for p in &**ps { es.push(self.inst_pat(p, subst)?) }
+        Some(LispVal::list(es))
+      }
+    }
+  }
+
+  /// Rewrite the selected occurrences in `e`, returning the new expression and a proof
+  /// of `eq e e'`, or `None` if nothing was rewritten.
+  fn rewrite(&self, rw: &mut Rewrite, e: &LispVal) -> Result<Option<(LispVal, LispVal)>, String> {
+    if *e == rw.from {
+      rw.count += 1;
+      return Ok(if rw.occ.map_or(true, |n| n == rw.count) {
+        Some((rw.to.clone(), rw.step.clone()))
+      } else { None })
+    }
+    let (t, es) = if let Some(x) = self.term_app(e) {x} else {return Ok(None)};
+    let mut new = Vec::with_capacity(es.len() - 1);
+    for x in &es[1..] { new.push(self.rewrite(rw, x)?) }
+    if new.iter().all(Option::is_none) { return Ok(None) }
+    let name = || &self.data[self.terms[t].atom].name;
+    let l = self.eqs.congr.get(&t).ok_or_else(|| format!(
+      "rw: no congruence lemma is registered for '{}'", name()))?;
+    let mut ys = vec![es[0].clone()];
+    let mut subproofs = Vec::with_capacity(new.len());
+    for (i, (x, r)) in es[1..].iter().zip(new).enumerate() {
+      subproofs.push(match r {
+        Some(_) if l.fixed(i) => return Err(format!(
+          "rw: cannot rewrite argument {} of '{}', which must be the same on both sides", i + 1, name())),
+        Some((y, p)) => { ys.push(y); p }
+        None => {
+          ys.push(x.clone());
+          if l.fixed(i) {LispVal::undef()} else {
+            let s = self.terms[t].args[i].1.sort();
+            let eq = self.eqs.sorts.get(&s).ok_or_else(|| format!(
+              "rw: no equality is registered for sort '{}'", self.sorts[s].name))?;
+            apply_lemma(self, &self.eqs.eqs[eq].refl, &[x.clone()], &[])
+          }
+        }
+      })
+    }
+    let roles: Vec<_> = es[1..].iter().chain(&ys[1..]).cloned().collect();
+    Ok(Some((LispVal::list(ys), apply_lemma(self, l, &roles, &subproofs))))
+  }
+
+  /// Rewrite the goal `ty` with the equality `lemma`, which is either the name `h` of a
+  /// theorem or hypothesis, or `(<- h)` to rewrite from right to left. If `occ` is given,
+  /// only that occurrence (counting from 1) is rewritten.
+  /// Returns a proof term for `refine`, whose holes are the new goals.
+  pub fn rw_proof(&self, ty: &LispVal, lemma: &LispVal, occ: Option<usize>) -> Result<LispVal, String> {
+    let (rev, h) = match lemma.unwrapped(|k| match k {
+      LispKind::List(es) if es.len() == 2 && es[0].as_atom()
+        .map_or(false, |a| self.data[a].name.as_str() == "<-") => Some(es[1].as_atom()),
+      _ => None
+    }) {
+      Some(h) => (true, h),
+      None => (false, lemma.as_atom()),
+    };
+    let h = h.ok_or("rw: expected a theorem or hypothesis name")?;
+    let is_eq = |t: TermID, n: usize| n == 2 &&
+      self.eqs.eqs.get(&t).map_or(false, |rel| self.eqs.sorts.get(&rel.sort) == Some(&t));
+    let (eq, lhs, rhs, thm) = if let Some((_, e, _)) = self.lc.get_proof(h) {
+      match self.term_app(e) {
+        Some((t, es)) if is_eq(t, es.len() - 1) =>
+          (t, Pat::Expr(es[1].clone()), Pat::Expr(es[2].clone()), None),
+        _ => return Err(format!("rw: '{}' is not a registered equality", self.data[h].name))
+      }
+    } else if let Some(thm) = self.thm(h) {
+      let td = &self.thms[thm];
+      let err = || format!("rw: '{}' does not prove a registered equality", self.data[h].name);
+      let (eq, l, r) = match deref(td, &td.ret) {
+        ExprNode::App(t, es) if is_eq(*t, es.len()) => (*t, &es[0], &es[1]),
+        _ => return Err(err())
+      };
+      let pat = |e: &ExprNode| Pat::from_expr(td, e).ok_or_else(err);
+      (eq, pat(l)?, pat(r)?, Some(td))
+    } else {
+      return Err(format!("rw: unknown theorem or hypothesis '{}'", self.data[h].name))
+    };
+    let from = if rev {&rhs} else {&lhs};
+    let subst = self.find_pat(from, ty, thm.map_or(0, |td| td.args.len())).ok_or_else(|| format!(
+      "rw: no instance of the {} side of '{}' in {}",
+      if rev {"right"} else {"left"}, self.data[h].name, self.print(ty)))?;
+    let inst = |p| self.inst_pat(p, &subst).ok_or_else(|| format!(
+      "rw: the variables of '{}' are not determined by the {} side",
+      self.data[h].name, if rev {"right"} else {"left"}));
+    let (l, r) = (inst(&lhs)?, inst(&rhs)?);
+    let mut step = LispVal::atom(h);
+    if let Some(td) = thm {
+      let under = || LispVal::atom(AtomID::UNDER);
+      let mut es = vec![step];
+      es.extend(subst.iter().map(|e| e.clone().unwrap_or_else(under)));
+      es.extend(td.hyps.iter().map(|_| under()));
+      step = LispVal::list(es)
+    }
+    if rev { step = apply_lemma(self, &self.eqs.eqs[&eq].symm, &[l.clone(), r.clone()], &[step]) }
+    let (from, to) = if rev {(r, l)} else {(l, r)};
+    let mut rw = Rewrite {from, to, step, occ, count: 0};
+    let (ty2, p) = self.rewrite(&mut rw, ty)?.ok_or_else(|| match occ {
+      Some(_) if rw.count > 0 => format!("rw: there are only {} occurrences", rw.count),
+      _ => "rw: nothing to rewrite".into(),
+    })?;
+    let s = match self.term_app(ty) {
+      Some((t, _)) => self.terms[t].ret.0,
+      None => return Err("rw: the goal is not an application of a term".into()),
+    };
+    let mp = self.eqs.sorts.get(&s).and_then(|eq| self.eqs.mp.get(eq)).ok_or_else(|| format!(
+      "rw: no lemma is registered with register-mp for sort '{}'", self.sorts[s].name))?;
+    Ok(apply_lemma(self, mp, &[ty.clone(), ty2], &[p, LispVal::atom(AtomID::UNDER)]))
+  }
+}
@@ -0,0 +1,1031 @@
+//! A versioned binary serialization of an [`Environment`], used by the
+//! [compilation cache](crate::cache) to save the result of elaborating a file.
+//!
+//! Everything that an importing file can see is saved: the sorts, terms and theorems
+//! (with their proofs), the notations, the `output string` directives, the lisp globals
+//! and the lemmas registered for `(cong)` and `(norm-num)`. The hover information
+//! ([`Environment::spans`]) and the resume points are not saved, so an environment that is
+//! read back is only good for importing, not for the language server features of the file
+//! itself.
+//!
+//! Lisp values are saved with their sharing, so that a value that is referenced from
+//! several places is read back as a single value. Mutable references (the only way to make
+//! a cycle) are numbered when they are first seen, and their contents are written after the
+//! global definition that contains them. Values that cannot be saved (the MMC compiler) make
+//! [`write_env`] fail, in which case the file is just not cached.
+//!
+//! The format is only meant to be read back by the same version of `mm0-rs`, and any
+//! change to it should bump [`VERSION`].
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::hash::Hash;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::rc::{Rc, Weak};
+use std::sync::Arc;
+use byteorder::{LE, ReadBytesExt, WriteBytesExt};
+use num::BigInt;
+use crate::util::{ArcString, FileRef, FileSpan, Span};
+use super::FrozenEnv;
+use super::environment::{AtomData, AtomID, AtomVec, Coe, DeclKey, Delims, Environment,
+  Expr, ExprNode, LispData, Literal, Modifiers, NotaInfo, OutputString, ParserEnv, Prec,
+  Proof, ProofNode, Sort, SortID, SortVec, StmtTrace, Term, TermID, TermKind, TermVec,
+  Thm, ThmID, ThmKind, ThmVec, Type};
+use super::cong::{EqLemma, EqLemmas, EqRel};
+use super::norm_num::{NumLemma, NumLemmas, NumRule, NumTerm};
+use super::lisp::{Annot, BuiltinProc, ContExpiry, InferTarget, LispKind, LispRef, LispVal,
+  LispWeak, MatchCont, Proc, ProcPos, ProcSpec, Promise, Syntax};
+use super::lisp::parser::{Branch, IR, MVarPattern, Pattern};
+
+/// The magic number at the start of a serialized environment.
+pub const MAGIC: [u8; 4] = *b"MM1E";
+
+/// The version of the format. Data written with a different version is rejected by
+/// [`read_env`].
+pub const VERSION: u32 = 1;
+
+fn invalid(msg: &str) -> io::Error { io::Error::new(io::ErrorKind::InvalidData, msg) }
+
+/// The state of the writer: the underlying stream, and the tables of files and lisp
+/// values written so far.
+struct Encoder<W> {
+  w: W,
+  /// The files that have been written, by index.
+  files: HashMap<FileRef, u32>,
+  /// The lisp values that have been written, by index.
+  lisp: HashMap<*const LispKind, u32>,
+  /// The number of lisp values that have been assigned an index.
+  nlisp: u32,
+  /// The references whose contents have not been written yet. These point into the
+  /// environment being written, which outlives the encoder.
+  refs: VecDeque<*const LispRef>,
+}
+
+/// The state of the reader.
+struct Decoder<'a> {
+  buf: &'a [u8],
+  /// Converts the path of a file to a [`FileRef`].
+  file: &'a mut dyn FnMut(PathBuf) -> io::Result<FileRef>,
+  /// The files that have been read, by index.
+  files: Vec<FileRef>,
+  /// The lisp values that have been read, by index.
+  lisp: Vec<LispVal>,
+  /// The references whose contents have not been read yet.
+  refs: VecDeque<LispVal>,
+  /// The number of atoms, sorts, terms and theorems, used to validate IDs.
+  counts: (usize, usize, usize, usize),
+}
+
+/// A type that can be written to and read from the binary format.
+trait Bin: Sized {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()>;
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self>;
+}
+
+impl<W: Write> Encoder<W> {
+  fn u8(&mut self, n: u8) -> io::Result<()> { self.w.write_u8(n) }
+  fn u32(&mut self, n: u32) -> io::Result<()> { self.w.write_u32::<LE>(n) }
+  fn u64(&mut self, n: u64) -> io::Result<()> { self.w.write_u64::<LE>(n) }
+  fn len(&mut self, n: usize) -> io::Result<()> { self.u64(n as u64) }
+  fn bytes(&mut self, s: &[u8]) -> io::Result<()> { self.len(s.len())?; self.w.write_all(s) }
+  fn slice<T: Bin>(&mut self, es: &[T]) -> io::Result<()> {
+    self.len(es.len())?;
+    for e in es { e.write(self)? }
+    Ok(())
+  }
+
+  /// Write a lisp value. A value that has already been written is written as a
+  /// back-reference to its index, and the contents of references are deferred
+  /// until [`flush_refs`](Self::flush_refs).
+  fn lisp(&mut self, e: &LispKind) -> io::Result<()> {
+    let ptr: *const LispKind = e;
+    if let Some(&i) = self.lisp.get(&ptr) { self.u8(0xff)?; return self.u32(i) }
+    if let LispKind::Ref(m) = e {
+      self.lisp.insert(ptr, self.nlisp);
+      self.nlisp += 1;
+      self.refs.push_back(m);
+      return self.u8(13)
+    }
+    match e {
+      LispKind::Atom(a) => { self.u8(0)?; a.write(self)? }
+      LispKind::List(es) => { self.u8(1)?; self.slice(es)? }
+      LispKind::DottedList(es, r) => { self.u8(2)?; self.slice(es)?; r.write(self)? }
+      LispKind::Vector(es) => { self.u8(3)?; self.slice(es)? }
+      LispKind::Annot(Annot::Span(fsp), v) => { self.u8(4)?; fsp.write(self)?; v.write(self)? }
+      LispKind::Number(n) => { self.u8(5)?; n.write(self)? }
+      &LispKind::SmallInt(n) => { self.u8(6)?; self.w.write_i64::<LE>(n)? }
+      LispKind::String(s) => { self.u8(7)?; s.write(self)? }
+      LispKind::Bool(b) => { self.u8(8)?; b.write(self)? }
+      LispKind::Syntax(s) => { self.u8(9)?; s.write(self)? }
+      LispKind::Undef => self.u8(10)?,
+      LispKind::Proc(p) => { self.u8(11)?; p.write(self)? }
+      LispKind::AtomMap(m) => { self.u8(12)?; m.write(self)? }
+      LispKind::Ref(_) => unreachable!(),
+      LispKind::MVar(n, is) => { self.u8(14)?; n.write(self)?; is.write(self)? }
+      LispKind::Goal(e) => { self.u8(15)?; e.write(self)? }
+    }
+    self.lisp.insert(ptr, self.nlisp);
+    self.nlisp += 1;
+    Ok(())
+  }
+
+  /// Write the contents of the references found so far (and the references found in them).
+  fn flush_refs(&mut self) -> io::Result<()> {
+    while let Some(m) = self.refs.pop_front() {
+      // Safety: the environment is frozen, so the reference is still alive and not borrowed
+      match unsafe { (*m).get_weak_unsafe() } {
+        LispWeak::Strong(e) => { self.u8(0)?; self.lisp(e)? }
+        LispWeak::Weak(e) if e.strong_count() == 0 => self.u8(2)?,
+        LispWeak::Weak(e) => { self.u8(1)?; self.lisp(unsafe { &*e.as_ptr() })? }
+      }
+    }
+    Ok(())
+  }
+}
+
+impl Decoder<'_> {
+  fn u8(&mut self) -> io::Result<u8> { self.buf.read_u8() }
+  fn u32(&mut self) -> io::Result<u32> { self.buf.read_u32::<LE>() }
+  fn u64(&mut self) -> io::Result<u64> { self.buf.read_u64::<LE>() }
+  fn len(&mut self) -> io::Result<usize> {
+    match usize::try_from(self.u64()?) {
+      Ok(n) if n <= self.buf.len() => Ok(n),
+      _ => Err(invalid("bad length"))
+    }
+  }
+  fn bytes(&mut self) -> io::Result<Vec<u8>> {
+    let mut s = vec![0; self.len()?];
+    self.buf.read_exact(&mut s)?;
+    Ok(s)
+  }
+  fn vec<T: Bin>(&mut self) -> io::Result<Vec<T>> {
+    let n = self.len()?;
+    (0..n).map(|_| T::read(self)).collect()
+  }
+
+  /// Read a lisp value written by [`Encoder::lisp`].
+  fn lisp(&mut self) -> io::Result<LispVal> {
+    let v = match self.u8()? {
+      0xff => {
+        let i = self.u32()? as usize;
+        return self.lisp.get(i).cloned().ok_or_else(|| invalid("bad lisp reference"))
+      }
+      13 => {
+        let r = LispVal::new_ref(LispVal::undef());
+        self.lisp.push(r.clone());
+        self.refs.push_back(r.clone());
+        return Ok(r)
+      }
+      0 => LispVal::atom(AtomID::read(self)?),
+      1 => LispVal::list(self.vec()?),
+      2 => { let es = self.vec::<LispVal>()?; LispVal::dotted_list(es, LispVal::read(self)?) }
+      3 => LispVal::vector(self.vec()?),
+      4 => {
+        let fsp = FileSpan::read(self)?;
+        LispVal::new(LispKind::Annot(Annot::Span(fsp), LispVal::read(self)?))
+      }
+      5 => LispVal::number(BigInt::read(self)?),
+      6 => LispVal::small_int(self.buf.read_i64::<LE>()?),
+      7 => LispVal::string(ArcString::read(self)?),
+      8 => LispVal::bool(bool::read(self)?),
+      9 => LispVal::syntax(Syntax::read(self)?),
+      10 => LispVal::undef(),
+      11 => LispVal::proc(Proc::read(self)?),
+      12 => LispVal::new(LispKind::AtomMap(HashMap::read(self)?)),
+      14 => {
+        let n = usize::read(self)?;
+        LispVal::new(LispKind::MVar(n, InferTarget::read(self)?))
+      }
+      15 => LispVal::new(LispKind::Goal(LispVal::read(self)?)),
+      _ => return Err(invalid("bad lisp value"))
+    };
+    self.lisp.push(v.clone());
+    Ok(v)
+  }
+
+  /// Read the contents of the references read so far, written by [`Encoder::flush_refs`].
+  fn flush_refs(&mut self) -> io::Result<()> {
+    while let Some(r) = self.refs.pop_front() {
+      let w = match self.u8()? {
+        0 => LispWeak::Strong(self.lisp()?),
+        1 => { let e = self.lisp()?; r.as_lref(|m| m.set_weak(&e)); continue }
+        2 => LispWeak::Weak(Weak::new()),
+        _ => return Err(invalid("bad reference"))
+      };
+      r.as_lref(|m| *m.get_mut_weak() = w);
+    }
+    Ok(())
+  }
+
+  /// Read an ID, checking that it is less than `n`.
+  fn id(&mut self, n: usize) -> io::Result<u32> {
+    let i = self.u32()?;
+    if (i as usize) < n { Ok(i) } else { Err(invalid("ID out of range")) }
+  }
+}
+
+impl Bin for u8 {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> { e.u8(*self) }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> { d.u8() }
+}
+impl Bin for u32 {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> { e.u32(*self) }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> { d.u32() }
+}
+impl Bin for u64 {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> { e.u64(*self) }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> { d.u64() }
+}
+impl Bin for usize {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> { e.u64(*self as u64) }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    usize::try_from(d.u64()?).map_err(|_| invalid("bad number"))
+  }
+}
+impl Bin for bool {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> { e.u8((*self).into()) }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> { Ok(d.u8()? != 0) }
+}
+impl Bin for ArcString {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> { e.bytes(self) }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> { Ok(d.bytes()?.into()) }
+}
+impl Bin for Arc<str> {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> { e.bytes(self.as_bytes()) }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    Ok(String::from_utf8(d.bytes()?).map_err(|_| invalid("bad string"))?.into())
+  }
+}
+impl Bin for BigInt {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    e.bytes(&self.to_signed_bytes_le())
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> { Ok(BigInt::from_signed_bytes_le(&d.bytes()?)) }
+}
+impl<T: Bin> Bin for Option<T> {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    match self { None => e.u8(0), Some(x) => { e.u8(1)?; x.write(e) } }
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    Ok(if bool::read(d)? { Some(T::read(d)?) } else { None })
+  }
+}
+impl<T: Bin> Bin for Vec<T> {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> { e.slice(self) }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> { d.vec() }
+}
+impl<T: Bin> Bin for Box<[T]> {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> { e.slice(self) }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> { Ok(d.vec()?.into()) }
+}
+impl<T: Bin> Bin for Box<T> {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> { (**self).write(e) }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> { Ok(Box::new(T::read(d)?)) }
+}
+impl<T: Bin> Bin for Arc<T> {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> { (**self).write(e) }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> { Ok(Arc::new(T::read(d)?)) }
+}
+impl<A: Bin, B: Bin> Bin for (A, B) {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    self.0.write(e)?; self.1.write(e)
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> { Ok((A::read(d)?, B::read(d)?)) }
+}
+impl<A: Bin, B: Bin, C: Bin> Bin for (A, B, C) {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    self.0.write(e)?; self.1.write(e)?; self.2.write(e)
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> { Ok((A::read(d)?, B::read(d)?, C::read(d)?)) }
+}
+impl<A: Bin, B: Bin, C: Bin, D: Bin> Bin for (A, B, C, D) {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    self.0.write(e)?; self.1.write(e)?; self.2.write(e)?; self.3.write(e)
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    Ok((A::read(d)?, B::read(d)?, C::read(d)?, D::read(d)?))
+  }
+}
+impl<K: Bin + Hash + Eq, V: Bin> Bin for HashMap<K, V> {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    e.len(self.len())?;
+    for (k, v) in self { k.write(e)?; v.write(e)? }
+    Ok(())
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    let n = d.len()?;
+    (0..n).map(|_| <(K, V)>::read(d)).collect()
+  }
+}
+
+impl Bin for Span {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    self.start.write(e)?; self.end.write(e)
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    Ok(Span {start: usize::read(d)?, end: usize::read(d)?})
+  }
+}
+impl Bin for FileRef {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    #[allow(clippy::cast_possible_truncation)]
+    let n = e.files.len() as u32;
+    match e.files.get(self) {
+      Some(&i) => e.u32(i),
+      None => {
+        e.files.insert(self.clone(), n);
+        e.u32(n)?;
+        e.bytes(self.path().to_str().ok_or_else(||
+          io::Error::new(io::ErrorKind::InvalidInput, "bad unicode in path"))?.as_bytes())
+      }
+    }
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    let i = d.u32()? as usize;
+    if i < d.files.len() { return Ok(d.files[i].clone()) }
+    if i > d.files.len() { return Err(invalid("bad file reference")) }
+    let path = String::from_utf8(d.bytes()?).map_err(|_| invalid("bad path"))?;
+    let f = (d.file)(path.into())?;
+    d.files.push(f.clone());
+    Ok(f)
+  }
+}
+impl Bin for FileSpan {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    self.file.write(e)?; self.span.write(e)
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    Ok(FileSpan {file: FileRef::read(d)?, span: Span::read(d)?})
+  }
+}
+
+impl Bin for SortID {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> { e.u8(self.0) }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    let i = d.u8()?;
+    if (i as usize) < d.counts.1 { Ok(SortID(i)) } else { Err(invalid("ID out of range")) }
+  }
+}
+impl Bin for AtomID {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> { e.u32(self.0) }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> { Ok(AtomID(d.id(d.counts.0)?)) }
+}
+impl Bin for TermID {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> { e.u32(self.0) }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> { Ok(TermID(d.id(d.counts.2)?)) }
+}
+impl Bin for ThmID {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> { e.u32(self.0) }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> { Ok(ThmID(d.id(d.counts.3)?)) }
+}
+
+impl Bin for Modifiers {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> { e.u8(self.bits()) }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    Modifiers::from_bits(d.u8()?).ok_or_else(|| invalid("bad modifiers"))
+  }
+}
+impl Bin for Prec {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    match *self { Prec::Prec(n) => { e.u8(0)?; e.u32(n) } Prec::Max => e.u8(1) }
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    match d.u8()? { 0 => Ok(Prec::Prec(d.u32()?)), 1 => Ok(Prec::Max), _ => Err(invalid("bad prec")) }
+  }
+}
+impl Bin for Type {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    match self {
+      Type::Bound(s) => { e.u8(0)?; s.write(e) }
+      Type::Reg(s, deps) => { e.u8(1)?; s.write(e)?; deps.write(e) }
+    }
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    match d.u8()? {
+      0 => Ok(Type::Bound(SortID::read(d)?)),
+      1 => Ok(Type::Reg(SortID::read(d)?, d.u64()?)),
+      _ => Err(invalid("bad type"))
+    }
+  }
+}
+impl Bin for ExprNode {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    match self {
+      ExprNode::Ref(i) => { e.u8(0)?; i.write(e) }
+      ExprNode::Dummy(a, s) => { e.u8(1)?; a.write(e)?; s.write(e) }
+      ExprNode::App(t, es) => { e.u8(2)?; t.write(e)?; es.write(e) }
+    }
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    match d.u8()? {
+      0 => Ok(ExprNode::Ref(usize::read(d)?)),
+      1 => Ok(ExprNode::Dummy(AtomID::read(d)?, SortID::read(d)?)),
+      2 => Ok(ExprNode::App(TermID::read(d)?, Bin::read(d)?)),
+      _ => Err(invalid("bad expr"))
+    }
+  }
+}
+impl Bin for ProofNode {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    match self {
+      ProofNode::Ref(i) => { e.u8(0)?; i.write(e) }
+      ProofNode::Dummy(a, s) => { e.u8(1)?; a.write(e)?; s.write(e) }
+      ProofNode::Term {term, args} => { e.u8(2)?; term.write(e)?; args.write(e) }
+      ProofNode::Hyp(i, p) => { e.u8(3)?; i.write(e)?; p.write(e) }
+      ProofNode::Thm {thm, args, res} => { e.u8(4)?; thm.write(e)?; args.write(e)?; res.write(e) }
+      ProofNode::Conv(p) => { e.u8(5)?; p.write(e) }
+      ProofNode::Refl(p) => { e.u8(6)?; p.write(e) }
+      ProofNode::Sym(p) => { e.u8(7)?; p.write(e) }
+      ProofNode::Cong {term, args} => { e.u8(8)?; term.write(e)?; args.write(e) }
+      ProofNode::Unfold {term, args, res} => { e.u8(9)?; term.write(e)?; args.write(e)?; res.write(e) }
+    }
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    Ok(match d.u8()? {
+      0 => ProofNode::Ref(usize::read(d)?),
+      1 => ProofNode::Dummy(AtomID::read(d)?, SortID::read(d)?),
+      2 => ProofNode::Term {term: TermID::read(d)?, args: Bin::read(d)?},
+      3 => ProofNode::Hyp(usize::read(d)?, Bin::read(d)?),
+      4 => ProofNode::Thm {thm: ThmID::read(d)?, args: Bin::read(d)?, res: Bin::read(d)?},
+      5 => ProofNode::Conv(Bin::read(d)?),
+      6 => ProofNode::Refl(Bin::read(d)?),
+      7 => ProofNode::Sym(Bin::read(d)?),
+      8 => ProofNode::Cong {term: TermID::read(d)?, args: Bin::read(d)?},
+      9 => ProofNode::Unfold {term: TermID::read(d)?, args: Bin::read(d)?, res: Bin::read(d)?},
+      _ => return Err(invalid("bad proof"))
+    })
+  }
+}
+
+impl Bin for Sort {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    self.atom.write(e)?; self.name.write(e)?; self.span.write(e)?;
+    self.full.write(e)?; self.doc.write(e)?; self.mods.write(e)
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    Ok(Sort {
+      atom: Bin::read(d)?, name: Bin::read(d)?, span: Bin::read(d)?,
+      full: Bin::read(d)?, doc: Bin::read(d)?, mods: Bin::read(d)?,
+    })
+  }
+}
+impl Bin for Term {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    self.atom.write(e)?; self.span.write(e)?; self.vis.write(e)?; self.full.write(e)?;
+    self.doc.write(e)?; self.args.write(e)?; self.ret.write(e)?;
+    match &self.kind {
+      TermKind::Term => e.u8(0),
+      TermKind::Def(None) => e.u8(1),
+      TermKind::Def(Some(Expr {heap, head})) => { e.u8(2)?; heap.write(e)?; head.write(e) }
+    }
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    Ok(Term {
+      atom: Bin::read(d)?, span: Bin::read(d)?, vis: Bin::read(d)?, full: Bin::read(d)?,
+      doc: Bin::read(d)?, args: Bin::read(d)?, ret: Bin::read(d)?,
+      kind: match d.u8()? {
+        0 => TermKind::Term,
+        1 => TermKind::Def(None),
+        2 => TermKind::Def(Some(Expr {heap: Bin::read(d)?, head: Bin::read(d)?})),
+        _ => return Err(invalid("bad term"))
+      }
+    })
+  }
+}
+impl Bin for Thm {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    self.atom.write(e)?; self.span.write(e)?; self.vis.write(e)?; self.full.write(e)?;
+    self.doc.write(e)?; self.args.write(e)?; self.heap.write(e)?; self.hyps.write(e)?;
+    self.ret.write(e)?;
+    match &self.kind {
+      ThmKind::Axiom => e.u8(0),
+      ThmKind::Thm(None) => e.u8(1),
+      ThmKind::Thm(Some(Proof {heap, hyps, head})) => {
+        e.u8(2)?; heap.write(e)?; hyps.write(e)?; head.write(e)
+      }
+    }
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    Ok(Thm {
+      atom: Bin::read(d)?, span: Bin::read(d)?, vis: Bin::read(d)?, full: Bin::read(d)?,
+      doc: Bin::read(d)?, args: Bin::read(d)?, heap: Bin::read(d)?, hyps: Bin::read(d)?,
+      ret: Bin::read(d)?,
+      kind: match d.u8()? {
+        0 => ThmKind::Axiom,
+        1 => ThmKind::Thm(None),
+        2 => ThmKind::Thm(Some(Proof {heap: Bin::read(d)?, hyps: Bin::read(d)?, head: Bin::read(d)?})),
+        _ => return Err(invalid("bad theorem"))
+      }
+    })
+  }
+}
+impl Bin for StmtTrace {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    match self {
+      StmtTrace::Sort(a) => { e.u8(0)?; a.write(e) }
+      StmtTrace::Decl(a) => { e.u8(1)?; a.write(e) }
+      StmtTrace::Global(a) => { e.u8(2)?; a.write(e) }
+      StmtTrace::OutputString(s) => {
+        e.u8(3)?; s.span.write(e)?; s.heap.write(e)?; s.exprs.write(e)
+      }
+    }
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    Ok(match d.u8()? {
+      0 => StmtTrace::Sort(Bin::read(d)?),
+      1 => StmtTrace::Decl(Bin::read(d)?),
+      2 => StmtTrace::Global(Bin::read(d)?),
+      3 => StmtTrace::OutputString(Box::new(OutputString {
+        span: Bin::read(d)?, heap: Bin::read(d)?, exprs: Bin::read(d)?
+      })),
+      _ => return Err(invalid("bad statement"))
+    })
+  }
+}
+impl Bin for DeclKey {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    match self {
+      DeclKey::Term(t) => { e.u8(0)?; t.write(e) }
+      DeclKey::Thm(t) => { e.u8(1)?; t.write(e) }
+    }
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    match d.u8()? {
+      0 => Ok(DeclKey::Term(Bin::read(d)?)),
+      1 => Ok(DeclKey::Thm(Bin::read(d)?)),
+      _ => Err(invalid("bad declaration"))
+    }
+  }
+}
+
+impl Bin for Literal {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    match self {
+      Literal::Var(i, p) => { e.u8(0)?; i.write(e)?; p.write(e) }
+      Literal::Const(s) => { e.u8(1)?; s.write(e) }
+    }
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    match d.u8()? {
+      0 => Ok(Literal::Var(Bin::read(d)?, Bin::read(d)?)),
+      1 => Ok(Literal::Const(Bin::read(d)?)),
+      _ => Err(invalid("bad literal"))
+    }
+  }
+}
+impl Bin for NotaInfo {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    self.span.write(e)?; self.term.write(e)?; self.nargs.write(e)?;
+    self.rassoc.write(e)?; self.lits.write(e)
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    Ok(NotaInfo {
+      span: Bin::read(d)?, term: Bin::read(d)?, nargs: Bin::read(d)?,
+      rassoc: Bin::read(d)?, lits: Bin::read(d)?,
+    })
+  }
+}
+impl Bin for Coe {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    match self {
+      Coe::One(fsp, t) => { e.u8(0)?; fsp.write(e)?; t.write(e) }
+      Coe::Trans(c1, s, c2) => { e.u8(1)?; c1.write(e)?; s.write(e)?; c2.write(e) }
+    }
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    match d.u8()? {
+      0 => Ok(Coe::One(Bin::read(d)?, Bin::read(d)?)),
+      1 => Ok(Coe::Trans(Bin::read(d)?, Bin::read(d)?, Bin::read(d)?)),
+      _ => Err(invalid("bad coercion"))
+    }
+  }
+}
+impl Bin for Delims {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    for i in 0..32 {
+      e.u8((0..8).fold(0, |n, j| if self.get(i << 3 | j) {n | 1 << j} else {n}))?
+    }
+    Ok(())
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    let mut delims = Delims::default();
+    for i in 0..32 {
+      let n = d.u8()?;
+      for j in 0..8 { if n & 1 << j != 0 { delims.set(i << 3 | j) } }
+    }
+    Ok(delims)
+  }
+}
+impl Bin for ParserEnv {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    self.delims_l.write(e)?; self.delims_r.write(e)?; self.consts.write(e)?;
+    self.prec_assoc.write(e)?; self.prefixes.write(e)?; self.infixes.write(e)?;
+    self.coes.write(e)?; self.coe_prov.write(e)?; self.decl_nota.write(e)
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    Ok(ParserEnv {
+      delims_l: Bin::read(d)?, delims_r: Bin::read(d)?, consts: Bin::read(d)?,
+      prec_assoc: Bin::read(d)?, prefixes: Bin::read(d)?, infixes: Bin::read(d)?,
+      coes: Bin::read(d)?, coe_prov: Bin::read(d)?, decl_nota: Bin::read(d)?,
+    })
+  }
+}
+
+impl Bin for EqLemma {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    self.thm.write(e)?; self.args.write(e)?; self.hyps.write(e)
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    Ok(EqLemma {thm: Bin::read(d)?, args: Bin::read(d)?, hyps: Bin::read(d)?})
+  }
+}
+impl Bin for EqRel {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    self.sort.write(e)?; self.refl.write(e)?; self.symm.write(e)?; self.trans.write(e)
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    Ok(EqRel {sort: Bin::read(d)?, refl: Bin::read(d)?, symm: Bin::read(d)?, trans: Bin::read(d)?})
+  }
+}
+impl Bin for EqLemmas {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    self.eqs.write(e)?; self.sorts.write(e)?; self.congr.write(e)?; self.mp.write(e)
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    Ok(EqLemmas {eqs: Bin::read(d)?, sorts: Bin::read(d)?, congr: Bin::read(d)?, mp: Bin::read(d)?})
+  }
+}
+impl Bin for NumTerm {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    #[allow(clippy::cast_possible_truncation)]
+    let i = NumTerm::ALL.iter().position(|t| t == self).expect("impossible") as u8;
+    e.u8(i)
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    NumTerm::ALL.get(d.u8()? as usize).copied().ok_or_else(|| invalid("bad norm-num term"))
+  }
+}
+impl Bin for NumRule {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    #[allow(clippy::cast_possible_truncation)]
+    let i = NumRule::ALL.iter().position(|t| t == self).expect("impossible") as u8;
+    e.u8(i)
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    NumRule::ALL.get(d.u8()? as usize).copied().ok_or_else(|| invalid("bad norm-num rule"))
+  }
+}
+impl Bin for NumLemma {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    self.thm.write(e)?; self.args.write(e)
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    Ok(NumLemma {thm: Bin::read(d)?, args: Bin::read(d)?})
+  }
+}
+impl Bin for NumLemmas {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    self.terms.write(e)?; self.lemmas.write(e)
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    Ok(NumLemmas {terms: Bin::read(d)?, lemmas: Bin::read(d)?})
+  }
+}
+
+impl Bin for LispVal {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> { e.lisp(self) }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> { d.lisp() }
+}
+impl Bin for LispData {
+  /// A global definition is a root of the lisp data, so the references in it are
+  /// written out here.
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    self.src.write(e)?; self.doc.write(e)?; self.overrides.write(e)?;
+    self.val.write(e)?; e.flush_refs()
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    let (src, doc, overrides) = (Bin::read(d)?, Bin::read(d)?, Bin::read(d)?);
+    let val = LispVal::read(d)?;
+    d.flush_refs()?;
+    Ok(LispData {src, doc, val, overrides})
+  }
+}
+impl Bin for Syntax {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> { e.bytes(self.to_byte_str()) }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    Syntax::from_bytes(&d.bytes()?).ok_or_else(|| invalid("bad syntax"))
+  }
+}
+impl Bin for BuiltinProc {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> { e.bytes(self.to_byte_str()) }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    BuiltinProc::from_bytes(&d.bytes()?).ok_or_else(|| invalid("bad builtin"))
+  }
+}
+impl Bin for InferTarget {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    match self {
+      InferTarget::Unknown => e.u8(0),
+      InferTarget::Provable => e.u8(1),
+      InferTarget::Bound(a) => { e.u8(2)?; a.write(e) }
+      InferTarget::Reg(a) => { e.u8(3)?; a.write(e) }
+    }
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    Ok(match d.u8()? {
+      0 => InferTarget::Unknown,
+      1 => InferTarget::Provable,
+      2 => InferTarget::Bound(Bin::read(d)?),
+      3 => InferTarget::Reg(Bin::read(d)?),
+      _ => return Err(invalid("bad infer target"))
+    })
+  }
+}
+impl Bin for ProcPos {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    match self {
+      ProcPos::Named(fsp, sp, a) => { e.u8(0)?; fsp.write(e)?; sp.write(e)?; a.write(e) }
+      ProcPos::Unnamed(fsp) => { e.u8(1)?; fsp.write(e) }
+    }
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    match d.u8()? {
+      0 => Ok(ProcPos::Named(Bin::read(d)?, Bin::read(d)?, Bin::read(d)?)),
+      1 => Ok(ProcPos::Unnamed(Bin::read(d)?)),
+      _ => Err(invalid("bad procedure position"))
+    }
+  }
+}
+impl Bin for ProcSpec {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    match self {
+      ProcSpec::Exact(n) => { e.u8(0)?; n.write(e) }
+      ProcSpec::AtLeast(n) => { e.u8(1)?; n.write(e) }
+    }
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    match d.u8()? {
+      0 => Ok(ProcSpec::Exact(Bin::read(d)?)),
+      1 => Ok(ProcSpec::AtLeast(Bin::read(d)?)),
+      _ => Err(invalid("bad procedure spec"))
+    }
+  }
+}
+impl Bin for ContExpiry {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    match self {
+      ContExpiry::Returned(fsp) => { e.u8(0)?; fsp.write(e) }
+      ContExpiry::Jumped(fsp) => { e.u8(1)?; fsp.write(e) }
+      ContExpiry::TailCall(fsp) => { e.u8(2)?; fsp.write(e) }
+      ContExpiry::Exception(fsp) => { e.u8(3)?; fsp.write(e) }
+      ContExpiry::Frozen => e.u8(4),
+    }
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    Ok(match d.u8()? {
+      0 => ContExpiry::Returned(Bin::read(d)?),
+      1 => ContExpiry::Jumped(Bin::read(d)?),
+      2 => ContExpiry::TailCall(Bin::read(d)?),
+      3 => ContExpiry::Exception(Bin::read(d)?),
+      4 => ContExpiry::Frozen,
+      _ => return Err(invalid("bad continuation"))
+    })
+  }
+}
+
+/// Write the state of a proof thunk.
+fn write_thunk<W: Write>(e: &mut Encoder<W>,
+  m: &RefCell<Result<LispVal, Box<[LispVal]>>>
+) -> io::Result<()> {
+  match unsafe { m.try_borrow_unguarded() }.expect("failed to deref ref") {
+    Ok(v) => { e.u8(0)?; v.write(e) }
+    Err(args) => { e.u8(1)?; args.write(e) }
+  }
+}
+
+/// Read the state of a proof thunk.
+fn read_thunk(d: &mut Decoder<'_>) -> io::Result<RefCell<Result<LispVal, Box<[LispVal]>>>> {
+  Ok(RefCell::new(match d.u8()? {
+    0 => Ok(LispVal::read(d)?),
+    1 => Err(Bin::read(d)?),
+    _ => return Err(invalid("bad thunk"))
+  }))
+}
+
+impl Bin for Proc {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    match self {
+      Proc::Builtin(p) => { e.u8(0)?; p.write(e) }
+      Proc::Lambda {pos, env, spec, code} => {
+        e.u8(1)?; pos.write(e)?; env.write(e)?; spec.write(e)?; code.write(e)
+      }
+      Proc::MatchCont(k) => {
+        e.u8(2)?; k.span.write(e)?;
+        match k.expiry() {
+          Some(why) => why.write(e),
+          None => ContExpiry::Frozen.write(e),
+        }
+      }
+      Proc::RefineCallback => e.u8(3),
+      Proc::ProofThunk(a, m) => { e.u8(4)?; a.write(e)?; write_thunk(e, m) }
+      Proc::Promise(m) => {
+        // A promise that has not been joined is saved as lost, like a copied one
+        e.u8(5)?;
+        match &*unsafe { m.try_borrow_unguarded() }.expect("failed to deref ref") {
+          Promise::Done(v) => { e.u8(0)?; v.write(e) }
+          Promise::Running(_) | Promise::Lost => e.u8(1),
+        }
+      }
+      Proc::MMCCompiler(_) => Err(io::Error::new(io::ErrorKind::InvalidInput,
+        "the MMC compiler state cannot be saved")),
+    }
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    Ok(match d.u8()? {
+      0 => Proc::Builtin(Bin::read(d)?),
+      1 => Proc::Lambda {pos: Bin::read(d)?, env: Bin::read(d)?, spec: Bin::read(d)?, code: Bin::read(d)?},
+      2 => {
+        let k = MatchCont::new(Bin::read(d)?);
+        k.expire(Bin::read(d)?);
+        Proc::MatchCont(Rc::new(k))
+      }
+      3 => Proc::RefineCallback,
+      4 => Proc::ProofThunk(Bin::read(d)?, read_thunk(d)?),
+      5 => Proc::Promise(Rc::new(RefCell::new(match d.u8()? {
+        0 => Promise::Done(LispVal::read(d)?),
+        1 => Promise::Lost,
+        _ => return Err(invalid("bad promise"))
+      }))),
+      _ => return Err(invalid("bad procedure"))
+    })
+  }
+}
+
+impl Bin for IR {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    match self {
+      IR::Local(i) => { e.u8(0)?; i.write(e) }
+      IR::Global(sp, a) => { e.u8(1)?; sp.write(e)?; a.write(e) }
+      IR::Const(v) => { e.u8(2)?; v.write(e) }
+      IR::List(sp, es) => { e.u8(3)?; sp.write(e)?; es.write(e) }
+      IR::DottedList(es, r) => { e.u8(4)?; es.write(e)?; r.write(e) }
+      IR::App(sp1, sp2, f, es) => { e.u8(5)?; sp1.write(e)?; sp2.write(e)?; f.write(e)?; es.write(e) }
+      IR::If(es) => { e.u8(6)?; es.write(e) }
+      IR::Focus(sp, es) => { e.u8(7)?; sp.write(e)?; es.write(e) }
+      IR::Def(n, x, v) => { e.u8(8)?; n.write(e)?; x.write(e)?; v.write(e) }
+      IR::Eval(keep, es) => { e.u8(9)?; keep.write(e)?; es.write(e) }
+      IR::Letrec(n, ds, v) => { e.u8(10)?; n.write(e)?; ds.write(e)?; v.write(e) }
+      IR::Lambda(sp, n, spec, code) => { e.u8(11)?; sp.write(e)?; n.write(e)?; spec.write(e)?; code.write(e) }
+      IR::OptArgs(sp, req, opt, kws, rest) => {
+        e.u8(12)?; sp.write(e)?; req.write(e)?; opt.write(e)?; kws.write(e)?; rest.write(e)
+      }
+      IR::Match(sp, v, brs) => { e.u8(13)?; sp.write(e)?; v.write(e)?; brs.write(e) }
+      IR::Label(sp, a, v) => { e.u8(14)?; sp.write(e)?; a.write(e)?; v.write(e) }
+    }
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    Ok(match d.u8()? {
+      0 => IR::Local(Bin::read(d)?),
+      1 => IR::Global(Bin::read(d)?, Bin::read(d)?),
+      2 => IR::Const(Bin::read(d)?),
+      3 => IR::List(Bin::read(d)?, Bin::read(d)?),
+      4 => IR::DottedList(Bin::read(d)?, Bin::read(d)?),
+      5 => IR::App(Bin::read(d)?, Bin::read(d)?, Bin::read(d)?, Bin::read(d)?),
+      6 => IR::If(Bin::read(d)?),
+      7 => IR::Focus(Bin::read(d)?, Bin::read(d)?),
+      8 => IR::Def(Bin::read(d)?, Bin::read(d)?, Bin::read(d)?),
+      9 => IR::Eval(Bin::read(d)?, Bin::read(d)?),
+      10 => IR::Letrec(Bin::read(d)?, Bin::read(d)?, Bin::read(d)?),
+      11 => IR::Lambda(Bin::read(d)?, Bin::read(d)?, Bin::read(d)?, Bin::read(d)?),
+      12 => IR::OptArgs(Bin::read(d)?, Bin::read(d)?, Bin::read(d)?, Bin::read(d)?, Bin::read(d)?),
+      13 => IR::Match(Bin::read(d)?, Bin::read(d)?, Bin::read(d)?),
+      14 => IR::Label(Bin::read(d)?, Bin::read(d)?, Bin::read(d)?),
+      _ => return Err(invalid("bad lisp code"))
+    })
+  }
+}
+impl Bin for Branch {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    self.vars.write(e)?; self.whole.write(e)?; self.cont.write(e)?;
+    self.pat.write(e)?; self.guard.write(e)?; self.eval.write(e)
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    Ok(Branch {
+      vars: Bin::read(d)?, whole: Bin::read(d)?, cont: Bin::read(d)?,
+      pat: Bin::read(d)?, guard: Bin::read(d)?, eval: Bin::read(d)?,
+    })
+  }
+}
+impl Bin for Pattern {
+  fn write<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    match self {
+      Pattern::Skip => e.u8(0),
+      Pattern::Atom(i) => { e.u8(1)?; i.write(e) }
+      Pattern::QuoteAtom(a) => { e.u8(2)?; a.write(e) }
+      Pattern::String(s) => { e.u8(3)?; s.write(e) }
+      Pattern::Bool(b) => { e.u8(4)?; b.write(e) }
+      Pattern::Undef => e.u8(5),
+      Pattern::Number(n) => { e.u8(6)?; n.write(e) }
+      Pattern::MVar(MVarPattern::Unknown) => e.u8(7),
+      Pattern::MVar(MVarPattern::Any) => e.u8(8),
+      Pattern::MVar(MVarPattern::Simple(p)) => { e.u8(9)?; p.write(e) }
+      Pattern::Goal(p) => { e.u8(10)?; p.write(e) }
+      Pattern::DottedList(ps, r) => { e.u8(11)?; ps.write(e)?; r.write(e) }
+      Pattern::List(ps, dot) => { e.u8(12)?; ps.write(e)?; dot.write(e) }
+      Pattern::And(ps) => { e.u8(13)?; ps.write(e) }
+      Pattern::Or(ps) => { e.u8(14)?; ps.write(e) }
+      Pattern::Not(ps) => { e.u8(15)?; ps.write(e) }
+      Pattern::Test(sp, f, xs, ps) => { e.u8(16)?; sp.write(e)?; f.write(e)?; xs.write(e)?; ps.write(e) }
+      Pattern::Vector(ps) => { e.u8(17)?; ps.write(e) }
+      Pattern::QExprAtom(a) => { e.u8(18)?; a.write(e) }
+    }
+  }
+  fn read(d: &mut Decoder<'_>) -> io::Result<Self> {
+    Ok(match d.u8()? {
+      0 => Pattern::Skip,
+      1 => Pattern::Atom(Bin::read(d)?),
+      2 => Pattern::QuoteAtom(Bin::read(d)?),
+      3 => Pattern::String(Bin::read(d)?),
+      4 => Pattern::Bool(Bin::read(d)?),
+      5 => Pattern::Undef,
+      6 => Pattern::Number(Bin::read(d)?),
+      7 => Pattern::MVar(MVarPattern::Unknown),
+      8 => Pattern::MVar(MVarPattern::Any),
+      9 => Pattern::MVar(MVarPattern::Simple(Bin::read(d)?)),
+      10 => Pattern::Goal(Bin::read(d)?),
+      11 => Pattern::DottedList(Bin::read(d)?, Bin::read(d)?),
+      12 => Pattern::List(Bin::read(d)?, Bin::read(d)?),
+      13 => Pattern::And(Bin::read(d)?),
+      14 => Pattern::Or(Bin::read(d)?),
+      15 => Pattern::Not(Bin::read(d)?),
+      16 => Pattern::Test(Bin::read(d)?, Bin::read(d)?, Bin::read(d)?, Bin::read(d)?),
+      17 => Pattern::Vector(Bin::read(d)?),
+      18 => Pattern::QExprAtom(Bin::read(d)?),
+      _ => return Err(invalid("bad pattern"))
+    })
+  }
+}
+
+/// Write the environment `env` to `w`. This fails if the environment contains a lisp
+/// value that cannot be saved (see the [module documentation](self)).
+pub fn write_env(env: &FrozenEnv, w: impl Write) -> io::Result<()> {
+  // Safety: the environment is only read, and no lisp values are cloned
+  let env = unsafe { env.thaw() };
+  let e = &mut Encoder {
+    w, files: HashMap::new(), lisp: HashMap::new(), nlisp: 0, refs: VecDeque::new()
+  };
+  e.w.write_all(&MAGIC)?;
+  e.u32(VERSION)?;
+  for n in &[env.data.len(), env.sorts.len(), env.terms.len(), env.thms.len()] { n.write(e)? }
+  for d in &env.data.0 { d.name.write(e)? }
+  for s in &env.sorts.0 { s.write(e)? }
+  for t in &env.terms.0 { t.write(e)? }
+  for t in &env.thms.0 { t.write(e)? }
+  for d in &env.data.0 {
+    d.lisp.write(e)?; d.graveyard.write(e)?; d.sort.write(e)?; d.decl.write(e)?
+  }
+  env.pe.write(e)?;
+  env.stmts.write(e)?;
+  env.eqs.write(e)?;
+  env.nums.write(e)?;
+  env.admitted.write(e)?;
+  e.w.flush()
+}
+
+/// Read an environment written by [`write_env`] from `buf`. The function `file` converts
+/// the paths of the files mentioned in the environment to [`FileRef`]s.
+pub fn read_env(buf: &[u8],
+  file: &mut dyn FnMut(PathBuf) -> io::Result<FileRef>
+) -> io::Result<Environment> {
+  let d = &mut Decoder {
+    buf, file, files: vec![], lisp: vec![], refs: VecDeque::new(), counts: (0, 0, 0, 0)
+  };
+  let mut magic = [0; 4];
+  d.buf.read_exact(&mut magic)?;
+  if magic != MAGIC { return Err(invalid("not a serialized environment")) }
+  if d.u32()? != VERSION { return Err(invalid("unsupported version")) }
+  d.counts = (usize::read(d)?, usize::read(d)?, usize::read(d)?, usize::read(d)?);
+  let (natoms, nsorts, nterms, nthms) = d.counts;
+  if nsorts > 128 { return Err(invalid("too many sorts")) }
+  let names = (0..natoms).map(|_| ArcString::read(d)).collect::<io::Result<Vec<_>>>()?;
+  let sorts = (0..nsorts).map(|_| Sort::read(d)).collect::<io::Result<SortVec<_>>>()?;
+  let terms = (0..nterms).map(|_| Term::read(d)).collect::<io::Result<TermVec<_>>>()?;
+  let thms = (0..nthms).map(|_| Thm::read(d)).collect::<io::Result<ThmVec<_>>>()?;
+  #[allow(clippy::cast_possible_truncation)]
+  let atoms = names.iter().enumerate().map(|(i, s)| (s.clone(), AtomID(i as u32))).collect();
+  let data = names.into_iter().map(|name| Ok(AtomData {
+    name, lisp: Bin::read(d)?, graveyard: Bin::read(d)?, sort: Bin::read(d)?, decl: Bin::read(d)?
+  })).collect::<io::Result<AtomVec<_>>>()?;
+  Ok(Environment {
+    sorts, terms, thms, atoms, data,
+    pe: Bin::read(d)?,
+    stmts: Bin::read(d)?,
+    eqs: Bin::read(d)?,
+    nums: Bin::read(d)?,
+    admitted: Bin::read(d)?,
+    spans: vec![],
+    resume: vec![],
+  })
+}
@@ -2,8 +2,10 @@
 
 use std::mem::MaybeUninit;
 use std::collections::BTreeMap;
-use super::environment::AtomID;
+use std::sync::Arc;
+use super::environment::{AtomID, Coe, SortID, Remap, Remapper};
 use super::local_context::LocalContext;
+use super::lisp::LispVal;
 use crate::util::{Span, OptionExt};
 
 /// A `Spans<T>` object is created for each declaration, and maintains data on the
@@ -26,11 +28,39 @@ pub struct Spans<T> {
   /// declarations that don't have names, like [`delimiter`](crate::parser::ast::Delimiter).
   decl: MaybeUninit<AtomID>,
   /// The local context as of the end of the proof. This is used to resolve variables
-  /// and subproof names.
+  /// and subproof names, and it contains the goals that were left unsolved.
   pub lc: Option<LocalContext>,
   /// The actual data associated to spans. They are indexed by span start, and one
   /// start point can contain many spans, even multiple data elements at the same span.
   data: BTreeMap<usize, Vec<(Span, T)>>,
+  /// The proof states recorded before each step of a tactic block, and at the end of the
+  /// block, indexed by position. Each one is the number of entries of `lc.proof_order`
+  /// that were in scope at that point, and the types of the goals.
+  goals: BTreeMap<usize, (usize, Vec<LispVal>)>,
+  /// The information filled in by the elaborator that does not appear in the source,
+  /// indexed by span. These are shown to the user as inlay hints.
+  hints: Vec<(Span, Hint)>,
+}
+
+/// Something the elaborator inferred at a span, which the source does not show.
+#[derive(Clone, Debug, DeepSizeOf)]
+#[allow(variant_size_differences)]
+pub enum Hint {
+  /// The variable at this span was declared without a sort (or not declared at all),
+  /// and this is the sort that was inferred for it.
+  Sort(SortID),
+  /// This coercion was inserted around the expression at this span.
+  Coe(Arc<Coe>),
+}
+
+impl Remap for Hint {
+  type Target = Self;
+  fn remap(&self, r: &mut Remapper) -> Self {
+    match self {
+      Hint::Sort(s) => Hint::Sort(s.remap(r)),
+      Hint::Coe(c) => Hint::Coe(c.remap(r)),
+    }
+  }
 }
 
 impl<'a, T> IntoIterator for &'a Spans<T> {
@@ -40,6 +70,24 @@ impl<'a, T> IntoIterator for &'a Spans<T> {
   fn into_iter(self) -> Self::IntoIter { self.data.values().flatten() }
 }
 
+impl<T: Remap<Target = T>> Remap for Spans<T> {
+  type Target = Self;
+  /// Copy the spans of a statement. The `decl` field is copied as is, because it may be
+  /// uninitialized, so this should only be used with remappers that fix atoms,
+  /// like [`Remapper::identity`].
+  fn remap(&self, r: &mut Remapper) -> Self {
+    Spans {
+      stmt: self.stmt,
+      decl: self.decl,
+      lc: self.lc.as_ref().map(|lc| lc.remap(r)),
+      data: self.data.iter().map(|(&k, v)|
+        (k, v.iter().map(|(sp, t)| (*sp, t.remap(r))).collect())).collect(),
+      goals: self.goals.iter().map(|(&k, (n, gs))| (k, (*n, gs.remap(r)))).collect(),
+      hints: self.hints.iter().map(|(sp, h)| (*sp, h.remap(r))).collect(),
+    }
+  }
+}
+
 use std::fmt;
 impl<T: fmt::Debug> fmt::Debug for Spans<T> {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -59,7 +107,9 @@ impl<T> Spans<T> {
       stmt: MaybeUninit::uninit(),
       decl: MaybeUninit::uninit(),
       lc: None,
-      data: BTreeMap::new()
+      data: BTreeMap::new(),
+      goals: BTreeMap::new(),
+      hints: vec![],
     }
   }
 
@@ -128,6 +178,31 @@ impl<T> Spans<T> {
     }
   }
 
+  /// Record the proof state at position `pos` in a tactic block: `hyps` is the number of
+  /// subproofs in the local context, and `goals` are the types of the goals.
+  pub fn insert_goals(&mut self, pos: usize, hyps: usize, goals: Vec<LispVal>) {
+    if pos >= self.stmt().start {
+      self.goals.insert(pos, (hyps, goals));
+    }
+  }
+
+  /// Get the proof state at position `pos`, which is the one recorded before the
+  /// tactic step containing `pos`. See [`insert_goals`](Self::insert_goals).
+  #[must_use] pub fn goals_at(&self, pos: usize) -> Option<&(usize, Vec<LispVal>)> {
+    self.goals.range(..=pos).next_back().map(|(_, g)| g)
+  }
+
+  /// Record an inferred sort or coercion at `sp`, if it lies within the current statement.
+  pub fn insert_hint(&mut self, sp: Span, hint: Hint) {
+    if sp.start >= self.stmt().start {
+      self.hints.push((sp, hint))
+    }
+  }
+
+  /// The inferred sorts and coercions recorded by [`insert_hint`](Self::insert_hint),
+  /// in the order they were inserted.
+  #[must_use] pub fn hints(&self) -> &[(Span, Hint)] { &self.hints }
+
   /// Get the data at a given [`Span`].
   /// If multiple data elements exist at this span, only the first will be returned.
   #[must_use] pub fn get(&self, sp: Span) -> Option<&T> {
@@ -142,6 +217,16 @@ impl<T> Spans<T> {
       v.iter_mut().find(|x| x.0 == sp).map(|x| &mut x.1))
   }
 
+  /// Remove the data elements that do not satisfy `f`, and the inferred sorts and coercions
+  /// that do not satisfy `hint`.
+  pub fn retain(&mut self, mut f: impl FnMut(&T) -> bool, mut hint: impl FnMut(&Hint) -> bool) {
+    self.data = std::mem::take(&mut self.data).into_iter().filter_map(|(k, mut v)| {
+      v.retain(|x| f(&x.1));
+      if v.is_empty() {None} else {Some((k, v))}
+    }).collect();
+    self.hints.retain(|x| hint(&x.1));
+  }
+
   /// Returns an iterator over all data elements in spans that overlap the target
   /// position. ([`Span`]s are considered as closed,
   /// i.e. `start <= pos <= end`, for this purpose.)
@@ -0,0 +1,31 @@
+//! One-shot evaluation of lisp expressions from the command line.
+//!
+//!     mm0-rs eval foo.mm1 -e '(get-decl (quote bar))'
+//!
+//! will elaborate `foo.mm1`, evaluate the expression in the resulting environment (as if it
+//! were in a `do` block at the end of the file), and print the value to stdout. The command
+//! fails if the file has elaboration errors or the evaluation raises an error, so it can be
+//! used for scripted checks on MM1 libraries, for example
+//! `mm0-rs eval foo.mm1 -e "(if (thm? 'bar) #t (error \"no bar\"))"`.
+use std::io;
+use clap::ArgMatches;
+
+/// Main entry point for `mm0-rs eval` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs eval <in.mm1> -e <expr>`, where:
+///
+/// - `in.mm1` is the MM1 (or MM0) file providing the environment
+/// - `expr` is the lisp expression to evaluate
+///
+/// The value is printed to stdout (nothing is printed if it is `#undef`), and the process
+/// exits with a nonzero status if there is an error in the file or in the evaluation.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let path = args.value_of("INPUT").expect("required arg");
+  let expr = args.value_of("expr").expect("required arg");
+  let (val, ok) = crate::compiler::eval_in_file(path, expr, None)?;
+  if let Some(val) = val.filter(|val| val != "#undef") { println!("{}", val) }
+  if !ok { std::process::exit(1) }
+  Ok(())
+}
@@ -0,0 +1,577 @@
+//! A formatter for MM0 and MM1 files.
+//!
+//!     mm0-rs format in.mm1 [-w WIDTH] [-o out.mm1] [--check]
+//!
+//! will elaborate `in.mm1` (to find the notations it uses) and rewrite it in place, or write
+//! the result to `out.mm1` (`-` for stdout), in a canonical layout:
+//!
+//! * The tokens of a declaration or notation command are separated by single spaces, with no
+//!   space inside brackets or before `:`, `;` and `,`. Math strings are written `$ a + b $`,
+//!   except for the tokens in notation commands, which are written `$+$`.
+//! * A declaration that does not fit in the width (default 80) gets one line for the name and
+//!   binders (continued on lines indented by two spaces), and one line for each hypothesis
+//!   and the conclusion. A math string that still does not fit is broken after infix
+//!   operators, choosing the ones outside of parentheses with the lowest precedence first.
+//! * Lisp code in `do` blocks and proofs keeps its line breaks, but every line is indented
+//!   by two spaces per open bracket (plus two inside a `do` block). Lines that start inside
+//!   a string or a math string are not changed.
+//! * Every statement starts on a new line, comments are kept, and runs of blank lines are
+//!   collapsed into one.
+//!
+//! A statement with a comment outside of its lisp code is kept as written, except for
+//! trailing whitespace. Files with parse errors are not formatted. Formatting is idempotent,
+//! and `--check` exits with an error instead of writing if the file is not already formatted.
+//! The server uses the same formatter for `textDocument/formatting`.
+use std::fs;
+use std::io::{self, Write};
+use std::ops::Range;
+use clap::ArgMatches;
+use crate::elab::environment::ParserEnv;
+use crate::parser::{parse, ErrorLevel, AST, ast::{Decl, Prec, SExprKind, Stmt, StmtKind}};
+
+/// A token in the parts of a statement that are not lisp code.
+#[derive(Clone, Copy)]
+enum Tok<'a> {
+  /// One of the punctuation characters in [`PUNCT`].
+  Punct(u8),
+  /// A math string, including the `$` delimiters.
+  Formula(&'a [u8]),
+  /// An identifier, keyword, number or string literal.
+  Word(&'a [u8]),
+}
+
+const PUNCT: &[u8] = b"(){}[]:;,=>";
+
+/// Split `s` into tokens, or return `None` if it contains a comment.
+fn tokens(s: &[u8]) -> Option<Vec<Tok<'_>>> {
+  let mut out = vec![];
+  let mut i = 0;
+  while i < s.len() {
+    let c = s[i];
+    if c.is_ascii_whitespace() { i += 1; continue }
+    if s[i..].starts_with(b"--") { return None }
+    let j = match c {
+      b'$' => {
+        let j = i + 2 + s[i + 1..].iter().position(|&c| c == b'$')?;
+        if s[i..j].windows(2).any(|w| w == b"--") { return None }
+        out.push(Tok::Formula(&s[i..j]));
+        j
+      }
+      b'"' => {
+        let mut j = i + 1;
+        loop {
+          match *s.get(j)? { b'\\' => j += 2, b'"' => break, _ => j += 1 }
+        }
+        out.push(Tok::Word(&s[i..=j]));
+        j + 1
+      }
+      _ if PUNCT.contains(&c) => { out.push(Tok::Punct(c)); i + 1 }
+      _ => {
+        let mut j = i + 1;
+        while j < s.len() && !s[j].is_ascii_whitespace() && !PUNCT.contains(&s[j]) &&
+          !matches!(s[j], b'$' | b'"') && !s[j..].starts_with(b"--") { j += 1 }
+        out.push(Tok::Word(&s[i..j]));
+        j
+      }
+    };
+    i = j
+  }
+  Some(out)
+}
+
+/// The tokens of a math string (including the `$` delimiters).
+fn math_tokens(f: &[u8]) -> Vec<&[u8]> {
+  f[1..f.len() - 1].split(u8::is_ascii_whitespace).filter(|t| !t.is_empty()).collect()
+}
+
+/// Write the math string `f` on one line, as `$ a + b $`, or `$+$` if `tight` is set.
+fn push_formula(out: &mut Vec<u8>, f: &[u8], tight: bool) {
+  let toks = math_tokens(f);
+  out.push(b'$');
+  if !tight && !toks.is_empty() { out.push(b' ') }
+  for (i, t) in toks.iter().enumerate() {
+    if i != 0 { out.push(b' ') }
+    out.extend_from_slice(t)
+  }
+  if !tight && !toks.is_empty() { out.push(b' ') }
+  out.push(b'$')
+}
+
+/// Write a sequence of tokens on one line.
+fn join(toks: &[Tok<'_>], tight: bool) -> Vec<u8> {
+  let mut out = vec![];
+  for (i, &t) in toks.iter().enumerate() {
+    let space = match (i.checked_sub(1).map(|j| toks[j]), t) {
+      (None, _) => false,
+      (Some(Tok::Punct(c)), _) if b"({[".contains(&c) => false,
+      (_, Tok::Punct(c)) if b")}]:;,".contains(&c) => false,
+      // The precedence in a notation literal like `($+$:23)`
+      (Some(Tok::Punct(b':')), _) =>
+        !matches!(i.checked_sub(2).map(|j| toks[j]), Some(Tok::Formula(_))),
+      _ => true
+    };
+    if space { out.push(b' ') }
+    match t {
+      Tok::Punct(c) => out.push(c),
+      Tok::Formula(f) => push_formula(&mut out, f, tight),
+      Tok::Word(w) => out.extend_from_slice(w),
+    }
+  }
+  out
+}
+
+fn trim_start(s: &[u8]) -> &[u8] {
+  &s[s.iter().position(|c| !c.is_ascii_whitespace()).unwrap_or(s.len())..]
+}
+
+fn trim_end(s: &[u8]) -> &[u8] {
+  &s[..s.iter().rposition(|c| !c.is_ascii_whitespace()).map_or(0, |i| i + 1)]
+}
+
+/// The lexical state at a point in lisp code.
+#[derive(Clone, Copy)]
+enum LispState {
+  /// Ordinary code
+  Code,
+  /// Inside a string literal
+  Str,
+  /// Inside a math string
+  Math,
+}
+
+/// The parts of a declaration header: the text up to the name, the binder groups,
+/// the hypotheses and conclusion (separated by `>`), and the final `=` or `;`.
+struct Header<'a> {
+  head: &'a [Tok<'a>],
+  binders: Vec<&'a [Tok<'a>]>,
+  ret: Vec<&'a [Tok<'a>]>,
+  end: u8,
+}
+
+impl<'a> Header<'a> {
+  fn new(toks: &'a [Tok<'a>]) -> Option<Self> {
+    let (&end, toks) = toks.split_last()?;
+    let end = if let Tok::Punct(c @ b'=') | Tok::Punct(c @ b';') = end {c} else {return None};
+    let mut i = toks.iter().position(|t| matches!(t, Tok::Punct(_))).unwrap_or(toks.len());
+    let head = &toks[..i];
+    let mut binders = vec![];
+    while let Some(&Tok::Punct(b'(')) | Some(&Tok::Punct(b'{')) = toks.get(i) {
+      let start = i;
+      let mut depth = 0_usize;
+      loop {
+        match toks.get(i)? {
+          Tok::Punct(b'(') | Tok::Punct(b'{') => depth += 1,
+          Tok::Punct(b')') | Tok::Punct(b'}') => depth -= 1,
+          _ => {}
+        }
+        i += 1;
+        if depth == 0 { break }
+      }
+      binders.push(&toks[start..i])
+    }
+    let ret = match toks.get(i) {
+      None => vec![],
+      Some(Tok::Punct(b':')) => toks[i + 1..].split(|t| matches!(t, Tok::Punct(b'>'))).collect(),
+      Some(_) => return None
+    };
+    if ret.iter().any(|r| r.is_empty()) { return None }
+    Some(Header {head, binders, ret, end})
+  }
+}
+
+/// The state of the formatter.
+struct Formatter<'a> {
+  /// The source text
+  src: &'a [u8],
+  /// The notations, used to break long math strings.
+  pe: Option<&'a ParserEnv>,
+  /// The maximum line width
+  width: usize,
+  /// The formatted text
+  out: Vec<u8>,
+}
+
+impl<'a> Formatter<'a> {
+  /// The current column in the output.
+  fn col(&self) -> usize {
+    self.out.len() - self.out.iter().rposition(|&c| c == b'\n').map_or(0, |i| i + 1)
+  }
+
+  fn newline(&mut self, indent: usize) {
+    self.out.push(b'\n');
+    self.out.resize(self.out.len() + indent, b' ')
+  }
+
+  /// Write the comments between two statements, with a space before a comment on the same
+  /// line as the previous statement, and at most one blank line between lines.
+  fn gap(&mut self, start: usize, end: usize, last: bool) {
+    let mut nl = 0;
+    let mut i = start;
+    while i < end {
+      match self.src[i] {
+        b'\n' => { nl += 1; i += 1 }
+        b'-' => {
+          let j = self.src[i..end].iter().position(|&c| c == b'\n').map_or(end, |j| i + j);
+          if self.out.is_empty() {
+          } else if nl == 0 {
+            self.out.push(b' ')
+          } else {
+            if nl > 1 { self.out.push(b'\n') }
+            self.out.push(b'\n')
+          }
+          self.out.extend_from_slice(trim_end(&self.src[i..j]));
+          nl = 0;
+          i = j
+        }
+        _ => i += 1
+      }
+    }
+    if !self.out.is_empty() {
+      if nl > 1 && !last { self.out.push(b'\n') }
+      self.out.push(b'\n')
+    }
+  }
+
+  /// Write a statement that cannot be formatted, removing trailing whitespace.
+  fn verbatim(&mut self, sp: Range<usize>) {
+    for (i, line) in self.src[sp].split(|&c| c == b'\n').enumerate() {
+      if i != 0 { self.out.push(b'\n') }
+      self.out.extend_from_slice(trim_end(line))
+    }
+  }
+
+  fn stmt(&mut self, s: &Stmt) {
+    let src = self.src;
+    let sp = s.span.start..s.span.end;
+    match &s.k {
+      StmtKind::DocComment(_, inner) => {
+        for line in src[s.span.start..inner.span.start].split(|&c| c == b'\n') {
+          let line = trim_end(trim_start(line));
+          if !line.is_empty() { self.out.extend_from_slice(line); self.out.push(b'\n') }
+        }
+        self.stmt(inner)
+      }
+      StmtKind::Annot(e, inner) => {
+        self.out.push(b'@');
+        self.lisp(&src[e.span.start..e.span.end], 2);
+        if src[e.span.end..inner.span.start].contains(&b'\n') {
+          self.out.push(b'\n')
+        } else {
+          self.out.push(b' ')
+        }
+        self.stmt(inner)
+      }
+      StmtKind::Do(_) => self.do_block(sp),
+      StmtKind::Decl(d) => self.decl(sp, d),
+      StmtKind::Sort(..) | StmtKind::Delimiter(_) | StmtKind::SimpleNota(_) |
+      StmtKind::Coercion {..} | StmtKind::Notation(_) | StmtKind::Import(..) =>
+        match tokens(&src[sp.clone()]) {
+          Some(toks) => {
+            let tight = !matches!(s.k, StmtKind::Delimiter(_));
+            self.out.extend(join(&toks, tight))
+          }
+          None => self.verbatim(sp)
+        },
+      StmtKind::Inout {..} => self.verbatim(sp),
+    }
+  }
+
+  /// Write a `do` block, with one lisp expression per line, or on one line if it was
+  /// written on one line.
+  fn do_block(&mut self, sp: Range<usize>) {
+    let src = self.src;
+    let body = trim_start(&src[sp.start + 2..sp.end - 1]);
+    if body.first() != Some(&b'{') {
+      if body.starts_with(b"--") { return self.verbatim(sp) }
+      self.out.extend_from_slice(b"do ");
+      self.lisp(trim_end(body), 2);
+      return self.out.push(b';')
+    }
+    let inner = trim_end(&body[1..]);
+    let inner = match inner.split_last() {
+      Some((b'}', inner)) => trim_end(trim_start(inner)),
+      _ => return self.verbatim(sp)
+    };
+    if inner.is_empty() {
+      self.out.extend_from_slice(b"do { };")
+    } else if !inner.contains(&b'\n') && !inner.windows(2).any(|w| w == b"--") {
+      self.out.extend_from_slice(b"do { ");
+      self.lisp(inner, 2);
+      self.out.extend_from_slice(b" };")
+    } else {
+      self.out.extend_from_slice(b"do {");
+      self.newline(2);
+      self.lisp(inner, 2);
+      self.out.extend_from_slice(b"\n};")
+    }
+  }
+
+  /// Write the lisp code `s` at the current position. The line breaks are kept, and the
+  /// following lines are indented by `base` plus two spaces per open bracket, except for
+  /// the lines that start inside a string or a math string, which are kept as written.
+  fn lisp(&mut self, s: &[u8], base: usize) {
+    let mut st = LispState::Code;
+    let mut depth = 0_usize;
+    let mut blank = false;
+    for (n, line) in s.split(|&c| c == b'\n').enumerate() {
+      let in_str = matches!(st, LispState::Str | LispState::Math);
+      let line = if in_str {line} else {trim_start(line)};
+      let mut indent = None;
+      let mut i = 0;
+      while i < line.len() {
+        let c = line[i];
+        if indent.is_none() && !matches!(c, b')' | b']' | b'}') { indent = Some(depth) }
+        match st {
+          LispState::Str => match c {
+            b'\\' => i += 1,
+            b'"' => st = LispState::Code,
+            _ => {}
+          },
+          LispState::Math => if c == b'$' { st = LispState::Code },
+          LispState::Code => match c {
+            b'"' => st = LispState::Str,
+            b'$' => st = LispState::Math,
+            b'-' if line.get(i + 1) == Some(&b'-') => break,
+            b'(' | b'[' | b'{' => depth += 1,
+            b')' | b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+          }
+        }
+        i += 1
+      }
+      // Whitespace at the end of a line inside a string is part of the string
+      let line = if matches!(st, LispState::Str | LispState::Math) {line} else {trim_end(line)};
+      if n == 0 {
+        self.out.extend_from_slice(line);
+        continue
+      }
+      if !in_str && line.is_empty() { blank = true; continue }
+      if std::mem::take(&mut blank) { self.out.push(b'\n') }
+      if in_str {
+        self.out.push(b'\n')
+      } else {
+        self.newline(base + 2 * indent.unwrap_or(depth))
+      }
+      self.out.extend_from_slice(line)
+    }
+  }
+
+  /// The precedence of `tok` if it is an infix operator.
+  fn infix_prec(&self, tok: &[u8]) -> Option<Prec> {
+    let pe = self.pe?;
+    if !pe.infixes.contains_key(tok) { return None }
+    pe.consts.get(tok).map(|&(_, p)| p)
+  }
+
+  /// Split the tokens `toks[r]` of a math string into lines of at most `avail` characters
+  /// (if possible), where `depth[i]` is the number of parentheses around `toks[i]`.
+  fn split_math(&self, toks: &[&[u8]], depth: &[usize], r: Range<usize>, avail: usize,
+    out: &mut Vec<Range<usize>>
+  ) {
+    let len = toks[r.clone()].iter().map(|t| t.len() + 1).sum::<usize>();
+    let cands = || (r.start..r.end - 1).filter_map(|i| Some(((depth[i], self.infix_prec(toks[i])?), i)));
+    let best = if len > avail + 1 { cands().map(|(k, _)| k).min() } else { None };
+    let best = match best { Some(best) => best, None => return out.push(r) };
+    let mut start = r.start;
+    for (k, i) in cands() {
+      if k == best {
+        self.split_math(toks, depth, start..i + 1, avail, out);
+        start = i + 1
+      }
+    }
+    self.split_math(toks, depth, start..r.end, avail, out)
+  }
+
+  /// Write the math string `f` at the current position, followed by `suffix`, breaking it
+  /// after infix operators if it does not fit.
+  fn formula(&mut self, f: &[u8], suffix: &[u8]) {
+    let col = self.col();
+    let mut flat = vec![];
+    push_formula(&mut flat, f, false);
+    if col + flat.len() + suffix.len() <= self.width {
+      self.out.extend(flat);
+      return self.out.extend_from_slice(suffix)
+    }
+    let toks = math_tokens(f);
+    let mut depth = Vec::with_capacity(toks.len());
+    let mut d = 0_usize;
+    for t in &toks {
+      // Parentheses are delimiters, so they need not be separate tokens
+      depth.push(d);
+      for &c in *t {
+        match c {
+          b'(' => d += 1,
+          b')' => d = d.saturating_sub(1),
+          _ => {}
+        }
+      }
+    }
+    let mut lines = vec![];
+    let avail = self.width.saturating_sub(col + 4 + suffix.len());
+    self.split_math(&toks, &depth, 0..toks.len(), avail, &mut lines);
+    self.out.push(b'$');
+    for (i, r) in lines.into_iter().enumerate() {
+      if i == 0 { self.out.push(b' ') } else { self.newline(col + 2) }
+      for (j, t) in toks[r].iter().enumerate() {
+        if j != 0 { self.out.push(b' ') }
+        self.out.extend_from_slice(t)
+      }
+    }
+    self.out.extend_from_slice(b" $");
+    self.out.extend_from_slice(suffix)
+  }
+
+  /// Write one of the hypotheses or the conclusion of a declaration, followed by `suffix`.
+  fn ret_part(&mut self, toks: &[Tok<'_>], suffix: &[u8]) {
+    if let [Tok::Formula(f)] = toks {
+      self.formula(f, suffix)
+    } else {
+      self.out.extend(join(toks, false));
+      self.out.extend_from_slice(suffix)
+    }
+  }
+
+  fn decl(&mut self, sp: Range<usize>, d: &Decl) {
+    let src = self.src;
+    let head_end = d.val.as_ref().map_or(sp.end, |v| v.span.start);
+    let toks = match tokens(&src[sp.start..head_end]) {
+      Some(toks) => toks,
+      None => return self.verbatim(sp)
+    };
+    if let Some(v) = &d.val {
+      if trim_start(&src[v.span.end..sp.end]) != b";" { return self.verbatim(sp) }
+    }
+    let h = match Header::new(&toks) {
+      Some(h) => h,
+      None => return self.out.extend(join(&toks, false)),
+    };
+    let end: &[u8] = if h.end == b'=' {b" ="} else {b";"};
+    let mut line = join(h.head, false);
+    for bi in &h.binders {
+      line.push(b' ');
+      line.extend(join(bi, false))
+    }
+    if !h.ret.is_empty() {
+      line.extend_from_slice(b": ");
+      for (i, r) in h.ret.iter().enumerate() {
+        if i != 0 { line.extend_from_slice(b" > ") }
+        line.extend(join(r, false))
+      }
+    }
+    line.extend_from_slice(end);
+    // A proof goes on its own line if it was written that way or does not fit after the
+    // header.
+    let mut own_line = src[trim_end(&src[..head_end]).len()..head_end].contains(&b'\n');
+    let first = match &d.val {
+      Some(v) if !matches!(v.k, SExprKind::Formula(_)) => {
+        let v = &src[v.span.start..v.span.end];
+        match v.iter().position(|&c| c == b'\n') {
+          Some(n) => 1 + trim_end(&v[..n]).len(),
+          None => 2 + v.len(),
+        }
+      }
+      _ => 0
+    };
+    if self.col() + line.len() <= self.width {
+      self.out.extend(line)
+    } else {
+      self.out.extend(join(h.head, false));
+      for (i, bi) in h.binders.iter().enumerate() {
+        let bi = join(bi, false);
+        let colon = usize::from(i + 1 == h.binders.len() && !h.ret.is_empty());
+        if self.col() + 1 + bi.len() + colon > self.width && i != 0 {
+          self.newline(2)
+        } else {
+          self.out.push(b' ')
+        }
+        self.out.extend(bi)
+      }
+      if h.ret.is_empty() { self.out.extend_from_slice(end) } else { self.out.push(b':') }
+      for (i, r) in h.ret.iter().enumerate() {
+        self.newline(2);
+        self.ret_part(r, if i + 1 == h.ret.len() {end} else {b" >"})
+      }
+    }
+    if let Some(v) = &d.val {
+      if let SExprKind::Formula(f) = &v.k {
+        let f = &src[f.0.start..f.0.end];
+        let mut flat = vec![b' '];
+        push_formula(&mut flat, f, false);
+        if self.col() + flat.len() < self.width {
+          self.out.extend(flat);
+          self.out.push(b';')
+        } else {
+          self.newline(2);
+          self.formula(f, b";")
+        }
+      } else {
+        own_line |= self.col() + first > self.width;
+        if own_line {
+          self.out.push(b'\n')
+        } else {
+          self.out.push(b' ')
+        }
+        self.lisp(&src[v.span.start..v.span.end], 0);
+        self.out.push(b';')
+      }
+    }
+  }
+}
+
+/// Format a parsed file, using the notations in `pe` (if available) to decide where to break
+/// long math strings. Returns `None` if the file has parse errors.
+#[must_use] pub fn format(ast: &AST, pe: Option<&ParserEnv>, width: usize) -> Option<String> {
+  if ast.errors.iter().any(|e| matches!(e.level, ErrorLevel::Error)) { return None }
+  let mut f = Formatter {src: ast.source.as_bytes(), pe, width, out: vec![]};
+  let mut last = 0;
+  for s in &ast.stmts {
+    f.gap(last, s.span.start, false);
+    f.stmt(s);
+    last = s.span.end;
+  }
+  f.gap(last, f.src.len(), true);
+  Some(String::from_utf8_lossy(&f.out).into_owned())
+}
+
+/// Main entry point for `mm0-rs format` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs format [-w WIDTH] [-o out.mm1] [--check] <in.mm1>`, where:
+///
+/// - `in.mm1` is the MM0 or MM1 file to format
+/// - `-w WIDTH` sets the maximum line width (default 80)
+/// - `out.mm1` is the file to write the result to (default: overwrite `in.mm1`)
+/// - `--check` checks that the file is formatted, without writing anything.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let width = match args.value_of("width") {
+    None => 80,
+    Some(n) => n.parse().map_err(|_|
+      io::Error::new(io::ErrorKind::InvalidInput, "expected a number for --width"))?,
+  };
+  let input = args.value_of("INPUT").expect("required arg");
+  let (_, text, env) = crate::compiler::elab_file(input)?;
+  let source = text.try_ascii().ok_or_else(||
+    io::Error::new(io::ErrorKind::InvalidInput, "expected a .mm0 or .mm1 file"))?.clone();
+  let (_, ast) = parse(source.clone(), None);
+  let out = match format(&ast, Some(&unsafe { env.thaw() }.pe), width) {
+    Some(out) => out,
+    None => {
+      eprintln!("{}: not formatted because of parse errors", input);
+      std::process::exit(1)
+    }
+  };
+  if args.is_present("check") {
+    if out != **source {
+      eprintln!("{}: not formatted", input);
+      std::process::exit(1)
+    }
+    return Ok(())
+  }
+  match args.value_of("output") {
+    Some("-") => io::stdout().write_all(out.as_bytes()),
+    Some(file) => fs::write(file, out),
+    None if out == **source => Ok(()),
+    None => fs::write(input, out),
+  }
+}
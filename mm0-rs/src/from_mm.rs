@@ -0,0 +1,906 @@
+//! Import a Metamath database as an MM1 file.
+//!
+//!     mm0-rs from-mm set.mm out.mm1
+//!
+//! will translate the declarations of `set.mm` into MM1:
+//!
+//! * Each typecode that is declared by a `$j syntax` statement becomes a sort, and the
+//!   typecode of provable assertions (`|-` in `syntax '|-' as 'wff';`) is mapped to the
+//!   `provable` sort it is an alias for. Sorts declared by `$j bound` (like `setvar`) are
+//!   the sorts of bound variables, written `{x: setvar}`.
+//! * Syntax axioms (`$a` statements with a syntax typecode) become `term`s, and
+//!   the other `$a` and `$p` statements become `axiom`s and `theorem`s. The mandatory
+//!   hypotheses turn into binders: first the bound variables, then the other variables,
+//!   which depend on all the bound variables they have no `$d` condition with, and then
+//!   the `$e` hypotheses. Math strings are parsed with the grammar given by the syntax
+//!   axioms, and printed in prefix form, like `$ wi ph (wn ps) $`.
+//! * Proofs, normal or compressed, are translated to refine scripts. Syntax theorems
+//!   (`$p` statements with a syntax typecode) are expanded where they are used.
+//!
+//! The grammar is read from the `$j` comments of the database, or from the file passed
+//! with `--grammar`, which should contain statements like those in `$j` comments:
+//!
+//! ```text
+//! syntax 'wff';
+//! syntax '|-' as 'wff';
+//! syntax 'class';
+//! syntax 'setvar';
+//! bound 'setvar';
+//! ```
+//!
+//! Labels and variables are renamed to valid MM1 identifiers, by replacing the
+//! characters MM1 does not allow in names with `_` (so `ax-mp` becomes `ax_mp`), and
+//! adding a suffix if the result is already taken.
+//!
+//! Not everything in a Metamath database has an MM0 counterpart, so the import is
+//! partial. Statements that can't be translated (for example, because a math string
+//! does not parse) are skipped with a comment, and proofs that can't be translated
+//! (because they are incomplete, use a skipped statement, need a dummy variable that is
+//! not a bound variable, or are longer than `--max-proof-size`) are replaced by
+//! `(admit)`. `$d` conditions between two variables that are not bound variables have
+//! no MM0 equivalent, and are dropped. The result should be elaborated with
+//! `mm0-rs compile`, which will report any remaining problems, for example a
+//! substitution of a non-distinct variable for a bound variable, which Metamath allows
+//! and MM0 does not. `--stop-at LABEL` ends the import after the statement `LABEL`,
+//! which is useful to import only the first part of a large database.
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use clap::ArgMatches;
+
+/// The default value of `--max-proof-size`, in bytes.
+const MAX_PROOF_SIZE: usize = 1_000_000;
+
+/// A token of a Metamath database: either a word, or the text of a comment.
+#[derive(Debug)]
+enum Tok {
+  /// A whitespace-separated word, like `$a` or `ax-mp`.
+  Word(Box<str>),
+  /// The contents of a `$( ... $)` comment.
+  Comment(Box<str>),
+}
+
+/// Split a string into whitespace-separated words, with their positions.
+fn words(text: &str) -> impl Iterator<Item=(usize, &str)> {
+  let mut pos = 0;
+  std::iter::from_fn(move || {
+    let rest = &text[pos..];
+    let start = pos + (rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_whitespace()).len());
+    if start == text.len() { return None }
+    let len = text[start..].find(|c: char| c.is_ascii_whitespace()).unwrap_or(text.len() - start);
+    pos = start + len;
+    Some((start, &text[start..pos]))
+  })
+}
+
+fn invalid(msg: String) -> io::Error { io::Error::new(io::ErrorKind::InvalidData, msg) }
+
+/// Read the tokens of a Metamath file, following `$[ file $]` includes (each file is
+/// included at most once).
+fn tokenize(path: &Path, seen: &mut HashSet<PathBuf>, out: &mut Vec<Tok>) -> io::Result<()> {
+  let text = fs::read_to_string(path)?;
+  let mut it = words(&text);
+  while let Some((start, w)) = it.next() {
+    match w {
+      "$(" => {
+        let body = start + 2;
+        loop {
+          match it.next() {
+            Some((end, "$)")) => {
+              out.push(Tok::Comment(text[body..end].into()));
+              break
+            }
+            Some(_) => {}
+            None => return Err(invalid(format!("{}: unclosed comment", path.display())))
+          }
+        }
+      }
+      "$[" => {
+        let file = match (it.next(), it.next()) {
+          (Some((_, file)), Some((_, "$]"))) => file,
+          _ => return Err(invalid(format!("{}: bad include statement", path.display())))
+        };
+        let file = path.parent().map_or_else(|| PathBuf::from(file), |dir| dir.join(file));
+        if seen.insert(fs::canonicalize(&file)?) { tokenize(&file, seen, out)? }
+      }
+      _ => out.push(Tok::Word(w.into()))
+    }
+  }
+  Ok(())
+}
+
+/// The typecode information from the `$j` statements.
+#[derive(Debug, Default)]
+struct Grammar {
+  /// The syntax typecodes, which become sorts, in declaration order.
+  syntax: Vec<String>,
+  /// The provable typecodes, mapped to the syntax typecode of their expressions.
+  provable: HashMap<String, String>,
+  /// The typecodes of bound variables.
+  bound: HashSet<String>,
+}
+
+impl Grammar {
+  /// Add the statements of a `$j` comment (or grammar file) to the grammar.
+  /// Statements other than `syntax` and `bound` are ignored.
+  fn add(&mut self, text: &str) {
+    for stmt in text.split(';') {
+      let ws: Vec<&str> = stmt.split_ascii_whitespace()
+        .map(|w| w.trim_matches(|c| c == '\'' || c == '"')).collect();
+      match *ws {
+        ["syntax", tc] => if !self.syntax.iter().any(|s| s == tc) { self.syntax.push(tc.into()) },
+        ["syntax", tc, "as", sort] => { self.provable.insert(tc.into(), sort.into()); }
+        ["bound", tc] => { self.bound.insert(tc.into()); }
+        _ => {}
+      }
+    }
+  }
+}
+
+/// An expression, in which the arguments of an application are in the order of the
+/// mandatory hypotheses of the syntax axiom.
+#[derive(Debug, PartialEq, Eq)]
+enum Expr {
+  /// A Metamath variable.
+  Var(Rc<str>),
+  /// A syntax axiom applied to arguments.
+  App(Rc<str>, Vec<Rc<Expr>>),
+}
+
+/// A proof, in which the arguments of an application are in the order of the
+/// mandatory hypotheses of the assertion.
+#[derive(Debug)]
+enum Proof {
+  /// A `$e` hypothesis of the theorem being proved.
+  Hyp(Rc<str>),
+  /// An assertion applied to expressions (for the `$f` hypotheses) and proofs
+  /// (for the `$e` hypotheses).
+  Thm(Rc<str>, Vec<Rc<Expr>>, Vec<Rc<Proof>>),
+}
+
+/// An entry of the proof stack.
+#[derive(Clone, Debug)]
+enum Step {
+  Expr(Rc<Expr>),
+  Proof(Rc<Proof>),
+}
+
+/// What an assertion was translated to.
+#[derive(Debug)]
+enum Kind {
+  /// A syntax axiom, translated to a `term`.
+  Term,
+  /// A syntax theorem, which is expanded to its proof (an expression) at each use.
+  Syntax(Rc<Expr>),
+  /// An `axiom` or `theorem`.
+  Thm,
+  /// An assertion that was not translated.
+  Skipped,
+}
+
+/// An assertion (`$a` or `$p` statement).
+#[derive(Debug)]
+struct Assert {
+  /// The MM1 name of the declaration.
+  name: String,
+  kind: Kind,
+  /// The mandatory hypotheses in Metamath order: the variable of a `$f`, or `None`
+  /// for a `$e`.
+  hyps: Vec<Option<Rc<str>>>,
+  /// For each binder of the MM1 declaration, the index of its variable among the
+  /// variables in `hyps`.
+  perm: Vec<usize>,
+}
+
+/// A symbol in the right hand side of a syntax axiom.
+#[derive(Debug)]
+enum Sym {
+  Const(Box<str>),
+  /// A variable with the given typecode, which is argument `n` of the syntax axiom.
+  Var(Rc<str>, usize),
+}
+
+/// A grammar production, given by a syntax axiom.
+#[derive(Debug)]
+struct Prod {
+  label: Rc<str>,
+  syms: Vec<Sym>,
+  nargs: usize,
+}
+
+/// A `$f` hypothesis.
+#[derive(Debug)]
+struct Float {
+  tc: Rc<str>,
+  var: Rc<str>,
+  /// The position of the statement, for ordering the mandatory hypotheses.
+  order: usize,
+}
+
+/// A `$e` hypothesis.
+#[derive(Debug)]
+struct Ess {
+  tc: Box<str>,
+  syms: Vec<Box<str>>,
+  order: usize,
+  /// The MM1 name of the hypothesis.
+  name: Rc<str>,
+}
+
+/// The declarations of a `${ ... $}` block.
+#[derive(Debug, Default)]
+struct Scope {
+  floats: Vec<Rc<str>>,
+  ess: Vec<Rc<str>>,
+  dvs: Vec<(Rc<str>, Rc<str>)>,
+}
+
+/// The mandatory hypotheses of an assertion.
+#[derive(Debug)]
+struct Frame {
+  /// The labels of the mandatory hypotheses, in Metamath order.
+  labels: Vec<Rc<str>>,
+  /// The mandatory hypotheses, as in [`Assert::hyps`].
+  hyps: Vec<Option<Rc<str>>>,
+  /// The `$e` hypotheses.
+  ess: Vec<Rc<str>>,
+}
+
+/// The productions of each typecode, by first symbol (`None` for a variable).
+type Prods = HashMap<(Rc<str>, Option<Box<str>>), Vec<usize>>;
+
+/// The state of the importer.
+#[derive(Debug)]
+struct Importer {
+  grammar: Grammar,
+  /// The sorts that have no syntax axioms, which are declared `pure`.
+  pure: HashSet<String>,
+  /// The MM1 names of the sorts, indexed by typecode.
+  sorts: HashMap<String, String>,
+  /// The indices of the productions in `prod_list`.
+  prods: Prods,
+  prod_list: Vec<Prod>,
+  scopes: Vec<Scope>,
+  /// The label of the active `$f` hypothesis of each variable.
+  float_of: HashMap<Rc<str>, Rc<str>>,
+  floats: HashMap<Rc<str>, Float>,
+  ess: HashMap<Rc<str>, Ess>,
+  asserts: HashMap<Rc<str>, Assert>,
+  /// The MM1 names of the variables.
+  var_names: HashMap<Rc<str>, String>,
+  /// The MM1 names already in use.
+  used: HashSet<String>,
+  max_proof_size: usize,
+  /// The number of statements processed, for ordering hypotheses.
+  order: usize,
+}
+
+/// A label or variable name made into a valid MM1 identifier, not in `used`.
+fn mangle(used: &mut HashSet<String>, s: &str) -> String {
+  let mut out: String = s.chars()
+    .map(|c| if c.is_ascii() && crate::parser::ident_rest(c as u8) { c } else { '_' }).collect();
+  if !crate::parser::ident_start(out.as_bytes()[0]) { out.insert(0, '_') }
+  if out == "_" { out.push('_') }
+  if !used.contains(&out) { used.insert(out.clone()); return out }
+  let mut n = 1;
+  loop {
+    let s = format!("{}_{}", out, n);
+    if !used.contains(&s) { used.insert(s.clone()); return s }
+    n += 1;
+  }
+}
+
+/// Print a Metamath comment as an MM1 doc comment.
+fn write_doc(w: &mut impl Write, doc: &str) -> io::Result<()> {
+  let lines: Vec<&str> = doc.lines().map(str::trim).collect();
+  let start = lines.iter().position(|l| !l.is_empty()).unwrap_or(lines.len());
+  let end = lines.iter().rposition(|l| !l.is_empty()).map_or(start, |i| i + 1);
+  for l in &lines[start..end] {
+    if l.is_empty() { writeln!(w, "--|")? } else { writeln!(w, "--| {}", l)? }
+  }
+  Ok(())
+}
+
+/// The parses of a typecode at a position, with their end positions.
+type Parses = Rc<Vec<(Rc<Expr>, usize)>>;
+
+/// A memoizing parser for the math string of a statement.
+struct ExprParser<'a> {
+  imp: &'a Importer,
+  syms: &'a [Box<str>],
+  /// The parses of each typecode at each position. An entry is added (empty) before the
+  /// parses are computed, so that left recursion fails.
+  memo: HashMap<(Rc<str>, usize), Parses>,
+  /// The entries being computed, innermost last. For each one, this also has the lowest
+  /// index in the stack of an entry whose empty placeholder was used to compute it (or
+  /// `usize::MAX`), and the entries that were memoized using its placeholder, which are
+  /// removed when it is replaced by the real parses.
+  stack: Vec<((Rc<str>, usize), usize, Vec<(Rc<str>, usize)>)>,
+  /// The memoized entries that were computed using a placeholder, with the index of the
+  /// placeholder in the stack. They are only valid until that placeholder is replaced.
+  deps: HashMap<(Rc<str>, usize), usize>,
+}
+
+impl ExprParser<'_> {
+  fn parse(&mut self, tc: &Rc<str>, pos: usize) -> Parses {
+    let key = (tc.clone(), pos);
+    if let Some(r) = self.memo.get(&key) {
+      // If this is a placeholder or depends on one, so do the caller's parses
+      let dep = self.stack.iter().position(|(k, _, _)| *k == key)
+        .or_else(|| self.deps.get(&key).copied());
+      if let (Some(i), Some(top)) = (dep, self.stack.last_mut()) { top.1 = top.1.min(i) }
+      return r.clone()
+    }
+    self.memo.insert(key.clone(), Rc::default());
+    self.stack.push((key.clone(), usize::MAX, vec![]));
+    let mut out = vec![];
+    let (imp, syms) = (self.imp, self.syms);
+    if let Some(s) = syms.get(pos) {
+      if let Some(f) = imp.float_of.get(&**s).map(|l| &imp.floats[l]) {
+        if f.tc == *tc { out.push((Rc::new(Expr::Var(f.var.clone())), pos + 1)) }
+      }
+      let first = imp.prods.get(&(tc.clone(), Some(s.clone())));
+      let var = imp.prods.get(&(tc.clone(), None));
+      for &i in first.into_iter().chain(var).flatten() {
+        self.production(&imp.prod_list[i], pos, &mut out)
+      }
+    }
+    let out = Rc::new(out);
+    self.memo.insert(key.clone(), out.clone());
+    let (_, dep, dependents) = self.stack.pop().expect("nonempty");
+    for k in dependents { self.memo.remove(&k); self.deps.remove(&k); }
+    if dep < self.stack.len() {
+      self.stack[dep].2.push(key.clone());
+      self.deps.insert(key, dep);
+      let top = self.stack.last_mut().expect("nonempty");
+      top.1 = top.1.min(dep);
+    }
+    out
+  }
+
+  fn production(&mut self, prod: &Prod, pos: usize, out: &mut Vec<(Rc<Expr>, usize)>) {
+    let mut states = vec![(pos, vec![None; prod.nargs])];
+    for sym in &prod.syms {
+      let mut next = vec![];
+      for (p, args) in states {
+        match sym {
+          Sym::Const(c) => if self.syms.get(p) == Some(c) { next.push((p + 1, args)) },
+          Sym::Var(tc, n) => for (e, p2) in &*self.parse(tc, p) {
+            let mut args = args.clone();
+            args[*n] = Some(e.clone());
+            next.push((*p2, args))
+          }
+        }
+      }
+      if next.is_empty() { return }
+      states = next;
+    }
+    for (p, args) in states {
+      if let Some(args) = args.into_iter().collect::<Option<Vec<_>>>() {
+        out.push((Rc::new(Expr::App(prod.label.clone(), args)), p))
+      }
+    }
+  }
+}
+
+impl Importer {
+  fn is_var(&self, s: &str) -> bool { self.float_of.contains_key(s) }
+
+  fn active_ess(&self) -> impl Iterator<Item=&Rc<str>> {
+    self.scopes.iter().flat_map(|s| &s.ess)
+  }
+
+  fn distinct(&self, x: &str, y: &str) -> bool {
+    self.scopes.iter().flat_map(|s| &s.dvs)
+      .any(|(a, b)| (**a == *x && **b == *y) || (**a == *y && **b == *x))
+  }
+
+  /// The MM1 name of a variable, which is chosen the first time it is used.
+  fn var_name(&mut self, v: &Rc<str>) -> String {
+    if let Some(s) = self.var_names.get(v) { return s.clone() }
+    let s = mangle(&mut self.used, v);
+    self.var_names.insert(v.clone(), s.clone());
+    s
+  }
+
+  /// The mandatory hypotheses of an assertion with the given math string.
+  fn frame(&self, syms: &[Box<str>], hyps: bool) -> Frame {
+    let ess: Vec<Rc<str>> = if hyps { self.active_ess().cloned().collect() } else { vec![] };
+    let mut vars = HashSet::new();
+    for s in syms.iter().chain(ess.iter().flat_map(|e| &self.ess[e].syms)) {
+      if self.is_var(s) { vars.insert(&**s); }
+    }
+    let mut hs: Vec<(usize, Rc<str>, Option<Rc<str>>)> = vars.into_iter().map(|v| {
+      let l = &self.float_of[v];
+      let f = &self.floats[l];
+      (f.order, l.clone(), Some(f.var.clone()))
+    }).collect();
+    hs.extend(ess.iter().map(|e| (self.ess[e].order, e.clone(), None)));
+    hs.sort_by_key(|h| h.0);
+    let (labels, hyps) = hs.into_iter().map(|(_, l, v)| (l, v)).unzip();
+    Frame {labels, hyps, ess}
+  }
+
+  /// Parse a math string as an expression of the given typecode. Returns `Ok(None)` if
+  /// there is no parse, and an error if there is more than one.
+  fn parse(&self, tc: &str, syms: &[Box<str>]) -> Result<Option<Rc<Expr>>, String> {
+    let mut p = ExprParser {imp: self, syms, memo: HashMap::new(), stack: vec![], deps: HashMap::new()};
+    let r = p.parse(&Rc::from(tc), 0);
+    let mut parses = r.iter().filter(|(_, end)| *end == syms.len()).map(|(e, _)| e);
+    let e = if let Some(e) = parses.next() {e} else {return Ok(None)};
+    if let Some(e2) = parses.find(|e2| *e2 != e) {
+      let (mut s1, mut s2) = (String::new(), String::new());
+      self.write_labels(e, &mut s1);
+      self.write_labels(e2, &mut s2);
+      return Err(format!("'{} {}' is ambiguous: it parses as {} and as {}",
+        tc, syms.join(" "), s1, s2))
+    }
+    Ok(Some(e.clone()))
+  }
+
+  /// Print an expression using the labels of the syntax axioms, for error messages.
+  fn write_labels(&self, e: &Expr, s: &mut String) {
+    match e {
+      Expr::Var(v) => s.push_str(v),
+      Expr::App(t, args) => {
+        s.push('(');
+        s.push_str(t);
+        for e in args { s.push(' '); self.write_labels(e, s) }
+        s.push(')');
+      }
+    }
+  }
+
+  /// Parse the math string of a statement with the given typecode, which should be a
+  /// provable typecode.
+  fn parse_stmt(&self, tc: &str, syms: &[Box<str>]) -> Result<Rc<Expr>, String> {
+    let sort = self.grammar.provable.get(tc)
+      .ok_or_else(|| format!("typecode {} is not provable", tc))?;
+    self.parse(sort, syms)?.ok_or_else(|| format!("could not parse '{} {}'", tc, syms.join(" ")))
+  }
+
+  /// Print the binders of a declaration, and compute its permutation
+  /// (see [`Assert::perm`]).
+  fn binders(&mut self, frame: &Frame, s: &mut String) -> Result<Vec<usize>, String> {
+    let vars: Vec<Rc<str>> = frame.hyps.iter().flatten().cloned().collect();
+    let mut tcs = vec![];
+    for v in &vars {
+      let tc = &self.floats[&self.float_of[v]].tc;
+      let sort = self.sorts.get(&**tc).ok_or_else(|| format!("typecode {} is not a sort", tc))?;
+      tcs.push((self.grammar.bound.contains(&**tc), sort.clone()));
+    }
+    let mut perm = vec![];
+    for (i, v) in vars.iter().enumerate() {
+      if tcs[i].0 {
+        perm.push(i);
+        let name = self.var_name(v);
+        write!(s, " {{{}: {}}}", name, tcs[i].1).expect("writing to a string");
+      }
+    }
+    for (i, v) in vars.iter().enumerate() {
+      if !tcs[i].0 {
+        perm.push(i);
+        let name = self.var_name(v);
+        write!(s, " ({}: {}", name, tcs[i].1).expect("writing to a string");
+        for (j, x) in vars.iter().enumerate() {
+          if tcs[j].0 && !self.distinct(x, v) {
+            let x = self.var_name(x);
+            write!(s, " {}", x).expect("writing to a string");
+          }
+        }
+        s.push(')');
+      }
+    }
+    Ok(perm)
+  }
+
+  /// Print an expression in a math string. Applications with arguments are
+  /// parenthesized unless `top` is set.
+  fn write_expr(&self, e: &Expr, top: bool, s: &mut String) {
+    match e {
+      Expr::Var(v) => s.push_str(&self.var_names[v]),
+      Expr::App(t, args) => {
+        let a = &self.asserts[t];
+        let paren = !top && !args.is_empty();
+        if paren { s.push('(') }
+        s.push_str(&a.name);
+        for &i in &a.perm { s.push(' '); self.write_expr(&args[i], false, s) }
+        if paren { s.push(')') }
+      }
+    }
+  }
+
+  fn math(&self, e: &Expr) -> String {
+    let mut s = String::from("$ ");
+    self.write_expr(e, true, &mut s);
+    s.push_str(" $");
+    s
+  }
+
+  /// Print the hypotheses of an axiom or theorem.
+  fn write_hyps(&self, frame: &Frame, s: &mut String) -> Result<(), String> {
+    for e in &frame.ess {
+      let h = &self.ess[e];
+      let e = self.parse_stmt(&h.tc, &h.syms)?;
+      write!(s, " ({}: {})", h.name, self.math(&e)).expect("writing to a string");
+    }
+    Ok(())
+  }
+
+  /// Print an expression as an s-expression, failing if the output exceeds the limit.
+  fn sexpr_expr(&self, e: &Expr, s: &mut String) -> Result<(), String> {
+    if s.len() > self.max_proof_size { return Err("the proof is too large".into()) }
+    match e {
+      Expr::Var(v) => s.push_str(&self.var_names[v]),
+      Expr::App(t, args) => {
+        let a = &self.asserts[t];
+        s.push('(');
+        s.push_str(&a.name);
+        for &i in &a.perm { s.push(' '); self.sexpr_expr(&args[i], s)? }
+        s.push(')');
+      }
+    }
+    Ok(())
+  }
+
+  /// Print a proof as a refine script, failing if the output exceeds the limit.
+  fn sexpr_proof(&self, p: &Proof, s: &mut String) -> Result<(), String> {
+    if s.len() > self.max_proof_size { return Err("the proof is too large".into()) }
+    match p {
+      Proof::Hyp(h) => s.push_str(h),
+      Proof::Thm(t, es, ps) => {
+        let a = &self.asserts[t];
+        s.push('(');
+        s.push_str(&a.name);
+        for &i in &a.perm { s.push(' '); self.sexpr_expr(&es[i], s)? }
+        for p in ps { s.push(' '); self.sexpr_proof(p, s)? }
+        s.push(')');
+      }
+    }
+    Ok(())
+  }
+
+  /// Perform one step of a proof.
+  fn step(&self, l: &str, stack: &mut Vec<Step>, vars: &mut Vec<Rc<str>>) -> Result<(), String> {
+    if let Some(f) = self.floats.get(l) {
+      if !vars.contains(&f.var) { vars.push(f.var.clone()) }
+      stack.push(Step::Expr(Rc::new(Expr::Var(f.var.clone()))));
+      return Ok(())
+    }
+    if let Some(h) = self.ess.get(l) {
+      stack.push(Step::Proof(Rc::new(Proof::Hyp(h.name.clone()))));
+      return Ok(())
+    }
+    let (l, a) = self.asserts.get_key_value(l).ok_or_else(|| format!("unknown label {}", l))?;
+    if let Kind::Skipped = a.kind { return Err(format!("it uses {}, which was skipped", l)) }
+    let n = stack.len().checked_sub(a.hyps.len()).ok_or("stack underflow")?;
+    let (mut es, mut ps) = (vec![], vec![]);
+    for (h, s) in a.hyps.iter().zip(stack.drain(n..)) {
+      match (h, s) {
+        (Some(_), Step::Expr(e)) => es.push(e),
+        (None, Step::Proof(p)) => ps.push(p),
+        _ => return Err(format!("bad arguments to {}", l))
+      }
+    }
+    stack.push(match &a.kind {
+      Kind::Term => Step::Expr(Rc::new(Expr::App(l.clone(), es))),
+      Kind::Syntax(e) => {
+        let subst: HashMap<&Rc<str>, Rc<Expr>> = a.hyps.iter().flatten().zip(es).collect();
+        Step::Expr(subst_expr(e, &subst))
+      }
+      Kind::Thm => Step::Proof(Rc::new(Proof::Thm(l.clone(), es, ps))),
+      Kind::Skipped => unreachable!(),
+    });
+    Ok(())
+  }
+
+  /// Run a proof, returning the result and the variables used.
+  fn run_proof(&self, labels: &[Rc<str>], proof: &[Box<str>]) -> Result<(Step, Vec<Rc<str>>), String> {
+    let mut stack = vec![];
+    let mut vars = vec![];
+    if proof.first().map(|s| &**s) == Some("(") {
+      let close = proof.iter().position(|s| &**s == ")").ok_or("bad compressed proof")?;
+      let refs = &proof[1..close];
+      let mut saved: Vec<Step> = vec![];
+      let mut n = 0_usize;
+      for c in proof[close + 1..].iter().flat_map(|s| s.bytes()) {
+        match c {
+          b'U'..=b'Y' => n = n * 5 + usize::from(c - b'U' + 1),
+          b'A'..=b'T' => {
+            n = n * 20 + usize::from(c - b'A' + 1);
+            if n <= labels.len() {
+              self.step(&labels[n - 1], &mut stack, &mut vars)?
+            } else if n <= labels.len() + refs.len() {
+              self.step(&refs[n - labels.len() - 1], &mut stack, &mut vars)?
+            } else {
+              let s = saved.get(n - labels.len() - refs.len() - 1).ok_or("bad compressed proof")?;
+              stack.push(s.clone())
+            }
+            n = 0;
+          }
+          b'Z' => saved.push(stack.last().ok_or("bad compressed proof")?.clone()),
+          b'?' => return Err("the proof is incomplete".into()),
+          _ => return Err("bad compressed proof".into()),
+        }
+      }
+    } else {
+      for l in proof {
+        if &**l == "?" { return Err("the proof is incomplete".into()) }
+        self.step(l, &mut stack, &mut vars)?
+      }
+    }
+    match (stack.pop(), stack.is_empty()) {
+      (Some(s), true) => Ok((s, vars)),
+      _ => Err("the proof does not end with one statement".into())
+    }
+  }
+
+  /// Translate the proof of a theorem to a refine script, also returning the
+  /// dummy variable binders.
+  fn proof(&mut self, frame: &Frame, proof: &[Box<str>]) -> Result<(String, String), String> {
+    let (p, vars) = self.run_proof(&frame.labels, proof)?;
+    let p = match p { Step::Proof(p) => p, Step::Expr(_) => return Err("the proof is a syntax proof".into()) };
+    let mut dummies = String::new();
+    for v in vars {
+      if frame.hyps.iter().flatten().any(|w| *w == v) { continue }
+      let tc = self.floats[&self.float_of[&v]].tc.clone();
+      if !self.grammar.bound.contains(&*tc) {
+        return Err(format!("it uses the dummy variable {} of non-bound typecode {}", v, tc))
+      }
+      let name = self.var_name(&v);
+      let sort = self.sorts.get(&*tc).ok_or_else(|| format!("typecode {} is not a sort", tc))?;
+      write!(dummies, " {{.{}: {}}}", name, sort).expect("writing to a string");
+    }
+    let mut s = String::from("'");
+    self.sexpr_proof(&p, &mut s)?;
+    Ok((dummies, s))
+  }
+
+  /// Add a syntax axiom as a production, and return the declaration of its term.
+  fn syntax_axiom(&mut self, label: &Rc<str>, name: &str, tc: &str, syms: &[Box<str>]) -> Result<(Kind, Frame, Vec<usize>, String), String> {
+    let frame = self.frame(syms, false);
+    if self.active_ess().next().is_some() { return Err("syntax axiom with hypotheses".into()) }
+    let sort = self.sorts.get(tc).ok_or_else(|| format!("typecode {} is not a sort", tc))?.clone();
+    let mut s = format!("term {}", name);
+    let perm = self.binders(&frame, &mut s)?;
+    write!(s, ": {};", sort).expect("writing to a string");
+    let vars: Vec<&Rc<str>> = frame.hyps.iter().flatten().collect();
+    let syms2 = syms.iter().map(|c| match vars.iter().position(|v| ***v == **c) {
+      Some(n) => Sym::Var(self.floats[&self.float_of[vars[n]]].tc.clone(), n),
+      None => Sym::Const(c.clone()),
+    }).collect::<Vec<_>>();
+    let first = match syms2.first() {
+      None => return Err("empty syntax axiom".into()),
+      Some(Sym::Const(c)) => Some(c.clone()),
+      Some(Sym::Var(..)) => None,
+    };
+    self.prods.entry((tc.into(), first)).or_default().push(self.prod_list.len());
+    self.prod_list.push(Prod {label: label.clone(), syms: syms2, nargs: vars.len()});
+    Ok((Kind::Term, frame, perm, s))
+  }
+
+  /// Translate an assertion, returning its kind, frame, permutation and declaration.
+  fn assertion(&mut self, label: &Rc<str>, name: &str, tc: &str, syms: &[Box<str>],
+      proof: Option<&[Box<str>]>) -> Result<(Kind, Frame, Vec<usize>, String), String> {
+    let syntax = self.sorts.contains_key(tc) && !self.grammar.provable.contains_key(tc);
+    match (syntax, proof) {
+      (true, None) => return self.syntax_axiom(label, name, tc, syms),
+      (true, Some(proof)) => {
+        let frame = self.frame(syms, false);
+        return match self.run_proof(&frame.labels, proof)? {
+          (Step::Expr(e), _) => Ok((Kind::Syntax(e), frame, vec![], String::new())),
+          (Step::Proof(_), _) => Err("the syntax proof proves a statement".into()),
+        }
+      }
+      (false, _) => {}
+    }
+    let e = self.parse_stmt(tc, syms)?;
+    let frame = self.frame(syms, true);
+    let mut s = format!("{} {}", if proof.is_some() { "theorem" } else { "axiom" }, name);
+    let perm = self.binders(&frame, &mut s)?;
+    self.write_hyps(&frame, &mut s)?;
+    write!(s, ":\n  {}", self.math(&e)).expect("writing to a string");
+    if let Some(proof) = proof {
+      // the binders are already in `self.asserts` for uses in the proof printer
+      self.asserts.insert(label.clone(), Assert {
+        name: name.into(), kind: Kind::Thm, hyps: frame.hyps.clone(), perm: perm.clone()});
+      match self.proof(&frame, proof) {
+        Ok((dummies, p)) => {
+          if !dummies.is_empty() {
+            // the dummies go before the colon
+            let i = s.find(":\n").expect("colon");
+            s.insert_str(i, &dummies);
+          }
+          write!(s, " =\n{};", p).expect("writing to a string")
+        }
+        Err(e) => write!(s, " =\n-- from-mm: {}\n'(admit);", e).expect("writing to a string"),
+      }
+    } else { s.push(';') }
+    Ok((Kind::Thm, frame, perm, s))
+  }
+
+  /// Read a statement ending with `$.` (or `$=`), starting at `toks[*i]`.
+  fn read_until(toks: &[Tok], i: &mut usize, end: &str) -> io::Result<Vec<Box<str>>> {
+    let mut out = vec![];
+    loop {
+      match toks.get(*i) {
+        None => return Err(invalid(format!("expected {}", end))),
+        Some(Tok::Comment(_)) => {}
+        Some(Tok::Word(w)) if **w == *end => { *i += 1; return Ok(out) }
+        Some(Tok::Word(w)) => out.push(w.clone()),
+      }
+      *i += 1;
+    }
+  }
+
+  /// Import the statements, writing the output to `w`.
+  fn run(&mut self, toks: &[Tok], stop_at: Option<&str>, w: &mut impl Write) -> io::Result<()> {
+    let mut doc: Option<&str> = None;
+    let mut i = 0;
+    while let Some(t) = toks.get(i) {
+      i += 1;
+      let w0 = match t {
+        Tok::Comment(c) => {
+          let t = c.trim_start();
+          if !t.starts_with("$j") && !t.starts_with("$t") { doc = Some(&**c) }
+          continue
+        }
+        Tok::Word(w0) => w0,
+      };
+      self.order += 1;
+      let doc = doc.take();
+      match &**w0 {
+        "${" => self.scopes.push(Scope::default()),
+        "$}" => {
+          if self.scopes.len() <= 1 { return Err(invalid("unmatched $}".into())) }
+          let s = self.scopes.pop().expect("nonempty");
+          for f in s.floats {
+            let v = self.floats[&f].var.clone();
+            if self.float_of.get(&v) == Some(&f) { self.float_of.remove(&v); }
+          }
+        }
+        // variables are active when they have a `$f` hypothesis
+        "$c" | "$v" => { Self::read_until(toks, &mut i, "$.")?; }
+        "$d" => {
+          let vs: Vec<Rc<str>> = Self::read_until(toks, &mut i, "$.")?.into_iter().map(Rc::from).collect();
+          let scope = self.scopes.last_mut().expect("nonempty");
+          for (j, x) in vs.iter().enumerate() {
+            for y in &vs[j + 1..] { scope.dvs.push((x.clone(), y.clone())) }
+          }
+        }
+        _ => {
+          let label: Rc<str> = (&**w0).into();
+          let kw = match toks.get(i) {
+            Some(Tok::Word(kw)) => kw,
+            _ => return Err(invalid(format!("{}: expected a keyword", label)))
+          };
+          i += 1;
+          match &**kw {
+            "$f" => {
+              let s = Self::read_until(toks, &mut i, "$.")?;
+              let (tc, var) = match &*s {
+                [tc, var] => (Rc::from(&**tc), Rc::from(&**var)),
+                _ => return Err(invalid(format!("{}: bad $f statement", label)))
+              };
+              self.float_of.insert(Rc::clone(&var), label.clone());
+              self.floats.insert(label.clone(), Float {tc, var, order: self.order});
+              self.scopes.last_mut().expect("nonempty").floats.push(label.clone());
+            }
+            "$e" => {
+              let mut s = Self::read_until(toks, &mut i, "$.")?;
+              if s.is_empty() { return Err(invalid(format!("{}: bad $e statement", label))) }
+              let tc = s.remove(0);
+              let name = mangle(&mut self.used, &label).into();
+              self.ess.insert(label.clone(), Ess {tc, syms: s, order: self.order, name});
+              self.scopes.last_mut().expect("nonempty").ess.push(label.clone());
+            }
+            "$a" | "$p" => {
+              let (mut s, proof) = if &**kw == "$a" {
+                (Self::read_until(toks, &mut i, "$.")?, None)
+              } else {
+                let s = Self::read_until(toks, &mut i, "$=")?;
+                (s, Some(Self::read_until(toks, &mut i, "$.")?))
+              };
+              if s.is_empty() { return Err(invalid(format!("{}: empty statement", label))) }
+              let tc = s.remove(0);
+              let name = mangle(&mut self.used, &label);
+              match self.assertion(&label, &name, &tc, &s, proof.as_deref()) {
+                Ok((kind, frame, perm, decl)) => {
+                  if !decl.is_empty() {
+                    writeln!(w)?;
+                    if let Some(doc) = doc { write_doc(w, doc)? }
+                    writeln!(w, "{}", decl)?;
+                  }
+                  self.asserts.insert(label.clone(), Assert {name, kind, hyps: frame.hyps, perm});
+                }
+                Err(e) => {
+                  writeln!(w, "\n-- from-mm: skipped {}: {}", label, e)?;
+                  self.asserts.insert(label.clone(), Assert {name, kind: Kind::Skipped, hyps: vec![], perm: vec![]});
+                }
+              }
+            }
+            _ => return Err(invalid(format!("{}: unexpected keyword {}", label, kw)))
+          }
+          if stop_at == Some(&*label) { break }
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Substitute expressions for the variables in an expression.
+fn subst_expr(e: &Rc<Expr>, subst: &HashMap<&Rc<str>, Rc<Expr>>) -> Rc<Expr> {
+  match &**e {
+    Expr::Var(v) => subst.get(v).cloned().unwrap_or_else(|| e.clone()),
+    Expr::App(t, args) => Rc::new(Expr::App(t.clone(), args.iter().map(|e| subst_expr(e, subst)).collect())),
+  }
+}
+
+/// Main entry point for `mm0-rs from-mm` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs from-mm <in.mm> [out.mm1]`, where:
+///
+/// - `in.mm` is the Metamath database to import
+/// - `out.mm1` is the output location, or stdout if omitted.
+/// - `--grammar FILE` reads the grammar from `FILE` instead of the `$j` comments
+/// - `--stop-at LABEL` stops the import after the statement `LABEL`
+/// - `--max-proof-size N` replaces proofs longer than `N` bytes by `(admit)`
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let path = PathBuf::from(args.value_of("INPUT").expect("required arg"));
+  let mut toks = vec![];
+  let mut seen = HashSet::new();
+  seen.insert(fs::canonicalize(&path)?);
+  tokenize(&path, &mut seen, &mut toks)?;
+  let mut grammar = Grammar::default();
+  if let Some(file) = args.value_of("grammar") {
+    grammar.add(&fs::read_to_string(file)?)
+  } else {
+    for t in &toks {
+      if let Tok::Comment(c) = t {
+        if let Some(c) = c.trim_start().strip_prefix("$j") { grammar.add(c) }
+      }
+    }
+  }
+  if grammar.syntax.is_empty() {
+    return Err(invalid("no syntax typecodes: use --grammar, or a database with $j comments".into()))
+  }
+  // the sorts with syntax axioms are not pure
+  let mut pure: HashSet<String> = grammar.syntax.iter().cloned().collect();
+  for ws in toks.windows(3) {
+    if let [_, Tok::Word(kw), Tok::Word(tc)] = ws {
+      if &**kw == "$a" { pure.remove(&**tc); }
+    }
+  }
+  let max_proof_size = match args.value_of("max_proof_size") {
+    Some(n) => n.parse().map_err(|_| invalid("--max-proof-size: expected a number".into()))?,
+    None => MAX_PROOF_SIZE,
+  };
+  let mut imp = Importer {
+    grammar, pure, sorts: HashMap::new(), prods: HashMap::new(), prod_list: vec![],
+    scopes: vec![Scope::default()], float_of: HashMap::new(), floats: HashMap::new(),
+    ess: HashMap::new(), asserts: HashMap::new(), var_names: HashMap::new(),
+    used: HashSet::new(), max_proof_size, order: 0,
+  };
+  let mut w: io::BufWriter<Box<dyn Write>> = io::BufWriter::new(match args.value_of("OUTPUT") {
+    None => Box::new(io::stdout()),
+    Some(out) => Box::new(File::create(out)?),
+  });
+  writeln!(w, "-- Imported from {} by mm0-rs from-mm\n", path.display())?;
+  writeln!(w, "delimiter $ ( ) $;")?;
+  let mut sort_names = HashSet::new();
+  for tc in &imp.grammar.syntax {
+    let name = mangle(&mut sort_names, tc);
+    let provable = imp.grammar.provable.values().any(|s| s == tc);
+    write!(w, "{}", if imp.pure.contains(tc) { "pure " } else { "" })?;
+    writeln!(w, "{}sort {};", if provable { "provable " } else { "" }, name)?;
+    imp.sorts.insert(tc.clone(), name);
+  }
+  imp.run(&toks, args.value_of("stop_at"), &mut w)?;
+  w.flush()
+}
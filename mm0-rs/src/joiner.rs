@@ -12,96 +12,350 @@
 //! of the `import "peano.mm0";` statement. The resulting file will be a proper MM0 file and
 //! can be run through the `mm0-c` verifier and other conforming verifiers.
 //!
+//! Several developments can be bundled into one file by adding more roots, as in
+//! `mm0-rs join a.mm1 out.mm1 --bundle b.mm1`. A file imported by more than one of them is
+//! only included once, but the roots may also declare the same names independently. So
+//! when a name is declared more than once:
+//!
+//! * If the declarations are the same (up to whitespace), the later one is removed.
+//! * Otherwise, if one of them is local (a `local def`, or a `theorem` without `pub` in an
+//!   `.mm1` file), it is renamed to a fresh name like `foo_1`, together with its uses in the
+//!   files that can see it. The uses are found by elaborating the files, in the same way as
+//!   for renaming in the server, so bound variables and notations with the same name are
+//!   not affected. If the name also appears in lisp code other than the recorded uses (for
+//!   example quoted in a `do` block), it cannot be renamed safely, and nothing is written.
+//! * Otherwise, the files cannot be joined, and nothing is written.
+//!
 //! [`mm0.mm0`]: https://github.com/digama0/mm0/blob/master/examples/mm0.mm0
-use std::collections::HashSet;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, hash_map::Entry};
 use std::fs;
 use std::io::{self, Write};
-use std::path::PathBuf;
 use std::sync::Arc;
 use clap::ArgMatches;
-use crate::util::FileRef;
+use crate::util::{FileRef, Span};
 use crate::lined_string::LinedString;
-use crate::parser::{parse, ast::StmtKind};
+use crate::elab::{ElabResult, FrozenEnv, environment::ObjectKind};
+use crate::parser::{AST, parse, ast::{Atom, DeclKind, Modifiers, SExpr, SExprKind, Stmt, StmtKind}};
+
+/// A piece of the joined file.
+enum Segment {
+  /// The text of a file in the given range, with the edits applied.
+  Text(FileRef, Span),
+  /// The comment naming a file, written after its text.
+  Header(FileRef),
+  /// A blank line after an included file.
+  Newline,
+}
+
+/// A file being joined.
+struct Source {
+  /// The text of the file.
+  src: Arc<LinedString>,
+  /// The parsed file.
+  ast: Arc<AST>,
+  /// The files imported by this file.
+  imports: Vec<FileRef>,
+  /// Replacements for parts of the text, to remove duplicate declarations and rename
+  /// local ones.
+  edits: Vec<(Span, String)>,
+}
+
+/// A declaration that has been seen while looking for duplicates.
+struct Seen {
+  /// The file containing the declaration.
+  file: FileRef,
+  /// The span of the declared name.
+  id: Span,
+  /// The text of the declaration, with the whitespace normalized.
+  text: Vec<u8>,
+  /// True if the declaration is local, and so may be renamed.
+  local: bool,
+}
 
 /// Running data for the file join process.
-struct Joiner<W: Write> {
+struct Joiner {
   /// True if we should add auxiliary comments
   comments: bool,
   /// The current stack of file references, to reify the recursive process of
   /// following `import` directives. This lets us check for import cycles.
   stack: Vec<FileRef>,
-  /// The set of files that have already been output or are currently being output.
+  /// The files that have already been output or are currently being output.
   /// A file that has been output will not be printed again if another `import`
   /// for the same file is declared.
   /// This means that in a diamond dependence `A -> {B, C} -> D`, `A` will not be
   /// printed twice (once before `B` and once before `C`).
-  working: HashSet<FileRef>,
+  files: HashMap<FileRef, Source>,
+  /// The pieces of the output, in order.
+  segments: Vec<Segment>,
   /// The list of files that were output, in post-order traversal.
   done: Vec<FileRef>,
-  /// The writer to print the output file to
-  w: W,
+  /// The roots of the join.
+  roots: Vec<FileRef>,
+  /// The removed and renamed declarations, for the header.
+  notes: Vec<String>,
 }
 
-impl<W: Write> Joiner<W> {
-  /// Create a new [`Joiner`] from a writer.
-  fn new(comments: bool, w: W) -> Self {
-    Self {comments, stack: vec![], working: HashSet::new(), done: vec![], w}
+/// The name declared by a statement: the span of the name, whether it is a sort
+/// (which have their own namespace), and whether the declaration is local.
+fn declared(s: &Stmt, mm1: bool) -> Option<(Span, bool, bool)> {
+  match &s.k {
+    StmtKind::DocComment(_, s) | StmtKind::Annot(_, s) => declared(s, mm1),
+    StmtKind::Sort(id, _) => Some((*id, true, false)),
+    StmtKind::Decl(d) => Some((d.id, false, d.mods.contains(Modifiers::LOCAL) ||
+      mm1 && d.k == DeclKind::Thm && !d.mods.contains(Modifiers::PUB))),
+    _ => None
   }
+}
 
-  /// Write the file at `path` to `self.w`, following all imports recursively.
-  fn write(&mut self, path: FileRef) -> io::Result<()> {
+/// The spans in the file of `env` where the term or theorem `name` is used, including the
+/// declaration, as recorded by the elaborator.
+fn uses(env: &FrozenEnv, name: &[u8]) -> Vec<Span> {
+  let mut out = vec![];
+  for spans in env.spans() {
+    for &(sp, ref k) in spans {
+      let a = match *k {
+        ObjectKind::Term(t, _) => env.term(t).atom,
+        ObjectKind::Thm(t) => env.thm(t).atom,
+        ObjectKind::Proof(ref p) => match p.uncons().next().and_then(|h| h.as_atom()) {
+          Some(a) => a,
+          None => continue
+        },
+        _ => continue
+      };
+      if *env.data()[a].name().0 == *name { out.push(sp) }
+    }
+  }
+  out
+}
+
+/// Add the spans of the identifiers in the lisp code of `s` to `out`: its annotations,
+/// the value or proof of a declaration, `do` blocks and `input`/`output` statements.
+/// Identifiers inside formulas are not included.
+fn lisp_idents(s: &Stmt, out: &mut Vec<Span>) {
+  fn sexpr(e: &SExpr, out: &mut Vec<Span>) {
+    match &e.k {
+      SExprKind::Atom(Atom::Ident) => out.push(e.span),
+      SExprKind::List(es) => for e in es { sexpr(e, out) },
+      SExprKind::DottedList(es, r) => {
+        for e in es { sexpr(e, out) }
+        sexpr(r, out)
+      }
+      SExprKind::DocComment(_, e) => sexpr(e, out),
+      _ => {}
+    }
+  }
+  match &s.k {
+    StmtKind::Annot(e, s) => { sexpr(e, out); lisp_idents(s, out) }
+    StmtKind::DocComment(_, s) => lisp_idents(s, out),
+    StmtKind::Decl(d) => if let Some(e) = &d.val { sexpr(e, out) },
+    StmtKind::Do(es) | StmtKind::Inout {hs: es, ..} => for e in es { sexpr(e, out) },
+    _ => {}
+  }
+}
+
+impl Joiner {
+  /// Create a new [`Joiner`].
+  fn new(comments: bool) -> Self {
+    Self {comments, stack: vec![], files: HashMap::new(), segments: vec![],
+      done: vec![], roots: vec![], notes: vec![]}
+  }
+
+  /// Add the file at `path` to the output, following all imports recursively.
+  fn add(&mut self, path: FileRef) -> io::Result<()> {
     if let Some(i) = self.stack.iter().rposition(|x| x == &path) {
       self.stack.push(path);
       panic!("import cycle: {:?}", &self.stack[i..])
     }
     self.stack.push(path.clone());
     let src = Arc::<LinedString>::new(fs::read_to_string(path.path())?.into());
-    let (_, ast) = parse(src.clone(), None);
+    let ast = Arc::new(parse(src.clone(), None).1);
+    self.files.insert(path.clone(),
+      Source {src: src.clone(), ast: ast.clone(), imports: vec![], edits: vec![]});
+    let mut imports = vec![];
     let mut start = 0;
     for s in &ast.stmts {
       if let StmtKind::Import(_, f) = &s.k {
         let f = std::str::from_utf8(f).map_err(|_|
           io::Error::new(io::ErrorKind::InvalidInput, "invalid utf8"))?;
-        let r: FileRef = path.path().parent()
-          .map_or_else(|| PathBuf::from(f), |p| p.join(f))
-          .canonicalize()?.into();
-        self.w.write_all(&src.as_bytes()[start..s.span.start])?;
-        if self.working.insert(r.clone()) {
-          self.write(r)?;
-          if self.comments {
-            self.w.write_all(&[b'\n'])?;
-          }
+        let r: FileRef = crate::project::resolve_import(path.path(), f)?.into();
+        self.segments.push(Segment::Text(path.clone(), (start..s.span.start).into()));
+        imports.push(r.clone());
+        if !self.files.contains_key(&r) {
+          self.add(r)?;
+          if self.comments { self.segments.push(Segment::Newline) }
         }
         start = s.span.end;
       }
     }
-    if self.comments {
-      write!(self.w, "{}\n-- {} --\n{0}\n",
-        // Safety: '-' is utf8
-        unsafe { String::from_utf8_unchecked(vec![b'-'; path.rel().len() + 6]) },
-        path.rel())?;
-    }
-    self.w.write_all(&src.as_bytes()[start..])?;
+    self.files.get_mut(&path).expect("file was added").imports = imports;
+    if self.comments { self.segments.push(Segment::Header(path.clone())) }
+    self.segments.push(Segment::Text(path.clone(), (start..src.len()).into()));
     self.stack.pop();
     self.done.push(path);
     Ok(())
   }
+
+  /// Add a root file to the output, if it is not already included.
+  fn add_root(&mut self, path: FileRef) -> io::Result<()> {
+    self.roots.push(path.clone());
+    if self.files.contains_key(&path) { return Ok(()) }
+    if self.comments && !self.segments.is_empty() { self.segments.push(Segment::Newline) }
+    self.add(path)
+  }
+
+  /// Find the names that are declared more than once, and add the edits to remove the
+  /// duplicate declarations and rename the local ones (see the [module documentation](self)).
+  fn resolve_duplicates(&mut self) -> io::Result<()> {
+    let mut decls = vec![];
+    for seg in &self.segments {
+      if let Segment::Text(file, sp) = seg {
+        let f = &self.files[file];
+        let mm1 = file.has_extension("mm1");
+        for s in &f.ast.stmts {
+          if s.span.start < sp.start || sp.end < s.span.end { continue }
+          if let Some((id, sort, local)) = declared(s, mm1) {
+            let mut body = s;
+            while let StmtKind::DocComment(_, s2) = &body.k { body = s2 }
+            let text = f.src[body.span].split(u8::is_ascii_whitespace)
+              .filter(|w| !w.is_empty()).collect::<Vec<_>>().join(&b' ');
+            decls.push((sort, Seen {file: file.clone(), id, text, local}, s.span))
+          }
+        }
+      }
+    }
+    let mut names = decls.iter().map(|(_, d, _)| self.files[&d.file].src[d.id].to_vec())
+      .collect::<HashSet<_>>();
+    let mut seen = HashMap::<(bool, Vec<u8>), Seen>::new();
+    let mut renames = vec![];
+    for (sort, d, stmt) in decls {
+      let name = self.files[&d.file].src[d.id].to_vec();
+      let name_str = String::from_utf8_lossy(&name).into_owned();
+      match seen.entry((sort, name.clone())) {
+        Entry::Vacant(e) => { e.insert(d); }
+        Entry::Occupied(mut e) => {
+          let prev = e.get();
+          if prev.text == d.text {
+            self.files.get_mut(&d.file).expect("file").edits.push((stmt, String::new()));
+            self.notes.push(format!("removed the duplicate declaration of {} in {}, \
+              which is the same as the one in {}", name_str, d.file, prev.file));
+          } else if d.local || prev.local {
+            let d = if d.local { d } else { e.insert(d) };
+            let new_name = (1..).map(|i| format!("{}_{}", name_str, i))
+              .find(|n| !names.contains(n.as_bytes())).expect("infinite");
+            names.insert(new_name.clone().into_bytes());
+            self.notes.push(format!("renamed the local declaration {} in {} to {}, \
+              because {} is also declared in {}",
+              name_str, d.file, new_name, name_str, e.get().file));
+            renames.push((d.file, d.id, name, new_name));
+          } else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+              "{} is declared differently in {} and {}, and neither declaration is local, \
+              so the files cannot be joined", name_str, prev.file, d.file)))
+          }
+        }
+      }
+    }
+    if !renames.is_empty() { self.rename(renames)? }
+    for f in self.files.values_mut() {
+      f.edits.sort_by_key(|(sp, _)| (sp.start, Reverse(sp.end)));
+      f.edits.dedup_by_key(|(sp, _)| *sp);
+    }
+    Ok(())
+  }
+
+  /// Rename the local declarations `(file, id, name, new_name)` and their uses, in `file`
+  /// and the files that import it (directly or indirectly). The files are elaborated to
+  /// find the uses. Lisp code can refer to a declaration in ways the elaborator does not
+  /// record, like a quoted atom in a `do` block, so if the name appears in lisp code
+  /// anywhere other than the recorded uses, an error is returned instead.
+  fn rename(&mut self, renames: Vec<(FileRef, Span, Vec<u8>, String)>) -> io::Result<()> {
+    /// The files imported by `file`, directly or indirectly, and `file` itself.
+    fn imports(files: &HashMap<FileRef, Source>, file: &FileRef,
+      closure: &mut HashMap<FileRef, HashSet<FileRef>>
+    ) -> HashSet<FileRef> {
+      if let Some(s) = closure.get(file) { return s.clone() }
+      let mut out = HashSet::new();
+      out.insert(file.clone());
+      for f in &files[file].imports { out.extend(imports(files, f, closure)) }
+      closure.insert(file.clone(), out.clone());
+      out
+    }
+    // The output may go to stdout, so the elaborator should not print anything
+    crate::compiler::set_quiet(true);
+    for (res, _) in crate::compiler::elab_files(self.roots.clone()) {
+      if !matches!(res?, ElabResult::Ok(..)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+          "the files could not be elaborated to find the uses of the local declarations"))
+      }
+    }
+    let mut closure = HashMap::new();
+    let all = self.files.keys().cloned().collect::<Vec<_>>();
+    for (file, id, name, new_name) in renames {
+      self.files.get_mut(&file).expect("file").edits.push((id, new_name.clone()));
+      for h in &all {
+        if !imports(&self.files, h, &mut closure).contains(&file) { continue }
+        let env = crate::compiler::elaborated(h).ok_or_else(|| io::Error::new(
+          io::ErrorKind::InvalidData, format!("{} was not elaborated", h)))?;
+        let f = self.files.get_mut(h).expect("file");
+        let sps = uses(&env, &name);
+        let mut idents = vec![];
+        for s in &f.ast.stmts { lisp_idents(s, &mut idents) }
+        if let Some(&sp) = idents.iter().find(|&&sp| f.src[sp] == *name && !sps.contains(&sp)) {
+          let pos = f.src.to_pos(sp.start);
+          return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+            "{}:{}:{}: cannot rename '{}' to '{}', because it appears in lisp code",
+            h, pos.line + 1, pos.character + 1, String::from_utf8_lossy(&name), new_name)))
+        }
+        for sp in sps {
+          if f.src[sp] == *name { f.edits.push((sp, new_name.clone())) }
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Write the joined file to `w`.
+  fn write(&self, mut w: impl Write) -> io::Result<()> {
+    for seg in &self.segments {
+      match seg {
+        Segment::Text(file, sp) => {
+          let f = &self.files[file];
+          let text = f.src.as_bytes();
+          let mut pos = sp.start;
+          for (esp, new) in &f.edits {
+            if esp.start < pos || sp.end < esp.end { continue }
+            w.write_all(&text[pos..esp.start])?;
+            w.write_all(new.as_bytes())?;
+            pos = esp.end;
+          }
+          w.write_all(&text[pos..sp.end])?;
+        }
+        Segment::Header(path) => write!(w, "{}\n-- {} --\n{0}\n",
+          // Safety: '-' is utf8
+          unsafe { String::from_utf8_unchecked(vec![b'-'; path.rel().len() + 6]) },
+          path.rel())?,
+        Segment::Newline => w.write_all(&[b'\n'])?,
+      }
+    }
+    Ok(())
+  }
 }
 
-fn join_with_header(comments: bool, header: bool, mut w: impl Write, file: FileRef) -> io::Result<()> {
-  let mut buf = vec![];
-  if comments && header {
-    let mut joiner = Joiner::new(comments, &mut buf);
-    joiner.write(file.clone())?;
+fn join_with_header(joiner: &Joiner, header: bool, mut w: impl Write) -> io::Result<()> {
+  if joiner.comments && header {
+    let roots = joiner.roots.iter().map(ToString::to_string).collect::<Vec<_>>();
     writeln!(w, "\
       -- This is an autogenerated file constructed by `mm0-rs join {}`.\n\
-      -- It concatenates the files:", file)?;
-    for p in joiner.done { writeln!(w, "-- * {}", p)? }
+      -- It concatenates the files:", roots.join(" "))?;
+    for p in &joiner.done { writeln!(w, "-- * {}", p)? }
+    if !joiner.notes.is_empty() {
+      writeln!(w, "-- and makes these changes:")?;
+      for n in &joiner.notes { writeln!(w, "-- * {}", n)? }
+    }
     writeln!(w)?;
-    w.write_all(&buf)
-  } else {
-    Joiner::new(comments, w).write(file)
   }
+  joiner.write(w)
 }
 
 /// Main entry point for `mm0-rs join` subcommand.
@@ -114,13 +368,22 @@ fn join_with_header(comments: bool, header: bool, mut w: impl Write, file: FileR
 ///
 /// - `in.mm0` (or `in.mm1`) is the file to join, an MM0 file with `import`s
 /// - `out.mm0` is the output location, or stdin if omitted.
+/// - `--bundle FILE`: also join `FILE` and its imports, after `in.mm0`. This can be
+///   given more than once.
+///
+/// If the files declare the same name in different ways that cannot be reconciled, an
+/// error is reported and nothing is written.
 pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
   let path = args.value_of("INPUT").expect("required arg");
-  let file = fs::canonicalize(path)?.into();
-  let comments = !args.is_present("bare");
+  let mut joiner = Joiner::new(!args.is_present("bare"));
+  joiner.add_root(fs::canonicalize(path)?.into())?;
+  for path in args.values_of("bundle").into_iter().flatten() {
+    joiner.add_root(fs::canonicalize(path)?.into())?;
+  }
+  joiner.resolve_duplicates()?;
   let header = !args.is_present("no_header");
   match args.value_of("OUTPUT") {
-    None => join_with_header(comments, header, io::stdout(), file),
-    Some(out) => join_with_header(comments, header, fs::File::create(out)?, file),
+    None => join_with_header(&joiner, header, io::stdout()),
+    Some(out) => join_with_header(&joiner, header, io::BufWriter::new(fs::File::create(out)?)),
   }
-}
\ No newline at end of file
+}
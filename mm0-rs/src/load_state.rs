@@ -0,0 +1,40 @@
+//! Resuming proof states saved with `(save-state)`.
+//!
+//!     mm0-rs load-state state.json
+//!
+//! will read the state file, elaborate the MM1 file it was saved from, restore the goals,
+//! hypotheses and metavariables of the saved state, and print it as `(stat)` would.
+//! With `-e '(tactic)'`, the expression is evaluated in the restored state instead, so that a
+//! "stuck" state from a bug report can be reproduced without the surrounding proof:
+//!
+//!     mm0-rs load-state state.json -e '(begin (refine (ax_mp _ h)) (stat))'
+use std::fs;
+use std::io;
+use clap::ArgMatches;
+
+/// Main entry point for `mm0-rs load-state` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs load-state <state.json> [-e <expr>]`, where:
+///
+/// - `state.json` is the state file written by `(save-state "state.json")`
+/// - `expr` is the lisp expression to evaluate in the restored state (default `(stat)`)
+///
+/// The source file recorded in the state is found relative to the state file, and
+/// must still contain the sorts and terms that the state uses. As with `mm0-rs eval`,
+/// the value of `expr` (if given) is printed, and the process exits with a nonzero status
+/// if there is an error in the file or in the evaluation.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let path = args.value_of("INPUT").expect("required arg");
+  let expr = args.value_of("expr");
+  let state: serde_json::Value = serde_json::from_slice(&fs::read(path)?)?;
+  let file = state.get("file").and_then(serde_json::Value::as_str).ok_or_else(||
+    io::Error::new(io::ErrorKind::InvalidData, "not a saved proof state"))?;
+  let file = std::path::Path::new(path).with_file_name(file);
+  let (val, ok) = crate::compiler::eval_in_file(
+    &file.to_string_lossy(), expr.unwrap_or("(stat)"), Some(&state))?;
+  if let Some(val) = val.filter(|val| val != "#undef" && expr.is_some()) { println!("{}", val) }
+  if !ok { std::process::exit(1) }
+  Ok(())
+}
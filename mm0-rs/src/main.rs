@@ -9,10 +9,24 @@
 //!     -V, --version    Prints version information
 //!
 //! SUBCOMMANDS:
+//!     build      Compile the output targets of a project (mm0.toml)
+//!     check-env  Check the internal consistency of elaborated environments
 //!     compile    Compile MM1 files into MMB
+//!     deps       Print the axioms and holes that theorems depend on
+//!     eval       Evaluate a lisp expression in the environment of an MM1 file
+//!     format     Format an MM0 or MM1 file
+//!     from-mm    Translate a Metamath database into an MM1 file
 //!     help       Prints this message or the help of the given subcommand(s)
 //!     join       Join MM1/MM0 files with imports by concatenation
+//!     load-state Resume a proof state saved with (save-state)
+//!     outline    Print a markdown outline of the declarations in an MM0 file
+//!     renotate   Re-print the math strings of a file using a different notation profile
+//!     reorder    Reorder the statements of a file so that declarations come before their uses
+//!     roundtrip  Check that MM1 files round-trip through the MMU and MMB formats
 //!     server     MM1 LSP server
+//!     stats      Print statistics about the declarations and proofs in MM1 files
+//!     verify     Check an MMB proof file against an MM0 specification
+//!     verify-all Verify all MM0 and MMB files in a directory tree
 //! ```
 //!
 //! [`mm0-rs/README.md`]: https://github.com/digama0/mm0/blob/master/mm0-rs/README.md
@@ -48,8 +62,22 @@ pub mod lined_string;
 pub mod parser;
 #[cfg(feature = "server")]
 #[macro_use] pub mod server;
+pub mod cache;
+pub mod check_env;
 pub mod compiler;
+pub mod deps;
+pub mod eval;
+pub mod format;
+pub mod from_mm;
 pub mod joiner;
+pub mod load_state;
+pub mod outline;
+pub mod project;
+pub mod renotate;
+pub mod reorder;
+pub mod roundtrip;
+pub mod stats;
+pub mod verify_all;
 pub mod elab;
 pub mod mmb;
 /// Import and export functionality for MMU ascii proof format
@@ -60,11 +88,34 @@ pub mod mmb;
 pub mod mmu { pub mod import; pub mod export; }
 pub mod mmc;
 
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use clap::clap_app;
 
 static CHECK_PROOFS: AtomicBool = AtomicBool::new(true);
 pub(crate) fn get_check_proofs() -> bool { CHECK_PROOFS.load(Ordering::Relaxed) }
+static BACKTRACE: AtomicBool = AtomicBool::new(false);
+pub(crate) fn get_backtrace() -> bool { BACKTRACE.load(Ordering::Relaxed) }
+static PROFILE_LISP: AtomicBool = AtomicBool::new(false);
+pub(crate) fn get_profile_lisp() -> bool { PROFILE_LISP.load(Ordering::Relaxed) }
+static TEST_MODE: AtomicBool = AtomicBool::new(false);
+pub(crate) fn get_test_mode() -> bool { TEST_MODE.load(Ordering::Relaxed) }
+static JOBS: AtomicUsize = AtomicUsize::new(1);
+pub(crate) fn get_jobs() -> usize { JOBS.load(Ordering::Relaxed) }
+
+/// Set the global flags for the options shared by `mm0-rs compile` and `mm0-rs build`.
+fn set_compile_flags(m: &clap::ArgMatches<'_>) -> std::io::Result<()> {
+  if m.is_present("no_proofs") { CHECK_PROOFS.store(false, Ordering::Relaxed) }
+  if m.is_present("backtrace") { BACKTRACE.store(true, Ordering::Relaxed) }
+  if m.is_present("profile_lisp") { PROFILE_LISP.store(true, Ordering::Relaxed) }
+  if m.is_present("test") { TEST_MODE.store(true, Ordering::Relaxed) }
+  if let Some(n) = m.value_of("jobs") {
+    let n = n.parse::<usize>().ok().filter(|&n| n != 0).ok_or_else(|| std::io::Error::new(
+      std::io::ErrorKind::InvalidInput, "expected a positive number for --jobs"))?;
+    JOBS.store(n, Ordering::Relaxed)
+  }
+  if let Some(dir) = m.value_of("cache") { cache::set_dir(dir.into()) }
+  Ok(())
+}
 
 fn main() -> std::io::Result<()> {
   let app = clap_app!(mm0_rs =>
@@ -75,18 +126,116 @@ fn main() -> std::io::Result<()> {
     (@setting InferSubcommands)
     (@setting SubcommandRequiredElseHelp)
     (@setting VersionlessSubcommands)
+    (@subcommand build =>
+      (about: "Compile the output targets of a project (mm0.toml)")
+      (@arg no_proofs: -n --("no-proofs") "Disable proof checking until (check-proofs #t)")
+      (@arg kernel_check: -k --("kernel-check") "Check the elaborated proofs with the MMB verifier before writing any output")
+      (@arg allow_sorry: -s --("allow-sorry") "Write the .mmb files even if some theorems have no proof (because of admit or --no-proofs)")
+      (@arg backtrace: -b --backtrace "Show the arguments of each call in lisp error backtraces")
+      (@arg display: -d --display [FILE] "Write the output of display, print and stat to a file instead of reporting it")
+      (@arg error_format: --("error-format") [FORMAT] possible_value[human json]
+        "Print diagnostics as snippets (human, the default) or as JSON lines (json)")
+      (@arg jobs: -j --jobs [N] "Check the proofs of theorems on N threads (default 1)")
+      (@arg cache: --cache [DIR] "Cache the elaborated imports in DIR, and load unchanged ones from there")
+      (@arg DIR: "Sets a directory in the project (default: the current directory)")
+      (@arg TARGET: +multiple "Sets the targets to build (default: all targets)"))
+    (@subcommand check_env =>
+      (name: "check-env")
+      (about: "Check the internal consistency of elaborated environments")
+      (@arg INPUT: +required +multiple "Sets the input files (.mm1, .mm0, .mmb or .mmu)"))
     (@subcommand compile =>
       (about: "Compile MM1 files into MMB")
       (@arg no_proofs: -n --("no-proofs") "Disable proof checking until (check-proofs #t)")
       (@arg output: -o --output [FILE] "Print 'output' commands to a file (use '-' to print to stdout)")
+      (@arg append: -a --append "Update an existing .mmb file by appending the new declarations")
+      (@arg split_index: -z --("split-index") "Write the debugging index of the .mmb file to a separate .mmz file")
+      (@arg compress: -c --compress "Compress the proof section of the .mmb file (requires the 'compress' feature)")
+      (@arg kernel_check: -k --("kernel-check") "Check the elaborated proofs with the MMB verifier before writing any output")
+      (@arg allow_sorry: -s --("allow-sorry") "Write the .mmb file even if some theorems have no proof (because of admit or --no-proofs)")
+      (@arg backtrace: -b --backtrace "Show the arguments of each call in lisp error backtraces")
+      (@arg profile_lisp: -p --("profile-lisp") "Profile lisp evaluation, and print a table of the time spent in each procedure")
+      (@arg display: -d --display [FILE] "Write the output of display, print and stat to a file instead of reporting it")
+      (@arg error_format: --("error-format") [FORMAT] possible_value[human json]
+        "Print diagnostics as snippets (human, the default) or as JSON lines (json)")
+      (@arg test: -t --test "Run the tests (@test do blocks) and report the number that passed and failed")
+      (@arg jobs: -j --jobs [N] "Check the proofs of theorems on N threads (default 1)")
+      (@arg cache: --cache [DIR] "Cache the elaborated imports in DIR, and load unchanged ones from there")
+      (@arg watch: -w --watch "Compile again whenever the input file or one of its imports changes")
       (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)")
       (@arg OUTPUT: "Sets the output file (.mmb or .mmu)"))
+    (@subcommand deps =>
+      (about: "Print the axioms and holes that theorems depend on")
+      (@arg no_proofs: -n --("no-proofs") "Disable proof checking until (check-proofs #t)")
+      (@arg allow: -a --allow [AXIOM] +multiple +use_delimiter number_of_values(1)
+        "Fail if a theorem depends on a hole or on an axiom not in this list")
+      (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)")
+      (@arg THM: +multiple "Sets the theorems to report on (default: all theorems in the file)"))
+    (@subcommand eval =>
+      (about: "Evaluate a lisp expression in the environment of an MM1 file")
+      (@arg no_proofs: -n --("no-proofs") "Disable proof checking until (check-proofs #t)")
+      (@arg expr: -e --expr <EXPR> "Sets the lisp expression to evaluate")
+      (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)"))
+    (@subcommand format =>
+      (about: "Format an MM0 or MM1 file")
+      (@arg width: -w --width [N] "Sets the maximum line width (default 80)")
+      (@arg check: -c --check "Check that the file is formatted instead of writing it")
+      (@arg output: -o --output [FILE] "Write the result to a file (use '-' to print to stdout)")
+      (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)"))
+    (@subcommand from_mm =>
+      (name: "from-mm")
+      (about: "Translate a Metamath database into an MM1 file")
+      (@arg grammar: -g --grammar [FILE] "Read the grammar ($j syntax statements) from FILE")
+      (@arg stop_at: --("stop-at") [LABEL] "Stop the import after the statement LABEL")
+      (@arg max_proof_size: --("max-proof-size") [N] "Replace proofs longer than N bytes by (admit)")
+      (@arg INPUT: +required "Sets the input file (.mm)")
+      (@arg OUTPUT: "Sets the output file (.mm1), or stdout if omitted"))
     (@subcommand join =>
       (about: "Join MM1/MM0 files with imports by concatenation")
       (@arg no_header: -h --("no-header") "Skip top header")
       (@arg bare: -b --("bare") "Don't add any comments")
+      (@arg bundle: --bundle [FILE] +multiple number_of_values(1) "Also join FILE and its imports (can be repeated)")
+      (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)")
+      (@arg OUTPUT: "Sets the output file (.mm1 or .mm0), or stdin if omitted"))
+    (@subcommand load_state =>
+      (name: "load-state")
+      (about: "Resume a proof state saved with (save-state)")
+      (@arg no_proofs: -n --("no-proofs") "Disable proof checking until (check-proofs #t)")
+      (@arg expr: -e --expr [EXPR] "Sets a lisp expression to evaluate in the restored state")
+      (@arg INPUT: +required "Sets the state file"))
+    (@subcommand outline =>
+      (about: "Print a markdown outline of the declarations in an MM0 file")
+      (@arg width: -w --width [N] "Sets the width used to pretty-print declarations (default 80)")
+      (@arg output: -o --output [FILE] "Write the outline to a file (use '-' to print to stdout)")
+      (@arg INPUT: +required "Sets the input file (.mm0 or .mm1)"))
+    (@subcommand renotate =>
+      (about: "Re-print the math strings of a file using a different notation profile")
+      (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)")
+      (@arg PROFILE: +required "Sets the file (.mm1 or .mm0) declaring the new notations")
+      (@arg OUTPUT: +required "Sets the output file (.mm1 or .mm0)"))
+    (@subcommand reorder =>
+      (about: "Reorder the statements of a file so that declarations come before their uses")
       (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)")
-      (@arg OUTPUT: "Sets the output file (.mm1 or .mm0), or stdin if omitted")));
+      (@arg OUTPUT: +required "Sets the output file (.mm1 or .mm0)"))
+    (@subcommand roundtrip =>
+      (about: "Check that MM1 files round-trip through the MMU and MMB formats")
+      (@arg INPUT: +required +multiple "Sets the input files (.mm1 or .mm0)"))
+    (@subcommand stats =>
+      (about: "Print statistics about the declarations and proofs in MM1 files")
+      (@arg project: -p --project "Include the declarations in imported files")
+      (@arg json: -j --json "Write the report as JSON instead of markdown")
+      (@arg top: -n --top [N] "Sets the number of longest proofs and largest files to list (default 10)")
+      (@arg output: -o --output [FILE] "Write the report to a file (use '-' to print to stdout)")
+      (@arg INPUT: +required +multiple "Sets the input files (.mm1 or .mm0)"))
+    (@subcommand verify =>
+      (about: "Check an MMB proof file against an MM0 specification")
+      (@arg SPEC: +required "Sets the specification (.mm0)")
+      (@arg PROOF: +required "Sets the proof file (.mmb)"))
+    (@subcommand verify_all =>
+      (name: "verify-all")
+      (about: "Verify all MM0 and MMB files in a directory tree")
+      (@arg no_proofs: -n --("no-proofs") "Disable proof checking until (check-proofs #t)")
+      (@arg sources: -s --sources "Also verify the .mm1 files")
+      (@arg DIR: +required "Sets the directory to search")));
 
   #[cfg(feature = "server")]
   let app = clap_app!(@app (app)
@@ -94,19 +243,50 @@ fn main() -> std::io::Result<()> {
       (about: "MM1 LSP server")
       (@arg no_proofs: -n --("no-proofs") "Disable proof checking until (check-proofs #t)")
       (@arg debug: -d --debug "Enable debug logging")
-      (@arg no_log_errors: -q --quiet "Don't print errors in server output log")));
+      (@arg no_log_errors: -q --quiet "Don't print errors in server output log")
+      (@arg cache: --cache [DIR] "Cache the elaborated imports in DIR, and load unchanged ones from there")));
 
   let m = app.get_matches();
 
   match m.subcommand() {
+    ("check-env", Some(m)) => check_env::main(m)?,
+    ("build", Some(m)) => {
+      set_compile_flags(m)?;
+      project::main(m)?
+    }
     ("compile", Some(m)) => {
-      if m.is_present("no_proofs") { CHECK_PROOFS.store(false, Ordering::Relaxed) }
+      set_compile_flags(m)?;
       compiler::main(m)?
     }
+    ("deps", Some(m)) => {
+      if m.is_present("no_proofs") { CHECK_PROOFS.store(false, Ordering::Relaxed) }
+      deps::main(m)?
+    }
+    ("eval", Some(m)) => {
+      if m.is_present("no_proofs") { CHECK_PROOFS.store(false, Ordering::Relaxed) }
+      eval::main(m)?
+    }
+    ("format", Some(m)) => format::main(m)?,
+    ("from-mm", Some(m)) => from_mm::main(m)?,
     ("join", Some(m)) => joiner::main(m)?,
+    ("load-state", Some(m)) => {
+      if m.is_present("no_proofs") { CHECK_PROOFS.store(false, Ordering::Relaxed) }
+      load_state::main(m)?
+    }
+    ("outline", Some(m)) => outline::main(m)?,
+    ("renotate", Some(m)) => renotate::main(m)?,
+    ("reorder", Some(m)) => reorder::main(m)?,
+    ("roundtrip", Some(m)) => roundtrip::main(m)?,
+    ("stats", Some(m)) => stats::main(m)?,
+    ("verify", Some(m)) => mmb::verify::main(m)?,
+    ("verify-all", Some(m)) => {
+      if m.is_present("no_proofs") { CHECK_PROOFS.store(false, Ordering::Relaxed) }
+      verify_all::main(m)?
+    }
     #[cfg(feature = "server")]
     ("server", Some(m)) => {
       if m.is_present("no_proofs") { CHECK_PROOFS.store(false, Ordering::Relaxed) }
+      if let Some(dir) = m.value_of("cache") { cache::set_dir(dir.into()) }
       server::main(m)
     }
     _ => unreachable!()
@@ -13,7 +13,8 @@ use crate::util::{FileRef, OptionExt};
 use crate::lined_string::LinedString;
 
 #[allow(clippy::wildcard_imports)]
-use super::{ProofCmd, UnifyCmd, cmd::*};
+use super::{Header, ProofCmd, UnifyCmd, cmd::*};
+use super::parser::{MMBFile, u32_as_usize, u64_as_usize};
 
 #[derive(Debug)]
 struct Reorder<T=u32> {
@@ -67,6 +68,14 @@ pub struct Exporter<'a, W: Write + Seek> {
   /// than the current writer location. We buffer these to avoid too many seeks
   /// of the underlying writer.
   fixups: Vec<(u64, Value)>,
+  /// The `(is_sort, name, pos)` entries for the debugging index, where `pos` is the
+  /// position of the statement in the proof stream. This is filled by [`run`](Self::run).
+  index_map: Vec<(bool, AtomID, u64)>,
+  /// True if we are writing a separate index file, which also contains doc comments.
+  docs: bool,
+  /// True if theorems without a proof are written as axioms, rather than being an error.
+  /// See [`allow_sorry`](Self::allow_sorry).
+  allow_sorry: bool,
 }
 
 /// A chunk of data that needs to be written out of order.
@@ -303,10 +312,20 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
   pub fn new(file: FileRef, source: &'a LinedString, env: &'a FrozenEnv, w: W) -> Self {
     Self {
       term_reord: TermVec(Vec::with_capacity(env.terms().len())),
-      file, source, env, w, pos: 0, fixups: vec![]
+      file, source, env, w, pos: 0, fixups: vec![], index_map: vec![], docs: false,
+      allow_sorry: false,
     }
   }
 
+  /// Write theorems without a proof (see `--allow-sorry`) as axioms. By default
+  /// [`run`](Self::run) and [`append`](Self::append) return an
+  /// [`InvalidData`](io::ErrorKind::InvalidData) error for them, since the resulting file
+  /// would be accepted by the verifier while asserting an unproved statement.
+  pub fn allow_sorry(&mut self, allow: bool) -> &mut Self {
+    self.allow_sorry = allow;
+    self
+  }
+
   fn write_u32(&mut self, n: u32) -> io::Result<()> {
     WriteBytesExt::write_u32::<LE>(self, n)
   }
@@ -520,11 +539,12 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
   fn write_index_entry(&mut self, header: &mut IndexHeader<'_>, il: u64, ir: u64,
       (sort, a, cmd): (bool, AtomID, u64)) -> io::Result<u64> {
     let n = self.align_to(8)?;
-    let (sp, ix, k, name) = if sort {
+    let (sp, ix, k, name, doc) = if sort {
       let ad = &self.env.data()[a];
       let s = ad.sort().expect("expected a sort");
       header.sort(s).set(n);
-      (&self.env.sort(s).span, s.0.into(), STMT_SORT, ad.name())
+      let sd = self.env.sort(s);
+      (&sd.span, s.0.into(), STMT_SORT, ad.name(), &sd.doc)
     } else {
       let ad = &self.env.data()[a];
       match ad.decl().expect("expected a term/thm") {
@@ -537,18 +557,18 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
               TermKind::Def(_) if td.vis == Modifiers::LOCAL => STMT_DEF | STMT_LOCAL,
               TermKind::Def(_) => STMT_DEF
             },
-            ad.name())
+            ad.name(), &td.doc)
         }
         DeclKey::Thm(t) => {
           let td = self.env.thm(t);
           header.thm(t).set(n);
           (&td.span, t.0,
             match td.kind {
-              ThmKind::Axiom => STMT_AXIOM,
-              ThmKind::Thm(_) if td.vis == Modifiers::PUB => STMT_THM,
-              ThmKind::Thm(_) => STMT_THM | STMT_LOCAL
+              ThmKind::Axiom | ThmKind::Thm(None) => STMT_AXIOM,
+              ThmKind::Thm(Some(_)) if td.vis == Modifiers::PUB => STMT_THM,
+              ThmKind::Thm(Some(_)) => STMT_THM | STMT_LOCAL
             },
-            ad.name())
+            ad.name(), &td.doc)
         }
       }
     };
@@ -563,9 +583,22 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
     self.write_u64(cmd)?;
     self.write_u32(ix)?;
     self.write_u8(k)?;
-    for &c in &**name {assert!(c != 0)}
+    // Names and doc comments are NUL-terminated in the index
+    if name.contains(&0) {
+      return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+        "name '{}' contains a NUL byte", name)))
+    }
     self.write_all(name)?;
     self.write_u8(0)?;
+    if self.docs {
+      let doc = doc.as_deref().unwrap_or_default().as_bytes();
+      if doc.contains(&0) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+          "the doc comment of '{}' contains a NUL byte", name)))
+      }
+      self.write_all(doc)?;
+      self.write_u8(0)?;
+    }
     Ok(n)
   }
 
@@ -612,7 +645,7 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
     let num_sorts = self.env.sorts().len();
     assert!(num_sorts <= 128, "too many sorts (max 128)");
     #[allow(clippy::cast_possible_truncation)]
-    self.write_all(&[MM0B_VERSION, num_sorts as u8, 0, 0])?; // two bytes reserved
+    self.write_all(&[MM0B_VERSION, num_sorts as u8, 0, 0])?; // flags, one byte reserved
     let num_terms = self.env.terms().len();
     self.write_u32(num_terms.try_into().expect("too many terms"))?; // num_terms
     let num_thms = self.env.thms().len();
@@ -669,15 +702,15 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
     // main body (proofs of theorems)
     p_proof.commit(self);
     let vec = &mut vec![];
-    let mut index_map = Vec::with_capacity(if index {num_sorts + num_terms + num_thms} else {0});
+    let mut index_map = Vec::with_capacity(num_sorts + num_terms + num_thms);
     for s in self.env.stmts() {
       match *s {
         StmtTrace::Sort(a) => {
-          if index {index_map.push((true, a, self.pos))}
+          index_map.push((true, a, self.pos));
           write_cmd_bytes(self, STMT_SORT, &[])?
         }
         StmtTrace::Decl(a) => {
-          if index {index_map.push((false, a, self.pos))}
+          index_map.push((false, a, self.pos));
           match self.env.data()[a].decl().expect("expected a term/thm") {
             DeclKey::Term(t) => {
               let td = self.env.term(t);
@@ -701,7 +734,11 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
               #[allow(clippy::cast_possible_truncation)] // no truncation
               let nargs = td.args.len() as u32;
               let cmd = match &td.kind {
-                ThmKind::Axiom => {
+                ThmKind::Thm(None) if !self.allow_sorry =>
+                  return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "theorem '{}' has no proof", self.env.data()[td.atom].name()))),
+                // A theorem without a proof (see `--allow-sorry`) is written as an axiom
+                ThmKind::Axiom | ThmKind::Thm(None) => {
                   let mut reorder = Reorder::new(nargs, td.heap.len(), |i| i);
                   for (_, h) in &*td.hyps {
                     write_expr_proof(vec, &td.heap, &mut reorder, h, false)?;
@@ -710,7 +747,6 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
                   write_expr_proof(vec, &td.heap, &mut reorder, &td.ret, false)?;
                   STMT_AXIOM
                 }
-                ThmKind::Thm(None) => panic!("proof {} missing", self.env.data()[td.atom].name()),
                 ThmKind::Thm(Some(Proof {heap, hyps, head})) => {
                   let mut reorder = Reorder::new(nargs, heap.len(), |i| i);
                   let mut ehyps = Vec::with_capacity(hyps.len());
@@ -741,17 +777,10 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
     self.write_u8(0)?;
 
     // debugging index
+    self.index_map = index_map;
     if index {
-      self.align_to(8)?; p_index.commit(self);
-      index_map.sort_unstable_by_key(|k| &**self.env.data()[k.1].name());
-      let size = 1 + num_sorts + num_terms + num_thms;
-      let mut index_header = self.fixup_large(8 * size)?;
-      let header = LayoutVerified::<_, [U64<LE>]>::new_slice_unaligned(&mut *index_header).expect("nonempty");
-      let (root, header) = unsafe { header.into_mut_slice().split_first_mut().unwrap_unchecked() };
-      let (sorts, header) = header.split_at_mut(num_sorts);
-      let (terms, thms) = header.split_at_mut(num_terms);
-      root.set(self.write_index(&mut IndexHeader {sorts, terms, thms}, &[], &index_map)?);
-      index_header.commit(self)
+      let n = self.write_debug_index(self.index_map.clone())?;
+      p_index.commit_val(self, n)
     } else {
       p_index.cancel();
       self.write_u32(0)?; // padding
@@ -759,6 +788,201 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
     Ok(())
   }
 
+  /// Write the debugging index, given the list of `(is_sort, name, pos)` entries
+  /// for all the statements in the file, where `pos` is the position of the statement
+  /// in the proof stream. Returns the position of the index.
+  fn write_debug_index(&mut self, mut index_map: Vec<(bool, AtomID, u64)>) -> io::Result<u64> {
+    let n = self.align_to(8)?;
+    index_map.sort_unstable_by_key(|k| &**self.env.data()[k.1].name());
+    let (num_sorts, num_terms) = (self.env.sorts().len(), self.env.terms().len());
+    let size = 1 + num_sorts + num_terms + self.env.thms().len();
+    let mut index_header = self.fixup_large(8 * size)?;
+    let header = LayoutVerified::<_, [U64<LE>]>::new_slice_unaligned(&mut *index_header).expect("nonempty");
+    let (root, header) = unsafe { header.into_mut_slice().split_first_mut().unwrap_unchecked() };
+    let (sorts, header) = header.split_at_mut(num_sorts);
+    let (terms, thms) = header.split_at_mut(num_terms);
+    root.set(self.write_index(&mut IndexHeader {sorts, terms, thms}, &[], &index_map)?);
+    index_header.commit(self);
+    Ok(n)
+  }
+
+  /// Update an existing MMB file by appending the declarations that are new in the
+  /// environment, rather than regenerating the whole file. `old` is the current
+  /// contents of the file, and the writer should be positioned on the same file.
+  ///
+  /// The declarations in `old` must be a prefix of the declarations in the
+  /// environment, with identical statements and proofs, and no new sorts can be added;
+  /// otherwise an [`InvalidData`](io::ErrorKind::InvalidData) error is returned and
+  /// a full export is required. Everything in `old` up to the end of the proof stream
+  /// is left untouched; the new declarations are appended to the proof stream,
+  /// followed by new term and theorem tables and the (optional) debugging index,
+  /// and the header is updated to point to them. The old tables and index become unused.
+  ///
+  /// As with [`run`], the caller should call [`finish`] afterwards, and then truncate
+  /// the file to [`pos`], since the result may be shorter than `old`.
+  ///
+  /// [`run`]: Self::run
+  /// [`finish`]: Self::finish
+  /// [`pos`]: Self::pos
+  pub fn append(&mut self, old: &[u8], index: bool) -> io::Result<()> {
+    fn invalid(msg: String) -> io::Error { io::Error::new(io::ErrorKind::InvalidData, msg) }
+    let env = self.env;
+    let changed = |a: AtomID| invalid(format!(
+      "declaration '{}' differs from the one in the existing file, a full export is required",
+      env.data()[a].name()));
+
+    // Export the whole file to memory, to compare against and copy from
+    let mut buf = vec![];
+    let mut ex = Exporter::new(self.file.clone(), self.source, env, io::Cursor::new(&mut buf));
+    ex.allow_sorry(self.allow_sorry).run(false)?;
+    ex.finish()?;
+    let new_file = MMBFile::parse(&buf).expect("exported file should be valid");
+    let old_file = MMBFile::parse(old).map_err(|e| invalid(format!("bad MMB file: {:?}", e)))?;
+    let old_header = LayoutVerified::<_, Header>::new_unaligned_from_prefix(old)
+      .expect("parsed above").0.into_ref();
+
+    let num_sorts = env.sorts().len();
+    if usize::from(old_header.num_sorts) != num_sorts ||
+      (0..num_sorts).any(|i| {
+        #[allow(clippy::cast_possible_truncation)] // num_sorts <= 128
+        let s = SortID(i as u8);
+        old_file.sort(s) != new_file.sort(s)
+      }) {
+      return Err(invalid("the sorts have changed, a full export is required".into()))
+    }
+    let (num_terms, num_thms) = (env.terms().len(), env.thms().len());
+    let old_terms = u32_as_usize(old_header.num_terms.get());
+    let old_thms = u32_as_usize(old_header.num_thms.get());
+    if old_terms > num_terms || old_thms > num_thms {
+      return Err(invalid("the existing file has more declarations than the environment".into()))
+    }
+    for i in 0..old_terms {
+      #[allow(clippy::cast_possible_truncation)] // no truncation
+      let t = TermID(i as u32);
+      let (t1, t2) = (old_file.term(t), new_file.term(t).expect("valid term"));
+      if !t1.map_or(false, |t1| t1.def() == t2.def() && t1.raw() == t2.raw()) {
+        return Err(changed(env.term(t).atom))
+      }
+    }
+    for i in 0..old_thms {
+      #[allow(clippy::cast_possible_truncation)] // no truncation
+      let t = ThmID(i as u32);
+      if old_file.thm(t).and_then(|t| t.raw()) != new_file.thm(t).expect("valid thm").raw() {
+        return Err(changed(env.thm(t).atom))
+      }
+    }
+
+    // Match up the statements of the old and new proof streams
+    let stmts = env.stmts().iter().filter_map(|s| match *s {
+      StmtTrace::Sort(a) => Some((true, a)),
+      StmtTrace::Decl(a) => Some((false, a)),
+      StmtTrace::Global(_) | StmtTrace::OutputString(_) => None,
+    }).collect::<Vec<_>>();
+    let (mut it1, mut it2) = (old_file.proof(), new_file.proof());
+    let mut index_map = Vec::with_capacity(stmts.len());
+    loop {
+      let (p1, p2) = (it1.pos, it2.pos);
+      match it1.next() {
+        None => break,
+        Some(Ok(_)) => {}
+        Some(Err(_)) => return Err(invalid("bad MMB file: malformed proof stream".into())),
+      }
+      let &(sort, a) = stmts.get(index_map.len())
+        .ok_or_else(|| invalid("the existing file has more declarations than the environment".into()))?;
+      if !matches!(it2.next(), Some(Ok(_))) || old[p1..it1.pos] != buf[p2..it2.pos] {
+        return Err(changed(a))
+      }
+      index_map.push((sort, a, p1 as u64));
+    }
+    let (old_end, new_start) = (it1.pos, it2.pos);
+    let mut p2 = new_start;
+    while let Some(r) = it2.next() {
+      r.expect("exported file should be valid");
+      let (sort, a) = stmts[index_map.len()];
+      index_map.push((sort, a, (old_end + p2 - new_start) as u64));
+      p2 = it2.pos;
+    }
+
+    // The new declarations, overwriting the terminator of the old proof stream
+    self.pos = self.w.seek(SeekFrom::Start(old_end as u64))?;
+    self.write_all(&buf[new_start..it2.pos])?;
+    self.write_u8(0)?;
+
+    // term header
+    let p_terms = self.align_to(8)?;
+    let mut term_header = self.fixup_large(num_terms * 8)?;
+    let start = u32_as_usize(old_header.p_terms.get());
+    term_header[..old_terms * 8].copy_from_slice(&old[start..start + old_terms * 8]);
+    for (i, head) in term_header.chunks_exact_mut(8).enumerate().skip(old_terms) {
+      #[allow(clippy::cast_possible_truncation)] // no truncation
+      let t = new_file.term(TermID(i as u32)).expect("valid term");
+      let nargs = t.args().len().try_into().expect("term has more than 65536 args");
+      Self::write_term_header(head, nargs, t.sort(), t.def(),
+        self.align_to(8)?.try_into().expect("address too large"));
+      self.write_all(t.raw().expect("valid term"))?;
+    }
+    term_header.commit(self);
+
+    // theorem header
+    let p_thms = self.align_to(8)?;
+    let mut thm_header = self.fixup_large(num_thms * 8)?;
+    let start = u32_as_usize(old_header.p_thms.get());
+    thm_header[..old_thms * 8].copy_from_slice(&old[start..start + old_thms * 8]);
+    for (i, head) in thm_header.chunks_exact_mut(8).enumerate().skip(old_thms) {
+      #[allow(clippy::cast_possible_truncation)] // no truncation
+      let t = new_file.thm(ThmID(i as u32)).expect("valid thm");
+      let nargs = t.args().len().try_into().expect("theorem has more than 65536 args");
+      Self::write_thm_header(head, nargs,
+        self.align_to(8)?.try_into().expect("address too large"));
+      self.write_all(t.raw().expect("valid thm"))?;
+    }
+    thm_header.commit(self);
+
+    // debugging index
+    self.index_map = index_map;
+    let p_index = if index {
+      self.write_debug_index(self.index_map.clone())?
+    } else {
+      self.write_u32(0)?; // padding
+      0
+    };
+
+    // file header
+    let mut header = FixupLarge(0, old[..mem::size_of::<Header>()].into());
+    let h = LayoutVerified::<_, Header>::new_unaligned(&mut *header).expect("header size").into_mut();
+    h.num_terms.set(num_terms.try_into().expect("too many terms"));
+    h.num_thms.set(num_thms.try_into().expect("too many thms"));
+    h.p_terms.set(p_terms.try_into().expect("address too large"));
+    h.p_thms.set(p_thms.try_into().expect("address too large"));
+    h.p_index.set(p_index);
+    header.commit(self);
+    Ok(())
+  }
+
+  /// Write a separate index file (`.mmz`) for the file produced by [`run`](Self::run) or
+  /// [`append`](Self::append) to `w`. This allows exporting an `.mmb` file without the
+  /// debugging index, which is not needed by the verifier, while keeping the names,
+  /// source positions and doc comments available for other tools.
+  ///
+  /// The index file consists of the magic number [`MMZ_MAGIC`], the version byte and three
+  /// reserved bytes, followed by an index in the same format as the debugging index of
+  /// an `.mmb` file, except that pointers to index entries are relative to the start of
+  /// the index file, and each entry has a second nul-terminated string after the name
+  /// containing the doc comment (or empty if there is none). The `p_proof` fields
+  /// of the entries point into the `.mmb` file.
+  pub fn write_index_file<W2: Write + Seek>(&self, w: W2) -> io::Result<()> {
+    let mut ex = Exporter::new(self.file.clone(), self.source, self.env, w);
+    ex.docs = true;
+    ex.write_all(&MMZ_MAGIC)?;
+    ex.write_all(&[MM0B_VERSION, 0, 0, 0])?; // three bytes reserved
+    ex.write_debug_index(self.index_map.clone())?;
+    ex.finish()
+  }
+
+  /// The current position of the writer, which after [`run`](Self::run) or
+  /// [`append`](Self::append) is the length of the file.
+  #[must_use] pub fn pos(&self) -> u64 { self.pos }
+
   /// Finalize the outstanding fixups, and flush the writer. Consumes self since we're done.
   pub fn finish(self) -> io::Result<()> {
     let Self {mut w, fixups, ..} = self;
@@ -772,4 +996,40 @@ impl<'a, W: Write + Seek> Exporter<'a, W> {
     }
     w.flush()
   }
-}
\ No newline at end of file
+}
+/// Compress the proof section of the (uncompressed) MMB file `buf` using zstd at the given
+/// compression `level`, and set the [`MM0B_FLAG_COMPRESSED`] flag in the header.
+/// The rest of the file is copied as is; see [`MM0B_FLAG_COMPRESSED`] for the layout.
+/// This is the inverse of [`decompress`](super::parser::decompress).
+///
+/// This requires the `compress` feature, and returns an error otherwise.
+pub fn compress(buf: &[u8], level: i32) -> io::Result<Vec<u8>> {
+  let bad = || io::Error::new(io::ErrorKind::InvalidData, "bad MMB header");
+  let header = LayoutVerified::<_, Header>::new_unaligned_from_prefix(buf).ok_or_else(bad)?.0;
+  if header.magic != MM0B_MAGIC || header.flags & MM0B_FLAG_COMPRESSED != 0 { return Err(bad()) }
+  let p_proof = u32_as_usize(header.p_proof.get());
+  let end = match u64_as_usize(header.p_index) { 0 => buf.len(), n => n };
+  let proof = buf.get(p_proof..end).ok_or_else(bad)?;
+  let mut out = buf[..p_proof].to_vec();
+  out.extend_from_slice(&[0; 8]); // compressed length, filled in below
+  let mut out = compress_into(out, proof, level)?;
+  let len = (out.len() - p_proof - 8) as u64;
+  LE::write_u64(&mut out[p_proof..p_proof + 8], len);
+  out.extend_from_slice(&buf[end..]);
+  LayoutVerified::<_, Header>::new_unaligned_from_prefix(&mut *out)
+    .expect("header size").0.into_mut().flags |= MM0B_FLAG_COMPRESSED;
+  Ok(out)
+}
+
+#[cfg(feature = "compress")]
+fn compress_into(out: Vec<u8>, data: &[u8], level: i32) -> io::Result<Vec<u8>> {
+  let mut enc = zstd::stream::write::Encoder::new(out, level)?;
+  enc.write_all(data)?;
+  enc.finish()
+}
+
+#[cfg(not(feature = "compress"))]
+fn compress_into(_: Vec<u8>, _: &[u8], _: i32) -> io::Result<Vec<u8>> {
+  Err(io::Error::new(io::ErrorKind::Other,
+    "compressing MMB files requires mm0-rs to be built with the 'compress' feature"))
+}
@@ -9,7 +9,7 @@ use crate::elab::{
   proof::{IDedup, NodeHash, ExprHash, ProofHash, build}};
 use crate::util::{FileRef, FileSpan, SliceExt};
 use super::{StmtCmd, UnifyCmd, ProofCmd,
-  parser::{MMBFile, Buffer, ParseError, UnifyIter, ProofIter}};
+  parser::{MMBFile, Buffer, ParseError, UnifyIter, ProofIter, decompress}};
 
 
 type Result<T> = std::result::Result<T, ParseError>;
@@ -375,7 +375,17 @@ fn parse_proof(
 
 fn parse(fref: &FileRef, buf: &[u8], env: &mut Environment) -> Result<()> {
   use ParseError::{BadIndex, StrError};
-  let file = MMBFile::parse(buf)?;
+  let index;
+  let decompressed = decompress(buf)?;
+  let buf = decompressed.as_deref().unwrap_or(buf);
+  let mut file = MMBFile::parse(buf)?;
+  if file.index.is_none() {
+    // Use the separate index file `foo.mmz` for `foo.mmb`, if there is one
+    if let Ok(buf) = std::fs::read(fref.path().with_extension("mmz")) {
+      index = buf;
+      file.add_index_file(&index)?;
+    }
+  }
   let diff = |p: *const u8| p as usize - buf.as_ptr() as usize;
   let mut it = file.proof();
   let mut start = it.pos;
@@ -407,7 +417,7 @@ fn parse(fref: &FileRef, buf: &[u8], env: &mut Environment) -> Result<()> {
         let fsp = FileSpan {file: fref.clone(), span};
         let sd = file.sort(sort).and_then(|sd| sd.try_into().ok())
           .ok_or(StrError("Step sort overflow", start))?;
-        env.add_sort(atom, fsp, span, sd, None)
+        env.add_sort(atom, fsp, span, sd, file.sort_doc(sort).map(Into::into))
           .map_err(|_| StrError("double add sort", start))?;
       }
       StmtCmd::TermDef {local} => {
@@ -433,7 +443,7 @@ fn parse(fref: &FileRef, buf: &[u8], env: &mut Environment) -> Result<()> {
         };
         let full = (start..pf.pos).into();
         env.add_term(Term {
-          atom, span: fsp, full, doc: None, args, kind,
+          atom, span: fsp, full, doc: file.term_doc(term).map(Into::into), args, kind,
           vis: if local {Modifiers::LOCAL} else {Modifiers::empty()},
           ret: (ret.sort(), ret.deps()),
         }).map_err(|_| StrError("double add term", start))?;
@@ -463,7 +473,7 @@ fn parse(fref: &FileRef, buf: &[u8], env: &mut Environment) -> Result<()> {
           if matches!(stmt, StmtCmd::Thm {local: false}) {Modifiers::PUB}
           else {Modifiers::empty()};
         env.add_thm(Thm {
-          atom, span: fsp, full, doc: None, args, kind,
+          atom, span: fsp, full, doc: file.thm_doc(thm).map(Into::into), args, kind,
           vis, heap, hyps: hyps.into_boxed_slice(), ret,
         }).map_err(|_| StrError("double add term", start))?;
       }
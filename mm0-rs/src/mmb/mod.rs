@@ -6,11 +6,12 @@
 #![allow(unused, missing_docs)]
 use crate::elab::environment::{SortID, TermID, ThmID};
 use byteorder::LE;
-use zerocopy::{FromBytes, Unaligned, U16, U32, U64};
+use zerocopy::{AsBytes, FromBytes, Unaligned, U16, U32, U64};
 
 pub mod parser;
 pub mod import;
 pub mod export;
+pub mod verify;
 
 /// Constants used in the MMB specification.
 pub mod cmd {
@@ -18,6 +19,16 @@ pub mod cmd {
   pub const MM0B_MAGIC: [u8; 4] = *b"MM0B";
   /// `MM0B_VERSION = 1`, maximum supported MMB version
   pub const MM0B_VERSION: u8 = 1;
+  /// `MM0B_FLAG_COMPRESSED = 1`: Header flag signalling that the proof section is compressed.
+  /// In this case, `p_proof` points to a `u64` length `n`, followed by `n` bytes containing
+  /// a zstd frame which decompresses to the proof section (up to `p_index`, or the end of
+  /// the file). All other pointers in the file refer to positions in the decompressed file.
+  /// `mm0-c` rejects files with any header flag set, so they must be decompressed first.
+  pub const MM0B_FLAG_COMPRESSED: u8 = 1;
+  /// `MMZ_MAGIC = "MM0Z"`: Magic number for a separate index file (`.mmz`), which holds
+  /// the debugging index of an `.mmb` file exported without one. It has the same version
+  /// number as the `.mmb` format.
+  pub const MMZ_MAGIC: [u8; 4] = *b"MM0Z";
 
   /// `DATA_8 = 0x40`, used as a command mask for an 8 bit data field
   pub const DATA_8: u8    = 0x40;
@@ -204,12 +215,13 @@ impl std::convert::TryFrom<(u8, u32)> for UnifyCmd {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy, FromBytes, Unaligned)]
+#[derive(Debug, Clone, Copy, FromBytes, AsBytes, Unaligned)]
 pub struct Header {
   magic: [u8; 4],
   version: u8,
   num_sorts: u8,
-  reserved: [u8; 2],
+  flags: u8,
+  reserved: u8,
   num_terms: U32<LE>,
   num_thms: U32<LE>,
   p_terms: U32<LE>,
@@ -1,16 +1,17 @@
 //! Parser for MMB binary proof files.
 use std::{mem, io};
+use std::cmp::Ordering;
 use std::convert::{TryFrom, TryInto};
 use std::fs::File;
 use memmap::{MmapOptions, Mmap};
 use byteorder::LE;
-use zerocopy::{LayoutVerified, FromBytes, Unaligned, U16, U32, U64};
+use zerocopy::{LayoutVerified, AsBytes, FromBytes, Unaligned, U16, U32, U64};
 use super::{Header, StmtCmd, TermEntry, ThmEntry, IndexEntry, IndexKind, UnifyCmd, ProofCmd};
 use crate::elab::environment::{SortID, TermID, ThmID, Modifiers};
 use crate::util::{Position, cstr_from_bytes_prefix};
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy, FromBytes, Unaligned)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromBytes, Unaligned)]
 pub struct SortData(u8);
 
 impl TryFrom<SortData> for Modifiers {
@@ -49,6 +50,8 @@ pub struct MMBFile<'a> {
 #[derive(Debug)]
 pub struct MMBIndex<'a> {
   buf: &'a [u8],
+  /// True if this is a separate index file, whose entries carry doc comments.
+  docs: bool,
   root: U64<LE>,
   sorts: &'a [U64<LE>],
   terms: &'a [U64<LE>],
@@ -58,6 +61,7 @@ pub struct MMBIndex<'a> {
 #[derive(Debug, Clone, Copy)]
 pub struct IndexEntryRef<'a> {
   buf: &'a [u8],
+  docs: bool,
   entry: &'a IndexEntry,
   value: &'a [u8],
 }
@@ -86,6 +90,14 @@ pub struct ThmRef<'a> {
   unify: UnifyIter<'a>,
 }
 
+/// A zero-copy view of a declaration in an MMB file, as returned by [`MMBFile::find`].
+#[derive(Debug, Clone)]
+pub enum DeclRef<'a> {
+  Sort(SortID, SortData),
+  Term(TermID, TermRef<'a>),
+  Thm(ThmID, ThmRef<'a>),
+}
+
 #[derive(Debug)]
 pub enum ParseError {
   BadHeader,
@@ -114,10 +126,70 @@ impl From<ParseError> for crate::elab::ElabError {
 #[inline] pub(crate) fn u32_as_usize(n: u32) -> usize {
   n.try_into().expect("here's a nickel, get a better computer")
 }
-#[inline] fn u64_as_usize(n: U64<LE>) -> usize {
+#[inline] pub(crate) fn u64_as_usize(n: U64<LE>) -> usize {
   n.get().try_into().expect("here's a nickel, get a better computer")
 }
 
+/// An owned copy of an MMB file in memory, such as one produced by [`decompress`].
+/// This is aligned to 8 bytes like a memory mapped file, so that it can be
+/// passed to [`MMBFile::parse`].
+#[derive(Debug)]
+pub struct AlignedBuffer(Vec<u64>, usize);
+
+impl AlignedBuffer {
+  /// Copy `buf` into a new aligned buffer.
+  #[must_use] pub fn new(buf: &[u8]) -> Self {
+    let mut aligned = vec![0_u64; (buf.len() + 7) / 8];
+    aligned.as_bytes_mut()[..buf.len()].copy_from_slice(buf);
+    Self(aligned, buf.len())
+  }
+}
+
+impl std::ops::Deref for AlignedBuffer {
+  type Target = [u8];
+  fn deref(&self) -> &[u8] { &self.0.as_bytes()[..self.1] }
+}
+
+/// If the MMB file `buf` has a compressed proof section (see [`MM0B_FLAG_COMPRESSED`]),
+/// decompress it, returning an equivalent uncompressed MMB file, or `None` if `buf`
+/// is not compressed. The proof section is decompressed in a streaming fashion,
+/// so its size does not need to be known in advance.
+///
+/// [`MM0B_FLAG_COMPRESSED`]: super::cmd::MM0B_FLAG_COMPRESSED
+pub fn decompress(buf: &[u8]) -> Result<Option<AlignedBuffer>, ParseError> {
+  use super::cmd::MM0B_FLAG_COMPRESSED;
+  let header = LayoutVerified::<_, Header>::
+    new_unaligned_from_prefix(buf).ok_or(ParseError::BadHeader)?.0;
+  if header.flags & MM0B_FLAG_COMPRESSED == 0 { return Ok(None) }
+  let p_proof = u32_as_usize(header.p_proof.get());
+  let (len, rest) = buf.get(p_proof..)
+    .and_then(LayoutVerified::<_, U64<LE>>::new_unaligned_from_prefix)
+    .ok_or(ParseError::StrError("bad compressed proof section", p_proof))?;
+  let n = u64_as_usize(*len);
+  if n > rest.len() {
+    return Err(ParseError::StrError("compressed proof section out of range", p_proof))
+  }
+  let (data, rest) = rest.split_at(n);
+  let mut out = buf[..p_proof].to_vec();
+  decompress_into(data, &mut out)?;
+  out.extend_from_slice(rest);
+  LayoutVerified::<_, Header>::new_unaligned_from_prefix(&mut *out)
+    .expect("header size").0.into_mut().flags &= !MM0B_FLAG_COMPRESSED;
+  Ok(Some(AlignedBuffer::new(&out)))
+}
+
+#[cfg(feature = "compress")]
+fn decompress_into(data: &[u8], out: &mut Vec<u8>) -> Result<(), ParseError> {
+  let mut dec = zstd::stream::read::Decoder::with_buffer(data)?;
+  io::copy(&mut dec, out)?;
+  Ok(())
+}
+
+#[cfg(not(feature = "compress"))]
+fn decompress_into(_: &[u8], _: &mut Vec<u8>) -> Result<(), ParseError> {
+  Err(ParseError::StrError("compressed MMB files require the 'compress' feature", 0))
+}
+
 #[inline] fn new_slice_prefix<T: FromBytes>(bytes: &[u8], n: usize) -> Option<(&[T], &[u8])> {
   let mid = mem::size_of::<T>().checked_mul(n)?;
   if mid <= bytes.len() {
@@ -152,12 +224,15 @@ impl Buffer {
 impl<'a> MMBFile<'a> {
   pub fn parse(buf: &'a [u8]) -> Result<MMBFile<'a>, ParseError> {
     use ParseError::{BadHeader, BadVersion, BadIndex};
-    use super::cmd::{MM0B_MAGIC, MM0B_VERSION};
+    use super::cmd::{MM0B_MAGIC, MM0B_VERSION, MM0B_FLAG_COMPRESSED};
     let (header, sorts) = LayoutVerified::<_, Header>::
       new_unaligned_from_prefix(buf).ok_or(BadHeader)?;
     let header = header.into_ref();
     if header.magic != MM0B_MAGIC { return Err(BadHeader) }
     if header.version != MM0B_VERSION { return Err(BadVersion) }
+    if header.flags & MM0B_FLAG_COMPRESSED != 0 {
+      return Err(ParseError::StrError("compressed MMB file, call decompress first", 0))
+    }
     let sorts = sorts.get(..header.num_sorts.into())
       .and_then(LayoutVerified::new_slice_unaligned)
       .ok_or(BadHeader)?.into_slice();
@@ -176,18 +251,40 @@ impl<'a> MMBFile<'a> {
         let (sorts, rest) = new_slice_prefix(rest, sorts.len())?;
         let (terms, rest) = new_slice_prefix(rest, terms.len())?;
         let (thms, _) = new_slice_prefix(rest, thms.len())?;
-        Some(MMBIndex {buf, root: *root, sorts, terms, thms})
+        Some(MMBIndex {buf, docs: false, root: *root, sorts, terms, thms})
       })().ok_or(BadIndex)?)
     };
     Ok(MMBFile {buf, sorts, terms, thms, proof, index})
   }
+
+  /// Use the separate index file (`.mmz`) with contents `buf` as the index for this file.
+  pub fn add_index_file(&mut self, buf: &'a [u8]) -> Result<(), ParseError> {
+    use ParseError::{BadHeader, BadVersion, BadIndex};
+    use super::cmd::{MMZ_MAGIC, MM0B_VERSION};
+    if buf.len() < 8 || buf[..4] != MMZ_MAGIC { return Err(BadHeader) }
+    if buf[4] != MM0B_VERSION { return Err(BadVersion) }
+    let rest = &buf[8..];
+    self.index = Some((|| -> Option<_> {
+      let (root, rest) = LayoutVerified::<_, U64<LE>>::new_unaligned_from_prefix(rest)?;
+      let (sorts, rest) = new_slice_prefix(rest, self.sorts.len())?;
+      let (terms, rest) = new_slice_prefix(rest, self.terms.len())?;
+      let (thms, _) = new_slice_prefix(rest, self.thms.len())?;
+      Some(MMBIndex {buf, docs: true, root: *root, sorts, terms, thms})
+    })().ok_or(BadIndex)?);
+    Ok(())
+  }
 }
 
-#[inline] fn index_ref(buf: &[u8], n: U64<LE>) -> Option<IndexEntryRef<'_>> {
+#[inline] fn index_ref(buf: &[u8], docs: bool, n: U64<LE>) -> Option<IndexEntryRef<'_>> {
   let (entry, value) = LayoutVerified::<_, IndexEntry>::
     new_unaligned_from_prefix(&*buf.get(u64_as_usize(n)..)?)?;
   let entry = entry.into_ref();
-  Some(IndexEntryRef {buf, entry, value})
+  Some(IndexEntryRef {buf, docs, entry, value})
+}
+
+/// Like [`index_ref`], but a null pointer (signalling a missing child) gives `None`.
+#[inline] fn child_ref(buf: &[u8], docs: bool, n: U64<LE>) -> Option<IndexEntryRef<'_>> {
+  if n.get() == 0 { None } else { index_ref(buf, docs, n) }
 }
 
 #[inline] fn term_ref(buf: &[u8], t: TermEntry) -> Option<TermRef<'_>> {
@@ -214,6 +311,10 @@ impl<'a> MMBFile<'a> {
   #[inline] #[must_use] pub fn thm(&self, n: ThmID) -> Option<ThmRef<'_>> {
     thm_ref(self.buf, *self.thms.get(u32_as_usize(n.0))?)
   }
+  /// The number of entries in the sort, term and theorem tables.
+  #[inline] #[must_use] pub fn counts(&self) -> (usize, usize, usize) {
+    (self.sorts.len(), self.terms.len(), self.thms.len())
+  }
   #[inline] #[must_use] pub fn proof(&self) -> DeclIter<'a> {
     DeclIter {buf: self.buf, pos: self.proof}
   }
@@ -232,6 +333,15 @@ impl<'a> MMBFile<'a> {
       Some(f(&format!("T{}", n.0)))
     }
   }
+  #[must_use] pub fn sort_doc(&self, n: SortID) -> Option<&str> {
+    self.index.as_ref()?.sort(n)?.doc()
+  }
+  #[must_use] pub fn term_doc(&self, n: TermID) -> Option<&str> {
+    self.index.as_ref()?.term(n)?.doc()
+  }
+  #[must_use] pub fn thm_doc(&self, n: ThmID) -> Option<&str> {
+    self.index.as_ref()?.thm(n)?.doc()
+  }
   #[must_use] pub fn sort_name<T>(&self, n: SortID, f: impl FnOnce(&str) -> T) -> Option<T> {
     if let Some(index) = &self.index {
       Some(f(index.sort(n)?.value()?))
@@ -239,30 +349,84 @@ impl<'a> MMBFile<'a> {
       Some(f(&format!("s{}", n.0)))
     }
   }
+
+  /// Look up a sort, term or theorem by name, using the index. Only the index entries on
+  /// the search path are read, so this is cheap even for a large memory mapped file.
+  #[must_use] pub fn find(&self, name: &str) -> Option<DeclRef<'_>> {
+    let e = self.index.as_ref()?.find(name)?;
+    let ix = e.ix.get();
+    Some(match e.kind()? {
+      IndexKind::Sort => {
+        let n = SortID(ix.try_into().ok()?);
+        DeclRef::Sort(n, self.sort(n)?)
+      }
+      IndexKind::Term | IndexKind::Def {..} => DeclRef::Term(TermID(ix), self.term(TermID(ix))?),
+      IndexKind::Axiom | IndexKind::Thm {..} => DeclRef::Thm(ThmID(ix), self.thm(ThmID(ix))?),
+      IndexKind::Var => return None,
+    })
+  }
+
+  /// The statement in the proof stream for an index entry. Unlike [`IndexEntryRef::decl`],
+  /// this also works for entries of a separate index file.
+  #[must_use] pub fn decl_of(&self, e: &IndexEntryRef<'_>) -> Option<(StmtCmd, ProofIter<'a>)> {
+    let (stmt, pf, _) = try_next_decl(self.buf, u64_as_usize(e.p_proof))??;
+    Some((stmt, pf))
+  }
 }
 impl<'a> MMBIndex<'a> {
   #[must_use] pub fn sort(&self, n: SortID) -> Option<IndexEntryRef<'_>> {
-    index_ref(self.buf, *self.sorts.get(usize::from(n.0))?)
+    index_ref(self.buf, self.docs, *self.sorts.get(usize::from(n.0))?)
   }
   #[must_use] pub fn term(&self, n: TermID) -> Option<IndexEntryRef<'_>> {
-    index_ref(self.buf, *self.terms.get(u32_as_usize(n.0))?)
+    index_ref(self.buf, self.docs, *self.terms.get(u32_as_usize(n.0))?)
   }
   #[must_use] pub fn thm(&self, n: ThmID) -> Option<IndexEntryRef<'_>> {
-    index_ref(self.buf, *self.thms.get(u32_as_usize(n.0))?)
+    index_ref(self.buf, self.docs, *self.thms.get(u32_as_usize(n.0))?)
+  }
+  /// The root of the binary search tree of index entries, ordered by name.
+  #[must_use] pub fn root(&self) -> Option<IndexEntryRef<'_>> {
+    child_ref(self.buf, self.docs, self.root)
+  }
+  /// Find the index entry with the given name, by searching the binary search tree.
+  /// A path in the tree cannot visit more entries than fit in the file, so a malformed
+  /// index containing a cycle makes the search fail rather than loop forever.
+  #[must_use] pub fn find(&self, name: &str) -> Option<IndexEntryRef<'_>> {
+    let mut e = self.root()?;
+    for _ in 0..self.buf.len() / mem::size_of::<IndexEntry>() {
+      e = match name.as_bytes().cmp(cstr_from_bytes_prefix(e.value)?.0.to_bytes()) {
+        Ordering::Less => e.left()?,
+        Ordering::Greater => e.right()?,
+        Ordering::Equal => return Some(e),
+      }
+    }
+    None
   }
 }
 
+/// Get the bytes of an argument list followed by a unify stream, including the terminator.
+/// This data is position independent, so it can be copied verbatim to another file.
+fn raw_args<'a>(args: &[Arg], unify: &UnifyIter<'a>) -> Option<&'a [u8]> {
+  let start = unify.pos.checked_sub(mem::size_of_val(args))?;
+  let mut it = unify.clone();
+  for r in &mut it { r.ok()?; }
+  unify.buf.get(start..=it.pos)
+}
+
 impl<'a> TermRef<'a> {
   #[inline] #[must_use] pub fn def(&self) -> bool { self.sort & 0x80 != 0 }
   #[inline] #[must_use] pub fn sort(&self) -> SortID { SortID(self.sort & 0x7F) }
   #[inline] #[must_use] pub fn args(&self) -> &[Arg] { self.args.split_last().expect("nonempty").1 }
   #[inline] #[must_use] pub fn ret(&self) -> Arg { *self.args.last().expect("nonempty") }
   #[inline] #[must_use] pub fn unify(&self) -> UnifyIter<'_> { self.unify.clone() }
+  /// The raw bytes of the argument list, return type and unify stream of the term.
+  #[must_use] pub fn raw(&self) -> Option<&'a [u8]> { raw_args(self.args, &self.unify) }
 }
 
 impl<'a> ThmRef<'a> {
   #[inline] #[must_use] pub fn args(&self) -> &[Arg] { self.args }
   #[inline] #[must_use] pub fn unify(&self) -> UnifyIter<'_> { self.unify.clone() }
+  /// The raw bytes of the argument list and unify stream of the theorem.
+  #[must_use] pub fn raw(&self) -> Option<&'a [u8]> { raw_args(self.args, &self.unify) }
 }
 
 impl Arg {
@@ -330,13 +494,23 @@ impl<'a> Iterator for UnifyIter<'a> {
 }
 
 impl<'a> IndexEntryRef<'a> {
-  #[must_use] pub fn left(&self) -> Option<Self> { index_ref(self.buf, self.p_left) }
-  #[must_use] pub fn right(&self) -> Option<Self> { index_ref(self.buf, self.p_right) }
+  #[must_use] pub fn left(&self) -> Option<Self> { child_ref(self.buf, self.docs, self.p_left) }
+  #[must_use] pub fn right(&self) -> Option<Self> { child_ref(self.buf, self.docs, self.p_right) }
   #[must_use] pub fn value(&self) -> Option<&'a str> {
     cstr_from_bytes_prefix(self.value)?.0.to_str().ok()
   }
+  /// The doc comment of the declaration. This is only available in a separate index file,
+  /// where it is stored after the name.
+  #[must_use] pub fn doc(&self) -> Option<&'a str> {
+    if !self.docs {return None}
+    let doc = cstr_from_bytes_prefix(cstr_from_bytes_prefix(self.value)?.1)?.0.to_str().ok()?;
+    if doc.is_empty() {None} else {Some(doc)}
+  }
   #[must_use] pub fn kind(&self) -> Option<IndexKind> { self.kind.try_into().ok() }
+  /// The statement for this entry. (This is only available for an index embedded in
+  /// the `.mmb` file, since the statements of a separate index file live in the `.mmb` file.)
   #[must_use] pub fn decl(&self) -> Option<(StmtCmd, ProofIter<'_>)> {
+    if self.docs {return None}
     let (stmt, pf, _) = try_next_decl(self.buf, u64_as_usize(self.p_proof))??;
     Some((stmt, pf))
   }
@@ -0,0 +1,869 @@
+//! A standalone checker for MMB proof files.
+//!
+//!     mm0-rs verify spec.mm0 proof.mmb
+//!
+//! checks every proof in `proof.mmb`, and checks that its public declarations are exactly
+//! the declarations of the specification `spec.mm0`, in the same order. This does not use
+//! the elaborator for the proofs: it is a port of the stack machine of
+//! [`mm0-c/verifier.c`], which reads the proof streams directly from the file and checks
+//! each step.
+//!
+//! The specification side is not independent of the elaborator: the specification and its
+//! imports must be `.mm0` files, which are read with the MM0 parser and elaborated in MM0
+//! mode (so no lisp code is run), and the resulting environment is what the proof file is
+//! compared against. A bug in the parser or in the MM0 mode of the elaborator could
+//! therefore make a specification be misread in the same way by both sides. For a check
+//! that shares no code with `mm0-rs`, use `mm0-c`.
+//! The first failure is reported with the byte offset of the failing command and the name
+//! of the declaration containing it.
+//!
+//! The one difference from `mm0-c` is that the terms built by a proof are hash-consed, so
+//! that two occurrences of the same term are identified even if the proof file does not
+//! share them explicitly. This is needed to match the statements of the specification,
+//! which are built independently of the proof file, against the unify streams.
+//!
+//! [`mm0-c/verifier.c`]: https://github.com/digama0/mm0/blob/master/mm0-c/verifier.c
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use clap::ArgMatches;
+use futures::channel::oneshot::channel;
+use futures::executor::block_on;
+use crate::elab::{self, ElabResult, ErrorLevel, FrozenEnv,
+  environment::{AtomID, DeclKey, ExprNode, Modifiers, SortID, StmtTrace,
+    TermID, TermKind, ThmID, Type}};
+use crate::parser::{parse, ast::{DeclKind, StmtKind}};
+use crate::util::FileRef;
+use super::{StmtCmd, ProofCmd, UnifyCmd,
+  parser::{AlignedBuffer, Arg, Buffer, MMBFile, ParseError, ProofIter, TermRef, UnifyIter, decompress}};
+
+/// The bit of a type which is set for bound variables.
+const TYPE_BOUND: u64 = 1 << 63;
+/// The bits of a type which hold the dependencies.
+const TYPE_DEPS: u64 = (1 << 56) - 1;
+
+/// The type of a binder, as a `u64` in the format of the MMB file.
+fn arg_ty(a: Arg) -> u64 {
+  u64::from(a.bound()) << 63 | u64::from(a.sort().0) << 56 | a.deps()
+}
+
+/// The sort of a type.
+#[allow(clippy::cast_possible_truncation)]
+fn ty_sort(ty: u64) -> usize { ((ty >> 56) & 0x7F) as usize }
+
+/// Returns true if bit `j` of `x` is set.
+fn bit(x: u64, j: usize) -> bool { j < 64 && (x >> j) & 1 != 0 }
+
+/// Returns true if a value with type `from` can be used where type `to` is expected.
+/// This requires that the sorts be the same, and additionally if `to` is a
+/// bound variable then so is `from`.
+fn sorts_compatible(from: u64, to: u64) -> bool {
+  let diff = from ^ to;
+  diff & !TYPE_DEPS == 0 || (diff & !TYPE_BOUND & !TYPE_DEPS == 0 && from & TYPE_BOUND != 0)
+}
+
+/// The encoding of the binders of a declaration of the specification.
+fn binder_tys(args: &[(Option<AtomID>, Type)]) -> Vec<u64> {
+  let mut bv = 1;
+  args.iter().map(|(_, ty)| match *ty {
+    Type::Bound(s) => {
+      let ty = TYPE_BOUND | u64::from(s.0) << 56 | bv;
+      bv *= 2;
+      ty
+    }
+    Type::Reg(s, deps) => u64::from(s.0) << 56 | deps,
+  }).collect()
+}
+
+/// An expression in the store.
+#[derive(Debug)]
+enum StoreExpr {
+  /// A variable, with its type
+  Var(u64),
+  /// A term application, with its type
+  Term(u64, TermID, Box<[u32]>),
+}
+
+/// An element of the stack or heap of the proof checker. The expressions are
+/// indices into the store.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Elem {
+  /// An expression `e`
+  Expr(u32),
+  /// A proof of `|- e`
+  Proof(u32),
+  /// A proof of the convertibility `e1 = e2`
+  Conv(u32, u32),
+  /// An obligation to prove `e1 = e2`, written `e1 =?= e2`
+  CoConv(u32, u32),
+}
+
+/// The kind of proof stream being checked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+  /// The body of a definition, in which bound variables are not free in terms
+  Def,
+  /// The proof of a theorem
+  Thm,
+}
+
+/// The kind of unify stream being checked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum UMode {
+  /// Unfolding a definition, or checking its header
+  Def,
+  /// Applying a theorem
+  Thm,
+  /// Checking the header of a theorem against its proof
+  ThmEnd,
+}
+
+/// A declaration of the specification.
+#[derive(Clone, Copy, Debug)]
+enum SpecDecl {
+  Sort(SortID),
+  Term(TermID),
+  /// A theorem or axiom, and whether it was declared with `axiom`. (The environment does
+  /// not record this, because an MM0 `theorem` has no proof and so it elaborates to
+  /// [`ThmKind::Axiom`](crate::elab::environment::ThmKind::Axiom) as well.)
+  Thm(ThmID, bool),
+}
+
+/// A verification failure.
+#[derive(Debug)]
+struct Error {
+  /// The byte offset of the failing command or statement
+  pos: usize,
+  /// The declaration containing the failure
+  decl: String,
+  /// The error message
+  msg: String,
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+macro_rules! ensure {($self:ident, $e:expr, $msg:expr) => {
+  if !$e { return Err($self.err($msg)) }
+}}
+
+/// The state of the proof checker.
+struct Checker<'a> {
+  file: &'a MMBFile<'a>,
+  /// The specification, if the public declarations are to be checked against one
+  env: Option<&'a FrozenEnv>,
+  /// The declarations of the specification, in order
+  spec: std::vec::IntoIter<SpecDecl>,
+  /// The term in the proof file for each term of the specification
+  term_map: Vec<TermID>,
+  /// The sorts declared so far
+  sorts: Vec<Modifiers>,
+  /// The number of terms declared so far
+  num_terms: u32,
+  /// The number of theorems declared so far
+  num_thms: u32,
+  /// The expressions allocated in the current declaration
+  store: Vec<StoreExpr>,
+  /// The terms in the store, for hash-consing
+  terms: HashMap<(TermID, Box<[u32]>, u64), u32>,
+  heap: Vec<Elem>,
+  stack: Vec<Elem>,
+  hstack: Vec<u32>,
+  uheap: Vec<u32>,
+  ustack: Vec<u32>,
+  /// The dependency bit for the next bound variable
+  next_bv: u64,
+  /// The position of the current command, for error reporting
+  pos: usize,
+  /// The name of the current declaration, for error reporting
+  decl: String,
+}
+
+impl<'a> Checker<'a> {
+  fn new(file: &'a MMBFile<'a>, env: Option<&'a FrozenEnv>, spec: Vec<SpecDecl>) -> Self {
+    Checker {
+      file, env, spec: spec.into_iter(), term_map: vec![],
+      sorts: vec![], num_terms: 0, num_thms: 0,
+      store: vec![], terms: HashMap::new(),
+      heap: vec![], stack: vec![], hstack: vec![], uheap: vec![], ustack: vec![],
+      next_bv: 1, pos: 0, decl: String::new(),
+    }
+  }
+
+  fn err(&self, msg: impl Into<String>) -> Error {
+    Error {pos: self.pos, decl: self.decl.clone(), msg: msg.into()}
+  }
+
+  fn ty(&self, e: u32) -> u64 {
+    match self.store[e as usize] {
+      StoreExpr::Var(ty) | StoreExpr::Term(ty, _, _) => ty
+    }
+  }
+
+  fn alloc_var(&mut self, ty: u64) -> Result<u32> {
+    let n = u32::try_from(self.store.len()).map_err(|_| self.err("store overflow"))?;
+    self.store.push(StoreExpr::Var(ty));
+    Ok(n)
+  }
+
+  fn alloc_term(&mut self, tid: TermID, args: Box<[u32]>, ty: u64) -> Result<u32> {
+    if let Some(&e) = self.terms.get(&(tid, args.clone(), ty)) { return Ok(e) }
+    let n = u32::try_from(self.store.len()).map_err(|_| self.err("store overflow"))?;
+    self.store.push(StoreExpr::Term(ty, tid, args.clone()));
+    self.terms.insert((tid, args, ty), n);
+    Ok(n)
+  }
+
+  /// Allocate a new bound variable of sort `s`.
+  fn new_dummy(&mut self, s: SortID) -> Result<u32> {
+    let mods = *self.sorts.get(usize::from(s.0)).ok_or_else(|| self.err("bad dummy sort"))?;
+    ensure!(self, !mods.contains(Modifiers::STRICT), "dummy variable in strict sort");
+    ensure!(self, self.next_bv >> 56 == 0, "too many bound variables");
+    let ty = TYPE_BOUND | u64::from(s.0) << 56 | self.next_bv;
+    self.next_bv *= 2;
+    self.alloc_var(ty)
+  }
+
+  /// Start checking a declaration with the given binders: clear the state, check that
+  /// the binders are well formed, and put variables for them on the heap.
+  fn load_args(&mut self, args: &[Arg]) -> Result<()> {
+    self.store.clear();
+    self.terms.clear();
+    self.heap.clear();
+    self.stack.clear();
+    self.hstack.clear();
+    self.next_bv = 1;
+    for &a in args {
+      let mods = *self.sorts.get(usize::from(a.sort().0)).ok_or_else(|| self.err("bad binder sort"))?;
+      if a.bound() {
+        ensure!(self, !mods.contains(Modifiers::STRICT), "bound variable in strict sort");
+        ensure!(self, a.deps() == self.next_bv, "bad binder deps");
+        self.next_bv *= 2;
+      } else {
+        ensure!(self, a.deps() & !(self.next_bv - 1) == 0, "bad binder deps");
+      }
+      let e = self.alloc_var(arg_ty(a))?;
+      self.heap.push(Elem::Expr(e))
+    }
+    Ok(())
+  }
+
+  /// The variables for the first `n` binders, which are at the start of the heap.
+  fn arg_vars(&self, n: usize) -> Vec<u32> {
+    self.heap[..n].iter().map(|e| match *e {
+      Elem::Expr(e) => e,
+      _ => unreachable!("binders are expressions"),
+    }).collect()
+  }
+
+  fn pop(&mut self) -> Result<Elem> {
+    let e = self.stack.pop();
+    e.ok_or_else(|| self.err("stack underflow"))
+  }
+
+  fn pop_expr(&mut self) -> Result<u32> {
+    match self.pop()? { Elem::Expr(e) => Ok(e), _ => Err(self.err("bad stack slot")) }
+  }
+
+  fn pop_proof(&mut self) -> Result<u32> {
+    match self.pop()? { Elem::Proof(e) => Ok(e), _ => Err(self.err("bad stack slot")) }
+  }
+
+  fn pop_co_conv(&mut self) -> Result<(u32, u32)> {
+    match self.pop()? { Elem::CoConv(e1, e2) => Ok((e1, e2)), _ => Err(self.err("bad stack slot")) }
+  }
+
+  fn pop_args(&mut self, n: usize) -> Result<Vec<u32>> {
+    ensure!(self, self.stack.len() >= n, "stack underflow");
+    let args = self.stack.split_off(self.stack.len() - n);
+    args.into_iter().map(|e| match e {
+      Elem::Expr(e) => Ok(e),
+      _ => Err(self.err("bad stack slot")),
+    }).collect()
+  }
+
+  /// The type of the term `t` applied to `args`, after checking the types of the arguments.
+  /// In `Def` mode, the dependencies on the bound variables of `t` are removed from the
+  /// arguments, and replaced by the dependencies of the return type.
+  fn term_ty(&self, t: &TermRef<'_>, args: &[u32], mode: Mode) -> Result<u64> {
+    let mut bound = vec![];
+    let mut accum = u64::from(t.sort().0) << 56;
+    for (&arg, &target) in args.iter().zip(t.args()) {
+      let target = arg_ty(target);
+      let ty = self.ty(arg);
+      ensure!(self, sorts_compatible(ty, target), "type mismatch");
+      let mut deps = ty & TYPE_DEPS;
+      if target & TYPE_BOUND != 0 {
+        bound.push(deps)
+      } else {
+        if mode == Mode::Def {
+          for (j, &d) in bound.iter().enumerate() {
+            if bit(target, j) { deps &= !d }
+          }
+        }
+        accum |= deps
+      }
+    }
+    if mode == Mode::Def {
+      let target = arg_ty(t.ret()) & TYPE_DEPS;
+      for (j, &d) in bound.iter().enumerate() {
+        if bit(target, j) { accum |= d }
+      }
+    }
+    Ok(accum)
+  }
+
+  /// Run a unify stream, matching it against the expression `tgt`.
+  fn run_unify(&mut self, mode: UMode, mut it: UnifyIter<'_>, tgt: u32) -> Result<()> {
+    self.ustack.clear();
+    self.ustack.push(tgt);
+    loop {
+      self.pos = it.pos;
+      let cmd = match it.next() {
+        None => {
+          if mode == UMode::ThmEnd {
+            ensure!(self, self.hstack.is_empty(), "unfinished hypothesis stack")
+          }
+          ensure!(self, self.ustack.is_empty(), "unfinished unify stack");
+          return Ok(())
+        }
+        Some(Err(p)) => { self.pos = p; return Err(self.err("bad unify command")) }
+        Some(Ok(cmd)) => cmd,
+      };
+      match cmd {
+        UnifyCmd::Ref(i) => {
+          let e = *self.uheap.get(i as usize).ok_or_else(|| self.err("bad ref step"))?;
+          let e2 = self.ustack.pop().ok_or_else(|| self.err("unify stack underflow"))?;
+          ensure!(self, e == e2, "unify failure at ref");
+        }
+        UnifyCmd::Term {tid, save} => {
+          let p = self.ustack.pop().ok_or_else(|| self.err("unify stack underflow"))?;
+          match &self.store[p as usize] {
+            StoreExpr::Term(_, t, args) if *t == tid => self.ustack.extend(args.iter().rev()),
+            _ => return Err(self.err("unify failure at term")),
+          }
+          if save { self.uheap.push(p) }
+        }
+        UnifyCmd::Dummy(s) => {
+          ensure!(self, mode == UMode::Def, "Dummy command not allowed in theorem statements");
+          let p = self.ustack.pop().ok_or_else(|| self.err("unify stack underflow"))?;
+          let ty = match self.store[p as usize] {
+            StoreExpr::Var(ty) if ty >> 56 == 0x80 | u64::from(s.0) => ty,
+            _ => return Err(self.err("unify failure at dummy")),
+          };
+          let deps = ty & TYPE_DEPS;
+          for &e in &self.uheap {
+            ensure!(self, self.ty(e) & deps == 0, "dummy disjoint variable violation");
+          }
+          self.uheap.push(p)
+        }
+        UnifyCmd::Hyp => match mode {
+          UMode::Thm => {
+            let e = self.pop_proof()?;
+            self.ustack.push(e)
+          }
+          UMode::ThmEnd => {
+            ensure!(self, self.ustack.is_empty(), "unfinished unify stack");
+            let e = self.hstack.pop().ok_or_else(|| self.err("hypothesis stack underflow"))?;
+            self.ustack.push(e)
+          }
+          UMode::Def => return Err(self.err("Hyp command not allowed in definition statements")),
+        }
+      }
+    }
+  }
+
+  /// Report a disjoint variable violation between arguments `i` and `j` of theorem `t`.
+  fn dv_fail(&self, t: ThmID, i: usize, j: usize) -> Error {
+    let name = self.file.thm_name(t, str::to_owned).unwrap_or_else(|| format!("T{}", t.0));
+    self.err(format!("disjoint variable violation between arguments {} and {} of {}", i, j, name))
+  }
+
+  /// Run a proof stream.
+  fn run_proof(&mut self, mode: Mode, it: &mut ProofIter<'_>) -> Result<()> {
+    let file = self.file;
+    let (_, num_terms, num_thms) = file.counts();
+    loop {
+      self.pos = it.pos;
+      let cmd = match it.next() {
+        None => return Ok(()),
+        Some(Err(p)) => { self.pos = p; return Err(self.err("bad proof command")) }
+        Some(Ok(cmd)) => cmd,
+      };
+      match cmd {
+        ProofCmd::Ref(i) => {
+          let e = *self.heap.get(i as usize).ok_or_else(|| self.err("bad ref step"))?;
+          self.stack.push(e)
+        }
+        ProofCmd::Dummy(s) => {
+          let e = self.new_dummy(s)?;
+          self.stack.push(Elem::Expr(e));
+          self.heap.push(Elem::Expr(e))
+        }
+        ProofCmd::Term {tid, save} => {
+          ensure!(self, (tid.0 as usize) < num_terms && tid.0 < self.num_terms, "term out of range");
+          let t = file.term(tid).ok_or_else(|| self.err("term out of range"))?;
+          let args = self.pop_args(t.args().len())?;
+          let ty = self.term_ty(&t, &args, mode)?;
+          let e = self.alloc_term(tid, args.into(), ty)?;
+          self.stack.push(Elem::Expr(e));
+          if save { self.heap.push(Elem::Expr(e)) }
+        }
+        ProofCmd::Thm {tid, save} => {
+          ensure!(self, mode != Mode::Def, "invalid opcode in def");
+          ensure!(self, (tid.0 as usize) < num_thms && tid.0 < self.num_thms, "theorem out of range");
+          let t = file.thm(tid).ok_or_else(|| self.err("theorem out of range"))?;
+          let e = self.pop_expr()?;
+          let targs = t.args();
+          let args = self.pop_args(targs.len())?;
+          let mut bound = vec![];
+          for (i, (&arg, &target)) in args.iter().zip(targs).enumerate() {
+            let target = arg_ty(target);
+            let ty = self.ty(arg);
+            ensure!(self, sorts_compatible(ty, target), "type mismatch");
+            let deps = ty & TYPE_DEPS;
+            if target & TYPE_BOUND == 0 {
+              for (j, &d) in bound.iter().enumerate() {
+                if !bit(target, j) && d & deps != 0 {
+                  let k = targs.iter().enumerate().filter(|(_, a)| a.bound()).nth(j).expect("bound arg").0;
+                  return Err(self.dv_fail(tid, k, i))
+                }
+              }
+            } else {
+              bound.push(deps);
+              for (j, &arg2) in args[..i].iter().enumerate() {
+                if self.ty(arg2) & deps != 0 { return Err(self.dv_fail(tid, j, i)) }
+              }
+            }
+          }
+          self.uheap = args;
+          self.run_unify(UMode::Thm, t.unify(), e)?;
+          self.stack.push(Elem::Proof(e));
+          if save { self.heap.push(Elem::Proof(e)) }
+        }
+        ProofCmd::Hyp => {
+          ensure!(self, mode != Mode::Def, "invalid opcode in def");
+          let e = self.pop_expr()?;
+          ensure!(self, self.sorts[ty_sort(self.ty(e))].contains(Modifiers::PROVABLE),
+            "hypothesis should have provable sort");
+          self.hstack.push(e);
+          self.heap.push(Elem::Proof(e))
+        }
+        ProofCmd::Conv => {
+          let e2 = self.pop_proof()?;
+          let e1 = self.pop_expr()?;
+          self.stack.push(Elem::Proof(e1));
+          self.stack.push(Elem::CoConv(e1, e2))
+        }
+        ProofCmd::Refl => {
+          let (e1, e2) = self.pop_co_conv()?;
+          ensure!(self, e1 == e2, "Refl unify failure");
+        }
+        ProofCmd::Sym => {
+          let (e1, e2) = self.pop_co_conv()?;
+          self.stack.push(Elem::CoConv(e2, e1))
+        }
+        ProofCmd::Cong => {
+          let (e1, e2) = self.pop_co_conv()?;
+          match (&self.store[e1 as usize], &self.store[e2 as usize]) {
+            (StoreExpr::Term(_, t1, args1), StoreExpr::Term(_, t2, args2)) if t1 == t2 =>
+              for (&a1, &a2) in args1.iter().zip(&**args2).rev() {
+                self.stack.push(Elem::CoConv(a1, a2))
+              },
+            _ => return Err(self.err("Cong unify error")),
+          }
+        }
+        ProofCmd::Unfold => {
+          let e = self.pop_expr()?;
+          let e1 = self.pop_expr()?;
+          let (tid, args) = match &self.store[e1 as usize] {
+            StoreExpr::Term(_, tid, args) => (*tid, args.to_vec()),
+            StoreExpr::Var(_) => return Err(self.err("Unfold: not a definition")),
+          };
+          let t = file.term(tid).ok_or_else(|| self.err("term out of range"))?;
+          ensure!(self, t.def(), "Unfold: not a definition");
+          self.uheap = args;
+          self.run_unify(UMode::Def, t.unify(), e)?;
+          let (c1, c2) = self.pop_co_conv()?;
+          ensure!(self, c1 == e1, "Unfold unify error");
+          self.stack.push(Elem::CoConv(e, c2))
+        }
+        ProofCmd::ConvCut => {
+          let (e1, e2) = self.pop_co_conv()?;
+          self.stack.push(Elem::Conv(e1, e2));
+          self.stack.push(Elem::CoConv(e1, e2))
+        }
+        ProofCmd::ConvRef(i) => {
+          let c = match self.heap.get(i as usize) {
+            Some(&Elem::Conv(e1, e2)) => (e1, e2),
+            _ => return Err(self.err("bad ConvRef step")),
+          };
+          ensure!(self, c == self.pop_co_conv()?, "ConvRef unify error");
+        }
+        ProofCmd::ConvSave => match self.pop()? {
+          Elem::Conv(e1, e2) => self.heap.push(Elem::Conv(e1, e2)),
+          _ => return Err(self.err("bad stack slot")),
+        },
+        ProofCmd::Save => match self.stack.last() {
+          None => return Err(self.err("stack underflow")),
+          Some(Elem::CoConv(..)) => return Err(self.err("Can't save proof obligation")),
+          Some(&e) => self.heap.push(e),
+        },
+      }
+    }
+  }
+
+  /// The single element left on the stack at the end of a proof stream.
+  fn result(&self) -> Result<Elem> {
+    ensure!(self, self.stack.len() == 1, "stack has != one element");
+    Ok(self.stack[0])
+  }
+
+  /// Build an expression of the specification in the store. `vals` are the expressions
+  /// for the heap of the declaration.
+  fn spec_expr(&mut self, vals: &[u32], e: &ExprNode) -> Result<u32> {
+    match *e {
+      ExprNode::Ref(i) => vals.get(i).copied().ok_or_else(|| self.err("bad expression in specification")),
+      ExprNode::Dummy(_, s) => self.new_dummy(s),
+      ExprNode::App(t, ref es) => {
+        let file = self.file;
+        let tid = *self.term_map.get(t.0 as usize).ok_or_else(|| self.err("bad expression in specification"))?;
+        let t = file.term(tid).ok_or_else(|| self.err("term out of range"))?;
+        let args = es.iter().map(|e| self.spec_expr(vals, e)).collect::<Result<Vec<_>>>()?;
+        let ty = self.term_ty(&t, &args, Mode::Def)?;
+        self.alloc_term(tid, args.into(), ty)
+      }
+    }
+  }
+
+  /// Build the expressions for the heap of a declaration of the specification, after the
+  /// first `nargs` elements, which are the variables already on the heap.
+  fn spec_heap(&mut self, nargs: usize, heap: &[ExprNode]) -> Result<Vec<u32>> {
+    let mut vals = self.arg_vars(nargs);
+    for e in heap.get(nargs..).unwrap_or(&[]) {
+      let v = self.spec_expr(&vals, e)?;
+      vals.push(v)
+    }
+    Ok(vals)
+  }
+
+  /// The next declaration of the specification, which should be of the same kind as
+  /// the public declaration of the proof file being checked.
+  fn next_spec(&mut self) -> Result<SpecDecl> {
+    self.spec.next().ok_or_else(|| self.err("declaration is not in the specification"))
+  }
+
+  /// Check the public term or definition `tid` against the specification.
+  fn match_term(&mut self, tid: TermID, t: &TermRef<'_>) -> Result<()> {
+    let env = self.env.expect("checking against a specification");
+    let td = match self.next_spec()? {
+      SpecDecl::Term(t2) => env.term(t2),
+      _ => return Err(self.err("the specification has a different declaration here")),
+    };
+    self.decl = env.data()[td.atom].name().to_string();
+    ensure!(self, binder_tys(&td.args) == t.args().iter().map(|&a| arg_ty(a)).collect::<Vec<_>>(),
+      "the binders differ from the specification");
+    ensure!(self, td.ret == (t.ret().sort(), t.ret().deps()),
+      "the return type differs from the specification");
+    match (&td.kind, t.def()) {
+      (TermKind::Term, false) | (TermKind::Def(None), true) => {}
+      (TermKind::Def(Some(body)), true) => {
+        let mut args = t.args().to_vec();
+        args.push(t.ret());
+        self.load_args(&args)?;
+        self.heap.pop();
+        let vals = self.spec_heap(t.args().len(), &body.heap)?;
+        let e = self.spec_expr(&vals, &body.head)?;
+        self.uheap = self.arg_vars(t.args().len());
+        self.run_unify(UMode::Def, t.unify(), e).map_err(|e| Error {
+          msg: format!("the definition differs from the specification ({})", e.msg), ..e})?
+      }
+      (TermKind::Term, true) => return Err(self.err("a term in the specification is a def")),
+      (TermKind::Def(_), false) => return Err(self.err("a def in the specification is a term")),
+    }
+    self.term_map.push(tid);
+    Ok(())
+  }
+
+  /// Check the public axiom or theorem `tid` against the specification.
+  fn match_thm(&mut self, tid: ThmID, axiom: bool) -> Result<()> {
+    let env = self.env.expect("checking against a specification");
+    let file = self.file;
+    let t = file.thm(tid).ok_or_else(|| self.err("theorem out of range"))?;
+    let (td, spec_axiom) = match self.next_spec()? {
+      SpecDecl::Thm(t2, ax) => (env.thm(t2), ax),
+      _ => return Err(self.err("the specification has a different declaration here")),
+    };
+    self.decl = env.data()[td.atom].name().to_string();
+    ensure!(self, axiom == spec_axiom,
+      if axiom {"an axiom is a theorem in the specification"}
+      else {"a theorem is an axiom in the specification"});
+    ensure!(self, binder_tys(&td.args) == t.args().iter().map(|&a| arg_ty(a)).collect::<Vec<_>>(),
+      "the binders differ from the specification");
+    self.load_args(t.args())?;
+    let vals = self.spec_heap(t.args().len(), &td.heap)?;
+    for (_, h) in &*td.hyps {
+      let e = self.spec_expr(&vals, h)?;
+      self.hstack.push(e)
+    }
+    let ret = self.spec_expr(&vals, &td.ret)?;
+    self.uheap = self.arg_vars(t.args().len());
+    self.run_unify(UMode::ThmEnd, t.unify(), ret).map_err(|e| Error {
+      msg: format!("the statement differs from the specification ({})", e.msg), ..e})
+  }
+
+  /// Check the whole proof file.
+  fn check(&mut self) -> Result<()> {
+    let file = self.file;
+    let (num_sorts, num_terms, num_thms) = file.counts();
+    let mut it = file.proof();
+    loop {
+      self.pos = it.pos;
+      let (stmt, mut pf) = match it.next() {
+        None => break,
+        Some(Err(p)) => { self.pos = p; return Err(self.err("bad statement command")) }
+        Some(Ok(x)) => x,
+      };
+      match stmt {
+        StmtCmd::Sort => {
+          let n = self.sorts.len();
+          ensure!(self, n < num_sorts, "Step sort overflow");
+          let s = SortID(u8::try_from(n).expect("at most 128 sorts"));
+          self.decl = file.sort_name(s, str::to_owned).unwrap_or_default();
+          ensure!(self, pf.is_null(), "Next statement incorrect");
+          let mods = file.sort(s).and_then(|sd| Modifiers::try_from(sd).ok())
+            .ok_or_else(|| self.err("bad sort modifiers"))?;
+          if let Some(env) = self.env {
+            match self.next_spec()? {
+              SpecDecl::Sort(s2) if env.sort(s2).mods == mods => {}
+              SpecDecl::Sort(_) => return Err(self.err("the sort modifiers differ from the specification")),
+              _ => return Err(self.err("the specification has a different declaration here")),
+            }
+          }
+          self.sorts.push(mods)
+        }
+        StmtCmd::TermDef {local} => {
+          let tid = TermID(self.num_terms);
+          self.decl = file.term_name(tid, str::to_owned).unwrap_or_default();
+          ensure!(self, (tid.0 as usize) < num_terms, "Step term overflow");
+          let t = file.term(tid).ok_or_else(|| self.err("bad term table entry"))?;
+          let mods = *self.sorts.get(usize::from(t.sort().0)).ok_or_else(|| self.err("bad sort"))?;
+          ensure!(self, !mods.contains(Modifiers::PURE), "term in pure sort");
+          let ret = t.ret();
+          ensure!(self, !ret.bound() && ret.sort() == t.sort(), "bad return type");
+          let mut args = t.args().to_vec();
+          args.push(ret);
+          self.load_args(&args)?;
+          self.heap.pop();
+          if t.def() {
+            self.run_proof(Mode::Def, &mut pf)?;
+            let val = match self.result()? {
+              Elem::Expr(e) => e,
+              _ => return Err(self.err("bad stack slot")),
+            };
+            let ty = self.ty(val);
+            ensure!(self, sorts_compatible(ty, arg_ty(ret)), "type mismatch");
+            ensure!(self, ty & TYPE_DEPS & !arg_ty(ret) == 0, "type has unaccounted dependencies");
+            self.uheap = self.arg_vars(t.args().len());
+            self.run_unify(UMode::Def, t.unify(), val)?;
+          } else {
+            ensure!(self, pf.is_null(), "Next statement incorrect");
+          }
+          self.num_terms += 1;
+          if !local && self.env.is_some() { self.match_term(tid, &t)? }
+        }
+        StmtCmd::Axiom | StmtCmd::Thm {..} => {
+          let tid = ThmID(self.num_thms);
+          self.decl = file.thm_name(tid, str::to_owned).unwrap_or_default();
+          ensure!(self, (tid.0 as usize) < num_thms, "Step theorem overflow");
+          let t = file.thm(tid).ok_or_else(|| self.err("bad theorem table entry"))?;
+          let axiom = matches!(stmt, StmtCmd::Axiom);
+          self.load_args(t.args())?;
+          self.run_proof(Mode::Thm, &mut pf)?;
+          let val = match (self.result()?, axiom) {
+            (Elem::Expr(e), true) | (Elem::Proof(e), false) => e,
+            _ => return Err(self.err("bad stack slot")),
+          };
+          ensure!(self, self.sorts[ty_sort(self.ty(val))].contains(Modifiers::PROVABLE),
+            "conclusion should have provable sort");
+          self.uheap = self.arg_vars(t.args().len());
+          self.run_unify(UMode::ThmEnd, t.unify(), val)?;
+          self.num_thms += 1;
+          if !matches!(stmt, StmtCmd::Thm {local: true}) && self.env.is_some() {
+            self.match_thm(tid, axiom)?
+          }
+        }
+      }
+    }
+    self.decl = String::new();
+    ensure!(self, self.sorts.len() == num_sorts, "not all sorts proved");
+    ensure!(self, self.num_terms as usize == num_terms, "not all terms proved");
+    ensure!(self, self.num_thms as usize == num_thms, "not all theorems proved");
+    ensure!(self, self.spec.next().is_none(),
+      "the specification has declarations that are not in the proof file");
+    Ok(())
+  }
+}
+
+/// Check the proofs in an MMB file which has just been exported, and if `spec` is given,
+/// check it against that specification as [`verify`] does. This is used by `mm0-rs compile
+/// --kernel-check`, so that nothing the elaborator produces is written out unless this
+/// checker (which shares no code with the elaborator) accepts it.
+///
+/// On failure, returns a message with the name of the failing declaration.
+pub fn check_kernel(buf: &[u8], spec: Option<&Path>) -> std::result::Result<(), String> {
+  let spec = spec.map(load_spec).transpose()?;
+  let buf = AlignedBuffer::new(buf);
+  let file = MMBFile::parse(&buf).map_err(|e| describe(&e))?;
+  let (env, decls) = match &spec {
+    Some((env, decls)) => (Some(env), decls.clone()),
+    None => (None, vec![]),
+  };
+  Checker::new(&file, env, decls).check().map_err(|e|
+    if e.decl.is_empty() { format!("at byte {:#x}: {}", e.pos, e.msg) }
+    else { format!("at byte {:#x}, in {}: {}", e.pos, e.decl, e.msg) })
+}
+
+fn describe(e: &ParseError) -> String {
+  match e {
+    ParseError::BadHeader => "bad header (not an MMB file?)".into(),
+    ParseError::BadVersion => "unknown MMB version".into(),
+    ParseError::BadIndex => "MMB index is malformed".into(),
+    ParseError::StrError(s, p) => format!("at byte {:#x}: {}", p, s),
+    ParseError::IOError(e) => e.to_string(),
+  }
+}
+
+/// Loads a specification and the files it imports, which must all be `.mm0` files. They are
+/// parsed with the MM0 parser and elaborated in MM0 mode, so no lisp code is run, but this
+/// does use the elaborator (see the module documentation).
+#[derive(Default)]
+struct SpecLoader {
+  /// The files loaded so far, so that a file imported twice is only elaborated once
+  envs: HashMap<FileRef, FrozenEnv>,
+  /// The files currently being loaded, to detect import cycles
+  stack: Vec<FileRef>,
+  /// The names of the declarations made with `axiom`
+  axioms: HashSet<Vec<u8>>,
+}
+
+impl SpecLoader {
+  fn load(&mut self, path: FileRef) -> std::result::Result<FrozenEnv, String> {
+    if let Some(env) = self.envs.get(&path) { return Ok(env.clone()) }
+    if !path.has_extension("mm0") {
+      return Err(format!("{}: the specification must be an .mm0 file", path))
+    }
+    if self.stack.contains(&path) { return Err(format!("{}: import cycle", path)) }
+    let text = fs::read_to_string(path.path()).map_err(|e| format!("{}: {}", path, e))?;
+    let (_, ast) = parse(Arc::new(text.into()), None);
+    if !ast.errors.is_empty() {
+      return Err(format!("{}: the specification has errors", path))
+    }
+    for mut s in &ast.stmts {
+      loop {
+        match &s.k {
+          StmtKind::Annot(_, s2) | StmtKind::DocComment(_, s2) => s = s2,
+          StmtKind::Decl(d) if d.k == DeclKind::Axiom => {
+            self.axioms.insert(ast.span(d.id).to_vec());
+            break
+          }
+          _ => break
+        }
+      }
+    }
+    let ast = Arc::new(ast);
+    self.stack.push(path.clone());
+    let mut import_err = None;
+    let (_, _, errors, env) = block_on(elab::elaborate::<()>(
+      &ast, path.clone(), true, false, false, Arc::default(), false, None,
+      |p| match self.load(p) {
+        Ok(env) => {
+          let (send, recv) = channel();
+          let _ = send.send(ElabResult::Ok((), None, env));
+          Ok(recv)
+        }
+        Err(e) => Err(import_err.get_or_insert(e).clone().into()),
+      }));
+    self.stack.pop();
+    if let Some(e) = import_err { return Err(e) }
+    if errors.iter().any(|e| matches!(e.level, ErrorLevel::Error)) {
+      return Err(format!("{}: the specification has errors", path))
+    }
+    self.envs.insert(path, env.clone());
+    Ok(env)
+  }
+}
+
+/// Read the specification `spec`, returning its environment (including the files it
+/// imports) and its public declarations, in order.
+fn load_spec(spec: &Path) -> std::result::Result<(FrozenEnv, Vec<SpecDecl>), String> {
+  let path = fs::canonicalize(spec).map_err(|e| format!("{}: {}", spec.display(), e))?;
+  let mut loader = SpecLoader::default();
+  let env = loader.load(path.into())?;
+  let mut decls = vec![];
+  for s in env.stmts() {
+    let ad = &env.data()[match *s {
+      StmtTrace::Sort(a) | StmtTrace::Decl(a) => a,
+      StmtTrace::Global(_) => continue,
+      StmtTrace::OutputString(_) =>
+        return Err(format!("{}: output statements are not supported", spec.display())),
+    }];
+    if let StmtTrace::Sort(_) = s {
+      decls.extend(ad.sort().map(SpecDecl::Sort))
+    } else {
+      decls.extend(ad.decl().map(|d| match d {
+        DeclKey::Term(t) => SpecDecl::Term(t),
+        DeclKey::Thm(t) => SpecDecl::Thm(t, loader.axioms.contains(&**ad.name())),
+      }))
+    }
+  }
+  Ok((env, decls))
+}
+
+/// Check the proof file `proof` against the specification `spec`. On success, returns
+/// the number of sorts, terms and theorems in the proof file, and on failure, a message
+/// (starting with the name of the file at fault) describing the first failure. If the
+/// proof file has a compressed proof section, it is decompressed first, and the reported
+/// byte offsets are positions in the decompressed file.
+pub(crate) fn verify(spec: &Path, proof: &Path) -> std::result::Result<(usize, usize, usize), String> {
+  let (env, decls) = load_spec(spec)?;
+  let proof_name = proof.display();
+  let buf = File::open(proof).and_then(|f| Buffer::new(&f))
+    .map_err(|e| format!("{}: {}", proof_name, e))?;
+  let decompressed = decompress(&buf).map_err(|e| format!("{}: {}", proof_name, describe(&e)))?;
+  let buf = decompressed.as_deref().unwrap_or(&*buf);
+  let file = MMBFile::parse(buf).map_err(|e| format!("{}: {}", proof_name, describe(&e)))?;
+  Checker::new(&file, Some(&env), decls).check().map_err(|e|
+    if e.decl.is_empty() {
+      format!("{}: at byte {:#x}: {}", proof_name, e.pos, e.msg)
+    } else {
+      format!("{}: at byte {:#x}, in {}: {}", proof_name, e.pos, e.decl, e.msg)
+    })?;
+  Ok(file.counts())
+}
+
+/// Main entry point for `mm0-rs verify` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs verify <spec.mm0> <proof.mmb>`, where:
+///
+/// - `spec.mm0` is the specification
+/// - `proof.mmb` is the proof file to check against it (see [`verify`]).
+///
+/// The process exits with a nonzero status if the proof file is not valid.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let spec = args.value_of("SPEC").expect("required arg");
+  let proof = args.value_of("PROOF").expect("required arg");
+  match verify(Path::new(spec), Path::new(proof)) {
+    Ok((sorts, terms, thms)) =>
+      println!("{}: verified {} sorts, {} terms and {} theorems", proof, sorts, terms, thms),
+    Err(msg) => {
+      eprintln!("{}", msg);
+      std::process::exit(1)
+    }
+  }
+  Ok(())
+}
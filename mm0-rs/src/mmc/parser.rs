@@ -239,6 +239,7 @@ impl<'a> Parser<'a> {
         _ => return Err(ElabError::new_e(self.try_get_span(e), "pattern syntax error"))
       }
       LispKind::Number(n) => Pattern::Number(n.clone()),
+      &LispKind::SmallInt(n) => Pattern::Number(n.into()),
       _ => return Err(ElabError::new_e(self.try_get_span(e), "pattern syntax error"))
     })
   }
@@ -447,6 +448,7 @@ impl<'a> Parser<'a> {
         }
       },
       LispKind::Number(n) => Expr::Number(n.clone()),
+      &LispKind::SmallInt(n) => Expr::Number(n.into()),
       _ => return Err(ElabError::new_e(self.try_get_span(&e), "unknown expression"))
     })
   }
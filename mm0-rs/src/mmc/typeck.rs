@@ -487,6 +487,7 @@ impl<'a> TypeChecker<'a> {
       }
       head.unwrapped(|e| match *e {
         LispKind::Number(ref n) => Ok(PureExpr::Int(n.clone())),
+        LispKind::SmallInt(n) => Ok(PureExpr::Int(n.into())),
         LispKind::Bool(b) => Ok(PureExpr::Bool(b)),
         _ => Err(ElabError::new_e(self.try_get_span(&head), "unexpected expression")),
       })
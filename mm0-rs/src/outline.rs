@@ -0,0 +1,162 @@
+//! A human-readable outline of a specification, for papers and reviews.
+//!
+//!     mm0-rs outline in.mm0 [-w WIDTH] [-o out.md]
+//!
+//! will elaborate `in.mm0` and print a markdown summary of the declarations in it, grouped
+//! into sorts, terms and definitions, notations, axioms and theorems (in declaration order
+//! within each group). Terms, definitions, axioms and theorems are pretty-printed at the given
+//! width (default 80), and notation commands are reproduced as written. Doc comments are
+//! printed as text before the declaration they document. Declarations in imported files are
+//! not included, and proofs are omitted, so this also works for `.mm1` files. For an `.mm1`
+//! file, the lisp definitions with doc comments are listed at the end, which makes the outline
+//! usable as API documentation for a tactic library.
+use std::fs::File;
+use std::io::{self, Write};
+use clap::ArgMatches;
+use crate::elab::environment::{DeclKey, DocComment, StmtTrace, ThmKind};
+use crate::parser::{parse, ast::{Atom, SExpr, SExprKind, Stmt, StmtKind}};
+use crate::util::Span;
+
+/// A group of declarations in the outline: a heading, and for each declaration
+/// its doc comment and text.
+struct Section {
+  title: &'static str,
+  items: Vec<(Option<DocComment>, String)>,
+}
+
+impl Section {
+  fn new(title: &'static str) -> Self { Section {title, items: vec![]} }
+
+  /// Write the section, putting consecutive declarations without doc comments
+  /// in the same code block.
+  fn write(&self, w: &mut impl Write) -> io::Result<()> {
+    if self.items.is_empty() { return Ok(()) }
+    writeln!(w, "\n## {}", self.title)?;
+    let mut open = false;
+    for (doc, text) in &self.items {
+      if let Some(doc) = doc {
+        if open { writeln!(w, "```")?; open = false }
+        writeln!(w)?;
+        for line in doc.lines() { writeln!(w, "{}", line.trim())? }
+      }
+      if !open { writeln!(w, "\n```metamath-zero")?; open = true }
+      writeln!(w, "{}", text)?
+    }
+    if open { writeln!(w, "```")? }
+    Ok(())
+  }
+}
+
+/// Get the notation command in a statement, along with its doc comment.
+fn notation(s: &Stmt) -> Option<(Option<DocComment>, &Stmt)> {
+  match &s.k {
+    StmtKind::DocComment(doc, s) => notation(s).map(|(_, s)| (Some(doc.clone()), s)),
+    StmtKind::Annot(_, s) => notation(s),
+    StmtKind::Delimiter(_) | StmtKind::SimpleNota(_) |
+    StmtKind::Coercion {..} | StmtKind::Notation(_) => Some((None, s)),
+    _ => None
+  }
+}
+
+/// Find the head of the lisp definition `(def head ...)` in `e` of the name with span `name`,
+/// which is `name` itself or a list like `(name args)`.
+fn def_head(e: &SExpr, name: Span) -> Option<Span> {
+  if !(e.span.start <= name.start && name.end <= e.span.end) { return None }
+  let es = match &e.k {
+    SExprKind::DocComment(_, e) => return def_head(e, name),
+    SExprKind::List(es) | SExprKind::DottedList(es, _) => es,
+    _ => return None
+  };
+  if let [SExpr {k: SExprKind::Atom(Atom::Ident), ..}, x, ..] = &**es {
+    if x.span == name { return Some(name) }
+    if let SExprKind::List(xs) | SExprKind::DottedList(xs, _) = &x.k {
+      if xs.first().map(|x| x.span) == Some(name) { return Some(x.span) }
+    }
+  }
+  es.iter().find_map(|e| def_head(e, name))
+}
+
+/// Main entry point for `mm0-rs outline` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs outline [-w WIDTH] [-o out.md] <in.mm0>`, where:
+///
+/// - `in.mm0` is the MM0 (or MM1) file to outline
+/// - `-w WIDTH` sets the width used to pretty-print declarations (default 80)
+/// - `out.md` is the file to write the outline to (default stdout).
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let width = match args.value_of("width") {
+    None => 80,
+    Some(n) => n.parse().map_err(|_|
+      io::Error::new(io::ErrorKind::InvalidInput, "expected a number for --width"))?,
+  };
+  let input = args.value_of("INPUT").expect("required arg");
+  let (fref, text, env) = crate::compiler::elab_file(input)?;
+  let source = text.try_ascii().ok_or_else(||
+    io::Error::new(io::ErrorKind::InvalidInput, "expected a .mm0 or .mm1 file"))?.clone();
+  let fe = unsafe { env.format_env(&source) };
+  let mut sorts = Section::new("Sorts");
+  let mut terms = Section::new("Terms and definitions");
+  let mut notations = Section::new("Notations");
+  let mut axioms = Section::new("Axioms");
+  let mut thms = Section::new("Theorems");
+  let mut globals = vec![];
+  for s in &*fe.stmts {
+    match *s {
+      StmtTrace::Sort(a) => if let Some(s) = fe.data[a].sort {
+        let sd = &fe.sorts[s];
+        if sd.span.file == fref { sorts.items.push((sd.doc.clone(), format!("{}", sd))) }
+      }
+      StmtTrace::Decl(a) => match fe.data[a].decl {
+        Some(DeclKey::Term(t)) => {
+          let td = &fe.terms[t];
+          if td.span.file != fref { continue }
+          let mut out = String::new();
+          fe.pretty(|p| p.term(td).render_fmt(width, &mut out)).expect("writing to a String");
+          terms.items.push((td.doc.clone(), out))
+        }
+        Some(DeclKey::Thm(t)) => {
+          let td = &fe.thms[t];
+          if td.span.file != fref { continue }
+          let mut out = String::new();
+          fe.pretty(|p| p.thm(td).render_fmt(width, &mut out)).expect("writing to a String");
+          let sec = if matches!(td.kind, ThmKind::Axiom) {&mut axioms} else {&mut thms};
+          sec.items.push((td.doc.clone(), out))
+        }
+        None => {}
+      },
+      StmtTrace::Global(a) => if let Some(ld) = &fe.data[a].lisp {
+        if let (Some((fsp, _)), Some(doc)) = (&ld.src, &ld.doc) {
+          if fsp.file == fref { globals.push((fsp.span, doc.clone())) }
+        }
+      }
+      StmtTrace::OutputString(_) => {}
+    }
+  }
+  // Notations are not stored in a form that can be printed back, so we take them
+  // from the source.
+  let (_, ast) = parse(source.clone(), None);
+  for s in &ast.stmts {
+    if let Some((doc, s)) = notation(s) {
+      notations.items.push((doc, String::from_utf8_lossy(&source[s.span]).into_owned()))
+    }
+  }
+  // Likewise, the parameters of lisp definitions are only in the source.
+  let mut lisp = Section::new("Lisp definitions");
+  for (name, doc) in globals {
+    let head = ast.stmts.iter().find(|s| s.span.start <= name.start && name.end <= s.span.end)
+      .and_then(|s| match &s.k {
+        StmtKind::Do(es) => es.iter().find_map(|e| def_head(e, name)),
+        _ => None
+      }).unwrap_or(name);
+    lisp.items.push((Some(doc), String::from_utf8_lossy(&source[head]).into_owned()))
+  }
+  let mut w: Box<dyn Write> = match args.value_of("output") {
+    None | Some("-") => Box::new(io::stdout()),
+    Some(file) => Box::new(io::BufWriter::new(File::create(file)?)),
+  };
+  writeln!(w, "# Outline of `{}`", fref.rel())?;
+  for sec in &[sorts, terms, notations, axioms, thms, lisp] { sec.write(&mut w)? }
+  w.flush()
+}
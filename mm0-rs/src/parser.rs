@@ -589,15 +589,17 @@ impl<'a> Parser<'a> {
   fn sexpr_dot(&mut self) -> Result<SExpr> {
     let start = self.idx;
     match self.cur_opt() {
-      Some(b'\'') => {
+      Some(b'\'') | Some(b'`') => {
         self.idx += 1;
         let e = self.sexpr()?;
         Ok(SExpr::list(start..e.span.end, vec![SExpr::atom(start..=start, Atom::Quote), e]))
       }
       Some(b',') => {
         self.idx += 1;
+        let a = if let Some(b'@') = self.cur_opt() {self.idx += 1; Atom::UnquoteSplicing} else {Atom::Unquote};
+        let end = self.idx;
         let e = self.sexpr()?;
-        Ok(SExpr::list(start..e.span.end, vec![SExpr::atom(start..=start, Atom::Unquote), e]))
+        Ok(SExpr::list(start..e.span.end, vec![SExpr::atom(start..end, a), e]))
       }
       Some(b'(') => {
         let start = self.idx; self.idx += 1; self.ws();
@@ -972,11 +974,14 @@ impl<'a> Parser<'a> {
 /// `old` contains the last successful parse of the same file, in order to reuse
 /// previous parsing work. The [`Position`] denotes the first byte where the
 /// new file differs from the old one.
+///
+/// Returns the number of statements at the start of the file which were reused from
+/// `old` (which are unchanged), and the parsed file.
 #[must_use] pub fn parse(
   file: Arc<LinedString>,
   old: Option<(Position, Arc<AST>)>
 ) -> (usize, AST) {
-  let (errors, imports, idx, mut stmts) =
+  let (ix, errors, imports, idx, mut stmts) =
     if let Some((pos, ast)) = old {
       let (ix, start) = ast.last_checkpoint(file.to_idx(pos).expect("bad line position"));
       match Arc::try_unwrap(ast) {
@@ -984,9 +989,9 @@ impl<'a> Parser<'a> {
           ast.errors.retain(|e| e.pos.start < start);
           ast.imports.retain(|e| e.0.start < start);
           ast.stmts.truncate(ix);
-          (ast.errors, ast.imports, start, ast.stmts)
+          (ix, ast.errors, ast.imports, start, ast.stmts)
         }
-        Err(ast) => (
+        Err(ast) => (ix,
           ast.errors.iter().filter(|e| e.pos.start < start).cloned().collect(),
           ast.imports.iter().filter(|e| e.0.start < start).cloned().collect(),
           start, ast.stmts[..ix].into())
@@ -995,5 +1000,5 @@ impl<'a> Parser<'a> {
   let mut p = Parser {source: file.as_bytes(), errors, imports, idx, restart_pos: None};
   p.ws();
   while let Some(d) = p.stmt_recover() { stmts.push(d) }
-  (0, AST { errors: p.errors, imports: p.imports, source: file, stmts })
+  (ix, AST { errors: p.errors, imports: p.imports, source: file, stmts })
 }
@@ -270,8 +270,9 @@ pub struct SExpr {
 /// Lisp atom kind.
 ///
 /// The [`Ident`](Atom::Ident) atom indicates that the atom text is the span,
-/// and the [`Quote`](Atom::Quote), [`Unquote`](Atom::Unquote) and [`Nfx`](Atom::Nfx)
-/// atoms have data `quote`, `unquote` and `:nfx` respectively,
+/// and the [`Quote`](Atom::Quote), [`Unquote`](Atom::Unquote),
+/// [`UnquoteSplicing`](Atom::UnquoteSplicing) and [`Nfx`](Atom::Nfx)
+/// atoms have data `quote`, `unquote`, `unquote-splicing` and `:nfx` respectively,
 /// but the span does not contain this text because
 /// these atoms are created implicitly via keywords like `'`.
 #[derive(Copy, Clone, Debug)]
@@ -280,11 +281,14 @@ pub enum Atom {
   /// `foo` and this is interpreted as an atom `"foo"`.
   Ident,
   /// This is an atom with the text `quote` that was generated from a
-  /// literal `'` in the input.
+  /// literal `'` or `` ` `` in the input.
   Quote,
   /// This is an atom with the text `unquote` that was generated from a
   /// literal `,` in the input.
   Unquote,
+  /// This is an atom with the text `unquote-splicing` that was generated from a
+  /// literal `,@` in the input.
+  UnquoteSplicing,
   /// This is an atom with the text `:nfx` that was generated by a malformed curly list
   /// (see [`curly_transform`]).
   Nfx,
@@ -637,6 +641,17 @@ impl Stmt {
   #[must_use] pub fn new(span: Span, k: StmtKind) -> Self {
     Stmt { span, k }
   }
+
+  /// The span of the "head" of the statement, suitable for attaching diagnostics
+  /// about the statement as a whole. This is the name of the declaration for
+  /// declarations, and the whole statement otherwise.
+  #[must_use] pub fn head(&self) -> Span {
+    match &self.k {
+      StmtKind::Decl(d) => d.id,
+      StmtKind::Annot(_, s) | StmtKind::DocComment(_, s) => s.head(),
+      _ => self.span,
+    }
+  }
 }
 
 
@@ -664,6 +679,7 @@ impl LinedString {
       Atom::Ident => &self[sp],
       Atom::Quote => b"quote",
       Atom::Unquote => b"unquote",
+      Atom::UnquoteSplicing => b"unquote-splicing",
       Atom::Nfx => b":nfx",
     }
   }
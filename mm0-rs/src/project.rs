@@ -0,0 +1,376 @@
+//! Project manifests (`mm0.toml`) and import resolution.
+//!
+//! By default, the file name in `import "foo.mm1";` is relative to the directory of the
+//! importing file. A development split over several directories, or using another
+//! development, can instead put a manifest `mm0.toml` in its root directory:
+//!
+//! ```toml
+//! [package]
+//! name = "peano"
+//! # Directories searched for imports, relative to this file (default ["."])
+//! roots = ["src", "lib"]
+//!
+//! [deps]
+//! # import "set/axioms.mm1"; finds axioms.mm1 in the roots of the project in ../set
+//! set = "../set"
+//!
+//! # Targets for `mm0-rs build`
+//! [[output]]
+//! name = "peano"
+//! input = "src/peano.mm1"
+//! output = "build/peano.mmb"
+//! ```
+//!
+//! An import is resolved as follows:
+//!
+//! * If the name starts with `./` or `../`, or is absolute, it is relative to the
+//!   importing file, as without a manifest.
+//! * Otherwise, if the file exists relative to the importing file, that file is used,
+//!   so developments that don't use a manifest are not affected by one.
+//! * Otherwise, the project of the importing file is found, by looking for `mm0.toml` in
+//!   its directory and the directories above it. If the first component of the name is a
+//!   dependency, the rest of the name is looked up in the roots of the dependency (which are
+//!   read from the `mm0.toml` in its directory, if it has one, and are that directory
+//!   otherwise). If not, the name is looked up in the roots of the project, in order.
+//!
+//! The same resolver is used by the elaborator, so both `mm0-rs compile` and the server
+//! follow these rules, and by `mm0-rs join`. Manifests are reread when they change.
+//!
+//! Only the subset of TOML used above is supported: tables, arrays of tables, and keys
+//! whose values are strings or arrays of strings.
+use std::collections::HashMap;
+use std::{fs, io};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use clap::ArgMatches;
+use crate::util::MutexExt;
+
+/// The file name of a project manifest.
+pub const MANIFEST: &str = "mm0.toml";
+
+lazy_static! {
+  /// The manifests that have been read, by path, with their modification times.
+  static ref PROJECTS: Mutex<HashMap<PathBuf, (Option<SystemTime>, Arc<Project>)>> =
+    Mutex::new(HashMap::new());
+}
+
+/// An output target of a project, built by `mm0-rs build`.
+#[derive(Debug)]
+pub struct Output {
+  /// The name of the target, which defaults to the file name of `input`
+  /// without the extension.
+  pub name: String,
+  /// The file to compile.
+  pub input: PathBuf,
+  /// The file to write (`.mmb` or `.mmu`), if any.
+  pub output: Option<PathBuf>,
+}
+
+/// A project manifest. All paths are absolute.
+#[derive(Debug)]
+pub struct Project {
+  /// The directory containing the manifest.
+  pub dir: PathBuf,
+  /// The name of the project, if given.
+  pub name: Option<String>,
+  /// The directories that are searched for imports, in order.
+  pub roots: Vec<PathBuf>,
+  /// The dependencies, and the directories containing them.
+  pub deps: Vec<(String, PathBuf)>,
+  /// The output targets.
+  pub outputs: Vec<Output>,
+}
+
+/// A parser for the subset of TOML used by manifests.
+struct Parser<'a> {
+  /// The manifest source.
+  s: &'a [u8],
+  /// The current position in `s`.
+  pos: usize,
+}
+
+/// The keys `name`, `input` and `output` of an `[[output]]` section being read.
+type PartialOutput = (Option<String>, Option<PathBuf>, Option<PathBuf>);
+
+/// A value in a manifest.
+enum Value {
+  /// A string.
+  Str(String),
+  /// An array of strings.
+  Array(Vec<String>),
+}
+
+impl Parser<'_> {
+  /// Make an error at the current position.
+  fn err(&self, msg: impl Into<String>) -> (usize, String) {
+    (self.s[..self.pos].iter().filter(|&&c| c == b'\n').count() + 1, msg.into())
+  }
+
+  fn peek(&self) -> Option<u8> { self.s.get(self.pos).copied() }
+
+  /// Skip spaces and comments, and also newlines if `nl` is true.
+  fn ws(&mut self, nl: bool) {
+    while let Some(c) = self.peek() {
+      match c {
+        b' ' | b'\t' | b'\r' => self.pos += 1,
+        b'\n' if nl => self.pos += 1,
+        b'#' => while !matches!(self.peek(), None | Some(b'\n')) { self.pos += 1 },
+        _ => break
+      }
+    }
+  }
+
+  fn eat(&mut self, c: u8) -> bool {
+    if self.peek() == Some(c) { self.pos += 1; true } else { false }
+  }
+
+  fn expect(&mut self, c: u8) -> Result<(), (usize, String)> {
+    if self.eat(c) { Ok(()) } else { Err(self.err(format!("expected '{}'", c as char))) }
+  }
+
+  /// Parse a basic string (in double quotes).
+  fn string(&mut self) -> Result<String, (usize, String)> {
+    self.expect(b'"')?;
+    let mut out = vec![];
+    loop {
+      match self.peek() {
+        None | Some(b'\n') => return Err(self.err("unterminated string")),
+        Some(b'"') => { self.pos += 1; break }
+        Some(b'\\') => {
+          self.pos += 1;
+          out.push(match self.peek() {
+            Some(b'"') => b'"',
+            Some(b'\\') => b'\\',
+            Some(b'n') => b'\n',
+            Some(b't') => b'\t',
+            _ => return Err(self.err("unsupported escape sequence"))
+          });
+          self.pos += 1
+        }
+        Some(c) => { out.push(c); self.pos += 1 }
+      }
+    }
+    String::from_utf8(out).map_err(|_| self.err("invalid utf8"))
+  }
+
+  /// Parse a key (a bare key or a string).
+  fn key(&mut self) -> Result<String, (usize, String)> {
+    if self.peek() == Some(b'"') { return self.string() }
+    let start = self.pos;
+    while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == b'_' || c == b'-') {
+      self.pos += 1
+    }
+    if start == self.pos { return Err(self.err("expected a key")) }
+    std::str::from_utf8(&self.s[start..self.pos]).map(Into::into).map_err(|_| self.err("invalid utf8"))
+  }
+
+  /// Parse a value (a string or an array of strings).
+  fn value(&mut self) -> Result<Value, (usize, String)> {
+    if !self.eat(b'[') { return Ok(Value::Str(self.string()?)) }
+    let mut out = vec![];
+    loop {
+      self.ws(true);
+      if self.eat(b']') { break }
+      out.push(self.string()?);
+      self.ws(true);
+      if !self.eat(b',') { self.ws(true); self.expect(b']')?; break }
+    }
+    Ok(Value::Array(out))
+  }
+
+  /// Parse the end of a line, after a header or a key-value pair.
+  fn end_line(&mut self) -> Result<(), (usize, String)> {
+    self.ws(false);
+    if self.peek().is_none() || self.eat(b'\n') { Ok(()) } else { Err(self.err("expected a newline")) }
+  }
+}
+
+impl Project {
+  /// Parse the manifest `text` of the project in directory `dir`. Errors are returned
+  /// with their line number.
+  fn parse(dir: PathBuf, text: &str) -> Result<Project, (usize, String)> {
+    /// Add the `[[output]]` section that has been read, if any, to `proj`.
+    fn finish(proj: &mut Project, output: Option<PartialOutput>) -> Result<(), String> {
+      if let Some((name, input, output)) = output {
+        let input = input.ok_or("[[output]] is missing the key 'input'")?;
+        let name = name.or_else(|| Some(input.file_stem()?.to_str()?.into()))
+          .ok_or("[[output]] is missing the key 'name'")?;
+        proj.outputs.push(Output {name, input, output})
+      }
+      Ok(())
+    }
+    let mut p = Parser {s: text.as_bytes(), pos: 0};
+    let base = dir.clone();
+    let mut proj = Project {dir, name: None, roots: vec![], deps: vec![], outputs: vec![]};
+    let mut roots = None;
+    let mut section = String::new();
+    let mut output: Option<PartialOutput> = None;
+    loop {
+      p.ws(true);
+      if p.peek().is_none() { break }
+      if p.eat(b'[') {
+        let array = p.eat(b'[');
+        p.ws(false);
+        let key = p.key()?;
+        p.ws(false);
+        p.expect(b']')?;
+        if array { p.expect(b']')? }
+        finish(&mut proj, output.take()).map_err(|e| p.err(e))?;
+        match (&*key, array) {
+          ("package", false) | ("deps", false) => {}
+          ("output", true) => output = Some((None, None, None)),
+          _ => return Err(p.err(format!("unknown section '{}'", key)))
+        }
+        section = key;
+      } else {
+        let key = p.key()?;
+        p.ws(false);
+        p.expect(b'=')?;
+        p.ws(false);
+        let val = p.value()?;
+        match (&*section, &*key, val) {
+          ("package", "name", Value::Str(s)) => proj.name = Some(s),
+          ("package", "roots", Value::Array(v)) => roots = Some(v.iter().map(|s| base.join(s)).collect()),
+          ("deps", _, Value::Str(s)) => {
+            if proj.deps.iter().any(|(n, _)| *n == key) {
+              return Err(p.err(format!("duplicate dependency '{}'", key)))
+            }
+            proj.deps.push((key.clone(), base.join(s)))
+          }
+          ("output", "name", Value::Str(s)) => output.as_mut().expect("output section").0 = Some(s),
+          ("output", "input", Value::Str(s)) => output.as_mut().expect("output section").1 = Some(base.join(s)),
+          ("output", "output", Value::Str(s)) => output.as_mut().expect("output section").2 = Some(base.join(s)),
+          ("", _, _) => return Err(p.err(format!("key '{}' must be in a section", key))),
+          _ => return Err(p.err(format!("unknown key '{}', or wrong type of value", key)))
+        }
+      }
+      p.end_line()?;
+    }
+    finish(&mut proj, output).map_err(|e| p.err(e))?;
+    proj.roots = roots.unwrap_or_else(|| vec![proj.dir.clone()]);
+    Ok(proj)
+  }
+
+  /// Load the manifest at `path`, reusing the last result if it has not changed.
+  /// Returns `None` if there is no manifest at `path`.
+  pub fn load(path: &Path) -> io::Result<Option<Arc<Project>>> {
+    let mtime = match fs::metadata(path) {
+      Ok(m) => m.modified().ok(),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(e)
+    };
+    if let Some((t, proj)) = PROJECTS.ulock().get(path) {
+      if mtime.is_some() && *t == mtime { return Ok(Some(proj.clone())) }
+    }
+    let text = fs::read_to_string(path)?;
+    let dir = path.parent().map_or_else(PathBuf::new, Path::to_owned);
+    let proj = Arc::new(Project::parse(dir, &text).map_err(|(line, msg)|
+      io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: {}", path.display(), line, msg)))?);
+    PROJECTS.ulock().insert(path.to_owned(), (mtime, proj.clone()));
+    Ok(Some(proj))
+  }
+
+  /// Find the project containing `dir`, by looking for a manifest in `dir` and the
+  /// directories above it.
+  pub fn find(dir: &Path) -> io::Result<Option<Arc<Project>>> {
+    for d in dir.ancestors() {
+      if let Some(proj) = Project::load(&d.join(MANIFEST))? { return Ok(Some(proj)) }
+    }
+    Ok(None)
+  }
+
+  /// The path of the manifest.
+  #[must_use] pub fn manifest(&self) -> PathBuf { self.dir.join(MANIFEST) }
+
+  /// The directories to search for the import `f` of a file in this project, which may be
+  /// a directory (ending in `/`) or a prefix of one, as well as a file name: the roots of
+  /// the dependency named by the first component of `f`, with the rest of `f`, or else the
+  /// roots of the project, with `f`.
+  pub fn search_path<'a>(&self, f: &'a str) -> io::Result<(Vec<PathBuf>, &'a str)> {
+    if let Some(i) = f.find('/') {
+      if let Some((_, dir)) = self.deps.iter().find(|(n, _)| *n == f[..i]) {
+        let roots = match Project::load(&dir.join(MANIFEST))? {
+          Some(dep) => dep.roots.clone(),
+          None => vec![dir.clone()]
+        };
+        return Ok((roots, &f[i+1..]))
+      }
+    }
+    Ok((self.roots.clone(), f))
+  }
+}
+
+/// Returns true if the import `f` is explicitly relative to the importing file
+/// (it starts with `./` or `../`, or is absolute).
+#[must_use] pub fn is_relative(f: &str) -> bool {
+  f.starts_with("./") || f.starts_with("../") || Path::new(f).is_absolute()
+}
+
+/// Resolve the import `f` in the file `path`, returning the canonicalized path of the
+/// imported file. See the [module documentation](self) for the rules.
+pub fn resolve_import(path: &Path, f: &str) -> io::Result<PathBuf> {
+  let dir = path.parent().unwrap_or_else(|| Path::new(""));
+  let local = dir.join(f);
+  // Files that are not in a manifest project resolve their imports as they always have,
+  // relative to the importing file, and so do explicitly relative imports.
+  if is_relative(f) || local.exists() { return local.canonicalize() }
+  let proj = match dir.canonicalize().ok().map(|d| Project::find(&d)).transpose()? {
+    Some(Some(proj)) => proj,
+    _ => return local.canonicalize()
+  };
+  let (roots, rest) = proj.search_path(f)?;
+  for root in &roots {
+    let p = root.join(rest);
+    if p.exists() { return p.canonicalize() }
+  }
+  Err(io::Error::new(io::ErrorKind::NotFound, format!(
+    "file '{}' not found in {} or the roots of {}", f, dir.display(), proj.manifest().display())))
+}
+
+/// Main entry point for `mm0-rs build` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs build [dir] [target...]`, where:
+///
+/// - `dir` is a directory in the project (default the current directory); the manifest is
+///   found in it or a directory above it.
+/// - `target...` are the names of the `[[output]]` targets to build, in the order of the
+///   manifest (default all of them).
+///
+/// Each target is compiled like `mm0-rs compile input output`, and the other options are
+/// the same as for `mm0-rs compile`. Imports that are shared between targets are only
+/// elaborated once. The process exits with a nonzero status if any target fails.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let dir = fs::canonicalize(args.value_of("DIR").unwrap_or("."))?;
+  let proj = Project::find(&dir)?.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound,
+    format!("no {} found in {} or the directories above it", MANIFEST, dir.display())))?;
+  let targets = args.values_of("TARGET").map(|ts| ts.collect::<Vec<_>>());
+  if let Some(targets) = &targets {
+    for t in targets {
+      if !proj.outputs.iter().any(|o| o.name == *t) {
+        eprintln!("{}: no target named '{}'", proj.manifest().display(), t);
+        std::process::exit(1)
+      }
+    }
+  }
+  crate::compiler::init(args)?;
+  let mut ok = true;
+  for out in &proj.outputs {
+    if targets.as_ref().map_or(false, |ts| !ts.contains(&&*out.name)) { continue }
+    let input = out.input.to_str().ok_or_else(||
+      io::Error::new(io::ErrorKind::InvalidInput, "input path is not valid utf8"))?;
+    let output = out.output.as_ref().map(|p| {
+      if let Some(dir) = p.parent() { fs::create_dir_all(dir)? }
+      p.to_str().ok_or_else(||
+        io::Error::new(io::ErrorKind::InvalidInput, "output path is not valid utf8"))
+    }).transpose()?;
+    if !crate::compiler::compile(args, input, output)? {
+      eprintln!("{}: target '{}' failed", proj.manifest().display(), out.name);
+      ok = false
+    }
+  }
+  if !ok { std::process::exit(1) }
+  Ok(())
+}
@@ -0,0 +1,269 @@
+//! Translate the math strings of a file to a different notation profile.
+//!
+//! When a library changes its notations (for example, renaming a notation or
+//! switching a token from ASCII to Unicode), every math string in every file that uses
+//! the old notation has to be rewritten. This module automates that process:
+//!
+//!     mm0-rs renotate in.mm1 profile.mm1 out.mm1
+//!
+//! will elaborate `in.mm1`, then re-parse each math string `$ .. $` using the notations
+//! that were in effect at its statement, and print it back using the notations
+//! declared in `profile.mm1`. Terms are matched up by name, so `profile.mm1` is typically
+//! a copy of the library header with the new notation declarations. Everything outside
+//! the math strings (comments, whitespace, lisp code) is preserved verbatim.
+//!
+//! Terms that are not declared in the profile are printed with the default notation
+//! for term applications, as in `foo x (bar y)`. For this they are added to the profile
+//! environment, together with their sorts if necessary, without notations.
+//!
+//! Math strings containing antiquotations `,e` are left unchanged, because they cannot
+//! be re-printed without evaluating the lisp code, as are any math strings that fail
+//! to parse.
+use std::fs::File;
+use std::io::{self, Write};
+use clap::ArgMatches;
+use crate::elab::{Environment, FrozenEnv, environment::{DeclKey, Delims, ParserEnv, Prec,
+  SortID, Term, TermID, TermKind, Type as EType}, math_parser::{parse_formula, QExpr, QExprKind}};
+use crate::elab::lisp::{LispVal, print::FormatEnv};
+use crate::parser::{parse, ast::{Delimiter, Formula, SExpr, SExprKind, Stmt, StmtKind, Type}};
+use crate::lined_string::LinedString;
+use crate::util::{FileRef, Span};
+
+/// The display width used for re-printed math strings. We do not want the printer to
+/// insert line breaks, since it does not know the indentation of the surrounding text.
+const WIDTH: usize = 10000;
+
+/// Collect the formulas in an s-expression.
+fn sexpr_formulas(e: &SExpr, out: &mut Vec<Formula>) {
+  match &e.k {
+    SExprKind::Formula(f) => out.push(*f),
+    SExprKind::List(es) => for e in es { sexpr_formulas(e, out) },
+    SExprKind::DottedList(es, r) => {
+      for e in es { sexpr_formulas(e, out) }
+      sexpr_formulas(r, out)
+    }
+    SExprKind::DocComment(_, e) => sexpr_formulas(e, out),
+    SExprKind::Atom(_) | SExprKind::Number(_) | SExprKind::String(_) |
+    SExprKind::Bool(_) | SExprKind::Undef => {}
+  }
+}
+
+/// The notations in force at each statement of the input file, which are those of the
+/// end of the file without the ones declared by later statements.
+struct Notations<'a> {
+  /// The input file.
+  file: &'a FileRef,
+  /// The notations at the end of the file.
+  pe: &'a ParserEnv,
+  /// The delimiters declared by the imports of the file.
+  delims: (Delims, Delims),
+  /// The delimiter statements of the file, with their positions.
+  delim_stmts: Vec<(usize, &'a Delimiter)>,
+  /// The sorted positions of the notations declared in the file.
+  positions: Vec<usize>,
+  /// The notations at the last statement, and the number of `positions` before it.
+  cur: Option<(usize, ParserEnv)>,
+}
+
+impl<'a> Notations<'a> {
+  fn new(file: &'a FileRef, pe: &'a ParserEnv, imports: &[FrozenEnv], ast: &'a [Stmt]) -> Self {
+    let mut delims = (Delims::default(), Delims::default());
+    for env in imports {
+      delims.0.merge(&env.pe().delims_l);
+      delims.1.merge(&env.pe().delims_r);
+    }
+    let mut delim_stmts = vec![];
+    for s in ast {
+      if let StmtKind::Delimiter(d) = &s.k { delim_stmts.push((s.span.start, d)) }
+    }
+    let here = |fsp: &crate::util::FileSpan| if fsp.file == *file {Some(fsp.span.start)} else {None};
+    let mut positions: Vec<usize> = delim_stmts.iter().map(|&(p, _)| p)
+      .chain(pe.consts.values().filter_map(|(fsp, _)| here(fsp)))
+      .chain(pe.prec_assoc.values().filter_map(|(fsp, _)| here(fsp)))
+      .chain(pe.prefixes.values().chain(pe.infixes.values()).filter_map(|n| here(&n.span)))
+      .collect();
+    positions.sort_unstable();
+    Self {file, pe, delims, delim_stmts, positions, cur: None}
+  }
+
+  /// The notations in force at the statement starting at `pos`.
+  fn at(&mut self, pos: usize) -> &ParserEnv {
+    let n = self.positions.partition_point(|&p| p < pos);
+    if !matches!(self.cur, Some((m, _)) if m == n) {
+      let file = self.file;
+      let before = |fsp: &crate::util::FileSpan| fsp.file != *file || fsp.span.start < pos;
+      let mut pe = self.pe.clone();
+      pe.consts.retain(|_, (fsp, _)| before(fsp));
+      pe.prec_assoc.retain(|_, (fsp, _)| before(fsp));
+      pe.prefixes.retain(|_, n| before(&n.span));
+      pe.infixes.retain(|_, n| before(&n.span));
+      pe.delims_l = self.delims.0;
+      pe.delims_r = self.delims.1;
+      for &(p, d) in &self.delim_stmts {
+        if p >= pos { break }
+        match d {
+          Delimiter::Both(f) => pe.add_delimiters(f, f),
+          Delimiter::LeftRight(ls, rs) => pe.add_delimiters(ls, rs),
+        }
+      }
+      self.cur = Some((n, pe));
+    }
+    &self.cur.as_ref().expect("set above").1
+  }
+}
+
+/// Collect the formulas in a statement. This skips the formulas in `delimiter`
+/// and notation commands, since these are constants rather than expressions.
+fn stmt_formulas(s: &Stmt, out: &mut Vec<Formula>) {
+  match &s.k {
+    StmtKind::Decl(d) => {
+      for bi in &d.bis {
+        if let Some(Type::Formula(f)) = bi.ty { out.push(f) }
+      }
+      if let Some(Type::Formula(f)) = d.ty { out.push(f) }
+      if let Some(e) = &d.val { sexpr_formulas(e, out) }
+    }
+    StmtKind::Do(es) | StmtKind::Inout {hs: es, ..} => for e in es { sexpr_formulas(e, out) },
+    StmtKind::Annot(e, s) => { sexpr_formulas(e, out); stmt_formulas(s, out) }
+    StmtKind::DocComment(_, s) => stmt_formulas(s, out),
+    StmtKind::Sort(..) | StmtKind::Delimiter(_) | StmtKind::SimpleNota(_) |
+    StmtKind::Coercion {..} | StmtKind::Notation(_) | StmtKind::Import(..) => {}
+  }
+}
+
+/// The state of the translation: the source environment, used to resolve the names in
+/// the input, and the target environment containing the notations to print with.
+struct Renotate<'a> {
+  /// The source text of the input file.
+  source: &'a LinedString,
+  /// The environment of the input file.
+  env: &'a FrozenEnv,
+  /// The target environment. This is a copy of the profile environment,
+  /// extended with atoms for the local variables of the input file, and with the terms
+  /// of the input file that are not in the profile.
+  target: Environment,
+}
+
+impl<'a> Renotate<'a> {
+  /// Translate a [`QExpr`] into an expression in the target environment, or return
+  /// [`None`] if the expression contains an antiquotation.
+  fn expr(&mut self, e: &QExpr) -> Option<LispVal> {
+    let env = self.env;
+    let (a, es) = match &e.k {
+      QExprKind::IdentApp(sp, es) => {
+        let name = &self.source[*sp];
+        match env.get_atom(name).and_then(|a| env.data()[a].decl()) {
+          Some(DeclKey::Term(t)) => (self.term(t)?, es),
+          _ => (self.target.get_atom(name), es),
+        }
+      }
+      QExprKind::App(_, t, es) => (self.term(*t)?, es),
+      QExprKind::Unquote(_) => return None,
+    };
+    if es.is_empty() { return Some(LispVal::atom(a)) }
+    let mut args = vec![LispVal::atom(a)];
+    for e in &**es { args.push(self.expr(e)?) }
+    Some(LispVal::list(args))
+  }
+
+  /// The term of the target environment with the name of the term `t` of the input. If
+  /// the profile does not declare it, it is added without a notation, so that it is
+  /// printed in the default notation. Returns [`None`] if the name is a theorem.
+  fn term(&mut self, t: TermID) -> Option<crate::elab::environment::AtomID> {
+    let td = self.env.term(t);
+    let a = self.target.get_atom(self.env.data()[td.atom].name());
+    match self.target.data[a].decl {
+      Some(DeclKey::Term(_)) => return Some(a),
+      Some(DeclKey::Thm(_)) => return None,
+      None => {}
+    }
+    let mut sort = |s: SortID| -> Option<SortID> {
+      let sd = self.env.sort(s);
+      let a = self.target.get_atom(&sd.name);
+      self.target.add_sort(a, sd.span.clone(), sd.full, sd.mods, None).ok()
+    };
+    let args = td.args.iter().map(|(_, ty)| Some((None, match *ty {
+      EType::Bound(s) => EType::Bound(sort(s)?),
+      EType::Reg(s, deps) => EType::Reg(sort(s)?, deps),
+    }))).collect::<Option<_>>()?;
+    let ret = (sort(td.ret.0)?, td.ret.1);
+    self.target.add_term(Term {
+      atom: a, span: td.span.clone(), vis: td.vis, full: td.full, doc: None,
+      args, ret, kind: TermKind::Term
+    }).ok()?;
+    Some(a)
+  }
+
+  /// Print an expression in the target environment, in parentheses if its precedence
+  /// is lower than `p`.
+  fn print(&self, e: &LispVal, p: Prec) -> String {
+    let fe = FormatEnv {source: self.source, env: &self.target};
+    fe.pretty(|pr| {
+      let mut s = String::new();
+      pr.expr_prec(e, p).render_fmt(WIDTH, &mut s).expect("writing to a string");
+      s
+    })
+  }
+
+  /// Re-print the formula `f` using the notations `pe`, returning [`None`] if it should be
+  /// left unchanged.
+  fn formula(&mut self, pe: &ParserEnv, f: Formula) -> Option<String> {
+    let mut errors = vec![];
+    let e = match parse_formula(pe, self.source.as_bytes(), f, &mut errors) {
+      Ok(e) if errors.is_empty() => e,
+      _ => {
+        let pos = self.source.to_pos(f.0.start);
+        eprintln!("{}:{}: could not parse math string, skipping",
+          pos.line + 1, pos.character + 1);
+        return None
+      }
+    };
+    let e = self.expr(&e)?;
+    Some(format!("$ {} $", self.print(&e, Prec::Prec(0))))
+  }
+}
+
+/// Main entry point for `mm0-rs renotate` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs renotate <in.mm1> <profile.mm1> <out.mm1>`, where:
+///
+/// - `in.mm1` is the MM1 (or MM0) file to translate
+/// - `profile.mm1` is an MM1 (or MM0) file containing the new notations
+/// - `out.mm1` is the output file. (This is not optional, because elaboration
+///   progress messages are printed to stdout.)
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let (path, text, env) = crate::compiler::elab_file(args.value_of("INPUT").expect("required arg"))?;
+  let (_, _, profile) = crate::compiler::elab_file(args.value_of("PROFILE").expect("required arg"))?;
+  let source = text.ascii().clone();
+  let mut target = Environment::new();
+  let mut errors = vec![];
+  if target.merge(&profile, Span::default(), &mut errors).is_err() || !errors.is_empty() {
+    eprintln!("failed to load notation profile");
+    std::process::exit(1)
+  }
+  let mut ren = Renotate {source: &source, env: &env, target};
+  let (_, ast) = parse(source.clone(), None);
+  let imports = ast.imports.iter()
+    .filter_map(|(_, f)| crate::compiler::elaborated(&crate::cache::resolve_import(&path, f)?))
+    .collect::<Vec<_>>();
+  let mut nota = Notations::new(&path, env.pe(), &imports, &ast.stmts);
+  let mut w = io::BufWriter::new(File::create(args.value_of("OUTPUT").expect("required arg"))?);
+  let mut start = 0;
+  for stmt in &ast.stmts {
+    let mut fmlas = vec![];
+    stmt_formulas(stmt, &mut fmlas);
+    if fmlas.is_empty() { continue }
+    let pe = nota.at(stmt.span.start);
+    for f in fmlas {
+      if let Some(s) = ren.formula(pe, f) {
+        w.write_all(&source.as_bytes()[start..f.0.start])?;
+        w.write_all(s.as_bytes())?;
+        start = f.0.end;
+      }
+    }
+  }
+  w.write_all(&source.as_bytes()[start..])?;
+  w.flush()
+}
@@ -0,0 +1,286 @@
+//! Reorder the statements of a file so that every declaration comes after the ones it uses.
+//!
+//! When lemmas are moved between sections of a library, it is easy to end up with a file
+//! in which a theorem is used before it is proved, which the elaborator reports as an
+//! unknown identifier. This module computes a valid order:
+//!
+//!     mm0-rs reorder in.mm1 out.mm1
+//!
+//! will parse `in.mm1` (without elaborating it), work out which statements each statement
+//! refers to, and write the statements to `out.mm1` in an order where every statement comes
+//! after the ones it refers to. Among the valid orders, the one closest to the original is
+//! chosen: statements only move if they have to, and a statement that has to move is placed
+//! as soon as the statements it depends on have been. Comments and whitespace before a
+//! statement move with it, except for the text at the start of the file. If the references
+//! are cyclic, no output is written, and the shortest cycle through each group of mutually
+//! dependent statements is reported instead.
+//!
+//! The dependency analysis is syntactic: a statement refers to a declaration if the name of
+//! the declaration appears as an identifier in it (including in math strings and proofs), and
+//! to a notation command if one of its tokens appears in a math string of the statement. This
+//! can find dependencies that are not real (for example a local variable with the same name as
+//! a theorem), but these only cause a statement to move when the names are declared out of
+//! order.
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::Arc;
+use clap::ArgMatches;
+use crate::parser::{parse, ErrorLevel, ast::{Atom, Delimiter, Formula, Literal, SExpr, SExprKind,
+  Stmt, StmtKind, Type}};
+use crate::lined_string::LinedString;
+use crate::util::Span;
+
+/// The names defined by a statement and the names it refers to.
+#[derive(Default)]
+struct Names<'a> {
+  defs: Vec<&'a [u8]>,
+  refs: HashSet<&'a [u8]>,
+}
+
+/// The state of the dependency analysis.
+struct Analyzer<'a> {
+  source: &'a LinedString,
+  /// The delimiter characters declared in the file, which separate the tokens of
+  /// math strings.
+  delims: HashSet<u8>,
+}
+
+impl<'a> Analyzer<'a> {
+  fn text(&self, sp: Span) -> &'a [u8] { &self.source[sp] }
+
+  fn formula(&self, f: Formula, out: &mut Names<'a>) {
+    let delims = &self.delims;
+    let s = self.text(f.inner());
+    let mut start = 0;
+    for (i, &c) in s.iter().enumerate() {
+      if c.is_ascii_whitespace() || delims.contains(&c) {
+        if start < i { out.refs.insert(&s[start..i]); }
+        if delims.contains(&c) { out.refs.insert(&s[i..=i]); }
+        start = i + 1;
+      }
+    }
+    if start < s.len() { out.refs.insert(&s[start..]); }
+  }
+
+  fn sexpr(&self, e: &SExpr, out: &mut Names<'a>) {
+    match &e.k {
+      SExprKind::Atom(Atom::Ident) => { out.refs.insert(self.text(e.span)); }
+      SExprKind::Formula(f) => self.formula(*f, out),
+      SExprKind::List(es) => for e in es { self.sexpr(e, out) },
+      SExprKind::DottedList(es, r) => {
+        for e in es { self.sexpr(e, out) }
+        self.sexpr(r, out)
+      }
+      SExprKind::DocComment(_, e) => self.sexpr(e, out),
+      SExprKind::Atom(_) | SExprKind::Number(_) | SExprKind::String(_) |
+      SExprKind::Bool(_) | SExprKind::Undef => {}
+    }
+  }
+
+  fn ty(&self, ty: &Option<Type>, out: &mut Names<'a>) {
+    match ty {
+      Some(Type::DepType(d)) => { out.refs.insert(self.text(d.sort)); }
+      Some(Type::Formula(f)) => self.formula(*f, out),
+      None => {}
+    }
+  }
+
+  /// The name defined by a top level `(def x ...)` or `(def (x ...) ...)` in a `do` block.
+  fn lisp_def(&self, e: &SExpr) -> Option<&'a [u8]> {
+    if let SExprKind::List(es) = &e.k {
+      if let [head, x, ..] = &**es {
+        if let SExprKind::Atom(Atom::Ident) = head.k {
+          if self.text(head.span) == b"def" {
+            return match &x.k {
+              SExprKind::Atom(Atom::Ident) => Some(self.text(x.span)),
+              SExprKind::List(xs) | SExprKind::DottedList(xs, _) => match xs.first() {
+                Some(x @ SExpr {k: SExprKind::Atom(Atom::Ident), ..}) => Some(self.text(x.span)),
+                _ => None
+              },
+              _ => None
+            }
+          }
+        }
+      }
+    }
+    None
+  }
+
+  fn stmt(&self, s: &Stmt, out: &mut Names<'a>) {
+    match &s.k {
+      &StmtKind::Sort(id, _) => out.defs.push(self.text(id)),
+      StmtKind::Decl(d) => {
+        out.defs.push(self.text(d.id));
+        for bi in &d.bis { self.ty(&bi.ty, out) }
+        self.ty(&d.ty, out);
+        if let Some(e) = &d.val { self.sexpr(e, out) }
+      }
+      StmtKind::Delimiter(_) | StmtKind::Import(..) => {}
+      StmtKind::SimpleNota(n) => {
+        out.refs.insert(self.text(n.id));
+        out.defs.push(self.text(n.c.trim));
+      }
+      &StmtKind::Coercion {id, from, to} => for sp in [id, from, to].iter() {
+        out.refs.insert(self.text(*sp));
+      },
+      StmtKind::Notation(n) => {
+        out.refs.insert(self.text(n.id));
+        for bi in &n.bis { self.ty(&bi.ty, out) }
+        for lit in &n.lits {
+          if let Literal::Const(c, _) = lit { out.defs.push(self.text(c.trim)) }
+        }
+      }
+      StmtKind::Inout {hs, ..} => for e in hs { self.sexpr(e, out) },
+      StmtKind::Annot(e, s) => { self.sexpr(e, out); self.stmt(s, out) }
+      StmtKind::DocComment(_, s) => self.stmt(s, out),
+      StmtKind::Do(es) => for e in es {
+        if let Some(x) = self.lisp_def(e) { out.defs.push(x) }
+        self.sexpr(e, out)
+      },
+    }
+  }
+}
+
+/// Compute the dependencies between the statements: `deps[i]` is the list of statements
+/// that statement `i` refers to. A name defined by several statements refers to the first.
+fn dependencies(names: &[Names<'_>]) -> Vec<Vec<usize>> {
+  let mut defs = HashMap::new();
+  for (i, n) in names.iter().enumerate() {
+    for &x in &n.defs { defs.entry(x).or_insert(i); }
+  }
+  names.iter().enumerate().map(|(i, n)| {
+    let mut deps: Vec<usize> = n.refs.iter()
+      .filter_map(|x| defs.get(x).copied()).filter(|&j| j != i).collect();
+    deps.sort_unstable();
+    deps.dedup();
+    deps
+  }).collect()
+}
+
+/// Order the statements so that each comes after its dependencies, staying as close as
+/// possible to the original order: at each step the first statement (in the original
+/// order) whose dependencies have all been placed is placed next. Returns the statements
+/// that could not be placed because of cycles as the second component.
+fn order(deps: &[Vec<usize>]) -> (Vec<usize>, Vec<usize>) {
+  let n = deps.len();
+  let mut waiting = vec![0; n];
+  let mut users = vec![vec![]; n];
+  for (i, ds) in deps.iter().enumerate() {
+    waiting[i] = ds.len();
+    for &j in ds { users[j].push(i) }
+  }
+  let mut ready: BinaryHeap<_> = (0..n).filter(|&i| waiting[i] == 0).map(Reverse).collect();
+  let mut out = Vec::with_capacity(n);
+  while let Some(Reverse(i)) = ready.pop() {
+    out.push(i);
+    for &j in &users[i] {
+      waiting[j] -= 1;
+      if waiting[j] == 0 { ready.push(Reverse(j)) }
+    }
+  }
+  let stuck = (0..n).filter(|&i| waiting[i] != 0).collect();
+  (out, stuck)
+}
+
+/// Find the shortest cycle through `start` among the statements in `stuck`, by a
+/// breadth first search along the dependencies.
+fn shortest_cycle(deps: &[Vec<usize>], stuck: &HashSet<usize>, start: usize) -> Option<Vec<usize>> {
+  let mut prev = HashMap::new();
+  let mut queue = VecDeque::from(vec![start]);
+  while let Some(i) = queue.pop_front() {
+    for &j in &deps[i] {
+      if !stuck.contains(&j) { continue }
+      if j == start {
+        let mut cycle = vec![i];
+        let mut k = i;
+        while k != start { k = prev[&k]; cycle.push(k) }
+        cycle.reverse();
+        return Some(cycle)
+      }
+      if let std::collections::hash_map::Entry::Vacant(e) = prev.entry(j) {
+        e.insert(i);
+        queue.push_back(j)
+      }
+    }
+  }
+  None
+}
+
+/// Main entry point for `mm0-rs reorder` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs reorder <in.mm1> <out.mm1>`, where:
+///
+/// - `in.mm1` is the MM1 (or MM0) file to reorder
+/// - `out.mm1` is the output file, which may be the same as the input file.
+///
+/// The number of statements that were moved is printed. If the statements cannot be
+/// ordered, the cycles are printed and the process exits with a nonzero status
+/// without writing the output.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let input = args.value_of("INPUT").expect("required arg");
+  let source = Arc::new(LinedString::from(std::fs::read_to_string(input)?));
+  let (_, ast) = parse(source.clone(), None);
+  for e in &ast.errors {
+    let pos = source.to_pos(e.pos.start);
+    eprintln!("{}:{}:{}: {}", input, pos.line + 1, pos.character + 1, e.msg)
+  }
+  if ast.errors.iter().any(|e| matches!(e.level, ErrorLevel::Error)) { std::process::exit(1) }
+  let mut delims = HashSet::new();
+  for s in &ast.stmts {
+    if let StmtKind::Delimiter(d) = &s.k {
+      match d {
+        Delimiter::Both(cs) => delims.extend(cs.iter().copied()),
+        Delimiter::LeftRight(ls, rs) => delims.extend(ls.iter().chain(&**rs).copied()),
+      }
+    }
+  }
+  let an = Analyzer {source: &source, delims};
+  let names: Vec<Names<'_>> = ast.stmts.iter().map(|s| {
+    let mut out = Names::default();
+    an.stmt(s, &mut out);
+    out
+  }).collect();
+  let deps = dependencies(&names);
+  let (order, stuck) = order(&deps);
+  let name = |i: usize| match names[i].defs.first() {
+    Some(x) => String::from_utf8_lossy(x).into_owned(),
+    None => format!("<statement at line {}>", source.to_pos(ast.stmts[i].span.start).line + 1),
+  };
+  if !stuck.is_empty() {
+    let stuck_set: HashSet<usize> = stuck.iter().copied().collect();
+    let mut reported = HashSet::new();
+    for &i in &stuck {
+      if reported.contains(&i) { continue }
+      if let Some(cycle) = shortest_cycle(&deps, &stuck_set, i) {
+        let mut msg = format!("{}: cycle: {}", input, name(cycle[0]));
+        for &j in cycle[1..].iter().chain(Some(&cycle[0])) { msg += &format!(" -> {}", name(j)) }
+        eprintln!("{}", msg);
+        reported.extend(cycle);
+      }
+    }
+    std::process::exit(1)
+  }
+  // Each statement owns the text from the end of the previous statement to its own end,
+  // so that the comments before a statement move with it.
+  let header = ast.stmts.first().map_or(0, |s| s.span.start);
+  let mut chunks = Vec::with_capacity(ast.stmts.len());
+  let mut start = header;
+  for s in &ast.stmts {
+    chunks.push(start..s.span.end);
+    start = s.span.end;
+  }
+  // A statement has moved if it is placed after a statement that came later in the input.
+  let mut last = 0;
+  let moved = order.iter().filter(|&&i| { let m = i < last; last = last.max(i); m }).count();
+  let mut w = io::BufWriter::new(File::create(args.value_of("OUTPUT").expect("required arg"))?);
+  w.write_all(&source.as_bytes()[..header])?;
+  for &i in &order { w.write_all(&source.as_bytes()[chunks[i].clone()])? }
+  w.write_all(&source.as_bytes()[start..])?;
+  w.flush()?;
+  println!("moved {} statements", moved);
+  Ok(())
+}
@@ -0,0 +1,234 @@
+//! Round-trip testing of the MMU and MMB proof formats.
+//!
+//!     mm0-rs roundtrip a.mm1 b.mm1 ...
+//!
+//! will elaborate each file, export it to both `.mmu` and `.mmb` (in memory), import the
+//! results again, and check that each re-imported environment has the same sorts, terms,
+//! definitions and theorem statements as the original. Every mismatch is reported,
+//! and the command fails if any file does not round-trip, so running it on a
+//! representative corpus is a good check before changing either format.
+//!
+//! Definitions and proofs are compared up to the sharing of subterms and the names of
+//! dummy variables. Variable names and visibility modifiers are not compared, since the
+//! formats are not required to preserve them exactly.
+use std::collections::{HashMap, HashSet};
+use std::io;
+use clap::ArgMatches;
+use crate::elab::environment::{Environment, AtomID, Type, ExprNode, ProofNode, Proof,
+  TermKind, ThmKind};
+use crate::mmb::{export::Exporter as MMBExporter, import::elab as mmb_elab, parser::AlignedBuffer};
+use crate::mmu::import::elab as mmu_elab;
+
+/// The state for comparing a proof in the old environment with one in the new environment.
+/// See [`ProofCmp::eq`].
+struct ProofCmp<'a> {
+  /// The number of variables, which are the first elements of both heaps.
+  nargs: usize,
+  /// The heap of the old proof.
+  h1: &'a [ProofNode],
+  /// The heap of the new proof.
+  h2: &'a [ProofNode],
+  /// The renaming of the dummy variables of the old proof to those of the new proof.
+  dummies: HashMap<AtomID, AtomID>,
+  /// The pairs of nodes already found to be equal, so that shared subproofs are
+  /// only compared once.
+  done: HashSet<(*const ProofNode, *const ProofNode)>,
+}
+
+impl<'a> ProofCmp<'a> {
+  fn new(nargs: usize, p1: &'a Proof, p2: &'a Proof) -> Self {
+    Self {nargs, h1: &p1.heap, h2: &p2.heap, dummies: HashMap::new(), done: HashSet::new()}
+  }
+
+  fn all(&mut self, xs: &'a [ProofNode], ys: &'a [ProofNode]) -> bool {
+    xs.len() == ys.len() && xs.iter().zip(ys).all(|(x, y)| self.eq(x, y))
+  }
+
+  fn eq3(&mut self, x: &'a (ProofNode, ProofNode, ProofNode), y: &'a (ProofNode, ProofNode, ProofNode)) -> bool {
+    self.eq(&x.0, &y.0) && self.eq(&x.1, &y.1) && self.eq(&x.2, &y.2)
+  }
+
+  /// Compare the node `x` of the old proof with `y` of the new one. As in
+  /// [`Compare::expr`], heap references are expanded, variables are compared by index,
+  /// and dummy variables up to renaming.
+  fn eq(&mut self, x: &'a ProofNode, y: &'a ProofNode) -> bool {
+    let key = (x as *const ProofNode, y as *const ProofNode);
+    if self.done.contains(&key) { return true }
+    let (h1, h2) = (self.h1, self.h2);
+    let res = match (x, y) {
+      (&ProofNode::Ref(i), _) if i >= self.nargs => return self.eq(&h1[i], y),
+      (_, &ProofNode::Ref(j)) if j >= self.nargs => return self.eq(x, &h2[j]),
+      (&ProofNode::Ref(i), &ProofNode::Ref(j)) => i == j,
+      (&ProofNode::Dummy(a, s), &ProofNode::Dummy(b, t)) =>
+        s == t && *self.dummies.entry(a).or_insert(b) == b,
+      (ProofNode::Term {term: t, args: es}, ProofNode::Term {term: u, args: fs}) |
+      (ProofNode::Cong {term: t, args: es}, ProofNode::Cong {term: u, args: fs}) =>
+        t == u && self.all(es, fs),
+      (ProofNode::Hyp(i, e), ProofNode::Hyp(j, f)) => i == j && self.eq(e, f),
+      (ProofNode::Thm {thm: t, args: es, res: e}, ProofNode::Thm {thm: u, args: fs, res: f}) =>
+        t == u && self.all(es, fs) && self.eq(e, f),
+      (ProofNode::Conv(p), ProofNode::Conv(q)) => self.eq3(p, q),
+      (ProofNode::Refl(e), ProofNode::Refl(f)) |
+      (ProofNode::Sym(e), ProofNode::Sym(f)) => self.eq(e, f),
+      (ProofNode::Unfold {term: t, args: es, res: p}, ProofNode::Unfold {term: u, args: fs, res: q}) =>
+        t == u && self.all(es, fs) && self.eq3(p, q),
+      _ => false
+    };
+    if res { self.done.insert(key); }
+    res
+  }
+}
+
+/// Compares an environment with the result of exporting and re-importing it.
+struct Compare<'a> {
+  old: &'a Environment,
+  new: &'a Environment,
+  /// The format being tested, used as a prefix for the error messages.
+  fmt: &'static str,
+  /// The mismatches found so far.
+  errors: Vec<String>,
+}
+
+impl Compare<'_> {
+  fn err(&mut self, msg: String) { self.errors.push(format!("{}: {}", self.fmt, msg)) }
+
+  fn binders(a: &[(Option<AtomID>, Type)], b: &[(Option<AtomID>, Type)]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(x, y)| x.1 == y.1)
+  }
+
+  /// Compare the expression `x` using the heap `h1` of the old environment with `y` using
+  /// the heap `h2` of the new one. Heap references are expanded, so the two sides can share
+  /// subterms differently. Variables (the first `nargs` heap elements) are compared by index,
+  /// and dummy variables up to the renaming `dummies`.
+  fn expr(dummies: &mut HashMap<AtomID, AtomID>, nargs: usize,
+    h1: &[ExprNode], x: &ExprNode, h2: &[ExprNode], y: &ExprNode
+  ) -> bool {
+    match (x, y) {
+      (&ExprNode::Ref(i), _) if i >= nargs => Self::expr(dummies, nargs, h1, &h1[i], h2, y),
+      (_, &ExprNode::Ref(j)) if j >= nargs => Self::expr(dummies, nargs, h1, x, h2, &h2[j]),
+      (&ExprNode::Ref(i), &ExprNode::Ref(j)) => i == j,
+      (&ExprNode::Dummy(a, s), &ExprNode::Dummy(b, t)) =>
+        s == t && *dummies.entry(a).or_insert(b) == b,
+      (ExprNode::App(t, es), ExprNode::App(u, fs)) => t == u && es.len() == fs.len() &&
+        es.iter().zip(&**fs).all(|(e, f)| Self::expr(dummies, nargs, h1, e, h2, f)),
+      _ => false
+    }
+  }
+
+  fn run(&mut self) {
+    let (old, new) = (self.old, self.new);
+    if old.sorts.0.len() != new.sorts.0.len() {
+      self.err(format!("expected {} sorts, found {}", old.sorts.0.len(), new.sorts.0.len()))
+    }
+    for (s1, s2) in old.sorts.0.iter().zip(&new.sorts.0) {
+      if s1.name != s2.name {
+        self.err(format!("sort {}: name changed to {}", s1.name, s2.name))
+      } else if s1.mods != s2.mods {
+        self.err(format!("sort {}: modifiers changed", s1.name))
+      }
+    }
+    if old.terms.0.len() != new.terms.0.len() {
+      self.err(format!("expected {} terms, found {}", old.terms.0.len(), new.terms.0.len()))
+    }
+    for (t1, t2) in old.terms.0.iter().zip(&new.terms.0) {
+      let name = &old.data[t1.atom].name;
+      if *name != new.data[t2.atom].name {
+        self.err(format!("term {}: name changed to {}", name, new.data[t2.atom].name));
+        continue
+      }
+      if !Self::binders(&t1.args, &t2.args) || t1.ret != t2.ret {
+        self.err(format!("term {}: type changed", name))
+      }
+      match (&t1.kind, &t2.kind) {
+        (TermKind::Term, TermKind::Term) |
+        (TermKind::Def(None), TermKind::Def(None)) => {}
+        (TermKind::Def(Some(e1)), TermKind::Def(Some(e2))) =>
+          if !Self::expr(&mut HashMap::new(), t1.args.len(), &e1.heap, &e1.head, &e2.heap, &e2.head) {
+            self.err(format!("def {}: value changed", name))
+          },
+        (TermKind::Def(Some(_)), TermKind::Def(None)) =>
+          self.err(format!("def {}: value lost", name)),
+        _ => self.err(format!("term {}: term/def classification changed", name)),
+      }
+    }
+    if old.thms.0.len() != new.thms.0.len() {
+      self.err(format!("expected {} theorems, found {}", old.thms.0.len(), new.thms.0.len()))
+    }
+    for (t1, t2) in old.thms.0.iter().zip(&new.thms.0) {
+      let name = &old.data[t1.atom].name;
+      if *name != new.data[t2.atom].name {
+        self.err(format!("theorem {}: name changed to {}", name, new.data[t2.atom].name));
+        continue
+      }
+      let nargs = t1.args.len();
+      let mut dummies = HashMap::new();
+      if !Self::binders(&t1.args, &t2.args) || t1.hyps.len() != t2.hyps.len() ||
+        !t1.hyps.iter().zip(&*t2.hyps).all(|(h1, h2)|
+          Self::expr(&mut dummies, nargs, &t1.heap, &h1.1, &t2.heap, &h2.1)) ||
+        !Self::expr(&mut dummies, nargs, &t1.heap, &t1.ret, &t2.heap, &t2.ret) {
+        self.err(format!("theorem {}: statement changed", name))
+      }
+      match (&t1.kind, &t2.kind) {
+        (ThmKind::Axiom, ThmKind::Axiom) |
+        (ThmKind::Thm(None), ThmKind::Thm(_)) => {}
+        (ThmKind::Thm(Some(p1)), ThmKind::Thm(Some(p2))) => {
+          let mut cmp = ProofCmp::new(nargs, p1, p2);
+          if !cmp.all(&p1.hyps, &p2.hyps) || !cmp.eq(&p1.head, &p2.head) {
+            self.err(format!("theorem {}: proof changed", name))
+          }
+        }
+        (ThmKind::Thm(Some(_)), ThmKind::Thm(None)) =>
+          self.err(format!("theorem {}: proof lost", name)),
+        _ => self.err(format!("theorem {}: axiom/theorem classification changed", name)),
+      }
+    }
+  }
+}
+
+/// Main entry point for `mm0-rs roundtrip` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs roundtrip <in.mm1>...`, where each `in.mm1` is an MM1 (or MM0) file to test.
+/// The mismatches are printed to stderr, and the process exits with a nonzero status
+/// if there are any.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let mut ok = true;
+  for path in args.values_of("INPUT").expect("required arg") {
+    let (fref, text, env) = crate::compiler::elab_file(path)?;
+    let source = if let Some(source) = text.try_ascii() { source.clone() } else {
+      eprintln!("{}: expected an .mm1 or .mm0 file", path);
+      ok = false;
+      continue
+    };
+    // The MMB exporter is run first, because it rejects theorems without a proof
+    let mut mmb = io::Cursor::new(vec![]);
+    let mut ex = MMBExporter::new(fref.clone(), &source, &env, &mut mmb);
+    if let Err(e) = ex.run(true) {
+      eprintln!("{}: {}", path, e);
+      ok = false;
+      continue
+    }
+    ex.finish()?;
+    let mut mmu = vec![];
+    env.export_mmu(&mut mmu)?;
+    let mmb = AlignedBuffer::new(mmb.get_ref());
+    // Safety: the environment is not modified or cloned while we hold this reference
+    let old = unsafe { env.thaw() };
+    for (fmt, (res, new)) in vec![("mmu", mmu_elab(&fref, &mmu)), ("mmb", mmb_elab(&fref, &mmb))] {
+      let mut cmp = Compare {old, new: &new, fmt, errors: vec![]};
+      match res {
+        Ok(()) => cmp.run(),
+        Err(e) => cmp.err(format!("import failed: {}", e.kind.msg())),
+      }
+      for e in &cmp.errors { eprintln!("{}: {}", path, e) }
+      ok &= cmp.errors.is_empty();
+    }
+  }
+  if ok {
+    println!("all files round-trip successfully");
+  } else {
+    std::process::exit(1)
+  }
+  Ok(())
+}
@@ -4,35 +4,38 @@ use std::{fs, io};
 use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}, Condvar};
 use std::collections::{VecDeque, HashMap, HashSet, hash_map::{Entry, DefaultHasher}};
 use std::hash::{Hash, Hasher};
+use std::convert::TryInto;
 use std::result::Result as StdResult;
 use std::thread::{ThreadId, self};
 use std::time::Instant;
+use std::path::{Path, PathBuf};
 use futures::{FutureExt, future::BoxFuture};
 use futures::channel::oneshot::{Sender as FSender, channel};
 use futures::executor::ThreadPool;
 use futures::lock::Mutex as FMutex;
 use lsp_server::{Connection, ErrorCode, Message, Notification, ProtocolError,
   Request, RequestId, Response, ResponseError};
-use serde::ser::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::{from_value, to_value};
 use serde_repr::{Serialize_repr, Deserialize_repr};
-use serde::Deserialize;
 #[allow(clippy::wildcard_imports)] use lsp_types::*;
 use crossbeam::channel::{SendError, RecvError};
 use clap::ArgMatches;
 use crate::util::{ArcList, ArcString, BoxError, FileRef, FileSpan, Span,
   MutexExt, CondvarExt};
 use crate::lined_string::LinedString;
-use crate::parser::{AST, parse};
+use crate::parser::{AST, parse,
+  ast::{Atom, DeclKind, Modifiers, SExpr, SExprKind, Stmt, StmtKind, Type}};
 use crate::mmb::import::elab as mmb_elab;
 use crate::mmu::import::elab as mmu_elab;
 use crate::compiler::FileContents;
-use crate::elab::{ElabResult, self, FrozenEnv,
-  environment::{ObjectKind, DeclKey, StmtTrace, AtomID, SortID, TermID, ThmID},
+use crate::elab::{ElabResult, ErrorLevel, self, FrozenEnv,
+  environment::{Coe, DocComment, ObjectKind, DeclKey, StmtTrace, AtomID, SortID, TermID, ThmID, ThmKind, ExprNode},
   FrozenLispKind, FrozenAtomData,
-  local_context::InferSort, proof::Subst,
-  lisp::{print::FormatEnv, pretty::Pretty, LispKind, Proc, BuiltinProc},
-  spans::Spans};
+  local_context::InferSort, proof::Subst, cong::deref,
+  lisp::{print::FormatEnv, pretty::Pretty, LispKind, LispVal, Proc, ProcSpec, BuiltinProc,
+    Syntax, debugger::{self, DebugCommand, Paused}, output::{self, Output, OutputChannel}},
+  spans::{Hint, Spans}};
 
 // Disabled because vscode doesn't handle them properly
 const USE_LOCATION_LINKS: bool = false;
@@ -151,11 +154,17 @@ macro_rules! log {
   ($($es:tt)*) => {crate::server::log(format!($($es)*))}
 }
 
+/// The position of the first character where `new` differs from `old`.
+fn first_change(old: &LinedString, new: &LinedString) -> Position {
+  let n = old.as_bytes().iter().zip(new.as_bytes()).take_while(|(a, b)| a == b).count();
+  new.to_pos(n)
+}
+
 async fn elaborate(path: FileRef, start: Option<Position>,
     cancel: Arc<AtomicBool>, rd: ArcList<FileRef>) -> Result<ElabResult<u64>> {
   let vfs = &SERVER.vfs;
   debug_assert!(!rd.contains(&path));
-  let (path, file) = vfs.get_or_insert(path)?;
+  let (path, file) = vfs.get_or_insert(path).await?;
   let v = file.text.ulock().0;
   let (old_ast, old_env, old_deps) = {
     let mut g = file.parsed.lock().await;
@@ -206,13 +215,16 @@ async fn elaborate(path: FileRef, start: Option<Position>,
     res
   };
   let (version, text) = file.text.ulock().clone();
-  let old_ast = old_ast.and_then(|(s, old_text, ast)|
-    if text.ptr_eq(&old_text) {Some((s, ast?))} else {None});
+  // There may have been several edits since the last elaboration, so rather than using the
+  // position of the last one, we compare the texts to find where they first differ.
+  let old_ast = old_ast.and_then(|(_, old_text, ast)|
+    Some((first_change(old_text.ascii(), text.ascii()), ast?)));
   let mut hasher = DefaultHasher::new();
   version.hash(&mut hasher);
   let source = text.clone();
 
   let mut deps = Vec::new();
+  let (mut cache_key, mut from_cache) = (None, false);
   let (ast, (cyc, toks, errors, env)) = if path.has_extension("mmb") {
     let (error, env) = mmb_elab(&path, &text);
     let errors = if let Err(e) = error {vec![e]} else {vec![]};
@@ -224,24 +236,46 @@ async fn elaborate(path: FileRef, start: Option<Position>,
   } else {
     let (idx, ast) = parse(text.ascii().clone(), old_ast);
     let ast = Arc::new(ast);
-    let rd = rd.push(path.clone());
-    (Some(ast.clone()), elab::elaborate(
-      &ast, path.clone(), path.has_extension("mm0"),
-      crate::get_check_proofs(), true, cancel.clone(),
-      old_env.map(|(errs, e)| (idx, errs, e)),
-      |p| {
-        let p = vfs.get_or_insert(p)?.0;
-        let (send, recv) = channel();
-        if rd.contains(&p) {
-          send.send(ElabResult::ImportCycle(rd.clone())).expect("failed to send");
-        } else {
-          Job::ElaborateDep(p.clone(), path.clone(), Some((send, rd.clone()))).spawn();
-          deps.push(p);
-        }
-        Ok(recv)
-      }).await)
+    // Dependencies that are not open in the editor are loaded from the cache, if possible
+    if version.is_none() && !rd.is_empty() && path.is_on_disk() &&
+        ast.errors.is_empty() && crate::cache::enabled() {
+      cache_key = crate::cache::key(&path, &mut HashMap::new(),
+        &mut |p| Ok(vfs.get_or_insert_blocking(p.clone())?.1.text.ulock().1.clone()));
+    }
+    let cached = cache_key.and_then(|key|
+      crate::cache::load(key, &mut |p| Ok(vfs.get_or_insert_blocking(p.into())?.0))
+        .unwrap_or_else(|e| { log!("failed to load {:?} from cache: {}", path, e); None }));
+    match (cached, crate::cache::imports(&path, &ast)) {
+      (Some(env), Some(imports)) => {
+        log!("loaded {:?} from cache", path);
+        from_cache = true;
+        deps = imports;
+        (Some(ast), (None, vec![], vec![], env))
+      }
+      _ => {
+        let rd = rd.push(path.clone());
+        (Some(ast.clone()), elab::elaborate(
+          &ast, path.clone(), path.has_extension("mm0"),
+          crate::get_check_proofs(), true, cancel.clone(),
+          true, old_env.map(|(errs, e)| (idx, errs, e)),
+          |p| {
+            let p = vfs.get_or_insert_blocking(p)?.0;
+            let (send, recv) = channel();
+            if rd.contains(&p) {
+              send.send(ElabResult::ImportCycle(rd.clone())).expect("failed to send");
+            } else {
+              Job::ElaborateDep(p.clone(), path.clone(), Some((send, rd.clone()))).spawn();
+              deps.push(p);
+            }
+            Ok(recv)
+          }).await)
+      }
+    }
   };
   for tok in toks {tok.hash(&mut hasher)}
+  // An environment loaded from the cache has no hover information, so the result is
+  // marked to make sure that the next request on this file elaborates it again.
+  if from_cache { from_cache.hash(&mut hasher) }
   let hash = hasher.finish();
   log!("elabbed {:?}", path);
   let is_canceled = cancel.load(Ordering::SeqCst);
@@ -289,6 +323,14 @@ async fn elaborate(path: FileRef, start: Option<Position>,
     }
   }
 
+  let ok = cyc.is_none() &&
+    !ast.iter().flat_map(|ast| &ast.errors).any(|e| matches!(e.level, ErrorLevel::Error)) &&
+    !errors.iter().any(|e| matches!(e.level, ErrorLevel::Error));
+  if let Some(key) = cache_key.filter(|_| ok && errors.is_empty() && !from_cache && !is_canceled) {
+    if let Err(e) = crate::cache::store(key, &env) {
+      log!("failed to save {:?} to cache: {}", path, e)
+    }
+  }
   let res = if is_canceled {
     ElabResult::Canceled
   } else if let Some(cyc) = &cyc {
@@ -305,7 +347,8 @@ async fn elaborate(path: FileRef, start: Option<Position>,
     }
   }
   if !is_canceled {
-    *g = Some(FileCache::Ready {hash, source, ast, res: res.clone(), deps});
+    let refs = Arc::new(RefIndex::new(&env));
+    *g = Some(FileCache::Ready {hash, source, ast, res: res.clone(), deps, refs});
     drop(g);
     for d in file.downstream.ulock().iter() {
       log!("{:?} affects {:?}", path, d);
@@ -340,6 +383,138 @@ fn dep_change(path: FileRef, cancel: Arc<AtomicBool>) -> BoxFuture<'static, ()>
   elaborate_and_report(path, None, cancel).boxed()
 }
 
+/// The `import` statements of the `.mm0` and `.mm1` files in the workspace folders, used to
+/// find the files that import a given file. The folders are scanned when the index is first
+/// needed, and after that the imports of a file are updated when it is saved.
+#[derive(Default)]
+struct ImportIndex {
+  /// The files imported by each file
+  imports: HashMap<FileRef, Vec<FileRef>>,
+  /// The files importing each file
+  importers: HashMap<FileRef, HashSet<FileRef>>,
+}
+
+impl ImportIndex {
+  /// Scan the workspace folders.
+  fn new() -> ImportIndex {
+    let mut files = vec![];
+    for root in &SERVER.roots {
+      if let Err(e) = crate::verify_all::find_files(root, &["mm0", "mm1"], &mut files) {
+        log!("failed to search {}: {}", root.display(), e)
+      }
+    }
+    let mut index = ImportIndex::default();
+    for f in files {
+      let f = match fs::canonicalize(&f) {Ok(f) => f, Err(_) => continue};
+      if let Ok(text) = fs::read_to_string(&f) { index.update(f.into(), Arc::new(text.into())) }
+    }
+    index
+  }
+
+  /// Record the imports of `path`, whose contents are now `text`.
+  fn update(&mut self, path: FileRef, text: Arc<LinedString>) {
+    let (_, ast) = parse(text, None);
+    let deps = ast.imports.iter().filter_map(|(_, dep)| {
+      let dep = std::str::from_utf8(dep).ok()?;
+      crate::project::resolve_import(path.path(), dep).ok().map(FileRef::from)
+    }).collect::<Vec<_>>();
+    for d in self.imports.remove(&path).into_iter().flatten() {
+      if let Some(set) = self.importers.get_mut(&d) { set.remove(&path); }
+    }
+    for d in &deps { self.importers.entry(d.clone()).or_default().insert(path.clone()); }
+    self.imports.insert(path, deps);
+  }
+
+  /// The files that import `path`, directly or indirectly.
+  fn importers(&self, path: &FileRef) -> Vec<FileRef> {
+    let mut visited = HashSet::new();
+    let mut stack = vec![path.clone()];
+    let mut res = vec![];
+    while let Some(p) = stack.pop() {
+      for f in self.importers.get(&p).into_iter().flatten() {
+        if visited.insert(f.clone()) {
+          res.push(f.clone());
+          stack.push(f.clone())
+        }
+      }
+    }
+    res
+  }
+}
+
+/// The files in the workspace that import `path`, directly or indirectly, according to the
+/// [`ImportIndex`], which is built first if necessary.
+fn workspace_importers(path: &FileRef) -> Vec<FileRef> {
+  SERVER.imports.ulock().get_or_insert_with(ImportIndex::new).importers(path)
+}
+
+/// Elaborate the files in the workspace that import the saved file `path`, directly or
+/// indirectly, and that the server has not seen yet. The files that it has seen are already
+/// kept up to date through their `downstream` links, but a file that is not open and
+/// has never been elaborated would otherwise not be diagnosed until it is opened. Afterwards
+/// these files are removed from the VFS again (keeping their diagnostics), so that it
+/// only holds the open files and their imports.
+async fn elaborate_importers(path: FileRef) {
+  let importers = {
+    let mut index = SERVER.imports.ulock();
+    let index = index.get_or_insert_with(ImportIndex::new);
+    let text = SERVER.vfs.get(&path).map_or_else(
+      || fs::read_to_string(path.path()).ok().map(|s| Arc::new(s.into())),
+      |file| file.text.ulock().1.try_ascii().cloned());
+    if let Some(text) = text { index.update(path.clone(), text) }
+    index.importers(&path)
+  };
+  for f in importers {
+    if SERVER.vfs.get(&f).is_none() {
+      log!("elaborate {:?}, which imports {:?}", f, path);
+      elaborate_and_report(f.clone(), None, Default::default()).await;
+      SERVER.vfs.release(&f)
+    }
+  }
+}
+
+/// The kind of a global object that can be referred to by name, for [`RefIndex`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+enum RefKind { Sort, Term, Thm, Global }
+crate::deep_size_0!(RefKind);
+
+/// A reverse index from the global objects used in a file to the spans where they are
+/// used, including their declarations. Objects are identified by kind and name rather than
+/// by ID, because each file has its own IDs, and renaming needs to find the uses in all
+/// files that import the declaration.
+#[derive(Default, DeepSizeOf)]
+struct RefIndex(HashMap<(RefKind, ArcString), Vec<Span>>);
+
+impl RefIndex {
+  /// Build the index from the spans that were recorded while elaborating `env`.
+  fn new(env: &FrozenEnv) -> RefIndex {
+    let mut map = HashMap::<_, Vec<Span>>::new();
+    for spans in env.spans() {
+      for &(sp, ref k) in spans {
+        let (kind, a) = match *k {
+          ObjectKind::Sort(s) => (RefKind::Sort, env.sort(s).atom),
+          ObjectKind::Term(t, _) => (RefKind::Term, env.term(t).atom),
+          ObjectKind::Thm(t) => (RefKind::Thm, env.thm(t).atom),
+          ObjectKind::Global(a) => (RefKind::Global, a),
+          ObjectKind::Proof(ref p) => match p.uncons().next().and_then(|h| h.as_atom()) {
+            Some(a) if matches!(env.data()[a].decl(), Some(DeclKey::Thm(_))) => (RefKind::Thm, a),
+            _ => continue
+          },
+          // The head of an expression has its own `Term` span
+          ObjectKind::Expr(_) | ObjectKind::Var(_) |
+          ObjectKind::Syntax(_) | ObjectKind::Import(_) => continue,
+        };
+        map.entry((kind, env.data()[a].name().clone())).or_default().push(sp)
+      }
+    }
+    for sps in map.values_mut() {
+      sps.sort_by_key(|sp| (sp.start, sp.end));
+      sps.dedup()
+    }
+    RefIndex(map)
+  }
+}
+
 #[derive(DeepSizeOf)]
 enum FileCache {
   InProgress {
@@ -354,6 +529,7 @@ enum FileCache {
     ast: Option<Arc<AST>>,
     res: ElabResult<u64>,
     deps: Vec<FileRef>,
+    refs: Arc<RefIndex>,
   }
 }
 
@@ -385,23 +561,58 @@ impl VFS {
     self.0.ulock().get(path).cloned()
   }
 
-  fn get_or_insert(&self, path: FileRef) -> io::Result<(FileRef, Arc<VirtualFile>)> {
+  /// Read the contents of a file from disk.
+  fn read_file(path: &FileRef) -> io::Result<FileContents> {
+    if !path.is_on_disk() {
+      return Err(io::Error::new(io::ErrorKind::NotFound,
+        format!("{} is not open in the editor", path.url())))
+    }
+    Ok(if path.has_extension("mmb") {
+      let file = fs::File::open(path.path())?;
+      FileContents::new_bin(unsafe { memmap::MmapOptions::new().map(&file)? })
+    } else {
+      FileContents::new(fs::read_to_string(path.path())?)
+    })
+  }
+
+  /// Add a file that was read from disk, unless someone else loaded it in the meantime,
+  /// in which case their copy is returned.
+  fn insert_read(&self, path: FileRef, fc: FileContents) -> (FileRef, Arc<VirtualFile>) {
     match self.0.ulock().entry(path) {
-      Entry::Occupied(e) => Ok((e.key().clone(), e.get().clone())),
+      Entry::Occupied(e) => (e.key().clone(), e.get().clone()),
       Entry::Vacant(e) => {
         let path = e.key().clone();
-        let fc = if path.has_extension("mmb") {
-          let file = fs::File::open(path.path())?;
-          FileContents::new_bin(unsafe { memmap::MmapOptions::new().map(&file)? })
-        } else {
-          FileContents::new(fs::read_to_string(path.path())?)
-        };
-        let val = e.insert(Arc::new(VirtualFile::new(None, fc))).clone();
-        Ok((path, val))
+        (path, e.insert(Arc::new(VirtualFile::new(None, fc))).clone())
       }
     }
   }
 
+  fn get_key_value(&self, path: &FileRef) -> Option<(FileRef, Arc<VirtualFile>)> {
+    self.0.ulock().get_key_value(path).map(|(k, v)| (k.clone(), v.clone()))
+  }
+
+  /// Get the file `path`, reading it from disk if it is not already loaded. The read is
+  /// done in a separate task on the server's thread pool, and the VFS is not locked
+  /// during the read.
+  async fn get_or_insert(&self, path: FileRef) -> io::Result<(FileRef, Arc<VirtualFile>)> {
+    if let Some(res) = self.get_key_value(&path) { return Ok(res) }
+    let (send, recv) = channel();
+    let path2 = path.clone();
+    SERVER.pool.spawn_ok(async move { let _ = send.send(Self::read_file(&path2)); });
+    let fc = recv.await.unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other,
+      "file reading task panicked")))?;
+    Ok(self.insert_read(path, fc))
+  }
+
+  /// Get the file `path`, reading it from disk on the current thread if it is not already
+  /// loaded. This is used when the caller cannot wait asynchronously. The VFS is not locked
+  /// during the read.
+  fn get_or_insert_blocking(&self, path: FileRef) -> io::Result<(FileRef, Arc<VirtualFile>)> {
+    if let Some(res) = self.get_key_value(&path) { return Ok(res) }
+    let fc = Self::read_file(&path)?;
+    Ok(self.insert_read(path, fc))
+  }
+
   fn source(&self, file: &FileRef) -> Arc<LinedString> {
     self.0.ulock().get(file).unwrap().text.ulock().1.ascii().clone()
   }
@@ -438,17 +649,35 @@ impl VFS {
     Ok(())
   }
 
+  /// Remove `path` from the VFS if it is not open and no other file depends on it, and then
+  /// do the same for the files it imports.
+  fn release(&self, path: &FileRef) {
+    let mut stack = vec![path.clone()];
+    while let Some(p) = stack.pop() {
+      let mut g = self.0.ulock();
+      let file = if let Some(file) = g.get(&p) { file.clone() } else { continue };
+      if file.text.ulock().0.is_some() || !file.downstream.ulock().is_empty() { continue }
+      let deps = match file.parsed.try_lock().as_deref() {
+        Some(Some(FileCache::Ready {deps, ..})) => deps.clone(),
+        _ => continue
+      };
+      g.remove(&p);
+      drop(g);
+      self.update_downstream(&deps, &[], &p);
+      stack.extend(deps)
+    }
+  }
+
   fn update_downstream(&self, old_deps: &[FileRef], deps: &[FileRef], to: &FileRef) {
     for from in old_deps {
       if !deps.contains(from) {
-        let file = self.0.ulock().get(from).unwrap().clone();
-        file.downstream.ulock().remove(to);
+        // The file may have been removed by `release` in the meantime
+        if let Some(file) = self.get(from) { file.downstream.ulock().remove(to); }
       }
     }
     for from in deps {
       if !old_deps.contains(from) {
-        let file = self.0.ulock().get(from).unwrap().clone();
-        file.downstream.ulock().insert(to.clone());
+        if let Some(file) = self.get(from) { file.downstream.ulock().insert(to.clone()); }
       }
     }
   }
@@ -463,6 +692,13 @@ enum RequestType {
   DocumentSymbol(DocumentSymbolParams),
   References(ReferenceParams),
   DocumentHighlight(DocumentHighlightParams),
+  Rename(RenameParams),
+  GoalState(TextDocumentPositionParams),
+  CodeAction(CodeActionParams),
+  Formatting(DocumentFormattingParams),
+  FoldingRange(FoldingRangeParams),
+  InlayHint(InlayHintParams),
+  SignatureHelp(SignatureHelpParams),
 }
 
 fn parse_request(Request {id, method, params}: Request) -> Result<Option<(RequestId, RequestType)>> {
@@ -474,6 +710,13 @@ fn parse_request(Request {id, method, params}: Request) -> Result<Option<(Reques
     "textDocument/documentSymbol"    => Some((id, RequestType::DocumentSymbol(from_value(params)?))),
     "textDocument/references"        => Some((id, RequestType::References(from_value(params)?))),
     "textDocument/documentHighlight" => Some((id, RequestType::DocumentHighlight(from_value(params)?))),
+    "textDocument/rename"            => Some((id, RequestType::Rename(from_value(params)?))),
+    "mm0/goalState"                  => Some((id, RequestType::GoalState(from_value(params)?))),
+    "textDocument/codeAction"        => Some((id, RequestType::CodeAction(from_value(params)?))),
+    "textDocument/formatting"        => Some((id, RequestType::Formatting(from_value(params)?))),
+    "textDocument/foldingRange"      => Some((id, RequestType::FoldingRange(from_value(params)?))),
+    "textDocument/inlayHint"         => Some((id, RequestType::InlayHint(from_value(params)?))),
+    "textDocument/signatureHelp"     => Some((id, RequestType::SignatureHelp(from_value(params)?))),
     _ => None
   })
 }
@@ -514,6 +757,123 @@ fn send_diagnostics(uri: Url, version: Option<i32>, diagnostics: Vec<Diagnostic>
   })
 }
 
+/// A frame of the lisp call stack, in a `mm0/debug/paused` notification.
+#[derive(Serialize)]
+struct DebugFrame {
+  label: String,
+  location: Option<Location>,
+}
+
+/// The parameters of the `mm0/debug/paused` notification, which is sent to the client
+/// when a lisp evaluation pauses in the debugger.
+#[derive(Serialize)]
+struct DebugPausedParams {
+  location: Option<Location>,
+  call: String,
+  stack: Vec<DebugFrame>,
+  locals: Vec<String>,
+}
+
+/// The parameters of the `mm0/debug/setBreakpoints` notification.
+#[derive(Deserialize)]
+struct DebugBreakpointsParams {
+  procedures: Vec<String>,
+}
+
+fn file_location(fsp: &FileSpan) -> Option<Location> {
+  let text = SERVER.vfs.get(&fsp.file)?.text.ulock().1.ascii().clone();
+  Some(text.to_loc(fsp))
+}
+
+fn send_debug_paused(p: Paused) -> Result<()> {
+  let stack = p.stack.into_iter().map(|f|
+    DebugFrame {location: file_location(&f.span), label: f.label}).collect();
+  send_message(Notification {
+    method: "mm0/debug/paused".to_owned(),
+    params: to_value(DebugPausedParams {
+      location: file_location(&p.span), call: p.call, stack, locals: p.locals})?
+  })
+}
+
+/// The parameters of the `mm0/output` notification, which carries the output of
+/// `display`, `print` and `stat`, so that the client can show it separately from
+/// the diagnostics.
+#[derive(Serialize)]
+struct OutputParams {
+  location: Option<Location>,
+  text: String,
+}
+
+/// The response to the `mm0/goalState` request: the proof state before the tactic step
+/// at the requested position.
+#[derive(Serialize)]
+struct GoalState {
+  /// The hypotheses and subproofs in scope, as `name: statement`.
+  hyps: Vec<String>,
+  /// The statements of the goals, the first of which is the main goal.
+  goals: Vec<String>,
+}
+
+/// The parameters of the `textDocument/inlayHint` request. (Inlay hints are newer than
+/// our version of `lsp_types`, so we declare them here.)
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InlayHintParams {
+  text_document: TextDocumentIdentifier,
+  range: Range,
+}
+
+/// The kind of an [`InlayHint`].
+#[derive(Serialize_repr)]
+#[repr(u8)]
+enum InlayHintKind { Type = 1 }
+
+/// An inlay hint: a label that the editor shows at a position without it being part
+/// of the text.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct InlayHint {
+  position: Position,
+  label: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  kind: Option<InlayHintKind>,
+  padding_right: bool,
+}
+
+fn send_output(out: Output) -> Result<()> {
+  send_message(Notification {
+    method: "mm0/output".to_owned(),
+    params: to_value(OutputParams {location: file_location(&out.span), text: out.text})?
+  })
+}
+
+/// Handle a `mm0/debug/*` notification from the client. The debugger is installed by
+/// the first `setBreakpoints` or `pause` notification, and applies to the elaborations
+/// that start after that, until it is removed by `end`.
+fn debug_notification(method: &str, params: serde_json::Value) -> Result<()> {
+  fn get_or_install() -> Arc<debugger::Debugger> {
+    debugger::current().unwrap_or_else(|| debugger::install(|p|
+      if let Err(e) = send_debug_paused(p) { log!("failed to send debug pause: {:?}", e) }))
+  }
+  let cmd = match method {
+    "mm0/debug/setBreakpoints" => {
+      let DebugBreakpointsParams {procedures} = from_value(params)?;
+      get_or_install().set_breakpoints(procedures);
+      return Ok(())
+    }
+    "mm0/debug/pause" => { get_or_install().pause_next(); return Ok(()) }
+    "mm0/debug/end" => { debugger::uninstall(); return Ok(()) }
+    "mm0/debug/continue" => DebugCommand::Continue,
+    "mm0/debug/step" => DebugCommand::Step,
+    "mm0/debug/next" => DebugCommand::Next,
+    "mm0/debug/stepOut" => DebugCommand::StepOut,
+    "mm0/debug/stop" => DebugCommand::Stop,
+    _ => return Ok(())
+  };
+  if let Some(dbg) = debugger::current() { dbg.command(cmd) }
+  Ok(())
+}
+
 type OpenRequests = Mutex<HashMap<RequestId, Arc<AtomicBool>>>;
 
 struct RequestHandler {
@@ -553,14 +913,25 @@ impl RequestHandler {
         self.finish(completion_resolve(*ci).await),
       RequestType::References(ReferenceParams {text_document_position: doc, context, ..}) => {
         let file: FileRef = doc.text_document.uri.into();
-        self.finish(references(file.clone(), doc.position, context.include_declaration,
-          |range| Location { uri: file.url().clone(), range }).await)
-      }
-      RequestType::DocumentHighlight(DocumentHighlightParams {text_document_position_params: doc, ..}) => {
-        let file: FileRef = doc.text_document.uri.into();
-        self.finish(references(file.clone(), doc.position, true,
-          |range| DocumentHighlight { range, kind: None }).await)
+        self.finish(references(file, doc.position, context.include_declaration, Scope::Loaded,
+          |file, range| Location { uri: file.url().clone(), range }).await)
       }
+      RequestType::DocumentHighlight(DocumentHighlightParams {text_document_position_params: doc, ..}) =>
+        self.finish(document_highlight(doc.text_document.uri.into(), doc.position).await),
+      RequestType::Rename(RenameParams {text_document_position: doc, new_name, ..}) =>
+        self.finish(rename(doc.text_document.uri.into(), doc.position, new_name).await),
+      RequestType::GoalState(TextDocumentPositionParams {text_document: doc, position}) =>
+        self.finish(goal_state(doc.uri.into(), position).await),
+      RequestType::CodeAction(CodeActionParams {text_document: doc, range, ..}) =>
+        self.finish(code_action(doc.uri.into(), range).await),
+      RequestType::Formatting(DocumentFormattingParams {text_document: doc, ..}) =>
+        self.finish(formatting(doc.uri.into()).await),
+      RequestType::FoldingRange(FoldingRangeParams {text_document: doc, ..}) =>
+        self.finish(folding_range(doc.uri.into()).await),
+      RequestType::InlayHint(InlayHintParams {text_document: doc, range}) =>
+        self.finish(inlay_hint(doc.uri.into(), range).await),
+      RequestType::SignatureHelp(SignatureHelpParams {text_document_position_params: doc, ..}) =>
+        self.finish(signature_help(doc.text_document.uri.into(), doc.position).await),
     }
   }
 
@@ -671,10 +1042,22 @@ async fn hover(path: FileRef, pos: Position) -> StdResult<Option<Hover>, Respons
       }
       &ObjectKind::Term(t, sp1) => {
         let td = &env.terms[t];
+        // A notation token inside a formula points at the whole notation,
+        // so we also say which notation it is and its precedence
+        if sp != sp1 {
+          if let Some((_, prec)) = env.pe.consts.get(&text[sp]) {
+            out.push((sp, mk_doc(&format!("notation `{}` for `{}`, precedence {}",
+              String::from_utf8_lossy(&text[sp]), fe.to(&td.atom), prec))));
+          }
+        }
         ((sp1, mk_mm0(format!("{}", fe.to(td)))), td.doc.clone())
       }
       &ObjectKind::Thm(t) => {
         let td = &env.thms[t];
+        // Theorems with admitted goals are flagged, including at their uses
+        if env.admitted.contains(&t) {
+          out.push((sp, mk_doc("**admitted**: this theorem has no proof, because it uses `admit`")));
+        }
         ((sp, mk_mm0(format!("{}", fe.to(td)))), td.doc.clone())
       }
       &ObjectKind::Var(x) => ((sp, mk_mm0(match spans.lc.as_ref().and_then(|lc| lc.vars.get(&x)) {
@@ -743,15 +1126,20 @@ async fn hover(path: FileRef, pos: Position) -> StdResult<Option<Hover>, Respons
       }
       &ObjectKind::Global(a) => {
         let ld = env.data[a].lisp.as_ref()?;
-        if let Some(doc) = &ld.doc {
-          ((sp, mk_doc(doc)), None)
-        } else {
-          let bp = ld.unwrapped(|e| match *e {
-            LispKind::Proc(Proc::Builtin(p)) => Some(p),
-            _ => None
-          })?;
-          ((sp, mk_doc(bp.doc())), None)
+        let mut info = format!("`{}`: {}", fe.to(&a), ld.type_name());
+        if let Some((fsp, _)) = &ld.src {
+          if let Some(file) = SERVER.vfs.get(&fsp.file) {
+            let Position {line, character} = file.text.ulock().1.ascii().to_pos(fsp.span.start);
+            use std::fmt::Write;
+            write!(info, ", defined at {}:{}:{}", fsp.file.rel(), line + 1, character + 1)
+              .expect("impossible");
+          }
         }
+        let doc = ld.doc.clone().or_else(|| ld.unwrapped(|e| match *e {
+          LispKind::Proc(Proc::Builtin(p)) => Some(p.doc().into()),
+          _ => None
+        }));
+        ((sp, mk_doc(&info)), doc)
       }
       ObjectKind::Import(_) => return None,
     }))() {
@@ -769,6 +1157,275 @@ async fn hover(path: FileRef, pos: Position) -> StdResult<Option<Hover>, Respons
   }))
 }
 
+async fn goal_state(path: FileRef, pos: Position) -> StdResult<Option<GoalState>, ResponseError> {
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "goal state nonexistent file"))?;
+  let text = file.text.ulock().1.ascii().clone();
+  let idx = match text.to_idx(pos) {Some(idx) => idx, None => return Ok(None)};
+  let env = elaborate(path, Some(Position::default()), Default::default(), Default::default())
+    .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
+  let env = match env.into_response_error()? {Some((_, env)) => env, None => return Ok(None)};
+  let env = unsafe { env.thaw() };
+  let fe = FormatEnv { source: &text, env };
+  let spans = match Spans::find(&env.spans, idx) {Some(spans) => spans, None => return Ok(None)};
+  let (n, goals) = match spans.goals_at(idx) {Some(g) => g, None => return Ok(None)};
+  let hyps = spans.lc.iter().flat_map(|lc| lc.proof_order.iter().take(*n))
+    .map(|(a, e, _)| format!("{}: {}", fe.to(a), fe.pp(e, 80))).collect();
+  let goals = goals.iter().map(|e| fe.pp(e, 80).to_string()).collect();
+  Ok(Some(GoalState {hyps, goals}))
+}
+
+/// Render `e` as a lisp s-expression to be inserted at column `col`, indenting the
+/// continuation lines so that the result lines up with the surrounding text.
+fn pp_lisp_at(fe: FormatEnv<'_>, e: &LispVal, col: usize) -> String {
+  let mut out = String::new();
+  fe.pretty(|p| p.pp_lisp(e).render_fmt(80_usize.saturating_sub(col).max(40), &mut out))
+    .expect("writing to a String");
+  out.replace('\n', &format!("\n{:1$}", "", col))
+}
+
+/// The innermost `(focus ...)` or `(begin ...)` tactic block in `e` containing `idx`.
+fn tactic_block<'a>(src: &LinedString, e: &'a SExpr, idx: usize) -> Option<&'a [SExpr]> {
+  if !(e.span.start < idx && idx < e.span.end) { return None }
+  match &e.k {
+    SExprKind::List(es) => {
+      if let Some(r) = es.iter().find_map(|e| tactic_block(src, e, idx)) { return Some(r) }
+      match es.first() {
+        Some(SExpr {span, k: SExprKind::Atom(Atom::Ident)})
+          if matches!(&src[*span], b"focus" | b"begin") => Some(es),
+        _ => None
+      }
+    }
+    SExprKind::DottedList(es, r) =>
+      es.iter().chain(Some(&**r)).find_map(|e| tactic_block(src, e, idx)),
+    _ => None
+  }
+}
+
+async fn code_action(path: FileRef, range: Range) -> StdResult<Vec<CodeActionOrCommand>, ResponseError> {
+  macro_rules! or_none {($e:expr)  => {match $e {
+    Some(x) => x,
+    None => return Ok(vec![])
+  }}}
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "code action nonexistent file"))?;
+  let res = elaborate(path.clone(), Some(Position::default()), Default::default(), Default::default())
+    .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
+  let (errors, env) = match res {
+    ElabResult::Ok(_, errors, env) => (errors, env),
+    ElabResult::Canceled => return Err(response_err(ErrorCode::RequestCanceled, "")),
+    ElabResult::ImportCycle(_) => return Ok(vec![]),
+  };
+  let ast = match &*file.parsed.lock().await {
+    Some(FileCache::Ready {ast: Some(ast), ..}) => ast.clone(),
+    _ => return Ok(vec![])
+  };
+  // The edits below are computed from the elaborated text, so bail if the user has
+  // typed something since.
+  if !Arc::ptr_eq(&ast.source, file.text.ulock().1.ascii()) { return Ok(vec![]) }
+  let src = &*ast.source;
+  let idx = or_none!(src.to_idx(range.start));
+  let mut stmt = or_none!(ast.stmts.iter().find(|s| s.span.start <= idx && idx <= s.span.end));
+  let span = stmt.span;
+  let d = loop {
+    match &stmt.k {
+      StmtKind::DocComment(_, s) | StmtKind::Annot(_, s) => stmt = &**s,
+      StmtKind::Decl(d) if matches!(d.k, DeclKind::Thm) => break d,
+      _ => return Ok(vec![])
+    }
+  };
+  let val = or_none!(d.val.as_ref());
+  let failing = errors.iter().flat_map(|es| &**es).any(|e|
+    matches!(e.level, ErrorLevel::Error) && span.start <= e.pos.start && e.pos.start <= span.end);
+  let edit = |sp: Span, new_text: String| Some(WorkspaceEdit {
+    changes: Some(std::iter::once((path.url().clone(),
+      vec![TextEdit {range: src.to_range(sp), new_text}])).collect()),
+    ..Default::default()
+  });
+  if failing {
+    // Print the proof state at the cursor, or at the end of the proof if the
+    // cursor is not in a tactic block.
+    let (sp, new_text) = match tactic_block(src, val, idx) {
+      Some(es) => match es.iter().skip(1).find(|e| idx <= e.span.start) {
+        Some(e) => (e.span.start..e.span.start, "(stat) ".into()),
+        None => {
+          let end = es.last().map_or(idx, |e| e.span.end);
+          (end..end, " (stat)".into())
+        }
+      },
+      None => (val.span.start..val.span.end,
+        format!("(focus {} (stat))", String::from_utf8_lossy(&src[val.span]))),
+    };
+    return Ok(vec![CodeActionOrCommand::CodeAction(CodeAction {
+      title: "Show the proof state here with (stat)".into(),
+      kind: Some(CodeActionKind::QUICKFIX),
+      edit: edit(sp.into(), new_text),
+      ..Default::default()
+    })])
+  }
+  // A quoted proof term is already explicit.
+  if matches!(&val.k, SExprKind::List(es) if matches!(es.first(),
+      Some(SExpr {k: SExprKind::Atom(Atom::Quote), ..}))) { return Ok(vec![]) }
+  let env = unsafe { env.thaw() };
+  let a = or_none!(env.atoms.get(&src[d.id]).copied());
+  let t = match env.data[a].decl {Some(DeclKey::Thm(t)) => t, _ => return Ok(vec![])};
+  let td = &env.thms[t];
+  if !matches!(td.kind, ThmKind::Thm(Some(_))) { return Ok(vec![]) }
+  let heap = td.args.iter().map(|&(a, _)| LispVal::atom(a.unwrap_or(AtomID::UNDER))).collect();
+  let pf = env.get_proof(t, heap);
+  let pf = or_none!(pf.unwrapped(|e| match e {
+    LispKind::List(es) if es.len() == 2 => Some(es[1].clone()),
+    _ => None
+  }));
+  let col = src.to_pos(val.span.start).character as usize;
+  let fe = FormatEnv { source: src, env };
+  Ok(vec![CodeActionOrCommand::CodeAction(CodeAction {
+    title: "Replace the tactic script with the proof term".into(),
+    kind: Some(CodeActionKind::REFACTOR_REWRITE),
+    edit: edit(val.span, format!("'{}", pp_lisp_at(fe, &pf, col + 1))),
+    ..Default::default()
+  })])
+}
+
+/// Format the whole file, using the notations from the last elaboration to break long
+/// math strings. Files with parse errors are left alone.
+async fn formatting(path: FileRef) -> StdResult<Option<Vec<TextEdit>>, ResponseError> {
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "formatting nonexistent file"))?;
+  let ast = current_ast(&file);
+  let env = try_old(&file).map(|(_, env)| env);
+  let pe = env.as_ref().map(|env| &unsafe { env.thaw() }.pe);
+  let out = match crate::format::format(&ast, pe, 80) {
+    Some(out) => out,
+    None => return Ok(None)
+  };
+  if out == **ast.source { return Ok(Some(vec![])) }
+  let range = ast.source.to_range(Span::from(0..ast.source.len()));
+  Ok(Some(vec![TextEdit {range, new_text: out}]))
+}
+
+/// Push a folding range for `sp` to `out`, if it spans more than one line.
+fn push_fold(src: &LinedString, sp: Span, kind: Option<FoldingRangeKind>, out: &mut Vec<FoldingRange>) {
+  let Range {start, end} = src.to_range(sp);
+  if start.line < end.line {
+    out.push(FoldingRange {
+      start_line: start.line, start_character: None,
+      end_line: end.line, end_character: None, kind
+    })
+  }
+}
+
+/// Add folding ranges for the multi-line lists in `e` to `out`.
+fn sexpr_folds(src: &LinedString, e: &SExpr, out: &mut Vec<FoldingRange>) {
+  match &e.k {
+    SExprKind::List(es) => {
+      push_fold(src, e.span, None, out);
+      for e in es { sexpr_folds(src, e, out) }
+    }
+    SExprKind::DottedList(es, r) => {
+      push_fold(src, e.span, None, out);
+      for e in es { sexpr_folds(src, e, out) }
+      sexpr_folds(src, r, out)
+    }
+    SExprKind::DocComment(_, e) => sexpr_folds(src, e, out),
+    SExprKind::Formula(f) => push_fold(src, f.0, None, out),
+    SExprKind::Atom(_) | SExprKind::Number(_) | SExprKind::String(_) |
+    SExprKind::Bool(_) | SExprKind::Undef => {}
+  }
+}
+
+/// Get the folding ranges of a file: every statement, theorem proof and lisp list that spans
+/// several lines, runs of consecutive imports, and runs of consecutive comment lines.
+async fn folding_range(path: FileRef) -> StdResult<Vec<FoldingRange>, ResponseError> {
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "folding range nonexistent file"))?;
+  let ast = current_ast(&file);
+  let src = &*ast.source;
+  let mut out = vec![];
+  let mut imports: Option<Span> = None;
+  for stmt in &ast.stmts {
+    if let StmtKind::Import(..) = stmt.k {
+      imports = Some(imports.map_or(stmt.span, |sp| (sp.start..stmt.span.end).into()));
+      continue
+    }
+    if let Some(sp) = imports.take() { push_fold(src, sp, Some(FoldingRangeKind::Imports), &mut out) }
+    push_fold(src, stmt.span, Some(FoldingRangeKind::Region), &mut out);
+    let mut s = stmt;
+    while let StmtKind::Annot(_, s2) | StmtKind::DocComment(_, s2) = &s.k { s = s2 }
+    match &s.k {
+      StmtKind::Decl(d) => if let Some(e) = &d.val { sexpr_folds(src, e, &mut out) },
+      StmtKind::Do(es) => for e in es { sexpr_folds(src, e, &mut out) },
+      _ => {}
+    }
+  }
+  if let Some(sp) = imports { push_fold(src, sp, Some(FoldingRangeKind::Imports), &mut out) }
+  let mut comment: Option<(u32, u32)> = None;
+  for (line, s) in (0..).zip(src.lines().chain(std::iter::once(""))) {
+    if s.trim_start().starts_with("--") {
+      comment = Some(comment.map_or((line, line), |(start, _)| (start, line)));
+    } else if let Some((start_line, end_line)) = comment.take() {
+      if start_line < end_line {
+        out.push(FoldingRange {
+          start_line, start_character: None,
+          end_line, end_character: None, kind: Some(FoldingRangeKind::Comment)
+        })
+      }
+    }
+  }
+  Ok(out)
+}
+
+/// Get the inlay hints in `range`: the sorts inferred for variables declared without one,
+/// after the variable, and the coercions inserted by the elaborator, before the expression
+/// they apply to.
+async fn inlay_hint(path: FileRef, range: Range) -> StdResult<Vec<InlayHint>, ResponseError> {
+  fn coe_names(fe: FormatEnv<'_>, c: &Coe, out: &mut Vec<String>) {
+    match c {
+      &Coe::One(_, t) => out.push(fe.to(&fe.terms[t].atom).to_string()),
+      Coe::Trans(c1, _, c2) => { coe_names(fe, c2, out); coe_names(fe, c1, out) }
+    }
+  }
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "inlay hint nonexistent file"))?;
+  let text = file.text.ulock().1.ascii().clone();
+  let (start, end) = match (text.to_idx(range.start), text.to_idx(range.end)) {
+    (Some(start), Some(end)) => (start, end),
+    _ => return Ok(vec![])
+  };
+  let env = elaborate(path, Some(Position::default()), Default::default(), Default::default())
+    .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
+  let env = match env.into_response_error()? {Some((_, env)) => env, None => return Ok(vec![])};
+  let env = unsafe { env.thaw() };
+  let fe = FormatEnv { source: &text, env };
+  let mut out = vec![];
+  for spans in &env.spans {
+    let stmt = spans.stmt();
+    if stmt.end < start || end < stmt.start { continue }
+    for (sp, hint) in spans.hints() {
+      if sp.end < start || end < sp.start { continue }
+      out.push(match hint {
+        &Hint::Sort(s) => InlayHint {
+          position: text.to_pos(sp.end),
+          label: format!(": {}", fe.to(&s)),
+          kind: Some(InlayHintKind::Type),
+          padding_right: false,
+        },
+        Hint::Coe(c) => {
+          let mut names = vec![];
+          coe_names(fe, c, &mut names);
+          InlayHint {
+            position: text.to_pos(sp.start),
+            label: names.join(" "),
+            kind: None,
+            padding_right: true,
+          }
+        }
+      })
+    }
+  }
+  Ok(out)
+}
+
 async fn definition<T>(path: FileRef, pos: Position,
     f: impl Fn(&LinedString, &LinedString, Span, &FileSpan, Span) -> T + Send) ->
     StdResult<Vec<T>, ResponseError> {
@@ -911,7 +1568,9 @@ async fn document_symbol(path: FileRef) -> StdResult<DocumentSymbolResponse, Res
                 r @ FrozenLispKind::List(_) |
                 r @ FrozenLispKind::DottedList(_, _) =>
                   if r.is_list() {SymbolKind::Array} else {SymbolKind::Object},
-                FrozenLispKind::Number(_) => SymbolKind::Number,
+                FrozenLispKind::Vector(_) => SymbolKind::Array,
+                FrozenLispKind::Number(_) |
+                FrozenLispKind::SmallInt(_) => SymbolKind::Number,
                 FrozenLispKind::String(_) => SymbolKind::String,
                 FrozenLispKind::Bool(_) => SymbolKind::Boolean,
                 FrozenLispKind::Syntax(_) => SymbolKind::Event,
@@ -939,10 +1598,16 @@ enum TraceKind {Sort, Decl, Global}
 
 fn make_completion_item(path: &FileRef, fe: FormatEnv<'_>, ad: &FrozenAtomData, detail: bool, tk: TraceKind) -> Option<CompletionItem> {
   use CompletionItemKind::{Class, Constructor, Method};
-  macro_rules! done {($desc:expr, $kind:expr) => {
+  macro_rules! done {($desc:expr, $doc:expr, $kind:expr) => {
     CompletionItem {
       label: String::from_utf8_lossy(ad.name()).into(),
       detail: if detail {Some($desc)} else {None},
+      documentation: if detail {
+        $doc.as_ref().map(|doc: &DocComment| Documentation::MarkupContent(MarkupContent {
+          kind: MarkupKind::Markdown,
+          value: trim_margin(doc),
+        }))
+      } else {None},
       kind: Some($kind),
       data: Some(to_value((path.url(), tk)).unwrap()),
       ..Default::default()
@@ -951,21 +1616,24 @@ fn make_completion_item(path: &FileRef, fe: FormatEnv<'_>, ad: &FrozenAtomData,
   match tk {
     TraceKind::Sort => ad.sort().map(|s| {
       let sd = &fe.sorts[s];
-      done!(format!("{}", sd), Class)
+      done!(format!("{}", sd), sd.doc, Class)
     }),
     TraceKind::Decl => ad.decl().map(|dk| match dk {
-      DeclKey::Term(t) => {let td = &fe.terms[t]; done!(format!("{}", fe.to(td)), Constructor)}
-      DeclKey::Thm(t) => {let td = &fe.thms[t]; done!(format!("{}", fe.to(td)), Method)}
+      DeclKey::Term(t) => {let td = &fe.terms[t]; done!(format!("{}", fe.to(td)), td.doc, Constructor)}
+      DeclKey::Thm(t) => {let td = &fe.thms[t]; done!(format!("{}", fe.to(td)), td.doc, Method)}
     }),
-    TraceKind::Global => ad.lisp().as_deref().map(|e| {
-      done!(format!("{}", fe.to(unsafe {e.thaw()})), match *e.unwrap() {
+    TraceKind::Global => ad.lisp().as_ref().map(|ld| {
+      let e = &**ld;
+      done!(format!("{}", fe.to(unsafe {e.thaw()})), ld.doc(), match *e.unwrap() {
         FrozenLispKind::Atom(_) |
         FrozenLispKind::MVar(_, _) |
         FrozenLispKind::Goal(_) => CompletionItemKind::Constant,
         FrozenLispKind::List(_) |
         FrozenLispKind::DottedList(_, _) |
+        FrozenLispKind::Vector(_) |
         FrozenLispKind::Undef |
         FrozenLispKind::Number(_) |
+        FrozenLispKind::SmallInt(_) |
         FrozenLispKind::String(_) |
         FrozenLispKind::Bool(_) |
         FrozenLispKind::AtomMap(_) |
@@ -978,9 +1646,278 @@ fn make_completion_item(path: &FileRef, fe: FormatEnv<'_>, ad: &FrozenAtomData,
   }
 }
 
-async fn completion(path: FileRef, _pos: Position) -> StdResult<CompletionResponse, ResponseError> {
+/// The head term of the first unsolved goal of the proof containing `idx`,
+/// as of the end of the last elaboration.
+fn goal_head(env: &FrozenEnv, idx: usize) -> Option<TermID> {
+  let env = unsafe { env.thaw() };
+  let g = Spans::find(&env.spans, idx)?.lc.as_ref()?.goals.first()?;
+  match env.data[g.goal_type()?.head()?.as_atom()?].decl {
+    Some(DeclKey::Term(t)) => Some(t),
+    _ => None
+  }
+}
+
+/// The syntactic context of a completion request, which determines what is offered.
+enum CompletionCtx {
+  /// Inside the file name of an `import`, with the text typed so far.
+  Import(String),
+  /// Inside a formula. `provable` is true at the start of a hypothesis or the statement
+  /// of an axiom or theorem, where the expression must have a provable sort,
+  /// and `vars` are the variables of the enclosing declaration.
+  Formula {provable: bool, vars: Vec<Vec<u8>>},
+  /// Inside a quoted lisp expression, usually a proof, where the atoms refer to
+  /// declarations and the variables and hypotheses `vars` of the enclosing declaration.
+  Quoted {vars: Vec<Vec<u8>>},
+  /// Inside lisp code, with the local variables `locals` in scope.
+  Lisp {locals: Vec<Vec<u8>>},
+  /// Anywhere else.
+  Other,
+}
+
+/// Parse the current text of `file`, reusing the unchanged statements of the last parse.
+/// This is used for requests that depend on the text the user is typing, which the
+/// last elaboration may not have seen yet.
+fn current_ast(file: &VirtualFile) -> AST {
+  let text = file.text.ulock().1.ascii().clone();
+  let old = file.parsed.try_lock().and_then(|g| match &*g {
+    Some(FileCache::Ready {source, ast: Some(ast), ..}) =>
+      Some((first_change(source.ascii(), &text), ast.clone())),
+    _ => None
+  });
+  parse(text, old).1
+}
+
+/// Add the variables bound by the lisp pattern `p` to `out`.
+fn pattern_vars(src: &LinedString, p: &SExpr, out: &mut Vec<Vec<u8>>) {
+  match &p.k {
+    &SExprKind::Atom(Atom::Ident) => {
+      let x = &src[p.span];
+      if !x.starts_with(b"_") && !x.starts_with(b"...") { out.push(x.into()) }
+    }
+    SExprKind::List(es) | SExprKind::DottedList(es, _) => {
+      let tail = if let SExprKind::DottedList(_, e) = &p.k {Some(&**e)} else {None};
+      let skip = match es.first().map(|e| &e.k) {
+        Some(&SExprKind::Atom(a)) => match src.span_atom(es[0].span, a) {
+          b"quote" => return,
+          b"and" | b"or" | b"not" | b"vector" | b"mvar" | b"goal" => 1,
+          b"?" => 2,
+          _ => 0
+        },
+        _ => 0
+      };
+      for e in es.iter().skip(skip).chain(tail) { pattern_vars(src, e, out) }
+    }
+    _ => {}
+  }
+}
+
+/// Add the name defined by `x` in `(def x e)`, `(def (x args) e)`, or a `let` binding
+/// `[x e]` or `[(x args) e]`, to `out`.
+fn bound_name(src: &LinedString, x: &SExpr, out: &mut Vec<Vec<u8>>) {
+  match &x.k {
+    SExprKind::List(xs) | SExprKind::DottedList(xs, _) =>
+      if let Some(x) = xs.first() { pattern_vars(src, x, out) },
+    _ => pattern_vars(src, x, out)
+  }
+}
+
+/// Add the arguments `args` of `(def (x args) e)` or a `let` binding `[(x args) e]` to `out`.
+fn bound_args(src: &LinedString, x: &SExpr, out: &mut Vec<Vec<u8>>) {
+  if let SExprKind::List(xs) | SExprKind::DottedList(xs, _) = &x.k {
+    for x in &xs[1..] { pattern_vars(src, x, out) }
+    if let SExprKind::DottedList(_, x) = &x.k { pattern_vars(src, x, out) }
+  }
+}
+
+/// If `e` is a lisp definition `(def x ...)`, add `x` to `out`.
+fn def_var(src: &LinedString, e: &SExpr, out: &mut Vec<Vec<u8>>) {
+  if let SExprKind::List(es) = &e.k {
+    if let [hd, x, ..] = &**es {
+      if matches!(hd.k, SExprKind::Atom(Atom::Ident)) && &src[hd.span] == b"def" {
+        bound_name(src, x, out)
+      }
+    }
+  }
+}
+
+/// If `idx` is in the body of the `match` branch `[pat body]`, add the variables of `pat`
+/// to `out`.
+fn match_vars(src: &LinedString, br: &SExpr, idx: usize, out: &mut Vec<Vec<u8>>) {
+  if let SExprKind::List(br) = &br.k {
+    if let Some(p) = br.first() {
+      if idx > p.span.end { pattern_vars(src, p, out) }
+    }
+  }
+}
+
+/// Find the context of position `idx` in the lisp expression `e`, adding the local variables
+/// bound at that position to `locals`, following the binding forms of the lisp parser.
+/// Returns `None` if `idx` is not in `e`, and otherwise one of `Formula`, `Quoted` or `Lisp`,
+/// with empty variable lists.
+fn sexpr_ctx(src: &LinedString, e: &SExpr, idx: usize, quoted: bool,
+    locals: &mut Vec<Vec<u8>>) -> Option<CompletionCtx> {
+  if idx < e.span.start || e.span.end < idx { return None }
+  let es = match &e.k {
+    SExprKind::Formula(_) =>
+      return Some(CompletionCtx::Formula {provable: false, vars: vec![]}),
+    SExprKind::DocComment(_, e) => return sexpr_ctx(src, e, idx, quoted, locals),
+    SExprKind::List(es) | SExprKind::DottedList(es, _) => es,
+    _ => return Some(if quoted {
+      CompletionCtx::Quoted {vars: vec![]}
+    } else {
+      CompletionCtx::Lisp {locals: vec![]}
+    })
+  };
+  let tail = if let SExprKind::DottedList(_, e) = &e.k {Some(&**e)} else {None};
+  let head: &[u8] = match es.first().map(|e| &e.k) {
+    Some(&SExprKind::Atom(a)) => src.span_atom(es[0].span, a),
+    _ => b""
+  };
+  let quoted2 = match head {
+    b"quote" => true,
+    b"unquote" => false,
+    _ => quoted
+  };
+  for (i, e2) in es.iter().chain(tail).enumerate() {
+    if idx < e2.span.start || e2.span.end < idx {
+      // A `def` binds its name for the rest of the enclosing list
+      if !quoted && idx > e2.span.end { def_var(src, e2, locals) }
+      continue
+    }
+    if !quoted {
+      match head {
+        b"fn" if i >= 2 => pattern_vars(src, &es[1], locals),
+        b"def" if i >= 2 => bound_args(src, &es[1], locals),
+        b"let" | b"letrec" if i >= 1 => if let Some(SExprKind::List(bs)) = es.get(1).map(|e| &e.k) {
+          for b in bs {
+            if let SExprKind::List(xs) = &b.k {
+              if let Some(x) = xs.first() {
+                // `let` bindings are sequential, and `letrec` bindings are mutually recursive
+                if i >= 2 || head == b"letrec" || idx > b.span.end { bound_name(src, x, locals) }
+                if b.span.start <= idx && idx <= b.span.end { bound_args(src, x, locals) }
+              }
+            }
+          }
+        },
+        b"match" if i >= 2 => match_vars(src, e2, idx, locals),
+        b"match-fn" | b"match-fn*" if i >= 1 => match_vars(src, e2, idx, locals),
+        _ => {}
+      }
+    }
+    return sexpr_ctx(src, e2, idx, quoted2, locals)
+  }
+  Some(if quoted { CompletionCtx::Quoted {vars: vec![]} } else { CompletionCtx::Lisp {locals: vec![]} })
+}
+
+/// Find the context of position `idx` in `ast`.
+fn completion_ctx(ast: &AST, idx: usize) -> CompletionCtx {
+  let src = &*ast.source;
+  let mut stmt = match ast.stmts.iter().find(|s| s.span.start <= idx && idx <= s.span.end) {
+    Some(s) => s,
+    None => return CompletionCtx::Other
+  };
+  let mut locals = vec![];
+  loop {
+    match &stmt.k {
+      StmtKind::DocComment(_, s) => stmt = &**s,
+      StmtKind::Annot(e, s) => {
+        if let Some(ctx) = sexpr_ctx(src, e, idx, false, &mut locals) {
+          break match ctx {
+            CompletionCtx::Lisp {..} => CompletionCtx::Lisp {locals},
+            ctx => ctx
+          }
+        }
+        stmt = &**s
+      }
+      StmtKind::Do(es) | StmtKind::Inout {hs: es, ..} => {
+        for e in es {
+          if let Some(ctx) = sexpr_ctx(src, e, idx, false, &mut locals) {
+            return match ctx {
+              CompletionCtx::Lisp {..} => CompletionCtx::Lisp {locals},
+              ctx => ctx
+            }
+          }
+        }
+        break CompletionCtx::Lisp {locals}
+      }
+      StmtKind::Import(sp, _) if sp.start < idx && idx < sp.end =>
+        break CompletionCtx::Import(
+          String::from_utf8_lossy(&src[Span::from(sp.start + 1..idx)]).into()),
+      StmtKind::Decl(d) => {
+        let vars = d.bis.iter().filter_map(|bi| bi.local).map(|sp| src[sp].to_vec()).collect();
+        let provable = matches!(d.k, DeclKind::Axiom | DeclKind::Thm);
+        let at_start = |sp: Span| src[Span::from(sp.start + 1..idx)].iter().all(u8::is_ascii_whitespace);
+        for ty in d.bis.iter().filter_map(|bi| bi.ty.as_ref()).chain(&d.ty) {
+          if let Type::Formula(f) = ty {
+            if f.0.start < idx && idx < f.0.end {
+              return CompletionCtx::Formula {provable: provable && at_start(f.0), vars}
+            }
+          }
+        }
+        break match d.val.as_ref().and_then(|e| sexpr_ctx(src, e, idx, false, &mut locals)) {
+          Some(CompletionCtx::Formula {..}) => CompletionCtx::Formula {provable: false, vars},
+          Some(CompletionCtx::Quoted {..}) => CompletionCtx::Quoted {vars},
+          Some(CompletionCtx::Lisp {..}) => CompletionCtx::Lisp {locals},
+          _ => CompletionCtx::Other
+        }
+      }
+      _ => break CompletionCtx::Other
+    }
+  }
+}
+
+/// The completions for the file name of an `import` in `path`, where `prefix` is the
+/// text typed so far: the directories and MM0 files in the directory named by `prefix`,
+/// relative to `path` or (unless `prefix` is explicitly relative) in the source roots and
+/// dependencies of the [project](crate::project) of `path`.
+fn import_completions(path: &FileRef, prefix: &str) -> Vec<CompletionItem> {
+  let dir = match prefix.rfind('/') {Some(i) => &prefix[..=i], None => ""};
+  let parent = path.path().parent().map_or_else(PathBuf::new, Path::to_owned);
+  let mut dirs = vec![parent.join(dir)];
+  let mut out = vec![];
+  if !crate::project::is_relative(prefix) {
+    if let Ok(Some(proj)) = crate::project::Project::find(&parent) {
+      if let Ok((roots, rest)) = proj.search_path(dir) {
+        dirs.extend(roots.iter().map(|r| r.join(rest)))
+      }
+      if dir.is_empty() {
+        out.extend(proj.deps.iter().map(|(name, _)| CompletionItem {
+          label: name.clone(),
+          kind: Some(CompletionItemKind::Module),
+          ..Default::default()
+        }))
+      }
+    }
+  }
+  for dir in dirs {
+    let entries = match fs::read_dir(dir) {Ok(es) => es, Err(_) => continue};
+    out.extend(entries.filter_map(|e| {
+      let e = e.ok()?;
+      let kind = if e.file_type().ok()?.is_dir() {
+        CompletionItemKind::Folder
+      } else if matches!(e.path().extension()?.to_str()?, "mm0" | "mm1" | "mmb" | "mmu") {
+        CompletionItemKind::File
+      } else {return None};
+      Some(CompletionItem {
+        label: e.file_name().to_str()?.into(),
+        kind: Some(kind),
+        ..Default::default()
+      })
+    }))
+  }
+  let mut seen = HashSet::new();
+  out.retain(|c| seen.insert(c.label.clone()));
+  out
+}
+
+async fn completion(path: FileRef, pos: Position) -> StdResult<CompletionResponse, ResponseError> {
   let file = SERVER.vfs.get(&path).ok_or_else(||
     response_err(ErrorCode::InvalidRequest, "document symbol nonexistent file"))?;
+  let ast = current_ast(&file);
+  let ctx = ast.source.to_idx(pos).map_or(CompletionCtx::Other, |idx| completion_ctx(&ast, idx));
+  if let CompletionCtx::Import(prefix) = &ctx {
+    return Ok(CompletionResponse::Array(import_completions(&path, prefix)))
+  }
   let (text, env) = if let Some(old) = try_old(&file) { old } else {
     let env = elaborate(path.clone(), Some(Position::default()), Default::default(), Default::default())
       .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
@@ -991,17 +1928,82 @@ async fn completion(path: FileRef, _pos: Position) -> StdResult<CompletionRespon
   };
   let text = text.ascii().clone();
   let fe = unsafe { env.format_env(&text) };
+  // Inside a proof with unsolved goals, the theorems that prove a statement with the same
+  // head as the first goal are listed first.
+  let goal = text.to_idx(pos).and_then(|idx| goal_head(&env, idx));
+  let applies = |ad: &FrozenAtomData| goal.map_or(false, |t| match ad.decl() {
+    Some(DeclKey::Thm(th)) => {
+      let td = &fe.thms[th];
+      matches!(*deref(td, &td.ret), ExprNode::App(t2, _) if t2 == t)
+    }
+    _ => false
+  });
+  // Formulas can only use term constructors, and at the start of a hypothesis or
+  // theorem statement only those whose sort is provable (possibly after a coercion).
+  let fits = |ad: &FrozenAtomData| match (ad.decl(), &ctx) {
+    (Some(DeclKey::Term(t)), CompletionCtx::Formula {provable: true, ..}) => {
+      let s = fe.terms[t].ret.0;
+      fe.sorts[s].mods.contains(Modifiers::PROVABLE) || fe.pe.coe_prov.contains_key(&s)
+    }
+    (Some(DeclKey::Thm(_)), CompletionCtx::Formula {..}) => false,
+    _ => true
+  };
+  let (sorts, decls, globals) = match ctx {
+    CompletionCtx::Formula {..} | CompletionCtx::Quoted {..} => (false, true, false),
+    CompletionCtx::Lisp {..} => (false, false, true),
+    CompletionCtx::Import(_) | CompletionCtx::Other => (true, true, true),
+  };
   let mut res = vec![];
   for ad in env.data().iter() {
-    if let Some(ci) = make_completion_item(&path, fe, ad, false, TraceKind::Sort) {res.push(ci)}
-    if let Some(ci) = make_completion_item(&path, fe, ad, false, TraceKind::Decl) {res.push(ci)}
-    if let Some(ci) = make_completion_item(&path, fe, ad, false, TraceKind::Global) {res.push(ci)}
+    if sorts {
+      if let Some(ci) = make_completion_item(&path, fe, ad, false, TraceKind::Sort) {res.push(ci)}
+    }
+    if decls && fits(ad) {
+      if let Some(mut ci) = make_completion_item(&path, fe, ad, false, TraceKind::Decl) {
+        if goal.is_some() {
+          ci.sort_text = Some(format!("{}{}", if applies(ad) {0} else {1}, ci.label))
+        }
+        res.push(ci)
+      }
+    }
+    if globals {
+      if let Some(ci) = make_completion_item(&path, fe, ad, false, TraceKind::Global) {res.push(ci)}
+    }
+  }
+  let item = |label: &[u8], kind| CompletionItem {
+    label: String::from_utf8_lossy(label).into(),
+    kind: Some(kind),
+    ..Default::default()
+  };
+  match ctx {
+    CompletionCtx::Formula {mut vars, ..} | CompletionCtx::Quoted {mut vars} |
+    CompletionCtx::Lisp {locals: mut vars} => {
+      vars.sort();
+      vars.dedup();
+      res.extend(vars.iter().map(|x| item(x, CompletionItemKind::Variable)))
+    }
+    CompletionCtx::Import(_) | CompletionCtx::Other => {}
+  }
+  if globals {
+    // The builtins that have been redefined are already listed with the globals
+    for &p in BuiltinProc::ALL {
+      if env.get_atom(p.to_byte_str()).map_or(true, |a| env.data()[a].lisp().is_none()) {
+        res.push(item(p.to_byte_str(), CompletionItemKind::Function))
+      }
+    }
+    res.extend(Syntax::ALL.iter().map(|s| item(s.to_byte_str(), CompletionItemKind::Keyword)));
+  }
+  if goal.is_some() {
+    for ci in &mut res {
+      if ci.sort_text.is_none() { ci.sort_text = Some(format!("1{}", ci.label)) }
+    }
   }
   Ok(CompletionResponse::Array(res))
 }
 
 async fn completion_resolve(ci: CompletionItem) -> StdResult<CompletionItem, ResponseError> {
-  let data = ci.data.ok_or_else(|| response_err(ErrorCode::InvalidRequest, "missing data"))?;
+  // Local variables, builtins and file names have nothing more to show
+  let data = match ci.data {Some(data) => data, None => return Ok(ci)};
   let (uri, tk): (Url, TraceKind) = from_value(data).map_err(|e|
     response_err(ErrorCode::InvalidRequest, format!("bad JSON {:?}", e)))?;
   let path = uri.into();
@@ -1025,12 +2027,52 @@ async fn completion_resolve(ci: CompletionItem) -> StdResult<CompletionItem, Res
     .ok_or_else(|| response_err(ErrorCode::ContentModified, "completion missing"))
 }
 
-async fn references<T>(
-  path: FileRef, pos: Position, include_self: bool, f: impl Fn(Range) -> T + Send
-) -> StdResult<Vec<T>, ResponseError> {
+/// The uses of an object, as found by [`find_uses`].
+struct Uses {
+  /// The kind of the object, or `None` for a local variable.
+  kind: Option<RefKind>,
+  /// The name of the object.
+  name: ArcString,
+  /// The declaration of the object. For a local variable this is its binder.
+  decl: Option<FileSpan>,
+  /// The files containing uses of the object, with their text and the spans of the uses.
+  files: Vec<(FileRef, Arc<LinedString>, Vec<Span>)>,
+  /// The environments of the files that were searched, in which the name is visible.
+  envs: Vec<FrozenEnv>,
+  /// The names of the other local variables of the statement, for a local variable.
+  locals: Vec<ArcString>,
+}
+
+/// Where [`find_uses`] looks for the uses of a global object.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Scope {
+  /// Only the current file.
+  File,
+  /// The file declaring the object and the files importing it that the server has loaded.
+  Loaded,
+  /// Like `Loaded`, but also the importing files in the workspace folders that are not open.
+  Workspace,
+}
+
+/// Get the text, reference index and environment of `path`, elaborating it if necessary.
+async fn ref_index(path: FileRef) -> Option<(Arc<LinedString>, Arc<RefIndex>, Option<FrozenEnv>)> {
+  let (path, file) = SERVER.vfs.get_or_insert(path).await.ok()?;
+  elaborate(path, Some(Position::default()), Default::default(), Default::default()).await.ok()?;
+  let g = file.parsed.lock().await;
+  if let Some(FileCache::Ready {source, refs, res, ..}) = &*g {
+    let env = if let ElabResult::Ok(_, _, env) = res {Some(env.clone())} else {None};
+    Some((source.ascii().clone(), refs.clone(), env))
+  } else {None}
+}
+
+/// Find the object at `pos` in `path` and its uses. Local variables are only used in the
+/// current statement. For global objects, this finds the uses in `path` and the other files
+/// in `scope`.
+async fn find_uses(path: FileRef, pos: Position, scope: Scope
+) -> StdResult<Option<Uses>, ResponseError> {
   macro_rules! or_none {($e:expr)  => {match $e {
     Some(x) => x,
-    None => return Ok(vec![])
+    None => return Ok(None)
   }}}
   #[derive(Copy, Clone, PartialEq, Eq)]
   enum Key {
@@ -1045,10 +2087,9 @@ async fn references<T>(
     response_err(ErrorCode::InvalidRequest, "references: nonexistent file"))?;
   let text = file.text.ulock().1.ascii().clone();
   let idx = or_none!(text.to_idx(pos));
-  let env = elaborate(path, Some(Position::default()), Default::default(), Default::default())
+  let env = elaborate(path.clone(), Some(Position::default()), Default::default(), Default::default())
     .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
   let env = or_none!(env.into_response_error()?).1;
-  let spans = or_none!(env.find(idx));
 
   let to_key = |k: &ObjectKind| match *k {
     ObjectKind::Expr(ref e) => {
@@ -1076,35 +2117,334 @@ async fn references<T>(
     ObjectKind::Global(a) => Some(Key::Global(a)),
   };
 
-  let mut res = vec![];
-  for &(sp, ref k) in spans.find_pos(idx) {
-    let key = match to_key(k) {Some(k) => k, None => continue};
-    match key {
-      Key::Global(a) if BuiltinProc::from_bytes(env.data()[a].name()).is_some() => continue,
-      _ => {}
+  // This is done before any files are elaborated, because `spans` cannot be held across
+  // an `await`.
+  let (kind, a, decl) = {
+    let spans = or_none!(env.find(idx));
+    let mut found = None;
+    for &(_, ref k) in spans.find_pos(idx) {
+      found = Some(match to_key(k) {
+        None => continue,
+        Some(Key::Var(a)) => {
+          let (mut sps, mut locals) = (vec![], vec![]);
+          for &(sp, ref k2) in spans.into_iter() {
+            if let ObjectKind::Var(_) | ObjectKind::Expr(_) | ObjectKind::Proof(_) = *k2 {
+              match to_key(k2) {
+                Some(Key::Var(b)) if a == b => sps.push(sp),
+                Some(Key::Var(b)) => locals.push(env.data()[b].name().clone()),
+                _ => {}
+              }
+            }
+          }
+          // The first occurrence of a local variable is its binder.
+          let decl = sps.iter().min_by_key(|sp| sp.start).map(|&sp| FileSpan {file: path.clone(), span: sp});
+          return Ok(Some(Uses {
+            kind: None, name: env.data()[a].name().clone(), decl,
+            files: vec![(path, text, sps)], envs: vec![env.clone()], locals
+          }))
+        }
+        Some(Key::Global(a)) if BuiltinProc::from_bytes(env.data()[a].name()).is_some() => continue,
+        Some(Key::Global(a)) => (RefKind::Global, a,
+          env.data()[a].lisp().as_ref().and_then(|ld| ld.src().as_ref()).map(|(fsp, _)| fsp.clone())),
+        Some(Key::Sort(s)) => (RefKind::Sort, env.sort(s).atom, Some(env.sort(s).span.clone())),
+        Some(Key::Term(t)) => (RefKind::Term, env.term(t).atom, Some(env.term(t).span.clone())),
+        Some(Key::Thm(t)) => (RefKind::Thm, env.thm(t).atom, Some(env.thm(t).span.clone())),
+      });
+      break
     }
-    let mut cont = |&(sp2, ref k2)| {
-      let eq = match *k2 {
-        ObjectKind::Expr(_) if !matches!(key, Key::Term(_) | Key::Var(_)) => false,
-        ObjectKind::Proof(_) if !matches!(key, Key::Thm(_) | Key::Var(_)) => false,
-        _ => Some(key) == to_key(k2),
-      };
-      if eq && (include_self || sp != sp2) {
-        let sp2 = if let ObjectKind::Term(_, sp2) = *k2 {sp2} else {sp2};
-        res.push(f(text.to_range(sp2)))
+    or_none!(found)
+  };
+  let name = env.data()[a].name().clone();
+  let mut files = vec![path.clone()];
+  if scope != Scope::File {
+    let home = decl.as_ref().map_or(&path, |fsp| &fsp.file).clone();
+    // The files that import the declaration, both the ones known to the server
+    // and the ones in the workspace folders
+    let mut stack = vec![home.clone()];
+    let mut related = vec![home.clone()];
+    while let Some(f) = stack.pop() {
+      if let Some(vf) = SERVER.vfs.get(&f) {
+        for d in vf.downstream.ulock().iter() {
+          if !related.contains(d) { related.push(d.clone()); stack.push(d.clone()) }
+        }
       }
-    };
-    if let Key::Var(_) = key {
-      spans.into_iter().for_each(&mut cont);
-    } else {
-      for spans2 in env.spans() {
-        spans2.into_iter().for_each(&mut cont);
+    }
+    if scope == Scope::Workspace {
+      for f in workspace_importers(&home) {
+        if !related.contains(&f) { related.push(f) }
+      }
+    }
+    files.extend(related.into_iter().filter(|f| *f != path));
+  }
+  let (mut res, mut envs) = (vec![], vec![]);
+  for f in files {
+    if let Some((text, refs, env)) = ref_index(f.clone()).await {
+      envs.extend(env);
+      if let Some(sps) = refs.0.get(&(kind, name.clone())) {
+        res.push((f, text, sps.clone()))
+      }
+    }
+  }
+  Ok(Some(Uses {kind: Some(kind), name, decl, files: res, envs, locals: vec![]}))
+}
+
+async fn references<T>(
+  path: FileRef, pos: Position, include_self: bool, scope: Scope,
+  f: impl Fn(&FileRef, Range) -> T + Send
+) -> StdResult<Vec<T>, ResponseError> {
+  let uses = match find_uses(path, pos, scope).await? {
+    Some(uses) => uses,
+    None => return Ok(vec![])
+  };
+  let mut res = vec![];
+  for (file, text, sps) in &uses.files {
+    for &sp in sps {
+      if include_self || !uses.decl.as_ref().map_or(false, |d| d.file == *file && d.span == sp) {
+        res.push(f(file, text.to_range(sp)))
       }
     }
   }
   Ok(res)
 }
 
+/// Rename the object at `pos` in `path` to `new_name`, in all the files that use it.
+/// Only the spans that contain the old name are changed, which excludes the notation tokens
+/// of a term, which are recorded as uses of the term. The rename is rejected if `new_name`
+/// already names an object of the same kind in one of these files.
+async fn rename(path: FileRef, pos: Position, new_name: String
+) -> StdResult<Option<WorkspaceEdit>, ResponseError> {
+  let uses = match find_uses(path, pos, Scope::Workspace).await? {
+    Some(uses) => uses,
+    None => return Ok(None)
+  };
+  let valid = match (new_name.as_bytes(), uses.kind) {
+    ([], _) => false,
+    (s, Some(RefKind::Global)) => s.iter().all(|&c| crate::parser::lisp_ident(c)),
+    ([c, s @ ..], _) => crate::parser::ident_start(*c) && s.iter().all(|&c| crate::parser::ident_rest(c)),
+  };
+  if !valid {
+    return Err(response_err(ErrorCode::InvalidParams, format!("invalid name '{}'", new_name)))
+  }
+  if *uses.name != *new_name.as_bytes() {
+    let clash = uses.locals.iter().any(|x| **x == *new_name.as_bytes()) ||
+      uses.envs.iter().any(|env| env.get_atom(new_name.as_bytes()).map_or(false, |a| {
+        let ad = &env.data()[a];
+        match uses.kind {
+          None | Some(RefKind::Term) | Some(RefKind::Thm) => ad.decl().is_some(),
+          Some(RefKind::Sort) => ad.sort().is_some(),
+          Some(RefKind::Global) => ad.lisp().is_some(),
+        }
+      })) ||
+      (uses.kind == Some(RefKind::Global) && BuiltinProc::from_bytes(new_name.as_bytes()).is_some());
+    if clash {
+      return Err(response_err(ErrorCode::InvalidParams, format!("'{}' is already defined", new_name)))
+    }
+  }
+  let mut changes = HashMap::new();
+  for (file, text, sps) in uses.files {
+    let edits: Vec<_> = sps.into_iter().filter(|&sp| text[sp] == *uses.name)
+      .map(|sp| TextEdit {range: text.to_range(sp), new_text: new_name.clone()}).collect();
+    if !edits.is_empty() { changes.insert(file.url().clone(), edits); }
+  }
+  Ok(Some(WorkspaceEdit {changes: Some(changes), ..Default::default()}))
+}
+
+/// The lisp code in a statement: its annotations, the value or proof of a declaration,
+/// and the body of a `do` block.
+fn stmt_lisp(mut stmt: &Stmt) -> Vec<&SExpr> {
+  let mut out = vec![];
+  loop {
+    match &stmt.k {
+      StmtKind::Annot(e, s) => { out.push(e); stmt = &**s }
+      StmtKind::DocComment(_, s) => stmt = &**s,
+      StmtKind::Decl(d) => { out.extend(&d.val); return out }
+      StmtKind::Do(es) => { out.extend(es); return out }
+      _ => return out
+    }
+  }
+}
+
+/// Add the spans of the identifiers in `e` to `out`, except those inside formulas.
+fn lisp_atoms(e: &SExpr, out: &mut Vec<Span>) {
+  match &e.k {
+    SExprKind::Atom(Atom::Ident) => out.push(e.span),
+    SExprKind::List(es) => for e in es { lisp_atoms(e, out) },
+    SExprKind::DottedList(es, r) => {
+      for e in es { lisp_atoms(e, out) }
+      lisp_atoms(r, out)
+    }
+    SExprKind::DocComment(_, e) => lisp_atoms(e, out),
+    _ => {}
+  }
+}
+
+/// Highlight the uses of the object at `pos` in the file. Local variables of lisp code are
+/// not recorded by the elaborator, so for those we highlight the identifiers with the same
+/// name in the lisp code of the statement instead.
+async fn document_highlight(path: FileRef, pos: Position
+) -> StdResult<Vec<DocumentHighlight>, ResponseError> {
+  let res = references(path.clone(), pos, true, Scope::File,
+    |_, range| DocumentHighlight { range, kind: None }).await?;
+  if !res.is_empty() { return Ok(res) }
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "document highlight nonexistent file"))?;
+  let ast = current_ast(&file);
+  let src = &*ast.source;
+  let idx = match src.to_idx(pos) {Some(idx) => idx, None => return Ok(vec![])};
+  let mut atoms = vec![];
+  if let Some(stmt) = ast.stmts.iter().find(|s| s.span.start <= idx && idx <= s.span.end) {
+    for e in stmt_lisp(stmt) { lisp_atoms(e, &mut atoms) }
+  }
+  let name = match atoms.iter().find(|sp| sp.start <= idx && idx <= sp.end) {
+    Some(&sp) => &src[sp],
+    None => return Ok(vec![])
+  };
+  Ok(atoms.iter().filter(|&&sp| src[sp] == *name)
+    .map(|&sp| DocumentHighlight { range: src.to_range(sp), kind: None }).collect())
+}
+
+/// Find the innermost application `(f args)` of a named procedure `f` that contains
+/// position `idx` in `e`, not counting quoted code, and return the span of `f` and the
+/// number of arguments that end before `idx`.
+fn lisp_app(src: &LinedString, e: &SExpr, idx: usize) -> Option<(Span, usize)> {
+  if idx <= e.span.start || e.span.end <= idx { return None }
+  let es = match &e.k {
+    SExprKind::DocComment(_, e) => return lisp_app(src, e, idx),
+    SExprKind::List(es) | SExprKind::DottedList(es, _) => es,
+    _ => return None
+  };
+  let tail = if let SExprKind::DottedList(_, e) = &e.k {Some(&**e)} else {None};
+  match es.first() {
+    Some(&SExpr {span, k: SExprKind::Atom(a)}) => {
+      if src.span_atom(span, a) == b"quote" { return None }
+      es.iter().chain(tail).find_map(|e| lisp_app(src, e, idx)).or_else(||
+        if matches!(a, Atom::Ident) && span.end < idx {
+          Some((span, es[1..].iter().take_while(|e| e.span.end < idx).count()))
+        } else { None })
+    }
+    _ => es.iter().chain(tail).find_map(|e| lisp_app(src, e, idx))
+  }
+}
+
+/// Find the parameters of the lisp definition in `e` of the global whose name is at `name`,
+/// if it is `(def (f args) ...)` or `(def f (fn args ...))`. A rest parameter is
+/// written `. xs`.
+fn def_params(src: &LinedString, e: &SExpr, name: Span) -> Option<Vec<String>> {
+  fn params(src: &LinedString, es: &[SExpr], tail: Option<&SExpr>) -> Vec<String> {
+    let mut out: Vec<_> = es.iter().map(|e| String::from_utf8_lossy(&src[e.span]).into()).collect();
+    if let Some(e) = tail { out.push(format!(". {}", String::from_utf8_lossy(&src[e.span]))) }
+    out
+  }
+  if !(e.span.start <= name.start && name.end <= e.span.end) { return None }
+  let es = match &e.k {
+    SExprKind::DocComment(_, e) => return def_params(src, e, name),
+    SExprKind::List(es) | SExprKind::DottedList(es, _) => es,
+    _ => return None
+  };
+  if let [hd, x, rest @ ..] = &**es {
+    if matches!(hd.k, SExprKind::Atom(Atom::Ident)) && &src[hd.span] == b"def" {
+      match &x.k {
+        SExprKind::List(xs) if xs.first().map(|x| x.span) == Some(name) =>
+          return Some(params(src, &xs[1..], None)),
+        SExprKind::DottedList(xs, y) if xs.first().map(|x| x.span) == Some(name) =>
+          return Some(params(src, &xs[1..], Some(y))),
+        SExprKind::Atom(_) if x.span == name => {
+          if let Some(SExprKind::List(ys)) = rest.first().map(|e| &e.k) {
+            if let [f, args, ..] = &**ys {
+              if matches!(f.k, SExprKind::Atom(Atom::Ident)) && &src[f.span] == b"fn" {
+                return Some(match &args.k {
+                  SExprKind::List(xs) => params(src, xs, None),
+                  SExprKind::DottedList(xs, y) => params(src, xs, Some(y)),
+                  _ => params(src, &[], Some(args)),
+                })
+              }
+            }
+          }
+          return None
+        }
+        _ => {}
+      }
+    }
+  }
+  let tail = if let SExprKind::DottedList(_, e) = &e.k {Some(&**e)} else {None};
+  es.iter().chain(tail).find_map(|e| def_params(src, e, name))
+}
+
+/// Show the signature of the lisp procedure applied at `pos`: its parameter names, if it
+/// is defined by `def` or `fn` in a file known to the server, the number of arguments it
+/// takes, and its documentation.
+async fn signature_help(path: FileRef, pos: Position) -> StdResult<Option<SignatureHelp>, ResponseError> {
+  macro_rules! or_none {($e:expr)  => {match $e {
+    Some(x) => x,
+    None => return Ok(None)
+  }}}
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "signature help nonexistent file"))?;
+  let ast = current_ast(&file);
+  let src = &*ast.source;
+  let idx = or_none!(src.to_idx(pos));
+  let stmt = or_none!(ast.stmts.iter().find(|s| s.span.start <= idx && idx <= s.span.end));
+  let (head, arg) = or_none!(stmt_lisp(stmt).into_iter().find_map(|e| lisp_app(src, e, idx)));
+  let name = &src[head];
+  if let CompletionCtx::Lisp {locals} = completion_ctx(&ast, idx) {
+    if locals.iter().any(|x| **x == *name) { return Ok(None) }
+  } else { return Ok(None) }
+
+  let env = match try_old(&file) {
+    Some((_, env)) => env,
+    None => {
+      let env = elaborate(path.clone(), Some(Position::default()), Default::default(), Default::default())
+        .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
+      or_none!(env.into_response_error()?).1
+    }
+  };
+  let ld = env.get_atom(name).and_then(|a| unsafe { env.thaw() }.data[a].lisp.as_ref());
+  let (spec, doc, params) = match ld {
+    Some(ld) => {
+      let spec = or_none!(ld.unwrapped(|e| match e {
+        LispKind::Proc(p) => Some(p.spec()),
+        _ => None
+      }));
+      let params = ld.src.as_ref().and_then(|(fsp, full)| {
+        let ast2;
+        let ast = if fsp.file == path { &ast } else {
+          ast2 = current_ast(&*SERVER.vfs.get(&fsp.file)?);
+          &ast2
+        };
+        let stmt = ast.stmts.iter().find(|s| s.span.start <= full.start && full.end <= s.span.end)?;
+        stmt_lisp(stmt).into_iter().find_map(|e| def_params(&ast.source, e, fsp.span))
+      });
+      (spec, ld.doc.as_ref().map(|doc| trim_margin(doc)), params)
+    }
+    None => {
+      let p = or_none!(BuiltinProc::from_bytes(name));
+      (p.spec(), Some(trim_margin(p.doc())), None)
+    }
+  };
+  let name = String::from_utf8_lossy(name);
+  let params = params.unwrap_or_default();
+  let label = params.iter().fold(format!("({}", name), |s, p| s + " " + p) + ")";
+  let documentation = format!("Takes {}.{}", spec,
+    doc.map_or_else(String::new, |doc| format!("\n\n{}", doc)));
+  let active = match spec {
+    ProcSpec::AtLeast(_) if !params.is_empty() => arg.min(params.len() - 1),
+    _ => arg,
+  };
+  Ok(Some(SignatureHelp {
+    signatures: vec![SignatureInformation {
+      label,
+      documentation: Some(Documentation::String(documentation)),
+      parameters: Some(params.into_iter().map(|p| ParameterInformation {
+        label: ParameterLabel::Simple(p),
+        documentation: None,
+      }).collect()),
+      active_parameter: None,
+    }],
+    active_signature: Some(0),
+    active_parameter: active.try_into().ok(),
+  }))
+}
+
 struct Server {
   conn: Connection,
   #[allow(unused)]
@@ -1115,22 +2455,36 @@ struct Server {
   #[allow(clippy::type_complexity)]
   threads: Arc<(Mutex<VecDeque<(Job, Arc<AtomicBool>)>>, Condvar)>,
   options: Mutex<ServerOptions>,
+  /// The workspace folders, which are searched for files importing a saved file.
+  roots: Vec<PathBuf>,
+  /// The imports of the files in the workspace folders, built when it is first needed.
+  imports: Mutex<Option<ImportIndex>>,
 }
 
 struct Capabilities {
   reg_id: Option<RequestId>,
   definition_location_links: Option<bool>,
+  /// True if the client handles `mm0/output` notifications, which it advertises with the
+  /// experimental capability `{"mm0Output": true}`.
+  output: bool,
+  /// True if the client supports inlay hints and their dynamic registration. Our version of
+  /// `lsp_types` has no capabilities for inlay hints, so this is read from the raw JSON.
+  inlay_hints: bool,
 }
 
 impl Capabilities {
-  fn new(params: &InitializeParams) -> Capabilities {
+  fn new(params: &InitializeParams, raw: &serde_json::Value) -> Capabilities {
     let dll = match params.capabilities.text_document.as_ref()
       .and_then(|d| d.definition.as_ref()) {
       Some(&GotoCapability {link_support: Some(b), ..}) => Some(b),
       Some(GotoCapability {dynamic_registration: Some(true), ..}) => Some(true),
       _ => Some(false)
     };
-    Capabilities { reg_id: None, definition_location_links: dll }
+    let output = params.capabilities.experimental.as_ref()
+      .and_then(|e| e.get("mm0Output")).and_then(serde_json::Value::as_bool).unwrap_or(false);
+    let inlay_hints = raw.pointer("/capabilities/textDocument/inlayHint/dynamicRegistration")
+      .and_then(serde_json::Value::as_bool).unwrap_or(false);
+    Capabilities { reg_id: None, definition_location_links: dll, output, inlay_hints }
   }
 
   fn register(&mut self) -> Result<()> {
@@ -1150,6 +2504,15 @@ impl Capabilities {
       register_options: None,
     });
 
+    // Our version of `lsp_types` has no static capability for inlay hints
+    if self.inlay_hints {
+      regs.push(Registration {
+        id: String::new(),
+        method: "textDocument/inlayHint".into(),
+        register_options: None,
+      })
+    }
+
     if !regs.is_empty() {
       register_capability("regs".into(), regs)?;
       self.reg_id = Some(String::from("regs").into());
@@ -1222,10 +2585,14 @@ impl std::fmt::Display for Job {
 }
 
 impl Job {
+  /// Run `fut` as the job `self`. While the debugger is on, elaborations run on their own
+  /// threads rather than the pool, so that an evaluation paused in the debugger does not
+  /// hold up the other jobs.
   fn spawn_core<F>(self, cancel: Arc<AtomicBool>, fut: F)
   where F: std::future::Future<Output=()> + Send + 'static {
+    let own_thread = !matches!(self, Job::RequestHandler(..)) && debugger::current().is_some();
     SERVER.threads.0.ulock().push_back((self, cancel.clone()));
-    SERVER.pool.spawn_ok(async move {
+    let fut = async move {
       fut.await;
       let (m, cvar) = &*SERVER.threads;
       let mut vec = m.ulock();
@@ -1234,7 +2601,12 @@ impl Job {
       vec.swap_remove_front(i);
       drop(vec);
       cvar.notify_all();
-    });
+    };
+    if own_thread {
+      debugger::spawn(move || futures::executor::block_on(fut))
+    } else {
+      SERVER.pool.spawn_ok(fut)
+    }
   }
 
   fn spawn(mut self) {
@@ -1278,7 +2650,7 @@ struct ServerOptions {
   elab_on: Option<ElabOn>,
   executable_path: Option<std::path::PathBuf>,
   max_number_of_problems: usize,
-  trace: Option<Trace>
+  trace: Option<Trace>,
 }
 
 impl std::default::Default for ServerOptions {
@@ -1344,7 +2716,7 @@ fn send_config_request() -> Result<()> {
 impl Server {
   fn new() -> Result<Server> {
     let (conn, _iot) = Connection::stdio();
-    let params = from_value(conn.initialize(
+    let raw = conn.initialize(
       to_value(ServerCapabilities {
         text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::Incremental)),
         hover_provider: Some(true.into()),
@@ -1356,17 +2728,33 @@ impl Server {
         document_symbol_provider: Some(OneOf::Left(true)),
         references_provider: Some(OneOf::Left(true)),
         document_highlight_provider: Some(OneOf::Left(true)),
+        rename_provider: Some(OneOf::Left(true)),
+        code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+        document_formatting_provider: Some(OneOf::Left(true)),
+        folding_range_provider: Some(FoldingRangeProviderCapability::Simple(true)),
+        signature_help_provider: Some(SignatureHelpOptions {
+          trigger_characters: Some(vec!["(".into(), " ".into()]),
+          retrigger_characters: None,
+          work_done_progress_options: Default::default(),
+        }),
         ..Default::default()
       })?
-    )?)?;
+    )?;
+    let params = from_value(raw.clone())?;
+    let roots = match &params.workspace_folders {
+      Some(folders) => folders.iter().map(|f| &f.uri).collect(),
+      None => params.root_uri.iter().collect::<Vec<_>>(),
+    }.into_iter().filter_map(|u| u.to_file_path().ok()).collect();
     Ok(Server {
-      caps: Mutex::new(Capabilities::new(&params)),
+      caps: Mutex::new(Capabilities::new(&params, &raw)),
       conn,
       reqs: Mutex::new(HashMap::new()),
       vfs: VFS(Mutex::new(HashMap::new())),
       pool: ThreadPool::new()?,
       threads: Default::default(),
       options: Mutex::new(ServerOptions::default()),
+      roots,
+      imports: Mutex::new(None),
     })
   }
 
@@ -1458,10 +2846,12 @@ impl Server {
                 let path = FileRef::from(doc.uri);
                 log!("save {:?}", path);
                 if options.ulock().elab_on.unwrap_or_default() == ElabOn::Save {
-                  Job::Elaborate(path, ElabReason::Save).spawn();
+                  Job::Elaborate(path.clone(), ElabReason::Save).spawn();
+                  SERVER.pool.spawn_ok(elaborate_importers(path));
                 }
               }
               DidChangeConfiguration::METHOD => send_config_request()?,
+              m if m.starts_with("mm0/debug/") => debug_notification(m, notif.params)?,
               _ => {}
             }
           }
@@ -1512,6 +2902,12 @@ pub fn main(args: &ArgMatches<'_>) {
     }
   }
   if args.is_present("no_log_errors") { LOG_ERRORS.store(false, Ordering::Relaxed) }
+  // Clients that don't handle `mm0/output` get the output as diagnostics, as usual
+  if SERVER.caps.ulock().output {
+    let chan: Arc<dyn OutputChannel> = Arc::new(|out: Output|
+      if let Err(e) = send_output(out) { log!("failed to send output: {:?}", e) });
+    output::set_channel(Some(chan));
+  }
   let _ = log_message("started".into());
   SERVER.run();
   let Server {reqs, vfs: VFS(vfs), ..} = &*SERVER;
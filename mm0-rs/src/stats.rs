@@ -0,0 +1,309 @@
+//! Library statistics, for keeping track of the health of a project.
+//!
+//!     mm0-rs stats a.mm1 b.mm1 ...
+//!
+//! will elaborate each file and print a report on the declarations in it: the number of
+//! sorts, terms, definitions, axioms and theorems, the distribution of proof sizes (and the
+//! total size the proofs would have without the sharing of common subproofs), the
+//! longest proofs, the largest files, and for each axiom the number of theorems that
+//! (transitively) depend on it. By default only the declarations in the given files are
+//! counted; with `--project` the files they import are included as well, so that running it
+//! on the root file of a library gives a report on the whole library.
+//!
+//! The report is written as markdown, or as JSON with `--json`. Nothing is sent anywhere;
+//! the output goes to stdout or to the file given by `--output`.
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Write};
+use clap::ArgMatches;
+use serde_json::{json, Value};
+use crate::elab::environment::{Environment, Proof, ProofNode, ThmID, TermKind, ThmKind};
+use crate::util::FileRef;
+
+/// The size of a [`ProofNode`], as the number of nodes in it, where the size of
+/// a [`Ref`](ProofNode::Ref) to heap element `i` is `heap(i)`. The sums saturate,
+/// because the size of a proof with all sharing expanded can be exponential.
+fn node_size_with(p: &ProofNode, heap: &impl Fn(usize) -> usize) -> usize {
+  let sum = |ps: &[ProofNode]| ps.iter().map(|p| node_size_with(p, heap)).fold(0, usize::saturating_add);
+  match p {
+    &ProofNode::Ref(i) => heap(i),
+    ProofNode::Dummy(..) => 1,
+    ProofNode::Term {args, ..} | ProofNode::Cong {args, ..} => sum(args).saturating_add(1),
+    ProofNode::Hyp(_, e) | ProofNode::Refl(e) | ProofNode::Sym(e) => node_size_with(e, heap).saturating_add(1),
+    ProofNode::Thm {args, res, ..} => sum(args).saturating_add(node_size_with(res, heap)).saturating_add(1),
+    ProofNode::Conv(p) => 1_usize
+      .saturating_add(node_size_with(&p.0, heap))
+      .saturating_add(node_size_with(&p.1, heap))
+      .saturating_add(node_size_with(&p.2, heap)),
+    ProofNode::Unfold {args, res, ..} => sum(args).saturating_add(1)
+      .saturating_add(node_size_with(&res.0, heap))
+      .saturating_add(node_size_with(&res.1, heap))
+      .saturating_add(node_size_with(&res.2, heap)),
+  }
+}
+
+/// The proof size of a [`ProofNode`], as the number of nodes in it.
+/// [`Ref`](ProofNode::Ref) nodes count as one node, so that shared subproofs
+/// are only counted once (on the heap).
+fn node_size(p: &ProofNode) -> usize { node_size_with(p, &|_| 1) }
+
+/// The size of a proof: the sum of the sizes of the heap, hypotheses and target.
+fn proof_size(pf: &Proof) -> usize {
+  pf.heap.iter().chain(&*pf.hyps).chain(Some(&pf.head)).map(node_size).sum()
+}
+
+/// The size of a proof as a tree, that is, with every reference to a shared subproof
+/// replaced by a copy of it. Comparing this with [`proof_size`] shows how much the
+/// deduplication of subproofs (see [`Dedup`](crate::elab::proof::Dedup)) saves.
+/// `nargs` is the number of variables, which are the first elements of the heap.
+fn unshared_size(pf: &Proof, nargs: usize) -> usize {
+  let mut sizes = Vec::with_capacity(pf.heap.len());
+  for (i, p) in pf.heap.iter().enumerate() {
+    let n = if i < nargs {1} else {node_size_with(p, &|j| sizes[j])};
+    sizes.push(n)
+  }
+  pf.hyps.iter().chain(Some(&pf.head)).map(|p| node_size_with(p, &|j| sizes[j]))
+    .fold(0, usize::saturating_add)
+}
+
+/// Per-file statistics.
+#[derive(Default)]
+struct FileStats {
+  bytes: usize,
+  lines: usize,
+  decls: usize,
+  proof_size: usize,
+}
+
+/// The statistics accumulated over all the input files.
+#[derive(Default)]
+struct Stats {
+  /// The declarations already counted, by file and name. The same file can be imported
+  /// by several of the inputs, so this is used to make sure it is only counted once.
+  seen: HashSet<(FileRef, String)>,
+  sorts: usize,
+  terms: usize,
+  defs: usize,
+  axioms: usize,
+  theorems: usize,
+  /// The theorems that have no proof (because proof checking was disabled).
+  unproved: usize,
+  /// `(name, file, size)` for each proved theorem.
+  proofs: Vec<(String, FileRef, usize)>,
+  /// The total size of the proofs without sharing of subproofs.
+  unshared_size: usize,
+  files: HashMap<FileRef, FileStats>,
+  /// `(name, file, count)` for each axiom, where `count` is the number of
+  /// theorems depending on it.
+  axiom_uses: Vec<(String, FileRef, usize)>,
+}
+
+impl Stats {
+  /// Returns true if this declaration should be counted, and records it in the file statistics.
+  fn new_decl(&mut self, roots: Option<&[FileRef]>, file: &FileRef, name: &str) -> bool {
+    if roots.map_or(false, |roots| !roots.contains(file)) ||
+      !self.seen.insert((file.clone(), name.to_owned())) { return false }
+    self.files.entry(file.clone()).or_default().decls += 1;
+    true
+  }
+
+  /// Add the declarations in `env`. If `roots` is set, only declarations in these files are counted.
+  fn add_env(&mut self, env: &Environment, roots: Option<&[FileRef]>) {
+    for s in &env.sorts.0 {
+      if self.new_decl(roots, &s.span.file, s.name.as_str()) { self.sorts += 1 }
+    }
+    for t in &env.terms.0 {
+      if self.new_decl(roots, &t.span.file, env.data[t.atom].name.as_str()) {
+        match t.kind {
+          TermKind::Term => self.terms += 1,
+          TermKind::Def(_) => self.defs += 1,
+        }
+      }
+    }
+    // For each axiom in the environment, its index in a bitset;
+    // for each theorem, the set of axioms it depends on.
+    let mut axiom_idx = vec![None; env.thms.0.len()];
+    let mut axioms = vec![];
+    for (i, t) in env.thms.0.iter().enumerate() {
+      if let ThmKind::Axiom = t.kind { axiom_idx[i] = Some(axioms.len()); axioms.push(i) }
+    }
+    let words = (axioms.len() + 63) / 64;
+    let mut deps: Vec<Vec<u64>> = Vec::with_capacity(env.thms.0.len());
+    for (i, t) in env.thms.0.iter().enumerate() {
+      let mut set = vec![0_u64; words];
+      match &t.kind {
+        ThmKind::Axiom => {
+          let n = axiom_idx[i].expect("axiom");
+          set[n / 64] |= 1 << (n % 64)
+        }
+        ThmKind::Thm(Some(pf)) => {
+          let mut add = |th: ThmID| {
+            // Proofs can only refer to earlier theorems
+            if let Some(d) = deps.get(th.0 as usize) {
+              for (a, b) in set.iter_mut().zip(d) { *a |= b }
+            }
+          };
+          pf.thms(&mut add)
+        }
+        ThmKind::Thm(None) => {}
+      }
+      deps.push(set);
+      let name = &env.data[t.atom].name;
+      if !self.new_decl(roots, &t.span.file, name.as_str()) { continue }
+      match &t.kind {
+        ThmKind::Axiom => self.axioms += 1,
+        ThmKind::Thm(pf) => {
+          self.theorems += 1;
+          if let Some(pf) = pf {
+            let size = proof_size(pf);
+            self.files.get_mut(&t.span.file).expect("just added").proof_size += size;
+            self.unshared_size = self.unshared_size.saturating_add(unshared_size(pf, t.args.len()));
+            self.proofs.push((name.to_string(), t.span.file.clone(), size))
+          } else { self.unproved += 1 }
+        }
+      }
+    }
+    for (n, &i) in axioms.iter().enumerate() {
+      let t = &env.thms.0[i];
+      if roots.map_or(false, |roots| !roots.contains(&t.span.file)) { continue }
+      let name = env.data[t.atom].name.to_string();
+      if self.axiom_uses.iter().any(|(s, f, _)| *s == name && *f == t.span.file) { continue }
+      let count = env.thms.0.iter().zip(&deps)
+        .filter(|(t, d)| matches!(t.kind, ThmKind::Thm(_)) && d[n / 64] & (1 << (n % 64)) != 0)
+        .count();
+      self.axiom_uses.push((name, t.span.file.clone(), count))
+    }
+  }
+
+  /// Fill in the sizes of the source files, and sort the tables for output.
+  fn finish(&mut self) -> io::Result<()> {
+    for (file, fs) in &mut self.files {
+      let text = std::fs::read_to_string(file.path())?;
+      fs.bytes = text.len();
+      fs.lines = text.lines().count();
+    }
+    self.proofs.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+    self.axiom_uses.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+    Ok(())
+  }
+
+  /// The proof size distribution, as a list of `(lo, hi, count)` where `count` is the
+  /// number of proofs with `lo <= size < hi`. The buckets are powers of 10.
+  fn distribution(&self) -> Vec<(usize, usize, usize)> {
+    let mut buckets = vec![];
+    let (mut lo, mut hi) = (0, 10);
+    let max = self.proofs.first().map_or(0, |p| p.2);
+    while lo <= max && !self.proofs.is_empty() {
+      let n = self.proofs.iter().filter(|p| lo <= p.2 && p.2 < hi).count();
+      buckets.push((lo, hi, n));
+      lo = hi;
+      hi = hi.saturating_mul(10);
+    }
+    buckets
+  }
+
+  /// The files, from largest to smallest.
+  fn largest_files(&self) -> Vec<(&FileRef, &FileStats)> {
+    let mut files: Vec<_> = self.files.iter().collect();
+    files.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes).then_with(|| a.0.rel().cmp(b.0.rel())));
+    files
+  }
+
+  fn to_json(&self, top: usize) -> Value {
+    json!({
+      "sorts": self.sorts,
+      "terms": self.terms,
+      "defs": self.defs,
+      "axioms": self.axioms,
+      "theorems": self.theorems,
+      "unproved": self.unproved,
+      "proof_size": {
+        "total": self.proofs.iter().map(|p| p.2).sum::<usize>(),
+        "unshared": self.unshared_size,
+        "distribution": self.distribution().into_iter().map(|(lo, hi, count)|
+          json!({"min": lo, "max": hi - 1, "count": count})).collect::<Vec<_>>(),
+      },
+      "longest_proofs": self.proofs.iter().take(top).map(|(name, file, size)|
+        json!({"name": name, "file": file.rel(), "size": size})).collect::<Vec<_>>(),
+      "largest_files": self.largest_files().into_iter().take(top).map(|(file, fs)|
+        json!({"file": file.rel(), "bytes": fs.bytes, "lines": fs.lines,
+          "decls": fs.decls, "proof_size": fs.proof_size})).collect::<Vec<_>>(),
+      "axiom_uses": self.axiom_uses.iter().map(|(name, file, count)|
+        json!({"name": name, "file": file.rel(), "theorems": count})).collect::<Vec<_>>(),
+    })
+  }
+
+  fn write_markdown(&self, w: &mut impl Write, top: usize) -> io::Result<()> {
+    writeln!(w, "# Library statistics\n")?;
+    writeln!(w, "| declarations | count |\n|---|---:|")?;
+    writeln!(w, "| sorts | {} |", self.sorts)?;
+    writeln!(w, "| terms | {} |", self.terms)?;
+    writeln!(w, "| definitions | {} |", self.defs)?;
+    writeln!(w, "| axioms | {} |", self.axioms)?;
+    writeln!(w, "| theorems | {} |", self.theorems)?;
+    if self.unproved != 0 { writeln!(w, "| theorems without proof | {} |", self.unproved)? }
+    writeln!(w, "\n## Proof sizes\n")?;
+    writeln!(w, "Total proof size: {} nodes ({} without sharing of subproofs)\n",
+      self.proofs.iter().map(|p| p.2).sum::<usize>(), self.unshared_size)?;
+    writeln!(w, "| size | theorems |\n|---|---:|")?;
+    for (lo, hi, n) in self.distribution() { writeln!(w, "| {}-{} | {} |", lo, hi - 1, n)? }
+    writeln!(w, "\n## Longest proofs\n")?;
+    writeln!(w, "| theorem | file | size |\n|---|---|---:|")?;
+    for (name, file, size) in self.proofs.iter().take(top) {
+      writeln!(w, "| `{}` | {} | {} |", name, file.rel(), size)?
+    }
+    writeln!(w, "\n## Largest files\n")?;
+    writeln!(w, "| file | bytes | lines | declarations | proof size |\n|---|---:|---:|---:|---:|")?;
+    for (file, fs) in self.largest_files().into_iter().take(top) {
+      writeln!(w, "| {} | {} | {} | {} | {} |", file.rel(), fs.bytes, fs.lines, fs.decls, fs.proof_size)?
+    }
+    writeln!(w, "\n## Axiom dependencies\n")?;
+    writeln!(w, "| axiom | file | theorems using it |\n|---|---|---:|")?;
+    for (name, file, count) in &self.axiom_uses {
+      writeln!(w, "| `{}` | {} | {} |", name, file.rel(), count)?
+    }
+    Ok(())
+  }
+}
+
+/// Main entry point for `mm0-rs stats` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs stats [--project] [--json] [-n N] [-o out] <in.mm1>...`, where:
+///
+/// - `in.mm1` is an MM1 (or MM0) file to report on. The counts are added up over all
+///   the given files.
+/// - `--project` includes the declarations in imported files as well.
+/// - `--json` writes the report as JSON instead of markdown.
+/// - `-n N` sets the number of entries in the "longest proofs" and "largest files"
+///   tables (default 10).
+/// - `out` is the file to write the report to (default stdout).
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let top = match args.value_of("top") {
+    None => 10,
+    Some(n) => n.parse().map_err(|_|
+      io::Error::new(io::ErrorKind::InvalidInput, "expected a number for --top"))?,
+  };
+  let project = args.is_present("project");
+  let mut stats = Stats::default();
+  for path in args.values_of("INPUT").expect("required arg") {
+    let (fref, _, env) = crate::compiler::elab_file(path)?;
+    // Safety: the environment is not modified or cloned while we hold this reference
+    let env = unsafe { env.thaw() };
+    let roots = [fref];
+    stats.add_env(env, if project { None } else { Some(&roots) });
+  }
+  stats.finish()?;
+  let mut w: Box<dyn Write> = match args.value_of("output") {
+    None | Some("-") => Box::new(io::stdout()),
+    Some(file) => Box::new(File::create(file)?),
+  };
+  if args.is_present("json") {
+    serde_json::to_writer_pretty(&mut w, &stats.to_json(top))?;
+    writeln!(w)?;
+  } else {
+    stats.write_markdown(&mut w, top)?
+  }
+  w.flush()
+}
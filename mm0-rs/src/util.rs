@@ -1,19 +1,19 @@
-//! Utilities, mainly path manipulation with some newtype definitions.
+//! Utilities, mainly extension traits and small data structures. The basic types for
+//! strings and source locations are defined in the `mm0_util` crate and re-exported here.
 
-use std::ops::{Deref, DerefMut};
-use std::borrow::Borrow;
 use std::ffi::CStr;
 use std::mem::{self, MaybeUninit};
-use std::fmt;
 use std::error::Error;
-use std::path::PathBuf;
 use std::sync::Arc;
-use std::hash::{Hash, Hasher, BuildHasher};
-use std::collections::{HashMap, hash_map::{Entry, OccupiedEntry}};
+
+pub use mm0_util::{ArcString, FileRef, FileSpan, HashMapExt, Span, CURRENT_DIR};
 
 /// Newtype for `Box<dyn Error + Send + Sync>`
 pub type BoxError = Box<dyn Error + Send + Sync>;
 
+/// Newtype for `Arc<dyn Error + Send + Sync>`, a [`BoxError`] that can be shared
+pub type ArcError = Arc<dyn Error + Send + Sync>;
+
 /// Extension trait for `cloned_box`.
 pub trait SliceExt<T> {
   /// Clones a slice into a boxed slice.
@@ -24,22 +24,6 @@ impl<T> SliceExt<T> for [T] {
   fn cloned_box(&self) -> Box<[T]> where T: Clone { self.to_vec().into() }
 }
 
-/// Extension trait for [`HashMap`]`<K, V>`.
-pub trait HashMapExt<K, V> {
-  /// Like `insert`, but if the insertion fails then it returns the value
-  /// that it attempted to insert, as well as an [`OccupiedEntry`] containing
-  /// the other value that was found.
-  fn try_insert(&mut self, k: K, v: V) -> Option<(V, OccupiedEntry<'_, K, V>)>;
-}
-
-impl<K: Hash + Eq, V, S: BuildHasher> HashMapExt<K, V> for HashMap<K, V, S> {
-  fn try_insert(&mut self, k: K, v: V) -> Option<(V, OccupiedEntry<'_, K, V>)> {
-    match self.entry(k) {
-      Entry::Vacant(e) => { e.insert(v); None }
-      Entry::Occupied(e) => Some((v, e))
-    }
-  }
-}
 /// Extension trait for [`Option`]`<T>`.
 pub trait OptionExt<T> {
   /// Like `unwrap`, but invokes undefined behavior instead of panicking.
@@ -82,51 +66,6 @@ impl CondvarExt for std::sync::Condvar {
   }
 }
 
-/// Newtype for an `Arc<String>`, so that we can implement `From<&str>`.
-#[derive(Clone, Hash, PartialEq, Eq, DeepSizeOf)]
-pub struct ArcString(pub Arc<[u8]>);
-
-impl Borrow<[u8]> for ArcString {
-  fn borrow(&self) -> &[u8] { &*self.0 }
-}
-impl Deref for ArcString {
-  type Target = [u8];
-  fn deref(&self) -> &[u8] { &*self.0 }
-}
-impl ArcString {
-  /// Constructs a new [`ArcString`].
-  #[must_use]
-  pub fn new(s: Box<[u8]>) -> Self { Self(s.into()) }
-}
-impl fmt::Display for ArcString {
-  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    write!(f, "{}", String::from_utf8_lossy(self))
-  }
-}
-impl fmt::Debug for ArcString {
-  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    write!(f, "{:?}", String::from_utf8_lossy(self))
-  }
-}
-impl From<&[u8]> for ArcString {
-  fn from(s: &[u8]) -> Self { Self::new(s.into()) }
-}
-impl From<Box<[u8]>> for ArcString {
-  fn from(s: Box<[u8]>) -> Self { Self::new(s) }
-}
-impl From<Vec<u8>> for ArcString {
-  fn from(s: Vec<u8>) -> Self { s.into_boxed_slice().into() }
-}
-impl From<String> for ArcString {
-  fn from(s: String) -> Self { s.into_bytes().into() }
-}
-
-impl ArcString {
-  pub(crate) fn as_str(&self) -> &str {
-    unsafe {std::str::from_utf8_unchecked(self)}
-  }
-}
-
 /// A structure that allows constructing linked lists on the call stack.
 #[derive(Debug, Clone, Copy)]
 pub struct StackList<'a, T>(pub Option<&'a (StackList<'a, T>, T)>);
@@ -234,61 +173,6 @@ impl<T> SliceUninit<T> {
   #[must_use] pub unsafe fn assume_init(self) -> Box<[T]> { mem::transmute(self.0) }
 }
 
-/// Points to a specific region of a source file by identifying the region's start and end points.
-#[derive(Copy, Clone, PartialEq, Eq, Hash)]
-pub struct Span {
-  /// The byte index of the beginning of the span (inclusive).
-  pub start: usize,
-  /// The byte index of the end of the span (exclusive).
-  pub end: usize,
-}
-crate::deep_size_0!(Span);
-
-impl From<std::ops::Range<usize>> for Span {
-  #[inline] fn from(r: std::ops::Range<usize>) -> Self {
-    Span {start: r.start, end: r.end}
-  }
-}
-
-impl From<std::ops::RangeInclusive<usize>> for Span {
-  #[inline] fn from(r: std::ops::RangeInclusive<usize>) -> Self {
-    Span {start: *r.start(), end: *r.end()+1}
-  }
-}
-
-impl From<usize> for Span {
-  #[inline] fn from(n: usize) -> Self { Span {start: n, end: n} }
-}
-
-impl From<Span> for std::ops::Range<usize> {
-  #[inline] fn from(s: Span) -> Self { s.start..s.end }
-}
-
-impl Deref for Span {
-  type Target = std::ops::Range<usize>;
-  fn deref(&self) -> &std::ops::Range<usize> {
-    unsafe { &*(self as *const Span as *const std::ops::Range<usize>) }
-  }
-}
-
-impl DerefMut for Span {
-  fn deref_mut(&mut self) -> &mut std::ops::Range<usize> {
-    unsafe { &mut *(self as *mut Span as *mut std::ops::Range<usize>) }
-  }
-}
-
-impl IntoIterator for Span {
-  type Item = usize;
-  type IntoIter = std::ops::Range<usize>;
-  fn into_iter(self) -> std::ops::Range<usize> { (*self).clone() }
-}
-
-impl fmt::Debug for Span {
-  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    write!(f, "{}..{}", self.start, self.end)
-  }
-}
-
 #[cfg(feature = "server")]
 pub use lsp_types::{Position, Range};
 
@@ -314,113 +198,97 @@ pub struct Range {
     pub end: Position,
 }
 
-lazy_static! {
-  /// A [`PathBuf`] created by `lazy_static!` pointing to a canonicalized "."
-  pub static ref CURRENT_DIR: PathBuf =
-    std::fs::canonicalize(".").expect("failed to find current directory");
-}
-
-/// Given a [`PathBuf`] 'buf', constructs a relative path from [`CURRENT_DIR`]
-/// to buf, returning it as a String.
-///
-/// Example: If [`CURRENT_DIR`] is `/home/johndoe/mm0`, and `buf` is
-/// `/home/johndoe/Documents/ahoy.mm1` will return `../Documents/ahoy.mm1`
+/// A [SHA-256](https://en.wikipedia.org/wiki/SHA-2) hasher.
 ///
-/// [`CURRENT_DIR`]: struct@CURRENT_DIR
-fn make_relative(buf: &PathBuf) -> String {
-  pathdiff::diff_paths(buf, &*CURRENT_DIR).as_ref().unwrap_or(buf)
-    .to_str().expect("bad unicode in file path").to_owned()
-}
-
-#[derive(DeepSizeOf)]
-struct FileRefInner {
-  path: PathBuf,
-  rel: String,
-  #[cfg(feature = "server")]
-  url: lsp_types::Url
-}
-
-/// A reference to a file. It wraps an [`Arc`] so it can be cloned thread-safely.
-/// A [`FileRef`] can be constructed either from a [`PathBuf`] or a
-/// (`file://`) [`Url`](lsp_types::Url),
-/// and provides (precomputed) access to these views using
-/// [`path()`](FileRef::path) and [`url()`](FileRef::url), as well as
-/// [`rel()`](FileRef::rel) to get the relative path from [`struct@CURRENT_DIR`].
-#[derive(Clone, DeepSizeOf)]
-pub struct FileRef(Arc<FileRefInner>);
-
-impl From<PathBuf> for FileRef {
-  fn from(path: PathBuf) -> FileRef {
-    FileRef(Arc::new(FileRefInner {
-      rel: make_relative(&path),
-      #[cfg(feature = "server")]
-      url: lsp_types::Url::from_file_path(&path).expect("bad file path"),
-      path,
-    }))
-  }
-}
-
-#[cfg(feature = "server")]
-impl From<lsp_types::Url> for FileRef {
-  fn from(url: lsp_types::Url) -> FileRef {
-    let path = url.to_file_path().expect("bad URL");
-    let rel = make_relative(&path);
-    FileRef(Arc::new(FileRefInner {path, rel, url}))
-  }
-}
-
-impl FileRef {
-  /// Convert this [`FileRef`] to a [`PathBuf`], for use with OS file actions.
-  #[must_use] pub fn path(&self) -> &PathBuf { &self.0.path }
-  /// Convert this [`FileRef`] to a relative path (as a `&str`).
-  #[must_use] pub fn rel(&self) -> &str { &self.0.rel }
-  /// Convert this [`FileRef`] to a `file:://` URL, for use with LSP.
-  #[cfg(feature = "server")]
-  #[must_use] pub fn url(&self) -> &lsp_types::Url { &self.0.url }
-  /// Get a pointer to this allocation, for use in hashing.
-  #[must_use] pub fn ptr(&self) -> *const PathBuf { self.path() }
-  /// Compare this with `other` for pointer equality.
-  #[must_use] pub fn ptr_eq(&self, other: &FileRef) -> bool { Arc::ptr_eq(&self.0, &other.0) }
-
-  /// Returns true if this file has the provided extension.
-  #[must_use] pub fn has_extension(&self, ext: &str) -> bool {
-    self.path().extension().map_or(false, |s| s == ext)
+/// This is used for hashes that are saved to disk, like the keys of the
+/// [compilation cache](crate::cache). Unlike [`DefaultHasher`](std::collections::hash_map::DefaultHasher), the result only depends on
+/// the bytes written, and it is collision resistant, so two different inputs can be
+/// assumed to have different hashes.
+#[derive(Clone, Debug)]
+pub struct Sha256 {
+  /// The hash of the blocks processed so far.
+  state: [u32; 8],
+  /// The bytes of the current block, if it is not complete.
+  buf: [u8; 64],
+  /// The number of bytes written so far.
+  len: u64,
+}
+
+impl Default for Sha256 {
+  fn default() -> Self {
+    Self {
+      state: [0x6a09_e667, 0xbb67_ae85, 0x3c6e_f372, 0xa54f_f53a,
+              0x510e_527f, 0x9b05_688c, 0x1f83_d9ab, 0x5be0_cd19],
+      buf: [0; 64],
+      len: 0,
+    }
   }
 }
-impl PartialEq for FileRef {
-  fn eq(&self, other: &Self) -> bool { self.0.rel == other.0.rel }
-}
-impl Eq for FileRef {}
-
-impl Hash for FileRef {
-  fn hash<H: Hasher>(&self, state: &mut H) { self.0.rel.hash(state) }
-}
 
-impl fmt::Display for FileRef {
-  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    self.0.path.file_name().unwrap_or_else(|| self.0.path.as_os_str())
-      .to_str().expect("bad unicode in path").fmt(f)
+impl Sha256 {
+  const K: [u32; 64] = [
+    0x428a_2f98, 0x7137_4491, 0xb5c0_fbcf, 0xe9b5_dba5, 0x3956_c25b, 0x59f1_11f1, 0x923f_82a4, 0xab1c_5ed5,
+    0xd807_aa98, 0x1283_5b01, 0x2431_85be, 0x550c_7dc3, 0x72be_5d74, 0x80de_b1fe, 0x9bdc_06a7, 0xc19b_f174,
+    0xe49b_69c1, 0xefbe_4786, 0x0fc1_9dc6, 0x240c_a1cc, 0x2de9_2c6f, 0x4a74_84aa, 0x5cb0_a9dc, 0x76f9_88da,
+    0x983e_5152, 0xa831_c66d, 0xb003_27c8, 0xbf59_7fc7, 0xc6e0_0bf3, 0xd5a7_9147, 0x06ca_6351, 0x1429_2967,
+    0x27b7_0a85, 0x2e1b_2138, 0x4d2c_6dfc, 0x5338_0d13, 0x650a_7354, 0x766a_0abb, 0x81c2_c92e, 0x9272_2c85,
+    0xa2bf_e8a1, 0xa81a_664b, 0xc24b_8b70, 0xc76c_51a3, 0xd192_e819, 0xd699_0624, 0xf40e_3585, 0x106a_a070,
+    0x19a4_c116, 0x1e37_6c08, 0x2748_774c, 0x34b0_bcb5, 0x391c_0cb3, 0x4ed8_aa4a, 0x5b9c_ca4f, 0x682e_6ff3,
+    0x748f_82ee, 0x78a5_636f, 0x84c8_7814, 0x8cc7_0208, 0x90be_fffa, 0xa450_6ceb, 0xbef9_a3f7, 0xc671_78f2,
+  ];
+
+  /// Process one 64 byte block.
+  #[allow(clippy::many_single_char_names)]
+  fn compress(state: &mut [u32; 8], block: &[u8]) {
+    let mut w = [0_u32; 64];
+    for (w, c) in w.iter_mut().zip(block.chunks_exact(4)) {
+      *w = u32::from_be_bytes([c[0], c[1], c[2], c[3]])
+    }
+    for i in 16..64 {
+      let s0 = w[i-15].rotate_right(7) ^ w[i-15].rotate_right(18) ^ (w[i-15] >> 3);
+      let s1 = w[i-2].rotate_right(17) ^ w[i-2].rotate_right(19) ^ (w[i-2] >> 10);
+      w[i] = w[i-16].wrapping_add(s0).wrapping_add(w[i-7]).wrapping_add(s1);
+    }
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+    for (&k, &w) in Self::K.iter().zip(&w) {
+      let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+      let ch = (e & f) ^ (!e & g);
+      let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(k).wrapping_add(w);
+      let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+      let maj = (a & b) ^ (a & c) ^ (b & c);
+      let t2 = s0.wrapping_add(maj);
+      h = g; g = f; f = e; e = d.wrapping_add(t1);
+      d = c; c = b; b = a; a = t1.wrapping_add(t2);
+    }
+    for (s, x) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) { *s = s.wrapping_add(x) }
   }
-}
 
-impl fmt::Debug for FileRef {
-  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    fmt::Display::fmt(self, f)
+  /// Add `data` to the input.
+  pub fn update(&mut self, mut data: &[u8]) {
+    #[allow(clippy::cast_possible_truncation)]
+    let mut pos = (self.len % 64) as usize;
+    self.len += data.len() as u64;
+    while !data.is_empty() {
+      let n = data.len().min(64 - pos);
+      self.buf[pos..pos + n].copy_from_slice(&data[..n]);
+      data = &data[n..];
+      pos += n;
+      if pos == 64 {
+        Self::compress(&mut self.state, &self.buf);
+        pos = 0;
+      }
+    }
   }
-}
-
-/// A span paired with a [`FileRef`].
-#[derive(Clone, PartialEq, Eq, DeepSizeOf)]
-pub struct FileSpan {
-  /// The file in which this span occured.
-  pub file: FileRef,
-  /// The span (as byte indexes into the file source text).
-  pub span: Span,
-}
 
-impl fmt::Debug for FileSpan {
-  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    write!(f, "{}:{:?}", self.file, self.span)
+  /// Returns the hash of the input.
+  #[must_use] pub fn finish(mut self) -> [u8; 32] {
+    let bits = self.len.wrapping_mul(8);
+    self.update(&[0x80]);
+    while self.len % 64 != 56 { self.update(&[0]) }
+    self.update(&bits.to_be_bytes());
+    let mut out = [0; 32];
+    for (o, s) in out.chunks_exact_mut(4).zip(&self.state) { o.copy_from_slice(&s.to_be_bytes()) }
+    out
   }
 }
 
@@ -430,7 +298,7 @@ impl fmt::Debug for FileSpan {
   let mid = memchr::memchr(0, bytes)? + 1;
   unsafe {
     Some((CStr::from_bytes_with_nul_unchecked(bytes.get_unchecked(..mid)),
-      bytes.get_unchecked(..mid)))
+      bytes.get_unchecked(mid..)))
   }
 }
 
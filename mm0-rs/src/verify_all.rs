@@ -0,0 +1,102 @@
+//! Batch verification of all the proof files in a directory tree.
+//!
+//!     mm0-rs verify-all dir
+//!
+//! will find all `.mm0` and `.mmb` files in `dir` and its subdirectories (and also the
+//! `.mm1` files, with `--sources`), check them, and print a table with the result and the
+//! time taken for each file, followed by the total time. The command fails if any file
+//! fails, so it can be used as the verification step of a release pipeline.
+//!
+//! The `.mm0` and `.mm1` files are elaborated in parallel, and pass if they elaborate with
+//! no errors (warnings are allowed). The errors themselves are printed as usual while the
+//! files are being elaborated. A `.mmb` file passes if it is accepted by the
+//! [`mm0-rs verify`](crate::mmb::verify) checker against the specification with the same
+//! name and the extension `.mm0`, in the same directory; it fails if there is no such file.
+use std::{fs, io};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use clap::ArgMatches;
+use crate::elab::{ElabResult, ErrorLevel};
+use crate::util::FileRef;
+
+/// Add all files in `dir` (recursively) with an extension in `exts` to `out`.
+pub(crate) fn find_files(dir: &Path, exts: &[&str], out: &mut Vec<PathBuf>) -> io::Result<()> {
+  let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+  entries.sort_by_key(fs::DirEntry::path);
+  for e in entries {
+    let path = e.path();
+    if e.file_type()?.is_dir() {
+      find_files(&path, exts, out)?
+    } else if path.extension().and_then(|s| s.to_str()).map_or(false, |s| exts.contains(&s)) {
+      out.push(path)
+    }
+  }
+  Ok(())
+}
+
+/// Main entry point for `mm0-rs verify-all` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs verify-all [--sources] <dir>`, where:
+///
+/// - `dir` is the directory to search for `.mm0` and `.mmb` files
+/// - `--sources`: also verify the `.mm1` files in `dir`
+///
+/// The summary table is printed to stdout, and the process exits with a nonzero status
+/// if any file fails.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let start = Instant::now();
+  let dir = args.value_of("DIR").expect("required arg");
+  let exts: &[&str] = if args.is_present("sources") {&["mm0", "mm1", "mmb"]} else {&["mm0", "mmb"]};
+  let mut paths = vec![];
+  find_files(Path::new(dir), exts, &mut paths)?;
+  if paths.is_empty() {
+    eprintln!("{}: no files to verify", dir);
+    std::process::exit(1)
+  }
+  let files = paths.iter().map(|p| Ok(FileRef::from(fs::canonicalize(p)?)))
+    .collect::<io::Result<Vec<_>>>()?;
+  let mut results = crate::compiler::elab_files(
+    files.iter().filter(|f| !f.has_extension("mmb")).cloned().collect()).into_iter();
+  let results = files.iter().map(|file| {
+    if file.has_extension("mmb") {
+      let start = Instant::now();
+      let spec = file.path().with_extension("mm0");
+      let msg = if spec.is_file() {
+        crate::mmb::verify::verify(&spec, file.path()).err().map(|msg| {
+          eprintln!("{}", msg);
+          "rejected by the checker".into()
+        })
+      } else {
+        Some("no matching .mm0 file".into())
+      };
+      return (msg, start.elapsed())
+    }
+    let (res, time) = results.next().expect("one result per file");
+    (match res {
+      Ok(ElabResult::Ok(_, errors, _)) => match errors.map_or(0, |es|
+        es.iter().filter(|e| matches!(e.level, ErrorLevel::Error)).count()) {
+        0 => None,
+        1 => Some("1 error".into()),
+        n => Some(format!("{} errors", n)),
+      },
+      Ok(ElabResult::Canceled) => Some("canceled".into()),
+      Ok(ElabResult::ImportCycle(_)) => Some("import cycle".into()),
+      Err(e) => Some(e.to_string()),
+    }, time)
+  }).collect::<Vec<(Option<String>, _)>>();
+  let width = files.iter().map(|f| f.rel().len()).max().unwrap_or(0).max("file".len());
+  let mut failed = 0;
+  println!("\n{:w$}  {:6}  {:>9}", "file", "result", "time", w = width);
+  for (file, (msg, time)) in files.iter().zip(results) {
+    let result = if msg.is_some() { failed += 1; "FAILED" } else { "ok" };
+    print!("{:w$}  {:6}  {:>8.2}s", file.rel(), result, time.as_secs_f64(), w = width);
+    if let Some(msg) = msg { print!("  ({})", msg) }
+    println!();
+  }
+  println!("\n{} files, {} passed, {} failed, in {:.2}s",
+    files.len(), files.len() - failed, failed, start.elapsed().as_secs_f64());
+  if failed != 0 { std::process::exit(1) }
+  Ok(())
+}
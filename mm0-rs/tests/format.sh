@@ -0,0 +1,47 @@
+#! /bin/sh
+# Tests for "mm0-rs format" on the example libraries. Each .mm0 and .mm1 file in
+# examples/ is formatted, and then the result is formatted again, which must not
+# change it. The comments of the formatted file (everything from "--" to the end
+# of a line, ignoring whitespace at the ends) must be the same as those of the
+# original, in the same order. The formatted files are written next to the
+# originals (as X.fmt.mm1 and so on), so that imports still resolve, and removed
+# afterwards. Also, each format/X.mm1 must format to format/X.out.
+# mm0-rs must be on the PATH.
+cd "$(dirname "$0")"
+command -v mm0-rs > /dev/null || { echo "mm0-rs is not on the PATH"; exit 1; }
+failed=0
+
+comments() {
+  grep -o -- '--.*' "$1" | sed 's/[[:space:]]*$//'
+}
+
+for f in format/*.mm1; do
+  if ! mm0-rs format "$f" -o - 2> /dev/null | grep -v '^elab' | cmp -s - "${f%.*}.out"; then
+    echo "FAIL: $f:"; mm0-rs format "$f" -o - 2> /dev/null | grep -v '^elab' |
+      diff - "${f%.*}.out" | head -20; failed=1
+  fi
+done
+
+cd ../../examples
+for f in *.mm0 *.mm1; do
+  case "$f" in *.fmt.*) continue;; esac
+  out1="${f%.*}.fmt.${f##*.}"
+  out2="${f%.*}.fmt2.${f##*.}"
+  if ! mm0-rs format "$f" -o "$out1" > /dev/null 2>&1; then
+    echo "FAIL: $f could not be formatted"; failed=1
+  elif ! mm0-rs format "$out1" -o "$out2" > /dev/null 2>&1; then
+    echo "FAIL: the formatted $f could not be formatted again"; failed=1
+  elif ! cmp -s "$out1" "$out2"; then
+    echo "FAIL: formatting $f is not idempotent:"; diff "$out1" "$out2" | head -20; failed=1
+  else
+    comments "$f" > "$f.c1"
+    comments "$out1" > "$f.c2"
+    if ! cmp -s "$f.c1" "$f.c2"; then
+      echo "FAIL: formatting $f changed its comments:"; diff "$f.c1" "$f.c2" | head -20; failed=1
+    fi
+  fi
+  rm -f "$out1" "$out2" "$f.c1" "$f.c2"
+done
+
+if [ $failed = 0 ]; then echo "all tests passed"; fi
+exit $failed
@@ -1,15 +1,51 @@
-import { commands, window, workspace, ExtensionContext, TextDocument, EndOfLine } from 'vscode';
+import { commands, window, workspace, ExtensionContext, TextDocument, EndOfLine,
+	OutputChannel, TextEditorSelectionChangeEvent } from 'vscode';
 
 import {
 	LanguageClient,
 	LanguageClientOptions,
 	ServerOptions,
 	ErrorAction,
-	CloseAction
+	CloseAction,
+	RequestType,
+	TextDocumentPositionParams
 } from 'vscode-languageclient';
 
 let client: LanguageClient;
 
+/** The response to the `mm0/goalState` request. */
+interface GoalState {
+	hyps: string[];
+	goals: string[];
+}
+
+const goalStateRequest =
+	new RequestType<TextDocumentPositionParams, GoalState | null, void, void>('mm0/goalState');
+
+/** The output pane showing the goals at the cursor, once `metamath-zero.showGoals` has been run. */
+let goalsChannel: OutputChannel | undefined;
+
+function updateGoals(e: TextEditorSelectionChangeEvent) {
+	const doc = e.textEditor.document;
+	if (!goalsChannel || !client || doc.languageId !== 'metamath-zero') { return; }
+	const channel = goalsChannel;
+	const params = client.code2ProtocolConverter
+		.asTextDocumentPositionParams(doc, e.selections[0].active);
+	client.sendRequest(goalStateRequest, params).then(state => {
+		channel.clear();
+		if (!state) {
+			channel.appendLine('(not in a tactic block)');
+		} else {
+			state.hyps.forEach(h => channel.appendLine(h));
+			if (state.goals.length === 0) {
+				channel.appendLine('no goals');
+			} else {
+				state.goals.forEach(g => channel.appendLine('|- ' + g));
+			}
+		}
+	}, () => {});
+}
+
 function startClient() {
 	let config = workspace.getConfiguration('metamath-zero');
 	let mm0Path: string = config.get('executablePath') || 'mm0-rs';
@@ -55,7 +91,15 @@ export function activate(context: ExtensionContext) {
 		commands.registerCommand('metamath-zero.shutdownServer',
 		  () => client.stop().then(() => {}, () => {})),
 		commands.registerCommand('metamath-zero.restartServer',
-			() => client.stop().then(startClient, startClient))
+			() => client.stop().then(startClient, startClient)),
+		commands.registerCommand('metamath-zero.showGoals', () => {
+			if (!goalsChannel) {
+				goalsChannel = window.createOutputChannel('MM0 Goals');
+				context.subscriptions.push(goalsChannel);
+			}
+			goalsChannel.show(true);
+		}),
+		window.onDidChangeTextEditorSelection(updateGoals)
 	);
 }
 